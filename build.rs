@@ -0,0 +1,20 @@
+use cfgrammar::yacc::YaccKind;
+use lrlex::CTLexerBuilder;
+
+fn main() {
+    // The SQL lexer and parser are only needed by the `sql` module
+    if std::env::var_os("CARGO_FEATURE_SQL").is_none() {
+        return;
+    }
+
+    CTLexerBuilder::new()
+        .lrpar_config(|ctp| {
+            ctp.yacckind(YaccKind::Grmtools)
+                .grammar_in_src_dir("sql.y")
+                .unwrap()
+        })
+        .lexer_in_src_dir("sql.l")
+        .unwrap()
+        .build()
+        .unwrap();
+}
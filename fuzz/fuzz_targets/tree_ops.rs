@@ -0,0 +1,82 @@
+//! Replays arbitrary sequences of inserts, deletes, lookups and scans against a tree and a
+//! `BTreeMap` model, asserting they agree and that the tree passes `verify` afterwards.
+//!
+//! Every 4 input bytes make up one operation: `[op, key, key, value]`. Keys are drawn from a
+//! small space so operations often collide, and values range up to ~1KB so leaves split often.
+#![no_main]
+
+use johndb::btree::key::KeyBytes;
+use johndb::btree::value::ValueBytes;
+use johndb::btree::BTree;
+use johndb::page_fetcher::InMemoryPageFetcher;
+use johndb::Error;
+use libfuzzer_sys::fuzz_target;
+use std::collections::BTreeMap;
+
+/// Entries compared after a scan.
+const SCAN_LEN: usize = 8;
+
+fuzz_target!(|data: &[u8]| {
+    let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(512)).unwrap();
+    let mut model: BTreeMap<Vec<u8>, Vec<u8>> = BTreeMap::new();
+
+    for op in data.chunks_exact(4) {
+        // The 0 prefix keeps keys below `KeyBytes::max_key()`
+        let key = KeyBytes {
+            key: vec![0, op[1], op[2] % 16],
+        };
+        match op[0] % 4 {
+            0 => {
+                let value = vec![op[3]; op[3] as usize * 4];
+                let existing = match btree.insert_if_absent(
+                    key.clone(),
+                    ValueBytes {
+                        value: value.clone(),
+                    },
+                ) {
+                    Ok(existing) => existing,
+                    // The fixed page budget ran out, which isn't a bug
+                    Err(err) if matches!(err.root_cause(), Error::OutOfPages) => break,
+                    Err(err) => panic!("insert failed: {}", err),
+                };
+                assert_eq!(
+                    existing.map(|value| value.value),
+                    model.get(&key.key).cloned()
+                );
+                model.entry(key.key).or_insert(value);
+            }
+            1 => {
+                let deleted = btree.delete(key.clone()).unwrap();
+                assert_eq!(deleted.map(|value| value.value), model.remove(&key.key));
+            }
+            2 => {
+                let found = btree.search(key.clone()).unwrap();
+                assert_eq!(
+                    found.value.map(|value| value.value),
+                    model.get(&key.key).cloned()
+                );
+            }
+            _ => {
+                let scanned = btree
+                    .range(key.clone()..)
+                    .unwrap()
+                    .take(SCAN_LEN)
+                    .map(|entry| {
+                        let (key, value) = entry.unwrap();
+                        (key.key, value.value)
+                    })
+                    .collect::<Vec<_>>();
+                let expected = model
+                    .range(key.key..)
+                    .take(SCAN_LEN)
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect::<Vec<_>>();
+                assert_eq!(scanned, expected);
+            }
+        }
+    }
+
+    let report = btree.verify().unwrap();
+    assert!(report.is_ok(), "{:?}", report.violations);
+    assert_eq!(btree.analyze().unwrap().entry_cnt(), model.len());
+});
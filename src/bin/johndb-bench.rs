@@ -0,0 +1,624 @@
+//! YCSB-style benchmarks against a database file, so changes to the split, lock and page code
+//! can be measured consistently.
+//!
+//! ```text
+//! $ cargo run --release --features bench --bin johndb-bench -- \
+//!     --workload read-heavy --records 100000 --ops 200000 --distribution zipfian
+//! ```
+//!
+//! Every run first loads `--records` entries, then runs `--ops` operations of the workload:
+//!
+//! * `load`: nothing beyond the load phase, which is always reported.
+//! * `read-heavy`: 95% reads, 5% updates (YCSB workload B).
+//! * `update-heavy`: 50% reads, 50% updates (YCSB workload A).
+//! * `scan`: 95% scans of 1 to 100 entries, 5% inserts of new keys (YCSB workload E).
+//! * `uuid`: loads `--records` UUID keys into an in-memory tree for each of random (v4), v7, and
+//!   v1 UUIDs stored raw and time-ordered, see `KeyUuid`, and reports how full the leaves end up.
+//!   Ignores `--ops`, `--threads`, `--distribution` and `--path`.
+//! * `pages`: decodes every item of a full in-memory page, and rebuilds the page from its bytes,
+//!   `--ops` times. Compare builds with and without the `safe-pages` feature to measure what its
+//!   bounds checks cost. Ignores every other option.
+//!
+//! `Database` isn't `Sync` yet, so threads share it behind a mutex and operations are
+//! serialized. Running with several threads measures the overhead of that contention rather than
+//! any parallel speedup.
+
+use johndb::btree::key::KeyU64;
+use johndb::btree::key::KeyUuid;
+use johndb::btree::key_uuid::Raw;
+use johndb::btree::key_uuid::TimeOrdered;
+use johndb::btree::key_uuid::UuidLayout;
+use johndb::btree::value::ValueTupleId;
+use johndb::btree::BTree;
+use johndb::page::Page;
+use johndb::page_fetcher::InMemoryPageFetcher;
+use johndb::Database;
+use johndb::Options;
+use std::hint::black_box;
+use std::ops::Bound;
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::time::Duration;
+use std::time::Instant;
+
+const USAGE: &str = "\
+usage: johndb-bench [options]
+  --workload <load|read-heavy|update-heavy|scan|uuid|pages>
+                                                   (default: read-heavy)
+  --records <n>                                    entries loaded up front (default: 10000)
+  --ops <n>                                        operations after loading (default: 100000)
+  --threads <n>                                    (default: 1)
+  --value-size <bytes>                             (default: 100)
+  --distribution <uniform|zipfian>                 key popularity (default: uniform)
+  --max-pages <n>                                  (default: 65536)
+  --path <file>                                    database file (default: a temporary file)";
+
+/// Maximum length of a scan.
+const MAX_SCAN_LEN: u64 = 100;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Workload {
+    Load,
+    ReadHeavy,
+    UpdateHeavy,
+    Scan,
+    Uuid,
+    Pages,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Distribution {
+    Uniform,
+    Zipfian,
+}
+
+#[derive(Debug, Clone)]
+struct Config {
+    workload: Workload,
+    records: u64,
+    ops: u64,
+    threads: u64,
+    value_size: usize,
+    distribution: Distribution,
+    max_pages: usize,
+    path: Option<String>,
+}
+
+fn main() {
+    env_logger::init();
+
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let config = match parse_args(&args) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    match config.workload {
+        Workload::Uuid => return uuid_fill(&config),
+        Workload::Pages => return report("pages", &page_access(config.ops)),
+        _ => {}
+    }
+
+    let path = match &config.path {
+        Some(path) => path.into(),
+        None => std::env::temp_dir().join(format!("johndb-bench-{}", std::process::id())),
+    };
+    let _ = std::fs::remove_file(&path);
+    let options = Options {
+        create_if_missing: true,
+        max_pages: config.max_pages,
+        ..Options::default()
+    };
+    let db = match Database::open(&path, options) {
+        Ok(db) => Arc::new(Mutex::new(db)),
+        Err(err) => {
+            eprintln!("failed to open {}: {}", path.display(), err);
+            std::process::exit(1);
+        }
+    };
+
+    let load = run_phase(&db, &config, config.records, |_, op, db| {
+        let key = key(op);
+        db.put(&key, &value(op, config.value_size)).map(|_| ())
+    });
+    report("load", &load);
+
+    if config.workload != Workload::Load {
+        // New keys inserted by the scan workload continue after the loaded ones
+        let next_insert = AtomicU64::new(config.records);
+        let chooser = KeyChooser::new(config.distribution, config.records);
+        let run = run_phase(&db, &config, config.ops, |rng, op, db| {
+            let roll = rng.next_f64();
+            match config.workload {
+                Workload::ReadHeavy | Workload::UpdateHeavy => {
+                    let read_ratio = if config.workload == Workload::ReadHeavy {
+                        0.95
+                    } else {
+                        0.5
+                    };
+                    let key = key(chooser.next(rng));
+                    if roll < read_ratio {
+                        db.get(&key).map(|_| ())
+                    } else {
+                        db.put(&key, &value(op, config.value_size))
+                    }
+                }
+                Workload::Scan => {
+                    if roll < 0.95 {
+                        let start = key(chooser.next(rng));
+                        let len = 1 + rng.next_u64() % MAX_SCAN_LEN;
+                        let range = db.range((Bound::Included(&start[..]), Bound::Unbounded))?;
+                        for entry in range.take(len as usize) {
+                            entry?;
+                        }
+                        Ok(())
+                    } else {
+                        let idx = next_insert.fetch_add(1, Ordering::Relaxed);
+                        db.put(&key(idx), &value(idx, config.value_size))
+                    }
+                }
+                Workload::Load | Workload::Uuid | Workload::Pages => unreachable!(),
+            }
+        });
+        report(workload_name(config.workload), &run);
+    }
+
+    drop(db);
+    if config.path.is_none() {
+        let _ = std::fs::remove_file(&path);
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Config, String> {
+    let mut config = Config {
+        workload: Workload::ReadHeavy,
+        records: 10000,
+        ops: 100000,
+        threads: 1,
+        value_size: 100,
+        distribution: Distribution::Uniform,
+        max_pages: 1 << 16,
+        path: None,
+    };
+
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", flag))?;
+        let number = || {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("invalid number {} for {}", value, flag))
+        };
+        match flag.as_str() {
+            "--workload" => {
+                config.workload = match value.as_str() {
+                    "load" => Workload::Load,
+                    "read-heavy" => Workload::ReadHeavy,
+                    "update-heavy" => Workload::UpdateHeavy,
+                    "scan" => Workload::Scan,
+                    "uuid" => Workload::Uuid,
+                    "pages" => Workload::Pages,
+                    _ => return Err(format!("unknown workload {}", value)),
+                }
+            }
+            "--distribution" => {
+                config.distribution = match value.as_str() {
+                    "uniform" => Distribution::Uniform,
+                    "zipfian" => Distribution::Zipfian,
+                    _ => return Err(format!("unknown distribution {}", value)),
+                }
+            }
+            "--records" => config.records = number()?,
+            "--ops" => config.ops = number()?,
+            "--threads" => config.threads = number()?.max(1),
+            "--value-size" => config.value_size = number()? as usize,
+            "--max-pages" => config.max_pages = number()? as usize,
+            "--path" => config.path = Some(value.clone()),
+            _ => return Err(format!("unknown option {}", flag)),
+        }
+    }
+
+    if config.records == 0 && config.workload != Workload::Load {
+        return Err("--records must be positive to run a workload".to_string());
+    }
+    Ok(config)
+}
+
+fn workload_name(workload: Workload) -> &'static str {
+    match workload {
+        Workload::Load => "load",
+        Workload::ReadHeavy => "read-heavy",
+        Workload::UpdateHeavy => "update-heavy",
+        Workload::Scan => "scan",
+        Workload::Uuid => "uuid",
+        Workload::Pages => "pages",
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum UuidVersion {
+    V1,
+    V4,
+    V7,
+}
+
+/// Loads `config.records` UUIDs of each version and layout into a tree and reports the load time
+/// and leaf fill factors.
+fn uuid_fill(config: &Config) {
+    report_uuid_fill::<Raw>("v4 raw", config, UuidVersion::V4);
+    report_uuid_fill::<Raw>("v7 raw", config, UuidVersion::V7);
+    report_uuid_fill::<Raw>("v1 raw", config, UuidVersion::V1);
+    report_uuid_fill::<TimeOrdered>("v1 ordered", config, UuidVersion::V1);
+}
+
+fn report_uuid_fill<L: UuidLayout>(name: &str, config: &Config, version: UuidVersion) {
+    let mut btree = match BTree::new(InMemoryPageFetcher::with_capacity(config.max_pages)) {
+        Ok(btree) => btree,
+        Err(err) => {
+            eprintln!("failed to create a tree: {}", err);
+            std::process::exit(1);
+        }
+    };
+    let mut rng = Rng::new(1);
+    let value = ValueTupleId {
+        page_no: 0,
+        offset: 0,
+    };
+    let start = Instant::now();
+    for idx in 0..config.records {
+        let key = KeyUuid::<L>::new(uuid(version, idx, &mut rng));
+        if let Err(err) = btree.insert(key, value) {
+            eprintln!("{}: insert {} failed: {}", name, idx, err);
+            std::process::exit(1);
+        }
+    }
+    let elapsed = start.elapsed();
+
+    let stats = match btree.analyze() {
+        Ok(stats) => stats,
+        Err(err) => {
+            eprintln!("{}: analyze failed: {}", name, err);
+            std::process::exit(1);
+        }
+    };
+    let leaves = stats.levels.last().cloned().unwrap_or_default();
+    println!(
+        "{:<13} {:>9} keys in {:>8.3}s | {} leaves, fill avg {:.2} p10 {:.2} p50 {:.2}",
+        name,
+        config.records,
+        elapsed.as_secs_f64(),
+        leaves.page_cnt,
+        leaves.avg_fill(),
+        leaves.fill_percentile(10.0),
+        leaves.fill_percentile(50.0)
+    );
+}
+
+/// Fills a page with `KeyU64` items, then times `ops` rounds of decoding all of them and
+/// rebuilding the page from its bytes.
+fn page_access(ops: u64) -> PhaseResult {
+    let mut page = Page::new(0);
+    let mut items = 0;
+    while page.add_item_v2(&KeyU64 { key: items }).is_ok() {
+        items += 1;
+    }
+
+    let mut latencies = Vec::with_capacity(ops as usize);
+    let mut errors = 0;
+    let start = Instant::now();
+    for _ in 0..ops {
+        let op_start = Instant::now();
+        let sum = page
+            .items_iter_v2::<KeyU64>()
+            .map(|item| item.key)
+            .fold(0u64, u64::wrapping_add);
+        page = Page::from_bytes(black_box(page.as_bytes()));
+        latencies.push(op_start.elapsed().as_nanos() as u64);
+        if black_box(sum) != items * (items - 1) / 2 {
+            errors += 1;
+        }
+    }
+    let elapsed = start.elapsed();
+    latencies.sort_unstable();
+
+    PhaseResult {
+        elapsed,
+        latencies,
+        errors,
+    }
+}
+
+/// The `idx`th UUID generated of `version`, with the bits not taken by their timestamp random.
+/// v1 UUIDs are generated about 0.4s apart, so the low 32 bits of their timestamps, which come
+/// first, wrap around every 1024 UUIDs. v7 UUIDs are generated 16 per millisecond, with a counter
+/// keeping them in order within the millisecond.
+fn uuid(version: UuidVersion, idx: u64, rng: &mut Rng) -> [u8; 16] {
+    let mut uuid = [0u8; 16];
+    uuid[..8].copy_from_slice(&rng.next_u64().to_be_bytes());
+    uuid[8..].copy_from_slice(&rng.next_u64().to_be_bytes());
+    let version_bits = match version {
+        UuidVersion::V1 => {
+            // 100ns ticks since 1582, starting in 2024
+            let timestamp = 0x01EE_B000_0000_0000 + (idx << 22);
+            uuid[0..4].copy_from_slice(&(timestamp as u32).to_be_bytes());
+            uuid[4..6].copy_from_slice(&((timestamp >> 32) as u16).to_be_bytes());
+            uuid[6..8].copy_from_slice(&((timestamp >> 48) as u16).to_be_bytes());
+            0x10
+        }
+        UuidVersion::V4 => 0x40,
+        UuidVersion::V7 => {
+            let millis = 1_700_000_000_000 + idx / 16;
+            uuid[0..6].copy_from_slice(&millis.to_be_bytes()[2..]);
+            uuid[6..8].copy_from_slice(&((idx % 16) as u16).to_be_bytes());
+            0x70
+        }
+    };
+    uuid[6] = (uuid[6] & 0x0F) | version_bits;
+    // RFC 4122 variant
+    uuid[8] = (uuid[8] & 0x3F) | 0x80;
+    uuid
+}
+
+/// Keys are scattered over the key space so that loading them doesn't just append to the
+/// rightmost leaf.
+fn key(idx: u64) -> Vec<u8> {
+    format!("user{:016x}", scramble(idx)).into_bytes()
+}
+
+fn value(seed: u64, size: usize) -> Vec<u8> {
+    (0..size)
+        .map(|i| b'a' + ((seed as usize + i) % 26) as u8)
+        .collect()
+}
+
+/// A bijective 64-bit mix (splitmix64's finalizer).
+fn scramble(mut x: u64) -> u64 {
+    x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+    x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+    x ^ (x >> 31)
+}
+
+/// splitmix64, which is plenty for picking keys.
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        scramble(self.state)
+    }
+
+    /// Uniform in `[0, 1)`.
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+/// Picks indexes of loaded keys.
+enum KeyChooser {
+    Uniform {
+        n: u64,
+    },
+    /// YCSB's scrambled zipfian: ranks follow Gray et al.'s zipfian generator with a constant of
+    /// 0.99, then get hashed so the popular keys don't all sit next to each other.
+    Zipfian {
+        n: u64,
+        theta: f64,
+        zetan: f64,
+        alpha: f64,
+        eta: f64,
+    },
+}
+
+impl KeyChooser {
+    fn new(distribution: Distribution, n: u64) -> Self {
+        match distribution {
+            Distribution::Uniform => KeyChooser::Uniform { n },
+            Distribution::Zipfian => {
+                let theta = 0.99;
+                let zeta = |n: u64| (1..=n).map(|i| 1.0 / (i as f64).powf(theta)).sum::<f64>();
+                let zetan = zeta(n);
+                let zeta2 = zeta(2);
+                KeyChooser::Zipfian {
+                    n,
+                    theta,
+                    zetan,
+                    alpha: 1.0 / (1.0 - theta),
+                    eta: (1.0 - (2.0 / n as f64).powf(1.0 - theta)) / (1.0 - zeta2 / zetan),
+                }
+            }
+        }
+    }
+
+    fn next(&self, rng: &mut Rng) -> u64 {
+        match *self {
+            KeyChooser::Uniform { n } => rng.next_u64() % n,
+            KeyChooser::Zipfian {
+                n,
+                theta,
+                zetan,
+                alpha,
+                eta,
+            } => {
+                let u = rng.next_f64();
+                let uz = u * zetan;
+                let rank = if uz < 1.0 {
+                    0
+                } else if uz < 1.0 + 0.5f64.powf(theta) {
+                    1
+                } else {
+                    ((n as f64 * (eta * u - eta + 1.0).powf(alpha)) as u64).min(n - 1)
+                };
+                scramble(rank) % n
+            }
+        }
+    }
+}
+
+struct PhaseResult {
+    elapsed: Duration,
+    /// Sorted latencies of every operation, in nanoseconds.
+    latencies: Vec<u64>,
+    errors: u64,
+}
+
+/// Runs `ops` operations split across the configured threads. `op_fn` gets the thread's RNG, the
+/// operation's global index and the locked database.
+fn run_phase<F>(db: &Arc<Mutex<Database>>, config: &Config, ops: u64, op_fn: F) -> PhaseResult
+where
+    F: Fn(&mut Rng, u64, &mut Database) -> johndb::Result<()> + Sync,
+{
+    let start = Instant::now();
+    let results = std::thread::scope(|scope| {
+        let handles = (0..config.threads)
+            .map(|thread| {
+                let op_fn = &op_fn;
+                scope.spawn(move || {
+                    let mut rng = Rng::new(scramble(thread + 1));
+                    let mut latencies = Vec::new();
+                    let mut errors = 0;
+                    let mut op = thread;
+                    while op < ops {
+                        let op_start = Instant::now();
+                        let result = {
+                            let mut db = db.lock().expect("a benchmark thread panicked");
+                            op_fn(&mut rng, op, &mut db)
+                        };
+                        latencies.push(op_start.elapsed().as_nanos() as u64);
+                        if let Err(err) = result {
+                            if errors == 0 {
+                                eprintln!("operation {} failed: {}", op, err);
+                            }
+                            errors += 1;
+                        }
+                        op += config.threads;
+                    }
+                    (latencies, errors)
+                })
+            })
+            .collect::<Vec<_>>();
+        handles
+            .into_iter()
+            .map(|handle| handle.join().expect("a benchmark thread panicked"))
+            .collect::<Vec<_>>()
+    });
+    let elapsed = start.elapsed();
+
+    let mut latencies = Vec::new();
+    let mut errors = 0;
+    for (thread_latencies, thread_errors) in results {
+        latencies.extend(thread_latencies);
+        errors += thread_errors;
+    }
+    latencies.sort_unstable();
+
+    PhaseResult {
+        elapsed,
+        latencies,
+        errors,
+    }
+}
+
+/// Nearest-rank percentile of sorted `values`.
+fn percentile(values: &[u64], percentile: f64) -> u64 {
+    if values.is_empty() {
+        return 0;
+    }
+    let rank = (percentile / 100.0 * values.len() as f64).ceil() as usize;
+    values[rank.clamp(1, values.len()) - 1]
+}
+
+fn report(name: &str, result: &PhaseResult) {
+    let ops = result.latencies.len();
+    let secs = result.elapsed.as_secs_f64();
+    let micros = |nanos: u64| nanos as f64 / 1000.0;
+    println!(
+        "{:<13} {:>9} ops in {:>8.3}s = {:>10.0} ops/s | latency us p50 {:.1} p95 {:.1} p99 {:.1} max {:.1} | {} errors",
+        name,
+        ops,
+        secs,
+        if secs > 0.0 { ops as f64 / secs } else { 0.0 },
+        micros(percentile(&result.latencies, 50.0)),
+        micros(percentile(&result.latencies, 95.0)),
+        micros(percentile(&result.latencies, 99.0)),
+        micros(result.latencies.last().copied().unwrap_or(0)),
+        result.errors
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_args;
+    use super::percentile;
+    use super::uuid;
+    use super::Distribution;
+    use super::KeyChooser;
+    use super::Rng;
+    use super::UuidVersion;
+    use super::Workload;
+
+    #[test]
+    fn zipfian_is_skewed() {
+        let n = 1000;
+        let chooser = KeyChooser::new(Distribution::Zipfian, n);
+        let mut rng = Rng::new(1);
+        let mut counts = vec![0u32; n as usize];
+        for _ in 0..100000 {
+            counts[chooser.next(&mut rng) as usize] += 1;
+        }
+
+        let mut sorted = counts.clone();
+        sorted.sort_unstable_by(|x, y| y.cmp(x));
+        // The most popular 10% of keys get the bulk of the accesses
+        let top = sorted[..(n / 10) as usize].iter().sum::<u32>();
+        assert!(top > 50000, "{}", top);
+    }
+
+    #[test]
+    fn uuids() {
+        let mut rng = Rng::new(1);
+        let v7 = (0..100)
+            .map(|idx| uuid(UuidVersion::V7, idx, &mut rng))
+            .collect::<Vec<_>>();
+        assert!(v7.windows(2).all(|pair| pair[0] < pair[1]));
+        for (version, bits) in [(UuidVersion::V1, 0x10), (UuidVersion::V4, 0x40)] {
+            let uuid = uuid(version, 5, &mut rng);
+            assert_eq!((uuid[6] & 0xF0, uuid[8] & 0xC0), (bits, 0x80));
+        }
+    }
+
+    #[test]
+    fn args_and_percentiles() {
+        let args = [
+            "--workload",
+            "scan",
+            "--threads",
+            "4",
+            "--distribution",
+            "zipfian",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect::<Vec<_>>();
+        let config = parse_args(&args).unwrap();
+        assert_eq!(config.workload, Workload::Scan);
+        assert_eq!(config.threads, 4);
+        assert_eq!(config.distribution, Distribution::Zipfian);
+        assert!(parse_args(&["--bogus".to_string(), "1".to_string()]).is_err());
+
+        assert_eq!(percentile(&[1, 2, 3, 4], 50.0), 2);
+        assert_eq!(percentile(&[1, 2, 3, 4], 99.0), 4);
+    }
+}
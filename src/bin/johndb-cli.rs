@@ -0,0 +1,300 @@
+//! An interactive shell for poking at a johndb database file.
+//!
+//! ```text
+//! $ johndb-cli data.db
+//! johndb> put hello world
+//! johndb> get hello
+//! world
+//! ```
+//!
+//! Keys and values are taken verbatim from the whitespace-separated arguments, and printed back
+//! lossily as UTF-8. Changes are flushed to disk when the shell exits.
+//!
+//! `johndb-cli pagedump <path> <page_no> [--hex]` prints a single page instead of starting the
+//! shell.
+//!
+//! With the `sql` feature, `sql <statement>` runs a statement of the subset `johndb::sql`
+//! supports, e.g. `sql SELECT * FROM users WHERE id = 1`.
+
+use johndb::Database;
+use johndb::Options;
+use johndb::Result;
+use std::fs::File;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::ops::Bound;
+
+const HELP: &str = "\
+put <key> <value>     set key to value
+get <key>             print the value of key
+del <key>             remove key
+scan [start] [end]    print the entries in [start, end)
+stats                 print page counts and per-level fill statistics
+check                 verify the tree's structure and every value
+hot [n]               print the n most accessed pages (default 10)
+dump <file>           write every entry to file in the portable dump format
+load <file>           put every entry of a dump file
+flush                 write all pages to disk
+help                  print this message
+quit                  flush and exit";
+
+#[cfg(feature = "sql")]
+const SQL_HELP: &str =
+    "sql <statement>       run a CREATE TABLE, INSERT, SELECT or DELETE statement";
+
+enum Flow {
+    Continue,
+    Quit,
+}
+
+fn main() {
+    env_logger::init();
+
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let path = match args.as_slice() {
+        [path] => path.clone(),
+        [cmd, path, page_no, flags @ ..] if cmd == "pagedump" && flags.len() <= 1 => {
+            let hex = match flags {
+                [] => false,
+                [flag] if flag == "--hex" => true,
+                _ => usage(),
+            };
+            let page_no = page_no.parse::<u32>().unwrap_or_else(|_| usage());
+            pagedump(path, page_no, hex);
+            return;
+        }
+        _ => usage(),
+    };
+
+    let mut db = match Database::open(&path, Options::default()) {
+        Ok(db) => db,
+        Err(err) => {
+            eprintln!("failed to open {}: {}", path, err);
+            std::process::exit(1);
+        }
+    };
+
+    let stdin = std::io::stdin();
+    let stdout = std::io::stdout();
+    let mut lines = stdin.lock().lines();
+    loop {
+        {
+            let mut out = stdout.lock();
+            let _ = write!(out, "johndb> ");
+            let _ = out.flush();
+        }
+
+        let line = match lines.next() {
+            Some(Ok(line)) => line,
+            Some(Err(err)) => {
+                eprintln!("failed to read input: {}", err);
+                break;
+            }
+            None => break,
+        };
+
+        match execute(&mut db, &line, &mut stdout.lock()) {
+            Ok(Flow::Continue) => {}
+            Ok(Flow::Quit) => break,
+            Err(err) => eprintln!("error: {}", err),
+        }
+    }
+
+    if let Err(err) = db.close() {
+        eprintln!("failed to close {}: {}", path, err);
+        std::process::exit(1);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: johndb-cli <path>");
+    eprintln!("       johndb-cli pagedump <path> <page_no> [--hex]");
+    std::process::exit(2);
+}
+
+fn pagedump(path: &str, page_no: u32, hex: bool) {
+    let options = Options {
+        create_if_missing: false,
+        ..Options::default()
+    };
+    let result = Database::open(path, options).and_then(|db| db.dump_page(page_no, hex));
+    match result {
+        Ok(dump) => print!("{}", dump),
+        Err(err) => {
+            eprintln!("failed to dump page {} of {}: {}", page_no, path, err);
+            std::process::exit(1);
+        }
+    }
+}
+
+/// Runs a single command line against `db`, writing its output to `out`.
+fn execute<W: Write>(db: &mut Database, line: &str, out: &mut W) -> Result<Flow> {
+    let args = line.split_whitespace().collect::<Vec<_>>();
+    match args.as_slice() {
+        [] => {}
+        ["put", key, value] => db.put(key.as_bytes(), value.as_bytes())?,
+        ["get", key] => match db.get(key.as_bytes())? {
+            Some(value) => writeln!(out, "{}", String::from_utf8_lossy(&value))?,
+            None => writeln!(out, "(not found)")?,
+        },
+        ["del", key] => {
+            if db.delete(key.as_bytes())?.is_none() {
+                writeln!(out, "(not found)")?;
+            }
+        }
+        ["scan", bounds @ ..] if bounds.len() <= 2 => {
+            let bound = |idx: usize| match bounds.get(idx) {
+                Some(key) => Bound::Included(key.as_bytes()),
+                None => Bound::Unbounded,
+            };
+            let end = match bound(1) {
+                Bound::Included(key) => Bound::Excluded(key),
+                other => other,
+            };
+
+            let mut cnt = 0;
+            for entry in db.range((bound(0), end))? {
+                let (key, value) = entry?;
+                writeln!(
+                    out,
+                    "{} => {}",
+                    String::from_utf8_lossy(&key),
+                    String::from_utf8_lossy(&value)
+                )?;
+                cnt += 1;
+            }
+            writeln!(out, "({} entries)", cnt)?;
+        }
+        ["stats"] => {
+            let stats = db.stats()?;
+            writeln!(out, "pages: {}", stats.page_cnt)?;
+            writeln!(out, "keys: {}", stats.key_cnt)?;
+            for (depth, level) in stats.tree.levels.iter().enumerate() {
+                writeln!(
+                    out,
+                    "level {}: {} pages, {} items, fill avg {:.1}% p50 {:.1}% p90 {:.1}%, \
+                     {} bytes dead, {} reachable only through siblings",
+                    depth,
+                    level.page_cnt,
+                    level.item_cnt,
+                    level.avg_fill() * 100.0,
+                    level.fill_percentile(50.0) * 100.0,
+                    level.fill_percentile(90.0) * 100.0,
+                    level.dead_space,
+                    level.sibling_only_cnt
+                )?;
+            }
+        }
+        ["check"] => {
+            let report = db.check_invariants()?;
+            for issue in report.issues.iter() {
+                writeln!(out, "{}", issue)?;
+            }
+            if report.is_ok() {
+                writeln!(out, "ok ({} pages checked)", report.pages_checked)?;
+            } else {
+                writeln!(out, "{} violations", report.issues.len())?;
+                for remediation in report.remediations() {
+                    writeln!(out, "suggested: {}", remediation)?;
+                }
+            }
+        }
+        ["hot", n @ ..] if n.len() <= 1 => {
+            let n = match n.first() {
+                Some(n) => match n.parse::<usize>() {
+                    Ok(n) => n,
+                    Err(_) => {
+                        writeln!(out, "invalid page count {}", n)?;
+                        return Ok(Flow::Continue);
+                    }
+                },
+                None => 10,
+            };
+            for page in db.hot_pages(n) {
+                writeln!(
+                    out,
+                    "page {}: {} reads, {} writes",
+                    page.page_no, page.reads, page.writes
+                )?;
+            }
+        }
+        ["dump", file] => {
+            let cnt = db.dump(BufWriter::new(File::create(file)?))?;
+            writeln!(out, "dumped {} entries", cnt)?;
+        }
+        ["load", file] => {
+            let cnt = db.load(BufReader::new(File::open(file)?))?;
+            writeln!(out, "loaded {} entries", cnt)?;
+        }
+        ["flush"] => db.flush()?,
+        #[cfg(feature = "sql")]
+        ["sql", ..] => {
+            let statement = line.trim_start()["sql".len()..].trim();
+            write!(out, "{}", johndb::sql::execute(db, statement)?)?;
+        }
+        ["help"] => {
+            writeln!(out, "{}", HELP)?;
+            #[cfg(feature = "sql")]
+            writeln!(out, "{}", SQL_HELP)?;
+        }
+        ["quit"] | ["exit"] => return Ok(Flow::Quit),
+        _ => writeln!(out, "unrecognized command, try `help`")?,
+    }
+
+    Ok(Flow::Continue)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::execute;
+    use super::Flow;
+    use johndb::Database;
+    use johndb::Options;
+
+    fn run(db: &mut Database, line: &str) -> String {
+        let mut out = Vec::new();
+        execute(db, line, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn commands() {
+        let path = std::env::temp_dir().join(format!("johndb-cli-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut db = Database::open(&path, Options::default()).unwrap();
+
+        assert_eq!(run(&mut db, "put b 2"), "");
+        assert_eq!(run(&mut db, "put a 1"), "");
+        assert_eq!(run(&mut db, "put c 3"), "");
+        assert_eq!(run(&mut db, "get a"), "1\n");
+        assert_eq!(run(&mut db, "scan a c"), "a => 1\nb => 2\n(2 entries)\n");
+        assert_eq!(run(&mut db, "del b"), "");
+        assert_eq!(run(&mut db, "get b"), "(not found)\n");
+        assert_eq!(run(&mut db, "scan"), "a => 1\nc => 3\n(2 entries)\n");
+        assert!(run(&mut db, "stats").contains("keys: 2\n"));
+        assert_eq!(run(&mut db, "check"), "ok (1 pages checked)\n");
+        assert_eq!(run(&mut db, "hot").lines().count(), 2);
+        assert!(run(&mut db, "hot 1").ends_with(" writes\n"));
+        assert!(run(&mut db, "bogus").starts_with("unrecognized"));
+        #[cfg(feature = "sql")]
+        {
+            assert_eq!(
+                run(&mut db, "sql CREATE TABLE t (id u32, note text)"),
+                "created\n"
+            );
+            run(&mut db, "sql INSERT INTO t VALUES (1, 'hi there')");
+            assert_eq!(
+                run(&mut db, "sql SELECT note FROM t WHERE id = 1"),
+                "note\nhi there\n(1 rows)\n"
+            );
+        }
+        assert!(matches!(
+            execute(&mut db, "quit", &mut Vec::new()),
+            Ok(Flow::Quit)
+        ));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
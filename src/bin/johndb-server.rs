@@ -0,0 +1,82 @@
+//! Serves a johndb database file over TCP with the protocol described in `johndb::server`, or
+//! with `--resp` over the Redis protocol described in `johndb::net`.
+//!
+//! ```text
+//! $ johndb-server data.db 127.0.0.1:7070
+//! $ johndb-server --resp data.db 127.0.0.1:6379
+//! ```
+//!
+//! Every connection is served on its own thread. The database is flushed every few seconds, so a
+//! crash loses at most the writes since the last flush.
+
+use johndb::net::serve_resp;
+use johndb::server::serve;
+use johndb::Database;
+use johndb::Options;
+use std::net::TcpListener;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+use std::time::Duration;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:7070";
+const DEFAULT_RESP_ADDR: &str = "127.0.0.1:6379";
+const FLUSH_INTERVAL: Duration = Duration::from_secs(5);
+
+fn main() {
+    env_logger::init();
+
+    let mut args = std::env::args().skip(1).collect::<Vec<_>>();
+    let resp = args.first().map(String::as_str) == Some("--resp");
+    if resp {
+        args.remove(0);
+    }
+    let (path, addr) = match (args.as_slice(), resp) {
+        ([path], false) => (path.clone(), DEFAULT_ADDR.to_string()),
+        ([path], true) => (path.clone(), DEFAULT_RESP_ADDR.to_string()),
+        ([path, addr], _) => (path.clone(), addr.clone()),
+        _ => usage(),
+    };
+
+    let db = match Database::open(&path, Options::default()) {
+        Ok(db) => Arc::new(Mutex::new(db)),
+        Err(err) => {
+            eprintln!("failed to open {}: {}", path, err);
+            std::process::exit(1);
+        }
+    };
+    let listener = match TcpListener::bind(&addr) {
+        Ok(listener) => listener,
+        Err(err) => {
+            eprintln!("failed to listen on {}: {}", addr, err);
+            std::process::exit(1);
+        }
+    };
+
+    let flush_db = db.clone();
+    thread::spawn(move || loop {
+        thread::sleep(FLUSH_INTERVAL);
+        let result = flush_db
+            .lock()
+            .map_err(johndb::Error::from)
+            .and_then(|db| db.flush());
+        if let Err(err) = result {
+            log::error!("failed to flush {}: {}", path, err);
+        }
+    });
+
+    log::info!("listening on {}", addr);
+    let result = match resp {
+        true => serve_resp(listener, db),
+        false => serve(listener, db),
+    };
+    if let Err(err) = result {
+        eprintln!("failed to accept connections: {}", err);
+        std::process::exit(1);
+    }
+}
+
+fn usage() -> ! {
+    eprintln!("usage: johndb-server [--resp] <path> [addr]");
+    std::process::exit(2);
+}
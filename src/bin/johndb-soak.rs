@@ -0,0 +1,450 @@
+//! A long-running randomized soak test: mixes puts, deletes, reads, scans, flushes, restarts and
+//! vacuums against a database file for as long as it's told to, checking every result against an
+//! in-memory model of what the database should hold.
+//!
+//! ```text
+//! $ cargo run --release --bin johndb-soak -- --duration 14400
+//! ```
+//!
+//! Operations are drawn from an RNG seeded with `--seed`, so a run can be replayed exactly up to
+//! any operation. On a divergence the soak prints the seed and operation count to replay it with,
+//! and exits with status 1.
+//!
+//! Restarts are either clean, flushing before the file is reopened, or crashes, which drop the
+//! database without flushing, after which it must hold exactly what it did at the last flush.
+//! Every `--check-every` operations the whole database is scanned against the model and checked
+//! with `Database::check`.
+
+use johndb::Database;
+use johndb::Options;
+use std::collections::BTreeMap;
+use std::fmt;
+use std::ops::RangeFull;
+use std::path::Path;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+const USAGE: &str = "\
+usage: johndb-soak [options]
+  --seed <n>                 (default: the current time)
+  --duration <seconds>       how long to run for (default: 3600)
+  --ops <n>                  stop after this many operations, e.g. to replay a divergence
+  --keys <n>                 size of the key space (default: 10000)
+  --max-value-size <bytes>   (default: 20000)
+  --check-every <n>          operations between full checks (default: 10000)
+  --max-pages <n>            (default: 65536)
+  --path <file>              database file (default: a temporary file)";
+
+/// Chance of each kind of operation, out of 1000. Reads make up the rest.
+const PUT: u64 = 400;
+const DELETE: u64 = 150;
+const SCAN: u64 = 170;
+const FLUSH: u64 = 10;
+const VACUUM: u64 = 1;
+const RESTART: u64 = 1;
+const CRASH: u64 = 1;
+
+/// Chance of a value being large enough for overflow pages, out of 1000.
+const LARGE_VALUE: u64 = 50;
+/// Maximum size of the other values.
+const SMALL_VALUE_SIZE: u64 = 200;
+/// Maximum length of a scan.
+const MAX_SCAN_LEN: u64 = 50;
+/// Seconds between progress reports.
+const REPORT_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Debug, Clone)]
+struct Config {
+    seed: u64,
+    duration: Duration,
+    ops: Option<u64>,
+    keys: u64,
+    max_value_size: usize,
+    check_every: u64,
+    max_pages: usize,
+    path: Option<String>,
+}
+
+type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+
+/// Counts of what a run did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+struct Summary {
+    ops: u64,
+    flushes: u64,
+    vacuums: u64,
+    restarts: u64,
+    crashes: u64,
+    checks: u64,
+    entries: usize,
+}
+
+impl fmt::Display for Summary {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} ops, {} entries | {} flushes, {} vacuums, {} restarts, {} crashes, {} full checks",
+            self.ops,
+            self.entries,
+            self.flushes,
+            self.vacuums,
+            self.restarts,
+            self.crashes,
+            self.checks
+        )
+    }
+}
+
+/// The database disagreed with the model, or failed, at operation `op`, counting from 0.
+#[derive(Debug)]
+struct Divergence {
+    op: u64,
+    detail: String,
+}
+
+impl Divergence {
+    fn new<S: Into<String>>(op: u64, detail: S) -> Self {
+        Divergence {
+            op,
+            detail: detail.into(),
+        }
+    }
+}
+
+fn main() {
+    env_logger::init();
+
+    let args = std::env::args().skip(1).collect::<Vec<_>>();
+    let config = match parse_args(&args) {
+        Ok(config) => config,
+        Err(err) => {
+            eprintln!("{}\n{}", err, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let path = match &config.path {
+        Some(path) => path.into(),
+        None => std::env::temp_dir().join(format!("johndb-soak-{}", std::process::id())),
+    };
+    let _ = std::fs::remove_file(&path);
+    println!("seed {}", config.seed);
+
+    let result = run(&config, &path, |elapsed, summary| {
+        println!("{:>8.0}s {}", elapsed.as_secs_f64(), summary)
+    });
+    if config.path.is_none() {
+        let _ = std::fs::remove_file(&path);
+    }
+    match result {
+        Ok(summary) => println!("done: {}", summary),
+        Err(divergence) => {
+            eprintln!(
+                "diverged at op {}: {}\nreplay with: johndb-soak --seed {} --ops {}",
+                divergence.op,
+                divergence.detail,
+                config.seed,
+                divergence.op + 1
+            );
+            std::process::exit(1);
+        }
+    }
+}
+
+fn parse_args(args: &[String]) -> Result<Config, String> {
+    let mut config = Config {
+        seed: SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_nanos() as u64),
+        duration: Duration::from_secs(3600),
+        ops: None,
+        keys: 10000,
+        max_value_size: 20000,
+        check_every: 10000,
+        max_pages: 1 << 16,
+        path: None,
+    };
+
+    let mut args = args.iter();
+    while let Some(flag) = args.next() {
+        let value = args
+            .next()
+            .ok_or_else(|| format!("missing value for {}", flag))?;
+        let number = || {
+            value
+                .parse::<u64>()
+                .map_err(|_| format!("invalid number {} for {}", value, flag))
+        };
+        match flag.as_str() {
+            "--seed" => config.seed = number()?,
+            "--duration" => config.duration = Duration::from_secs(number()?),
+            "--ops" => config.ops = Some(number()?),
+            "--keys" => config.keys = number()?,
+            "--max-value-size" => config.max_value_size = number()? as usize,
+            "--check-every" => config.check_every = number()?,
+            "--max-pages" => config.max_pages = number()? as usize,
+            "--path" => config.path = Some(value.clone()),
+            _ => return Err(format!("unknown option {}", flag)),
+        }
+    }
+
+    if config.keys == 0 || config.check_every == 0 {
+        return Err("--keys and --check-every must be positive".to_string());
+    }
+    Ok(config)
+}
+
+fn open(path: &Path, config: &Config, op: u64) -> Result<Database, Divergence> {
+    let options = Options {
+        create_if_missing: true,
+        max_pages: config.max_pages,
+        ..Options::default()
+    };
+    Database::open(path, options).map_err(|err| Divergence::new(op, format!("open: {}", err)))
+}
+
+/// Runs the soak against a new database at `path` until the configured duration or number of
+/// operations is up, calling `progress` every `REPORT_INTERVAL`.
+fn run<F>(config: &Config, path: &Path, mut progress: F) -> Result<Summary, Divergence>
+where
+    F: FnMut(Duration, &Summary),
+{
+    let start = Instant::now();
+    let mut last_report = start;
+    let mut rng = Rng::new(config.seed);
+    let mut db = open(path, config, 0)?;
+    let mut model = Model::new();
+    // What the database held at the last flush, which a crash goes back to
+    let mut durable = Model::new();
+    let mut summary = Summary::default();
+
+    for op in 0.. {
+        if config.ops.is_some_and(|ops| op >= ops) {
+            break;
+        }
+        if op % 1000 == 0 {
+            let now = Instant::now();
+            if now - start >= config.duration {
+                break;
+            }
+            if now - last_report >= REPORT_INTERVAL {
+                summary.entries = model.len();
+                progress(now - start, &summary);
+                last_report = now;
+            }
+        }
+        summary.ops += 1;
+
+        let failed = |what: &str| {
+            let what = what.to_string();
+            move |err: johndb::Error| Divergence::new(op, format!("{}: {}", what, err))
+        };
+        let key = format!("key{:08}", rng.next_u64() % config.keys).into_bytes();
+        let mut roll = rng.next_u64() % 1000;
+        let mut next = |chance: u64| match roll < chance {
+            true => true,
+            false => {
+                roll -= chance;
+                false
+            }
+        };
+
+        if next(PUT) {
+            let len = match rng.next_u64() % 1000 < LARGE_VALUE {
+                true => rng.next_u64() % (config.max_value_size as u64 + 1),
+                false => rng.next_u64() % (SMALL_VALUE_SIZE + 1),
+            };
+            let value = value(rng.next_u64(), len as usize);
+            db.put(&key, &value).map_err(failed("put"))?;
+            model.insert(key, value);
+        } else if next(DELETE) {
+            let deleted = db.delete(&key).map_err(failed("delete"))?;
+            let expected = model.remove(&key);
+            if deleted != expected {
+                return Err(Divergence::new(
+                    op,
+                    format!(
+                        "delete {} returned {}, expected {}",
+                        show(&key),
+                        describe(&deleted),
+                        describe(&expected)
+                    ),
+                ));
+            }
+        } else if next(SCAN) {
+            let len = 1 + rng.next_u64() % MAX_SCAN_LEN;
+            let found = db
+                .range(&key[..]..)
+                .map_err(failed("scan"))?
+                .take(len as usize)
+                .collect::<Result<Vec<_>, _>>()
+                .map_err(failed("scan"))?;
+            let expected = model.range(key.clone()..).take(len as usize);
+            compare_entries(op, found.into_iter(), expected)?;
+        } else if next(FLUSH) {
+            db.flush().map_err(failed("flush"))?;
+            durable = model.clone();
+            summary.flushes += 1;
+        } else if next(VACUUM) {
+            db.vacuum().map_err(failed("vacuum"))?;
+            summary.vacuums += 1;
+        } else if next(RESTART) {
+            db.close().map_err(failed("close"))?;
+            db = open(path, config, op)?;
+            durable = model.clone();
+            summary.restarts += 1;
+            check_all(op, &db, &model)?;
+        } else if next(CRASH) {
+            drop(db);
+            db = open(path, config, op)?;
+            model = durable.clone();
+            summary.crashes += 1;
+            check_all(op, &db, &model)?;
+        } else {
+            let found = db.get(&key).map_err(failed("get"))?;
+            let expected = model.get(&key);
+            if found.as_ref() != expected {
+                return Err(Divergence::new(
+                    op,
+                    format!(
+                        "get {} returned {}, expected {}",
+                        show(&key),
+                        describe(&found),
+                        describe(&expected.cloned())
+                    ),
+                ));
+            }
+        }
+
+        if op % config.check_every == config.check_every - 1 {
+            check_all(op, &db, &model)?;
+            summary.checks += 1;
+        }
+    }
+
+    summary.entries = model.len();
+    Ok(summary)
+}
+
+/// Checks the database's structure and that it holds exactly the model's entries.
+fn check_all(op: u64, db: &Database, model: &Model) -> Result<(), Divergence> {
+    let failed = |err: johndb::Error| Divergence::new(op, format!("check: {}", err));
+    let report = db.check().map_err(failed)?;
+    if !report.is_ok() {
+        return Err(Divergence::new(
+            op,
+            format!("check found {:?}", report.violations),
+        ));
+    }
+    let found = db
+        .range::<RangeFull>(..)
+        .map_err(failed)?
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(failed)?;
+    compare_entries(op, found.into_iter(), model.iter())
+}
+
+fn compare_entries<'a, F, E>(op: u64, mut found: F, mut expected: E) -> Result<(), Divergence>
+where
+    F: Iterator<Item = (Vec<u8>, Vec<u8>)>,
+    E: Iterator<Item = (&'a Vec<u8>, &'a Vec<u8>)>,
+{
+    loop {
+        match (found.next(), expected.next()) {
+            (None, None) => return Ok(()),
+            (Some((key, value)), Some((expected_key, expected_value)))
+                if key == *expected_key && value == *expected_value => {}
+            (found, expected) => {
+                let describe_entry = |entry: Option<(&[u8], &[u8])>| match entry {
+                    Some((key, value)) => format!("{} ({} bytes)", show(key), value.len()),
+                    None => "the end".to_string(),
+                };
+                return Err(Divergence::new(
+                    op,
+                    format!(
+                        "scan found {}, expected {}",
+                        describe_entry(found.as_ref().map(|(k, v)| (&k[..], &v[..]))),
+                        describe_entry(expected.map(|(k, v)| (&k[..], &v[..])))
+                    ),
+                ));
+            }
+        }
+    }
+}
+
+fn show(key: &[u8]) -> String {
+    String::from_utf8_lossy(key).into_owned()
+}
+
+fn describe(value: &Option<Vec<u8>>) -> String {
+    match value {
+        Some(value) => format!("{} bytes", value.len()),
+        None => "nothing".to_string(),
+    }
+}
+
+fn value(seed: u64, size: usize) -> Vec<u8> {
+    (0..size)
+        .map(|i| b'a' + ((seed as usize).wrapping_add(i) % 26) as u8)
+        .collect()
+}
+
+/// splitmix64, so that runs replay the same on every platform.
+struct Rng {
+    state: u64,
+}
+
+impl Rng {
+    fn new(seed: u64) -> Self {
+        Rng { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+        let mut x = self.state;
+        x = (x ^ (x >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+        x = (x ^ (x >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+        x ^ (x >> 31)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::parse_args;
+    use super::run;
+
+    #[test]
+    fn short_runs_replay() {
+        let args = [
+            "--seed",
+            "7",
+            "--ops",
+            "20000",
+            "--keys",
+            "300",
+            "--check-every",
+            "2000",
+        ]
+        .iter()
+        .map(|arg| arg.to_string())
+        .collect::<Vec<_>>();
+        let config = parse_args(&args).unwrap();
+        assert!(parse_args(&["--keys".to_string(), "0".to_string()]).is_err());
+
+        let path = std::env::temp_dir().join(format!("johndb-soak-test-{}", std::process::id()));
+        let mut summaries = Vec::new();
+        for _ in 0..2 {
+            let _ = std::fs::remove_file(&path);
+            summaries.push(run(&config, &path, |_, _| {}).unwrap());
+        }
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(summaries[0], summaries[1]);
+        let summary = &summaries[0];
+        assert_eq!(summary.ops, 20000);
+        assert_eq!(summary.checks, 10);
+        assert!(summary.flushes > 0 && summary.entries > 0, "{}", summary);
+    }
+}
@@ -0,0 +1,122 @@
+use super::key::Key;
+use super::value::Value;
+use crate::error::Result;
+use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use std::ops::RangeBounds;
+
+/// An aggregate function computed by `BTree::aggregate`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AggFn {
+    /// The number of entries a value was extracted from.
+    Count,
+    Sum,
+    Min,
+    Max,
+}
+
+/// The running state of an `AggFn` over a sequence of values.
+pub(super) struct Accumulator {
+    agg: AggFn,
+    cnt: i128,
+    acc: Option<i128>,
+}
+
+impl Accumulator {
+    pub(super) fn new(agg: AggFn) -> Self {
+        Self {
+            agg,
+            cnt: 0,
+            acc: None,
+        }
+    }
+
+    pub(super) fn add(&mut self, value: i64) {
+        let value = value as i128;
+        self.cnt += 1;
+        self.acc = Some(match (self.agg, self.acc) {
+            (_, None) => value,
+            (AggFn::Count, Some(acc)) => acc,
+            (AggFn::Sum, Some(acc)) => acc + value,
+            (AggFn::Min, Some(acc)) => acc.min(value),
+            (AggFn::Max, Some(acc)) => acc.max(value),
+        });
+    }
+
+    pub(super) fn finish(&self) -> Option<i128> {
+        match self.agg {
+            AggFn::Count => Some(self.cnt),
+            _ => self.acc,
+        }
+    }
+}
+
+impl<K, V, PageFetcher> super::BTree<K, V, PageFetcher>
+where
+    K: Key,
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Computes `agg` over the values `extract` returns for the entries within `range`. Like
+    /// SQL's NULLs, entries for which `extract` returns `None` are left out, and `Sum`, `Min` and
+    /// `Max` return `None` if there are no values at all, while `Count` returns 0.
+    ///
+    /// `extract` is called with each key and the value's encoded bytes while the leaf is read
+    /// locked, see `range_visit`, so nothing is copied out of the pages.
+    pub fn aggregate<R, F>(&self, range: R, agg: AggFn, mut extract: F) -> Result<Option<i128>>
+    where
+        R: RangeBounds<K>,
+        F: FnMut(&K, &[u8]) -> Option<i64>,
+    {
+        let mut acc = Accumulator::new(agg);
+        self.range_visit(range, |entry| {
+            if let Some(value) = extract(entry.key(), entry.value_bytes()) {
+                acc.add(value);
+            }
+            true
+        })?;
+        Ok(acc.finish())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::AggFn;
+    use crate::btree::key::KeyU32;
+    use crate::btree::value::ValueBytes;
+    use crate::btree::BTree;
+    use crate::page_fetcher::InMemoryPageFetcher;
+    use std::convert::TryInto;
+
+    #[test]
+    fn aggregates_over_range() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(64)).unwrap();
+        for key in 0..2000u32 {
+            let value = (key as i64 - 1000).to_be_bytes().to_vec();
+            btree.insert(KeyU32 { key }, ValueBytes { value }).unwrap();
+        }
+        // Odd keys stand in for NULLs
+        let extract = |key: &KeyU32, value: &[u8]| {
+            Some(i64::from_be_bytes(value.try_into().unwrap()))
+                .filter(|_| key.key.is_multiple_of(2))
+        };
+        let range = KeyU32 { key: 500 }..KeyU32 { key: 1500 };
+
+        let agg = |agg| btree.aggregate(range.clone(), agg, extract).unwrap();
+        assert_eq!(agg(AggFn::Count), Some(500));
+        assert_eq!(
+            agg(AggFn::Sum),
+            Some((500..1500).step_by(2).map(|key| key - 1000).sum::<i128>())
+        );
+        assert_eq!(agg(AggFn::Min), Some(-500));
+        assert_eq!(agg(AggFn::Max), Some(498));
+
+        let empty = KeyU32 { key: 3000 }..;
+        assert_eq!(
+            btree
+                .aggregate(empty.clone(), AggFn::Count, extract)
+                .unwrap(),
+            Some(0)
+        );
+        assert_eq!(btree.aggregate(empty, AggFn::Max, extract).unwrap(), None);
+    }
+}
@@ -0,0 +1,267 @@
+use super::internal_node::InternalNodeItemData;
+use super::key::Key;
+use super::metadata_node::MetadataRead;
+use super::node::check_node_items;
+use super::value::Value;
+use super::BTreePageData;
+use super::NodeType;
+use crate::error::Error;
+use crate::error::Result;
+use crate::page::ITEM_POINTER_SIZE;
+use crate::page::PAGE_DATA_SIZE;
+use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use std::collections::HashSet;
+
+/// Shape and space usage of a tree, as computed by `BTree::analyze`.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct TreeStats {
+    /// One entry per level, starting with the root's.
+    pub levels: Vec<LevelStats>,
+}
+
+impl TreeStats {
+    pub fn height(&self) -> usize {
+        self.levels.len()
+    }
+
+    /// Entries stored in the leaves.
+    pub fn entry_cnt(&self) -> usize {
+        self.levels.last().map_or(0, |level| level.item_cnt)
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct LevelStats {
+    pub page_cnt: usize,
+    /// Items stored on the level's pages, not counting their separators.
+    pub item_cnt: usize,
+    /// Bytes left behind by removed items, summed over the level's pages.
+    pub dead_space: usize,
+    /// Pages that can only be reached through their left sibling's right sibling link, because
+    /// the level above has no downlink to them. Anything but 0 points at an incomplete split.
+    pub sibling_only_cnt: usize,
+    /// The fraction of each page's usable space taken up by live items and their pointers,
+    /// sorted in ascending order.
+    pub fill_factors: Vec<f64>,
+}
+
+impl LevelStats {
+    pub fn avg_fill(&self) -> f64 {
+        if self.fill_factors.is_empty() {
+            return 0.0;
+        }
+        self.fill_factors.iter().sum::<f64>() / self.fill_factors.len() as f64
+    }
+
+    /// The fill factor below which `percentile` percent of the level's pages fall, using the
+    /// nearest-rank method.
+    pub fn fill_percentile(&self, percentile: f64) -> f64 {
+        if self.fill_factors.is_empty() {
+            return 0.0;
+        }
+        let rank = (percentile / 100.0 * self.fill_factors.len() as f64).ceil() as usize;
+        self.fill_factors[rank.clamp(1, self.fill_factors.len()) - 1]
+    }
+}
+
+impl<K, V, PageFetcher> super::BTree<K, V, PageFetcher>
+where
+    K: Key,
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Walks every level of the tree left to right through the right sibling links, starting
+    /// from the leftmost child of the level above, and gathers per-level statistics.
+    ///
+    /// Pages are read-locked one at a time, so concurrent modifications may skew the numbers.
+    pub fn analyze(&self) -> Result<TreeStats> {
+        let metadata = self.metadata_read()?;
+        let mut next_level = metadata.root_no()?.map(|root_no| {
+            let mut downlinks = HashSet::new();
+            downlinks.insert(root_no);
+            (root_no, downlinks)
+        });
+        drop(metadata);
+
+        let mut stats = TreeStats::default();
+        while let Some((leftmost_no, downlinks)) = next_level.take() {
+            let (level, below) = self.analyze_level(leftmost_no, &downlinks)?;
+            stats.levels.push(level);
+            next_level = below;
+        }
+
+        Ok(stats)
+    }
+
+    /// Gathers the statistics of the level starting at `leftmost_no`, whose pages the level
+    /// above links to through `downlinks`. Returns the leftmost page and downlinks of the level
+    /// below, if there is one.
+    #[allow(clippy::type_complexity)]
+    fn analyze_level(
+        &self,
+        leftmost_no: u32,
+        downlinks: &HashSet<u32>,
+    ) -> Result<(LevelStats, Option<(u32, HashSet<u32>)>)> {
+        let mut level = LevelStats::default();
+        let mut visited = HashSet::new();
+        let mut child_downlinks = HashSet::new();
+        let mut leftmost_child: Option<InternalNodeItemData<K>> = None;
+
+        let mut next_page_no = Some(leftmost_no);
+        while let Some(page_no) = next_page_no {
+            if !visited.insert(page_no) {
+                return Err(Error::page_corruption(
+                    page_no,
+                    "right sibling links loop back to the page",
+                ));
+            }
+
+            let page = self.page_fetcher.fetch_page_read(page_no)?;
+            page.check_layout()
+                .and_then(|_| BTreePageData::check(&page))
+                .map_err(|err| err.on_page(page_no))?;
+            let special_data = page.special_data::<BTreePageData>();
+
+            let item_cnt = page.item_cnt().saturating_sub(1);
+            let usable = PAGE_DATA_SIZE - page.header.special_size();
+            let used =
+                page.item_cnt() * ITEM_POINTER_SIZE + page.item_data_size() - page.dead_space();
+            level.page_cnt += 1;
+            level.item_cnt += item_cnt;
+            level.dead_space += page.dead_space();
+            level.fill_factors.push(used as f64 / usable as f64);
+            if !downlinks.contains(&page_no) {
+                level.sibling_only_cnt += 1;
+            }
+
+            match special_data.node_type {
+                NodeType::Leaf => {}
+                NodeType::Internal => {
+                    check_node_items::<InternalNodeItemData<K>>(&page)
+                        .map_err(|err| err.on_page(page_no))?;
+                    for downlink in page.items_iter_from_v2::<InternalNodeItemData<K>>(1) {
+                        child_downlinks.insert(downlink.page_no);
+                        if page_no == leftmost_no
+                            && leftmost_child
+                                .as_ref()
+                                .is_none_or(|child| downlink.key < child.key)
+                        {
+                            leftmost_child = Some(downlink);
+                        }
+                    }
+                }
+                ref other => {
+                    return Err(Error::page_corruption(
+                        page_no,
+                        format!("expected a tree node, found {:?}", other),
+                    ))
+                }
+            }
+
+            next_page_no = special_data.right_sibling().get();
+        }
+
+        level
+            .fill_factors
+            .sort_by(|x, y| x.partial_cmp(y).expect("fill factors are never NaN"));
+        let below = leftmost_child.map(|child| (child.page_no, child_downlinks));
+        Ok((level, below))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::LevelStats;
+    use crate::btree::internal_node::InternalNodeItemData;
+    use crate::btree::key::KeyU32;
+    use crate::btree::metadata_node::MetadataRead;
+    use crate::btree::value::ValueTupleId;
+    use crate::btree::BTree;
+    use crate::page_fetcher::InMemoryPageFetcher;
+    use crate::page_fetcher::PageFetcher;
+
+    #[test]
+    fn tree_shape() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(64)).unwrap();
+        assert_eq!(btree.analyze().unwrap().height(), 0);
+
+        for i in 0..3000u32 {
+            let key = (i * 7919) % 3000;
+            btree
+                .insert(
+                    KeyU32 { key },
+                    ValueTupleId {
+                        page_no: key,
+                        offset: 0,
+                    },
+                )
+                .unwrap();
+        }
+        for key in 0..100u32 {
+            btree.delete(KeyU32 { key }).unwrap();
+        }
+
+        let stats = btree.analyze().unwrap();
+        assert_eq!(stats.height(), 2);
+        assert_eq!(stats.entry_cnt(), 2900);
+
+        let (root, leaves) = (&stats.levels[0], &stats.levels[1]);
+        assert_eq!(root.page_cnt, 1);
+        assert_eq!(root.item_cnt, leaves.page_cnt);
+        assert_eq!(
+            root.page_cnt + leaves.page_cnt,
+            btree.page_fetcher.page_cnt() - 1
+        );
+        assert!(leaves.dead_space > 0);
+        assert_eq!(leaves.sibling_only_cnt, 0);
+
+        let avg = leaves.avg_fill();
+        assert!(0.3 < avg && avg <= 1.0, "{}", avg);
+        assert!(leaves.fill_percentile(0.0) <= leaves.fill_percentile(50.0));
+        assert!(leaves.fill_percentile(50.0) <= leaves.fill_percentile(100.0));
+    }
+
+    #[test]
+    fn sibling_only_pages() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(64)).unwrap();
+        for key in 0..1000u32 {
+            btree
+                .insert(
+                    KeyU32 { key },
+                    ValueTupleId {
+                        page_no: key,
+                        offset: 0,
+                    },
+                )
+                .unwrap();
+        }
+
+        // Drop the root's downlink to its second child, as if a split never finished
+        let root_no = btree.metadata_read().unwrap().root_no().unwrap().unwrap();
+        {
+            let mut root = btree.page_fetcher.fetch_page_write(root_no).unwrap();
+            let mut downlinks = root
+                .items_iter_from_v2::<InternalNodeItemData<KeyU32>>(1)
+                .enumerate()
+                .collect::<Vec<_>>();
+            downlinks.sort_by_key(|(_, downlink)| downlink.key);
+            root.remove_item_v2(downlinks[1].0 + 1);
+        }
+
+        let stats = btree.analyze().unwrap();
+        assert_eq!(stats.levels[1].sibling_only_cnt, 1);
+        assert_eq!(stats.entry_cnt(), 1000);
+    }
+
+    #[test]
+    fn percentiles() {
+        let level = LevelStats {
+            fill_factors: vec![0.1, 0.2, 0.3, 0.4],
+            ..LevelStats::default()
+        };
+        assert_eq!(level.fill_percentile(50.0), 0.2);
+        assert_eq!(level.fill_percentile(90.0), 0.4);
+        assert_eq!(level.fill_percentile(0.0), 0.1);
+        assert!((level.avg_fill() - 0.25).abs() < 1e-9);
+    }
+}
@@ -0,0 +1,285 @@
+use super::internal_node::InternalNodeItemData;
+use super::key::Key;
+use super::leaf_node::LeafNodeItemData;
+use super::metadata_node::MetadataRead;
+use super::node::NodeItem;
+use super::node::NodeWrite;
+use super::node::Separator;
+use super::value::Value;
+use super::BTreePageData;
+use super::NodeType;
+use crate::error::Error;
+use crate::error::Result;
+use crate::page::Page;
+use crate::page::PageNo;
+use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use log::debug;
+use std::collections::VecDeque;
+use std::mem::size_of;
+
+impl<K, V, PageFetcher> super::BTree<K, V, PageFetcher>
+where
+    K: Key,
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Loads `entries`, which must be in strictly ascending key order, into the empty tree by
+    /// building its leaves and then each level of internal nodes bottom up, rather than inserting
+    /// the entries one by one. Nodes are packed as full as they go, so the tree is as small as it
+    /// can be but the first inserts into it split. Returns the number of entries loaded.
+    ///
+    /// Fails with `Error::NotEmpty` if the tree already has entries. Pages written before an
+    /// error aren't reachable from the tree, and aren't returned to the free list.
+    pub fn bulk_load<I>(&self, entries: I) -> Result<u64>
+    where
+        I: IntoIterator<Item = Result<(K, V)>>,
+    {
+        let old_root_no = self.metadata_read()?.root_no()?;
+        if let Some(root_no) = old_root_no {
+            self.check_empty_root(root_no)?;
+        }
+
+        let mut prev_key: Option<K> = None;
+        let mut cnt = 0;
+        let items = entries.into_iter().map(|entry| {
+            let (key, value) = entry?;
+            if prev_key.as_ref().is_some_and(|prev_key| *prev_key >= key) {
+                return Err(Error::corruption(format!(
+                    "bulk loaded key {:?} is out of order",
+                    key
+                )));
+            }
+            prev_key = Some(key.clone());
+            cnt += 1;
+            let value = self.store_value(value)?;
+            Ok(LeafNodeItemData { key, value })
+        });
+        let mut downlinks = build_level(
+            &self.page_fetcher,
+            items,
+            |page_fetcher| super::leaf_node::new_page::<_, K, V>(page_fetcher, PageNo::INVALID),
+            true,
+        )?;
+        while downlinks.len() > 1 {
+            downlinks = build_level(
+                &self.page_fetcher,
+                downlinks.into_iter().map(Ok),
+                |page_fetcher| {
+                    super::internal_node::new_page::<_, K>(page_fetcher, PageNo::INVALID)
+                },
+                false,
+            )?;
+        }
+
+        let root_no = match downlinks.pop() {
+            Some(root) => root.page_no,
+            None => return Ok(0),
+        };
+        let mut metadata = self.metadata_write()?;
+        if metadata.root_no()? != old_root_no {
+            return Err(Error::NotEmpty);
+        }
+        metadata.set_root_no(root_no)?;
+        debug!("[bulk_load] Loaded {} entries under root {}", cnt, root_no);
+        self.notify(|listener| listener.on_new_root(root_no));
+        Ok(cnt)
+    }
+
+    /// Fails with `Error::NotEmpty` unless `root_no` is a leaf without entries, as left behind by
+    /// deleting every entry.
+    fn check_empty_root(&self, root_no: u32) -> Result<()> {
+        let root = self.page_fetcher.fetch_page_read(root_no)?;
+        match BTreePageData::checked_node_type(root_no, &root)? {
+            NodeType::Leaf if root.item_cnt() <= 1 => Ok(()),
+            _ => Err(Error::NotEmpty),
+        }
+    }
+}
+
+/// Builds one level of the tree from `items`, in ascending key order, packing as many into each
+/// node as fit and linking the nodes through their right siblings. A leaf's separator is the key
+/// of the first item of the next leaf, while an internal node's is the key of its last downlink.
+/// The last node of the level is unbounded. Returns a downlink to each node, from left to right.
+fn build_level<'a, P, I, N, F>(
+    page_fetcher: &'a P,
+    mut items: impl Iterator<Item = Result<I>>,
+    new_node: F,
+    is_leaf: bool,
+) -> Result<Vec<InternalNodeItemData<I::Key>>>
+where
+    P: PageFetcherTrait,
+    I: NodeItem,
+    N: NodeWrite<I>,
+    F: Fn(&'a P) -> Result<(u32, N)>,
+{
+    let mut pending = VecDeque::new();
+    let mut downlinks = Vec::new();
+    let mut prev: Option<N> = None;
+    loop {
+        // Find how many of the next items fit alongside the smallest possible separator...
+        let mut scratch = new_scratch_page();
+        scratch.add_item_v2(&I::Key::max_key())?;
+        let mut cnt = 0;
+        loop {
+            if cnt == pending.len() {
+                match items.next() {
+                    Some(item) => pending.push_back(item?),
+                    None => break,
+                }
+            }
+            if scratch.add_item_v2(&pending[cnt]).is_err() {
+                break;
+            }
+            cnt += 1;
+        }
+        if pending.is_empty() {
+            break;
+        }
+
+        // ...then leave items out until they fit alongside the actual one
+        let separator = loop {
+            if cnt == 0 {
+                return Err(Error::ItemTooLarge(pending[0].size()));
+            }
+            // All the remaining items fit, so this is the level's last node
+            let separator = match cnt == pending.len() {
+                true => Separator::Unbounded,
+                false if is_leaf => Separator::Key(pending[cnt].key().clone()),
+                false => Separator::Key(pending[cnt - 1].key().clone()),
+            };
+            let mut scratch = new_scratch_page();
+            if scratch.add_item_v2(&separator.stored_key()).is_ok()
+                && pending
+                    .range(..cnt)
+                    .all(|item| scratch.add_item_v2(item).is_ok())
+            {
+                break separator;
+            }
+            cnt -= 1;
+        };
+
+        let (page_no, mut node) = new_node(page_fetcher)?;
+        node.set_separator(&separator)?;
+        for item in pending.drain(..cnt) {
+            node.add_item(&item)?;
+        }
+        if let Some(mut prev) = prev.take() {
+            prev.set_right_sibling_no(page_no);
+        }
+        downlinks.push(InternalNodeItemData {
+            key: separator.stored_key(),
+            page_no,
+        });
+        prev = Some(node);
+    }
+
+    Ok(downlinks)
+}
+
+fn new_scratch_page() -> Page {
+    Page::new(size_of::<BTreePageData>() as u32)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::btree::key::Key;
+    use crate::btree::key::KeyBytes;
+    use crate::btree::key::KeyU32;
+    use crate::btree::value::ValueBytes;
+    use crate::btree::value::ValueTupleId;
+    use crate::btree::BTree;
+    use crate::error::Error;
+    use crate::page_fetcher::InMemoryPageFetcher;
+
+    fn tuple_id(page_no: u32) -> ValueTupleId {
+        ValueTupleId { page_no, offset: 1 }
+    }
+
+    #[test]
+    fn bulk_load() {
+        let btree = BTree::new(InMemoryPageFetcher::with_capacity(256)).unwrap();
+        let entries = (0..20000u32).map(|i| Ok((KeyU32 { key: i * 2 }, tuple_id(i))));
+        assert_eq!(btree.bulk_load(entries).unwrap(), 20000);
+        assert!(btree.verify().unwrap().is_ok());
+        assert_eq!(btree.analyze().unwrap().entry_cnt(), 20000);
+        assert_eq!(
+            btree.search(KeyU32 { key: 500 }).unwrap().value,
+            Some(tuple_id(250))
+        );
+        assert_eq!(btree.search(KeyU32 { key: 501 }).unwrap().value, None);
+        assert!(matches!(
+            btree.bulk_load(vec![Ok((KeyU32 { key: 1 }, tuple_id(0)))]),
+            Err(Error::NotEmpty)
+        ));
+
+        // The loaded tree takes inserts like any other
+        let mut btree = btree;
+        for i in 0..1000u32 {
+            btree
+                .insert(KeyU32 { key: i * 2 + 1 }, tuple_id(0))
+                .unwrap();
+        }
+        assert!(btree.verify().unwrap().is_ok());
+        assert_eq!(btree.analyze().unwrap().entry_cnt(), 21000);
+    }
+
+    #[test]
+    fn bulk_load_max_key() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(256)).unwrap();
+        let entries = (0..5000u32)
+            .map(|i| KeyU32 { key: i })
+            .chain(std::iter::once(KeyU32::max_key()))
+            .map(|key| Ok((key, tuple_id(key.key))));
+        assert_eq!(btree.bulk_load(entries).unwrap(), 5001);
+        assert!(btree.verify().unwrap().is_ok());
+        assert_eq!(
+            btree.search(KeyU32::max_key()).unwrap().value,
+            Some(tuple_id(u32::MAX))
+        );
+
+        btree
+            .insert(KeyU32 { key: u32::MAX - 1 }, tuple_id(0))
+            .unwrap();
+        assert!(btree.verify().unwrap().is_ok());
+        assert_eq!(btree.analyze().unwrap().entry_cnt(), 5002);
+    }
+
+    #[test]
+    fn bulk_load_dynamic_size() {
+        let btree = BTree::new(InMemoryPageFetcher::with_capacity(256)).unwrap();
+        let entries = (0..5000u32)
+            .map(|i| {
+                let key = KeyBytes {
+                    key: format!("key-{:0width$}", i, width = (i % 50) as usize + 5).into_bytes(),
+                };
+                let value = ValueBytes {
+                    value: vec![i as u8; (i % 300) as usize],
+                };
+                (key, value)
+            })
+            .collect::<Vec<_>>();
+        let mut sorted = entries.clone();
+        sorted.sort();
+        assert_eq!(btree.bulk_load(sorted.into_iter().map(Ok)).unwrap(), 5000);
+        assert!(btree.verify().unwrap().is_ok());
+        for (key, value) in entries {
+            assert_eq!(btree.search(key).unwrap().value, Some(value));
+        }
+
+        let btree = BTree::<KeyBytes, ValueBytes, _>::new(InMemoryPageFetcher::new()).unwrap();
+        let unsorted = vec![
+            Ok((
+                KeyBytes { key: b"b".to_vec() },
+                ValueBytes { value: vec![] },
+            )),
+            Ok((
+                KeyBytes { key: b"a".to_vec() },
+                ValueBytes { value: vec![] },
+            )),
+        ];
+        assert!(matches!(
+            btree.bulk_load(unsorted),
+            Err(Error::Corruption { .. })
+        ));
+    }
+}
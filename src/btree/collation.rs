@@ -0,0 +1,156 @@
+//! Orderings for `KeyString`. A tree's collation is recorded in its metadata page when it's
+//! created and checked when it's opened, since traversing a tree with another ordering than the
+//! one it was built with would silently miss keys.
+//!
+//! Strings a collation considers equal are the same key, e.g. `"Foo"` and `"foo"` under
+//! `CaseInsensitive`.
+
+use std::borrow::Cow;
+use std::cmp::Ordering;
+#[cfg(feature = "icu")]
+use std::collections::HashMap;
+#[cfg(feature = "icu")]
+use std::marker::PhantomData;
+
+/// Byte-wise order, which is also `String`'s. Trees whose metadata doesn't record a collation were
+/// built with it.
+pub const BINARY_COLLATION: &str = "binary";
+
+pub trait Collation: 'static {
+    /// Identifies the collation in a tree's metadata. Must change whenever the ordering does.
+    fn id() -> Cow<'static, str>;
+
+    fn compare(a: &str, b: &str) -> Ordering;
+
+    /// A string at least as large as any other under the collation, see `Key::max_key`.
+    fn max_key() -> String {
+        char::MAX.to_string()
+    }
+}
+
+/// Compares strings byte-wise.
+pub struct Binary;
+
+impl Collation for Binary {
+    fn id() -> Cow<'static, str> {
+        Cow::Borrowed(BINARY_COLLATION)
+    }
+
+    fn compare(a: &str, b: &str) -> Ordering {
+        a.cmp(b)
+    }
+}
+
+/// Compares the strings' lowercase forms byte-wise, see `char::to_lowercase`.
+pub struct CaseInsensitive;
+
+impl Collation for CaseInsensitive {
+    fn id() -> Cow<'static, str> {
+        Cow::Borrowed("case-insensitive")
+    }
+
+    fn compare(a: &str, b: &str) -> Ordering {
+        a.chars()
+            .flat_map(char::to_lowercase)
+            .cmp(b.chars().flat_map(char::to_lowercase))
+    }
+}
+
+/// The locale of an `Icu` collation, as a BCP 47 tag, e.g. `"de"` or `"sv-u-co-trad"`.
+#[cfg(feature = "icu")]
+pub trait IcuLocale: 'static {
+    const LOCALE: &'static str;
+}
+
+/// ICU's root locale, i.e. the Unicode Collation Algorithm's default ordering.
+#[cfg(feature = "icu")]
+pub struct Root;
+
+#[cfg(feature = "icu")]
+impl IcuLocale for Root {
+    const LOCALE: &'static str = "und";
+}
+
+/// Compares strings with ICU's collator for locale `L`, with its default options.
+///
+/// Collation data is compiled in, so a tree's ordering can change with the ICU version. Trees
+/// record the collation's locale, not the ICU version, so they need rebuilding after an upgrade
+/// that changes the locale's ordering.
+#[cfg(feature = "icu")]
+pub struct Icu<L> {
+    phantom: PhantomData<L>,
+}
+
+#[cfg(feature = "icu")]
+thread_local! {
+    /// Collators are expensive to create, so each thread keeps one per locale.
+    static COLLATORS: std::cell::RefCell<HashMap<&'static str, icu_collator::Collator>> =
+        std::cell::RefCell::new(HashMap::new());
+}
+
+#[cfg(feature = "icu")]
+impl<L: IcuLocale> Collation for Icu<L> {
+    fn id() -> Cow<'static, str> {
+        Cow::Owned(format!("icu:{}", L::LOCALE))
+    }
+
+    fn compare(a: &str, b: &str) -> Ordering {
+        COLLATORS.with(|collators| {
+            let mut collators = collators.borrow_mut();
+            let collator = collators.entry(L::LOCALE).or_insert_with(|| {
+                let locale = L::LOCALE
+                    .parse()
+                    .unwrap_or_else(|err| panic!("invalid locale {:?}: {}", L::LOCALE, err));
+                icu_collator::Collator::try_new(&locale, Default::default())
+                    .unwrap_or_else(|err| panic!("no collator for {:?}: {}", L::LOCALE, err))
+            });
+            collator.compare(a, b)
+        })
+    }
+
+    /// U+FFFF sorts above every other character in the root collation, which other locales
+    /// tailor but don't move.
+    fn max_key() -> String {
+        '\u{FFFF}'.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Binary;
+    use super::CaseInsensitive;
+    use super::Collation;
+    use std::cmp::Ordering;
+
+    #[test]
+    fn case_insensitive() {
+        assert_eq!(CaseInsensitive::compare("Foo", "foo"), Ordering::Equal);
+        assert_eq!(CaseInsensitive::compare("apple", "Banana"), Ordering::Less);
+        assert_eq!(Binary::compare("apple", "Banana"), Ordering::Greater);
+        assert_eq!(CaseInsensitive::compare("ÉCOLE", "école"), Ordering::Equal);
+    }
+
+    #[test]
+    #[cfg(feature = "icu")]
+    fn icu() {
+        use super::Icu;
+        use super::IcuLocale;
+        use super::Root;
+
+        struct Swedish;
+        impl IcuLocale for Swedish {
+            const LOCALE: &'static str = "sv";
+        }
+
+        // Accented letters sort next to their base letter rather than after "z"
+        assert_eq!(Icu::<Root>::compare("été", "ezra"), Ordering::Less);
+        assert_eq!(Binary::compare("été", "ezra"), Ordering::Greater);
+        // Swedish sorts "ö" after "z"
+        assert_eq!(Icu::<Root>::compare("öl", "zebra"), Ordering::Less);
+        assert_eq!(Icu::<Swedish>::compare("öl", "zebra"), Ordering::Greater);
+        assert_eq!(
+            Icu::<Swedish>::compare("zebra", &Icu::<Swedish>::max_key()),
+            Ordering::Less
+        );
+    }
+}
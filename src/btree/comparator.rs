@@ -0,0 +1,276 @@
+//! User-defined orderings for byte string keys, for domain-specific encodings that don't sort
+//! byte-wise, e.g. version numbers. Implement `Comparator` and key the tree with `KeyCustom`,
+//! rather than wrapping every key in a type that re-encodes it.
+//!
+//! Like a `Collation`, a tree's comparator is recorded by name in its metadata page when the tree
+//! is created and checked when it's opened, see `Key::collation`.
+
+use super::key::Key;
+use crate::page::Item;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+
+pub trait Comparator: 'static {
+    /// Identifies the comparator in a tree's metadata. Must change whenever the ordering does.
+    const NAME: &'static str;
+
+    /// Must be a total order. Keys it considers equal are the same key.
+    fn compare(a: &[u8], b: &[u8]) -> Ordering;
+
+    /// A key at least as large as any other, see `Key::max_key`.
+    fn max_key() -> Vec<u8>;
+}
+
+/// Compares runs of ASCII digits by their numeric value and everything else byte-wise, so
+/// `"file2" < "file10"` and `"1.9.3" < "1.10.0"`. Numbers that differ only in leading zeros fall
+/// back to byte-wise order, so `"07"` and `"7"` are distinct keys.
+///
+/// `max_key()` is `[0xFF]`, which is above every UTF-8 string, so non-UTF-8 keys need to stay
+/// below it like `KeyBytes` keys do.
+pub struct Natural;
+
+impl Comparator for Natural {
+    const NAME: &'static str = "natural";
+
+    fn compare(a: &[u8], b: &[u8]) -> Ordering {
+        let digit_run = |bytes: &[u8], start: usize| {
+            let end = bytes[start..]
+                .iter()
+                .position(|b| !b.is_ascii_digit())
+                .map_or(bytes.len(), |len| start + len);
+            let zeros = bytes[start..end].iter().take_while(|b| **b == b'0').count();
+            (start + zeros, end)
+        };
+
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() && j < b.len() {
+            let ord = match a[i].is_ascii_digit() && b[j].is_ascii_digit() {
+                true => {
+                    let (a_start, a_end) = digit_run(a, i);
+                    let (b_start, b_end) = digit_run(b, j);
+                    let (a_num, b_num) = (&a[a_start..a_end], &b[b_start..b_end]);
+                    i = a_end;
+                    j = b_end;
+                    a_num.len().cmp(&b_num.len()).then(a_num.cmp(b_num))
+                }
+                false => {
+                    i += 1;
+                    j += 1;
+                    a[i - 1].cmp(&b[j - 1])
+                }
+            };
+            if ord != Ordering::Equal {
+                return ord;
+            }
+        }
+        (a.len() - i).cmp(&(b.len() - j)).then_with(|| a.cmp(b))
+    }
+
+    fn max_key() -> Vec<u8> {
+        vec![0xFF]
+    }
+}
+
+/// Variable length byte string key ordered by comparator `C`.
+pub struct KeyCustom<C: Comparator> {
+    pub key: Vec<u8>,
+    comparator: PhantomData<C>,
+}
+
+impl<C: Comparator> KeyCustom<C> {
+    pub fn new<B: Into<Vec<u8>>>(key: B) -> Self {
+        Self {
+            key: key.into(),
+            comparator: PhantomData,
+        }
+    }
+}
+
+impl<C: Comparator> From<Vec<u8>> for KeyCustom<C> {
+    fn from(key: Vec<u8>) -> Self {
+        Self::new(key)
+    }
+}
+
+impl<C: Comparator> From<&str> for KeyCustom<C> {
+    fn from(key: &str) -> Self {
+        Self::new(key)
+    }
+}
+
+impl<C: Comparator> Clone for KeyCustom<C> {
+    fn clone(&self) -> Self {
+        Self::new(self.key.clone())
+    }
+}
+
+impl<C: Comparator> Debug for KeyCustom<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "KeyCustom<{}>({:?})",
+            C::NAME,
+            String::from_utf8_lossy(&self.key)
+        )
+    }
+}
+
+impl<C: Comparator> PartialEq for KeyCustom<C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<C: Comparator> Eq for KeyCustom<C> {}
+
+impl<C: Comparator> PartialOrd for KeyCustom<C> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<C: Comparator> Ord for KeyCustom<C> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        C::compare(&self.key, &other.key)
+    }
+}
+
+impl<C: Comparator> Key for KeyCustom<C> {
+    fn max_key() -> Self {
+        Self::new(C::max_key())
+    }
+
+    /// The comparator is identified by its name alone, so renaming or moving its type doesn't
+    /// affect existing trees.
+    fn type_name() -> Cow<'static, str> {
+        Cow::Borrowed("johndb::btree::comparator::KeyCustom")
+    }
+
+    fn collation() -> Cow<'static, str> {
+        Cow::Borrowed(C::NAME)
+    }
+}
+
+impl<C: Comparator> Item for KeyCustom<C> {
+    fn size(&self) -> usize {
+        self.key.len()
+    }
+
+    fn align() -> usize {
+        1
+    }
+
+    fn is_fixed_size() -> bool {
+        false
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        buffer.copy_from_slice(&self.key);
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        Self::new(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Comparator;
+    use super::KeyCustom;
+    use super::Natural;
+    use crate::btree::key::Key;
+    use crate::btree::value::ValueTupleId;
+    use crate::btree::BTree;
+    use crate::error::Error;
+    use crate::page_fetcher::InMemoryPageFetcher;
+    use std::cmp::Ordering;
+
+    /// Orders keys by their bytes read back to front.
+    struct Reversed;
+
+    impl Comparator for Reversed {
+        const NAME: &'static str = "test:reversed";
+
+        fn compare(a: &[u8], b: &[u8]) -> Ordering {
+            a.iter().rev().cmp(b.iter().rev())
+        }
+
+        fn max_key() -> Vec<u8> {
+            vec![0xFF; 64]
+        }
+    }
+
+    #[test]
+    fn natural_order() {
+        let sorted = [
+            "", "1.2", "1.9.3", "1.10", "1.10.0", "02", "2", "10", "a", "file1", "file2", "file10",
+            "file10b", "file011", "z",
+        ];
+        for (idx, a) in sorted.iter().enumerate() {
+            for (other, b) in sorted.iter().enumerate() {
+                assert_eq!(
+                    Natural::compare(a.as_bytes(), b.as_bytes()),
+                    idx.cmp(&other),
+                    "{:?} vs {:?}",
+                    a,
+                    b
+                );
+            }
+            assert!(KeyCustom::<Natural>::from(*a) < KeyCustom::max_key());
+        }
+    }
+
+    #[test]
+    fn trees_keep_their_comparator() {
+        let value = ValueTupleId {
+            page_no: 1,
+            offset: 0,
+        };
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(64)).unwrap();
+        for i in (0..3000u32).rev() {
+            let version = format!("1.{}.{}", i / 100, i % 100);
+            btree
+                .insert(KeyCustom::<Natural>::from(version.as_str()), value)
+                .unwrap();
+        }
+        let versions = btree
+            .range(KeyCustom::from("1.9.95")..KeyCustom::from("1.10.3"))
+            .unwrap()
+            .map(|res| String::from_utf8(res.unwrap().0.key).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(
+            versions,
+            ["1.9.95", "1.9.96", "1.9.97", "1.9.98", "1.9.99", "1.10.0", "1.10.1", "1.10.2"]
+        );
+        assert!(btree.verify().unwrap().is_ok());
+
+        let page_fetcher = btree.into_page_fetcher();
+        let btree = BTree::<KeyCustom<Natural>, ValueTupleId, _>::new(page_fetcher).unwrap();
+        assert_eq!(
+            btree.search(KeyCustom::from("1.29.99")).unwrap().value,
+            Some(value)
+        );
+        let page_fetcher = btree.into_page_fetcher();
+        match BTree::<KeyCustom<Reversed>, ValueTupleId, _>::new(page_fetcher) {
+            Err(Error::TypeMismatch(detail)) => assert!(detail.contains("natural"), "{}", detail),
+            Err(err) => panic!("expected TypeMismatch, got {:?}", err),
+            Ok(_) => panic!("expected TypeMismatch"),
+        }
+
+        let mut reversed = BTree::new(InMemoryPageFetcher::new()).unwrap();
+        for key in ["ab", "ba", "ca", "ac"] {
+            reversed
+                .insert(KeyCustom::<Reversed>::from(key), value)
+                .unwrap();
+        }
+        let keys = reversed
+            .range(..)
+            .unwrap()
+            .map(|res| String::from_utf8(res.unwrap().0.key).unwrap())
+            .collect::<Vec<_>>();
+        assert_eq!(keys, ["ba", "ca", "ab", "ac"]);
+    }
+}
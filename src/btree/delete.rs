@@ -0,0 +1,502 @@
+use super::internal_node;
+use super::internal_node::InternalNodeRead;
+use super::key::Key;
+use super::leaf_node;
+use super::leaf_node::LeafNodeItemData;
+use super::metadata_node::MetadataRead;
+use super::node::NodeRead;
+use super::node::NodeWrite;
+use super::vacuum::VacuumStats;
+use super::value::Value;
+use super::value::ValueMaybe;
+use super::BTreePageData;
+use super::NodeType;
+use crate::error::Error;
+use crate::error::Result;
+use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use log::debug;
+use std::ops::Bound;
+use std::ops::RangeBounds;
+
+impl<K, V, PageFetcher> super::BTree<K, V, PageFetcher>
+where
+    K: Key,
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Removes `key` from the tree, returning its value if it was present.
+    ///
+    /// The leaf's space isn't reclaimed right away; it's compacted the next time an insert runs
+    /// out of room in that page or by `vacuum`, which also merges underfull pages. Removed items
+    /// are counted in `dead_item_cnt` until then. Overflow pages the value was moved to, see
+    /// `Value::overflow`, are freed right away.
+    pub fn delete(&mut self, key: K) -> Result<Option<V>> {
+        self.op_stats.deletes.inc();
+        self.delete_inner(key).map_err(|err| err.context("delete"))
+    }
+
+    fn delete_inner(&mut self, key: K) -> Result<Option<V>> {
+        debug!("[delete] Begin delete {:?}", key);
+        let leaf_no = match self.find_leaf_no(Some(&key))? {
+            Some(leaf_no) => leaf_no,
+            None => return Ok(None),
+        };
+
+        let mut leaf = super::leaf_node::find_move_right::<PageFetcher, K, V>(
+            &self.page_fetcher,
+            leaf_no,
+            &key,
+        )?;
+
+        if leaf.find_item(&key).is_none() {
+            return Ok(None);
+        }
+        // The item is only removed once it's been counted, so that failing to lock the metadata
+        // page leaves the tree untouched. Inserts splitting the root lock it while holding a leaf
+        // too.
+        let mut metadata = self.metadata_write()?;
+        let cnt = metadata.dead_item_cnt()?;
+        metadata.set_dead_item_cnt(cnt + 1)?;
+        let value = leaf.remove_item(&key).unwrap();
+        drop(metadata);
+        drop(leaf);
+        let loaded = self.load_value(value.clone())?;
+        if let Some(first_page_no) = value.overflow_no() {
+            self.free_overflow(first_page_no)?;
+        }
+        Ok(Some(loaded))
+    }
+
+    /// Removes every entry with a key within `range`, returning how many there were.
+    ///
+    /// Each leaf the range covers is write-locked once and loses all its matching entries at
+    /// once, rather than descending from the root for every key. The parents of those leaves are
+    /// then vacuumed, which merges emptied leaves into their left siblings, handing over their
+    /// downlinks and sibling links, and frees them. A parent's first child has no left sibling
+    /// to merge into, so it may be left empty until a merge of the level above makes room.
+    pub fn delete_range<R: RangeBounds<K>>(&mut self, range: R) -> Result<u64> {
+        self.op_stats.deletes.inc();
+        self.delete_range_inner(range)
+            .map_err(|err| err.context("delete_range"))
+    }
+
+    fn delete_range_inner<R: RangeBounds<K>>(&mut self, range: R) -> Result<u64> {
+        let start_key = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => Some(key),
+            Bound::Unbounded => None,
+        };
+        let (first_leaf_no, first_parent_no) = match self.find_leaf_and_parent_no(start_key)? {
+            Some(found) => found,
+            None => return Ok(0),
+        };
+
+        let mut removed = 0;
+        let mut overflow_nos = Vec::new();
+        let mut next_leaf_no = Some(first_leaf_no);
+        while let Some(leaf_no) = next_leaf_no {
+            let mut leaf = leaf_node::fetch_page_write::<_, K, V>(&self.page_fetcher, leaf_no)?;
+            if V::overflow().is_some() {
+                overflow_nos.extend(
+                    leaf.item_iter()
+                        .filter(|item| range.contains(&item.key))
+                        .filter_map(|item| item.value.overflow_no()),
+                );
+            }
+            removed += leaf.remove_range(&range);
+            next_leaf_no = match leaf.separator().is_past_end(range.end_bound()) {
+                true => None,
+                false => leaf.special_data().right_sibling().get(),
+            };
+        }
+        if removed == 0 {
+            return Ok(0);
+        }
+        for first_page_no in overflow_nos {
+            self.free_overflow(first_page_no)?;
+        }
+        {
+            let mut metadata = self.metadata_write()?;
+            let cnt = metadata.dead_item_cnt()?;
+            metadata.set_dead_item_cnt(cnt + removed as u64)?;
+        }
+
+        let mut stats = VacuumStats::default();
+        let mut next_parent_no = first_parent_no;
+        while let Some(parent_no) = next_parent_no {
+            let separator = internal_node::fetch_page_read::<_, K>(&self.page_fetcher, parent_no)?
+                .separator()
+                .clone();
+            let right_sibling_no = self.vacuum_parent(parent_no, None, &mut stats)?;
+            next_parent_no = match separator.is_past_end(range.end_bound()) {
+                true => None,
+                false => right_sibling_no,
+            };
+        }
+        self.collapse_root(None, &mut stats)?;
+
+        debug!("[delete_range] Removed {} entries, {:?}", removed, stats);
+        Ok(removed as u64)
+    }
+
+    /// How many items were deleted from the tree since it was last vacuumed, i.e. since the start
+    /// of the last vacuum that ran to completion.
+    pub fn dead_item_cnt(&self) -> Result<u64> {
+        self.metadata_read()?.dead_item_cnt()
+    }
+
+    /// Returns every page of the tree to the page fetcher's free list, its metadata page included,
+    /// so the tree is gone afterwards. Each value is handed to `free_value` first, to free any
+    /// pages it refers to besides its own overflow pages, which are freed along with the tree's
+    /// statistics and free space map. Heap pages are left alone, as they may hold the tuples of
+    /// other trees sharing the page fetcher, see `Vacuum::with_heap`.
+    pub fn destroy<F>(self, mut free_value: F) -> Result<()>
+    where
+        F: FnMut(&Self, V) -> Result<()>,
+    {
+        self.destroy_inner(&mut free_value)
+            .map_err(|err| err.context("destroy"))
+    }
+
+    fn destroy_inner<F>(&self, free_value: &mut F) -> Result<()>
+    where
+        F: FnMut(&Self, V) -> Result<()>,
+    {
+        let (root_no, stats_no) = {
+            let metadata = self.metadata_read()?;
+            (metadata.root_no()?, metadata.stats_no()?)
+        };
+        let mut page_nos = self.free_space_map_nos()?;
+
+        // Level by level from the root down, each from its leftmost node through the right
+        // siblings
+        let mut next_level_no = root_no;
+        while let Some(level_no) = next_level_no {
+            let is_leaf = {
+                let page = self.page_fetcher.fetch_page_read(level_no)?;
+                matches!(
+                    BTreePageData::checked_node_type(level_no, &page)?,
+                    NodeType::Leaf
+                )
+            };
+            next_level_no = None;
+            let mut next_no = Some(level_no);
+            while let Some(page_no) = next_no {
+                page_nos.push(page_no);
+                if !is_leaf {
+                    let internal =
+                        internal_node::fetch_page_read::<_, K>(&self.page_fetcher, page_no)?;
+                    if page_no == level_no {
+                        next_level_no = internal.first_child_ptr();
+                    }
+                    next_no = internal.special_data().right_sibling().get();
+                    continue;
+                }
+
+                let values = {
+                    let leaf = leaf_node::fetch_page_read::<_, K, V>(&self.page_fetcher, page_no)?;
+                    next_no = leaf.special_data().right_sibling().get();
+                    leaf.page_ref()
+                        .item_refs_from::<LeafNodeItemData<K, V>>(1)
+                        .filter(|item| !V::is_tombstone(item.value_bytes()))
+                        .map(|item| item.value())
+                        .collect::<Vec<_>>()
+                };
+                for value in values {
+                    if let Some(first_page_no) = value.overflow_no() {
+                        self.free_overflow(first_page_no)?;
+                    }
+                    free_value(self, value)?;
+                }
+            }
+        }
+
+        if let Some(stats_no) = stats_no {
+            self.free_overflow(stats_no)?;
+        }
+        page_nos.push(self.metadata_no);
+        for page_no in page_nos {
+            self.page_fetcher.free_page(page_no)?;
+        }
+        debug!("[destroy] Freed tree {}", self.metadata_no);
+        Ok(())
+    }
+}
+
+impl<K, V, PageFetcher> super::BTree<K, ValueMaybe<V>, PageFetcher>
+where
+    K: Key,
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Replaces `key`'s value with a tombstone, returning the value if there was one. The key is
+    /// absent to reads from then on, but stays in its leaf until `vacuum` removes it, so the
+    /// delete can be seen by whoever scans the leaves in the meantime, e.g. to replicate it.
+    pub fn delete_logically(&mut self, key: K) -> Result<Option<V>> {
+        self.op_stats.deletes.inc();
+        self.delete_logically_inner(key)
+            .map_err(|err| err.context("delete_logically"))
+    }
+
+    fn delete_logically_inner(&mut self, key: K) -> Result<Option<V>> {
+        debug!("[delete_logically] Begin delete {:?}", key);
+        let leaf_no = match self.find_leaf_no(Some(&key))? {
+            Some(leaf_no) => leaf_no,
+            None => return Ok(None),
+        };
+
+        let mut leaf = super::leaf_node::find_move_right::<PageFetcher, K, ValueMaybe<V>>(
+            &self.page_fetcher,
+            leaf_no,
+            &key,
+        )?;
+        let value = match leaf.find_item(&key) {
+            Some((_, item)) if !ValueMaybe::<V>::is_tombstone(item.value_bytes()) => item.value(),
+            _ => return Ok(None),
+        };
+        leaf.remove_item(&key);
+        let tombstone = LeafNodeItemData {
+            key,
+            value: ValueMaybe::Tombstone,
+        };
+        // The tombstone is no larger than the value, so compacting always makes room for it
+        match leaf.add_item(&tombstone) {
+            Err(Error::PageFull) => {
+                leaf.compact()?;
+                leaf.add_item(&tombstone)?;
+            }
+            res => res?,
+        }
+        drop(leaf);
+
+        {
+            let mut metadata = self.metadata_write()?;
+            let cnt = metadata.dead_item_cnt()?;
+            metadata.set_dead_item_cnt(cnt + 1)?;
+        }
+        let loaded = self.load_value(value.clone())?;
+        if let Some(first_page_no) = value.overflow_no() {
+            self.free_overflow(first_page_no)?;
+        }
+        match loaded {
+            ValueMaybe::Live(value) => Ok(Some(value)),
+            ValueMaybe::Tombstone => Ok(None),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::btree::key::KeyU32;
+    use crate::btree::key::KeyU64;
+    use crate::btree::vacuum::Vacuum;
+    use crate::btree::value::ValueLarge;
+    use crate::btree::value::ValueMaybe;
+    use crate::btree::value::ValueTupleId;
+    use crate::btree::BTree;
+    use crate::page_fetcher::InMemoryPageFetcher;
+
+    #[test]
+    fn delete_and_reinsert() {
+        let mut btree = BTree::new(InMemoryPageFetcher::new()).unwrap();
+        for i in 0..2000 {
+            btree
+                .insert(
+                    KeyU32 { key: i },
+                    ValueTupleId {
+                        page_no: i,
+                        offset: 0,
+                    },
+                )
+                .unwrap();
+        }
+
+        for i in (0..2000).step_by(2) {
+            assert_eq!(
+                btree.delete(KeyU32 { key: i }).unwrap(),
+                Some(ValueTupleId {
+                    page_no: i,
+                    offset: 0
+                })
+            );
+        }
+        assert_eq!(btree.delete(KeyU32 { key: 0 }).unwrap(), None);
+
+        for i in 0..2000 {
+            let found = btree.search(KeyU32 { key: i }).unwrap().value;
+            assert_eq!(found.is_some(), i % 2 == 1, "key {}", i);
+        }
+
+        // Reinserting into the leaves reuses the space left behind by the deletes rather than
+        // splitting again.
+        for i in (0..2000).step_by(2) {
+            btree
+                .insert(
+                    KeyU32 { key: i },
+                    ValueTupleId {
+                        page_no: i,
+                        offset: 1,
+                    },
+                )
+                .unwrap();
+        }
+        for i in 0..2000 {
+            assert!(btree.search(KeyU32 { key: i }).unwrap().value.is_some());
+        }
+    }
+
+    #[test]
+    fn delete_range() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(256)).unwrap();
+        let value = |key| ValueTupleId {
+            page_no: key,
+            offset: 0,
+        };
+        for key in 0..20000 {
+            btree.insert(KeyU32 { key }, value(key)).unwrap();
+        }
+        let leaves = btree.analyze().unwrap().levels.last().unwrap().page_cnt;
+
+        assert_eq!(
+            btree
+                .delete_range(KeyU32 { key: 1000 }..KeyU32 { key: 19000 })
+                .unwrap(),
+            18000
+        );
+        assert_eq!(
+            btree
+                .delete_range(KeyU32 { key: 10 }..=KeyU32 { key: 20 })
+                .unwrap(),
+            11
+        );
+        assert_eq!(
+            btree
+                .delete_range(KeyU32 { key: 5000 }..KeyU32 { key: 6000 })
+                .unwrap(),
+            0
+        );
+        assert_eq!(btree.dead_item_cnt().unwrap(), 18011);
+
+        // The emptied leaves were unlinked and freed
+        let stats = btree.analyze().unwrap();
+        assert_eq!(stats.entry_cnt(), 1989);
+        assert!(stats.levels.last().unwrap().page_cnt * 4 < leaves);
+        assert!(btree.verify().unwrap().is_ok());
+        for key in (0..20000).step_by(7) {
+            let found = btree.search(KeyU32 { key }).unwrap().value;
+            let kept = !(10..=20).contains(&key) && !(1000..19000).contains(&key);
+            assert_eq!(found, Some(value(key)).filter(|_| kept), "key {}", key);
+        }
+
+        assert_eq!(btree.delete_range(..).unwrap(), 1989);
+        assert_eq!(btree.analyze().unwrap().entry_cnt(), 0);
+        btree.insert(KeyU32 { key: 7 }, value(7)).unwrap();
+        assert_eq!(
+            btree.search(KeyU32 { key: 7 }).unwrap().value,
+            Some(value(7))
+        );
+        assert!(btree.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn logical_deletes() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(64)).unwrap();
+        let value = |key| ValueTupleId {
+            page_no: key,
+            offset: 0,
+        };
+        for key in 0..2000 {
+            btree
+                .insert(KeyU32 { key }, ValueMaybe::from(value(key)))
+                .unwrap();
+        }
+        for key in (0..2000).step_by(2) {
+            assert_eq!(
+                btree.delete_logically(KeyU32 { key }).unwrap(),
+                Some(value(key))
+            );
+        }
+        assert_eq!(btree.delete_logically(KeyU32 { key: 0 }).unwrap(), None);
+        assert_eq!(btree.dead_item_cnt().unwrap(), 1000);
+
+        // Tombstones are absent to reads, but still take up their leaf slots
+        assert_eq!(btree.search(KeyU32 { key: 10 }).unwrap().value, None);
+        assert_eq!(
+            btree.search(KeyU32 { key: 11 }).unwrap().value,
+            Some(ValueMaybe::Live(value(11)))
+        );
+        assert_eq!(btree.range(..).unwrap().count(), 1000);
+        let mut visited = 0;
+        btree
+            .range_visit(.., |_| {
+                visited += 1;
+                true
+            })
+            .unwrap();
+        assert_eq!(visited, 1000);
+        let top = btree.top_n(..KeyU32 { key: 1000 }, 2).unwrap();
+        assert_eq!(
+            top.iter().map(|(key, _)| key.key).collect::<Vec<_>>(),
+            vec![999, 997]
+        );
+        assert_eq!(btree.analyze().unwrap().entry_cnt(), 2000);
+
+        // A new value replaces the tombstone
+        let reinserted = ValueMaybe::from(value(7));
+        assert_eq!(
+            btree
+                .insert_if_absent(KeyU32 { key: 10 }, reinserted.clone())
+                .unwrap(),
+            None
+        );
+        assert_eq!(
+            btree.search(KeyU32 { key: 10 }).unwrap().value,
+            Some(reinserted)
+        );
+        assert!(btree.verify().unwrap().is_ok());
+
+        let stats = btree.vacuum(Vacuum::new()).unwrap();
+        assert_eq!(stats.tombstones_removed, 999);
+        assert_eq!(btree.analyze().unwrap().entry_cnt(), 1001);
+        assert_eq!(btree.range(..).unwrap().count(), 1001);
+        assert!(btree.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn destroy_frees_every_page() {
+        let fetcher = InMemoryPageFetcher::with_capacity(2048);
+        let live_page_cnt = || fetcher.page_cnt() - fetcher.free_page_cnt();
+        let value = |key: u64| match key % 10 {
+            0 => ValueLarge::from(vec![key as u8; 20000]),
+            _ => ValueLarge::from(vec![key as u8; 10]),
+        };
+        let mut kept = BTree::new(&fetcher).unwrap();
+        for key in 0..100u64 {
+            kept.insert(KeyU64 { key }, value(key)).unwrap();
+        }
+        let live = live_page_cnt();
+
+        let mut btree = BTree::create(&fetcher).unwrap();
+        for key in 0..3000u64 {
+            btree.insert(KeyU64 { key }, value(key)).unwrap();
+        }
+        btree.collect_stats(1.0).unwrap();
+        btree.record_free_space(0, 4096).unwrap();
+        assert!(btree.analyze().unwrap().height() > 1);
+        assert!(live_page_cnt() > live + 900);
+
+        let mut freed_values = 0;
+        btree
+            .destroy(|_, _| {
+                freed_values += 1;
+                Ok(())
+            })
+            .unwrap();
+        assert_eq!(freed_values, 3000);
+        assert_eq!(live_page_cnt(), live);
+        assert!(kept.verify().unwrap().is_ok());
+        assert_eq!(
+            kept.search(KeyU64 { key: 10 }).unwrap().value,
+            Some(value(10))
+        );
+    }
+}
@@ -0,0 +1,139 @@
+//! Applies random sequences of operations to a tree and to a `BTreeMap` model and checks they
+//! agree, with `verify` run along the way. Complements the `tree_ops` fuzz target, which covers
+//! the same ground from coverage-guided input but doesn't run with `cargo test`.
+//!
+//! Keys come from a small alphabet so operations collide and share prefixes, and values are long
+//! enough that a few dozen of them split a leaf.
+
+use super::key::KeyBytes;
+use super::value::ValueBytes;
+use super::BTree;
+use crate::error::Error;
+use crate::page_fetcher::InMemoryPageFetcher;
+use proptest::prelude::*;
+use std::collections::BTreeMap;
+
+/// Operations between `verify` runs.
+const VERIFY_EVERY: usize = 32;
+
+#[derive(Debug, Clone)]
+enum Op {
+    /// Inserts a value of the given length unless the key is present.
+    Insert(Vec<u8>, usize),
+    Delete(Vec<u8>),
+    Search(Vec<u8>),
+    /// Scans the half-open range between the keys, whichever order they're in.
+    Scan(Vec<u8>, Vec<u8>),
+    DeleteRange(Vec<u8>, Vec<u8>),
+}
+
+/// Stays below `KeyBytes::max_key()`.
+fn key() -> impl Strategy<Value = Vec<u8>> {
+    prop::collection::vec(0u8..8, 1..4)
+}
+
+fn op() -> impl Strategy<Value = Op> {
+    prop_oneof![
+        8 => (key(), 0usize..700).prop_map(|(key, len)| Op::Insert(key, len)),
+        3 => key().prop_map(Op::Delete),
+        2 => key().prop_map(Op::Search),
+        2 => (key(), key()).prop_map(|(start, end)| Op::Scan(start, end)),
+        1 => (key(), key()).prop_map(|(start, end)| Op::DeleteRange(start, end)),
+    ]
+}
+
+fn ordered(a: Vec<u8>, b: Vec<u8>) -> (KeyBytes, KeyBytes) {
+    let (start, end) = match a <= b {
+        true => (a, b),
+        false => (b, a),
+    };
+    (KeyBytes { key: start }, KeyBytes { key: end })
+}
+
+fn check(ops: Vec<Op>) -> Result<(), TestCaseError> {
+    let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(256)).unwrap();
+    let mut model: BTreeMap<Vec<u8>, Vec<u8>> = BTreeMap::new();
+
+    for (idx, op) in ops.into_iter().enumerate() {
+        match op {
+            Op::Insert(key, len) => {
+                let value = vec![len as u8; len];
+                let existing = match btree.insert_if_absent(
+                    KeyBytes { key: key.clone() },
+                    ValueBytes {
+                        value: value.clone(),
+                    },
+                ) {
+                    Ok(existing) => existing,
+                    // The fixed page budget ran out, which isn't a bug
+                    Err(err) if matches!(err.root_cause(), Error::OutOfPages) => break,
+                    Err(err) => panic!("insert failed: {}", err),
+                };
+                prop_assert_eq!(existing.map(|value| value.value), model.get(&key).cloned());
+                model.entry(key).or_insert(value);
+            }
+            Op::Delete(key) => {
+                let deleted = btree.delete(KeyBytes { key: key.clone() }).unwrap();
+                prop_assert_eq!(deleted.map(|value| value.value), model.remove(&key));
+            }
+            Op::Search(key) => {
+                let found = btree.search(KeyBytes { key: key.clone() }).unwrap();
+                prop_assert_eq!(
+                    found.value.map(|value| value.value),
+                    model.get(&key).cloned()
+                );
+            }
+            Op::Scan(a, b) => {
+                let (start, end) = ordered(a, b);
+                let scanned = btree
+                    .range(start.clone()..end.clone())
+                    .unwrap()
+                    .map(|entry| {
+                        let (key, value) = entry.unwrap();
+                        (key.key, value.value)
+                    })
+                    .collect::<Vec<_>>();
+                let expected = model
+                    .range(start.key..end.key)
+                    .map(|(key, value)| (key.clone(), value.clone()))
+                    .collect::<Vec<_>>();
+                prop_assert_eq!(scanned, expected);
+            }
+            Op::DeleteRange(a, b) => {
+                let (start, end) = ordered(a, b);
+                let deleted = btree.delete_range(start.clone()..end.clone()).unwrap();
+                let doomed = model
+                    .range(start.key..end.key)
+                    .map(|(key, _)| key.clone())
+                    .collect::<Vec<_>>();
+                prop_assert_eq!(deleted, doomed.len() as u64);
+                for key in doomed {
+                    model.remove(&key);
+                }
+            }
+        }
+        if idx % VERIFY_EVERY == VERIFY_EVERY - 1 {
+            let report = btree.verify().unwrap();
+            prop_assert!(report.is_ok(), "after op {}: {:?}", idx, report.violations);
+        }
+    }
+
+    let report = btree.verify().unwrap();
+    prop_assert!(report.is_ok(), "{:?}", report.violations);
+    let scanned = btree
+        .range(..)
+        .unwrap()
+        .map(|entry| entry.unwrap().0.key)
+        .collect::<Vec<_>>();
+    prop_assert_eq!(scanned, model.into_keys().collect::<Vec<_>>());
+    Ok(())
+}
+
+proptest! {
+    #![proptest_config(ProptestConfig::with_cases(64))]
+
+    #[test]
+    fn matches_btree_map(ops in prop::collection::vec(op(), 1..500)) {
+        check(ops)?;
+    }
+}
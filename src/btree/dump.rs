@@ -0,0 +1,250 @@
+use super::internal_node::InternalNodeItemData;
+use super::key::Key;
+use super::key::KeyU32;
+use super::leaf_node::LeafNodeItemData;
+use super::metadata_node::DeadItemCnt;
+use super::metadata_node::MetadataTypes;
+use super::metadata_node::SequenceLimit;
+use super::metadata_node::COLLATION_IDX;
+use super::metadata_node::DEAD_ITEM_CNT_IDX;
+use super::metadata_node::SEQUENCE_LIMIT_IDX;
+use super::node::check_node_items;
+use super::value::Value;
+use super::value::ValueBytes;
+use super::BTreePageData;
+use super::NodeType;
+use crate::error::Result;
+use crate::page::Item;
+use crate::page::Page;
+use crate::page::PAGE_DATA_SIZE;
+use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use std::fmt::Debug;
+use std::fmt::Write;
+use std::mem::size_of;
+
+/// Bytes per line of `hexdump`.
+const HEXDUMP_WIDTH: usize = 16;
+
+impl<K, V, PageFetcher> super::BTree<K, V, PageFetcher>
+where
+    K: Key,
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Pretty-prints page `page_no` for debugging: its header, special data, and every item
+    /// pointer along with the item it points to, decoded according to the node type. With `hex`
+    /// set, a hexdump of the whole page is appended.
+    pub fn dump_page(&self, page_no: u32, hex: bool) -> Result<String> {
+        let page = self.page_fetcher.fetch_page_read(page_no)?;
+
+        let mut out = String::new();
+        dump_page_to::<K, V>(&page, page_no, &mut out).expect("writing to a String can't fail");
+        if hex {
+            out.push_str("raw:\n");
+            out.push_str(&hexdump(page.as_bytes()));
+        }
+        Ok(out)
+    }
+}
+
+pub(super) fn dump_page_to<K, V>(page: &Page, page_no: u32, out: &mut String) -> std::fmt::Result
+where
+    K: Key,
+    V: Value,
+{
+    writeln!(out, "page {}", page_no)?;
+    writeln!(
+        out,
+        "  header: item_upper={} item_lower={} special_size={}",
+        page.header.item_upper(),
+        page.header.item_lower(),
+        page.header.special_size()
+    )?;
+    if let Err(err) = page.check_layout() {
+        return writeln!(out, "  malformed page: {}", err);
+    }
+    writeln!(
+        out,
+        "  items: {}, item data: {} bytes, dead space: {} bytes",
+        page.item_cnt(),
+        page.item_data_size(),
+        page.dead_space()
+    )?;
+
+    if let Err(err) = BTreePageData::check(page) {
+        return writeln!(out, "  special: not a btree page: {}", err);
+    }
+    let special_data = page.special_data::<BTreePageData>();
+    writeln!(
+        out,
+        "  special: node_type={:?} right_sibling={}",
+        special_data.node_type,
+        special_data.right_sibling()
+    )?;
+
+    let items = match special_data.node_type {
+        NodeType::Leaf => check_node_items::<LeafNodeItemData<K, V>>(page),
+        NodeType::Internal => check_node_items::<InternalNodeItemData<K>>(page),
+        NodeType::Metadata | NodeType::Overflow | NodeType::Heap | NodeType::FreeSpace => {
+            page.check_layout()
+        }
+    };
+    if let Err(err) = &items {
+        writeln!(out, "  malformed items, showing pointers only: {}", err)?;
+    }
+
+    let data_end = PAGE_DATA_SIZE - page.header.special_size();
+    for idx in 0..page.item_cnt() {
+        let (offset, size) = page.item_pointer(idx);
+        write!(out, "  [{}] offset={} size={}: ", idx, offset, size)?;
+        if offset < page.header.item_upper() || offset + size > data_end {
+            writeln!(out, "<pointer out of bounds>")?;
+            continue;
+        }
+        if items.is_err() {
+            writeln!(out)?;
+            continue;
+        }
+
+        match (&special_data.node_type, idx) {
+            (NodeType::Metadata, 0) => item::<MetadataTypes>(page, idx, size, out)?,
+            (NodeType::Metadata, SEQUENCE_LIMIT_IDX) => {
+                item::<SequenceLimit>(page, idx, size, out)?
+            }
+            (NodeType::Metadata, DEAD_ITEM_CNT_IDX) => item::<DeadItemCnt>(page, idx, size, out)?,
+            (NodeType::Metadata, COLLATION_IDX) => {
+                let collation = page.get_item_ref::<ValueBytes>(idx).bytes();
+                writeln!(out, "collation {:?}", String::from_utf8_lossy(collation))?
+            }
+            (NodeType::Metadata, _) => item::<KeyU32>(page, idx, size, out)?,
+            (NodeType::Internal, 0) | (NodeType::Leaf, 0) if special_data.is_unbounded() => {
+                write!(out, "separator unbounded, placeholder ")?;
+                item::<K>(page, idx, size, out)?
+            }
+            (NodeType::Internal, 0) | (NodeType::Leaf, 0) => {
+                write!(out, "separator ")?;
+                item::<K>(page, idx, size, out)?
+            }
+            (NodeType::Internal, _) => item::<InternalNodeItemData<K>>(page, idx, size, out)?,
+            (NodeType::Leaf, _) => item::<LeafNodeItemData<K, V>>(page, idx, size, out)?,
+            (NodeType::Overflow, _) => {
+                let chunk = page.get_item_v2::<ValueBytes>(idx);
+                writeln!(out, "overflow chunk of {} bytes", chunk.value.len())?
+            }
+            (NodeType::FreeSpace, _) => {
+                let map = page.get_item_ref::<ValueBytes>(idx).bytes();
+                let with_room = map.iter().filter(|category| **category > 0).count();
+                writeln!(out, "free space map, {} pages with room", with_room)?
+            }
+            (NodeType::Heap, _) => {
+                let tuple = page.get_item_v2::<ValueBytes>(idx);
+                match super::heap::TupleHeader::parse(&tuple.value) {
+                    Some((header, bytes)) if header.flag == super::heap::TUPLE_LIVE => writeln!(
+                        out,
+                        "tuple of {} bytes, xmin {} xmax {}",
+                        bytes.len(),
+                        header.xmin,
+                        header.xmax
+                    )?,
+                    Some(_) => writeln!(out, "dead tuple")?,
+                    None => writeln!(out, "malformed tuple")?,
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// Writes the decoded item, unless its size rules out decoding it as an `I`.
+fn item<I: Item + Debug>(
+    page: &Page,
+    idx: usize,
+    size: usize,
+    out: &mut String,
+) -> std::fmt::Result {
+    if I::is_fixed_size() && size != size_of::<I>() {
+        return writeln!(out, "<expected {} bytes>", size_of::<I>());
+    }
+    writeln!(out, "{:?}", page.get_item_v2::<I>(idx))
+}
+
+/// Formats `bytes` as offset, hex and ASCII columns. Runs of all-zero lines are collapsed into a
+/// single `*`.
+pub fn hexdump(bytes: &[u8]) -> String {
+    let mut out = String::new();
+    let mut skipping = false;
+    for (line_no, line) in bytes.chunks(HEXDUMP_WIDTH).enumerate() {
+        if line.iter().all(|byte| *byte == 0) && line_no > 0 {
+            if !skipping {
+                out.push_str("*\n");
+                skipping = true;
+            }
+            continue;
+        }
+        skipping = false;
+
+        let _ = write!(out, "{:08x} ", line_no * HEXDUMP_WIDTH);
+        for byte in line.iter() {
+            let _ = write!(out, " {:02x}", byte);
+        }
+        out.push_str("  |");
+        out.extend(line.iter().map(|byte| {
+            if byte.is_ascii_graphic() || *byte == b' ' {
+                *byte as char
+            } else {
+                '.'
+            }
+        }));
+        out.push_str("|\n");
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::hexdump;
+    use crate::btree::key::KeyU32;
+    use crate::btree::value::ValueTupleId;
+    use crate::btree::BTree;
+    use crate::page_fetcher::InMemoryPageFetcher;
+
+    #[test]
+    fn dump_leaf_and_metadata() {
+        let mut btree = BTree::new(InMemoryPageFetcher::new()).unwrap();
+        btree
+            .insert(
+                KeyU32 { key: 42 },
+                ValueTupleId {
+                    page_no: 7,
+                    offset: 3,
+                },
+            )
+            .unwrap();
+
+        let metadata = btree.dump_page(0, false).unwrap();
+        assert!(metadata.contains("node_type=Metadata"), "{}", metadata);
+        assert!(metadata.contains("[1] "), "{}", metadata);
+
+        let leaf = btree.dump_page(1, true).unwrap();
+        assert!(leaf.contains("node_type=Leaf"), "{}", leaf);
+        assert!(leaf.contains("separator unbounded"), "{}", leaf);
+        assert!(leaf.contains("key: 42"), "{}", leaf);
+        assert!(leaf.contains("raw:\n00000000 "), "{}", leaf);
+
+        assert!(btree.dump_page(100, false).is_err());
+    }
+
+    #[test]
+    fn hexdump_collapses_zeros() {
+        let mut bytes = vec![0u8; 64];
+        bytes[0] = b'j';
+        bytes[63] = 0xff;
+        assert_eq!(
+            hexdump(&bytes),
+            "00000000  6a 00 00 00 00 00 00 00 00 00 00 00 00 00 00 00  |j...............|\n\
+             *\n\
+             00000030  00 00 00 00 00 00 00 00 00 00 00 00 00 00 00 ff  |................|\n"
+        );
+    }
+}
@@ -0,0 +1,309 @@
+//! Reports what an operation is expected to do before running it, and what it actually did after,
+//! to help make sense of how a workload performs. `explain_*` only reads pages: it descends to the
+//! leaf the operation would touch, and for scans follows the leaf chain up to the end of the
+//! range, without decoding any values.
+
+use super::internal_node::find_child_ptr_move_right_read_lock;
+use super::internal_node::from_read_lock as from_read_lock_internal;
+use super::internal_node::InternalNodeRead;
+use super::key::Key;
+use super::leaf_node::LeafNodeItemData;
+use super::metadata_node::MetadataRead;
+use super::node::NodeRead;
+use super::value::Value;
+use super::BTreePageData;
+use super::NodeType;
+use crate::error::Error;
+use crate::error::Result;
+use crate::metrics::Metrics;
+use crate::page::Item;
+use crate::page::Page;
+use crate::page::ITEM_POINTER_SIZE;
+use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use std::fmt;
+use std::ops::Bound;
+use std::ops::RangeBounds;
+
+/// A shortcut an operation can take, see `Explain::fast_path`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FastPath {
+    /// The key sorts after every key in the tree, so the insert appends to the rightmost leaf.
+    RightmostInsert,
+    /// The range starts at a key and ends within the same leaf, so the scan reads a single leaf.
+    PrefixScan,
+}
+
+/// The plan for an operation, see `BTree::explain_search`, `explain_insert` and `explain_range`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Explain {
+    pub op: &'static str,
+    /// Levels from the root down to the leaves, 0 for a tree without a root yet.
+    pub height: usize,
+    /// Pages the operation is expected to read, counting the metadata page.
+    pub expected_pages: u64,
+    pub fast_path: Option<FastPath>,
+    /// Whether the leaf the key goes into doesn't have room for it, even after compacting. Only
+    /// set for inserts.
+    pub expect_split: bool,
+}
+
+impl fmt::Display for Explain {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} (height={} pages={}",
+            self.op, self.height, self.expected_pages
+        )?;
+        match self.fast_path {
+            Some(FastPath::RightmostInsert) => write!(f, " fast_path=rightmost_insert")?,
+            Some(FastPath::PrefixScan) => write!(f, " fast_path=prefix_scan")?,
+            None => {}
+        }
+        if self.expect_split {
+            write!(f, " split")?;
+        }
+        write!(f, ")")
+    }
+}
+
+/// What an operation run through `BTree::measure` did.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Actual {
+    /// Page fetches, whether served from memory or not.
+    pub pages_read: u64,
+    /// Leaf and internal page splits.
+    pub splits: u64,
+}
+
+impl fmt::Display for Actual {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "pages_read={} splits={}", self.pages_read, self.splits)
+    }
+}
+
+impl<K, V, PageFetcher> super::BTree<K, V, PageFetcher>
+where
+    K: Key,
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    pub fn explain_search(&self, key: &K) -> Result<Explain> {
+        let (_, height) = self.descend(Some(key))?;
+        Ok(Explain {
+            op: "search",
+            height,
+            expected_pages: 1 + height as u64,
+            fast_path: None,
+            expect_split: false,
+        })
+    }
+
+    /// The plan for `insert(key, value)`. An insert expected to split also writes the new
+    /// sibling and updates the parent, which isn't counted in `expected_pages`.
+    pub fn explain_insert(&self, key: &K, value: &V) -> Result<Explain> {
+        let (leaf_no, height) = self.descend(Some(key))?;
+        let mut explain = Explain {
+            op: "insert",
+            height,
+            expected_pages: 1 + height as u64,
+            fast_path: None,
+            expect_split: false,
+        };
+        let leaf_no = match leaf_no {
+            Some(leaf_no) => leaf_no,
+            // The first insert creates the root leaf
+            None => return Ok(explain),
+        };
+
+        let leaf =
+            super::leaf_node::fetch_page_read::<PageFetcher, K, V>(&self.page_fetcher, leaf_no)?;
+        let rightmost = !leaf.special_data().right_sibling().is_valid();
+        let above_all = match leaf.special_data().is_sorted() {
+            true => leaf
+                .item_iter_rev()
+                .next()
+                .is_none_or(|last| last.key < *key),
+            false => leaf.item_iter().all(|item| item.key < *key),
+        };
+        if rightmost && above_all {
+            explain.fast_path = Some(FastPath::RightmostInsert);
+        }
+
+        let item = LeafNodeItemData {
+            key: key.clone(),
+            value: value.clone(),
+        };
+        // Mirrors `add_item_compacting` on scratch copies of the leaf: the item either fits as
+        // is, or after rebuilding the leaf without its dead space
+        let page = leaf.page_ref();
+        let mut scratch = *page;
+        let fits = scratch.add_item_v2(&item).is_ok();
+        explain.expect_split = !fits
+            && (page.dead_space() < item.size() + ITEM_POINTER_SIZE + K::align() || {
+                let mut compacted = Page::new(page.header.special_size() as u32);
+                compacted.add_item_v2(&leaf.separator().stored_key())?;
+                for leaf_item in leaf.item_iter() {
+                    compacted.add_item_v2(&leaf_item)?;
+                }
+                compacted.add_item_v2(&item).is_err()
+            });
+        Ok(explain)
+    }
+
+    /// The plan for scanning `range` with `range`, `range_visit` or `scan_filter`. The leaves
+    /// within the range are counted by following their sibling links.
+    pub fn explain_range<R>(&self, range: R) -> Result<Explain>
+    where
+        R: RangeBounds<K>,
+    {
+        let start_key = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => Some(key),
+            Bound::Unbounded => None,
+        };
+        let (mut next_leaf_no, height) = self.descend(start_key)?;
+
+        let mut leaf_cnt = 0u64;
+        while let Some(leaf_no) = next_leaf_no {
+            leaf_cnt += 1;
+            let leaf = super::leaf_node::fetch_page_read::<PageFetcher, K, V>(
+                &self.page_fetcher,
+                leaf_no,
+            )?;
+            next_leaf_no = match leaf.separator().is_past_end(range.end_bound()) {
+                true => None,
+                false => leaf.special_data().right_sibling().get(),
+            };
+        }
+
+        let bounded = start_key.is_some() && !matches!(range.end_bound(), Bound::Unbounded);
+        Ok(Explain {
+            op: "range",
+            height,
+            // The descent already read the first leaf
+            expected_pages: 1 + height as u64 + leaf_cnt.saturating_sub(1),
+            fast_path: match bounded && leaf_cnt == 1 {
+                true => Some(FastPath::PrefixScan),
+                false => None,
+            },
+            expect_split: false,
+        })
+    }
+
+    /// Runs `op` against the tree and reports the pages it read and the splits it performed,
+    /// to compare against what `explain_*` expected. Pages are counted off the fetcher's
+    /// `Metrics`, so operations running concurrently on the same fetcher count towards it too.
+    pub fn measure<T, F>(&mut self, op: F) -> Result<(T, Actual)>
+    where
+        F: FnOnce(&mut Self) -> Result<T>,
+    {
+        let pages_before = pages_read(self.page_fetcher.metrics());
+        let splits_before = self.op_stats.splits.get();
+        let res = op(self)?;
+        let actual = Actual {
+            pages_read: pages_read(self.page_fetcher.metrics()) - pages_before,
+            splits: self.op_stats.splits.get() - splits_before,
+        };
+        Ok((res, actual))
+    }
+
+    /// Descends like `find_leaf_no`, also returning the number of levels passed through.
+    fn descend(&self, key: Option<&K>) -> Result<(Option<u32>, usize)> {
+        let mut page_no = match self.metadata_read()?.root_no()? {
+            Some(root_no) => root_no,
+            None => return Ok((None, 0)),
+        };
+
+        let mut height = 1;
+        loop {
+            let node = self.page_fetcher.fetch_page_read(page_no)?;
+            let node_type = BTreePageData::checked_node_type(page_no, &node)?;
+            match node_type {
+                NodeType::Leaf => return Ok((Some(page_no), height)),
+                NodeType::Internal => {
+                    let internal = from_read_lock_internal::<K>(page_no, node)?;
+                    page_no = match key {
+                        Some(key) => {
+                            find_child_ptr_move_right_read_lock(&self.page_fetcher, internal, key)?
+                                .1
+                        }
+                        None => internal.first_child_ptr().ok_or_else(|| {
+                            Error::page_corruption(page_no, "internal page has no items")
+                        })?,
+                    };
+                    height += 1;
+                }
+                NodeType::Metadata | NodeType::Overflow | NodeType::Heap | NodeType::FreeSpace => {
+                    return Err(Error::page_corruption(
+                        page_no,
+                        format!("encountered a {:?} page while traversing down", node_type),
+                    ));
+                }
+            }
+        }
+    }
+}
+
+fn pages_read(metrics: &Metrics) -> u64 {
+    metrics.pool_hits.get() + metrics.pool_misses.get()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FastPath;
+    use crate::btree::key::KeyU32;
+    use crate::btree::value::ValueBytes;
+    use crate::btree::BTree;
+    use crate::page_fetcher::InMemoryPageFetcher;
+
+    #[test]
+    fn explain_then_measure() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(256)).unwrap();
+        let value = ValueBytes {
+            value: vec![0; 100],
+        };
+        for key in 0..2000u32 {
+            btree.insert(KeyU32 { key }, value.clone()).unwrap();
+        }
+
+        let explain = btree.explain_search(&KeyU32 { key: 10 }).unwrap();
+        assert!(explain.height >= 2);
+        assert_eq!(explain.expected_pages, 1 + explain.height as u64);
+        let (_, actual) = btree
+            .measure(|btree| btree.search(KeyU32 { key: 10 }))
+            .unwrap();
+        assert_eq!(actual.pages_read, explain.expected_pages);
+        assert_eq!(actual.splits, 0);
+
+        let last = KeyU32 { key: 5000 };
+        let explain = btree.explain_insert(&last, &value).unwrap();
+        assert_eq!(explain.fast_path, Some(FastPath::RightmostInsert));
+        let explain = btree.explain_insert(&KeyU32 { key: 7 }, &value).unwrap();
+        assert_eq!(explain.fast_path, None);
+
+        let explain = btree
+            .explain_range(KeyU32 { key: 5 }..KeyU32 { key: 8 })
+            .unwrap();
+        assert_eq!(explain.fast_path, Some(FastPath::PrefixScan));
+        let explain = btree.explain_range(..).unwrap();
+        assert_eq!(explain.fast_path, None);
+        assert!(explain.expected_pages > 1 + explain.height as u64);
+        assert!(explain
+            .to_string()
+            .starts_with(&format!("range (height={} ", explain.height)));
+
+        // Fill the rightmost leaf until an insert is expected to split it
+        let mut key = 5000;
+        while !btree
+            .explain_insert(&KeyU32 { key }, &value)
+            .unwrap()
+            .expect_split
+        {
+            btree.insert(KeyU32 { key }, value.clone()).unwrap();
+            key += 1;
+        }
+        let (_, actual) = btree
+            .measure(|btree| btree.insert(KeyU32 { key }, value.clone()))
+            .unwrap();
+        assert!(actual.splits >= 1);
+    }
+}
@@ -0,0 +1,147 @@
+use super::key::Key;
+use super::value::Value;
+use crate::error::Error;
+use crate::error::Result;
+use crate::export::DumpReader;
+use crate::export::DumpWriter;
+use crate::page::Item;
+use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use std::io::Read;
+use std::io::Write;
+use std::mem::size_of;
+
+impl<K, V, PageFetcher> super::BTree<K, V, PageFetcher>
+where
+    K: Key,
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Writes every entry to `writer` in ascending key order, in the portable format described in
+    /// `crate::export`. Keys and values are stored as their item encodings, tagged with
+    /// `Key::type_name` and `Value::type_name`. Returns the number of entries written.
+    ///
+    /// Leaves are read one at a time, so the dump is only a consistent snapshot if the tree isn't
+    /// modified concurrently.
+    pub fn dump<W: Write>(&self, writer: W) -> Result<u64> {
+        let mut dump = DumpWriter::new(writer, &K::type_name(), &V::type_name())?;
+
+        let mut result = Ok(());
+        self.range_visit(.., |entry| {
+            result = dump.write_entry(&item_to_bytes(entry.key()), entry.value_bytes());
+            result.is_ok()
+        })?;
+        result?;
+
+        dump.finish()
+    }
+
+    /// Inserts every entry of a stream written by `dump`, which must have been produced by a
+    /// tree with the same key and value types. Keys already in the tree are an error. Returns
+    /// the number of entries loaded.
+    pub fn load<R: Read>(&mut self, reader: R) -> Result<u64> {
+        let mut dump = DumpReader::new(reader)?;
+        if dump.key_type() != K::type_name() || dump.value_type() != V::type_name() {
+            return Err(Error::TypeMismatch(format!(
+                "dump holds ({}, {}), loading into ({}, {})",
+                dump.key_type(),
+                dump.value_type(),
+                K::type_name(),
+                V::type_name()
+            )));
+        }
+
+        let mut prev_key: Option<K> = None;
+        let mut cnt = 0;
+        while let Some((key, value)) = dump.next_entry()? {
+            let key = item_from_bytes::<K>(&key)?;
+            let value = item_from_bytes::<V>(&value)?;
+            if prev_key.as_ref().is_some_and(|prev_key| *prev_key >= key) {
+                return Err(Error::corruption(format!(
+                    "dump key {:?} is out of order",
+                    key
+                )));
+            }
+
+            if self.insert_if_absent(key.clone(), value)?.is_some() {
+                return Err(Error::corruption(format!(
+                    "dump key {:?} is already in the tree",
+                    key
+                )));
+            }
+            prev_key = Some(key);
+            cnt += 1;
+        }
+
+        Ok(cnt)
+    }
+}
+
+/// The item's encoded bytes, as they're stored in a page.
+pub(crate) fn item_to_bytes<I: Item>(item: &I) -> Vec<u8> {
+    let mut bytes = vec![0; item.size()];
+    item.write(&mut bytes);
+    bytes
+}
+
+pub(crate) fn item_from_bytes<I: Item>(bytes: &[u8]) -> Result<I> {
+    if I::is_fixed_size() && bytes.len() != size_of::<I>() {
+        return Err(Error::corruption(format!(
+            "expected a {} byte item, found {} bytes",
+            size_of::<I>(),
+            bytes.len()
+        )));
+    }
+
+    Ok(I::read(bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::btree::key::KeyString;
+    use crate::btree::key::KeyU32;
+    use crate::btree::value::ValueBytes;
+    use crate::btree::value::ValueTupleId;
+    use crate::btree::BTree;
+    use crate::error::Error;
+    use crate::page_fetcher::InMemoryPageFetcher;
+
+    #[test]
+    fn dump_and_load() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(64)).unwrap();
+        for i in 0..2000u32 {
+            let key = (i * 7919) % 2000;
+            btree
+                .insert(
+                    KeyString::from(format!("key-{}", key)),
+                    ValueBytes {
+                        value: vec![key as u8; (key % 50) as usize],
+                    },
+                )
+                .unwrap();
+        }
+
+        let mut buf = Vec::new();
+        assert_eq!(btree.dump(&mut buf).unwrap(), 2000);
+
+        let mut loaded = BTree::new(InMemoryPageFetcher::with_capacity(64)).unwrap();
+        assert_eq!(loaded.load(&buf[..]).unwrap(), 2000);
+        let entries = |btree: &BTree<KeyString, ValueBytes, _>| {
+            btree
+                .range(..)
+                .unwrap()
+                .map(|res| res.unwrap())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(entries(&loaded), entries(&btree));
+
+        // Loading the same entries again collides with the existing keys
+        assert!(matches!(
+            loaded.load(&buf[..]),
+            Err(Error::Corruption { .. })
+        ));
+
+        let mut other: BTree<KeyU32, ValueTupleId, _> =
+            BTree::new(InMemoryPageFetcher::new()).unwrap();
+        assert!(matches!(other.load(&buf[..]), Err(Error::TypeMismatch(_))));
+    }
+}
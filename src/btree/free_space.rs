@@ -0,0 +1,250 @@
+//! The free space map records roughly how many bytes each heap page has room for, so that tuples
+//! can be placed in a page with enough room without trying every page. It's stored in a chain of
+//! `NodeType::FreeSpace` pages starting at the one in the tree's metadata, the `n`th covering
+//! page numbers `n * PAGES_PER_MAP_PAGE` up.
+//!
+//! Entries are only updated when space is found missing or reclaimed, so they may overstate a
+//! page's room, and callers must be prepared for the page to be full (or not a heap page anymore).
+
+use super::key::Key;
+use super::metadata_node::MetadataRead;
+use super::value::Value;
+use super::value::ValueBytes;
+use super::BTreePageData;
+use super::NodeType;
+use crate::error::Error;
+use crate::error::Result;
+use crate::page::Page;
+use crate::page::PageNo;
+use crate::page::ITEM_POINTER_SIZE;
+use crate::page::PAGE_DATA_SIZE;
+use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use log::debug;
+use std::mem::size_of;
+
+/// Free space is recorded in units of this many bytes, rounded down, so that a page's fits in a
+/// byte.
+const GRANULE: usize = 32;
+
+/// Pages covered by each page of the map, which holds one byte per page as its only item.
+const PAGES_PER_MAP_PAGE: usize = PAGE_DATA_SIZE - size_of::<BTreePageData>() - ITEM_POINTER_SIZE;
+
+impl<K, V, PageFetcher> super::BTree<K, V, PageFetcher>
+where
+    K: Key,
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Records that page `page_no` has room for `free` bytes.
+    pub(super) fn record_free_space(&self, page_no: u32, free: usize) -> Result<()> {
+        let category = (free / GRANULE).min(u8::MAX as usize) as u8;
+        let (map_no, slot) = match self.map_page_for(page_no, category > 0)? {
+            Some(map_page) => map_page,
+            None => return Ok(()),
+        };
+
+        let changed = {
+            let page = self.page_fetcher.fetch_page_read(map_no)?;
+            map_entries(map_no, &page)?[slot] != category
+        };
+        if changed {
+            let mut page = self.page_fetcher.fetch_page_write(map_no)?;
+            check_map_page(map_no, &page)?;
+            let (offset, _) = page.item_pointer(0);
+            page.data[offset + slot] = category;
+        }
+        Ok(())
+    }
+
+    /// Roughly how many bytes page `page_no` has room for, as last recorded.
+    pub fn recorded_free_space(&self, page_no: u32) -> Result<usize> {
+        let (map_no, slot) = match self.map_page_for(page_no, false)? {
+            Some(map_page) => map_page,
+            None => return Ok(0),
+        };
+        let page = self.page_fetcher.fetch_page_read(map_no)?;
+        Ok(map_entries(map_no, &page)?[slot] as usize * GRANULE)
+    }
+
+    /// Returns the first page recorded as having room for `needed` bytes.
+    pub(super) fn find_free_space(&self, needed: usize) -> Result<Option<u32>> {
+        let category = needed.div_ceil(GRANULE);
+        if category > u8::MAX as usize {
+            return Ok(None);
+        }
+
+        let mut next_map_no = self.metadata_read()?.free_space_no()?;
+        let mut first_page_no = 0;
+        while let Some(map_no) = next_map_no {
+            let page = self.page_fetcher.fetch_page_read(map_no)?;
+            let found = map_entries(map_no, &page)?
+                .iter()
+                .position(|entry| *entry as usize >= category);
+            if let Some(slot) = found {
+                return Ok(Some((first_page_no + slot) as u32));
+            }
+            next_map_no = page.special_data::<BTreePageData>().right_sibling().get();
+            first_page_no += PAGES_PER_MAP_PAGE;
+        }
+
+        Ok(None)
+    }
+
+    /// The pages of the free space map, in order.
+    pub(super) fn free_space_map_nos(&self) -> Result<Vec<u32>> {
+        let mut map_nos = Vec::new();
+        let mut next_map_no = self.metadata_read()?.free_space_no()?;
+        while let Some(map_no) = next_map_no {
+            let page = self.page_fetcher.fetch_page_read(map_no)?;
+            check_map_page(map_no, &page)?;
+            map_nos.push(map_no);
+            next_map_no = page.special_data::<BTreePageData>().right_sibling().get();
+        }
+        Ok(map_nos)
+    }
+
+    /// The map page covering `page_no` and its slot within it, extending the map to it first if
+    /// `create` is set.
+    fn map_page_for(&self, page_no: u32, create: bool) -> Result<Option<(u32, usize)>> {
+        let idx = page_no as usize / PAGES_PER_MAP_PAGE;
+        let slot = page_no as usize % PAGES_PER_MAP_PAGE;
+
+        // The metadata read lock mustn't be held while the map is created below
+        let first_no = self.metadata_read()?.free_space_no()?;
+        let mut map_no = match first_no {
+            Some(map_no) => map_no,
+            None if create => {
+                let map_no = self.new_map_page()?;
+                self.metadata_write()?.set_free_space_no(map_no)?;
+                map_no
+            }
+            None => return Ok(None),
+        };
+        for _ in 0..idx {
+            let next_no = {
+                let page = self.page_fetcher.fetch_page_read(map_no)?;
+                check_map_page(map_no, &page)?;
+                page.special_data::<BTreePageData>().right_sibling().get()
+            };
+            map_no = match next_no {
+                Some(next_no) => next_no,
+                None if create => {
+                    let next_no = self.new_map_page()?;
+                    let mut page = self.page_fetcher.fetch_page_write(map_no)?;
+                    page.special_data_mut::<BTreePageData>()
+                        .set_right_sibling(PageNo::new(next_no));
+                    next_no
+                }
+                None => return Ok(None),
+            };
+        }
+
+        Ok(Some((map_no, slot)))
+    }
+
+    fn new_map_page(&self) -> Result<u32> {
+        let (map_no, mut page) = self
+            .page_fetcher
+            .new_page(BTreePageData::new(NodeType::FreeSpace, PageNo::INVALID))?;
+        page.add_item_v2(&ValueBytes {
+            value: vec![0; PAGES_PER_MAP_PAGE],
+        })?;
+        debug!(
+            "[new_map_page] Extended the free space map with page {}",
+            map_no
+        );
+        Ok(map_no)
+    }
+}
+
+fn map_entries(map_no: u32, page: &Page) -> Result<&[u8]> {
+    check_map_page(map_no, page)?;
+    Ok(page.get_item_ref::<ValueBytes>(0).bytes())
+}
+
+fn check_map_page(map_no: u32, page: &Page) -> Result<()> {
+    page.check_layout()
+        .and_then(|_| BTreePageData::check(page))
+        .map_err(|err| err.on_page(map_no))?;
+    let malformed = !matches!(
+        page.special_data::<BTreePageData>().node_type,
+        NodeType::FreeSpace
+    ) || page.item_cnt() != 1
+        || page.item_pointer(0).1 != PAGES_PER_MAP_PAGE;
+    if malformed {
+        return Err(Error::page_corruption(map_no, "not a free space map page"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::GRANULE;
+    use super::PAGES_PER_MAP_PAGE;
+    use crate::btree::heap::MAX_TUPLE_SIZE;
+    use crate::btree::key::KeyU32;
+    use crate::btree::vacuum::Vacuum;
+    use crate::btree::value::ValueTupleId;
+    use crate::btree::BTree;
+    use crate::page_fetcher::InMemoryPageFetcher;
+
+    type Tree = BTree<KeyU32, ValueTupleId, InMemoryPageFetcher>;
+
+    #[test]
+    fn reuse_vacuumed_heap_pages() {
+        let mut btree: Tree = BTree::new(InMemoryPageFetcher::with_capacity(256)).unwrap();
+        let ids = (0..1000)
+            .map(|_| btree.insert_tuple(&[1; 100]).unwrap())
+            .collect::<Vec<_>>();
+        for id in ids.iter().take(500) {
+            btree.delete_tuple(*id).unwrap();
+        }
+        let first_no = ids[0].page_no;
+        assert!(btree.recorded_free_space(first_no).unwrap() < 100);
+
+        btree.vacuum(Vacuum::with_heap(0)).unwrap();
+        assert!(btree.recorded_free_space(first_no).unwrap() > 4000);
+
+        // The tuples go to the vacuumed pages rather than new ones
+        let page_cnt = btree.page_fetcher().page_cnt();
+        let id = (0..400)
+            .map(|_| btree.insert_tuple(&[2; 100]).unwrap())
+            .last()
+            .unwrap();
+        assert_eq!(btree.page_fetcher().page_cnt(), page_cnt);
+        assert_eq!(btree.get_tuple(id).unwrap(), Some(vec![2; 100]));
+        assert_eq!(btree.get_tuple(ids[999]).unwrap(), Some(vec![1; 100]));
+    }
+
+    #[test]
+    fn persists_across_reopen() {
+        let mut btree: Tree = BTree::new(InMemoryPageFetcher::new()).unwrap();
+        let first = btree.insert_tuple(b"first").unwrap();
+
+        let mut btree: Tree = BTree::open(btree.into_page_fetcher(), 0).unwrap();
+        let second = btree.insert_tuple(b"second").unwrap();
+        assert_eq!(second.page_no, first.page_no);
+
+        // A tuple larger than the page's room goes to a new page
+        let large = btree.insert_tuple(&[3; MAX_TUPLE_SIZE]).unwrap();
+        assert_ne!(large.page_no, first.page_no);
+    }
+
+    #[test]
+    fn map_spans_pages() {
+        let btree: Tree = BTree::new(InMemoryPageFetcher::new()).unwrap();
+        let page_no = (2 * PAGES_PER_MAP_PAGE + 10) as u32;
+        assert_eq!(btree.find_free_space(1).unwrap(), None);
+
+        btree.record_free_space(page_no, 1000).unwrap();
+        assert_eq!(
+            btree.recorded_free_space(page_no).unwrap(),
+            1000 / GRANULE * GRANULE
+        );
+        assert_eq!(btree.find_free_space(900).unwrap(), Some(page_no));
+        assert_eq!(btree.find_free_space(1000).unwrap(), None);
+
+        btree.record_free_space(page_no, 0).unwrap();
+        assert_eq!(btree.find_free_space(1).unwrap(), None);
+    }
+}
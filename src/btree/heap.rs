@@ -0,0 +1,481 @@
+use super::key::Key;
+use super::value::Value;
+use super::value::ValueBytes;
+use super::value::ValueTupleId;
+use super::BTreePageData;
+use super::NodeType;
+use crate::error::Error;
+use crate::error::Result;
+use crate::page::Item;
+use crate::page::Page;
+use crate::page::PageNo;
+use crate::page::ITEM_POINTER_SIZE;
+use crate::page::PAGE_DATA_SIZE;
+use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use log::debug;
+use std::convert::TryInto;
+use std::mem::size_of;
+
+/// Transaction ids as stored in tuple headers.
+pub type TxnId = u64;
+
+/// The `xmin` of tuples that are visible to every snapshot, such as those stored by
+/// `insert_tuple`. As an `xmax` it means the tuple hasn't been deleted.
+pub const FROZEN_TXN_ID: TxnId = 0;
+
+/// Every tuple is stored behind a header holding a flag byte and the ids of the transactions that
+/// created (`xmin`) and deleted (`xmax`) it. Removing a tuple only flips the flag, since removing
+/// the item would shift the slots after it and invalidate their `ValueTupleId`s.
+pub(super) const TUPLE_LIVE: u8 = 0;
+const TUPLE_DEAD: u8 = 1;
+const TUPLE_HEADER_SIZE: usize = 1 + 2 * size_of::<TxnId>();
+
+/// The largest tuple a heap page can hold: the whole data region minus the special data, the
+/// item pointer and the tuple header.
+pub const MAX_TUPLE_SIZE: usize =
+    PAGE_DATA_SIZE - size_of::<BTreePageData>() - ITEM_POINTER_SIZE - TUPLE_HEADER_SIZE;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(super) struct TupleHeader {
+    pub(super) flag: u8,
+    pub(super) xmin: TxnId,
+    pub(super) xmax: TxnId,
+}
+
+impl TupleHeader {
+    /// Splits a stored tuple into its header and bytes, or returns `None` if it's malformed.
+    pub(super) fn parse(tuple: &[u8]) -> Option<(TupleHeader, &[u8])> {
+        if tuple.len() < TUPLE_HEADER_SIZE || tuple[0] > TUPLE_DEAD {
+            return None;
+        }
+        let (xmin, xmax) = tuple[1..TUPLE_HEADER_SIZE].split_at(size_of::<TxnId>());
+        let header = TupleHeader {
+            flag: tuple[0],
+            xmin: TxnId::from_be_bytes(xmin.try_into().unwrap()),
+            xmax: TxnId::from_be_bytes(xmax.try_into().unwrap()),
+        };
+        Some((header, &tuple[TUPLE_HEADER_SIZE..]))
+    }
+
+    fn write(&self, tuple: &mut [u8]) {
+        tuple[0] = self.flag;
+        tuple[1..9].copy_from_slice(&self.xmin.to_be_bytes());
+        tuple[9..TUPLE_HEADER_SIZE].copy_from_slice(&self.xmax.to_be_bytes());
+    }
+}
+
+/// Which transactions' changes a reader sees. Transactions with ids below `xmax` that aren't in
+/// `in_progress` are taken to have committed, and everything else as not having happened yet,
+/// except for the reader's own changes as `current`.
+///
+/// TODO: There's no record of aborted transactions yet, so the caller has to keep them in
+/// `in_progress` for as long as their tuples may be around.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Snapshot {
+    pub current: TxnId,
+    pub xmax: TxnId,
+    pub in_progress: Vec<TxnId>,
+}
+
+impl Snapshot {
+    /// Sees every change made so far, as if no transaction were running.
+    pub fn latest() -> Self {
+        Snapshot {
+            current: FROZEN_TXN_ID,
+            xmax: TxnId::MAX,
+            in_progress: Vec::new(),
+        }
+    }
+
+    fn sees(&self, txn_id: TxnId) -> bool {
+        txn_id == FROZEN_TXN_ID
+            || txn_id == self.current
+            || (txn_id < self.xmax && !self.in_progress.contains(&txn_id))
+    }
+
+    /// Whether a tuple with this header is part of the snapshot: its creation is seen and its
+    /// deletion, if any, isn't.
+    fn is_visible(&self, header: &TupleHeader) -> bool {
+        header.flag == TUPLE_LIVE
+            && self.sees(header.xmin)
+            && (header.xmax == FROZEN_TXN_ID || !self.sees(header.xmax))
+    }
+}
+
+impl<K, V, PageFetcher> super::BTree<K, V, PageFetcher>
+where
+    K: Key,
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Stores `bytes` in a heap page and returns its id, where `offset` is the tuple's slot
+    /// within the page. Ids stay valid until the tuple is deleted, so they can be used as
+    /// `ValueTupleId` values of an index over the stored rows. The tuple is frozen, i.e.
+    /// visible to every snapshot.
+    ///
+    /// Tuples are appended to the heap page last written to. Once it's full, they go to a page
+    /// the free space map has room recorded for, and only then to a new page.
+    pub fn insert_tuple(&mut self, bytes: &[u8]) -> Result<ValueTupleId> {
+        self.insert_tuple_in(FROZEN_TXN_ID, bytes)
+    }
+
+    /// Like `insert_tuple`, but the tuple is created by transaction `xmin`, so it's only
+    /// visible to snapshots that see it.
+    pub fn insert_tuple_in(&mut self, xmin: TxnId, bytes: &[u8]) -> Result<ValueTupleId> {
+        if bytes.len() > MAX_TUPLE_SIZE {
+            return Err(Error::ItemTooLarge(bytes.len()));
+        }
+        let mut tuple = vec![0; TUPLE_HEADER_SIZE + bytes.len()];
+        TupleHeader {
+            flag: TUPLE_LIVE,
+            xmin,
+            xmax: FROZEN_TXN_ID,
+        }
+        .write(&mut tuple);
+        tuple[TUPLE_HEADER_SIZE..].copy_from_slice(bytes);
+        let tuple = ValueBytes { value: tuple };
+
+        if let Some(page_no) = self.heap_page_no {
+            if let Some(id) = self.add_tuple(page_no, &tuple)? {
+                return Ok(id);
+            }
+        }
+        // Pages that turn out not to have room are recorded as such, so this ends
+        while let Some(page_no) = self.find_free_space(tuple.size() + ITEM_POINTER_SIZE)? {
+            if let Some(id) = self.add_tuple(page_no, &tuple)? {
+                debug!("[insert_tuple] Moved to heap page {} with room", page_no);
+                self.heap_page_no = Some(page_no);
+                return Ok(id);
+            }
+        }
+
+        let (page_no, free) = {
+            let (page_no, mut page) = self
+                .page_fetcher
+                .new_page(BTreePageData::new(NodeType::Heap, PageNo::INVALID))?;
+            page.add_item_v2(&tuple)?;
+            (page_no, page.free_space())
+        };
+        debug!("[insert_tuple] Started heap page {}", page_no);
+        self.record_free_space(page_no, free)?;
+        self.heap_page_no = Some(page_no);
+
+        Ok(ValueTupleId { page_no, offset: 0 })
+    }
+
+    /// Adds `tuple` to `page_no` if it's a heap page with room for it. Otherwise its room is
+    /// recorded in the free space map, as none if it's not a heap page or was truncated away.
+    fn add_tuple(&self, page_no: u32, tuple: &ValueBytes) -> Result<Option<ValueTupleId>> {
+        if page_no as usize >= self.page_fetcher.page_cnt() {
+            self.record_free_space(page_no, 0)?;
+            return Ok(None);
+        }
+        let free = {
+            let mut page = self.page_fetcher.fetch_page_write(page_no)?;
+            match check_heap_page(page_no, &page) {
+                Ok(()) => match page.add_item_v2(tuple) {
+                    Ok(()) => {
+                        return Ok(Some(ValueTupleId {
+                            page_no,
+                            offset: (page.item_cnt() - 1) as u16,
+                        }))
+                    }
+                    Err(Error::PageFull) => page.free_space(),
+                    Err(err) => return Err(err),
+                },
+                Err(_) => 0,
+            }
+        };
+        self.record_free_space(page_no, free)?;
+        Ok(None)
+    }
+
+    /// Returns the tuple stored under `id`, or `None` if it was deleted or never existed. Tuples
+    /// deleted with `delete_tuple_in` count as deleted once their transaction is.
+    pub fn get_tuple(&self, id: ValueTupleId) -> Result<Option<Vec<u8>>> {
+        self.get_tuple_in(id, &Snapshot::latest())
+    }
+
+    /// Returns the tuple stored under `id` if it's visible to `snapshot`.
+    pub fn get_tuple_in(&self, id: ValueTupleId, snapshot: &Snapshot) -> Result<Option<Vec<u8>>> {
+        self.visit_tuple(id, snapshot, |bytes| bytes.to_vec())
+    }
+
+    /// Calls `visit` on the tuple stored under `id` if it's visible to `snapshot`, returning its
+    /// result. The tuple's bytes are borrowed from the page while its read lock is held, so
+    /// nothing is copied unless `visit` does.
+    pub fn visit_tuple<T, F>(
+        &self,
+        id: ValueTupleId,
+        snapshot: &Snapshot,
+        visit: F,
+    ) -> Result<Option<T>>
+    where
+        F: FnOnce(&[u8]) -> T,
+    {
+        let page = self.page_fetcher.fetch_page_read(id.page_no)?;
+        check_heap_page(id.page_no, &page)?;
+        if id.offset as usize >= page.item_cnt() {
+            return Ok(None);
+        }
+
+        let tuple = page
+            .try_get_item_ref::<ValueBytes>(id.offset as usize)
+            .map_err(|err| err.on_page(id.page_no))?;
+        let (header, bytes) = parse_tuple(id, tuple.bytes())?;
+        Ok(Some(header)
+            .filter(|header| snapshot.is_visible(header))
+            .map(|_| visit(bytes)))
+    }
+
+    /// Removes the tuple stored under `id` for good, returning it if it was present. Its space
+    /// is reclaimed by the next `vacuum` with a heap horizon.
+    pub fn delete_tuple(&mut self, id: ValueTupleId) -> Result<Option<Vec<u8>>> {
+        self.update_tuple_header(id, |header| match header.flag {
+            TUPLE_LIVE => {
+                header.flag = TUPLE_DEAD;
+                true
+            }
+            _ => false,
+        })
+    }
+
+    /// Deletes the tuple stored under `id` on behalf of transaction `xmax`, returning it if it
+    /// was present and not already deleted. Snapshots that don't see `xmax` keep seeing the
+    /// tuple until it's vacuumed.
+    pub fn delete_tuple_in(&mut self, id: ValueTupleId, xmax: TxnId) -> Result<Option<Vec<u8>>> {
+        self.update_tuple_header(id, |header| {
+            match header.flag == TUPLE_LIVE && header.xmax == FROZEN_TXN_ID {
+                true => {
+                    header.xmax = xmax;
+                    true
+                }
+                false => false,
+            }
+        })
+    }
+
+    /// Removes the tuple stored under `id` for good if it was deleted by a transaction below
+    /// `horizon`, i.e. one that every running and future snapshot sees. Returns whether it was
+    /// removed.
+    pub fn vacuum_tuple(&mut self, id: ValueTupleId, horizon: TxnId) -> Result<bool> {
+        let removed = self.update_tuple_header(id, |header| {
+            match header.flag == TUPLE_LIVE && header.xmax != FROZEN_TXN_ID && header.xmax < horizon
+            {
+                true => {
+                    header.flag = TUPLE_DEAD;
+                    true
+                }
+                false => false,
+            }
+        })?;
+        Ok(removed.is_some())
+    }
+
+    /// Whether the tuple stored under `id` is dead to every running and future snapshot: removed
+    /// for good, or deleted by a transaction below `horizon`. False if `id` doesn't point at a
+    /// tuple at all.
+    pub(super) fn is_tuple_dead(&self, id: ValueTupleId, horizon: TxnId) -> Result<bool> {
+        if id.page_no as usize >= self.page_fetcher.page_cnt() {
+            return Ok(false);
+        }
+        let page = self.page_fetcher.fetch_page_read(id.page_no)?;
+        if check_heap_page(id.page_no, &page).is_err() || id.offset as usize >= page.item_cnt() {
+            return Ok(false);
+        }
+        let tuple = page
+            .try_get_item_ref::<ValueBytes>(id.offset as usize)
+            .map_err(|err| err.on_page(id.page_no))?;
+        let (header, _) = parse_tuple(id, tuple.bytes())?;
+        Ok(header.flag == TUPLE_DEAD || (header.xmax != FROZEN_TXN_ID && header.xmax < horizon))
+    }
+
+    /// Applies `update` to the header of the tuple stored under `id` and writes it back if it
+    /// returns true, in which case the tuple's bytes are returned.
+    fn update_tuple_header<F>(&mut self, id: ValueTupleId, update: F) -> Result<Option<Vec<u8>>>
+    where
+        F: FnOnce(&mut TupleHeader) -> bool,
+    {
+        let mut page = self.page_fetcher.fetch_page_write(id.page_no)?;
+        check_heap_page(id.page_no, &page)?;
+        if id.offset as usize >= page.item_cnt() {
+            return Ok(None);
+        }
+
+        let mut tuple = page
+            .try_get_item_v2::<ValueBytes>(id.offset as usize)
+            .map_err(|err| err.on_page(id.page_no))?;
+        let (mut header, bytes) = parse_tuple(id, &tuple.value)?;
+        let bytes = bytes.to_vec();
+        if !update(&mut header) {
+            return Ok(None);
+        }
+        header.write(&mut tuple.value);
+        page.update_item_v2(id.offset as usize, &tuple);
+        Ok(Some(bytes))
+    }
+}
+
+/// Marks every tuple on heap page `page` deleted by a transaction below `horizon` as dead, and
+/// shrinks dead tuples down to their header before compacting the page. Their slots stay, so the
+/// `ValueTupleId`s of the other tuples remain valid. Returns the number of tuples shrunk.
+pub(super) fn vacuum_heap_page(page_no: u32, page: &mut Page, horizon: TxnId) -> Result<usize> {
+    check_heap_page(page_no, page)?;
+    let mut removed = 0;
+    for idx in 0..page.item_cnt() {
+        let id = ValueTupleId {
+            page_no,
+            offset: idx as u16,
+        };
+        let tuple = page
+            .try_get_item_ref::<ValueBytes>(idx)
+            .map_err(|err| err.on_page(page_no))?
+            .bytes();
+        let (mut header, bytes) = parse_tuple(id, tuple)?;
+        let expired =
+            header.flag == TUPLE_LIVE && header.xmax != FROZEN_TXN_ID && header.xmax < horizon;
+        if !expired && (header.flag == TUPLE_LIVE || bytes.is_empty()) {
+            continue;
+        }
+
+        header.flag = TUPLE_DEAD;
+        let (offset, _) = page.item_pointer(idx);
+        header.write(&mut page.data[offset..offset + TUPLE_HEADER_SIZE]);
+        page.truncate_item(idx, TUPLE_HEADER_SIZE);
+        removed += 1;
+    }
+
+    if removed > 0 {
+        page.compact::<ValueBytes>()?;
+    }
+    Ok(removed)
+}
+
+fn parse_tuple(id: ValueTupleId, tuple: &[u8]) -> Result<(TupleHeader, &[u8])> {
+    TupleHeader::parse(tuple).ok_or_else(|| {
+        Error::page_corruption(
+            id.page_no,
+            format!("tuple {} has no valid header", id.offset),
+        )
+    })
+}
+
+fn check_heap_page(page_no: u32, page: &Page) -> Result<()> {
+    BTreePageData::check(page).map_err(|err| err.on_page(page_no))?;
+    if !matches!(
+        page.special_data::<BTreePageData>().node_type,
+        NodeType::Heap
+    ) {
+        return Err(Error::page_corruption(page_no, "not a heap page"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Snapshot;
+    use super::MAX_TUPLE_SIZE;
+    use crate::btree::key::KeyU32;
+    use crate::btree::value::ValueTupleId;
+    use crate::btree::BTree;
+    use crate::error::Error;
+    use crate::page_fetcher::InMemoryPageFetcher;
+
+    #[test]
+    fn index_over_heap_tuples() {
+        let mut btree: BTree<KeyU32, ValueTupleId, _> =
+            BTree::new(InMemoryPageFetcher::with_capacity(256)).unwrap();
+
+        for i in 0..2000u32 {
+            let row = format!("row {}", i).repeat(i as usize % 10);
+            let id = btree.insert_tuple(row.as_bytes()).unwrap();
+            btree.insert(KeyU32 { key: i }, id).unwrap();
+        }
+        for i in (0..2000u32).step_by(3) {
+            let id = btree.search(KeyU32 { key: i }).unwrap().value.unwrap();
+            let row = format!("row {}", i).repeat(i as usize % 10);
+            assert_eq!(btree.delete_tuple(id).unwrap(), Some(row.into_bytes()));
+            assert_eq!(btree.delete_tuple(id).unwrap(), None);
+        }
+
+        for i in 0..2000u32 {
+            let id = btree.search(KeyU32 { key: i }).unwrap().value.unwrap();
+            let expected = match i % 3 {
+                0 => None,
+                _ => Some(format!("row {}", i).repeat(i as usize % 10).into_bytes()),
+            };
+            assert_eq!(btree.get_tuple(id).unwrap(), expected);
+        }
+
+        let id = btree.insert_tuple(&vec![7; MAX_TUPLE_SIZE]).unwrap();
+        assert_eq!(btree.get_tuple(id).unwrap(), Some(vec![7; MAX_TUPLE_SIZE]));
+        assert!(matches!(
+            btree.insert_tuple(&vec![7; MAX_TUPLE_SIZE + 1]),
+            Err(Error::ItemTooLarge(_))
+        ));
+        // Ids pointing at index pages are rejected
+        assert!(matches!(
+            btree.get_tuple(ValueTupleId {
+                page_no: 0,
+                offset: 0
+            }),
+            Err(Error::Corruption { .. })
+        ));
+    }
+
+    #[test]
+    fn snapshot_visibility() {
+        let mut btree: BTree<KeyU32, ValueTupleId, _> =
+            BTree::new(InMemoryPageFetcher::new()).unwrap();
+        let old = btree.insert_tuple_in(5, b"old").unwrap();
+        let new = btree.insert_tuple_in(8, b"new").unwrap();
+        assert_eq!(
+            btree.delete_tuple_in(old, 8).unwrap(),
+            Some(b"old".to_vec())
+        );
+        assert_eq!(btree.delete_tuple_in(old, 9).unwrap(), None);
+
+        // Started while 8 was running: the delete and the insert aren't seen yet
+        let before = Snapshot {
+            current: 7,
+            xmax: 9,
+            in_progress: vec![8],
+        };
+        assert_eq!(
+            btree.get_tuple_in(old, &before).unwrap(),
+            Some(b"old".to_vec())
+        );
+        assert_eq!(btree.get_tuple_in(new, &before).unwrap(), None);
+        // 8 sees its own changes
+        let own = Snapshot {
+            current: 8,
+            ..before.clone()
+        };
+        assert_eq!(btree.get_tuple_in(old, &own).unwrap(), None);
+        assert_eq!(
+            btree.get_tuple_in(new, &own).unwrap(),
+            Some(b"new".to_vec())
+        );
+        // Started after 8 committed
+        let after = Snapshot {
+            current: 10,
+            xmax: 11,
+            in_progress: vec![],
+        };
+        assert_eq!(btree.get_tuple_in(old, &after).unwrap(), None);
+        assert_eq!(
+            btree.get_tuple_in(new, &after).unwrap(),
+            Some(b"new".to_vec())
+        );
+
+        // Only vacuumed once no snapshot can see it anymore
+        assert!(!btree.vacuum_tuple(old, 8).unwrap());
+        assert_eq!(
+            btree.get_tuple_in(old, &before).unwrap(),
+            Some(b"old".to_vec())
+        );
+        assert!(btree.vacuum_tuple(old, 9).unwrap());
+        assert_eq!(btree.get_tuple_in(old, &before).unwrap(), None);
+        assert!(!btree.vacuum_tuple(new, 20).unwrap());
+    }
+}
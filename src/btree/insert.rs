@@ -1,33 +1,110 @@
+use super::internal_node::InternalNodeItemData;
 use super::internal_node::InternalNodeRead;
 use super::internal_node::InternalNodeWriteLock;
 use super::key::Key;
+use super::leaf_node::LeafNodeItemData;
+use super::node::check_item_size;
+use super::node::split_node_data;
+use super::node::NodeRead;
+use super::node::NodeWrite;
+use super::node::Separator;
+use super::span::OpSpan;
 use super::value::Value;
-use crate::btree::leaf_node::LeafNodeRead;
 use crate::btree::metadata_node::MetadataRead;
-use crate::btree::metadata_node::MetadataReadLock;
-use crate::btree::metadata_node::MetadataWriteLock;
+use crate::error::Error;
+use crate::error::Result;
 use crate::page::Item;
-use crate::page::Page;
+use crate::page::PageNo;
 use crate::page_fetcher::PageFetcher as PageFetcherTrait;
 use crate::page_fetcher::PagePtr;
 use log::debug;
-use std::ops::DerefMut;
 use std::sync::RwLockWriteGuard;
 
-impl<PageFetcher> super::BTree<PageFetcher>
+enum InsertOutcome<V> {
+    /// Inserted into the given leaf page.
+    Inserted(u32),
+    /// The key was already present with this value.
+    Existing(V),
+}
+
+impl<K, V, PageFetcher> super::BTree<K, V, PageFetcher>
 where
+    K: Key,
+    V: Value,
     PageFetcher: PageFetcherTrait,
 {
     /// Returns the leaf page number where it was inserted.
-    pub fn insert<K, V>(&mut self, key: K, value: V) -> u32
+    pub fn insert(&mut self, key: K, value: V) -> Result<u32> {
+        self.op_stats.inserts.inc();
+        let outcome = self
+            .insert_inner(key, false, || value)
+            .map_err(|err| err.context("insert"))?;
+        match outcome {
+            InsertOutcome::Inserted(leaf_node_no) => Ok(leaf_node_no),
+            InsertOutcome::Existing(_) => Err(Error::corruption(
+                "insert found an existing value without checking for one".to_string(),
+            )),
+        }
+    }
+
+    /// Inserts `value` unless `key` is already present, in which case the existing value is
+    /// returned and the tree is left untouched. The check and the insert happen under the same
+    /// leaf write lock, so there's no window for another writer to slip in between.
+    pub fn insert_if_absent(&mut self, key: K, value: V) -> Result<Option<V>> {
+        self.op_stats.inserts.inc();
+        let outcome = self
+            .insert_inner(key, true, || value)
+            .map_err(|err| err.context("insert"))?;
+        match outcome {
+            InsertOutcome::Inserted(_) => Ok(None),
+            InsertOutcome::Existing(existing) => Ok(Some(existing)),
+        }
+    }
+
+    /// Returns the value for `key`, inserting the one built by `make_value` first if there's none.
+    /// `make_value` is only called when the key is absent.
+    pub fn get_or_insert_with<F>(&mut self, key: K, make_value: F) -> Result<V>
     where
-        K: Key,
-        V: Value,
+        F: FnOnce() -> V,
     {
-        debug!("[insert] Begin insert {:?}, {:?}", key, value);
+        self.op_stats.inserts.inc();
+        let mut inserted = None;
+        let outcome = self
+            .insert_inner(key, true, || {
+                let value = make_value();
+                inserted = Some(value.clone());
+                value
+            })
+            .map_err(|err| err.context("insert"))?;
+
+        match (outcome, inserted) {
+            (InsertOutcome::Existing(existing), _) => Ok(existing),
+            (InsertOutcome::Inserted(_), Some(value)) => Ok(value),
+            (InsertOutcome::Inserted(_), None) => Err(Error::corruption(
+                "inserted without building a value".to_string(),
+            )),
+        }
+    }
+
+    /// Shared insert path. When `if_absent` is set, the target leaf is checked for `key` once it's
+    /// write locked, and `make_value` is never called if it's already there.
+    fn insert_inner<F>(
+        &mut self,
+        key: K,
+        if_absent: bool,
+        make_value: F,
+    ) -> Result<InsertOutcome<V>>
+    where
+        F: FnOnce() -> V,
+    {
+        debug!("[insert] Begin insert {:?}", key);
+        let _span = OpSpan::enter("insert", &key, self.page_fetcher.metrics());
+        // Keys too large to split a node around fail up front rather than leaving a full node
+        // that can't be split
+        check_item_size::<InternalNodeItemData<K>>(InternalNodeItemData::size_with_key(&key))?;
         let mut leaf_node_no = {
-            let metadata = MetadataReadLock::from(self.page_fetcher.fetch_page_read(0).unwrap());
-            let root_no_opt = metadata.root_no();
+            let metadata = self.metadata_read()?;
+            let root_no_opt = metadata.root_no()?;
 
             match root_no_opt {
                 Some(root_no) => root_no,
@@ -37,20 +114,21 @@ where
                     );
                     // Dropping read lock prior to acquiring the write lock
                     drop(metadata);
-                    let mut metadata_w =
-                        MetadataWriteLock::from(self.page_fetcher.fetch_page_write(0).unwrap());
-                    let root_no_opt = metadata_w.root_no();
+                    let mut metadata_w = self.metadata_write()?;
+                    let root_no_opt = metadata_w.root_no()?;
                     match root_no_opt {
                         Some(root_no) => root_no,
                         None => {
                             let (new_root_no, mut new_root_lock) =
-                                super::leaf_node::new_page::<_, K, V>(&self.page_fetcher, 0);
+                                super::leaf_node::new_page::<_, K, V>(
+                                    &self.page_fetcher,
+                                    PageNo::INVALID,
+                                )?;
 
-                            new_root_lock.set_separator(&K::max_key());
+                            new_root_lock.set_separator(&Separator::Unbounded)?;
 
-                            // TODO: Add better error messsage unstead of unwrapping
                             // TODO: Create a new Metadata wrapper struct
-                            metadata_w.set_root_no(new_root_no);
+                            metadata_w.set_root_no(new_root_no)?;
                             new_root_no
                         }
                     }
@@ -61,24 +139,31 @@ where
         // to start from the top of the tree (in the very rare case that the "previous" root had
         // split from the time we started this method call to the bottom of this method where we're
         // walking up the tree to split pages.
-        let mut traversed: Vec<u32> = vec![0];
+        let mut traversed: Vec<u32> = vec![self.metadata_no];
 
         loop {
             debug!("[insert.traverse_down] Begin loop: {})", leaf_node_no);
-            let current = self.page_fetcher.fetch_page_read(leaf_node_no).unwrap();
-            let special_data = current.special_data::<super::BTreePageData>();
-            match special_data.node_type {
-                super::NodeType::Metadata => {
-                    panic!("Somehow we encountered a metadata, this should never occur")
+            let current = self.page_fetcher.fetch_page_read(leaf_node_no)?;
+            let node_type = super::BTreePageData::checked_node_type(leaf_node_no, &current)?;
+            match node_type {
+                super::NodeType::Metadata
+                | super::NodeType::Overflow
+                | super::NodeType::Heap
+                | super::NodeType::FreeSpace => {
+                    return Err(Error::page_corruption(
+                        leaf_node_no,
+                        format!("encountered a {:?} page while traversing down", node_type),
+                    ));
                 }
                 super::NodeType::Internal => {
-                    let internal = super::internal_node::from_read_lock::<K>(leaf_node_no, current);
+                    let internal =
+                        super::internal_node::from_read_lock::<K>(leaf_node_no, current)?;
                     let (parent_node, child_node) =
                         super::internal_node::find_child_ptr_move_right_read_lock(
                             &self.page_fetcher,
                             internal,
-                            key,
-                        );
+                            &key,
+                        )?;
                     traversed.push(parent_node);
                     leaf_node_no = child_node;
                     debug!("[insert.traverse_down] Traversing to {}", child_node,);
@@ -97,35 +182,65 @@ where
         let mut leaf_lock = super::leaf_node::find_move_right::<PageFetcher, K, V>(
             &self.page_fetcher,
             leaf_node_no,
-            key,
-        );
+            &key,
+        )?;
+        // We may have moved right of the leaf we descended to
+        let leaf_node_no = leaf_lock.page_no;
 
-        let leaf_data = super::leaf_node::LeafNodeItemData { key, value };
-        match leaf_lock.add_item(&leaf_data) {
-            Ok(()) => {
-                return leaf_node_no;
+        if if_absent || V::tombstone().is_some() {
+            match leaf_lock.find_item(&key) {
+                // A deleted key's tombstone makes way for its new value
+                Some((_, existing)) if V::is_tombstone(existing.value_bytes()) => {
+                    leaf_lock.remove_item(&key);
+                }
+                Some((_, existing)) if if_absent => {
+                    return Ok(InsertOutcome::Existing(self.load_value(existing.value())?));
+                }
+                _ => {}
             }
-            Err(_err) => {
+        }
+
+        let leaf_data = LeafNodeItemData {
+            key,
+            value: self.store_value(make_value())?,
+        };
+        check_item_size::<LeafNodeItemData<K, V>>(leaf_data.size())?;
+        match leaf_lock.add_item_compacting(&leaf_data) {
+            Ok(()) => Ok(InsertOutcome::Inserted(leaf_node_no)),
+            Err(Error::PageFull) => {
                 // Not enough space to add item to this page, therefore we must split.
                 debug!(
                     "[insert] Not enough space to add, now we're splitting leaf page {}",
                     leaf_lock.page_no,
                 );
 
-                // First, we split the leaf node into a new sibling page
-                let prev_sibling_no = leaf_lock.special_data().right_sibling_page_no;
+                // The split may propagate all the way up and add a new root, and can't be undone
+                // half way, so we make sure there are pages for all of that before changing
+                // anything
+                self.page_fetcher.check_room(traversed.len() + 1)?;
+
+                // First, we split the leaf node into a new sibling page. The original page keeps
+                // the lower half of the items and the new sibling to its right takes the upper
+                // half along with the original separator.
+                let prev_sibling = leaf_lock.special_data().right_sibling();
                 let (new_sibling_no, mut new_sibling) =
                     super::leaf_node::new_page::<PageFetcher, K, V>(
                         &self.page_fetcher,
-                        prev_sibling_no,
-                    );
-                leaf_lock.special_data_mut().right_sibling_page_no = new_sibling_no;
+                        prev_sibling,
+                    )?;
+                leaf_lock.set_right_sibling_no(new_sibling_no);
+                self.page_fetcher.metrics().leaf_splits.inc();
+                self.op_stats.splits.inc();
+                self.notify(|listener| listener.on_split(leaf_node_no, new_sibling_no, true));
 
-                split_node_data_v2::<super::leaf_node::LeafNodeItemData<K, V>, K, _>(
-                    leaf_lock.page_ref_mut(),
-                    new_sibling.page_ref_mut(),
-                    |item| item.key,
-                );
+                // Leaf separators are exclusive upper bounds, so the original page's new
+                // separator is the first key that moved to the new sibling.
+                split_node_data(
+                    &mut leaf_lock,
+                    &mut new_sibling,
+                    &leaf_data,
+                    |_lower, upper| upper.key.clone(),
+                )?;
 
                 debug!(
                     "[insert] Splitted leaf pages: page_no={:?} sep={:?}, NEW page_no={:?} sep={:?}",
@@ -135,28 +250,28 @@ where
                     new_sibling.separator(),
                 );
 
-                let return_leaf_node_no: u32;
-                if key <= leaf_lock.separator() {
-                    return_leaf_node_no = leaf_node_no;
-                    leaf_lock.add_item(&leaf_data).unwrap();
+                let return_leaf_node_no = if leaf_lock.separator().is_above(&leaf_data.key) {
+                    leaf_lock.add_item(&leaf_data)?;
+                    leaf_node_no
                 } else {
-                    return_leaf_node_no = new_sibling_no;
-                    new_sibling.add_item(&leaf_data).unwrap();
-                }
+                    new_sibling.add_item(&leaf_data)?;
+                    new_sibling_no
+                };
+                #[cfg(debug_assertions)]
+                super::invariants::assert_split::<K, V, _, _>(&leaf_lock, &new_sibling, false);
 
                 // Then we begin the unwinding of the `traversed` stack to update the parent
                 // linkage
                 {
-                    let mut orig_child = super::internal_node::InternalNodeItemData {
+                    let mut orig_child = InternalNodeItemData {
                         page_no: leaf_node_no,
-                        key: leaf_lock.separator(),
+                        key: leaf_lock.separator().stored_key(),
                     };
-                    let mut new_child = super::internal_node::InternalNodeItemData {
+                    let mut new_child = InternalNodeItemData {
                         page_no: new_sibling_no,
-                        key: new_sibling.separator(),
+                        key: new_sibling.separator().stored_key(),
                     };
-                    #[allow(unused_variables)]
-                    let mut orig_child_lock: RwLockWriteGuard<PagePtr> = leaf_lock.into();
+                    let mut _orig_child_lock: RwLockWriteGuard<PagePtr> = leaf_lock.into();
 
                     let mut split = true;
 
@@ -166,206 +281,241 @@ where
 
                     // now, we traverse up the tree to update the pointers and see if we need to split
                     // any internal nodes.
-                    while split && traversed.len() > 0 {
-                        let parent_node_no = traversed.pop().unwrap();
+                    while split {
+                        let parent_node_no = match traversed.pop() {
+                            Some(parent_node_no) => parent_node_no,
+                            None => break,
+                        };
                         debug!(
                             "[insert.traverse_up] Begin loop: ORIG {:?}, NEW {:?}, parent_no: {}",
                             orig_child, new_child, parent_node_no,
                         );
 
-                        if parent_node_no == 0 {
-                            // in the scenario where we split the root, it's possible that the root had
-                            // already splitted prior to reaching this code. thus, we want to start at
-                            // the metadata page and traverse down until we find the root's parent (if
-                            // there is one)
+                        if parent_node_no == self.metadata_no {
+                            // `orig_child` was the root when we descended. It's still the root
+                            // unless another insert split it in the meantime, in which case we
+                            // look for its parent below, after releasing the metadata page so
+                            // other inserts reaching the top of the tree aren't held up.
                             debug!("[insert.traverse_up] Arrived at metadata, meaning the root had split");
-                            let mut metadata = MetadataWriteLock::from(
-                                self.page_fetcher.fetch_page_write(0).unwrap(),
-                            );
+                            let mut metadata = self.metadata_write()?;
 
-                            match metadata.root_no() {
+                            let root_no = match metadata.root_no()? {
                                 Some(root_no) if root_no == orig_child.page_no => {
                                     // we initialize a new root, have the two roots point to the two pages,
                                     // and update the metadata, and we're done
                                     let (new_root_no, mut new_root_lock) =
-                                        super::internal_node::new_page(&self.page_fetcher, 0);
+                                        super::internal_node::new_page(
+                                            &self.page_fetcher,
+                                            PageNo::INVALID,
+                                        )?;
 
                                     debug!(
                                         "[insert.traverse_up] Creating new root {}",
                                         new_root_no
                                     );
 
-                                    new_root_lock.set_separator(&K::max_key());
-                                    metadata.set_root_no(new_root_no);
-                                    new_root_lock.add_item(orig_child).unwrap();
-                                    new_root_lock.add_item(new_child).unwrap();
+                                    new_root_lock.set_separator(&Separator::Unbounded)?;
+                                    metadata.set_root_no(new_root_no)?;
+                                    new_root_lock.add_item(&orig_child)?;
+                                    new_root_lock.add_item(&new_child)?;
+                                    #[cfg(debug_assertions)]
+                                    super::invariants::assert_downlinks::<K, V>(
+                                        &new_root_lock,
+                                        None,
+                                        &orig_child,
+                                        &new_child,
+                                    );
+                                    self.notify(|listener| listener.on_new_root(new_root_no));
                                     split = false;
+                                    continue;
                                 }
-                                _ => {
-                                    debug!(
-                                        "[insert.traverse_up] Traversing down tree from metadata until we find the parent",
-                                    );
-                                    traversed.push(0);
-                                    let mut page_no = metadata.root_no().unwrap();
-
-                                    loop {
-                                        let page = super::internal_node::fetch_page_read::<
-                                            PageFetcher,
-                                            K,
-                                        >(
-                                            &self.page_fetcher, page_no
-                                        )
-                                        .unwrap();
-                                        let (candidate_no, downlink_no) =
-                                        super::internal_node::find_child_ptr_move_right_read_lock(
-                                            &self.page_fetcher,
-                                            page,
-                                            key,
-                                        );
-                                        if downlink_no == orig_child.page_no {
-                                            traversed.push(candidate_no);
-                                            break;
-                                        } else {
-                                            page_no = downlink_no;
-                                        }
-                                    }
-                                    split = true;
+                                Some(root_no) => root_no,
+                                None => {
+                                    return Err(Error::page_corruption(
+                                        self.metadata_no,
+                                        "metadata page lost its root while splitting",
+                                    ));
                                 }
-                            }
+                            };
+                            drop(metadata);
+
+                            debug!(
+                                "[insert.traverse_up] Traversing down tree from root {} until we find the parent",
+                                root_no
+                            );
+                            // Every node on the way down is recorded, so that if the parent splits
+                            // in turn, its own parent is found by moving right rather than by
+                            // coming back here
+                            traversed.push(self.metadata_no);
+                            traversed.extend(self.find_parent_path(
+                                root_no,
+                                &orig_child.key,
+                                &[orig_child.page_no, new_child.page_no],
+                            )?);
                         } else {
                             let mut parent =
                                 super::internal_node::find_node_with_entry_move_right_write_lock(
                                     &self.page_fetcher,
                                     parent_node_no,
                                     orig_child.page_no,
-                                );
+                                )?;
+                            let parent_node_no = parent.page_no();
 
-                            match update_child_ptr(
+                            let updated = update_child_ptr(
                                 &self.page_fetcher,
                                 &mut parent,
-                                orig_child,
-                                new_child,
-                            ) {
+                                orig_child.clone(),
+                                new_child.clone(),
+                            )?;
+                            #[cfg(debug_assertions)]
+                            super::invariants::assert_downlinks::<K, V>(
+                                &parent,
+                                updated.as_ref().map(|(_, new_parent)| new_parent),
+                                &orig_child,
+                                &new_child,
+                            );
+                            match updated {
                                 None => {
                                     split = false;
                                 }
-                                Some(_res) => {
-                                    orig_child = super::internal_node::InternalNodeItemData {
+                                Some((new_parent_no, new_parent)) => {
+                                    self.op_stats.splits.inc();
+                                    self.notify(|listener| {
+                                        listener.on_split(parent_node_no, new_parent_no, false)
+                                    });
+                                    orig_child = InternalNodeItemData {
                                         page_no: parent_node_no,
-                                        key: parent.separator(),
+                                        key: parent.separator().stored_key(),
                                     };
-                                    new_child = super::internal_node::InternalNodeItemData {
-                                        page_no: parent_node_no,
-                                        key: parent.separator(),
+                                    new_child = InternalNodeItemData {
+                                        page_no: new_parent_no,
+                                        key: new_parent.separator().stored_key(),
                                     };
-                                    orig_child_lock = parent.into();
+                                    _orig_child_lock = parent.into();
                                     split = true;
                                 }
                             };
                         }
                     }
 
-                    return_leaf_node_no
+                    Ok(InsertOutcome::Inserted(return_leaf_node_no))
                 }
             }
+            Err(err) => Err(err),
         }
     }
-}
 
-fn split_node_data_v2<I, S, F>(orig: &mut Page, new: &mut Page, separator_fn: F)
-where
-    I: Item + Ord,
-    S: Key,
-    F: Fn(&I) -> S,
-{
-    let separator = orig.get_item_v2::<S>(0);
-
-    let mut sorted_rev = orig.items_iter_v2::<I>().skip(1).collect::<Vec<_>>();
-    sorted_rev.sort();
-
-    // First, add separator to the `new` Page. It's always guaranteed to be the first item in the
-    // page.
-    new.add_item_v2(&separator).unwrap();
-
-    let item_data_size: usize = sorted_rev.iter().fold(0, |sum, i| sum + i.size());
-    let mut added: usize = 0;
-    let mut count: usize = 0;
-    for (i, item) in sorted_rev.iter().enumerate() {
-        // TODO: Make this not unwrap
-        new.add_item_v2(item).unwrap();
-        added += item.size();
-        if added > item_data_size / 2 {
-            count = i + 1;
-            break;
+    /// Descends from `root_no` towards `key` until reaching the internal node holding a downlink to
+    /// one of `child_nos`. Returns the page numbers of the internal nodes on the way, ending with
+    /// that parent.
+    fn find_parent_path(&self, root_no: u32, key: &K, child_nos: &[u32]) -> Result<Vec<u32>> {
+        let mut path = Vec::new();
+        let mut page_no = root_no;
+        loop {
+            let page = super::internal_node::fetch_page_read::<PageFetcher, K>(
+                &self.page_fetcher,
+                page_no,
+            )?;
+            let (candidate_no, downlink_no) =
+                super::internal_node::find_child_ptr_move_right_read_lock(
+                    &self.page_fetcher,
+                    page,
+                    key,
+                )?;
+            path.push(candidate_no);
+            if child_nos.contains(&downlink_no) {
+                return Ok(path);
+            }
+            page_no = downlink_no;
         }
     }
-
-    orig.zero_out_item_data();
-
-    let sep = separator_fn(sorted_rev.get(count).unwrap());
-    orig.add_item_v2(&sep).unwrap();
-
-    for item in sorted_rev.iter().skip(count) {
-        orig.add_item_v2(item).unwrap();
-    }
 }
 
+/// After `orig`'s page was split into `orig` and `new`, the downlink that used to point at
+/// `orig` (keyed by the old separator, which `new` took over) now points at `new`, and a fresh
+/// downlink for `orig` is added right before it. If that doesn't fit, `parent` is split and the
+/// new sibling returned so the caller can keep propagating the split upward.
+///
+/// `orig`'s downlink is placed by position rather than by key, since when `orig` kept the keys up
+/// to `max_key` and `new` is unbounded, both downlinks are keyed `max_key` and `new`'s must stay
+/// last.
 fn update_child_ptr<'a, P, K>(
     page_fetcher: &'a P,
     parent: &mut InternalNodeWriteLock<'a, K>,
-    orig: super::internal_node::InternalNodeItemData<K>,
-    new: super::internal_node::InternalNodeItemData<K>,
-) -> Option<(u32, InternalNodeWriteLock<'a, K>)>
+    orig: InternalNodeItemData<K>,
+    new: InternalNodeItemData<K>,
+) -> Result<Option<(u32, InternalNodeWriteLock<'a, K>)>>
 where
     P: PageFetcherTrait,
     K: Key,
 {
-    parent.update_item(&orig).unwrap();
+    let idx = parent.replace_downlink(orig.page_no, new.page_no)?;
 
-    match parent.add_item(new) {
-        Ok(()) => None,
-        Err(_err) => {
-            // TODO: Log + handle error
+    match parent.insert_item(idx, &orig) {
+        Ok(()) => Ok(None),
+        Err(Error::PageFull) => {
             let (new_sibling_no, mut new_sibling_lock) = super::internal_node::new_page(
                 page_fetcher,
-                parent.special_data().right_sibling_page_no,
-            );
+                parent.special_data().right_sibling(),
+            )?;
+            parent.set_right_sibling_no(new_sibling_no);
+            page_fetcher.metrics().internal_splits.inc();
 
-            split_node_data_v2::<super::internal_node::InternalNodeItemData<K>, _, _>(
-                parent.page_ref_mut(),
-                new_sibling_lock.page_ref_mut(),
-                |i| i.key,
-            );
+            // Internal node separators are the largest downlink key within the page
+            split_node_data(parent, &mut new_sibling_lock, &orig, |lower, _upper| {
+                lower.key.clone()
+            })?;
 
-            if new.key < parent.separator() {
-                parent.add_item(new).unwrap();
-            } else {
-                new_sibling_lock.add_item(new).unwrap();
+            // `orig` goes to whichever node `new`'s downlink ended up in
+            match parent.downlink_idx(new.page_no) {
+                Some(idx) => parent.insert_item(idx, &orig)?,
+                None => {
+                    let idx = new_sibling_lock.downlink_idx(new.page_no).ok_or_else(|| {
+                        Error::page_corruption(
+                            new_sibling_no,
+                            format!("no downlink to page {} after splitting", new.page_no),
+                        )
+                    })?;
+                    new_sibling_lock.insert_item(idx, &orig)?;
+                }
             }
 
-            Some((new_sibling_no, new_sibling_lock))
+            Ok(Some((new_sibling_no, new_sibling_lock)))
         }
+        Err(err) => Err(err),
     }
 }
 
 #[cfg(test)]
 mod tests {
+    use crate::btree::internal_node::InternalNodeItemData;
+    use crate::btree::key::Key;
+    use crate::btree::key::KeyBytes;
     use crate::btree::key::KeyU32;
     use crate::btree::leaf_node::LeafNodeItemData;
-    use crate::btree::leaf_node::LeafNodeRead;
     use crate::btree::leaf_node::LeafNodeReadLock;
     use crate::btree::metadata_node::MetadataRead;
-    use crate::btree::metadata_node::MetadataReadLock;
+    use crate::btree::node::NodeRead;
+    use crate::btree::value::ValueBytes;
     use crate::btree::value::ValueTupleId;
     use crate::btree::BTree;
     use crate::btree::BTreePageData;
     use crate::btree::NodeType;
+    use crate::error::Error;
+    use crate::error::Result;
+    use crate::metrics::Metrics;
     use crate::page::ITEM_POINTER_SIZE;
     use crate::page::PAGE_DATA_SIZE;
     use crate::page_fetcher::InMemoryPageFetcher;
     use crate::page_fetcher::PageFetcher;
+    use crate::page_fetcher::PagePtr;
     use log::debug;
+    use std::cell::Cell;
+    use std::cell::RefCell;
+    use std::convert::TryFrom;
     use std::mem::size_of;
+    use std::sync::RwLockReadGuard;
+    use std::sync::RwLockWriteGuard;
 
     #[test]
     fn no_root() {
@@ -386,10 +536,10 @@ mod tests {
             },
         );
 
-        assert_eq!(btree.insert(entry1.0, entry1.1), 1);
-        assert_eq!(btree.insert(entry2.0, entry2.1), 1);
-        let metadata = MetadataReadLock::from(btree.page_fetcher.fetch_page_read(0).unwrap());
-        assert_eq!(metadata.root_no(), Some(1));
+        assert_eq!(btree.insert(entry1.0, entry1.1).unwrap(), 1);
+        assert_eq!(btree.insert(entry2.0, entry2.1).unwrap(), 1);
+        let metadata = btree.metadata_read().unwrap();
+        assert_eq!(metadata.root_no().unwrap(), Some(1));
         let page = btree.page_fetcher.fetch_page_read(1).unwrap();
         assert_eq!(page.item_cnt(), 3); // 1 is separator, 2 are keys
                                         // let leaf = LeafNodeReadLock::<KeyU32, ValueTupleId>::from((1, page));
@@ -420,7 +570,7 @@ mod tests {
                 },
             );
 
-            assert_eq!(btree.insert(entry.0, entry.1), 1);
+            assert_eq!(btree.insert(entry.0, entry.1).unwrap(), 1);
         }
 
         let entry = (
@@ -433,16 +583,18 @@ mod tests {
             },
         );
 
-        assert_eq!(btree.insert(entry.0, entry.1), 2);
+        assert_eq!(btree.insert(entry.0, entry.1).unwrap(), 2);
 
-        let leaf1 = LeafNodeReadLock::<KeyU32, ValueTupleId>::from((
+        let leaf1 = LeafNodeReadLock::<KeyU32, ValueTupleId>::try_from((
             1,
             btree.page_fetcher.fetch_page_read(1).unwrap(),
-        ));
-        let leaf2 = LeafNodeReadLock::<KeyU32, ValueTupleId>::from((
+        ))
+        .unwrap();
+        let leaf2 = LeafNodeReadLock::<KeyU32, ValueTupleId>::try_from((
             2,
             btree.page_fetcher.fetch_page_read(2).unwrap(),
-        ));
+        ))
+        .unwrap();
 
         let mut items = leaf1.item_iter().collect::<Vec<_>>();
         items.extend(leaf2.item_iter());
@@ -456,25 +608,511 @@ mod tests {
     }
 
     #[test]
-    #[ignore]
-    fn multi_internal_level() {
-        todo!("TODO: Need to add this test!");
+    fn insert_if_absent() {
+        let mut btree = setup_btree();
+        let value = |page_no| ValueTupleId { page_no, offset: 0 };
+
+        for i in 0..1000u32 {
+            assert_eq!(
+                btree.insert_if_absent(KeyU32 { key: i }, value(i)).unwrap(),
+                None
+            );
+        }
+        for i in 0..1000u32 {
+            assert_eq!(
+                btree
+                    .insert_if_absent(KeyU32 { key: i }, value(i + 1))
+                    .unwrap(),
+                Some(value(i))
+            );
+        }
+
+        assert_eq!(
+            btree
+                .get_or_insert_with(KeyU32 { key: 5 }, || panic!("key 5 is present"))
+                .unwrap(),
+            value(5)
+        );
+        assert_eq!(
+            btree
+                .get_or_insert_with(KeyU32 { key: 5000 }, || value(42))
+                .unwrap(),
+            value(42)
+        );
+        assert_eq!(
+            btree.search(KeyU32 { key: 5000 }).unwrap().value,
+            Some(value(42))
+        );
     }
 
-    fn setup_btree() -> BTree<InMemoryPageFetcher> {
-        let page_fetcher = InMemoryPageFetcher::new();
+    #[test]
+    fn splits_keep_items_sorted() {
+        let value = |page_no| ValueTupleId { page_no, offset: 0 };
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(64)).unwrap();
+        for i in 0..20u32 {
+            btree.insert(KeyU32 { key: i * 999 }, value(i)).unwrap();
+        }
+
+        // Unsort the root leaf as if it had been written before items were kept sorted
         {
-            let (page_no, _lock) = page_fetcher.new_page(BTreePageData {
-                node_type: NodeType::Metadata,
-                right_sibling_page_no: 0,
-            });
-            assert_eq!(page_no, 0);
-            debug!("{:?}", page_fetcher.pages[0]);
-            debug!(
+            let mut page = btree.page_fetcher.fetch_page_write(1).unwrap();
+            page.special_data_mut::<BTreePageData>().flags = 0;
+            for _ in 0..10 {
+                let item = page.get_item_v2::<LeafNodeItemData<KeyU32, ValueTupleId>>(1);
+                page.remove_item_v2(1);
+                page.add_item_v2(&item).unwrap();
+            }
+        }
+
+        for i in 0..5000u32 {
+            let key = (i * 7919) % 5000 * 3 + 1;
+            btree.insert(KeyU32 { key }, value(key)).unwrap();
+        }
+
+        let report = btree.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.violations);
+        for page_no in 1..btree.page_fetcher.page_cnt() as u32 {
+            let page = btree.page_fetcher.fetch_page_read(page_no).unwrap();
+            let special_data = page.special_data::<BTreePageData>();
+            let keys = match special_data.node_type {
+                NodeType::Leaf => page
+                    .items_iter_from_v2::<LeafNodeItemData<KeyU32, ValueTupleId>>(1)
+                    .map(|item| item.key)
+                    .collect::<Vec<_>>(),
+                NodeType::Internal => page
+                    .items_iter_from_v2::<InternalNodeItemData<KeyU32>>(1)
+                    .map(|item| item.key)
+                    .collect::<Vec<_>>(),
+                _ => continue,
+            };
+            assert!(special_data.is_sorted(), "page {} isn't sorted", page_no);
+            assert!(
+                keys.windows(2).all(|pair| pair[0] < pair[1]),
+                "page {} is out of order",
+                page_no
+            );
+        }
+    }
+
+    #[test]
+    fn max_key() {
+        // Long keys, so that the tree grows a few levels
+        let key = |i: u32| KeyBytes {
+            key: format!("{:0200}", i).into_bytes(),
+        };
+        let value = |i: u32| ValueBytes {
+            value: i.to_le_bytes().to_vec(),
+        };
+        let max = KeyBytes::max_key();
+        let orders: [Vec<u32>; 3] = [
+            (0..2000).collect(),
+            (0..2000).rev().collect(),
+            (0..2000).map(|i| (i * 7919) % 2000).collect(),
+        ];
+        for keys in orders {
+            let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(1024)).unwrap();
+            // Inserted first, so that the nodes it's split off from get it as their separator
+            // while the downlinks to them share its key with the unbounded nodes' placeholders
+            btree.insert(max.clone(), value(u32::MAX)).unwrap();
+            for i in keys {
+                btree.insert(key(i), value(i)).unwrap();
+            }
+
+            let report = btree.verify().unwrap();
+            assert!(report.is_ok(), "{:?}", report.violations);
+            assert!(btree.analyze().unwrap().height() > 2);
+            assert_eq!(
+                btree.search(max.clone()).unwrap().value,
+                Some(value(u32::MAX))
+            );
+            for i in 0..2000 {
+                assert_eq!(btree.search(key(i)).unwrap().value, Some(value(i)));
+            }
+            assert_eq!(
+                btree.top_n(.., 1).unwrap(),
+                vec![(max.clone(), value(u32::MAX))]
+            );
+            let tail = btree
+                .range(key(1998)..)
+                .unwrap()
+                .map(|entry| entry.unwrap().0)
+                .collect::<Vec<_>>();
+            assert_eq!(tail, vec![key(1998), key(1999), max.clone()]);
+        }
+    }
+
+    #[test]
+    fn oversized_keys() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(1024)).unwrap();
+        let value = ValueBytes { value: Vec::new() };
+        for len in [3000, PAGE_DATA_SIZE] {
+            let key = KeyBytes::from(vec![1; len]);
+            let err = btree.insert(key, value.clone()).unwrap_err();
+            assert!(
+                matches!(err.root_cause(), Error::ItemTooLarge(_)),
                 "{:?}",
-                page_fetcher.pages[0].special_data::<BTreePageData>()
+                err
             );
         }
-        BTree { page_fetcher }
+        assert_eq!(btree.metadata_read().unwrap().root_no().unwrap(), None);
+
+        // The longest keys allowed still leave every node splittable
+        let key = |i: u8| KeyBytes::from([vec![i; 2600], vec![0; 100]].concat());
+        for i in 0..100 {
+            btree.insert(key(i), value.clone()).unwrap();
+        }
+        let report = btree.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.violations);
+        assert!(btree.analyze().unwrap().height() > 2);
+        for i in 0..100 {
+            assert_eq!(btree.search(key(i)).unwrap().value, Some(value.clone()));
+        }
+    }
+
+    #[test]
+    fn sequential_inserts_fill_leaves() {
+        let leaf_fill = |keys: Vec<u32>| {
+            let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(256)).unwrap();
+            for key in keys {
+                let value = ValueTupleId {
+                    page_no: key,
+                    offset: 0,
+                };
+                btree.insert(KeyU32 { key }, value).unwrap();
+            }
+            btree.verify().unwrap();
+            btree.analyze().unwrap().levels.pop().unwrap()
+        };
+
+        // Every leaf but the one still being filled is left full
+        for keys in [(0..20000).collect(), (0..20000).rev().collect()] {
+            let leaves = leaf_fill(keys);
+            assert!(leaves.fill_factors[1] > 0.99, "{:?}", leaves.fill_factors);
+        }
+        // Random inserts still split down the middle
+        let leaves = leaf_fill((0..20000).map(|i| i * 7919 % 20000).collect());
+        assert!(leaves.avg_fill() < 0.9, "{:?}", leaves.fill_factors);
+        assert!(leaves.fill_factors[0] > 0.4, "{:?}", leaves.fill_factors);
+    }
+
+    #[test]
+    fn find_parent_path() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(1024)).unwrap();
+        let key = |i: u32| KeyBytes {
+            key: format!("{:0300}", i).into_bytes(),
+        };
+        for i in 0..3000 {
+            let value = ValueBytes { value: vec![] };
+            btree.insert(key(i), value).unwrap();
+        }
+        let root_no = btree.metadata_read().unwrap().root_no().unwrap().unwrap();
+
+        let mut depths = Vec::new();
+        let mut next_leaf_no = btree.find_leaf_no(None).unwrap();
+        while let Some(leaf_no) = next_leaf_no {
+            let leaf = btree.page_fetcher.fetch_page_read(leaf_no).unwrap();
+            let first_key = leaf
+                .get_item_v2::<LeafNodeItemData<KeyBytes, ValueBytes>>(1)
+                .key;
+            let path = btree
+                .find_parent_path(root_no, &first_key, &[leaf_no])
+                .unwrap();
+            assert_eq!(path[0], root_no);
+            let parent = btree
+                .page_fetcher
+                .fetch_page_read(*path.last().unwrap())
+                .unwrap();
+            assert!(parent
+                .items_iter_from_v2::<InternalNodeItemData<KeyBytes>>(1)
+                .any(|downlink| downlink.page_no == leaf_no));
+
+            depths.push(path.len());
+            next_leaf_no = leaf.special_data::<BTreePageData>().right_sibling().get();
+        }
+        assert!(
+            depths[0] > 1,
+            "the tree should have several internal levels"
+        );
+        assert!(depths.iter().all(|depth| *depth == depths[0]));
+    }
+
+    #[test]
+    fn out_of_pages() {
+        let capacity = 40;
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(capacity)).unwrap();
+        let key = |i: u32| KeyBytes {
+            key: format!("{:0600}", i * 7919 % 1000).into_bytes(),
+        };
+
+        let mut inserted = Vec::new();
+        let err = loop {
+            let page_cnt = btree.page_fetcher.page_cnt();
+            let i = inserted.len() as u32;
+            match btree.insert(key(i), ValueBytes { value: vec![] }) {
+                Ok(_) => inserted.push(key(i)),
+                Err(err) => {
+                    // Nothing was allocated for a split that couldn't be finished
+                    assert_eq!(btree.page_fetcher.page_cnt(), page_cnt);
+                    break err;
+                }
+            }
+        };
+        assert!(matches!(err.root_cause(), Error::OutOfPages), "{}", err);
+        assert!(btree.page_fetcher.page_cnt() <= capacity);
+
+        // The tree is left as it was before the failed insert
+        let report = btree.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.violations);
+        for key in inserted.iter() {
+            assert!(btree.search(key.clone()).unwrap().value.is_some());
+        }
+        let failed = key(inserted.len() as u32);
+        assert!(btree.search(failed).unwrap().value.is_none());
+    }
+
+    type TallTree = BTree<KeyBytes, ValueBytes, InMemoryPageFetcher>;
+
+    /// Keys long enough that only a few fit on a page, so trees grow tall quickly. They sort by
+    /// `i`.
+    fn tall_key(i: u32) -> KeyBytes {
+        KeyBytes::from([format!("{:010}", i).into_bytes(), vec![b'.'; 1990]].concat())
+    }
+
+    /// Inserts the keys `next_key` returns for 0, 1, 2 and so on until the tree is `height` levels
+    /// tall, verifying it after every insert that split a node. Returns the tree and the keys in
+    /// the order they were inserted.
+    fn grow_tree<F: FnMut(u32) -> u32>(height: usize, mut next_key: F) -> (TallTree, Vec<u32>) {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(2048)).unwrap();
+        let mut inserted = Vec::new();
+        let mut current_height = 0;
+        while current_height < height {
+            let page_cnt = btree.page_fetcher.page_cnt();
+            let key = next_key(inserted.len() as u32);
+            btree
+                .insert(tall_key(key), ValueBytes { value: vec![] })
+                .unwrap();
+            inserted.push(key);
+            if btree.page_fetcher.page_cnt() > page_cnt {
+                let report = btree.verify().unwrap();
+                assert!(
+                    report.is_ok(),
+                    "after inserting {}: {:?}",
+                    key,
+                    report.violations
+                );
+                current_height = btree.analyze().unwrap().height();
+            }
+        }
+        (btree, inserted)
+    }
+
+    fn ascending(i: u32) -> u32 {
+        i
+    }
+
+    fn descending(i: u32) -> u32 {
+        u32::MAX - i
+    }
+
+    /// Multiplying by an odd number permutes the `u32`s.
+    fn scattered(i: u32) -> u32 {
+        i.wrapping_mul(0x9E37_79B1)
+    }
+
+    #[test]
+    fn multi_internal_level() {
+        let orders: [fn(u32) -> u32; 3] = [ascending, descending, scattered];
+        for order in orders {
+            // 4 internal levels above the leaves
+            let (btree, inserted) = grow_tree(5, order);
+            let stats = btree.analyze().unwrap();
+            assert_eq!(stats.levels[0].page_cnt, 1);
+            assert!(stats
+                .levels
+                .windows(2)
+                .all(|pair| pair[0].page_cnt < pair[1].page_cnt));
+            assert_eq!(stats.entry_cnt(), inserted.len());
+
+            for key in inserted.iter() {
+                assert!(btree.search(tall_key(*key)).unwrap().value.is_some());
+            }
+            let mut sorted = inserted.clone();
+            sorted.sort_unstable();
+            let scanned = btree
+                .range(..)
+                .unwrap()
+                .map(|entry| entry.unwrap().0)
+                .collect::<Vec<_>>();
+            assert!(scanned == sorted.into_iter().map(tall_key).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn root_split_while_splits_unwind() {
+        let orders: [fn(u32) -> u32; 3] = [ascending, descending, scattered];
+        for order in orders {
+            let (mut btree, inserted) = grow_tree(3, order);
+            let mut next = inserted.len() as u32;
+            let height = loop {
+                let height = btree.analyze().unwrap().height();
+                let page_cnt = btree.page_fetcher.page_cnt();
+                btree
+                    .insert(tall_key(order(next)), ValueBytes { value: vec![] })
+                    .unwrap();
+                next += 1;
+                if btree.analyze().unwrap().height() > height {
+                    // The leaf split, and so did every internal node above it, the root last,
+                    // which took a new root above it
+                    assert_eq!(btree.page_fetcher.page_cnt(), page_cnt + height + 1);
+                    break height + 1;
+                }
+            };
+            assert_eq!(height, 4);
+            let report = btree.verify().unwrap();
+            assert!(report.is_ok(), "{:?}", report.violations);
+            let stats = btree.analyze().unwrap();
+            assert_eq!(stats.levels[0].item_cnt, 2);
+            assert!(btree
+                .search(tall_key(order(next - 1)))
+                .unwrap()
+                .value
+                .is_some());
+        }
+    }
+
+    /// Passes calls through to `inner`, except that the next write lock taken after `hook` is set
+    /// first runs the hook, standing in for another insert that got in between, and that write
+    /// locks of the metadata page are counted. Fetchers aren't `Sync`, so inserts racing each
+    /// other are interleaved this way rather than run on threads.
+    struct Interleaved<'a> {
+        inner: &'a InMemoryPageFetcher,
+        hook: RefCell<Option<Box<dyn FnOnce() + 'a>>>,
+        metadata_writes: Cell<usize>,
+    }
+
+    impl PageFetcher for Interleaved<'_> {
+        fn fetch_page_read(&self, page_no: u32) -> Result<RwLockReadGuard<'_, PagePtr>> {
+            self.inner.fetch_page_read(page_no)
+        }
+
+        fn fetch_page_write(&self, page_no: u32) -> Result<RwLockWriteGuard<'_, PagePtr>> {
+            let hook = self.hook.borrow_mut().take();
+            if let Some(hook) = hook {
+                hook();
+            }
+            if page_no == 0 {
+                self.metadata_writes.set(self.metadata_writes.get() + 1);
+            }
+            self.inner.fetch_page_write(page_no)
+        }
+
+        fn new_page<T: Sized>(
+            &self,
+            special_data: T,
+        ) -> Result<(u32, RwLockWriteGuard<'_, PagePtr>)> {
+            self.inner.new_page(special_data)
+        }
+
+        fn free_page(&self, page_no: u32) -> Result<()> {
+            self.inner.free_page(page_no)
+        }
+
+        fn page_cnt(&self) -> usize {
+            self.inner.page_cnt()
+        }
+
+        fn metrics(&self) -> &Metrics {
+            self.inner.metrics()
+        }
+    }
+
+    /// The leaf and internal splits inserting `key` into a copy of the tree in `pages` takes, and
+    /// the copy's height afterwards.
+    fn splits_to_insert(pages: &InMemoryPageFetcher, key: KeyBytes) -> (u64, u64, usize) {
+        let copy = InMemoryPageFetcher::with_capacity(pages.page_cnt() + 8);
+        for page_no in 0..pages.page_cnt() {
+            copy.push_page(&pages.fetch_page_read(page_no as u32).unwrap())
+                .unwrap();
+        }
+        copy.rebuild_free_list().unwrap();
+        let mut btree = TallTree::open(copy, 0).unwrap();
+        btree.insert(key, ValueBytes { value: vec![] }).unwrap();
+        let metrics = btree.page_fetcher.metrics();
+        let height = btree.analyze().unwrap().height();
+        (
+            metrics.leaf_splits.get(),
+            metrics.internal_splits.get(),
+            height,
+        )
+    }
+
+    #[test]
+    fn root_split_by_another_insert() {
+        let pages = InMemoryPageFetcher::with_capacity(2048);
+        let inserted = RefCell::new(vec![0]);
+        let splits = |metrics: &Metrics| (metrics.leaf_splits.get(), metrics.internal_splits.get());
+        let splits_before = Cell::new((0, 0));
+        let interleaved = Interleaved {
+            inner: &pages,
+            hook: RefCell::new(None),
+            metadata_writes: Cell::new(0),
+        };
+        let mut btree = BTree::new(interleaved).unwrap();
+        btree
+            .insert(tall_key(0), ValueBytes { value: vec![] })
+            .unwrap();
+        let key = tall_key(u32::MAX / 2);
+
+        // Once this insert has read the root, a leaf, another one splits it twice over, and stops
+        // once the leaf this insert lands on and that leaf's parent are both full
+        let mut other: BTree<KeyBytes, ValueBytes, _> = BTree::open(&pages, 0).unwrap();
+        let (pages_ref, inserted_ref, splits_before_ref) = (&pages, &inserted, &splits_before);
+        let other_key = key.clone();
+        let hook = Box::new(move || {
+            for i in 1.. {
+                other
+                    .insert(tall_key(scattered(i)), ValueBytes { value: vec![] })
+                    .unwrap();
+                inserted_ref.borrow_mut().push(scattered(i));
+                if other.analyze().unwrap().height() == 3
+                    && splits_to_insert(pages_ref, other_key.clone()) == (1, 1, 3)
+                {
+                    break;
+                }
+                assert!(i < 5000, "the tree never lined up for the insert");
+            }
+            splits_before_ref.set(splits(pages_ref.metrics()));
+        });
+        *btree.page_fetcher.hook.borrow_mut() = Some(hook);
+        btree.page_fetcher.metadata_writes.set(0);
+        btree
+            .insert(key.clone(), ValueBytes { value: vec![] })
+            .unwrap();
+
+        // The insert found the leaf's new parent by descending from the new root, and that
+        // parent's own parent on the way down, rather than going back to the metadata page for it
+        assert_eq!(btree.page_fetcher.metadata_writes.get(), 1);
+        let (leaf_splits, internal_splits) = splits_before.get();
+        assert_eq!(
+            splits(pages.metrics()),
+            (leaf_splits + 1, internal_splits + 1)
+        );
+        let report = btree.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.violations);
+        assert_eq!(btree.analyze().unwrap().height(), 3);
+        assert!(btree.search(key).unwrap().value.is_some());
+        for i in inserted.borrow().iter() {
+            assert!(btree.search(tall_key(*i)).unwrap().value.is_some());
+        }
+    }
+
+    fn setup_btree() -> BTree<KeyU32, ValueTupleId, InMemoryPageFetcher> {
+        let btree = BTree::new(InMemoryPageFetcher::new()).unwrap();
+        {
+            let page = btree.page_fetcher.fetch_page_read(0).unwrap();
+            debug!("{:?}", page.header);
+            debug!("{:?}", page.special_data::<BTreePageData>());
+        }
+        btree
     }
 }
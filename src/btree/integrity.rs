@@ -0,0 +1,214 @@
+//! A structured account of a tree's broken invariants, for tools that act on them rather than
+//! just report whether there are any: the CLI's `check` command and recovery tooling. Built from
+//! `BTree::verify`'s findings, with each one's offending keys pulled out and a suggested
+//! remediation attached.
+
+use super::key::Key;
+use super::value::Value;
+use super::verify::VerifyReport;
+use super::verify::Violation;
+use super::verify::ViolationKind;
+use crate::error::Result;
+use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use std::fmt;
+
+/// The outcome of `BTree::check_invariants`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct IntegrityReport {
+    /// Pages reached through downlinks from the root.
+    pub pages_checked: usize,
+    pub issues: Vec<Issue>,
+}
+
+impl IntegrityReport {
+    pub fn is_ok(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// The distinct remediations suggested by the issues, most drastic first. Following the first
+    /// one addresses every issue.
+    pub fn remediations(&self) -> Vec<Remediation> {
+        let mut remediations = self
+            .issues
+            .iter()
+            .map(|issue| issue.remediation)
+            .collect::<Vec<_>>();
+        remediations.sort_unstable();
+        remediations.dedup();
+        remediations
+    }
+}
+
+impl From<VerifyReport> for IntegrityReport {
+    fn from(report: VerifyReport) -> Self {
+        IntegrityReport {
+            pages_checked: report.pages_checked,
+            issues: report.violations.into_iter().map(Issue::from).collect(),
+        }
+    }
+}
+
+/// A broken invariant found on page `page_no`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Issue {
+    pub page_no: u32,
+    pub kind: ViolationKind,
+    /// The keys that break the invariant, through their `Debug` representation. Empty for
+    /// invariants about the page as a whole.
+    pub keys: Vec<String>,
+    pub remediation: Remediation,
+}
+
+impl From<Violation> for Issue {
+    fn from(violation: Violation) -> Self {
+        let keys = match &violation.kind {
+            ViolationKind::SeparatorMismatch { found, .. } => vec![found.clone()],
+            ViolationKind::KeyOutOfRange(key) | ViolationKind::DuplicateKey(key) => {
+                vec![key.clone()]
+            }
+            _ => Vec::new(),
+        };
+        let remediation = match &violation.kind {
+            ViolationKind::Unreadable(_)
+            | ViolationKind::BadLayout(_)
+            | ViolationKind::WrongNodeType(_) => Remediation::RestoreFromBackup,
+            _ => Remediation::Rebuild,
+        };
+        Issue {
+            page_no: violation.page_no,
+            kind: violation.kind,
+            keys,
+            remediation,
+        }
+    }
+}
+
+impl fmt::Display for Issue {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let violation = Violation {
+            page_no: self.page_no,
+            kind: self.kind.clone(),
+        };
+        write!(f, "{}", violation)
+    }
+}
+
+/// What to do about an issue. Ordered from most to least drastic.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum Remediation {
+    /// The page's entries can't be read back, so they're lost from this copy of the tree. Restore
+    /// it from a backup or snapshot, or salvage the rest of the entries with a rebuild.
+    RestoreFromBackup,
+    /// The entries are intact but the tree's structure is damaged, so lookups and scans may miss
+    /// or repeat some of them. Dump the entries and load them into a new tree, see
+    /// `Database::dump` and `Database::load`.
+    Rebuild,
+}
+
+impl fmt::Display for Remediation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Remediation::RestoreFromBackup => write!(
+                f,
+                "restore from a backup, or dump and load into a new database to salvage the \
+                 remaining entries"
+            ),
+            Remediation::Rebuild => write!(f, "dump and load into a new database"),
+        }
+    }
+}
+
+impl<K, V, PageFetcher> super::BTree<K, V, PageFetcher>
+where
+    K: Key,
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Checks the same invariants as `verify`, reporting each violation with its offending keys
+    /// and a suggested remediation.
+    pub fn check_invariants(&self) -> Result<IntegrityReport> {
+        self.verify().map(IntegrityReport::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Remediation;
+    use crate::btree::key::KeyU32;
+    use crate::btree::leaf_node::LeafNodeItemData;
+    use crate::btree::value::ValueTupleId;
+    use crate::btree::verify::ViolationKind;
+    use crate::btree::BTree;
+    use crate::btree::BTreePageData;
+    use crate::btree::NodeType;
+    use crate::page_fetcher::InMemoryPageFetcher;
+    use crate::page_fetcher::PageFetcher;
+
+    #[test]
+    fn issues_carry_keys_and_remediations() {
+        let value = ValueTupleId {
+            page_no: 0,
+            offset: 0,
+        };
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(64)).unwrap();
+        for key in 0..2000 {
+            btree.insert(KeyU32 { key }, value).unwrap();
+        }
+        let report = btree.check_invariants().unwrap();
+        assert!(report.is_ok(), "{:?}", report.issues);
+        assert!(report.remediations().is_empty());
+
+        let mut leaves = Vec::new();
+        let mut next_page_no = btree.find_leaf_no(None).unwrap();
+        while let Some(page_no) = next_page_no {
+            leaves.push(page_no);
+            let page = btree.page_fetcher.fetch_page_read(page_no).unwrap();
+            next_page_no = page.special_data::<BTreePageData>().right_sibling().get();
+        }
+        // Sneak a key into a leaf whose range doesn't cover it
+        btree
+            .page_fetcher
+            .fetch_page_write(leaves[1])
+            .unwrap()
+            .add_item_v2(&LeafNodeItemData {
+                key: KeyU32 { key: 100000 },
+                value,
+            })
+            .unwrap();
+        let report = btree.check_invariants().unwrap();
+        let issue = report
+            .issues
+            .iter()
+            .find(|issue| issue.page_no == leaves[1])
+            .unwrap();
+        assert_eq!(
+            issue.kind,
+            ViolationKind::KeyOutOfRange("KeyU32 { key: 100000 }".to_string())
+        );
+        assert_eq!(issue.keys, ["KeyU32 { key: 100000 }"]);
+        assert_eq!(issue.remediation, Remediation::Rebuild);
+        assert!(issue
+            .to_string()
+            .starts_with(&format!("page {}: ", leaves[1])));
+        assert_eq!(report.remediations(), [Remediation::Rebuild]);
+
+        btree
+            .page_fetcher
+            .fetch_page_write(leaves[2])
+            .unwrap()
+            .special_data_mut::<BTreePageData>()
+            .node_type = NodeType::Overflow;
+        let report = btree.check_invariants().unwrap();
+        let issue = report
+            .issues
+            .iter()
+            .find(|issue| issue.page_no == leaves[2])
+            .unwrap();
+        assert!(issue.keys.is_empty());
+        assert_eq!(issue.remediation, Remediation::RestoreFromBackup);
+        assert_eq!(
+            report.remediations(),
+            [Remediation::RestoreFromBackup, Remediation::Rebuild]
+        );
+    }
+}
@@ -1,15 +1,20 @@
 use super::key::Key;
+use super::node::NodeItem;
+use super::node::NodeRead;
+use super::node::NodeWrite;
+use super::node::Separator;
 use super::BTreePageData;
 use super::NodeType;
 use crate::btree::PageFetcherTrait;
+use crate::error::Error;
+use crate::error::Result;
 use crate::mem::align_offset;
 use crate::page::Item;
 use crate::page::Page;
-use crate::page::PageItemIteratorV2;
+use crate::page::PageNo;
 use crate::page_fetcher::PagePtr;
-use core::marker::PhantomData;
 use log::debug;
-use std::iter::Skip;
+use std::cell::OnceCell;
 use std::mem::align_of;
 use std::mem::size_of;
 use std::ops::Deref;
@@ -17,7 +22,7 @@ use std::ops::DerefMut;
 use std::sync::RwLockReadGuard;
 use std::sync::RwLockWriteGuard;
 
-#[derive(Copy, Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Debug)]
 pub(super) struct InternalNodeItemData<K>
 where
     K: Key,
@@ -32,26 +37,7 @@ where
     K: Key,
 {
     fn size(&self) -> usize {
-        if Self::is_fixed_size() {
-            size_of::<Self>()
-        } else {
-            // Unfortunately when we have dynamic width, we have 6 byte overhead.
-            // TODO: Save 2 bytes in scenarios when either K or V is fixed size.
-            let mut size: usize = 0;
-
-            // key
-            size += self.key.size();
-            size = align_offset(size, align_of::<u32>());
-
-            // page_no (u32)
-            size += size_of::<u32>();
-            size = align_offset(size, align_of::<u16>());
-
-            // u16 representing size of key
-            // u16 representing offset for value
-            size += 2 * size_of::<u16>();
-            size
-        }
+        Self::size_with_key(&self.key)
     }
 
     fn is_fixed_size() -> bool {
@@ -59,44 +45,47 @@ where
     }
 
     fn align() -> usize {
-        if Self::is_fixed_size() {
-            std::mem::align_of::<Self>()
-        } else {
-            std::cmp::max(K::align(), std::mem::align_of::<u32>())
-        }
+        std::cmp::max(K::align(), align_of::<u32>())
     }
 
-    unsafe fn write(&self, buffer: *mut u8) {
+    fn write(&self, buffer: &mut [u8]) {
         if Self::is_fixed_size() {
-            *(buffer as *mut Self) = self.clone();
+            let (page_no_offset, _) = Self::fixed_layout();
+            buffer.fill(0);
+            self.key.write(&mut buffer[..K::fixed_size()]);
+            write_u32_le(&mut buffer[page_no_offset..], self.page_no);
         } else {
             // key
-            self.key.write(buffer);
+            self.key.write(&mut buffer[..self.key.size()]);
 
             // value
             let mut value_offset: usize = 0;
             value_offset += self.key.size();
             value_offset = align_offset(value_offset, align_of::<u32>());
-            *(buffer as *mut u32) = self.page_no;
+            write_u32_le(&mut buffer[value_offset..], self.page_no);
 
             // key size
             let mut size_offset = value_offset;
             size_offset += size_of::<u32>();
             size_offset = align_offset(size_offset, align_of::<u16>());
-            let size_ptr = buffer.offset(size_offset as isize) as *mut u16;
-
-            *size_ptr = self.key.size() as u16;
-            *(size_ptr.offset(1)) = value_offset as u16;
+            let sizes = [self.key.size(), value_offset];
+            for (idx, size) in sizes.iter().enumerate() {
+                let at = size_offset + 2 * idx;
+                buffer[at..at + 2].copy_from_slice(&(*size as u16).to_le_bytes());
+            }
         }
     }
 
-    unsafe fn read(buffer: *const u8, size: usize) -> Self {
+    fn read(bytes: &[u8]) -> Self {
         if Self::is_fixed_size() {
-            (buffer as *mut Self).read()
+            let (page_no_offset, _) = Self::fixed_layout();
+            Self {
+                key: K::read(&bytes[..K::fixed_size()]),
+                page_no: read_u32_le(&bytes[page_no_offset..]),
+            }
         } else {
-            let size_ptr = buffer.offset((size - 3 * size_of::<u16>()) as isize) as *mut u16;
-            let key_size = *size_ptr;
-            let value_offset = *size_ptr.offset(1);
+            let (key_size, value_offset) = Self::dynamic_layout(bytes)
+                .unwrap_or_else(|err| panic!("InternalNodeItemData.read: {}", err));
 
             debug!(
                 "InternalNodeRead.read: key_size: {}, value_offset: {}",
@@ -104,66 +93,196 @@ where
             );
 
             Self {
-                key: K::read(buffer, key_size as usize),
-                page_no: *(buffer.offset(value_offset as isize) as *const u32),
+                key: K::read(&bytes[..key_size]),
+                page_no: read_u32_le(&bytes[value_offset..]),
             }
         }
     }
 }
 
-pub(super) trait InternalNodeRead<K>
+impl<K> InternalNodeItemData<K>
 where
     K: Key,
 {
-    fn page_ref(&self) -> &Page;
-    fn page_no(&self) -> u32;
-
-    /*
-    fn internal_node_has_child_ptr(&self, child_page_no: u32) -> bool {
-        let special_data = self.page_ref().special_data::<BTreePageData>();
-        assert!(
-            matches!(special_data.node_type, NodeType::Internal),
-            "Only internal nodes should be used"
-        );
-
-        self.page_ref()
-            .items_itr_typed::<InternalNodeItemData<K>>()
-            .any(|i| i.to_data_ref().page_no == child_page_no)
+    /// The size of a downlink keyed with `key`, without needing to build one.
+    pub fn size_with_key(key: &K) -> usize {
+        if Self::is_fixed_size() {
+            Self::fixed_layout().1
+        } else {
+            // Unfortunately when we have dynamic width, we have 6 byte overhead.
+            // TODO: Save 2 bytes in scenarios when either K or V is fixed size.
+            let mut size: usize = 0;
+
+            // key
+            size += key.size();
+            size = align_offset(size, align_of::<u32>());
+
+            // page_no (u32)
+            size += size_of::<u32>();
+            size = align_offset(size, align_of::<u16>());
+
+            // u16 representing size of key
+            // u16 representing offset for value
+            size += 2 * size_of::<u16>();
+            size
+        }
+    }
+
+    /// The `(page number offset, size)` of a fixed size item, which holds the key followed by the
+    /// little-endian page number, padded with zeroes to the item's alignment.
+    fn fixed_layout() -> (usize, usize) {
+        let page_no_offset = align_offset(K::fixed_size(), align_of::<u32>());
+        let size = align_offset(page_no_offset + size_of::<u32>(), Self::align());
+        (page_no_offset, size)
     }
-    */
 
-    fn item_iter(&self) -> Skip<PageItemIteratorV2<InternalNodeItemData<K>>> {
-        // We skip the first element, because it's always the separator
-        self.page_ref()
-            .items_iter_v2::<InternalNodeItemData<K>>()
-            .skip(1)
+    /// Decodes the `(key size, page number offset)` trailer of a dynamically sized item, see
+    /// `LeafNodeItemData::dynamic_layout`.
+    fn dynamic_layout(bytes: &[u8]) -> Result<(usize, usize)> {
+        let malformed = || {
+            Error::corruption(format!(
+                "malformed {} byte internal item {:?}",
+                bytes.len(),
+                &bytes[bytes.len().saturating_sub(2 * size_of::<u16>())..]
+            ))
+        };
+
+        let trailer = bytes
+            .len()
+            .checked_sub(2 * size_of::<u16>())
+            .ok_or_else(malformed)?;
+        let read_u16 = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]) as usize;
+        let (key_size, value_offset) = (read_u16(trailer), read_u16(trailer + 2));
+
+        if key_size > trailer
+            || value_offset + size_of::<u32>() > trailer
+            || (K::is_fixed_size() && key_size != K::fixed_size())
+        {
+            return Err(malformed());
+        }
+        Ok((key_size, value_offset))
     }
+}
 
-    fn separator(&self) -> K {
-        self.page_ref()
-            .get_item_v2::<InternalNodeItemData<K>>(0)
-            .key
+fn write_u32_le(buffer: &mut [u8], value: u32) {
+    buffer[..size_of::<u32>()].copy_from_slice(&value.to_le_bytes());
+}
+
+fn read_u32_le(bytes: &[u8]) -> u32 {
+    let mut value = [0u8; 4];
+    value.copy_from_slice(&bytes[..size_of::<u32>()]);
+    u32::from_le_bytes(value)
+}
+
+impl<K> NodeItem for InternalNodeItemData<K>
+where
+    K: Key,
+{
+    type Key = K;
+
+    fn key(&self) -> &K {
+        &self.key
+    }
+
+    fn read_key(bytes: &[u8]) -> K {
+        let key_size = match K::is_fixed_size() {
+            true => K::fixed_size(),
+            false => {
+                Self::dynamic_layout(bytes)
+                    .unwrap_or_else(|err| panic!("InternalNodeItemData.read_key: {}", err))
+                    .0
+            }
+        };
+        K::read(&bytes[..key_size])
+    }
+
+    fn check_encoding(bytes: &[u8]) -> Result<()> {
+        if Self::is_fixed_size() {
+            let (_, size) = Self::fixed_layout();
+            if bytes.len() != size {
+                return Err(Error::corruption(format!(
+                    "{} byte internal item, expected {} bytes",
+                    bytes.len(),
+                    size
+                )));
+            }
+            return Ok(());
+        }
+        Self::dynamic_layout(bytes).map(|_| ())
     }
+}
+
+/// Downlink lookups on top of `NodeRead`.
+pub(super) trait InternalNodeRead<K>: NodeRead<InternalNodeItemData<K>>
+where
+    K: Key,
+{
+    /// Finds the downlink with the smallest key strictly greater than `key`, or the last downlink
+    /// of an unbounded node for keys at or above every downlink key.
+    fn find_child_ptr(&self, key: &K) -> Option<u32> {
+        let unbounded = matches!(self.separator(), Separator::Unbounded);
+        if self.special_data().is_sorted() {
+            let item_cnt = self.page_ref().item_cnt();
+            let idx = match self.upper_bound(key) {
+                idx if idx == item_cnt && unbounded => item_cnt - 1,
+                idx => idx,
+            };
+            return (idx > 0 && idx < item_cnt).then(|| self.downlink(idx).page_no);
+        }
 
-    fn find_child_ptr(&self, key: K) -> Option<u32> {
-        let mut child_ptr: u32 = 0;
-        let mut child_key: K = K::max_key();
+        let mut child: Option<InternalNodeItemData<K>> = None;
         for key_ptr in self.item_iter() {
-            if key < key_ptr.key && key_ptr.key < child_key {
-                child_ptr = key_ptr.page_no;
-                child_key = key_ptr.key;
+            if *key < key_ptr.key && child.as_ref().is_none_or(|c| key_ptr.key < c.key) {
+                child = Some(key_ptr);
             }
         }
+        if child.is_none() && unbounded {
+            // The placeholder is the last of the downlinks with the largest key
+            child = self.item_iter().max_by(|x, y| x.key.cmp(&y.key));
+        }
 
-        if child_ptr != 0 {
-            Some(child_ptr)
-        } else {
-            None
+        child.map(|c| c.page_no)
+    }
+
+    /// The downlinks in key order, along with the separators of the children they point at, i.e.
+    /// their keys except for the last downlink of an unbounded node.
+    fn downlink_bounds(&self) -> Vec<(Separator<K>, u32)> {
+        let mut downlinks = self.item_iter().collect::<Vec<_>>();
+        if !self.special_data().is_sorted() {
+            downlinks.sort_by(|x, y| x.key.cmp(&y.key));
+        }
+        let unbounded = matches!(self.separator(), Separator::Unbounded);
+        let cnt = downlinks.len();
+        downlinks
+            .into_iter()
+            .enumerate()
+            .map(|(idx, downlink)| match unbounded && idx + 1 == cnt {
+                true => (Separator::Unbounded, downlink.page_no),
+                false => (Separator::Key(downlink.key), downlink.page_no),
+            })
+            .collect()
+    }
+
+    /// The downlink with the smallest key, i.e. the leftmost child.
+    fn first_child_ptr(&self) -> Option<u32> {
+        if self.special_data().is_sorted() {
+            return (self.page_ref().item_cnt() > 1).then(|| self.downlink(1).page_no);
         }
+
+        self.item_iter()
+            .min_by(|x, y| x.key.cmp(&y.key))
+            .map(|c| c.page_no)
     }
 
-    fn special_data(&self) -> &super::BTreePageData {
-        self.page_ref().special_data()
+    /// The index of the downlink to `child_no`.
+    fn downlink_idx(&self, child_no: u32) -> Option<usize> {
+        self.item_iter()
+            .position(|downlink| downlink.page_no == child_no)
+            .map(|idx| idx + 1)
+    }
+
+    fn downlink(&self, idx: usize) -> InternalNodeItemData<K> {
+        self.page_ref().get_item_v2(idx)
     }
 }
 
@@ -173,10 +292,10 @@ where
 {
     page_no: u32,
     page: RwLockReadGuard<'a, PagePtr>,
-    phantom: PhantomData<K>,
+    separator: OnceCell<Separator<K>>,
 }
 
-impl<'a, K> InternalNodeRead<K> for InternalNodeReadLock<'a, K>
+impl<'a, K> NodeRead<InternalNodeItemData<K>> for InternalNodeReadLock<'a, K>
 where
     K: Key,
 {
@@ -188,18 +307,24 @@ where
     fn page_no(&self) -> u32 {
         self.page_no
     }
+
+    fn separator_cache(&self) -> &OnceCell<Separator<K>> {
+        &self.separator
+    }
 }
 
+impl<'a, K> InternalNodeRead<K> for InternalNodeReadLock<'a, K> where K: Key {}
+
 pub(super) struct InternalNodeWriteLock<'a, K>
 where
     K: Key,
 {
     page_no: u32,
     page: RwLockWriteGuard<'a, PagePtr>,
-    phantom: PhantomData<K>,
+    separator: OnceCell<Separator<K>>,
 }
 
-impl<'a, K> InternalNodeRead<K> for InternalNodeWriteLock<'a, K>
+impl<'a, K> NodeRead<InternalNodeItemData<K>> for InternalNodeWriteLock<'a, K>
 where
     K: Key,
 {
@@ -211,238 +336,221 @@ where
     fn page_no(&self) -> u32 {
         self.page_no
     }
+
+    fn separator_cache(&self) -> &OnceCell<Separator<K>> {
+        &self.separator
+    }
 }
 
-impl<'a, K> InternalNodeWriteLock<'a, K>
+impl<'a, K> InternalNodeRead<K> for InternalNodeWriteLock<'a, K> where K: Key {}
+
+impl<'a, K> NodeWrite<InternalNodeItemData<K>> for InternalNodeWriteLock<'a, K>
 where
     K: Key,
 {
-    pub fn page_ref_mut(&mut self) -> &mut Page {
+    fn page_ref_mut(&mut self) -> &mut Page {
         self.page.deref_mut()
     }
 
-    pub fn add_item(&mut self, item: InternalNodeItemData<K>) -> Result<(), &'static str> {
-        if item.key > self.separator() {
-            return Err(
-                "We can't add due to item not fitting within this page's allowed key range",
-            );
-        }
-
-        self.page.add_item_v2(&item)
+    fn separator_cache_mut(&mut self) -> &mut OnceCell<Separator<K>> {
+        &mut self.separator
     }
+}
 
-    pub fn update_item(&mut self, item: &InternalNodeItemData<K>) -> Result<(), &'static str> {
-        let (idx, cur) = self
-            .item_iter()
-            .enumerate()
-            .find(|(_idx, i)| i.page_no == item.page_no)
-            .unwrap();
-
-        if cur == *item {
-            return Ok(());
-        }
-
-        // Note that the idx above "skips" the  the underlying page's first
-        // item, which is reserved for the page's separator value
-        self.page.update_item_v2(idx + 1, item);
-
-        if self.separator() == cur.key {
-            let max_key = self
-                .item_iter()
-                .max_by(|x, y| x.key.cmp(&y.key))
-                .map(|i| i.key)
-                .unwrap();
-
-            self.page.update_item_v2(0, &max_key)
-        }
-
-        Ok(())
-    }
-
-    pub fn set_separator(&mut self, sep: &K) {
-        assert_eq!(self.page.item_cnt(), 0);
-
-        // TODO: handle error here
-        self.page.add_item_v2(sep).unwrap();
+impl<'a, K> InternalNodeWriteLock<'a, K>
+where
+    K: Key,
+{
+    /// Points the downlink currently referencing `old_child_no` at `new_child_no`, keeping its
+    /// key, and returns its index. Since only the page number changes, the item is updated in
+    /// place.
+    pub fn replace_downlink(&mut self, old_child_no: u32, new_child_no: u32) -> Result<usize> {
+        let idx = self.downlink_idx(old_child_no).ok_or_else(|| {
+            Error::page_corruption(
+                self.page_no,
+                format!("no downlink to page {}", old_child_no),
+            )
+        })?;
+
+        let mut cur = self.downlink(idx);
+        cur.page_no = new_child_no;
+        self.page.update_item_v2(idx, &cur);
+
+        Ok(idx)
     }
 }
 
-impl<'a, K> Into<RwLockWriteGuard<'a, PagePtr>> for InternalNodeWriteLock<'a, K>
+impl<'a, K> From<InternalNodeWriteLock<'a, K>> for RwLockWriteGuard<'a, PagePtr>
 where
     K: Key,
 {
-    fn into(self) -> RwLockWriteGuard<'a, PagePtr> {
-        self.page
+    fn from(node: InternalNodeWriteLock<'a, K>) -> Self {
+        node.page
     }
 }
 
-pub(super) fn fetch_page_read<'a, P, K>(
-    page_fetcher: &'a P,
+pub(super) fn fetch_page_read<P, K>(
+    page_fetcher: &P,
     page_no: u32,
-) -> Option<InternalNodeReadLock<'a, K>>
+) -> Result<InternalNodeReadLock<'_, K>>
 where
     P: PageFetcherTrait,
     K: Key,
 {
-    page_fetcher
-        .fetch_page_read(page_no)
-        .map(|lock| from_read_lock(page_no, lock))
+    from_read_lock(page_no, page_fetcher.fetch_page_read(page_no)?)
 }
-pub(super) fn fetch_page_write<'a, P, K>(
-    page_fetcher: &'a P,
+pub(super) fn fetch_page_write<P, K>(
+    page_fetcher: &P,
     page_no: u32,
-) -> Option<InternalNodeWriteLock<'a, K>>
+) -> Result<InternalNodeWriteLock<'_, K>>
 where
     P: PageFetcherTrait,
     K: Key,
 {
-    page_fetcher
-        .fetch_page_write(page_no)
-        .map(|lock| from_write_lock(page_no, lock))
+    from_write_lock(page_no, page_fetcher.fetch_page_write(page_no)?)
 }
 
-pub(super) fn new_page<'a, P, K>(
-    page_fetcher: &'a P,
-    right_sibling_page_no: u32,
-) -> (u32, InternalNodeWriteLock<'a, K>)
+pub(super) fn new_page<P, K>(
+    page_fetcher: &P,
+    right_sibling: PageNo,
+) -> Result<(u32, InternalNodeWriteLock<'_, K>)>
 where
     P: PageFetcherTrait,
     K: Key,
 {
-    let (page_no, lock) = page_fetcher.new_page(BTreePageData {
-        node_type: NodeType::Internal,
-        right_sibling_page_no,
-    });
+    let (page_no, lock) =
+        page_fetcher.new_page(BTreePageData::new(NodeType::Internal, right_sibling))?;
 
-    (
+    Ok((
         // TODO: Eliminate the `page_no` from being returned
         page_no,
         InternalNodeWriteLock {
             page_no,
             page: lock,
-            phantom: PhantomData,
+            separator: OnceCell::new(),
         },
-    )
+    ))
 }
 
 pub(super) fn from_read_lock<K>(
     page_no: u32,
-    lock: RwLockReadGuard<PagePtr>,
-) -> InternalNodeReadLock<K>
+    lock: RwLockReadGuard<'_, PagePtr>,
+) -> Result<InternalNodeReadLock<'_, K>>
 where
     K: Key,
 {
-    assert!(matches!(
-        lock.special_data::<BTreePageData>().node_type,
-        NodeType::Leaf
-    ));
+    check_node_type(page_no, &lock)?;
 
-    InternalNodeReadLock {
+    Ok(InternalNodeReadLock {
         page_no,
         page: lock,
-        phantom: PhantomData,
-    }
+        separator: OnceCell::new(),
+    })
 }
 
 pub(super) fn from_write_lock<K>(
     page_no: u32,
-    lock: RwLockWriteGuard<PagePtr>,
-) -> InternalNodeWriteLock<K>
+    lock: RwLockWriteGuard<'_, PagePtr>,
+) -> Result<InternalNodeWriteLock<'_, K>>
 where
     K: Key,
 {
-    assert!(matches!(
-        lock.special_data::<BTreePageData>().node_type,
-        NodeType::Leaf
-    ));
+    check_node_type(page_no, &lock)?;
 
-    InternalNodeWriteLock {
+    Ok(InternalNodeWriteLock {
         page_no,
         page: lock,
-        phantom: PhantomData,
+        separator: OnceCell::new(),
+    })
+}
+
+fn check_node_type(page_no: u32, page: &Page) -> Result<()> {
+    let node_type = BTreePageData::checked_node_type(page_no, page)?;
+    if !matches!(node_type, NodeType::Internal) {
+        return Err(Error::page_corruption(
+            page_no,
+            format!("expected an internal node, found {:?}", node_type),
+        ));
     }
+
+    Ok(())
 }
 
 /// Returns (internal_node_page_no, downlink_child_no)
-pub(super) fn find_child_ptr_move_right_read_lock<'a, P, K>(
+pub(super) fn find_child_ptr_move_right_read_lock<P, K>(
     page_fetcher: &P,
-    page: InternalNodeReadLock<'a, K>,
-    key: K,
-) -> (u32, u32)
+    page: InternalNodeReadLock<'_, K>,
+    key: &K,
+) -> Result<(u32, u32)>
 where
     P: PageFetcherTrait,
     K: Key,
 {
-    find_child_ptr_move_right(page, key, |page_no| fetch_page_read(page_fetcher, page_no))
+    find_child_ptr_move_right(page, key, |page_no| {
+        page_fetcher.metrics().move_rights.inc();
+        fetch_page_read(page_fetcher, page_no)
+    })
 }
 
-pub(super) fn find_child_ptr_move_right_write_lock<'a, P, K>(
+pub(super) fn find_node_with_entry_move_right_write_lock<P, K>(
     page_fetcher: &P,
     page_no: u32,
-    key: K,
-) -> (u32, u32)
-where
-    P: PageFetcherTrait,
-    K: Key,
-{
-    let page = fetch_page_write(page_fetcher, page_no).unwrap();
-    find_child_ptr_move_right(page, key, |page_no| fetch_page_write(page_fetcher, page_no))
-}
-
-pub(super) fn find_node_with_entry_move_right_write_lock<'a, P, K>(
-    page_fetcher: &'a P,
-    page_no: u32,
     child_no: u32,
-) -> InternalNodeWriteLock<'a, K>
+) -> Result<InternalNodeWriteLock<'_, K>>
 where
     P: PageFetcherTrait,
     K: Key,
 {
-    let mut next = page_no;
-    while next != 0 {
+    let mut next = Some(page_no);
+    while let Some(next_no) = next {
         // we want to drop read lock of current page prior to fetching the next page to reduce
         // overall lock contentions.
-        let page = fetch_page_write(page_fetcher, next).unwrap();
+        let page = fetch_page_write(page_fetcher, next_no)?;
         let child_ptr: Option<InternalNodeItemData<K>> =
             page.item_iter().find(|i| i.page_no == child_no);
         if child_ptr.is_some() {
-            return page;
+            return Ok(page);
         } else {
-            next = page.special_data().right_sibling_page_no;
+            page_fetcher.metrics().move_rights.inc();
+            next = page.special_data().right_sibling().get();
         }
     }
 
-    panic!("For some reason we couldn't find the child ptr containing key, probably bug here!");
+    Err(Error::page_corruption(
+        page_no,
+        format!("couldn't find downlink to page {} moving right", child_no),
+    ))
 }
 
 /// Returns (internal_node_page_no, downlink_child_no)
-fn find_child_ptr_move_right<'a, I, K, F>(page: I, key: K, fetch_page: F) -> (u32, u32)
+fn find_child_ptr_move_right<I, K, F>(page: I, key: &K, fetch_page: F) -> Result<(u32, u32)>
 where
     I: InternalNodeRead<K>,
     K: Key,
-    F: Fn(u32) -> Option<I>,
+    F: Fn(u32) -> Result<I>,
 {
-    let mut child_ptr = page.find_child_ptr(key);
-
-    if child_ptr.is_some() {
-        return (page.page_no(), child_ptr.unwrap());
+    let start_no = page.page_no();
+    if let Some(child_ptr) = page.find_child_ptr(key) {
+        return Ok((page.page_no(), child_ptr));
     }
 
-    let mut next = page.special_data().right_sibling_page_no;
+    let mut next = page.special_data().right_sibling().get();
     // we want to drop the read lock prior entering the while loop. Otherwise, we will hold
     // onto two locks at any given time during the while loop execution.
     drop(page);
-    while next != 0 {
+    while let Some(next_no) = next {
         // we want to drop read lock of current page prior to fetching the next page to reduce
         // overall lock contentions.
-        let page = fetch_page(next).unwrap();
-        child_ptr = page.find_child_ptr(key);
-        if child_ptr.is_some() {
-            return (next, child_ptr.unwrap());
+        let page = fetch_page(next_no)?;
+        if let Some(child_ptr) = page.find_child_ptr(key) {
+            return Ok((next_no, child_ptr));
         } else {
-            next = page.special_data().right_sibling_page_no;
+            next = page.special_data().right_sibling().get();
         }
     }
 
-    panic!("For some reason we couldn't find the child ptr containing key, probably bug here!");
+    Err(Error::page_corruption(
+        start_no,
+        format!("couldn't find child ptr for key {:?} moving right", key),
+    ))
 }
@@ -0,0 +1,280 @@
+//! Checks of the invariants structure modifications must leave in place, run on the pages a split
+//! touched right after it in builds with debug assertions. A violation panics with a dump of those
+//! pages, so a bad split is caught where it happens rather than by a lookup going astray later.
+//!
+//! Only pages the caller holds locked are read, so the checks are safe while other inserts run.
+
+use super::dump::dump_page_to;
+use super::internal_node::InternalNodeItemData;
+use super::internal_node::InternalNodeWriteLock;
+use super::key::Key;
+use super::node::NodeItem;
+use super::node::NodeRead;
+use super::value::Value;
+use crate::page::PageNo;
+
+/// Checks `left` and `right` right after `left` was split into them, panicking with a dump of
+/// both otherwise.
+pub(super) fn assert_split<K, V, N, I>(left: &N, right: &N, internal: bool)
+where
+    K: Key,
+    V: Value,
+    N: NodeRead<I>,
+    I: NodeItem,
+{
+    if let Err(violation) = check_split(left, right, internal) {
+        fail::<K, V, _, _>(&violation, &[left, right]);
+    }
+}
+
+/// Checks `parent`, and `new_parent` if it split in turn, right after the downlinks to `orig` and
+/// `new` were updated following a split of `orig`, panicking with a dump of both otherwise.
+pub(super) fn assert_downlinks<K, V>(
+    parent: &InternalNodeWriteLock<K>,
+    new_parent: Option<&InternalNodeWriteLock<K>>,
+    orig: &InternalNodeItemData<K>,
+    new: &InternalNodeItemData<K>,
+) where
+    K: Key,
+    V: Value,
+{
+    let mut parents = vec![parent];
+    parents.extend(new_parent);
+    let checked = match new_parent {
+        Some(new_parent) => check_split(parent, new_parent, true),
+        None => check_node(parent, true),
+    }
+    .and_then(|_| check_downlinks(&parents, &[orig, new]));
+    if let Err(violation) = checked {
+        fail::<K, V, _, _>(&violation, &parents);
+    }
+}
+
+/// That both nodes are valid on their own, that `left` links to `right`, and that everything in
+/// `right` is above `left`'s separator.
+fn check_split<N, I>(left: &N, right: &N, internal: bool) -> Result<(), String>
+where
+    N: NodeRead<I>,
+    I: NodeItem,
+{
+    check_node(left, internal)?;
+    check_node(right, internal)?;
+
+    let right_sibling = left.special_data().right_sibling();
+    if right_sibling != PageNo::new(right.page_no()) {
+        return Err(format!(
+            "page {} links to page {} rather than to its new sibling {}",
+            left.page_no(),
+            right_sibling,
+            right.page_no()
+        ));
+    }
+    let lower = left.separator();
+    if lower >= right.separator() {
+        return Err(format!(
+            "page {}'s separator {:?} isn't below its new sibling {}'s separator {:?}",
+            left.page_no(),
+            lower,
+            right.page_no(),
+            right.separator()
+        ));
+    }
+    // A leaf's separator is the first key that moved to its sibling, while an internal node's is
+    // the largest downlink key it kept
+    let below = right.item_iter().find(|item| match internal {
+        true => lower.is_at_least(item.key()),
+        false => lower.is_above(item.key()),
+    });
+    if let Some(item) = below {
+        return Err(format!(
+            "page {} holds key {:?}, which belongs left of page {}'s separator {:?}",
+            right.page_no(),
+            item.key(),
+            left.page_no(),
+            lower
+        ));
+    }
+    Ok(())
+}
+
+/// That every key is below the node's separator, or at most equal to it for internal nodes whose
+/// separator must be their largest downlink key, and that keys are in order if the node is flagged
+/// sorted.
+fn check_node<N, I>(node: &N, internal: bool) -> Result<(), String>
+where
+    N: NodeRead<I>,
+    I: NodeItem,
+{
+    let separator = node.separator();
+    let keys = node
+        .item_iter()
+        .map(|item| item.key().clone())
+        .collect::<Vec<_>>();
+
+    let beyond = keys.iter().find(|key| match internal {
+        true => !separator.is_at_least(key),
+        false => !separator.is_above(key),
+    });
+    if let Some(key) = beyond {
+        return Err(format!(
+            "page {} holds key {:?}, beyond its separator {:?}",
+            node.page_no(),
+            key,
+            separator
+        ));
+    }
+    if node.special_data().is_sorted() && keys.windows(2).any(|pair| pair[0] > pair[1]) {
+        return Err(format!(
+            "page {} is flagged sorted but its keys aren't",
+            node.page_no()
+        ));
+    }
+    // The downlink to an unbounded node's last child is keyed with the `max_key` placeholder
+    if internal && keys.iter().max() != Some(&separator.stored_key()) {
+        return Err(format!(
+            "page {}'s separator {:?} isn't its largest downlink key {:?}",
+            node.page_no(),
+            separator,
+            keys.iter().max()
+        ));
+    }
+    Ok(())
+}
+
+/// That `parents` hold exactly one downlink to each of `children`, keyed by its separator.
+fn check_downlinks<K: Key>(
+    parents: &[&InternalNodeWriteLock<K>],
+    children: &[&InternalNodeItemData<K>],
+) -> Result<(), String> {
+    for child in children {
+        let downlinks = parents
+            .iter()
+            .flat_map(|parent| parent.item_iter())
+            .filter(|downlink| downlink.page_no == child.page_no)
+            .collect::<Vec<_>>();
+        match downlinks.as_slice() {
+            [downlink] if downlink.key == child.key => {}
+            [downlink] => {
+                return Err(format!(
+                    "downlink to page {} is keyed {:?} rather than by its separator {:?}",
+                    child.page_no, downlink.key, child.key
+                ));
+            }
+            _ => {
+                return Err(format!(
+                    "found {} downlinks to page {}, expected one",
+                    downlinks.len(),
+                    child.page_no
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+fn fail<K, V, N, I>(violation: &str, nodes: &[&N]) -> !
+where
+    K: Key,
+    V: Value,
+    N: NodeRead<I>,
+    I: NodeItem,
+{
+    let mut dump = String::new();
+    for node in nodes {
+        dump_page_to::<K, V>(node.page_ref(), node.page_no(), &mut dump)
+            .expect("writing to a String can't fail");
+    }
+    panic!("b-tree invariant violated: {}\n{}", violation, dump);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::check_downlinks;
+    use super::check_split;
+    use crate::btree::internal_node::InternalNodeItemData;
+    use crate::btree::key::KeyU32;
+    use crate::btree::leaf_node::LeafNodeItemData;
+    use crate::btree::node::NodeWrite;
+    use crate::btree::node::Separator;
+    use crate::btree::value::ValueTupleId;
+    use crate::page::PageNo;
+    use crate::page_fetcher::InMemoryPageFetcher;
+
+    fn leaf_item(key: u32) -> LeafNodeItemData<KeyU32, ValueTupleId> {
+        LeafNodeItemData {
+            key: KeyU32 { key },
+            value: ValueTupleId {
+                page_no: key,
+                offset: 0,
+            },
+        }
+    }
+
+    #[test]
+    fn bad_splits() {
+        let page_fetcher = InMemoryPageFetcher::new();
+        let (_, mut right) = crate::btree::leaf_node::new_page::<_, KeyU32, ValueTupleId>(
+            &page_fetcher,
+            PageNo::INVALID,
+        )
+        .unwrap();
+        let (_, mut left) = crate::btree::leaf_node::new_page::<_, KeyU32, ValueTupleId>(
+            &page_fetcher,
+            PageNo::INVALID,
+        )
+        .unwrap();
+        right
+            .set_separator(&Separator::Key(KeyU32 { key: 100 }))
+            .unwrap();
+        right.add_item(&leaf_item(50)).unwrap();
+        left.set_separator(&Separator::Key(KeyU32 { key: 50 }))
+            .unwrap();
+        left.add_item(&leaf_item(10)).unwrap();
+
+        // Not linked yet
+        let err = check_split(&left, &right, false).unwrap_err();
+        assert!(err.contains("rather than to its new sibling"), "{}", err);
+
+        left.set_right_sibling_no(right.page_no);
+        check_split(&left, &right, false).unwrap();
+
+        right.add_item(&leaf_item(40)).unwrap();
+        let err = check_split(&left, &right, false).unwrap_err();
+        assert!(err.contains("belongs left"), "{}", err);
+
+        let (_, mut right) = crate::btree::leaf_node::new_page::<_, KeyU32, ValueTupleId>(
+            &page_fetcher,
+            PageNo::INVALID,
+        )
+        .unwrap();
+        right.set_separator(&Separator::Unbounded).unwrap();
+        left.set_right_sibling_no(right.page_no);
+        // Leaf separators are exclusive
+        left.add_item(&leaf_item(50)).unwrap();
+        let err = check_split(&left, &right, false).unwrap_err();
+        assert!(err.contains("beyond its separator"), "{}", err);
+    }
+
+    #[test]
+    fn bad_downlinks() {
+        let page_fetcher = InMemoryPageFetcher::new();
+        let (_, mut parent) =
+            crate::btree::internal_node::new_page::<_, KeyU32>(&page_fetcher, PageNo::INVALID)
+                .unwrap();
+        let downlink = |page_no, key| InternalNodeItemData {
+            page_no,
+            key: KeyU32 { key },
+        };
+        parent
+            .set_separator(&Separator::Key(KeyU32 { key: 100 }))
+            .unwrap();
+        parent.add_item(&downlink(1, 50)).unwrap();
+        parent.add_item(&downlink(2, 100)).unwrap();
+
+        check_downlinks(&[&parent], &[&downlink(1, 50), &downlink(2, 100)]).unwrap();
+        let err = check_downlinks(&[&parent], &[&downlink(1, 40)]).unwrap_err();
+        assert!(err.contains("rather than by its separator"), "{}", err);
+        let err = check_downlinks(&[&parent], &[&downlink(3, 40)]).unwrap_err();
+        assert!(err.contains("found 0 downlinks"), "{}", err);
+    }
+}
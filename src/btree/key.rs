@@ -1,9 +1,37 @@
+use super::collation::Binary;
+use super::collation::Collation;
+use super::collation::BINARY_COLLATION;
+use crate::encoding;
+use crate::encoding::Decode;
+use crate::encoding::Encode;
+use crate::error::Error;
+use crate::error::Result;
 use crate::page::Item;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fmt;
 use std::fmt::Debug;
+use std::marker::PhantomData;
 use std::mem::size_of;
 
-pub trait Key: Item + Ord + Copy + Clone + Debug {
+pub trait Key: Item + Ord + Clone + Debug {
+    /// A key at least as large as any other. It's a valid key like any other, but also stands in
+    /// for the separators of the rightmost nodes, which have no upper bound.
     fn max_key() -> Self;
+
+    /// Identifies the key type in a tree's metadata page, so a tree can't be opened with a
+    /// different key type than it was created with. The default is `std::any::type_name`, which
+    /// changes when the type is renamed or moved and isn't guaranteed to be stable across compiler
+    /// versions, so types stored in long lived trees should override it with a fixed name.
+    fn type_name() -> Cow<'static, str> {
+        Cow::Borrowed(std::any::type_name::<Self>())
+    }
+
+    /// Identifies the key's ordering in a tree's metadata page, so a tree can't be opened with a
+    /// different ordering than it was built with, see `collation` and `comparator`.
+    fn collation() -> Cow<'static, str> {
+        Cow::Borrowed(BINARY_COLLATION)
+    }
 }
 
 #[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Copy, Clone)]
@@ -15,34 +43,825 @@ impl Key for KeyU32 {
     fn max_key() -> Self {
         Self { key: u32::MAX }
     }
+
+    fn type_name() -> Cow<'static, str> {
+        Cow::Borrowed("johndb::btree::key::KeyU32")
+    }
 }
 
+/// Stored little-endian, and compared as an integer rather than byte-wise.
 impl Item for KeyU32 {
     fn size(&self) -> usize {
-        std::mem::size_of::<Self>()
+        size_of::<u32>()
     }
 
     fn align() -> usize {
-        std::mem::align_of::<Self>()
+        4
     }
 
     fn is_fixed_size() -> bool {
         true
     }
 
-    unsafe fn write(&self, buffer: *mut u8) {
-        *(buffer as *mut Self) = self.clone()
+    fn write(&self, buffer: &mut [u8]) {
+        buffer.copy_from_slice(&self.key.to_le_bytes());
     }
 
-    unsafe fn read(buffer: *const u8, size: usize) -> Self {
+    fn read(bytes: &[u8]) -> Self {
         assert!(
-            size == size_of::<Self>(),
+            bytes.len() == size_of::<u32>(),
             "{} != {} ({})",
-            size,
-            size_of::<Self>(),
+            bytes.len(),
+            size_of::<u32>(),
             "KeyU32",
         );
 
-        (*(buffer as *mut Self)).clone()
+        let mut key = [0u8; 4];
+        key.copy_from_slice(bytes);
+        Self {
+            key: u32::from_le_bytes(key),
+        }
+    }
+}
+
+/// Variable length key compared lexicographically byte by byte.
+///
+/// `max_key()` is `[0xFF]`, so callers need to keep their keys at or below it (e.g. by prefixing
+/// every key with a byte smaller than `0xFF`). Keys can be up to about a third of a page long,
+/// inserting longer ones fails with `Error::ItemTooLarge`.
+#[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Clone)]
+pub struct KeyBytes {
+    pub key: Vec<u8>,
+}
+
+impl From<Vec<u8>> for KeyBytes {
+    fn from(key: Vec<u8>) -> Self {
+        Self { key }
+    }
+}
+
+impl From<&[u8]> for KeyBytes {
+    fn from(key: &[u8]) -> Self {
+        Self { key: key.to_vec() }
+    }
+}
+
+impl AsRef<[u8]> for KeyBytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.key
+    }
+}
+
+impl Key for KeyBytes {
+    fn max_key() -> Self {
+        Self { key: vec![0xFF] }
+    }
+
+    fn type_name() -> Cow<'static, str> {
+        Cow::Borrowed("johndb::btree::key::KeyBytes")
+    }
+}
+
+impl Item for KeyBytes {
+    fn size(&self) -> usize {
+        self.key.len()
+    }
+
+    fn align() -> usize {
+        1
+    }
+
+    fn is_fixed_size() -> bool {
+        false
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        buffer.copy_from_slice(&self.key);
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        Self {
+            key: bytes.to_vec(),
+        }
+    }
+}
+
+/// Fixed width unsigned integer keys. They're stored big-endian so the on-page bytes sort the
+/// same way as the integers themselves.
+macro_rules! unsigned_key {
+    ($name:ident, $int:ty) => {
+        #[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Copy, Clone)]
+        pub struct $name {
+            pub key: $int,
+        }
+
+        impl From<$int> for $name {
+            fn from(key: $int) -> Self {
+                Self { key }
+            }
+        }
+
+        impl Key for $name {
+            fn max_key() -> Self {
+                Self { key: <$int>::MAX }
+            }
+
+            fn type_name() -> Cow<'static, str> {
+                Cow::Borrowed(concat!("johndb::btree::key::", stringify!($name)))
+            }
+        }
+
+        impl Item for $name {
+            fn size(&self) -> usize {
+                size_of::<$int>()
+            }
+
+            fn align() -> usize {
+                1
+            }
+
+            fn is_fixed_size() -> bool {
+                true
+            }
+
+            fn write(&self, buffer: &mut [u8]) {
+                buffer.copy_from_slice(&self.key.to_be_bytes());
+            }
+
+            fn read(bytes: &[u8]) -> Self {
+                assert!(
+                    bytes.len() == size_of::<$int>(),
+                    "{} != {} ({})",
+                    bytes.len(),
+                    size_of::<$int>(),
+                    stringify!($name),
+                );
+
+                let mut key = [0u8; size_of::<$int>()];
+                key.copy_from_slice(bytes);
+                Self {
+                    key: <$int>::from_be_bytes(key),
+                }
+            }
+        }
+    };
+}
+
+unsigned_key!(KeyU64, u64);
+unsigned_key!(KeyU128, u128);
+
+/// Signed 64 bit key. The sign bit is flipped before storing it big-endian, so negative keys sort
+/// below positive ones byte-wise too.
+#[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Copy, Clone)]
+pub struct KeyI64 {
+    pub key: i64,
+}
+
+const I64_SIGN_BIT: u64 = 1 << 63;
+
+impl From<i64> for KeyI64 {
+    fn from(key: i64) -> Self {
+        Self { key }
+    }
+}
+
+impl Key for KeyI64 {
+    fn max_key() -> Self {
+        Self { key: i64::MAX }
+    }
+
+    fn type_name() -> Cow<'static, str> {
+        Cow::Borrowed("johndb::btree::key::KeyI64")
+    }
+}
+
+impl Item for KeyI64 {
+    fn size(&self) -> usize {
+        size_of::<i64>()
+    }
+
+    fn align() -> usize {
+        1
+    }
+
+    fn is_fixed_size() -> bool {
+        true
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        buffer.copy_from_slice(&((self.key as u64) ^ I64_SIGN_BIT).to_be_bytes());
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        assert!(
+            bytes.len() == size_of::<i64>(),
+            "{} != {} ({})",
+            bytes.len(),
+            size_of::<i64>(),
+            "KeyI64",
+        );
+
+        let mut key = [0u8; 8];
+        key.copy_from_slice(bytes);
+        Self {
+            key: (u64::from_be_bytes(key) ^ I64_SIGN_BIT) as i64,
+        }
+    }
+}
+
+/// 64 bit float key, ordered by IEEE 754's total order, so `-0.0` sorts below `0.0`. NaNs, whatever
+/// their sign and payload, are all the same key, which sorts above infinity and is `max_key()`.
+///
+/// Stored big-endian with the sign bit flipped for positive numbers and every bit flipped for
+/// negative ones, so the bytes sort the same way.
+#[derive(Debug, Copy, Clone)]
+pub struct KeyF64 {
+    pub key: f64,
+}
+
+impl KeyF64 {
+    fn ordered_bits(&self) -> u64 {
+        let bits = match self.key.is_nan() {
+            true => f64::NAN.to_bits(),
+            false => self.key.to_bits(),
+        };
+        match bits & I64_SIGN_BIT {
+            0 => bits ^ I64_SIGN_BIT,
+            _ => !bits,
+        }
+    }
+}
+
+impl From<f64> for KeyF64 {
+    fn from(key: f64) -> Self {
+        Self { key }
+    }
+}
+
+impl PartialEq for KeyF64 {
+    fn eq(&self, other: &Self) -> bool {
+        self.ordered_bits() == other.ordered_bits()
+    }
+}
+
+impl Eq for KeyF64 {}
+
+impl PartialOrd for KeyF64 {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for KeyF64 {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.ordered_bits().cmp(&other.ordered_bits())
+    }
+}
+
+impl Key for KeyF64 {
+    fn max_key() -> Self {
+        Self { key: f64::NAN }
+    }
+
+    fn type_name() -> Cow<'static, str> {
+        Cow::Borrowed("johndb::btree::key::KeyF64")
+    }
+}
+
+impl Item for KeyF64 {
+    fn size(&self) -> usize {
+        size_of::<f64>()
+    }
+
+    fn align() -> usize {
+        1
+    }
+
+    fn is_fixed_size() -> bool {
+        true
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        buffer.copy_from_slice(&self.ordered_bits().to_be_bytes());
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        assert!(
+            bytes.len() == size_of::<f64>(),
+            "{} != {} ({})",
+            bytes.len(),
+            size_of::<f64>(),
+            "KeyF64",
+        );
+
+        let mut key = [0u8; 8];
+        key.copy_from_slice(bytes);
+        let ordered = u64::from_be_bytes(key);
+        let bits = match ordered & I64_SIGN_BIT {
+            0 => !ordered,
+            _ => ordered ^ I64_SIGN_BIT,
+        };
+        Self {
+            key: f64::from_bits(bits),
+        }
+    }
+}
+
+/// Fixed length byte array key, e.g. a hash or an externally generated id.
+#[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Copy, Clone)]
+pub struct KeyArray<const N: usize> {
+    pub key: [u8; N],
+}
+
+impl<const N: usize> From<[u8; N]> for KeyArray<N> {
+    fn from(key: [u8; N]) -> Self {
+        Self { key }
+    }
+}
+
+impl<const N: usize> Key for KeyArray<N> {
+    fn max_key() -> Self {
+        Self { key: [0xFF; N] }
+    }
+
+    fn type_name() -> Cow<'static, str> {
+        Cow::Owned(format!("johndb::btree::key::KeyArray<{}>", N))
+    }
+}
+
+impl<const N: usize> Item for KeyArray<N> {
+    fn size(&self) -> usize {
+        N
+    }
+
+    fn align() -> usize {
+        1
+    }
+
+    fn is_fixed_size() -> bool {
+        true
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        buffer.copy_from_slice(&self.key);
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        assert!(
+            bytes.len() == N,
+            "{} != {} ({})",
+            bytes.len(),
+            N,
+            "KeyArray"
+        );
+
+        let mut key = [0u8; N];
+        key.copy_from_slice(bytes);
+        Self { key }
+    }
+}
+
+/// UTF-8 string key ordered by collation `C`, byte-wise by default, which matches `String`'s
+/// ordering. See `collation` for the others.
+///
+/// `max_key()` is `C::max_key()` on its own, `char::MAX` for byte-wise order, so like `KeyBytes`
+/// callers need to keep their keys at or below it.
+pub struct KeyString<C: Collation = Binary> {
+    pub key: String,
+    collation: PhantomData<C>,
+}
+
+impl<C: Collation> KeyString<C> {
+    pub fn new<S: Into<String>>(key: S) -> Self {
+        Self {
+            key: key.into(),
+            collation: PhantomData,
+        }
+    }
+}
+
+/// Only byte-wise keys convert from strings, so that `KeyString::from` doesn't need the collation
+/// spelled out. Use `KeyString::new` for the others.
+impl From<String> for KeyString {
+    fn from(key: String) -> Self {
+        Self::new(key)
+    }
+}
+
+impl From<&str> for KeyString {
+    fn from(key: &str) -> Self {
+        Self::new(key)
+    }
+}
+
+impl<C: Collation> Clone for KeyString<C> {
+    fn clone(&self) -> Self {
+        Self::new(self.key.clone())
+    }
+}
+
+impl<C: Collation> Debug for KeyString<C> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("KeyString").field("key", &self.key).finish()
+    }
+}
+
+impl<C: Collation> PartialEq for KeyString<C> {
+    fn eq(&self, other: &Self) -> bool {
+        self.cmp(other) == Ordering::Equal
+    }
+}
+
+impl<C: Collation> Eq for KeyString<C> {}
+
+impl<C: Collation> PartialOrd for KeyString<C> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<C: Collation> Ord for KeyString<C> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        C::compare(&self.key, &other.key)
+    }
+}
+
+impl<C: Collation> Key for KeyString<C> {
+    fn max_key() -> Self {
+        Self::new(C::max_key())
+    }
+
+    /// The same for every collation, which is recorded separately, see `Key::collation`.
+    fn type_name() -> Cow<'static, str> {
+        Cow::Borrowed("johndb::btree::key::KeyString")
+    }
+
+    fn collation() -> Cow<'static, str> {
+        C::id()
+    }
+}
+
+impl<C: Collation> Item for KeyString<C> {
+    fn size(&self) -> usize {
+        self.key.len()
+    }
+
+    fn align() -> usize {
+        1
+    }
+
+    fn is_fixed_size() -> bool {
+        false
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        buffer.copy_from_slice(self.key.as_bytes());
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        Self::new(String::from_utf8_lossy(bytes).into_owned())
+    }
+}
+
+/// Key built from the order-preserving encoding in `crate::encoding`, so any encodable type (or
+/// tuple of them) can be used as a key and compared with a single memcmp. Wrap a component in
+/// `encoding::Desc` to sort it in descending order.
+///
+/// The encoded bytes are stored behind a `0x00` prefix so that `max_key()` (`[0xFF]`) stays above
+/// every real key.
+#[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Clone)]
+pub struct KeyEncoded {
+    bytes: Vec<u8>,
+}
+
+const KEY_ENCODED_PREFIX: u8 = 0x00;
+
+impl KeyEncoded {
+    pub fn new<T: Encode + ?Sized>(value: &T) -> Self {
+        let mut bytes = vec![KEY_ENCODED_PREFIX];
+        value.encode_to(&mut bytes);
+        Self { bytes }
+    }
+
+    /// The encoded key, without the internal prefix.
+    pub fn encoded(&self) -> &[u8] {
+        &self.bytes[1..]
+    }
+
+    pub fn decode<T: Decode>(&self) -> Result<T> {
+        if self.bytes.first() != Some(&KEY_ENCODED_PREFIX) {
+            return Err(Error::corruption(format!(
+                "can't decode the max key or a malformed key {:?}",
+                self.bytes
+            )));
+        }
+        encoding::decode(self.encoded())
+    }
+}
+
+impl Key for KeyEncoded {
+    fn max_key() -> Self {
+        Self { bytes: vec![0xFF] }
+    }
+
+    fn type_name() -> Cow<'static, str> {
+        Cow::Borrowed("johndb::btree::key::KeyEncoded")
+    }
+}
+
+impl Item for KeyEncoded {
+    fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    fn align() -> usize {
+        1
+    }
+
+    fn is_fixed_size() -> bool {
+        false
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        buffer.copy_from_slice(&self.bytes);
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        Self {
+            bytes: bytes.to_vec(),
+        }
+    }
+}
+
+/// Sorts `K` in descending order, so that a tree keyed by it keeps its largest keys leftmost and
+/// `range` and `bottom_n` return them first, e.g. for `ORDER BY x DESC LIMIT n`. The direction is
+/// recorded as the tree's collation, `desc:` followed by `K`'s, so a tree can't be opened in the
+/// other direction than it was built with. For a direction per component of a composite key,
+/// use `KeyEncoded` with `encoding::Desc` instead.
+///
+/// `K` has no smallest key to stand in for `max_key`, so that's marked by a byte stored after the
+/// key.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct KeyDesc<K: Key> {
+    pub key: K,
+    max: bool,
+}
+
+impl<K: Key> KeyDesc<K> {
+    pub fn new(key: K) -> Self {
+        Self { key, max: false }
+    }
+
+    /// The size of the stored key, without the marker.
+    fn key_size(item_size: usize) -> usize {
+        match K::is_fixed_size() {
+            true => K::fixed_size(),
+            false => item_size - 1,
+        }
+    }
+}
+
+impl<K: Key> From<K> for KeyDesc<K> {
+    fn from(key: K) -> Self {
+        Self::new(key)
+    }
+}
+
+impl<K: Key> PartialOrd for KeyDesc<K> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<K: Key> Ord for KeyDesc<K> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.max, other.max) {
+            (false, false) => other.key.cmp(&self.key),
+            (max, other_max) => max.cmp(&other_max),
+        }
+    }
+}
+
+impl<K: Key> Key for KeyDesc<K> {
+    fn max_key() -> Self {
+        Self {
+            key: K::max_key(),
+            max: true,
+        }
+    }
+
+    /// The key type is `K`'s, while the direction goes into the collation.
+    fn type_name() -> Cow<'static, str> {
+        K::type_name()
+    }
+
+    fn collation() -> Cow<'static, str> {
+        Cow::Owned(format!("desc:{}", K::collation()))
+    }
+}
+
+impl<K: Key> Item for KeyDesc<K> {
+    fn size(&self) -> usize {
+        match K::is_fixed_size() {
+            true => Self::fixed_size(),
+            false => self.key.size() + 1,
+        }
+    }
+
+    fn align() -> usize {
+        K::align()
+    }
+
+    fn is_fixed_size() -> bool {
+        K::is_fixed_size()
+    }
+
+    fn fixed_size() -> usize {
+        K::fixed_size() + 1
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        let key_size = Self::key_size(buffer.len());
+        self.key.write(&mut buffer[..key_size]);
+        buffer[key_size] = self.max as u8;
+        buffer[key_size + 1..].fill(0);
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        let key_size = Self::key_size(bytes.len());
+        Self {
+            key: K::read(&bytes[..key_size]),
+            max: bytes[key_size] != 0,
+        }
+    }
+}
+
+#[cfg(feature = "uuid")]
+pub use super::key_uuid::KeyUuid;
+pub use super::time_series::KeyTimestamp;
+
+#[cfg(test)]
+mod tests {
+    use super::Key;
+    use super::KeyArray;
+    use super::KeyDesc;
+    use super::KeyEncoded;
+    use super::KeyF64;
+    use super::KeyI64;
+    use super::KeyString;
+    use super::KeyU128;
+    use super::KeyU32;
+    use super::KeyU64;
+    use crate::btree::value::ValueTupleId;
+    use crate::btree::BTree;
+    use crate::error::Error;
+    use crate::page::Item;
+    use crate::page_fetcher::InMemoryPageFetcher;
+
+    fn encode<K: Item>(key: &K) -> Vec<u8> {
+        let mut buffer = vec![0u8; key.size()];
+        key.write(&mut buffer);
+        buffer
+    }
+
+    fn round_trip<K: Item>(key: &K) -> K {
+        K::read(&encode(key))
+    }
+
+    #[test]
+    fn signed_encoding_preserves_order() {
+        let keys = [i64::MIN, -1000, -1, 0, 1, 1000, i64::MAX]
+            .iter()
+            .map(|k| KeyI64::from(*k))
+            .collect::<Vec<_>>();
+
+        for pair in keys.windows(2) {
+            assert!(encode(&pair[0]) < encode(&pair[1]), "{:?}", pair);
+        }
+        for key in keys.iter() {
+            assert_eq!(round_trip(key), *key);
+        }
+    }
+
+    #[test]
+    fn float_encoding_preserves_order() {
+        let keys = [
+            f64::NEG_INFINITY,
+            -1e10,
+            -1.5,
+            -f64::MIN_POSITIVE,
+            -0.0,
+            0.0,
+            f64::MIN_POSITIVE,
+            2.5,
+            f64::MAX,
+            f64::INFINITY,
+            f64::NAN,
+        ]
+        .iter()
+        .map(|k| KeyF64::from(*k))
+        .collect::<Vec<_>>();
+
+        for pair in keys.windows(2) {
+            assert!(pair[0] < pair[1], "{:?}", pair);
+            assert!(encode(&pair[0]) < encode(&pair[1]), "{:?}", pair);
+        }
+        for key in keys.iter() {
+            assert_eq!(round_trip(key), *key);
+            assert_eq!(round_trip(key).key.to_bits(), key.key.to_bits());
+        }
+
+        // Every NaN is the same key
+        let negative_nan = KeyF64::from(-f64::NAN);
+        assert_eq!(negative_nan, KeyF64::max_key());
+        assert_eq!(encode(&negative_nan), encode(&KeyF64::max_key()));
+    }
+
+    #[test]
+    fn round_trips() {
+        assert_eq!(round_trip(&KeyU64::from(1 << 40)), KeyU64::from(1 << 40));
+        assert_eq!(
+            round_trip(&KeyU128::from(u128::MAX - 1)),
+            KeyU128::from(u128::MAX - 1)
+        );
+        assert_eq!(
+            round_trip(&KeyArray::from([1, 2, 3])),
+            KeyArray::from([1, 2, 3])
+        );
+        assert_eq!(
+            round_trip(&KeyString::from("héllo")),
+            KeyString::from("héllo")
+        );
+        assert!(encode(&KeyU64::from(255)) < encode(&KeyU64::from(256)));
+    }
+
+    #[test]
+    fn encoded_keys() {
+        let a = KeyEncoded::new(&("users", -5i64));
+        let b = KeyEncoded::new(&("users", 3i64));
+        let c = KeyEncoded::new(&("usersx", i64::MIN));
+        assert!(a < b && b < c && c < KeyEncoded::max_key());
+
+        assert_eq!(round_trip(&b), b);
+        assert_eq!(
+            b.decode::<(String, i64)>().unwrap(),
+            ("users".to_string(), 3)
+        );
+        assert!(KeyEncoded::max_key().decode::<u32>().is_err());
+    }
+
+    #[test]
+    fn descending_trees() {
+        let desc = |key| KeyDesc::new(KeyU32 { key });
+        assert!(desc(2) < desc(1) && desc(0) < KeyDesc::max_key());
+        assert!(desc(u32::MAX) < KeyDesc::max_key());
+        assert_eq!(encode(&desc(7)).len(), 5);
+        assert_eq!(round_trip(&desc(7)), desc(7));
+        assert_eq!(
+            round_trip(&KeyDesc::<KeyU32>::max_key()),
+            KeyDesc::max_key()
+        );
+        let text = KeyDesc::new(KeyString::from("héllo"));
+        assert_eq!(encode(&text).len(), "héllo".len() + 1);
+        assert_eq!(round_trip(&text), text);
+
+        let value = ValueTupleId {
+            page_no: 1,
+            offset: 0,
+        };
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(256)).unwrap();
+        // Scrambled, so that splits happen all over the tree
+        for i in 0..20000u32 {
+            btree.insert(desc((i * 7919) % 20000), value).unwrap();
+        }
+        assert!(btree.analyze().unwrap().height() > 1);
+        assert!(btree.verify().unwrap().is_ok());
+        let keys = |entries: Vec<(KeyDesc<KeyU32>, ValueTupleId)>| {
+            entries
+                .into_iter()
+                .map(|(key, _)| key.key.key)
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(keys(btree.bottom_n(.., 3).unwrap()), [19999, 19998, 19997]);
+        assert_eq!(keys(btree.top_n(.., 2).unwrap()), [0, 1]);
+        let range = btree
+            .range(desc(10005)..=desc(10000))
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(keys(range), [10005, 10004, 10003, 10002, 10001, 10000]);
+        assert_eq!(btree.search(desc(123)).unwrap().value, Some(value));
+        assert_eq!(btree.delete(desc(123)).unwrap(), Some(value));
+        assert_eq!(btree.search(desc(123)).unwrap().value, None);
+
+        // The direction is part of the tree's metadata
+        let page_fetcher = btree.into_page_fetcher();
+        match BTree::<KeyU32, ValueTupleId, _>::new(&page_fetcher) {
+            Err(Error::TypeMismatch(detail)) => assert!(detail.contains("desc"), "{}", detail),
+            Err(err) => panic!("expected TypeMismatch, got {:?}", err),
+            Ok(_) => panic!("expected TypeMismatch"),
+        }
+        let btree = BTree::<KeyDesc<KeyU32>, ValueTupleId, _>::new(&page_fetcher).unwrap();
+        assert_eq!(keys(btree.bottom_n(.., 1).unwrap()), [19999]);
     }
 }
@@ -0,0 +1,270 @@
+//! UUID keys. Random (v4) UUIDs land all over a tree, so every leaf ends up split about half full
+//! and stays there, while time-based UUIDs inserted as they're generated mostly land in the
+//! rightmost leaf, which sequential splits leave full, see `BTreePageData::is_sequential`.
+//!
+//! v7 UUIDs already start with their timestamp and work as is. v1 UUIDs start with the low bits
+//! of theirs, so `TimeOrdered` moves the high bits to the front when storing them, like the v6
+//! layout does. `johndb-bench --workload uuid` compares the resulting fill factors.
+
+use super::key::Key;
+use crate::page::Item;
+use std::borrow::Cow;
+use std::cmp::Ordering;
+use std::fmt;
+use std::fmt::Debug;
+use std::marker::PhantomData;
+use uuid::Uuid;
+
+const UUID_SIZE: usize = 16;
+
+/// How a `KeyUuid` is laid out on page, which is also the order keys sort in.
+pub trait UuidLayout: 'static {
+    /// Identifies the layout in a tree's metadata, see `Key::type_name`.
+    const TYPE_NAME: &'static str;
+
+    fn encode(uuid: &[u8; UUID_SIZE]) -> [u8; UUID_SIZE];
+
+    fn decode(bytes: &[u8; UUID_SIZE]) -> [u8; UUID_SIZE];
+}
+
+/// Stores UUIDs as their 16 bytes, in the order they're usually printed.
+pub struct Raw;
+
+impl UuidLayout for Raw {
+    /// Trees keyed by UUIDs used to be keyed by `KeyArray<16>`, which is stored the same way.
+    const TYPE_NAME: &'static str = "johndb::btree::key::KeyArray<16>";
+
+    fn encode(uuid: &[u8; UUID_SIZE]) -> [u8; UUID_SIZE] {
+        *uuid
+    }
+
+    fn decode(bytes: &[u8; UUID_SIZE]) -> [u8; UUID_SIZE] {
+        *bytes
+    }
+}
+
+/// Stores v1 UUIDs with their timestamp's high, middle and low fields in that order, so they sort
+/// by time. Other versions are shuffled the same way, which keeps them distinct but doesn't order
+/// them in any useful way, so v7 UUIDs should use `Raw`.
+pub struct TimeOrdered;
+
+impl UuidLayout for TimeOrdered {
+    const TYPE_NAME: &'static str = "johndb::btree::key_uuid::KeyUuid<TimeOrdered>";
+
+    fn encode(uuid: &[u8; UUID_SIZE]) -> [u8; UUID_SIZE] {
+        let mut bytes = *uuid;
+        // time_low, time_mid, time_hi_and_version -> time_hi_and_version, time_mid, time_low
+        bytes[0..2].copy_from_slice(&uuid[6..8]);
+        bytes[2..4].copy_from_slice(&uuid[4..6]);
+        bytes[4..8].copy_from_slice(&uuid[0..4]);
+        bytes
+    }
+
+    fn decode(bytes: &[u8; UUID_SIZE]) -> [u8; UUID_SIZE] {
+        let mut uuid = *bytes;
+        uuid[0..4].copy_from_slice(&bytes[4..8]);
+        uuid[4..6].copy_from_slice(&bytes[2..4]);
+        uuid[6..8].copy_from_slice(&bytes[0..2]);
+        uuid
+    }
+}
+
+/// 128 bit UUID key, stored and ordered according to layout `L`. Converts to and from
+/// `uuid::Uuid`.
+pub struct KeyUuid<L: UuidLayout = Raw> {
+    pub key: [u8; UUID_SIZE],
+    layout: PhantomData<L>,
+}
+
+impl<L: UuidLayout> KeyUuid<L> {
+    pub fn new(key: [u8; UUID_SIZE]) -> Self {
+        Self {
+            key,
+            layout: PhantomData,
+        }
+    }
+
+    /// The UUID's version, e.g. 4 for random UUIDs.
+    pub fn version(&self) -> u8 {
+        self.key[6] >> 4
+    }
+}
+
+/// Only `Raw` keys convert from bytes, so that `KeyUuid::from` doesn't need the layout spelled
+/// out. Use `KeyUuid::new` for the others.
+impl From<[u8; UUID_SIZE]> for KeyUuid {
+    fn from(key: [u8; UUID_SIZE]) -> Self {
+        Self::new(key)
+    }
+}
+
+impl<L: UuidLayout> From<Uuid> for KeyUuid<L> {
+    fn from(uuid: Uuid) -> Self {
+        Self::new(uuid.into_bytes())
+    }
+}
+
+impl<L: UuidLayout> From<KeyUuid<L>> for Uuid {
+    fn from(key: KeyUuid<L>) -> Self {
+        Uuid::from_bytes(key.key)
+    }
+}
+
+impl<L: UuidLayout> Clone for KeyUuid<L> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<L: UuidLayout> Copy for KeyUuid<L> {}
+
+impl<L: UuidLayout> Debug for KeyUuid<L> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let hex = self
+            .key
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect::<String>();
+        write!(
+            f,
+            "KeyUuid({}-{}-{}-{}-{})",
+            &hex[0..8],
+            &hex[8..12],
+            &hex[12..16],
+            &hex[16..20],
+            &hex[20..32]
+        )
+    }
+}
+
+impl<L: UuidLayout> PartialEq for KeyUuid<L> {
+    fn eq(&self, other: &Self) -> bool {
+        self.key == other.key
+    }
+}
+
+impl<L: UuidLayout> Eq for KeyUuid<L> {}
+
+impl<L: UuidLayout> PartialOrd for KeyUuid<L> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl<L: UuidLayout> Ord for KeyUuid<L> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        L::encode(&self.key).cmp(&L::encode(&other.key))
+    }
+}
+
+impl<L: UuidLayout> Key for KeyUuid<L> {
+    fn max_key() -> Self {
+        Self::new(L::decode(&[0xFF; UUID_SIZE]))
+    }
+
+    fn type_name() -> Cow<'static, str> {
+        Cow::Borrowed(L::TYPE_NAME)
+    }
+}
+
+impl<L: UuidLayout> Item for KeyUuid<L> {
+    fn size(&self) -> usize {
+        UUID_SIZE
+    }
+
+    fn align() -> usize {
+        1
+    }
+
+    fn is_fixed_size() -> bool {
+        true
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        buffer.copy_from_slice(&L::encode(&self.key));
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        assert!(
+            bytes.len() == UUID_SIZE,
+            "{} != {} ({})",
+            bytes.len(),
+            UUID_SIZE,
+            "KeyUuid"
+        );
+
+        let mut encoded = [0u8; UUID_SIZE];
+        encoded.copy_from_slice(bytes);
+        Self::new(L::decode(&encoded))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeyUuid;
+    use super::Raw;
+    use super::TimeOrdered;
+    use crate::btree::key::Key;
+    use crate::btree::key::KeyArray;
+    use crate::btree::value::ValueTupleId;
+    use crate::btree::BTree;
+    use crate::page::Item;
+    use crate::page_fetcher::InMemoryPageFetcher;
+    use uuid::Uuid;
+
+    /// A v1 UUID for the 60 bit `timestamp`.
+    fn v1(timestamp: u64, node: u8) -> [u8; 16] {
+        let mut uuid = [node; 16];
+        uuid[0..4].copy_from_slice(&(timestamp as u32).to_be_bytes());
+        uuid[4..6].copy_from_slice(&((timestamp >> 32) as u16).to_be_bytes());
+        let time_hi = ((timestamp >> 48) as u16 & 0x0FFF) | 0x1000;
+        uuid[6..8].copy_from_slice(&time_hi.to_be_bytes());
+        uuid
+    }
+
+    #[test]
+    fn time_ordered_layout() {
+        let keys = [1u64, 1 << 20, 1 << 33, 1 << 50, (1 << 60) - 1]
+            .iter()
+            .map(|timestamp| KeyUuid::<TimeOrdered>::new(v1(*timestamp, 7)))
+            .collect::<Vec<_>>();
+        for pair in keys.windows(2) {
+            assert!(pair[0] < pair[1], "{:?}", pair);
+        }
+        // Raw keys compare the low bits of the timestamp first
+        let (earlier, later) = (v1(u32::MAX as u64, 7), v1(1 << 32, 7));
+        assert!(KeyUuid::<TimeOrdered>::new(earlier) < KeyUuid::new(later));
+        assert!(KeyUuid::from(earlier) > KeyUuid::from(later));
+
+        for key in keys.iter() {
+            assert_eq!(key.version(), 1);
+            let uuid = Uuid::from(*key);
+            assert_eq!(uuid.get_version_num(), 1);
+            assert_eq!(KeyUuid::<TimeOrdered>::from(uuid), *key);
+            let mut bytes = [0u8; 16];
+            key.write(&mut bytes);
+            assert_eq!(KeyUuid::<TimeOrdered>::read(&bytes), *key);
+            assert!(*key < KeyUuid::max_key());
+        }
+    }
+
+    #[test]
+    fn raw_trees_open_as_key_arrays() {
+        let value = ValueTupleId {
+            page_no: 1,
+            offset: 1,
+        };
+        let mut btree = BTree::new(InMemoryPageFetcher::new()).unwrap();
+        btree.insert(KeyUuid::from([3; 16]), value).unwrap();
+        let page_fetcher = btree.into_page_fetcher();
+
+        let btree = BTree::<KeyArray<16>, ValueTupleId, _>::new(page_fetcher).unwrap();
+        assert_eq!(
+            btree.search(KeyArray::from([3; 16])).unwrap().value,
+            Some(value)
+        );
+        let page_fetcher = btree.into_page_fetcher();
+        assert!(BTree::<KeyUuid<TimeOrdered>, ValueTupleId, _>::new(page_fetcher).is_err());
+        assert_eq!(KeyUuid::<Raw>::max_key(), KeyUuid::<Raw>::new([0xFF; 16]));
+    }
+}
@@ -1,24 +1,35 @@
 use super::key::Key;
+use super::node::NodeItem;
+use super::node::NodeRead;
+use super::node::NodeWrite;
+use super::node::Separator;
 use super::value::Value;
+use super::value::ValueTupleId;
 use super::BTreePageData;
 use super::NodeType;
 use crate::btree::PageFetcherTrait;
+use crate::error::Error;
+use crate::error::Result;
 use crate::mem::align_offset;
 use crate::page::Item;
+use crate::page::ItemRef;
 use crate::page::Page;
-use crate::page::PageItemIteratorV2;
+use crate::page::PageNo;
+use crate::page::ITEM_POINTER_SIZE;
 use crate::page_fetcher::PagePtr;
 use core::marker::PhantomData;
 use log::debug;
-use std::iter::Skip;
+use std::cell::OnceCell;
+use std::convert::TryFrom;
 use std::mem::align_of;
 use std::mem::size_of;
 use std::ops::Deref;
 use std::ops::DerefMut;
+use std::ops::RangeBounds;
 use std::sync::RwLockReadGuard;
 use std::sync::RwLockWriteGuard;
 
-#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Copy, Clone)]
+#[derive(Debug, Ord, PartialOrd, Eq, PartialEq, Clone)]
 pub struct LeafNodeItemData<K, V>
 where
     K: Key,
@@ -26,7 +37,6 @@ where
 {
     pub key: K,
     pub value: V,
-    // TODO: Need to figure out how to deal with string type with unboudn length (i.e. `VARCHAR`).
 }
 
 impl<K, V> Item for LeafNodeItemData<K, V>
@@ -36,7 +46,7 @@ where
 {
     fn size(&self) -> usize {
         if Self::is_fixed_size() {
-            size_of::<Self>()
+            Self::fixed_layout().1
         } else {
             // Unfortunately when we have dynamic width, we have 6 byte overhead.
             // TODO: Save 2 bytes in scenarios when either K or V is fixed size.
@@ -59,141 +69,270 @@ where
     }
 
     fn align() -> usize {
-        std::cmp::max(K::align(), V::align())
+        if Self::is_fixed_size() {
+            std::cmp::max(K::align(), V::align())
+        } else {
+            // The trailing sizes are u16s, so we need at least their alignment
+            std::cmp::max(std::cmp::max(K::align(), V::align()), align_of::<u16>())
+        }
     }
 
     fn is_fixed_size() -> bool {
         K::is_fixed_size() && V::is_fixed_size()
     }
 
-    unsafe fn write(&self, buffer: *mut u8) {
+    fn write(&self, buffer: &mut [u8]) {
         if Self::is_fixed_size() {
-            *(buffer as *mut Self) = self.clone();
+            let (value_offset, _) = Self::fixed_layout();
+            buffer.fill(0);
+            self.key.write(&mut buffer[..K::fixed_size()]);
+            self.value
+                .write(&mut buffer[value_offset..value_offset + V::fixed_size()]);
         } else {
             // key
-            self.key.write(buffer);
+            self.key.write(&mut buffer[..self.key.size()]);
 
             // value
             let mut value_offset: usize = 0;
             value_offset += self.key.size();
             value_offset = align_offset(value_offset, V::align());
-            self.value.write(buffer.offset(value_offset as isize));
+            self.value
+                .write(&mut buffer[value_offset..value_offset + self.value.size()]);
 
             // key size
             let mut size_offset = value_offset;
             size_offset += self.value.size();
             size_offset = align_offset(size_offset, align_of::<u16>());
-            let size_ptr = buffer.offset(size_offset as isize) as *mut u16;
-
-            *size_ptr = self.key.size() as u16;
-            *(size_ptr.offset(1)) = self.value.size() as u16;
-            *(size_ptr.offset(2)) = value_offset as u16;
+            let sizes = [self.key.size(), self.value.size(), value_offset];
+            for (idx, size) in sizes.iter().enumerate() {
+                let at = size_offset + 2 * idx;
+                buffer[at..at + 2].copy_from_slice(&(*size as u16).to_le_bytes());
+            }
         }
     }
 
-    unsafe fn read(buffer: *const u8, size: usize) -> Self {
+    fn read(bytes: &[u8]) -> Self {
         if Self::is_fixed_size() {
-            (buffer as *mut Self).read()
+            let (value_offset, _) = Self::fixed_layout();
+            Self {
+                key: K::read(&bytes[..K::fixed_size()]),
+                value: V::read(&bytes[value_offset..value_offset + V::fixed_size()]),
+            }
         } else {
-            let size_ptr = buffer.offset((size - 3 * size_of::<u16>()) as isize) as *mut u16;
-            let key_size = *size_ptr;
-            let value_size = *size_ptr.offset(1);
-            let value_offset = *size_ptr.offset(2);
+            let (key_size, value_size, value_offset) = Self::dynamic_layout(bytes)
+                .unwrap_or_else(|err| panic!("LeafNodeItemData.read: {}", err));
             debug!(
                 "LeafNodeDataItem.read: key_size: {}, value_size: {}, value_offset: {}",
                 key_size, value_size, value_offset
             );
 
             Self {
-                key: K::read(buffer, key_size as usize),
-                value: V::read(buffer.offset(value_offset as isize), value_size as usize),
+                key: K::read(&bytes[..key_size]),
+                value: V::read(&bytes[value_offset..value_offset + value_size]),
             }
         }
     }
 }
 
-pub(super) fn fetch_page_write<'a, P, K, V>(
-    page_fetcher: &'a P,
+impl<K, V> LeafNodeItemData<K, V>
+where
+    K: Key,
+    V: Value,
+{
+    /// The `(value offset, size)` of a fixed size item, which holds the key followed by the value
+    /// at its alignment, padded with zeroes to the item's alignment.
+    fn fixed_layout() -> (usize, usize) {
+        let value_offset = align_offset(K::fixed_size(), V::align());
+        let size = align_offset(value_offset + V::fixed_size(), Self::align());
+        (value_offset, size)
+    }
+
+    /// Decodes the `(key size, value size, value offset)` trailer of a dynamically sized item
+    /// (see `write`), checking that the key and value lie within the item. The trailer is read
+    /// byte-wise since a corrupted page may not leave it aligned.
+    fn dynamic_layout(bytes: &[u8]) -> Result<(usize, usize, usize)> {
+        let malformed = || {
+            Error::corruption(format!(
+                "malformed {} byte leaf item {:?}",
+                bytes.len(),
+                &bytes[bytes.len().saturating_sub(3 * size_of::<u16>())..]
+            ))
+        };
+
+        let trailer = bytes
+            .len()
+            .checked_sub(3 * size_of::<u16>())
+            .ok_or_else(malformed)?;
+        let read_u16 = |at: usize| u16::from_le_bytes([bytes[at], bytes[at + 1]]) as usize;
+        let (key_size, value_size, value_offset) = (
+            read_u16(trailer),
+            read_u16(trailer + 2),
+            read_u16(trailer + 4),
+        );
+
+        if key_size > trailer
+            || value_offset + value_size > trailer
+            || (K::is_fixed_size() && key_size != K::fixed_size())
+            || (V::is_fixed_size() && value_size != V::fixed_size())
+        {
+            return Err(malformed());
+        }
+        Ok((key_size, value_size, value_offset))
+    }
+}
+
+impl<'a, K, V> ItemRef<'a, LeafNodeItemData<K, V>>
+where
+    K: Key,
+    V: Value,
+{
+    /// (key offset, key size, value offset, value size) within the encoded item.
+    fn layout(&self) -> (usize, usize, usize, usize) {
+        let bytes = self.bytes();
+        if LeafNodeItemData::<K, V>::is_fixed_size() {
+            let (value_offset, _) = LeafNodeItemData::<K, V>::fixed_layout();
+            (0, K::fixed_size(), value_offset, V::fixed_size())
+        } else {
+            let (key_size, value_size, value_offset) =
+                LeafNodeItemData::<K, V>::dynamic_layout(bytes)
+                    .unwrap_or_else(|err| panic!("ItemRef.layout: {}", err));
+            (0, key_size, value_offset, value_size)
+        }
+    }
+
+    pub fn key(&self) -> K {
+        let (key_offset, key_size, _, _) = self.layout();
+        K::read(&self.bytes()[key_offset..key_offset + key_size])
+    }
+
+    /// Decodes just the value, leaving the key in the page.
+    pub fn value(&self) -> V {
+        V::read(self.value_bytes())
+    }
+
+    /// The value's encoded bytes, borrowed from the page. For `ValueBytes` these are the value
+    /// itself.
+    pub fn value_bytes(&self) -> &'a [u8] {
+        let (_, _, value_offset, value_size) = self.layout();
+        &self.bytes()[value_offset..value_offset + value_size]
+    }
+}
+
+pub(super) fn fetch_page_read<P, K, V>(
+    page_fetcher: &P,
     page_no: u32,
-) -> Option<LeafNodeWriteLock<'a, K, V>>
+) -> Result<LeafNodeReadLock<'_, K, V>>
 where
     P: PageFetcherTrait,
     K: Key,
     V: Value,
 {
-    page_fetcher
-        .fetch_page_write(page_no)
-        .map(|lock| from_write_lock(page_no, lock))
+    LeafNodeReadLock::try_from((page_no, page_fetcher.fetch_page_read(page_no)?))
+}
+
+pub(super) fn fetch_page_write<P, K, V>(
+    page_fetcher: &P,
+    page_no: u32,
+) -> Result<LeafNodeWriteLock<'_, K, V>>
+where
+    P: PageFetcherTrait,
+    K: Key,
+    V: Value,
+{
+    from_write_lock(page_no, page_fetcher.fetch_page_write(page_no)?)
 }
 
 /// Initializes empty page. Note that the separator is not set here, so you'll need to do
 /// `node.set_separator(&separator)`.
-pub(super) fn new_page<'a, P, K, V>(
-    page_fetcher: &'a P,
-    right_sibling_page_no: u32,
-) -> (u32, LeafNodeWriteLock<'a, K, V>)
+pub(super) fn new_page<P, K, V>(
+    page_fetcher: &P,
+    right_sibling: PageNo,
+) -> Result<(u32, LeafNodeWriteLock<'_, K, V>)>
 where
     P: PageFetcherTrait,
     K: Key,
     V: Value,
 {
-    let (page_no, lock) = page_fetcher.new_page(BTreePageData {
-        node_type: NodeType::Leaf,
-        right_sibling_page_no,
-    });
+    let (page_no, lock) =
+        page_fetcher.new_page(BTreePageData::new(NodeType::Leaf, right_sibling))?;
 
-    (
+    Ok((
         page_no,
         LeafNodeWriteLock {
             page_no,
             page: lock,
-            phantom: PhantomData,
+            separator: OnceCell::new(),
             phantom_value: PhantomData,
         },
-    )
+    ))
 }
 
 pub(super) fn from_write_lock<K, V>(
     page_no: u32,
-    lock: RwLockWriteGuard<PagePtr>,
-) -> LeafNodeWriteLock<K, V>
+    lock: RwLockWriteGuard<'_, PagePtr>,
+) -> Result<LeafNodeWriteLock<'_, K, V>>
 where
     K: Key,
     V: Value,
 {
-    assert!(matches!(
-        lock.special_data::<BTreePageData>().node_type,
-        NodeType::Leaf
-    ));
+    check_node_type(page_no, &lock)?;
 
-    LeafNodeWriteLock {
+    Ok(LeafNodeWriteLock {
         page_no,
         page: lock,
-        phantom: PhantomData,
+        separator: OnceCell::new(),
         phantom_value: PhantomData,
+    })
+}
+
+fn check_node_type(page_no: u32, page: &Page) -> Result<()> {
+    let node_type = BTreePageData::checked_node_type(page_no, page)?;
+    if !matches!(node_type, NodeType::Leaf) {
+        return Err(Error::page_corruption(
+            page_no,
+            format!("expected a leaf node, found {:?}", node_type),
+        ));
     }
+
+    Ok(())
 }
 
-pub(super) trait LeafNodeRead<K, V>
+impl<K, V> NodeItem for LeafNodeItemData<K, V>
 where
     K: Key,
     V: Value,
 {
-    fn page_ref(&self) -> &Page;
+    type Key = K;
 
-    fn item_iter(&self) -> Skip<PageItemIteratorV2<LeafNodeItemData<K, V>>> {
-        // We skip the first element, because it's always the separator
-        self.page_ref()
-            .items_iter_v2::<LeafNodeItemData<K, V>>()
-            .skip(1)
+    fn key(&self) -> &K {
+        &self.key
     }
 
-    fn separator(&self) -> K {
-        self.page_ref().get_item_v2::<K>(0)
+    fn read_key(bytes: &[u8]) -> K {
+        let key_size = match K::is_fixed_size() {
+            true => K::fixed_size(),
+            false => {
+                Self::dynamic_layout(bytes)
+                    .unwrap_or_else(|err| panic!("LeafNodeItemData.read_key: {}", err))
+                    .0
+            }
+        };
+        K::read(&bytes[..key_size])
     }
 
-    fn special_data(&self) -> &BTreePageData {
-        self.page_ref().special_data()
+    fn check_encoding(bytes: &[u8]) -> Result<()> {
+        if Self::is_fixed_size() {
+            let (_, size) = Self::fixed_layout();
+            if bytes.len() != size {
+                return Err(Error::corruption(format!(
+                    "{} byte leaf item, expected {} bytes",
+                    bytes.len(),
+                    size
+                )));
+            }
+            return Ok(());
+        }
+        Self::dynamic_layout(bytes).map(|_| ())
     }
 }
 
@@ -202,13 +341,13 @@ where
     K: Key,
     V: Value,
 {
-    page_no: u32,
+    pub(super) page_no: u32,
     page: RwLockReadGuard<'a, PagePtr>,
-    phantom: PhantomData<K>,
+    separator: OnceCell<Separator<K>>,
     phantom_value: PhantomData<V>,
 }
 
-impl<'a, K, V> LeafNodeRead<K, V> for LeafNodeReadLock<'a, K, V>
+impl<'a, K, V> NodeRead<LeafNodeItemData<K, V>> for LeafNodeReadLock<'a, K, V>
 where
     K: Key,
     V: Value,
@@ -217,20 +356,32 @@ where
     fn page_ref(&self) -> &Page {
         self.page.deref().deref()
     }
+
+    fn page_no(&self) -> u32 {
+        self.page_no
+    }
+
+    fn separator_cache(&self) -> &OnceCell<Separator<K>> {
+        &self.separator
+    }
 }
 
-impl<'a, K, V> From<(u32, RwLockReadGuard<'a, PagePtr>)> for LeafNodeReadLock<'a, K, V>
+impl<'a, K, V> TryFrom<(u32, RwLockReadGuard<'a, PagePtr>)> for LeafNodeReadLock<'a, K, V>
 where
     K: Key,
     V: Value,
 {
-    fn from(value: (u32, RwLockReadGuard<'a, PagePtr>)) -> Self {
-        Self {
+    type Error = Error;
+
+    fn try_from(value: (u32, RwLockReadGuard<'a, PagePtr>)) -> Result<Self> {
+        check_node_type(value.0, &value.1)?;
+
+        Ok(Self {
             page_no: value.0,
             page: value.1,
-            phantom: PhantomData,
+            separator: OnceCell::new(),
             phantom_value: PhantomData,
-        }
+        })
     }
 }
 
@@ -241,11 +392,11 @@ where
 {
     pub page_no: u32,
     page: RwLockWriteGuard<'a, PagePtr>,
-    phantom: PhantomData<K>,
+    separator: OnceCell<Separator<K>>,
     phantom_value: PhantomData<V>,
 }
 
-impl<'a, K, V> LeafNodeRead<K, V> for LeafNodeWriteLock<'a, K, V>
+impl<'a, K, V> NodeRead<LeafNodeItemData<K, V>> for LeafNodeWriteLock<'a, K, V>
 where
     K: Key,
     V: Value,
@@ -254,6 +405,28 @@ where
     fn page_ref(&self) -> &Page {
         self.page.deref().deref()
     }
+
+    fn page_no(&self) -> u32 {
+        self.page_no
+    }
+
+    fn separator_cache(&self) -> &OnceCell<Separator<K>> {
+        &self.separator
+    }
+}
+
+impl<'a, K, V> NodeWrite<LeafNodeItemData<K, V>> for LeafNodeWriteLock<'a, K, V>
+where
+    K: Key,
+    V: Value,
+{
+    fn page_ref_mut(&mut self) -> &mut Page {
+        self.page.deref_mut()
+    }
+
+    fn separator_cache_mut(&mut self) -> &mut OnceCell<Separator<K>> {
+        &mut self.separator
+    }
 }
 
 impl<'a, K, V> LeafNodeWriteLock<'a, K, V>
@@ -261,72 +434,153 @@ where
     K: Key,
     V: Value,
 {
-    pub(super) fn add_item(&mut self, item: &LeafNodeItemData<K, V>) -> Result<(), &'static str> {
-        if item.key > self.separator() {
-            return Err(
-                "We can't add due to item not fitting within this page's allowed key range",
-            );
+    /// Like `add_item`, but if the page is full and rebuilding it would reclaim enough dead
+    /// space, we compact it and try again before giving up with `Error::PageFull`. The free space
+    /// left counts towards the room too, so an item removed from a full page fits back in.
+    pub(super) fn add_item_compacting(&mut self, item: &LeafNodeItemData<K, V>) -> Result<()> {
+        match self.add_item(item) {
+            Err(Error::PageFull)
+                if self.page.free_space() + self.page.dead_space()
+                    >= item.size() + ITEM_POINTER_SIZE =>
+            {
+                debug!(
+                    "[LeafNodeWriteLock.add_item_compacting ({})] Compacting before retry",
+                    self.page_no
+                );
+                self.compact()?;
+                self.add_item(item)
+            }
+            res => res,
         }
+    }
 
-        debug!(
-            "[LeafNodeWriteLock.add_item ({})] Adding {:?}",
-            self.page_no, item
-        );
+    /// Removes the item with `key`, returning its value if it was present.
+    pub(super) fn remove_item(&mut self, key: &K) -> Option<V> {
+        let (idx, item) = self.find_item(key)?;
+        let value = item.value();
 
-        self.page.add_item_v2(item)
+        self.page.remove_item_v2(idx);
+        Some(value)
     }
 
-    pub(super) fn set_separator(&mut self, sep: &K) {
-        assert_eq!(self.page.item_cnt(), 0);
+    /// Removes every item with a key within `range`, returning how many there were.
+    pub(super) fn remove_range<R: RangeBounds<K>>(&mut self, range: &R) -> usize {
+        let idxs = self
+            .page
+            .item_refs_from::<LeafNodeItemData<K, V>>(1)
+            .enumerate()
+            .filter(|(_, item)| range.contains(&item.key()))
+            .map(|(idx, _)| idx + 1)
+            .collect::<Vec<_>>();
+        // Back to front, since removing an item shifts the ones after it
+        for idx in idxs.iter().rev() {
+            self.page.remove_item_v2(*idx);
+        }
+        idxs.len()
+    }
 
-        // TODO: handle error here
-        self.page.add_item_v2(sep).unwrap();
+    /// Removes every item whose value is a tombstone, see `Value::tombstone`, returning how many
+    /// there were.
+    pub(super) fn remove_tombstones(&mut self) -> usize {
+        let idxs = self
+            .page
+            .item_refs_from::<LeafNodeItemData<K, V>>(1)
+            .enumerate()
+            .filter(|(_, item)| V::is_tombstone(item.value_bytes()))
+            .map(|(idx, _)| idx + 1)
+            .collect::<Vec<_>>();
+        for idx in idxs.iter().rev() {
+            self.page.remove_item_v2(*idx);
+        }
+        idxs.len()
     }
 
-    pub fn special_data_mut(&mut self) -> &mut BTreePageData {
-        self.page_ref_mut().special_data_mut()
+    /// Hints every value pointing at a tuple for which `is_dead` returns true as dead, see
+    /// `ValueTupleId::is_hinted_dead`, returning how many weren't already. Values without tuples
+    /// are left alone, see `Value::tuple_id`.
+    pub(super) fn hint_dead_tuples<F>(&mut self, mut is_dead: F) -> Result<usize>
+    where
+        F: FnMut(ValueTupleId) -> Result<bool>,
+    {
+        let mut hinted = Vec::new();
+        for (idx, item) in self
+            .page
+            .item_refs_from::<LeafNodeItemData<K, V>>(1)
+            .enumerate()
+        {
+            let bytes = item.value_bytes();
+            let id = match V::tuple_id(bytes) {
+                Some(id) if !ValueTupleId::is_hinted_dead(bytes) => id,
+                _ => continue,
+            };
+            if is_dead(id)? {
+                let (_, _, value_offset, value_size) = item.layout();
+                hinted.push((idx + 1, value_offset, value_size));
+            }
+        }
+        for (idx, value_offset, value_size) in hinted.iter() {
+            let (offset, _) = self.page.item_pointer(*idx);
+            let start = offset + value_offset;
+            ValueTupleId::hint_dead(&mut self.page.data[start..start + value_size]);
+        }
+        Ok(hinted.len())
     }
 
-    pub(super) fn page_ref_mut(&mut self) -> &mut Page {
-        self.page.deref_mut()
+    /// Rebuilds the page from its live items, reclaiming space left behind by removed items.
+    pub(super) fn compact(&mut self) -> Result<()> {
+        let separator = self.separator().clone();
+        let items = self.item_iter().collect::<Vec<_>>();
+
+        self.page.zero_out_item_data();
+        self.set_separator(&separator)?;
+        for item in items.iter() {
+            self.page.add_item_v2(item)?;
+        }
+
+        Ok(())
     }
 }
 
-impl<'a, K, V> Into<RwLockWriteGuard<'a, PagePtr>> for LeafNodeWriteLock<'a, K, V>
+impl<'a, K, V> From<LeafNodeWriteLock<'a, K, V>> for RwLockWriteGuard<'a, PagePtr>
 where
     K: Key,
     V: Value,
 {
-    fn into(self) -> RwLockWriteGuard<'a, PagePtr> {
-        self.page
+    fn from(node: LeafNodeWriteLock<'a, K, V>) -> Self {
+        node.page
     }
 }
 
 pub(super) fn find_move_right<'a, P, K, V>(
     page_fetcher: &'a P,
-    mut leaf_no: u32,
-    key: K,
-) -> LeafNodeWriteLock<'a, K, V>
+    leaf_no: u32,
+    key: &K,
+) -> Result<LeafNodeWriteLock<'a, K, V>>
 where
     P: PageFetcherTrait,
     K: Key,
     V: Value,
 {
     debug!("[find_move_right] Starting leaf_no: {}", leaf_no);
-    while leaf_no != 0 {
+    let mut next = Some(leaf_no);
+    while let Some(next_no) = next {
         // We release the leaf lock at the end of this while block, which means we're at most
         // holding one write lock at any given time within this function
-        let leaf = fetch_page_write(page_fetcher, leaf_no).unwrap();
+        let leaf = fetch_page_write(page_fetcher, next_no)?;
 
-        if key < leaf.separator() {
-            debug!("[find_move_right] Found leaf_no: {}", leaf_no);
-            return leaf;
+        if leaf.separator().is_above(key) {
+            debug!("[find_move_right] Found leaf_no: {}", next_no);
+            return Ok(leaf);
         } else {
-            leaf_no = leaf.special_data().right_sibling_page_no;
+            page_fetcher.metrics().move_rights.inc();
+            next = leaf.special_data().right_sibling().get();
         }
     }
 
-    panic!("For some reason we couldn't find the child ptr containing key, probably bug somewhere here!");
+    Err(Error::page_corruption(
+        leaf_no,
+        format!("couldn't find leaf for key {:?} moving right", key),
+    ))
 }
 
 #[cfg(test)]
@@ -334,13 +588,16 @@ where
 mod tests {
     use crate::btree::key::Key;
     use crate::btree::key::KeyU32;
-    use crate::btree::leaf_node::LeafNodeRead;
+    use crate::btree::node::NodeRead;
+    use crate::btree::node::NodeWrite;
+    use crate::btree::node::Separator;
     use crate::btree::value::ValueTupleId;
     use crate::page_fetcher::InMemoryPageFetcher;
 
     use super::new_page;
     use super::LeafNodeItemData;
     use crate::page::Item;
+    use crate::page::PageNo;
     use std::mem::align_of;
     use std::mem::align_of_val;
     use std::mem::size_of_val;
@@ -369,11 +626,11 @@ mod tests {
         fn is_fixed_size() -> bool {
             false
         }
-        unsafe fn write(&self, buffer: *mut u8) {
-            *buffer = self.key
+        fn write(&self, buffer: &mut [u8]) {
+            buffer[0] = self.key
         }
-        unsafe fn read(buffer: *const u8, size: usize) -> Self {
-            Self { key: *buffer }
+        fn read(bytes: &[u8]) -> Self {
+            Self { key: bytes[0] }
         }
     }
 
@@ -399,12 +656,11 @@ mod tests {
             align_of_val(&leaf_data)
         );
 
-        unsafe {
-            leaf_data.write(&mut buffer.0[0] as *mut u8);
-        }
-        assert_eq!(leaf_data, unsafe {
-            LeafNodeItemData::<KeyU32, ValueTupleId>::read(&mut buffer.0[0] as *mut u8, 12)
-        })
+        leaf_data.write(&mut buffer.0[..12]);
+        assert_eq!(
+            leaf_data,
+            LeafNodeItemData::<KeyU32, ValueTupleId>::read(&buffer.0[..12])
+        )
     }
 
     #[test]
@@ -430,9 +686,7 @@ mod tests {
             align_of_val(&leaf_data)
         );
         assert_eq!(LeafNodeItemData::<KeyDynamic, ValueTupleId>::align(), 4,);
-        unsafe {
-            leaf_data.write(&mut buffer.0[0] as *mut u8);
-        }
+        leaf_data.write(&mut buffer.0[..expected_size]);
         println!("buffer: {:#04X?}", &buffer.0);
         #[rustfmt::skip]
         assert_eq!(&buffer.0, &([
@@ -455,21 +709,20 @@ mod tests {
             0x04, 0x00,
         ] as [u8; 18]));
 
-        assert_eq!(leaf_data, unsafe {
-            LeafNodeItemData::<KeyDynamic, ValueTupleId>::read(
-                &mut buffer.0[0] as *mut u8,
-                expected_size,
-            )
-        })
+        assert_eq!(
+            leaf_data,
+            LeafNodeItemData::<KeyDynamic, ValueTupleId>::read(&buffer.0[..expected_size])
+        )
     }
 
     #[test]
     fn leaf_node_separator() {
         let page_fetcher = InMemoryPageFetcher::new();
-        let (_, mut leaf) = new_page::<_, KeyU32, ValueTupleId>(&page_fetcher, 0);
+        let (_, mut leaf) =
+            new_page::<_, KeyU32, ValueTupleId>(&page_fetcher, PageNo::INVALID).unwrap();
 
-        let sep = KeyU32 { key: 34 };
-        leaf.set_separator(&sep);
-        assert_eq!(leaf.separator(), sep);
+        let sep = Separator::Key(KeyU32 { key: 34 });
+        leaf.set_separator(&sep).unwrap();
+        assert_eq!(*leaf.separator(), sep);
     }
 }
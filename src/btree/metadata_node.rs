@@ -1,25 +1,263 @@
+use super::key::Key;
 use super::key::KeyU32;
+use super::value::Value;
+use super::value::ValueBytes;
 use crate::btree::BTreePageData;
 use crate::btree::NodeType;
+use crate::error::Error;
+use crate::error::Result;
+use crate::page::Item;
 use crate::page::Page;
 use crate::page_fetcher::PagePtr;
+use std::convert::TryFrom;
+use std::mem::size_of;
 use std::ops::Deref;
 use std::sync::RwLockReadGuard;
 use std::sync::RwLockWriteGuard;
 
+/// Hashes of the key and value type names a tree was created with. Stored as the metadata page's
+/// first item, followed by the root page number once there is a root.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MetadataTypes {
+    key_type: u64,
+    value_type: u64,
+}
+
+impl MetadataTypes {
+    pub fn of<K: Key, V: Value>() -> Self {
+        Self {
+            key_type: fnv1a(&K::type_name()),
+            value_type: fnv1a(&V::type_name()),
+        }
+    }
+}
+
+/// FNV-1a, which unlike `DefaultHasher` is guaranteed to be stable across releases.
+fn fnv1a(s: &str) -> u64 {
+    s.bytes().fold(0xcbf29ce484222325, |hash, byte| {
+        (hash ^ byte as u64).wrapping_mul(0x100000001b3)
+    })
+}
+
+impl Item for MetadataTypes {
+    fn size(&self) -> usize {
+        size_of::<Self>()
+    }
+
+    fn align() -> usize {
+        // Page data starts right after the 12 byte header, so in-page alignment doesn't carry
+        // over to u64s. We use unaligned accesses instead.
+        1
+    }
+
+    fn is_fixed_size() -> bool {
+        true
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        write_u64_le(buffer, self.key_type);
+        write_u64_le(&mut buffer[8..], self.value_type);
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        assert!(bytes.len() == size_of::<Self>());
+
+        Self {
+            key_type: read_u64_le(bytes),
+            value_type: read_u64_le(&bytes[8..]),
+        }
+    }
+}
+
+/// The upper bound of a tree's sequence, see `BTree::next_sequence`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SequenceLimit {
+    pub limit: u64,
+}
+
+impl Item for SequenceLimit {
+    fn size(&self) -> usize {
+        size_of::<Self>()
+    }
+
+    fn align() -> usize {
+        // See `MetadataTypes::align`
+        1
+    }
+
+    fn is_fixed_size() -> bool {
+        true
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        write_u64_le(buffer, self.limit);
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        assert!(bytes.len() == size_of::<Self>());
+
+        Self {
+            limit: read_u64_le(bytes),
+        }
+    }
+}
+
+/// How many items were deleted from the tree since it was last vacuumed, see
+/// `BTree::dead_item_cnt`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DeadItemCnt {
+    pub cnt: u64,
+}
+
+impl Item for DeadItemCnt {
+    fn size(&self) -> usize {
+        size_of::<Self>()
+    }
+
+    fn align() -> usize {
+        // See `MetadataTypes::align`
+        1
+    }
+
+    fn is_fixed_size() -> bool {
+        true
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        write_u64_le(buffer, self.cnt);
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        assert!(bytes.len() == size_of::<Self>());
+
+        Self {
+            cnt: read_u64_le(bytes),
+        }
+    }
+}
+
+fn write_u64_le(buffer: &mut [u8], value: u64) {
+    buffer[..size_of::<u64>()].copy_from_slice(&value.to_le_bytes());
+}
+
+fn read_u64_le(bytes: &[u8]) -> u64 {
+    let mut value = [0u8; 8];
+    value.copy_from_slice(&bytes[..size_of::<u64>()]);
+    u64::from_le_bytes(value)
+}
+
+/// Items of the metadata page after the types, each only present once it or a later one is set.
+/// Page numbers of 0 stand in for missing ones, since page 0 can't be a root or attached page.
+const ROOT_NO_IDX: usize = 1;
+const ATTACHED_NO_IDX: usize = 2;
+pub const SEQUENCE_LIMIT_IDX: usize = 3;
+const FREE_SPACE_NO_IDX: usize = 4;
+pub const DEAD_ITEM_CNT_IDX: usize = 5;
+const STATS_NO_IDX: usize = 6;
+pub const COLLATION_IDX: usize = 7;
+const MAX_ITEM_CNT: usize = 8;
+
+/// The metadata page holds the tree's types, then its root page number, then optionally an
+/// attached page number (e.g. a catalog's metadata page), the tree's sequence limit, the first
+/// page of its free space map, its dead item count, the first page of its statistics and its
+/// key's collation.
 pub trait MetadataRead {
     fn page(&self) -> &Page;
 
-    fn root_no(&self) -> Option<u32> {
+    fn page_no(&self) -> u32;
+
+    fn types(&self) -> Result<MetadataTypes> {
+        if self.page().item_cnt() == 0 {
+            return Err(Error::page_corruption(
+                self.page_no(),
+                "metadata page is missing the tree's types",
+            ));
+        }
+        Ok(self.page().get_item_v2::<MetadataTypes>(0))
+    }
+
+    fn root_no(&self) -> Result<Option<u32>> {
+        self.page_no_item(ROOT_NO_IDX)
+    }
+
+    fn attached_no(&self) -> Result<Option<u32>> {
+        self.page_no_item(ATTACHED_NO_IDX)
+    }
+
+    fn free_space_no(&self) -> Result<Option<u32>> {
+        self.page_no_item(FREE_SPACE_NO_IDX)
+    }
+
+    fn stats_no(&self) -> Result<Option<u32>> {
+        self.page_no_item(STATS_NO_IDX)
+    }
+
+    fn sequence_limit(&self) -> Result<Option<u64>> {
+        self.check_item_cnt()?;
+        if self.page().item_cnt() <= SEQUENCE_LIMIT_IDX {
+            return Ok(None);
+        }
+        Ok(Some(
+            self.page()
+                .get_item_v2::<SequenceLimit>(SEQUENCE_LIMIT_IDX)
+                .limit,
+        ))
+    }
+
+    fn dead_item_cnt(&self) -> Result<u64> {
+        self.check_item_cnt()?;
+        if self.page().item_cnt() <= DEAD_ITEM_CNT_IDX {
+            return Ok(0);
+        }
+        Ok(self
+            .page()
+            .get_item_v2::<DeadItemCnt>(DEAD_ITEM_CNT_IDX)
+            .cnt)
+    }
+
+    /// The collation the tree was built with, if it isn't `BINARY_COLLATION`, see `Key::collation`.
+    fn collation(&self) -> Result<Option<String>> {
+        self.check_item_cnt()?;
+        if self.page().item_cnt() <= COLLATION_IDX {
+            return Ok(None);
+        }
+        let collation = self.page().get_item_ref::<ValueBytes>(COLLATION_IDX);
+        match std::str::from_utf8(collation.bytes()) {
+            Ok(collation) => Ok(Some(collation.to_string())),
+            Err(_) => Err(Error::page_corruption(
+                self.page_no(),
+                "tree's collation isn't UTF-8",
+            )),
+        }
+    }
+
+    fn page_no_item(&self, idx: usize) -> Result<Option<u32>> {
+        self.check_item_cnt()?;
+        if self.page().item_cnt() <= idx {
+            return Ok(None);
+        }
+        match self.page().get_item_v2::<KeyU32>(idx).key {
+            0 => Ok(None),
+            page_no => Ok(Some(page_no)),
+        }
+    }
+
+    fn check_item_cnt(&self) -> Result<()> {
         match self.page().item_cnt() {
-            0 => None,
-            1 => Some(self.page().get_item_v2::<KeyU32>(0).key),
-            _ => panic!("Somehow we have multiple items in the metadata node!"),
+            1..=MAX_ITEM_CNT => Ok(()),
+            cnt => Err(Error::page_corruption(
+                self.page_no(),
+                format!(
+                    "metadata page has {} items, expected 1 to {}",
+                    cnt, MAX_ITEM_CNT
+                ),
+            )),
         }
     }
 }
 
 pub struct MetadataReadLock<'a> {
+    page_no: u32,
     page: RwLockReadGuard<'a, PagePtr>,
 }
 
@@ -27,19 +265,23 @@ impl<'a> MetadataRead for MetadataReadLock<'a> {
     fn page(&self) -> &Page {
         self.page.deref().deref()
     }
+
+    fn page_no(&self) -> u32 {
+        self.page_no
+    }
 }
 
-impl<'a> From<RwLockReadGuard<'a, PagePtr>> for MetadataReadLock<'a> {
-    fn from(page: RwLockReadGuard<'a, PagePtr>) -> Self {
-        assert!(matches!(
-            page.special_data::<BTreePageData>().node_type,
-            NodeType::Metadata
-        ));
-        Self { page }
+impl<'a> TryFrom<(u32, RwLockReadGuard<'a, PagePtr>)> for MetadataReadLock<'a> {
+    type Error = Error;
+
+    fn try_from((page_no, page): (u32, RwLockReadGuard<'a, PagePtr>)) -> Result<Self> {
+        check_node_type(page_no, &page)?;
+        Ok(Self { page_no, page })
     }
 }
 
 pub struct MetadataWriteLock<'a> {
+    page_no: u32,
     page: RwLockWriteGuard<'a, PagePtr>,
 }
 
@@ -47,28 +289,132 @@ impl<'a> MetadataRead for MetadataWriteLock<'a> {
     fn page(&self) -> &Page {
         self.page.deref().deref()
     }
+
+    fn page_no(&self) -> u32 {
+        self.page_no
+    }
 }
 
 impl<'a> MetadataWriteLock<'a> {
-    pub fn set_root_no(&mut self, root_no: u32) {
-        match self.page.item_cnt() {
-            0 => {
-                self.page.add_item_v2(&KeyU32 { key: root_no });
-            }
-            1 => {
-                self.page.update_item_v2(0, &KeyU32 { key: root_no });
-            }
-            _ => panic!("Somehow we have multiple items in the metadata node!"),
+    pub fn init_types(&mut self, types: &MetadataTypes) -> Result<()> {
+        assert_eq!(self.page.item_cnt(), 0);
+
+        self.page.add_item_v2(types)
+    }
+
+    pub fn set_root_no(&mut self, root_no: u32) -> Result<()> {
+        self.set_item(ROOT_NO_IDX, &KeyU32 { key: root_no })
+    }
+
+    pub fn set_attached_no(&mut self, attached_no: u32) -> Result<()> {
+        self.set_item(ATTACHED_NO_IDX, &KeyU32 { key: attached_no })
+    }
+
+    pub fn set_sequence_limit(&mut self, limit: u64) -> Result<()> {
+        self.set_item(SEQUENCE_LIMIT_IDX, &SequenceLimit { limit })
+    }
+
+    pub fn set_free_space_no(&mut self, free_space_no: u32) -> Result<()> {
+        self.set_item(FREE_SPACE_NO_IDX, &KeyU32 { key: free_space_no })
+    }
+
+    pub fn set_dead_item_cnt(&mut self, cnt: u64) -> Result<()> {
+        self.set_item(DEAD_ITEM_CNT_IDX, &DeadItemCnt { cnt })
+    }
+
+    pub fn set_stats_no(&mut self, stats_no: u32) -> Result<()> {
+        self.set_item(STATS_NO_IDX, &KeyU32 { key: stats_no })
+    }
+
+    pub fn set_collation(&mut self, collation: &str) -> Result<()> {
+        let collation = ValueBytes {
+            value: collation.as_bytes().to_vec(),
         };
+        self.set_item(COLLATION_IDX, &collation)
+    }
+
+    /// Sets item `idx`, first adding any missing items before it as 0s, which a sequence limit or
+    /// dead item count of 0 also stands in for.
+    fn set_item<I: Item>(&mut self, idx: usize, item: &I) -> Result<()> {
+        self.check_item_cnt()?;
+        while self.page.item_cnt() < idx {
+            match self.page.item_cnt() {
+                SEQUENCE_LIMIT_IDX => self.page.add_item_v2(&SequenceLimit { limit: 0 })?,
+                DEAD_ITEM_CNT_IDX => self.page.add_item_v2(&DeadItemCnt { cnt: 0 })?,
+                _ => self.page.add_item_v2(&KeyU32 { key: 0 })?,
+            }
+        }
+        if self.page.item_cnt() == idx {
+            self.page.add_item_v2(item)
+        } else {
+            self.page.update_item_v2(idx, item);
+            Ok(())
+        }
+    }
+}
+
+impl<'a> TryFrom<(u32, RwLockWriteGuard<'a, PagePtr>)> for MetadataWriteLock<'a> {
+    type Error = Error;
+
+    fn try_from((page_no, page): (u32, RwLockWriteGuard<'a, PagePtr>)) -> Result<Self> {
+        check_node_type(page_no, &page)?;
+        Ok(Self { page_no, page })
     }
 }
 
-impl<'a> From<RwLockWriteGuard<'a, PagePtr>> for MetadataWriteLock<'a> {
-    fn from(page: RwLockWriteGuard<'a, PagePtr>) -> Self {
-        assert!(matches!(
-            page.special_data::<BTreePageData>().node_type,
-            NodeType::Metadata
+fn check_node_type(page_no: u32, page: &Page) -> Result<()> {
+    let node_type = BTreePageData::checked_node_type(page_no, page)?;
+    if !matches!(node_type, NodeType::Metadata) {
+        return Err(Error::page_corruption(
+            page_no,
+            format!("expected a metadata page, found {:?}", node_type),
         ));
-        Self { page }
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::MetadataTypes;
+    use crate::btree::collation::Binary;
+    use crate::btree::collation::CaseInsensitive;
+    use crate::btree::key::*;
+    use crate::btree::time_series::KeyTimestamp;
+    use crate::btree::value::*;
+    use crate::json::ValueJson;
+
+    fn key_type<K: Key>() -> u64 {
+        MetadataTypes::of::<K, ValueBytes>().key_type
+    }
+
+    fn value_type<V: Value>() -> u64 {
+        MetadataTypes::of::<KeyBytes, V>().value_type
+    }
+
+    /// These are stored in every tree's metadata page, so changing one makes existing trees fail
+    /// to open with `TypeMismatch`.
+    #[test]
+    fn type_ids_are_stable() {
+        assert_eq!(key_type::<KeyU32>(), 0x85992bd8563a67b8);
+        assert_eq!(key_type::<KeyBytes>(), 0x1fb21f46b11a4da9);
+        assert_eq!(key_type::<KeyU64>(), 0x858f1fd85631fecf);
+        assert_eq!(key_type::<KeyU128>(), 0x2b8e329a7ac0bebe);
+        assert_eq!(key_type::<KeyI64>(), 0x91da33d7cbd35d4b);
+        assert_eq!(key_type::<KeyF64>(), 0xe0fa96d7f8f96766);
+        assert_eq!(key_type::<KeyArray<16>>(), 0x61e6161964fd0590);
+        assert_eq!(key_type::<KeyEncoded>(), 0xb4124791b5f8b52c);
+        assert_eq!(key_type::<KeyTimestamp>(), 0x030e00a1951d06d6);
+        assert_eq!(key_type::<KeyString<Binary>>(), 0xc72a09cbeb35a0e3);
+        assert_eq!(key_type::<KeyString<CaseInsensitive>>(), 0xc72a09cbeb35a0e3);
+        assert_eq!(key_type::<KeyDesc<KeyU32>>(), 0x85992bd8563a67b8);
+
+        assert_eq!(value_type::<ValueTupleId>(), 0x344698b6077d26f9);
+        assert_eq!(value_type::<ValueBytes>(), 0xbf0ce94c08a8dd51);
+        assert_eq!(value_type::<ValueLarge>(), 0x86f6120ddfd8addd);
+        assert_eq!(value_type::<ValueMaybe<ValueBytes>>(), 0x236487b9e9121ba8);
+        assert_eq!(value_type::<ValueMaybe<ValueTupleId>>(), 0xa0373b966cadabe0);
+        assert_eq!(value_type::<ValueNumeric>(), 0x24089d96b078a85d);
+        assert_eq!(value_type::<ValueJson>(), 0xfce97cdd9d390797);
     }
 }
@@ -1,12 +1,60 @@
+use crate::error::Error;
+use crate::error::Result;
+use crate::events::EventListener;
+use crate::metrics::OpStats;
+use crate::metrics::OpStatsSnapshot;
+use crate::page::special_area_size;
+use crate::page::Page;
+use crate::page::PageNo;
+use crate::page::PAGE_DATA_SIZE;
 use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use collation::BINARY_COLLATION;
+use core::marker::PhantomData;
+use key::Key;
+use metadata_node::MetadataRead;
+use metadata_node::MetadataReadLock;
+use metadata_node::MetadataTypes;
+use metadata_node::MetadataWriteLock;
+use std::convert::TryFrom;
+use std::mem::offset_of;
+use std::mem::size_of;
+use std::sync::Arc;
+use value::Value;
 
+pub mod aggregate;
+pub mod analyze;
+pub mod bulk_load;
+pub mod collation;
+pub mod comparator;
+pub mod delete;
+#[cfg(test)]
+mod differential;
+pub mod dump;
+pub mod explain;
+pub mod export;
+mod free_space;
+pub mod heap;
 pub mod insert;
+pub mod integrity;
 mod internal_node;
-mod key;
+#[cfg(debug_assertions)]
+mod invariants;
+pub mod key;
+#[cfg(feature = "uuid")]
+pub mod key_uuid;
 mod leaf_node;
 mod metadata_node;
-mod search;
-mod value;
+mod node;
+pub mod overflow;
+pub mod scan;
+pub mod search;
+pub mod sequence;
+mod span;
+pub mod stats;
+pub mod time_series;
+pub mod vacuum;
+pub mod value;
+pub mod verify;
 /*
  * Running TODOs:
  *  * ? Sort items based on key for binary search?
@@ -16,8 +64,6 @@ mod value;
  *  * Store max key in special data and not iterate through items to find max key
  *  * Add left_sibling_no so we can traverse in both directions
  *  * Remove <T> from PagePtr<T>. Not necessary.
- *  * Audit all the `unwrap()` calls and add better error messaging/logging instead
- *  * Add error handling + logging in case PageFetcher.fetch* returns an Err(...)
  *
 */
 
@@ -25,65 +71,384 @@ mod value;
  * Btree
  */
 
-pub struct BTree<PageFetcher>
+pub struct BTree<K, V, PageFetcher>
 where
+    K: Key,
+    V: Value,
     PageFetcher: PageFetcherTrait,
 {
     page_fetcher: PageFetcher,
+    metadata_no: u32,
+    listeners: Vec<Arc<dyn EventListener>>,
+    op_stats: OpStats,
+    /// The heap page `insert_tuple` appends to, until it fills up.
+    heap_page_no: Option<u32>,
+    /// Loaded from the metadata page on first use.
+    sequence: Option<sequence::Sequence>,
+    phantom: PhantomData<(K, V)>,
+}
+
+impl<K, V, PageFetcher> BTree<K, V, PageFetcher>
+where
+    K: Key,
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Wraps `page_fetcher`, initializing the metadata page if the fetcher doesn't have one yet.
+    /// An existing tree must have been created with the same key and value types, otherwise this
+    /// fails with `Error::TypeMismatch`.
+    pub fn new(page_fetcher: PageFetcher) -> Result<Self> {
+        if page_fetcher.fetch_page_read(0).is_err() {
+            let btree = Self::create(page_fetcher)?;
+            assert_eq!(btree.metadata_no, 0);
+            Ok(btree)
+        } else {
+            Self::open(page_fetcher, 0)
+        }
+    }
+
+    /// Creates an empty tree with its metadata on a newly allocated page, see `metadata_no`.
+    /// This lets several trees share a page fetcher.
+    pub fn create(page_fetcher: PageFetcher) -> Result<Self> {
+        let (metadata_no, lock) =
+            page_fetcher.new_page(BTreePageData::new(NodeType::Metadata, PageNo::INVALID))?;
+        let mut metadata = MetadataWriteLock::try_from((metadata_no, lock))?;
+        metadata.init_types(&MetadataTypes::of::<K, V>())?;
+        if K::collation() != BINARY_COLLATION {
+            metadata.set_collation(&K::collation())?;
+        }
+        drop(metadata);
+        Ok(Self::with_metadata_no(page_fetcher, metadata_no))
+    }
+
+    /// Opens the tree whose metadata is on `metadata_no`, which must have been created with the
+    /// same key and value types and key collation, otherwise this fails with
+    /// `Error::TypeMismatch`.
+    pub fn open(page_fetcher: PageFetcher, metadata_no: u32) -> Result<Self> {
+        let types = MetadataTypes::of::<K, V>();
+        let metadata =
+            MetadataReadLock::try_from((metadata_no, page_fetcher.fetch_page_read(metadata_no)?))?;
+        let found = metadata.types()?;
+        if found != types {
+            return Err(Error::TypeMismatch(format!(
+                "tree was created for {:?}, opened as ({}, {})",
+                found,
+                K::type_name(),
+                V::type_name()
+            )));
+        }
+        let collation = metadata.collation()?;
+        let collation = collation.as_deref().unwrap_or(BINARY_COLLATION);
+        if collation != K::collation() {
+            return Err(Error::TypeMismatch(format!(
+                "tree was built with collation {:?}, opened with {:?}",
+                collation,
+                K::collation()
+            )));
+        }
+        drop(metadata);
+
+        Ok(Self::with_metadata_no(page_fetcher, metadata_no))
+    }
+
+    fn with_metadata_no(page_fetcher: PageFetcher, metadata_no: u32) -> Self {
+        BTree {
+            page_fetcher,
+            metadata_no,
+            listeners: Vec::new(),
+            op_stats: OpStats::default(),
+            heap_page_no: None,
+            sequence: None,
+            phantom: PhantomData,
+        }
+    }
+
+    /// The page holding the tree's types and root page number.
+    pub fn metadata_no(&self) -> u32 {
+        self.metadata_no
+    }
+
+    fn metadata_read(&self) -> Result<MetadataReadLock<'_>> {
+        MetadataReadLock::try_from((
+            self.metadata_no,
+            self.page_fetcher.fetch_page_read(self.metadata_no)?,
+        ))
+    }
+
+    fn metadata_write(&self) -> Result<MetadataWriteLock<'_>> {
+        MetadataWriteLock::try_from((
+            self.metadata_no,
+            self.page_fetcher.fetch_page_write(self.metadata_no)?,
+        ))
+    }
+
+    /// The page number attached to the tree's metadata with `set_attached_no`, if any. The tree
+    /// doesn't use it itself; it's for keeping track of e.g. another tree sharing the fetcher.
+    pub fn attached_no(&self) -> Result<Option<u32>> {
+        self.metadata_read()?.attached_no()
+    }
+
+    pub fn set_attached_no(&self, attached_no: u32) -> Result<()> {
+        self.metadata_write()?.set_attached_no(attached_no)
+    }
+
+    pub fn page_fetcher(&self) -> &PageFetcher {
+        &self.page_fetcher
+    }
+
+    /// Registers `listener` to be told about splits and new roots, see `EventListener`.
+    pub fn add_listener(&mut self, listener: Arc<dyn EventListener>) {
+        self.listeners.push(listener);
+    }
+
+    pub(crate) fn notify<F: Fn(&dyn EventListener)>(&self, event: F) {
+        self.listeners
+            .iter()
+            .for_each(|listener| event(listener.as_ref()));
+    }
+
+    /// Counts of the operations run against this tree since it was opened. The page fetcher's
+    /// `metrics` are shared by every tree stored with it, while these are this tree's alone.
+    pub fn op_stats(&self) -> OpStatsSnapshot {
+        self.op_stats.snapshot()
+    }
+
+    pub fn into_page_fetcher(self) -> PageFetcher {
+        self.page_fetcher
+    }
 }
 
 #[derive(Debug, Clone)]
+#[repr(u8)]
 enum NodeType {
     Metadata,
     Internal,
     Leaf,
+    /// Holds a chunk of a value too large to store inline in a leaf. Chunks are chained through
+    /// `right_sibling`.
+    Overflow,
+    /// Holds tuples stored with `insert_tuple`. Heap pages aren't linked to each other.
+    Heap,
+    /// Holds part of a tree's free space map, see `free_space`. Chained through
+    /// `right_sibling`.
+    FreeSpace,
 }
 
-trait DynamicSized {
-    fn size(&self) -> usize;
-}
+/// Set on leaf and internal nodes whose items (after the separator) are stored in key order.
+/// Nodes written before items were kept sorted don't have it, and are sorted on their next split.
+const FLAG_SORTED: u8 = 1;
+/// Set on nodes whose last insert landed right after, or at the same index as, the one before it,
+/// i.e. keys are arriving in ascending or descending order. See `split_node_data`.
+const FLAG_SEQUENTIAL: u8 = 2;
+/// Set on pages whose right sibling link holds `PageNo::INVALID` when there's no sibling. Pages
+/// written before it used 0 instead, which is still read as no sibling for them: page 0 always
+/// held a metadata page back then, so nothing could link to it.
+const FLAG_INVALID_LINK: u8 = 4;
+/// Set on leaf and internal nodes whose separator is unbounded, i.e. the rightmost node of each
+/// level. Their separator item, and the key of the last downlink of such an internal node, only
+/// hold `Key::max_key` as a placeholder, see `node::Separator`.
+const FLAG_UNBOUNDED: u8 = 8;
 
-#[derive(Debug, Clone)]
+/// The special data of every btree page. Laid out explicitly so that it's the same on every
+/// platform: the right sibling as a little-endian u32 (see `FLAG_INVALID_LINK`), then the node
+/// type, the flags and the index of the last item inserted as a little-endian u16 (0 if unknown).
+#[derive(Clone)]
+#[repr(C)]
 struct BTreePageData {
+    right_sibling_le: u32,
     node_type: NodeType,
-    right_sibling_page_no: u32,
+    flags: u8,
+    last_insert_le: [u8; 2],
 }
 
-#[derive(Copy, Clone)]
-#[deprecated]
-struct InternalNodeItemData<K>
-where
-    K: Sized + Ord + Copy + Clone,
-{
-    page_no: u32,
-    // TODO: Need to figure out how to deal with string type with unboudn length (i.e. `VARCHAR`).
-    key: K,
+impl BTreePageData {
+    fn new(node_type: NodeType, right_sibling: PageNo) -> Self {
+        let flags = match node_type {
+            NodeType::Leaf | NodeType::Internal => FLAG_SORTED | FLAG_INVALID_LINK,
+            _ => FLAG_INVALID_LINK,
+        };
+        BTreePageData {
+            right_sibling_le: right_sibling.to_raw().to_le(),
+            node_type,
+            flags,
+            last_insert_le: [0; 2],
+        }
+    }
+
+    fn is_sorted(&self) -> bool {
+        self.flags & FLAG_SORTED != 0
+    }
+
+    fn set_sorted(&mut self) {
+        self.flags |= FLAG_SORTED;
+    }
+
+    fn is_unbounded(&self) -> bool {
+        self.flags & FLAG_UNBOUNDED != 0
+    }
+
+    fn set_unbounded(&mut self, unbounded: bool) {
+        match unbounded {
+            true => self.flags |= FLAG_UNBOUNDED,
+            false => self.flags &= !FLAG_UNBOUNDED,
+        }
+    }
+
+    fn is_sequential(&self) -> bool {
+        self.flags & FLAG_SEQUENTIAL != 0
+    }
+
+    /// The index the last item was inserted at. Removing items since may have shifted it.
+    fn last_insert(&self) -> Option<usize> {
+        match u16::from_le_bytes(self.last_insert_le) {
+            0 => None,
+            idx => Some(idx as usize),
+        }
+    }
+
+    fn record_insert(&mut self, idx: usize) {
+        let sequential = self
+            .last_insert()
+            .is_some_and(|last| idx == last || idx == last + 1);
+        match sequential {
+            true => self.flags |= FLAG_SEQUENTIAL,
+            false => self.flags &= !FLAG_SEQUENTIAL,
+        }
+        self.last_insert_le = (idx as u16).to_le_bytes();
+    }
+
+    fn right_sibling(&self) -> PageNo {
+        match u32::from_le(self.right_sibling_le) {
+            0 if self.flags & FLAG_INVALID_LINK == 0 => PageNo::INVALID,
+            page_no => PageNo::new(page_no),
+        }
+    }
+
+    fn set_right_sibling(&mut self, right_sibling: PageNo) {
+        self.right_sibling_le = right_sibling.to_raw().to_le();
+        self.flags |= FLAG_INVALID_LINK;
+    }
+
+    /// Checks that `page` holds btree special data with a valid node type, which must be done
+    /// before calling `special_data::<BTreePageData>` on a page that may be corrupted.
+    fn check(page: &Page) -> Result<()> {
+        let special_size = special_area_size(size_of::<Self>());
+        if page.header.special_size() != special_size {
+            return Err(Error::corruption(format!(
+                "{} byte special data, expected {} bytes",
+                page.header.special_size(),
+                special_size
+            )));
+        }
+
+        let node_type = page.data[PAGE_DATA_SIZE - special_size + offset_of!(Self, node_type)];
+        if node_type > NodeType::FreeSpace as u8 {
+            return Err(Error::corruption(format!(
+                "unknown node type {}",
+                node_type
+            )));
+        }
+        Ok(())
+    }
+
+    /// The node type of `page`, after checking it like `check`. Code that branches on the type of
+    /// a page it has just fetched goes through this rather than reading `node_type` directly.
+    fn checked_node_type(page_no: u32, page: &Page) -> Result<NodeType> {
+        Self::check(page).map_err(|err| err.on_page(page_no))?;
+        Ok(page.special_data::<Self>().node_type.clone())
+    }
+}
+
+impl std::fmt::Debug for BTreePageData {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("BTreePageData")
+            .field("node_type", &self.node_type)
+            .field("right_sibling", &self.right_sibling())
+            .field("sorted", &self.is_sorted())
+            .field("unbounded", &self.is_unbounded())
+            .finish()
+    }
 }
 
 #[cfg(test)]
 mod tests {
+    use super::collation::Binary;
+    use super::collation::CaseInsensitive;
+    use super::key::KeyBytes;
+    use super::key::KeyString;
     use super::key::KeyU32;
+    use super::key::KeyU64;
     use super::search::SearchResult;
+    use super::value::ValueBytes;
     use super::value::ValueTupleId;
     use super::BTree;
-    use crate::btree::leaf_node::LeafNodeRead;
+    use super::BTreePageData;
+    use super::NodeType;
+    use super::FLAG_SORTED;
     use crate::btree::leaf_node::LeafNodeReadLock;
-    use crate::btree::BTreePageData;
+    use crate::btree::node::NodeRead;
+    use crate::error::Error;
+    use crate::page::Page;
+    use crate::page::PageNo;
+    use crate::page::PAGE_SIZE;
     use crate::page_fetcher::InMemoryPageFetcher;
     use crate::page_fetcher::PageFetcher;
     use log::debug;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn reopen_with_other_types() {
+        let mut btree = BTree::new(InMemoryPageFetcher::new()).unwrap();
+        btree
+            .insert(
+                KeyU32 { key: 1 },
+                ValueTupleId {
+                    page_no: 1,
+                    offset: 1,
+                },
+            )
+            .unwrap();
+
+        let page_fetcher = btree.into_page_fetcher();
+        match BTree::<KeyU64, ValueTupleId, _>::new(page_fetcher) {
+            Err(Error::TypeMismatch(_)) => {}
+            Err(err) => panic!("expected TypeMismatch, got {:?}", err),
+            Ok(_) => panic!("expected TypeMismatch"),
+        }
+    }
+
+    #[test]
+    fn reopen_with_other_collation() {
+        type Tree<C> = BTree<KeyString<C>, ValueTupleId, InMemoryPageFetcher>;
+        let value = ValueTupleId {
+            page_no: 1,
+            offset: 1,
+        };
+        let mut btree: Tree<CaseInsensitive> = BTree::new(InMemoryPageFetcher::new()).unwrap();
+        btree.insert(KeyString::new("Apple"), value).unwrap();
+        btree.insert(KeyString::new("banana"), value).unwrap();
+        assert!(btree
+            .search(KeyString::new("APPLE"))
+            .unwrap()
+            .value
+            .is_some());
+
+        let page_fetcher = btree.into_page_fetcher();
+        match Tree::<Binary>::new(page_fetcher) {
+            Err(Error::TypeMismatch(detail)) => assert!(detail.contains("collation"), "{}", detail),
+            Err(err) => panic!("expected TypeMismatch, got {:?}", err),
+            Ok(_) => panic!("expected TypeMismatch"),
+        }
+
+        // Byte-wise trees record no collation, like those built before collations were recorded
+        let btree: Tree<Binary> = BTree::new(InMemoryPageFetcher::new()).unwrap();
+        let page_fetcher = btree.into_page_fetcher();
+        assert!(Tree::<CaseInsensitive>::new(page_fetcher).is_err());
+    }
 
     #[test]
     fn basic_test() {
-        let page_fetcher = InMemoryPageFetcher::new();
-        {
-            let (page_no, _lock) = page_fetcher.new_page(BTreePageData {
-                node_type: super::NodeType::Metadata,
-                right_sibling_page_no: 0,
-            });
-            assert_eq!(page_no, 0);
-        }
-        let mut btree = BTree { page_fetcher };
+        let mut btree = BTree::new(InMemoryPageFetcher::new()).unwrap();
         let entry1 = (
             KeyU32 { key: 0 },
             ValueTupleId {
@@ -99,34 +464,124 @@ mod tests {
             },
         );
 
-        assert_eq!(btree.insert(entry1.0, entry1.1), 1);
-        assert_eq!(btree.insert(entry2.0, entry2.1), 1);
-        let leaf = LeafNodeReadLock::<KeyU32, ValueTupleId>::from((
+        assert_eq!(btree.insert(entry1.0, entry1.1).unwrap(), 1);
+        assert_eq!(btree.insert(entry2.0, entry2.1).unwrap(), 1);
+        let leaf = LeafNodeReadLock::<KeyU32, ValueTupleId>::try_from((
             1,
             btree.page_fetcher.fetch_page_read(1).unwrap(),
-        ));
+        ))
+        .unwrap();
         leaf.item_iter().for_each(|i| debug!("{:?}", i));
 
         assert_eq!(
-            btree.search::<_, ValueTupleId>(entry1.0),
+            btree.search(entry1.0).unwrap(),
             SearchResult {
                 leaf_page_no: 1,
                 value: Some(entry1.1),
             }
         );
         assert_eq!(
-            btree.search::<_, ValueTupleId>(KeyU32 { key: 1 }),
+            btree.search(KeyU32 { key: 1 }).unwrap(),
             SearchResult {
                 leaf_page_no: 1,
                 value: None,
             }
         );
         assert_eq!(
-            btree.search::<_, ValueTupleId>(entry2.0),
+            btree.search(entry2.0).unwrap(),
             SearchResult {
                 leaf_page_no: 1,
                 value: Some(entry2.1),
             }
         );
     }
+
+    #[test]
+    fn right_sibling_links() {
+        let mut special_data = BTreePageData::new(NodeType::Leaf, PageNo::INVALID);
+        assert_eq!(special_data.right_sibling(), PageNo::INVALID);
+        special_data.set_right_sibling(PageNo::new(0));
+        assert_eq!(special_data.right_sibling().get(), Some(0));
+
+        // Pages written before `FLAG_INVALID_LINK` link to page 0 when they have no sibling
+        special_data.flags = FLAG_SORTED;
+        assert_eq!(special_data.right_sibling(), PageNo::INVALID);
+        special_data.right_sibling_le = 7u32.to_le();
+        assert_eq!(special_data.right_sibling(), PageNo::new(7));
+    }
+
+    /// Loads pages written by `testdata/gen_golden_pages.py`, which encodes the on-disk layout
+    /// independently of this crate.
+    fn golden_fetcher(bytes: &[u8]) -> InMemoryPageFetcher {
+        let page_fetcher = InMemoryPageFetcher::new();
+        for chunk in bytes.chunks(PAGE_SIZE) {
+            page_fetcher.push_page(&Page::from_bytes(chunk)).unwrap();
+        }
+        page_fetcher
+    }
+
+    fn assert_pages_eq<F: PageFetcher>(page_fetcher: &F, golden: &[u8]) {
+        for (page_no, chunk) in golden.chunks(PAGE_SIZE).enumerate() {
+            let page = page_fetcher.fetch_page_read(page_no as u32).unwrap();
+            assert!(
+                page.as_bytes() == chunk,
+                "page {} differs from the golden page",
+                page_no
+            );
+        }
+    }
+
+    #[test]
+    fn golden_fixed_size_pages() {
+        let golden = include_bytes!("../../testdata/u32_tuple_id.pages");
+        let entries = (1..4)
+            .map(|key| {
+                let value = ValueTupleId {
+                    page_no: 100 + key,
+                    offset: key as u16,
+                };
+                (KeyU32 { key }, value)
+            })
+            .collect::<Vec<_>>();
+
+        let btree = BTree::<KeyU32, ValueTupleId, _>::new(golden_fetcher(golden)).unwrap();
+        for (key, value) in entries.iter() {
+            assert_eq!(btree.search(*key).unwrap().value, Some(*value));
+        }
+
+        let mut btree = BTree::new(InMemoryPageFetcher::new()).unwrap();
+        for (key, value) in entries {
+            btree.insert(key, value).unwrap();
+        }
+        assert_pages_eq(&btree.into_page_fetcher(), golden);
+    }
+
+    #[test]
+    fn golden_dynamic_size_pages() {
+        let golden = include_bytes!("../../testdata/bytes.pages");
+        let entries = vec![("a", "x"), ("bb", "yyy"), ("ccc", "")]
+            .into_iter()
+            .map(|(key, value)| {
+                let key = KeyBytes { key: key.into() };
+                let value = ValueBytes {
+                    value: value.into(),
+                };
+                (key, value)
+            })
+            .collect::<Vec<_>>();
+
+        let btree = BTree::<KeyBytes, ValueBytes, _>::new(golden_fetcher(golden)).unwrap();
+        for (key, value) in entries.iter() {
+            assert_eq!(
+                btree.search(key.clone()).unwrap().value.as_ref(),
+                Some(value)
+            );
+        }
+
+        let mut btree = BTree::new(InMemoryPageFetcher::new()).unwrap();
+        for (key, value) in entries {
+            btree.insert(key, value).unwrap();
+        }
+        assert_pages_eq(&btree.into_page_fetcher(), golden);
+    }
 }
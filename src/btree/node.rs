@@ -0,0 +1,667 @@
+use super::key::Key;
+use super::BTreePageData;
+use crate::error::Error;
+use crate::error::Result;
+use crate::page::Item;
+use crate::page::ItemRef;
+use crate::page::Page;
+use crate::page::PageItemIteratorV2;
+use crate::page::PageNo;
+use crate::page::ITEM_POINTER_SIZE;
+use crate::page::PAGE_DATA_SIZE;
+use std::cell::OnceCell;
+use std::fmt::Debug;
+use std::iter::Rev;
+use std::mem::size_of;
+use std::ops::Bound;
+
+/// An item stored in a node after its separator, i.e. a leaf's key/value or an internal node's
+/// downlink.
+pub(super) trait NodeItem: Item + Clone + Debug {
+    type Key: Key;
+
+    fn key(&self) -> &Self::Key;
+
+    /// Decodes just the key of an item encoded as `bytes`, without copying its value.
+    fn read_key(bytes: &[u8]) -> Self::Key;
+
+    /// Checks that `bytes`, as found in a page, can be decoded as an item without panicking.
+    fn check_encoding(bytes: &[u8]) -> Result<()>;
+}
+
+/// Room for a node's separator and items.
+const NODE_CAPACITY: usize = PAGE_DATA_SIZE - size_of::<BTreePageData>();
+
+/// Checks that an `I` of `size` bytes is small enough to be stored in a node, i.e. that a node
+/// holding it and
+/// another item as large, along with a separator at most as large, still has room. A full node
+/// can then always be split with an item on either side, while a node full with a single item
+/// couldn't be split at all. Leaf items and the downlinks to their keys are checked alike, since
+/// a leaf's separator is the key of some other leaf item.
+pub(super) fn check_item_size<I: Item>(size: usize) -> Result<()> {
+    // With the item pointer and the worst case of padding to align the item
+    let footprint = size + I::align() - 1 + ITEM_POINTER_SIZE;
+    match 3 * footprint <= NODE_CAPACITY {
+        true => Ok(()),
+        false => Err(Error::ItemTooLarge(size)),
+    }
+}
+
+/// Checks that `page` is well-formed and that every item, including the separator, can be decoded
+/// as a node holding `I`s. Pages are otherwise trusted as-is, so anything reading pages that may
+/// be corrupted (e.g. `BTree::verify`) must call this before decoding any items.
+pub(super) fn check_node_items<I: NodeItem>(page: &Page) -> Result<()> {
+    page.check_layout()?;
+    if page.item_cnt() == 0 {
+        return Err(Error::corruption(
+            "node is missing its separator".to_string(),
+        ));
+    }
+
+    for idx in 0..page.item_cnt() {
+        let align = match idx {
+            0 => <I::Key as Item>::align(),
+            _ => I::align(),
+        };
+        let bytes = page.item_bytes(idx, align)?;
+        if idx == 0 {
+            if <I::Key as Item>::is_fixed_size() && bytes.len() != <I::Key as Item>::fixed_size() {
+                return Err(Error::corruption(format!(
+                    "{} byte separator, expected {} bytes",
+                    bytes.len(),
+                    <I::Key as Item>::fixed_size()
+                )));
+            }
+        } else {
+            I::check_encoding(bytes)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// The upper bound of the keys a node may hold, exclusive for leaves and inclusive for internal
+/// nodes (whose separator is their largest downlink key). The rightmost node of each level has no
+/// bound at all, so that every key, `Key::max_key` included, has a node to go to. That's recorded
+/// with the page's unbounded flag, while its separator item only holds `max_key` as a placeholder.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub(super) enum Separator<K> {
+    Key(K),
+    Unbounded,
+}
+
+impl<K: Key> Separator<K> {
+    /// Reads the separator of node `page`. Pages written before the unbounded flag existed used
+    /// `max_key` as the rightmost nodes' separator instead, so a `max_key` separator without a right
+    /// sibling is unbounded too.
+    pub(super) fn read(page: &Page) -> Self {
+        let data = page.special_data::<BTreePageData>();
+        if data.is_unbounded() {
+            return Separator::Unbounded;
+        }
+        let key = page.get_item_v2::<K>(0);
+        match key == K::max_key() && data.right_sibling().get().is_none() {
+            true => Separator::Unbounded,
+            false => Separator::Key(key),
+        }
+    }
+
+    /// Inserts the separator as item 0 of `page`, which mustn't have one already, and flags the
+    /// page accordingly.
+    pub(super) fn write(&self, page: &mut Page) -> Result<()> {
+        page.insert_item_v2(0, &self.stored_key())?;
+        page.special_data_mut::<BTreePageData>()
+            .set_unbounded(matches!(self, Separator::Unbounded));
+        Ok(())
+    }
+
+    /// The key the separator is stored as, which is also the key of the downlink to its node.
+    pub(super) fn stored_key(&self) -> K {
+        match self {
+            Separator::Key(key) => key.clone(),
+            Separator::Unbounded => K::max_key(),
+        }
+    }
+
+    /// Whether `key` sorts below the separator.
+    pub(super) fn is_above(&self, key: &K) -> bool {
+        match self {
+            Separator::Key(sep) => key < sep,
+            Separator::Unbounded => true,
+        }
+    }
+
+    /// Whether `key` sorts below or equal to the separator.
+    pub(super) fn is_at_least(&self, key: &K) -> bool {
+        match self {
+            Separator::Key(sep) => key <= sep,
+            Separator::Unbounded => true,
+        }
+    }
+
+    /// Whether the keys within a range ending at `end` all sort before the separator of a leaf,
+    /// so that none of them are in its right siblings.
+    pub(super) fn is_past_end(&self, end: Bound<&K>) -> bool {
+        match end {
+            Bound::Included(end) => self.is_above(end),
+            Bound::Excluded(end) => self.is_at_least(end),
+            Bound::Unbounded => matches!(self, Separator::Unbounded),
+        }
+    }
+}
+
+/// Read access shared by leaf and internal nodes. Item 0 of every node is its separator, see
+/// `Separator`.
+pub(super) trait NodeRead<I>
+where
+    I: NodeItem,
+{
+    fn page_ref(&self) -> &Page;
+    fn page_no(&self) -> u32;
+    /// Holds the separator once it's been decoded, for as long as the node stays locked.
+    fn separator_cache(&self) -> &OnceCell<Separator<I::Key>>;
+
+    fn item_iter(&self) -> PageItemIteratorV2<'_, I> {
+        // We skip the first element, because it's always the separator
+        self.page_ref().items_iter_from_v2::<I>(1)
+    }
+
+    /// Like `item_iter`, but last item first, so in descending key order if the node is sorted.
+    fn item_iter_rev(&self) -> Rev<PageItemIteratorV2<'_, I>> {
+        self.item_iter().rev()
+    }
+
+    /// The node's separator, only decoded the first time it's needed while the node is locked.
+    fn separator(&self) -> &Separator<I::Key> {
+        self.separator_cache()
+            .get_or_init(|| Separator::read(self.page_ref()))
+    }
+
+    fn special_data(&self) -> &BTreePageData {
+        self.page_ref().special_data()
+    }
+
+    /// The index of the first item (after the separator) for which `pred` doesn't hold, found by
+    /// binary search over the slots. Only the keys of the items probed are decoded. `pred` must
+    /// hold for a prefix of the items, so the node's items must be sorted, see
+    /// `BTreePageData::is_sorted`.
+    fn partition_point<F>(&self, pred: F) -> usize
+    where
+        F: Fn(&I::Key) -> bool,
+    {
+        let page = self.page_ref();
+        let probe = |idx: usize| pred(&I::read_key(page.get_item_ref::<I>(idx).bytes()));
+        if !<I::Key as Item>::is_fixed_size() {
+            let (mut lo, mut hi) = (1, page.item_cnt());
+            while lo < hi {
+                let mid = lo + (hi - lo) / 2;
+                match probe(mid) {
+                    true => lo = mid + 1,
+                    false => hi = mid,
+                }
+            }
+            return lo;
+        }
+
+        // Fixed-size keys are cheap to decode, so rather than stopping early we always halve the
+        // range, which lets the compiler pick between the halves with a conditional move instead
+        // of a branch the CPU can't predict
+        let (mut base, mut size) = (1, page.item_cnt().saturating_sub(1));
+        if size == 0 {
+            return base;
+        }
+        while size > 1 {
+            let half = size / 2;
+            let mid = base + half;
+            base = match probe(mid) {
+                true => mid,
+                false => base,
+            };
+            size -= half;
+        }
+        base + probe(base) as usize
+    }
+
+    /// The index of the first item whose key is greater than `key`, see `partition_point`.
+    fn upper_bound(&self, key: &I::Key) -> usize {
+        self.partition_point(|probe| probe <= key)
+    }
+
+    /// The index of the item with `key`, borrowed straight from the page without decoding any item
+    /// but the keys it's compared against. Sorted nodes are binary searched, others scanned.
+    fn find_item(&self, key: &I::Key) -> Option<(usize, ItemRef<'_, I>)> {
+        let page = self.page_ref();
+        let idx = match self.special_data().is_sorted() {
+            true => {
+                Some(self.partition_point(|probe| probe < key)).filter(|idx| *idx < page.item_cnt())
+            }
+            false => (1..page.item_cnt())
+                .find(|idx| I::read_key(page.get_item_ref::<I>(*idx).bytes()) == *key),
+        }?;
+
+        let item = page.get_item_ref::<I>(idx);
+        match I::read_key(item.bytes()) == *key {
+            true => Some((idx, item)),
+            false => None,
+        }
+    }
+}
+
+pub(super) trait NodeWrite<I>: NodeRead<I>
+where
+    I: NodeItem,
+{
+    fn page_ref_mut(&mut self) -> &mut Page;
+    /// Must be reset whenever the separator changes, see `NodeRead::separator_cache`.
+    fn separator_cache_mut(&mut self) -> &mut OnceCell<Separator<I::Key>>;
+
+    fn add_item(&mut self, item: &I) -> Result<()> {
+        // Items with equal keys keep their order, which matters for the downlink placeholder of an
+        // unbounded internal node, see `insert_item`
+        let idx = match self.special_data().is_sorted() {
+            true => self.upper_bound(item.key()),
+            false => self.page_ref().item_cnt(),
+        };
+        self.insert_item(idx, item)
+    }
+
+    /// Inserts `item` at index `idx`, which must keep the node's items in order if it's sorted.
+    /// Only needed over `add_item` where a downlink is added next to one with an equal key, as a
+    /// downlink keyed `max_key` must go before the placeholder that shares its key.
+    fn insert_item(&mut self, idx: usize, item: &I) -> Result<()> {
+        if !self.separator().is_at_least(item.key()) {
+            return Err(Error::page_corruption(
+                self.page_no(),
+                format!(
+                    "key {:?} doesn't fit within the page's key range",
+                    item.key()
+                ),
+            ));
+        }
+
+        self.page_ref_mut().insert_item_v2(idx, item)?;
+        self.special_data_mut().record_insert(idx);
+        Ok(())
+    }
+
+    fn set_separator(&mut self, sep: &Separator<I::Key>) -> Result<()> {
+        assert_eq!(self.page_ref().item_cnt(), 0);
+
+        sep.write(self.page_ref_mut())?;
+        *self.separator_cache_mut() = OnceCell::from(sep.clone());
+        Ok(())
+    }
+
+    fn zero_out_item_data(&mut self) {
+        self.page_ref_mut().zero_out_item_data();
+        self.separator_cache_mut().take();
+    }
+
+    fn special_data_mut(&mut self) -> &mut BTreePageData {
+        self.page_ref_mut().special_data_mut()
+    }
+
+    fn set_right_sibling_no(&mut self, right_sibling_no: u32) {
+        self.special_data_mut()
+            .set_right_sibling(PageNo::new(right_sibling_no));
+    }
+}
+
+/// Moves the upper half (by data size) of `orig`'s items into the empty `new` node, to make room
+/// for `pending`, which the caller adds to either node afterwards. `new` takes over `orig`'s
+/// separator while `orig`'s new separator is computed by `separator_fn` from the last item staying
+/// in `orig` and the first item moving to `new`.
+///
+/// If keys have been arriving in ascending or descending order, the node is split right where
+/// `pending` goes instead (see `skewed_split_point`).
+///
+/// Since the items are sorted, the upper half is moved over byte for byte and `orig` compacted in
+/// place, decoding only the two items `separator_fn` needs. Nodes that aren't sorted yet are
+/// rebuilt in key order instead.
+pub(super) fn split_node_data<N, I, F>(
+    orig: &mut N,
+    new: &mut N,
+    pending: &I,
+    separator_fn: F,
+) -> Result<()>
+where
+    N: NodeWrite<I>,
+    I: NodeItem,
+    F: Fn(&I, &I) -> I::Key,
+{
+    if !orig.special_data().is_sorted() {
+        split_unsorted_node_data(orig, new, separator_fn)?;
+        orig.special_data_mut().set_sorted();
+        return Ok(());
+    }
+
+    let page = orig.page_ref();
+    let item_cnt = page.item_cnt();
+    if item_cnt < 3 {
+        // A single item that fills the page on its own can't be split any further
+        return Err(Error::PageFull);
+    }
+
+    let at = skewed_split_point(orig, pending, &separator_fn).unwrap_or_else(|| {
+        let item_size = |idx| page.item_pointer(idx).1;
+        let item_data_size: usize = (1..item_cnt).map(item_size).sum();
+        let mut added: usize = 0;
+        for idx in 1..item_cnt {
+            added += item_size(idx);
+            if added > item_data_size / 2 {
+                // Always leave at least one item on either side
+                return std::cmp::min(std::cmp::max(idx, 2), item_cnt - 1);
+            }
+        }
+        item_cnt - 1
+    });
+    let separator = separator_fn(&page.get_item_v2::<I>(at - 1), &page.get_item_v2::<I>(at));
+
+    // First, add separator to the `new` node. It's always guaranteed to be the first item in the
+    // page.
+    new.set_separator(orig.separator())?;
+    orig.page_ref_mut()
+        .move_items_to::<I>(at, new.page_ref_mut())?;
+
+    let page = orig.page_ref_mut();
+    page.remove_item_v2(0);
+    page.compact::<I>()?;
+    let separator = Separator::Key(separator);
+    separator.write(page)?;
+    *orig.separator_cache_mut() = OnceCell::from(separator);
+    Ok(())
+}
+
+/// Where to split a sorted node `pending` didn't fit into, if its last two inserts each landed next
+/// to the one before and `pending` would too, i.e. keys are arriving in ascending or descending
+/// order. Splitting right where `pending` goes leaves one node full, and room for the rest of the
+/// run in the other, instead of two half empty nodes that will never be filled. Returns `None` for
+/// any other pattern, or if `pending` might not fit in its node after such a split.
+fn skewed_split_point<N, I, F>(node: &N, pending: &I, separator_fn: &F) -> Option<usize>
+where
+    N: NodeRead<I>,
+    I: NodeItem,
+    F: Fn(&I, &I) -> I::Key,
+{
+    let data = node.special_data();
+    let last = data.last_insert()?;
+    let pos = node.upper_bound(pending.key());
+    if !data.is_sequential() || (pos != last && pos != last + 1) {
+        return None;
+    }
+
+    let page = node.page_ref();
+    let item_cnt = page.item_cnt();
+    // Always leave at least one item on either side
+    let at = pos.clamp(2, item_cnt - 1);
+    let separator = separator_fn(&page.get_item_v2::<I>(at - 1), &page.get_item_v2::<I>(at));
+
+    // `orig` gives up the items from `at` on and swaps its separator for the new one, while `new`
+    // gets those items and the old separator but none of the items before `at`
+    let freed = |idx| page.item_pointer(idx).1 + ITEM_POINTER_SIZE;
+    let orig_room = (freed(0) + (at..item_cnt).map(freed).sum::<usize>())
+        .checked_sub(separator.size() + ITEM_POINTER_SIZE + <I::Key as Item>::align())?;
+    let new_room = (1..at).map(freed).sum::<usize>();
+    let needed = pending.size() + ITEM_POINTER_SIZE + I::align();
+    let fits = match pending.key().cmp(&separator) {
+        std::cmp::Ordering::Less => orig_room >= needed,
+        std::cmp::Ordering::Equal => orig_room >= needed && new_room >= needed,
+        std::cmp::Ordering::Greater => new_room >= needed,
+    };
+    Some(at).filter(|_| fits)
+}
+
+/// The split of nodes written before items were kept sorted: every item is decoded, sorted and
+/// added back to one node or the other.
+fn split_unsorted_node_data<N, I, F>(orig: &mut N, new: &mut N, separator_fn: F) -> Result<()>
+where
+    N: NodeWrite<I>,
+    I: NodeItem,
+    F: Fn(&I, &I) -> I::Key,
+{
+    let separator = orig.separator().clone();
+
+    // The sort is stable, so a downlink placeholder stays after any downlink sharing its key
+    let mut sorted = orig.item_iter().collect::<Vec<_>>();
+    sorted.sort_by(|x, y| x.key().cmp(y.key()));
+
+    if sorted.len() < 2 {
+        return Err(Error::PageFull);
+    }
+
+    let item_data_size: usize = sorted.iter().fold(0, |sum, i| sum + i.size());
+    let mut added: usize = 0;
+    let mut count: usize = sorted.len() - 1;
+    for (i, item) in sorted.iter().enumerate() {
+        added += item.size();
+        if added > item_data_size / 2 {
+            count = std::cmp::min(std::cmp::max(i, 1), sorted.len() - 1);
+            break;
+        }
+    }
+    let (lower, upper) = sorted.split_at(count);
+
+    new.set_separator(&separator)?;
+    for item in upper.iter() {
+        new.add_item(item)?;
+    }
+
+    orig.zero_out_item_data();
+    orig.set_separator(&Separator::Key(separator_fn(
+        &lower[lower.len() - 1],
+        &upper[0],
+    )))?;
+    for item in lower.iter() {
+        orig.add_item(item)?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::split_node_data;
+    use super::NodeRead;
+    use super::NodeWrite;
+    use super::Separator;
+    use crate::btree::key::Key;
+    use crate::btree::key::KeyBytes;
+    use crate::btree::key::KeyU32;
+    use crate::btree::leaf_node::new_page;
+    use crate::btree::leaf_node::LeafNodeItemData;
+    use crate::btree::value::ValueBytes;
+    use crate::btree::value::ValueTupleId;
+    use crate::page::PageNo;
+    use crate::page_fetcher::InMemoryPageFetcher;
+
+    #[test]
+    fn find_item() {
+        let page_fetcher = InMemoryPageFetcher::new();
+        let (_, mut leaf) =
+            new_page::<_, KeyU32, ValueTupleId>(&page_fetcher, PageNo::INVALID).unwrap();
+        leaf.set_separator(&Separator::Unbounded).unwrap();
+        for key in [40, 10, 30, 20, 50] {
+            let value = ValueTupleId {
+                page_no: key,
+                offset: 0,
+            };
+            leaf.add_item(&LeafNodeItemData {
+                key: KeyU32 { key },
+                value,
+            })
+            .unwrap();
+        }
+
+        for sorted in [true, false] {
+            if !sorted {
+                leaf.special_data_mut().flags = 0;
+                leaf.page_ref_mut().remove_item_v2(1);
+                leaf.add_item(&LeafNodeItemData {
+                    key: KeyU32 { key: 10 },
+                    value: ValueTupleId {
+                        page_no: 10,
+                        offset: 0,
+                    },
+                })
+                .unwrap();
+            }
+            for key in [10, 20, 30, 40, 50] {
+                let (idx, item) = leaf.find_item(&KeyU32 { key }).unwrap();
+                assert_eq!(item.key(), KeyU32 { key });
+                assert_eq!(item.value().page_no, key);
+                assert_eq!(
+                    leaf.page_ref()
+                        .get_item_v2::<LeafNodeItemData<KeyU32, ValueTupleId>>(idx)
+                        .key,
+                    KeyU32 { key }
+                );
+            }
+            for key in [0, 25, 60] {
+                assert!(leaf.find_item(&KeyU32 { key }).is_none());
+            }
+        }
+        assert_eq!(leaf.remove_item(&KeyU32 { key: 30 }).unwrap().page_no, 30);
+        assert!(leaf.find_item(&KeyU32 { key: 30 }).is_none());
+    }
+
+    #[test]
+    fn legacy_unbounded_separator() {
+        let page_fetcher = InMemoryPageFetcher::new();
+        let (_, mut leaf) =
+            new_page::<_, KeyU32, ValueTupleId>(&page_fetcher, PageNo::INVALID).unwrap();
+        // As written before the unbounded flag, with `max_key` standing in for it
+        leaf.page_ref_mut().add_item_v2(&KeyU32::max_key()).unwrap();
+        assert_eq!(
+            Separator::<KeyU32>::read(leaf.page_ref()),
+            Separator::Unbounded
+        );
+
+        leaf.set_right_sibling_no(7);
+        assert_eq!(
+            Separator::<KeyU32>::read(leaf.page_ref()),
+            Separator::Key(KeyU32::max_key())
+        );
+    }
+
+    #[test]
+    fn upper_bound() {
+        let page_fetcher = InMemoryPageFetcher::with_capacity(128);
+        for cnt in 0..40u32 {
+            let (_, mut fixed) =
+                new_page::<_, KeyU32, ValueTupleId>(&page_fetcher, PageNo::INVALID).unwrap();
+            let (_, mut dynamic) =
+                new_page::<_, KeyBytes, ValueBytes>(&page_fetcher, PageNo::INVALID).unwrap();
+            fixed.set_separator(&Separator::Unbounded).unwrap();
+            dynamic.set_separator(&Separator::Unbounded).unwrap();
+            for i in 0..cnt {
+                let value = ValueTupleId {
+                    page_no: i,
+                    offset: 0,
+                };
+                fixed
+                    .add_item(&LeafNodeItemData {
+                        key: KeyU32 { key: 2 * i + 1 },
+                        value,
+                    })
+                    .unwrap();
+                let key = KeyBytes {
+                    key: vec![2 * i as u8 + 1],
+                };
+                let value = ValueBytes { value: vec![] };
+                dynamic.add_item(&LeafNodeItemData { key, value }).unwrap();
+            }
+
+            for key in 0..2 * cnt + 2 {
+                let expected = 1 + (0..cnt).filter(|i| 2 * i < key).count();
+                assert_eq!(fixed.upper_bound(&KeyU32 { key }), expected);
+                let key = KeyBytes {
+                    key: vec![key as u8],
+                };
+                assert_eq!(dynamic.upper_bound(&key), expected);
+            }
+        }
+    }
+
+    #[test]
+    fn skewed_split_point() {
+        let page_fetcher = InMemoryPageFetcher::with_capacity(64);
+        let item = |key| LeafNodeItemData {
+            key: KeyU32 { key },
+            value: ValueTupleId {
+                page_no: key,
+                offset: 0,
+            },
+        };
+        let node = |keys: &mut dyn Iterator<Item = u32>| {
+            let (_, mut node) =
+                new_page::<_, KeyU32, ValueTupleId>(&page_fetcher, PageNo::INVALID).unwrap();
+            node.set_separator(&Separator::Unbounded).unwrap();
+            for key in keys {
+                node.add_item(&item(key)).unwrap();
+            }
+            node
+        };
+        let split_point =
+            |node, key| super::skewed_split_point(node, &item(key), &|_, upper| upper.key);
+
+        // Ascending keys leave all but the last item behind, descending ones all but the first
+        let ascending = node(&mut (10..100));
+        assert_eq!(split_point(&ascending, 100), Some(90));
+        let descending = node(&mut (10..100).rev());
+        assert_eq!(split_point(&descending, 5), Some(2));
+        // An ascending run in the middle of the node is split where it continues
+        let middle = node(&mut (0..50).map(|key| 10 * key).chain(41..45));
+        assert_eq!(split_point(&middle, 45), Some(10));
+
+        assert_eq!(split_point(&ascending, 50), None);
+        assert_eq!(
+            split_point(&node(&mut [7, 3, 9, 1, 5].iter().copied()), 11),
+            None
+        );
+    }
+
+    #[test]
+    fn split_updates_cached_separators() {
+        let page_fetcher = InMemoryPageFetcher::new();
+        let (_, mut orig) =
+            new_page::<_, KeyU32, ValueTupleId>(&page_fetcher, PageNo::INVALID).unwrap();
+        let (_, mut new) =
+            new_page::<_, KeyU32, ValueTupleId>(&page_fetcher, PageNo::INVALID).unwrap();
+        orig.set_separator(&Separator::Unbounded).unwrap();
+        for key in 0..100 {
+            let value = ValueTupleId {
+                page_no: key,
+                offset: 0,
+            };
+            orig.add_item(&LeafNodeItemData {
+                key: KeyU32 { key },
+                value,
+            })
+            .unwrap();
+        }
+        assert_eq!(*orig.separator(), Separator::Unbounded);
+
+        let pending = LeafNodeItemData {
+            key: KeyU32 { key: 50 },
+            value: ValueTupleId {
+                page_no: 0,
+                offset: 0,
+            },
+        };
+        split_node_data(&mut orig, &mut new, &pending, |_lower, upper| upper.key).unwrap();
+        assert_eq!(*orig.separator(), Separator::Key(KeyU32 { key: 50 }));
+        assert_eq!(orig.page_ref().get_item_v2::<KeyU32>(0), KeyU32 { key: 50 });
+        assert_eq!(*new.separator(), Separator::Unbounded);
+        assert_eq!(new.page_ref().get_item_v2::<KeyU32>(0), KeyU32::max_key());
+        assert_eq!(
+            orig.item_iter()
+                .map(|item| item.key.key)
+                .collect::<Vec<_>>(),
+            (0..50).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            new.item_iter().map(|item| item.key.key).collect::<Vec<_>>(),
+            (50..100).collect::<Vec<_>>()
+        );
+    }
+}
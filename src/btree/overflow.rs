@@ -0,0 +1,467 @@
+use super::key::Key;
+use super::value::Value;
+use super::value::ValueBytes;
+use super::BTreePageData;
+use super::NodeType;
+use crate::checksum::crc32;
+use crate::error::Error;
+use crate::error::Result;
+use crate::page::Item;
+use crate::page::PageNo;
+use crate::page::ITEM_POINTER_SIZE;
+use crate::page::PAGE_DATA_SIZE;
+use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use log::debug;
+use std::convert::TryInto;
+use std::io;
+use std::io::Read;
+use std::mem::size_of;
+
+/// Bytes of payload each overflow page holds: the whole data region minus the special data and
+/// the single item pointer.
+const OVERFLOW_CHUNK_SIZE: usize = PAGE_DATA_SIZE - size_of::<BTreePageData>() - ITEM_POINTER_SIZE;
+
+/// Bytes of payload each blob page holds, after the chunk's checksum.
+const BLOB_CHUNK_SIZE: usize = OVERFLOW_CHUNK_SIZE - size_of::<u32>();
+
+impl<K, V, PageFetcher> super::BTree<K, V, PageFetcher>
+where
+    K: Key,
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Stores `bytes` in a new chain of overflow pages and returns the first page's number. Each
+    /// page holds a single `ValueBytes` chunk and points to the next one through its right
+    /// sibling link, with `PageNo::INVALID` terminating the chain. Whoever stops pointing at the
+    /// chain returns it to the free list with `free_overflow`.
+    pub fn write_overflow(&self, bytes: &[u8]) -> Result<u32> {
+        let mut chunks = bytes.chunks(OVERFLOW_CHUNK_SIZE).collect::<Vec<_>>();
+        if chunks.is_empty() {
+            chunks.push(&[]);
+        }
+
+        // Write the chain back to front so every page already knows its successor.
+        let mut next_page_no = None;
+        for chunk in chunks.iter().rev() {
+            let (page_no, mut page) = self.page_fetcher.new_page(BTreePageData::new(
+                NodeType::Overflow,
+                PageNo::from(next_page_no),
+            ))?;
+            page.add_item_v2(&ValueBytes {
+                value: chunk.to_vec(),
+            })?;
+            next_page_no = Some(page_no);
+        }
+
+        let first_page_no = next_page_no.unwrap();
+        debug!(
+            "[write_overflow] Wrote {} bytes across {} pages starting at {}",
+            bytes.len(),
+            chunks.len(),
+            first_page_no
+        );
+        Ok(first_page_no)
+    }
+
+    /// Reassembles the bytes stored by `write_overflow` starting at `first_page_no`.
+    pub fn read_overflow(&self, first_page_no: u32) -> Result<Vec<u8>> {
+        read_overflow(&self.page_fetcher, first_page_no)
+    }
+
+    /// The form of `value` to store in a leaf, which is a pointer to a new overflow chain holding
+    /// it if it's larger than its `Value::overflow` inline limit.
+    pub(super) fn store_value(&self, value: V) -> Result<V> {
+        match V::overflow() {
+            Some(overflow) if value.size() > overflow.inline_limit => {
+                let mut bytes = vec![0; value.size()];
+                value.write(&mut bytes);
+                Ok((overflow.pointer)(self.write_overflow(&bytes)?))
+            }
+            _ => Ok(value),
+        }
+    }
+
+    /// Undoes `store_value` for a value read from a leaf.
+    pub(super) fn load_value(&self, value: V) -> Result<V> {
+        load_value(&self.page_fetcher, value)
+    }
+
+    /// Returns the pages of the chain starting at `first_page_no` to the free list.
+    pub(crate) fn free_overflow(&self, first_page_no: u32) -> Result<()> {
+        let mut next_page_no = Some(first_page_no);
+        while let Some(page_no) = next_page_no {
+            next_page_no = {
+                let page = self.page_fetcher.fetch_page_read(page_no)?;
+                BTreePageData::check(&page).map_err(|err| err.on_page(page_no))?;
+                let special_data = page.special_data::<BTreePageData>();
+                if !matches!(special_data.node_type, NodeType::Overflow) {
+                    return Err(Error::page_corruption(page_no, "not a valid overflow page"));
+                }
+                special_data.right_sibling().get()
+            };
+            self.page_fetcher.free_page(page_no)?;
+        }
+        Ok(())
+    }
+
+    /// Streams `reader` into a new chain of overflow pages, holding no more than a page of it in
+    /// memory at a time. Chains are laid out like `write_overflow`'s, except that each chunk is
+    /// preceded by a big-endian CRC-32 of its bytes. Returns the first page's number and the
+    /// number of bytes stored.
+    ///
+    /// Since the size isn't known up front, the chain is written front to back and each page is
+    /// linked to its successor once that's been allocated. Pages written before an error are
+    /// freed again.
+    pub fn write_blob<R: Read>(&self, reader: R) -> Result<(u32, u64)> {
+        let mut page_nos = Vec::new();
+        match self.write_blob_pages(reader, &mut page_nos) {
+            Ok(size) => {
+                debug!(
+                    "[write_blob] Wrote {} bytes starting at {}",
+                    size, page_nos[0]
+                );
+                Ok((page_nos[0], size))
+            }
+            Err(err) => {
+                for page_no in page_nos {
+                    self.page_fetcher.free_page(page_no)?;
+                }
+                Err(err)
+            }
+        }
+    }
+
+    /// Writes the chain of `write_blob`, adding each page to `page_nos` as it's allocated.
+    fn write_blob_pages<R: Read>(&self, mut reader: R, page_nos: &mut Vec<u32>) -> Result<u64> {
+        let mut chunk = vec![0; BLOB_CHUNK_SIZE];
+        let mut size = 0;
+        loop {
+            let len = read_full(&mut reader, &mut chunk)?;
+            if len == 0 && !page_nos.is_empty() {
+                break;
+            }
+
+            let mut value = Vec::with_capacity(size_of::<u32>() + len);
+            value.extend_from_slice(&crc32(&chunk[..len]).to_be_bytes());
+            value.extend_from_slice(&chunk[..len]);
+            let (page_no, mut page) = self
+                .page_fetcher
+                .new_page(BTreePageData::new(NodeType::Overflow, PageNo::INVALID))?;
+            let prev_page_no = page_nos.last().copied();
+            page_nos.push(page_no);
+            page.add_item_v2(&ValueBytes { value })?;
+            drop(page);
+
+            if let Some(prev_page_no) = prev_page_no {
+                let mut prev = self.page_fetcher.fetch_page_write(prev_page_no)?;
+                prev.special_data_mut::<BTreePageData>()
+                    .set_right_sibling(PageNo::new(page_no));
+            }
+            size += len as u64;
+            if len < BLOB_CHUNK_SIZE {
+                break;
+            }
+        }
+        Ok(size)
+    }
+
+    /// Streams the `size` bytes stored by `write_blob` starting at `first_page_no`, a page at a
+    /// time.
+    pub fn blob_reader(&self, first_page_no: u32, size: u64) -> BlobReader<'_, PageFetcher> {
+        BlobReader {
+            page_fetcher: &self.page_fetcher,
+            next_page_no: Some(first_page_no),
+            chunk: Vec::new(),
+            pos: 0,
+            remaining: size,
+        }
+    }
+}
+
+fn read_overflow<P: PageFetcherTrait>(page_fetcher: &P, first_page_no: u32) -> Result<Vec<u8>> {
+    let mut bytes = Vec::new();
+    let mut next_page_no = Some(first_page_no);
+
+    while let Some(page_no) = next_page_no {
+        let page = page_fetcher.fetch_page_read(page_no)?;
+        BTreePageData::check(&page).map_err(|err| err.on_page(page_no))?;
+        let special_data = page.special_data::<BTreePageData>();
+        if !matches!(special_data.node_type, NodeType::Overflow) || page.item_cnt() != 1 {
+            return Err(Error::page_corruption(page_no, "not a valid overflow page"));
+        }
+
+        let chunk = page
+            .try_get_item_v2::<ValueBytes>(0)
+            .map_err(|err| err.on_page(page_no))?;
+        if chunk.size() > OVERFLOW_CHUNK_SIZE {
+            return Err(Error::page_corruption(
+                page_no,
+                format!("overflow page holds {} bytes", chunk.size()),
+            ));
+        }
+        bytes.extend_from_slice(&chunk.value);
+        next_page_no = special_data.right_sibling().get();
+    }
+
+    Ok(bytes)
+}
+
+/// See `BTree::load_value`. A free function so iterators holding only the page fetcher can use
+/// it.
+pub(super) fn load_value<P: PageFetcherTrait, V: Value>(page_fetcher: &P, value: V) -> Result<V> {
+    match value.overflow_no() {
+        Some(first_page_no) => Ok(V::read(&read_overflow(page_fetcher, first_page_no)?)),
+        None => Ok(value),
+    }
+}
+
+/// Fills `buf` from `reader` unless it runs out first, returning the number of bytes read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(len)
+}
+
+/// Reads a blob back out of its overflow chain, see `BTree::blob_reader`. Each page's checksum
+/// is verified as it's read; corruption is reported as an `io::Error` wrapping the `Error`.
+pub struct BlobReader<'a, PageFetcher> {
+    page_fetcher: &'a PageFetcher,
+    next_page_no: Option<u32>,
+    chunk: Vec<u8>,
+    pos: usize,
+    /// Bytes of the blob not yet loaded into `chunk`.
+    remaining: u64,
+}
+
+impl<'a, PageFetcher: PageFetcherTrait> BlobReader<'a, PageFetcher> {
+    /// Loads the next chunk once the current one is used up. Returns false at the end of the
+    /// blob.
+    fn fill(&mut self) -> Result<bool> {
+        while self.pos == self.chunk.len() {
+            let page_no = match self.next_page_no {
+                Some(page_no) => page_no,
+                None => {
+                    return match self.remaining {
+                        0 => Ok(false),
+                        remaining => Err(Error::corruption(format!(
+                            "blob is missing its last {} bytes",
+                            remaining
+                        ))),
+                    }
+                }
+            };
+
+            let page = self.page_fetcher.fetch_page_read(page_no)?;
+            BTreePageData::check(&page).map_err(|err| err.on_page(page_no))?;
+            let special_data = page.special_data::<BTreePageData>();
+            if !matches!(special_data.node_type, NodeType::Overflow) || page.item_cnt() != 1 {
+                return Err(Error::page_corruption(page_no, "not a valid blob page"));
+            }
+            let item = page
+                .try_get_item_v2::<ValueBytes>(0)
+                .map_err(|err| err.on_page(page_no))?
+                .value;
+            if item.len() < size_of::<u32>()
+                || (item.len() - size_of::<u32>()) as u64 > self.remaining
+            {
+                return Err(Error::page_corruption(
+                    page_no,
+                    format!("blob page holds {} bytes", item.len()),
+                ));
+            }
+            let (crc, chunk) = item.split_at(size_of::<u32>());
+            if u32::from_be_bytes(crc.try_into().unwrap()) != crc32(chunk) {
+                return Err(Error::page_corruption(
+                    page_no,
+                    "blob chunk checksum mismatch",
+                ));
+            }
+
+            self.remaining -= chunk.len() as u64;
+            self.chunk = chunk.to_vec();
+            self.pos = 0;
+            self.next_page_no = special_data.right_sibling().get();
+        }
+        Ok(true)
+    }
+
+    /// Reads the rest of the blob into memory.
+    pub fn read_all(mut self) -> Result<Vec<u8>> {
+        let mut bytes = Vec::with_capacity(self.remaining as usize);
+        while self.fill()? {
+            bytes.extend_from_slice(&self.chunk[self.pos..]);
+            self.pos = self.chunk.len();
+        }
+        Ok(bytes)
+    }
+}
+
+impl<'a, PageFetcher: PageFetcherTrait> Read for BlobReader<'a, PageFetcher> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if !self.fill().map_err(io::Error::other)? {
+            return Ok(0);
+        }
+        let len = buf.len().min(self.chunk.len() - self.pos);
+        buf[..len].copy_from_slice(&self.chunk[self.pos..self.pos + len]);
+        self.pos += len;
+        Ok(len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::BLOB_CHUNK_SIZE;
+    use crate::btree::key::KeyU64;
+    use crate::btree::value::Value;
+    use crate::btree::value::ValueBytes;
+    use crate::btree::value::ValueLarge;
+    use crate::btree::value::VALUE_INLINE_LIMIT;
+    use crate::btree::BTree;
+    use crate::error::Result;
+    use crate::page::Page;
+    use crate::page_fetcher::InMemoryPageFetcher;
+    use crate::page_fetcher::PageFetcher;
+    use std::io::Read;
+
+    #[test]
+    fn overflow_round_trip() {
+        let btree: BTree<KeyU64, ValueBytes, _> = BTree::new(InMemoryPageFetcher::new()).unwrap();
+
+        let bytes = (0..20000u32).map(|i| (i % 251) as u8).collect::<Vec<_>>();
+        let first_page_no = btree.write_overflow(&bytes).unwrap();
+        assert_eq!(btree.read_overflow(first_page_no).unwrap(), bytes);
+
+        let empty_page_no = btree.write_overflow(&[]).unwrap();
+        assert!(btree.read_overflow(empty_page_no).unwrap().is_empty());
+    }
+
+    #[test]
+    fn blob_round_trip() {
+        let btree: BTree<KeyU64, ValueBytes, _> = BTree::new(InMemoryPageFetcher::new()).unwrap();
+
+        for size in [
+            0,
+            1,
+            BLOB_CHUNK_SIZE,
+            BLOB_CHUNK_SIZE + 1,
+            5 * BLOB_CHUNK_SIZE - 7,
+        ] {
+            let bytes = (0..size).map(|i| (i % 253) as u8).collect::<Vec<_>>();
+            let (first_page_no, stored) = btree.write_blob(&bytes[..]).unwrap();
+            assert_eq!(stored, size as u64);
+
+            // Read through a small buffer so chunks are split across reads
+            let mut reader = btree.blob_reader(first_page_no, stored);
+            let mut read = Vec::new();
+            let mut buf = [0; 1000];
+            loop {
+                match reader.read(&mut buf).unwrap() {
+                    0 => break,
+                    len => read.extend_from_slice(&buf[..len]),
+                }
+            }
+            assert_eq!(read, bytes);
+            assert_eq!(
+                btree.blob_reader(first_page_no, stored).read_all().unwrap(),
+                bytes
+            );
+        }
+
+        // A blob cut short of its recorded size is corrupt
+        let (first_page_no, stored) = btree.write_blob(&[7u8; 100][..]).unwrap();
+        assert!(btree
+            .blob_reader(first_page_no, stored + 1)
+            .read_all()
+            .is_err());
+
+        // As is one whose bytes changed
+        let page_fetcher = btree.page_fetcher();
+        let mut bytes = page_fetcher
+            .fetch_page_read(first_page_no)
+            .unwrap()
+            .as_bytes()
+            .to_vec();
+        let pos = bytes.windows(100).position(|w| w == [7; 100]).unwrap();
+        bytes[pos] ^= 1;
+        **page_fetcher.fetch_page_write(first_page_no).unwrap() = Page::from_bytes(&bytes);
+        assert!(btree.blob_reader(first_page_no, stored).read_all().is_err());
+
+        // A blob whose reader fails partway leaves no pages behind
+        let live_page_cnt = page_fetcher.page_cnt() - page_fetcher.free_page_cnt();
+        let failing = (&[1u8; 3 * BLOB_CHUNK_SIZE][..]).chain(FailingReader);
+        assert!(btree.write_blob(failing).is_err());
+        assert_eq!(
+            page_fetcher.page_cnt() - page_fetcher.free_page_cnt(),
+            live_page_cnt
+        );
+    }
+
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("reader failed"))
+        }
+    }
+
+    #[test]
+    fn large_values_move_to_overflow_pages() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(256)).unwrap();
+        let value = |key: u64| {
+            let size = match key % 3 {
+                0 => 10,
+                1 => VALUE_INLINE_LIMIT,
+                _ => 20000 + key as usize,
+            };
+            ValueLarge::from(vec![key as u8; size])
+        };
+        for key in 0..60u64 {
+            btree.insert(KeyU64 { key }, value(key)).unwrap();
+        }
+
+        let page_cnt = btree.page_fetcher().page_cnt();
+        // 20 values of 3 pages each, plus the metadata page and the leaves
+        assert!(page_cnt > 60 && page_cnt < 70, "{}", page_cnt);
+        for key in [0, 1, 2, 59] {
+            let found = btree.search(KeyU64 { key }).unwrap().value.unwrap();
+            assert_eq!(found, value(key));
+            assert_eq!(found.overflow_no(), None);
+        }
+        let entries = btree
+            .range(..)
+            .unwrap()
+            .collect::<Result<Vec<_>>>()
+            .unwrap();
+        assert_eq!(entries.len(), 60);
+        assert!(entries.iter().all(|(key, found)| *found == value(key.key)));
+        let last = btree.top_n(.., 1).unwrap();
+        assert_eq!(last[0].1, value(59));
+        assert_eq!(
+            btree.insert_if_absent(KeyU64 { key: 5 }, value(0)).unwrap(),
+            Some(value(5))
+        );
+
+        // Deleting values frees their chains
+        assert_eq!(btree.delete(KeyU64 { key: 2 }).unwrap(), Some(value(2)));
+        assert_eq!(btree.page_fetcher().free_page_cnt(), 3);
+        assert_eq!(
+            btree
+                .delete_range(KeyU64 { key: 3 }..KeyU64 { key: 30 })
+                .unwrap(),
+            27
+        );
+        assert!(btree.page_fetcher().free_page_cnt() >= 3 * 10);
+        assert_eq!(
+            btree.search(KeyU64 { key: 32 }).unwrap().value,
+            Some(value(32))
+        );
+        btree.verify().unwrap();
+    }
+}
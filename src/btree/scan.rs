@@ -0,0 +1,692 @@
+use super::internal_node::InternalNodeRead;
+use super::key::Key;
+use super::leaf_node::LeafNodeItemData;
+use super::metadata_node::MetadataRead;
+use super::node::NodeRead;
+use super::node::Separator;
+use super::overflow::load_value;
+use super::value::Value;
+use super::BTreePageData;
+use super::NodeType;
+use crate::error::Error;
+use crate::error::Result;
+use crate::page::ItemRef;
+use crate::page::PageNo;
+use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use std::collections::VecDeque;
+use std::ops::Bound;
+use std::ops::RangeBounds;
+
+impl<K, V, PageFetcher> super::BTree<K, V, PageFetcher>
+where
+    K: Key,
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Returns the entries within `range` in ascending key order.
+    ///
+    /// Leaves are read one at a time: the iterator copies out the matching items of a leaf and
+    /// releases its read lock before following the right sibling link, so concurrent inserts
+    /// may or may not be observed.
+    pub fn range<R>(&self, range: R) -> Result<RangeIter<'_, PageFetcher, K, V>>
+    where
+        R: RangeBounds<K>,
+    {
+        self.range_iter(range, None)
+    }
+
+    /// Like `range`, but only returns the entries for which `filter` returns true. `filter` is
+    /// called with each key and the value's encoded bytes while the leaf's read lock is held,
+    /// and only the matching entries are decoded and copied out, which makes this much cheaper
+    /// than filtering the output of `range` when few entries match.
+    pub fn scan_filter<'a, R, F>(
+        &'a self,
+        range: R,
+        filter: F,
+    ) -> Result<RangeIter<'a, PageFetcher, K, V>>
+    where
+        R: RangeBounds<K>,
+        F: FnMut(&K, &[u8]) -> bool + 'a,
+    {
+        self.range_iter(range, Some(Box::new(filter)))
+    }
+
+    fn range_iter<'a, R>(
+        &'a self,
+        range: R,
+        filter: Option<EntryFilter<'a, K>>,
+    ) -> Result<RangeIter<'a, PageFetcher, K, V>>
+    where
+        R: RangeBounds<K>,
+    {
+        self.op_stats.scans.inc();
+        let start = range.start_bound().cloned();
+        let end = range.end_bound().cloned();
+
+        let start_key = match &start {
+            Bound::Included(key) | Bound::Excluded(key) => Some(key),
+            Bound::Unbounded => None,
+        };
+        let next_leaf_no = self.find_leaf_no(start_key)?;
+
+        Ok(RangeIter {
+            page_fetcher: &self.page_fetcher,
+            start,
+            end,
+            next_leaf_no,
+            buffer: VecDeque::new(),
+            filter,
+        })
+    }
+
+    /// Calls `visit` on the entries within `range` in ascending key order, until it returns
+    /// `false`.
+    ///
+    /// Unlike `range`, values aren't copied out of the pages: each `EntryRef` borrows the leaf
+    /// while its read lock is held, which makes this the cheaper option for scans over large
+    /// values. Only one leaf is locked at a time.
+    pub fn range_visit<R, F>(&self, range: R, mut visit: F) -> Result<()>
+    where
+        R: RangeBounds<K>,
+        F: FnMut(EntryRef<'_, K, V>) -> bool,
+    {
+        self.op_stats.scans.inc();
+        let start_key = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => Some(key),
+            Bound::Unbounded => None,
+        };
+        let mut next_leaf_no = self.find_leaf_no(start_key)?;
+
+        while let Some(leaf_no) = next_leaf_no {
+            let leaf = super::leaf_node::fetch_page_read::<PageFetcher, K, V>(
+                &self.page_fetcher,
+                leaf_no,
+            )?;
+
+            // Only the keys are decoded, to filter the leaf's entries and sort them unless the
+            // leaf already keeps them sorted
+            let sorted = leaf.special_data().is_sorted();
+            let start = match sorted {
+                true => first_in_range(&leaf, range.start_bound()),
+                false => 1,
+            };
+            let mut entries = leaf
+                .page_ref()
+                .item_refs_from::<LeafNodeItemData<K, V>>(start)
+                .map(|item| (item.key(), item))
+                .filter(|(key, item)| range.contains(key) && !V::is_tombstone(item.value_bytes()))
+                .collect::<Vec<_>>();
+            if !sorted {
+                entries.sort_by(|x, y| x.0.cmp(&y.0));
+            }
+
+            for (key, item) in entries {
+                if !visit(EntryRef { key, item }) {
+                    return Ok(());
+                }
+            }
+
+            next_leaf_no = if leaf.separator().is_past_end(range.end_bound()) {
+                None
+            } else {
+                leaf.special_data().right_sibling().get()
+            };
+        }
+
+        Ok(())
+    }
+
+    /// Returns the `n` entries with the smallest keys within `range`, in ascending key order.
+    /// Leaves are read one at a time until there are `n` entries, see `range`.
+    pub fn bottom_n<R>(&self, range: R, n: usize) -> Result<Vec<(K, V)>>
+    where
+        R: RangeBounds<K>,
+    {
+        self.range(range)?.take(n).collect()
+    }
+
+    /// Returns the `n` entries with the largest keys within `range`, in descending key order.
+    ///
+    /// Leaves have no left sibling links to walk backwards, so instead this descends into the
+    /// rightmost subtree that may hold entries within `range` and backtracks through the parents
+    /// once it's exhausted, stopping as soon as there are `n` entries. Only one node is locked at
+    /// a time, and a node that split after its parent was read is read along with the right
+    /// siblings its entries moved to.
+    pub fn top_n<R>(&self, range: R, n: usize) -> Result<Vec<(K, V)>>
+    where
+        R: RangeBounds<K>,
+    {
+        self.op_stats.scans.inc();
+        let mut entries = Vec::with_capacity(n);
+        if n > 0 {
+            if let Some(root_no) = self.metadata_read()?.root_no()? {
+                self.top_n_visit(root_no, None, &range, n, &mut entries)?;
+            }
+        }
+        entries
+            .into_iter()
+            .map(|(key, value)| Ok((key, self.load_value(value)?)))
+            .collect()
+    }
+
+    /// Appends the largest entries within `range` of the subtree rooted at `page_no`, which holds
+    /// keys below `upper`, to `entries` until there are `n` of them.
+    fn top_n_visit<R>(
+        &self,
+        page_no: u32,
+        upper: Option<&Separator<K>>,
+        range: &R,
+        n: usize,
+        entries: &mut Vec<(K, V)>,
+    ) -> Result<()>
+    where
+        R: RangeBounds<K>,
+    {
+        let is_leaf = {
+            let node = self.page_fetcher.fetch_page_read(page_no)?;
+            match BTreePageData::checked_node_type(page_no, &node)? {
+                NodeType::Leaf => true,
+                NodeType::Internal => false,
+                node_type => {
+                    return Err(Error::page_corruption(
+                        page_no,
+                        format!("encountered a {:?} page while traversing down", node_type),
+                    ))
+                }
+            }
+        };
+        match is_leaf {
+            true => {
+                let mut leaf_entries = self.read_split_nodes(page_no, upper, |page_no| {
+                    let leaf = super::leaf_node::fetch_page_read::<PageFetcher, K, V>(
+                        &self.page_fetcher,
+                        page_no,
+                    )?;
+                    let items = leaf
+                        .page_ref()
+                        .item_refs_from::<LeafNodeItemData<K, V>>(1)
+                        .map(|item| (item.key(), item))
+                        .filter(|(key, item)| {
+                            range.contains(key) && !V::is_tombstone(item.value_bytes())
+                        })
+                        .map(|(key, item)| (key, item.value()))
+                        .collect();
+                    Ok((
+                        items,
+                        leaf.separator().clone(),
+                        leaf.special_data().right_sibling(),
+                    ))
+                })?;
+                leaf_entries.sort_by(|x, y| y.0.cmp(&x.0));
+                let missing = n - entries.len();
+                entries.extend(leaf_entries.into_iter().take(missing));
+            }
+            false => {
+                let mut downlinks = self.read_split_nodes(page_no, upper, |page_no| {
+                    let internal = super::internal_node::fetch_page_read::<PageFetcher, K>(
+                        &self.page_fetcher,
+                        page_no,
+                    )?;
+                    Ok((
+                        internal.downlink_bounds(),
+                        internal.separator().clone(),
+                        internal.special_data().right_sibling(),
+                    ))
+                })?;
+                downlinks.reverse();
+
+                for (idx, (key, child_no)) in downlinks.iter().enumerate() {
+                    if entries.len() >= n {
+                        break;
+                    }
+                    // The child holds the keys from the next smaller downlink's up to its own
+                    let below_start = match range.start_bound() {
+                        Bound::Included(start) | Bound::Excluded(start) => !key.is_above(start),
+                        Bound::Unbounded => false,
+                    };
+                    if below_start {
+                        break;
+                    }
+                    let lower = downlinks.get(idx + 1).map(|(lower, _)| lower);
+                    let above_end = match (lower, range.end_bound()) {
+                        (Some(lower), Bound::Included(end)) => lower.is_above(end),
+                        (Some(lower), Bound::Excluded(end)) => lower.is_at_least(end),
+                        _ => false,
+                    };
+                    if !above_end {
+                        self.top_n_visit(*child_no, Some(key), range, n, entries)?;
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Reads the items of node `page_no` with `read`, which returns them along with the node's
+    /// separator and right sibling. If the node's separator is below `upper`, it split since
+    /// `upper` was read from its parent, and its right siblings are read as well until one
+    /// reaches `upper`.
+    fn read_split_nodes<T, F>(
+        &self,
+        mut page_no: u32,
+        upper: Option<&Separator<K>>,
+        mut read: F,
+    ) -> Result<Vec<T>>
+    where
+        F: FnMut(u32) -> Result<(Vec<T>, Separator<K>, PageNo)>,
+    {
+        let mut items = Vec::new();
+        loop {
+            let (node_items, separator, right_sibling) = read(page_no)?;
+            items.extend(node_items);
+            match (upper, right_sibling.get()) {
+                (Some(upper), Some(right_sibling_no)) if separator < *upper => {
+                    self.page_fetcher.metrics().move_rights.inc();
+                    page_no = right_sibling_no;
+                }
+                _ => return Ok(items),
+            }
+        }
+    }
+}
+
+/// An entry borrowed from a leaf page by `range_visit`.
+pub struct EntryRef<'a, K, V>
+where
+    K: Key,
+    V: Value,
+{
+    key: K,
+    item: ItemRef<'a, LeafNodeItemData<K, V>>,
+}
+
+impl<'a, K, V> EntryRef<'a, K, V>
+where
+    K: Key,
+    V: Value,
+{
+    pub fn key(&self) -> &K {
+        &self.key
+    }
+
+    /// The value's encoded bytes, without copying them out of the page.
+    pub fn value_bytes(&self) -> &'a [u8] {
+        self.item.value_bytes()
+    }
+
+    /// Decodes the value, copying it out of the page.
+    pub fn value(&self) -> V {
+        self.item.value()
+    }
+}
+
+/// A predicate over a key and its value's encoded bytes, see `BTree::scan_filter`.
+type EntryFilter<'a, K> = Box<dyn FnMut(&K, &[u8]) -> bool + 'a>;
+
+pub struct RangeIter<'a, PageFetcher, K, V>
+where
+    PageFetcher: PageFetcherTrait,
+    K: Key,
+    V: Value,
+{
+    page_fetcher: &'a PageFetcher,
+    start: Bound<K>,
+    end: Bound<K>,
+    next_leaf_no: Option<u32>,
+    buffer: VecDeque<(K, V)>,
+    filter: Option<EntryFilter<'a, K>>,
+}
+
+impl<'a, PageFetcher, K, V> RangeIter<'a, PageFetcher, K, V>
+where
+    PageFetcher: PageFetcherTrait,
+    K: Key,
+    V: Value,
+{
+    /// Buffers the matching items of the next leaf in sibling order.
+    fn load_next_leaf(&mut self, leaf_no: u32) -> Result<()> {
+        let leaf =
+            super::leaf_node::fetch_page_read::<PageFetcher, K, V>(self.page_fetcher, leaf_no)?;
+
+        let bounds = (self.start.as_ref(), self.end.as_ref());
+        let sorted = leaf.special_data().is_sorted();
+        let start = match sorted {
+            true => first_in_range(&leaf, bounds.0),
+            false => 1,
+        };
+        let filter = &mut self.filter;
+        let mut items = leaf
+            .page_ref()
+            .item_refs_from::<LeafNodeItemData<K, V>>(start)
+            .filter_map(|item| {
+                let key = item.key();
+                let matches = bounds.contains(&key)
+                    && !V::is_tombstone(item.value_bytes())
+                    && filter
+                        .as_mut()
+                        .is_none_or(|filter| filter(&key, item.value_bytes()));
+                matches.then(|| (key, item.value()))
+            })
+            .collect::<Vec<_>>();
+        if !sorted {
+            items.sort_by(|x, y| x.0.cmp(&y.0));
+        }
+        self.buffer.extend(items);
+
+        self.next_leaf_no = if leaf.separator().is_past_end(self.end.as_ref()) {
+            None
+        } else {
+            leaf.special_data().right_sibling().get()
+        };
+
+        Ok(())
+    }
+}
+
+impl<'a, PageFetcher, K, V> Iterator for RangeIter<'a, PageFetcher, K, V>
+where
+    PageFetcher: PageFetcherTrait,
+    K: Key,
+    V: Value,
+{
+    type Item = Result<(K, V)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while self.buffer.is_empty() {
+            let leaf_no = self.next_leaf_no?;
+            if let Err(err) = self.load_next_leaf(leaf_no) {
+                self.next_leaf_no = None;
+                return Some(Err(err));
+            }
+        }
+
+        let (key, value) = self.buffer.pop_front()?;
+        Some(load_value(self.page_fetcher, value).map(|value| (key, value)))
+    }
+}
+
+/// The index of the first item of the sorted leaf `leaf` that isn't below `start`.
+fn first_in_range<N, K, V>(leaf: &N, start: Bound<&K>) -> usize
+where
+    N: NodeRead<LeafNodeItemData<K, V>>,
+    K: Key,
+    V: Value,
+{
+    match start {
+        Bound::Included(start) => leaf.partition_point(|key| key < start),
+        Bound::Excluded(start) => leaf.partition_point(|key| key <= start),
+        Bound::Unbounded => 1,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::btree::key::KeyEncoded;
+    use crate::btree::key::KeyF64;
+    use crate::btree::key::KeyI64;
+    use crate::btree::key::KeyString;
+    use crate::btree::key::KeyU32;
+    use crate::btree::value::ValueBytes;
+    use crate::btree::value::ValueTupleId;
+    use crate::btree::BTree;
+    use crate::encoding::Desc;
+    use crate::page_fetcher::InMemoryPageFetcher;
+
+    #[test]
+    fn range_across_leaves() {
+        let mut btree = BTree::new(InMemoryPageFetcher::new()).unwrap();
+        // Insert in a scrambled order so the leaves aren't filled sequentially
+        for i in 0..3000u32 {
+            let key = (i * 7919) % 3000;
+            btree
+                .insert(
+                    KeyU32 { key },
+                    ValueTupleId {
+                        page_no: key,
+                        offset: 0,
+                    },
+                )
+                .unwrap();
+        }
+
+        let keys = |iter: super::RangeIter<_, KeyU32, ValueTupleId>| {
+            iter.map(|res| res.unwrap().0.key).collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            keys(btree.range(..).unwrap()),
+            (0..3000).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            keys(
+                btree
+                    .range(KeyU32 { key: 1000 }..KeyU32 { key: 2500 })
+                    .unwrap()
+            ),
+            (1000..2500).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            keys(
+                btree
+                    .range(KeyU32 { key: 2990 }..=KeyU32 { key: 5000 })
+                    .unwrap()
+            ),
+            (2990..3000).collect::<Vec<_>>()
+        );
+        assert!(keys(
+            btree
+                .range(KeyU32 { key: 4000 }..KeyU32 { key: 5000 })
+                .unwrap()
+        )
+        .is_empty());
+    }
+
+    #[test]
+    fn range_over_signed_and_string_keys() {
+        let mut signed = BTree::new(InMemoryPageFetcher::with_capacity(64)).unwrap();
+        let mut floats = BTree::new(InMemoryPageFetcher::with_capacity(64)).unwrap();
+        let mut strings = BTree::new(InMemoryPageFetcher::with_capacity(64)).unwrap();
+        let value = ValueTupleId {
+            page_no: 0,
+            offset: 0,
+        };
+        for i in 0..1500i64 {
+            let key = ((i * 7919) % 1500) - 750;
+            signed.insert(KeyI64 { key }, value).unwrap();
+            floats
+                .insert(KeyF64::from(key as f64 / 4.0), value)
+                .unwrap();
+            strings
+                .insert(KeyString::from(format!("key-{}", key)), value)
+                .unwrap();
+        }
+
+        let signed_keys = signed
+            .range(KeyI64 { key: -10 }..KeyI64 { key: 10 })
+            .unwrap()
+            .map(|res| res.unwrap().0.key)
+            .collect::<Vec<_>>();
+        assert_eq!(signed_keys, (-10..10).collect::<Vec<_>>());
+
+        floats.insert(KeyF64::from(f64::NAN), value).unwrap();
+        floats
+            .insert(KeyF64::from(f64::NEG_INFINITY), value)
+            .unwrap();
+        let float_keys = floats
+            .range(KeyF64::from(-2.0)..=KeyF64::from(2.0))
+            .unwrap()
+            .map(|res| res.unwrap().0.key)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            float_keys,
+            (-8..=8).map(|key| key as f64 / 4.0).collect::<Vec<_>>()
+        );
+        let first = floats.range(..).unwrap().next().unwrap().unwrap().0;
+        let last = floats.top_n(.., 1).unwrap()[0].0;
+        assert_eq!(
+            (first, last),
+            (KeyF64::from(f64::NEG_INFINITY), KeyF64::from(f64::NAN))
+        );
+
+        let mut expected = (-750..750)
+            .map(|key| format!("key-{}", key))
+            .collect::<Vec<_>>();
+        expected.sort();
+        let string_keys = strings
+            .range(..)
+            .unwrap()
+            .map(|res| res.unwrap().0.key)
+            .collect::<Vec<_>>();
+        assert_eq!(string_keys, expected);
+    }
+
+    #[test]
+    fn latest_per_user_from_descending_column() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(64)).unwrap();
+        let value = ValueTupleId {
+            page_no: 0,
+            offset: 0,
+        };
+        for i in 0..2000i64 {
+            let (user_id, created_at) = ((i * 7919) % 20, i * 31 - 20_000);
+            btree
+                .insert(KeyEncoded::new(&(user_id as u32, Desc(created_at))), value)
+                .unwrap();
+        }
+
+        // (user_id ASC, created_at DESC): a user's newest items come first
+        let latest = btree
+            .range(KeyEncoded::new(&(7u32,))..KeyEncoded::new(&(8u32,)))
+            .unwrap()
+            .take(3)
+            .map(|res| res.unwrap().0.decode::<(u32, Desc<i64>)>().unwrap())
+            .collect::<Vec<_>>();
+        let mut expected = (0..2000i64)
+            .filter(|i| (i * 7919) % 20 == 7)
+            .map(|i| (7, Desc(i * 31 - 20_000)))
+            .collect::<Vec<_>>();
+        expected.sort();
+        expected.truncate(3);
+        assert_eq!(latest, expected);
+        let created_at = latest
+            .iter()
+            .map(|(_, Desc(created_at))| *created_at)
+            .collect::<Vec<_>>();
+        assert!(created_at.windows(2).all(|pair| pair[0] > pair[1]));
+    }
+
+    #[test]
+    fn range_visit_borrows_values() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(64)).unwrap();
+        for i in 0..400u32 {
+            let key = (i * 7919) % 400;
+            btree
+                .insert(
+                    KeyU32 { key },
+                    ValueBytes {
+                        value: vec![key as u8; 100],
+                    },
+                )
+                .unwrap();
+        }
+
+        let mut seen = Vec::new();
+        btree
+            .range_visit(KeyU32 { key: 50 }..KeyU32 { key: 350 }, |entry| {
+                assert_eq!(entry.value_bytes(), &[entry.key().key as u8; 100][..]);
+                seen.push(entry.key().key);
+                entry.key().key < 300
+            })
+            .unwrap();
+        assert_eq!(seen, (50..=300).collect::<Vec<_>>());
+
+        let mut fixed = BTree::new(InMemoryPageFetcher::new()).unwrap();
+        let value = ValueTupleId {
+            page_no: 7,
+            offset: 3,
+        };
+        fixed.insert(KeyU32 { key: 1 }, value).unwrap();
+        fixed
+            .range_visit(.., |entry| {
+                assert_eq!(entry.key().key, 1);
+                assert_eq!(entry.value(), value);
+                true
+            })
+            .unwrap();
+    }
+
+    #[test]
+    fn scan_filter_copies_only_matches() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(64)).unwrap();
+        for key in 0..1000u32 {
+            let value = ValueBytes {
+                value: vec![(key % 10) as u8; 50],
+            };
+            btree.insert(KeyU32 { key }, value).unwrap();
+        }
+
+        let mut calls = 0;
+        let matches = btree
+            .scan_filter(KeyU32 { key: 100 }..KeyU32 { key: 600 }, |key, value| {
+                calls += 1;
+                key.key % 2 == 1 && value[0] == 3
+            })
+            .unwrap()
+            .map(|entry| entry.unwrap())
+            .collect::<Vec<_>>();
+        let expected = (100..600u32)
+            .filter(|key| key % 10 == 3)
+            .map(|key| {
+                let value = ValueBytes { value: vec![3; 50] };
+                (KeyU32 { key }, value)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(matches, expected);
+        assert_eq!(calls, 500);
+    }
+
+    #[test]
+    fn top_n_and_bottom_n() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(2048)).unwrap();
+        for i in 0..5000u32 {
+            let key = (i * 7919) % 5000;
+            let value = ValueBytes {
+                value: vec![key as u8; 1000],
+            };
+            btree.insert(KeyU32 { key }, value).unwrap();
+        }
+        assert!(btree.analyze().unwrap().height() > 2);
+        let keys = |entries: Vec<(KeyU32, ValueBytes)>| {
+            entries
+                .into_iter()
+                .map(|(key, value)| {
+                    assert_eq!(value.value, vec![key.key as u8; 1000]);
+                    key.key
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            keys(btree.top_n(.., 50).unwrap()),
+            (4950..5000).rev().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            keys(btree.top_n(..=KeyU32 { key: 3000 }, 5).unwrap()),
+            vec![3000, 2999, 2998, 2997, 2996]
+        );
+        assert_eq!(
+            keys(
+                btree
+                    .top_n(KeyU32 { key: 1200 }..KeyU32 { key: 1900 }, 1000)
+                    .unwrap()
+            ),
+            (1200..1900).rev().collect::<Vec<_>>()
+        );
+        assert_eq!(
+            keys(btree.bottom_n(KeyU32 { key: 10 }.., 3).unwrap()),
+            vec![10, 11, 12]
+        );
+        assert!(btree.top_n(KeyU32 { key: 6000 }.., 10).unwrap().is_empty());
+        assert!(btree.top_n(.., 0).unwrap().is_empty());
+    }
+}
@@ -1,14 +1,19 @@
 use super::internal_node::find_child_ptr_move_right_read_lock;
 use super::internal_node::from_read_lock as from_read_lock_internal;
+use super::internal_node::InternalNodeRead;
 use super::key::Key;
-use super::leaf_node::LeafNodeRead;
 use super::leaf_node::LeafNodeReadLock;
 use super::metadata_node::MetadataRead;
+use super::node::NodeRead;
+use super::span::OpSpan;
 use super::value::Value;
 use super::BTreePageData;
 use super::NodeType;
 use crate::btree::metadata_node::MetadataReadLock;
+use crate::error::Error;
+use crate::error::Result;
 use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use std::convert::TryFrom;
 
 #[derive(Debug, PartialEq)]
 pub struct SearchResult<T> {
@@ -16,69 +21,140 @@ pub struct SearchResult<T> {
     pub value: Option<T>,
 }
 
-impl<PageFetcher> super::BTree<PageFetcher>
+impl<K, V, PageFetcher> super::BTree<K, V, PageFetcher>
 where
+    K: Key,
+    V: Value,
     PageFetcher: PageFetcherTrait,
 {
-    pub fn search<K, V>(&self, key: K) -> SearchResult<V>
-    where
-        K: Key,
-        V: Value,
-    {
-        let mut page_no = 0;
+    pub fn search(&self, key: K) -> Result<SearchResult<V>> {
+        self.op_stats.searches.inc();
+        self.search_inner(key)
+            .and_then(|mut res| {
+                res.value = res.value.map(|value| self.load_value(value)).transpose()?;
+                Ok(res)
+            })
+            .map_err(|err| err.context("search"))
+    }
+
+    fn search_inner(&self, key: K) -> Result<SearchResult<V>> {
+        let _span = OpSpan::enter("search", &key, self.page_fetcher.metrics());
+        let mut page_no = self.metadata_no;
 
         loop {
-            let node = self.page_fetcher.fetch_page_read(page_no).unwrap();
-            let special_data = node.special_data::<BTreePageData>();
-            let right_sibling_page_no = special_data.right_sibling_page_no;
-            match special_data.node_type {
+            let node = self.page_fetcher.fetch_page_read(page_no)?;
+            let node_type = BTreePageData::checked_node_type(page_no, &node)?;
+            let right_sibling = node.special_data::<BTreePageData>().right_sibling();
+            match node_type {
                 NodeType::Leaf => {
-                    let leaf = LeafNodeReadLock::<K, V>::from((page_no, node));
-                    if key < leaf.separator() {
-                        let found_row = leaf.item_iter().find(|item_data| key == item_data.key);
-
-                        return match found_row {
-                            Some(row) => SearchResult {
-                                leaf_page_no: page_no,
-                                value: Some(row.value),
-                            },
+                    let leaf = LeafNodeReadLock::<K, V>::try_from((page_no, node))?;
+                    if leaf.separator().is_above(&key) {
+                        let found = leaf
+                            .find_item(&key)
+                            .filter(|(_, item)| !V::is_tombstone(item.value_bytes()));
+                        return match found {
+                            Some((_, item)) => Ok(SearchResult {
+                                leaf_page_no: leaf.page_no,
+                                value: Some(item.value()),
+                            }),
                             // This indicates the scenario where page was splitted in between the release
                             // of the parent node's lock and the lock acquisition of current node
-                            None => SearchResult {
-                                leaf_page_no: page_no,
+                            None => Ok(SearchResult {
+                                leaf_page_no: leaf.page_no,
                                 value: None,
-                            },
+                            }),
                         };
-                    } else if right_sibling_page_no == 0 {
-                        return SearchResult {
+                    } else if let Some(right_sibling_no) = right_sibling.get() {
+                        self.page_fetcher.metrics().move_rights.inc();
+                        page_no = right_sibling_no;
+                    } else {
+                        return Ok(SearchResult {
                             leaf_page_no: page_no,
                             value: None,
-                        };
-                    } else {
-                        page_no = right_sibling_page_no;
+                        });
                     }
                 }
                 NodeType::Internal => {
                     let (_, child_no) = find_child_ptr_move_right_read_lock(
                         &self.page_fetcher,
-                        from_read_lock_internal(page_no, node),
-                        key,
-                    );
+                        from_read_lock_internal(page_no, node)?,
+                        &key,
+                    )?;
 
                     page_no = child_no
                 }
                 NodeType::Metadata => {
-                    let root_no = MetadataReadLock::from(node).root_no();
+                    let root_no = MetadataReadLock::try_from((page_no, node))?.root_no()?;
                     match root_no {
                         None => {
-                            return SearchResult {
+                            return Ok(SearchResult {
                                 leaf_page_no: 0,
                                 value: None,
-                            };
+                            });
                         }
                         Some(root_no) => page_no = root_no,
                     };
                 }
+                NodeType::Overflow | NodeType::Heap | NodeType::FreeSpace => {
+                    return Err(Error::page_corruption(
+                        page_no,
+                        format!("encountered a {:?} page while searching", node_type),
+                    ));
+                }
+            }
+        }
+    }
+
+    /// Descends from the root to the leaf whose key range should contain `key`, or to the
+    /// leftmost leaf when `key` is `None`. The leaf may have split after we released its parent,
+    /// so callers looking for `key` still need to move right. Returns `None` when the tree has no
+    /// root yet.
+    pub(super) fn find_leaf_no(&self, key: Option<&K>) -> Result<Option<u32>> {
+        Ok(self
+            .find_leaf_and_parent_no(key)?
+            .map(|(leaf_no, _)| leaf_no))
+    }
+
+    /// Like `find_leaf_no`, but also returns the internal node the leaf was reached from, unless
+    /// the root is the leaf.
+    pub(super) fn find_leaf_and_parent_no(
+        &self,
+        key: Option<&K>,
+    ) -> Result<Option<(u32, Option<u32>)>> {
+        let root_no = self.metadata_read()?.root_no()?;
+        let mut page_no = match root_no {
+            Some(root_no) => root_no,
+            None => return Ok(None),
+        };
+        let mut parent_no = None;
+
+        loop {
+            let node = self.page_fetcher.fetch_page_read(page_no)?;
+            let node_type = BTreePageData::checked_node_type(page_no, &node)?;
+            match node_type {
+                NodeType::Leaf => return Ok(Some((page_no, parent_no))),
+                NodeType::Internal => {
+                    let internal = from_read_lock_internal::<K>(page_no, node)?;
+                    let (node_no, child_no) = match key {
+                        Some(key) => {
+                            find_child_ptr_move_right_read_lock(&self.page_fetcher, internal, key)?
+                        }
+                        None => {
+                            let child_no = internal.first_child_ptr().ok_or_else(|| {
+                                Error::page_corruption(page_no, "internal page has no items")
+                            })?;
+                            (page_no, child_no)
+                        }
+                    };
+                    parent_no = Some(node_no);
+                    page_no = child_no;
+                }
+                NodeType::Metadata | NodeType::Overflow | NodeType::Heap | NodeType::FreeSpace => {
+                    return Err(Error::page_corruption(
+                        page_no,
+                        format!("encountered a {:?} page while traversing down", node_type),
+                    ));
+                }
             }
         }
     }
@@ -0,0 +1,161 @@
+use super::key::Key;
+use super::key::KeyU32;
+use super::key::KeyU64;
+use super::metadata_node::MetadataRead;
+use super::value::Value;
+use crate::error::Error;
+use crate::error::Result;
+use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use std::convert::TryFrom;
+
+/// Sequence values handed out between writes of the sequence limit to the metadata page.
+const SEQUENCE_CACHE: u64 = 64;
+
+/// Keys that `insert_auto` can generate from sequence values.
+pub trait SequenceKey: Key {
+    /// The key for sequence value `n`, or `None` if it's out of the key's range.
+    fn from_sequence(n: u64) -> Option<Self>;
+
+    fn to_sequence(&self) -> u64;
+}
+
+impl SequenceKey for KeyU32 {
+    fn from_sequence(n: u64) -> Option<Self> {
+        u32::try_from(n).ok().map(|key| KeyU32 { key })
+    }
+
+    fn to_sequence(&self) -> u64 {
+        self.key as u64
+    }
+}
+
+impl SequenceKey for KeyU64 {
+    fn from_sequence(n: u64) -> Option<Self> {
+        Some(KeyU64 { key: n })
+    }
+
+    fn to_sequence(&self) -> u64 {
+        self.key
+    }
+}
+
+/// The in-memory part of a tree's sequence. Values below `limit` have been reserved in the
+/// metadata page, so after reopening the tree the sequence resumes at `limit`, skipping the ones
+/// that weren't handed out but never reusing any that were.
+#[derive(Debug, Clone, Copy)]
+pub(super) struct Sequence {
+    next: u64,
+    limit: u64,
+}
+
+impl<K, V, PageFetcher> super::BTree<K, V, PageFetcher>
+where
+    K: Key,
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Returns the tree's next sequence value, starting from 0. Values are reserved in batches,
+    /// so a write to the metadata page is only needed every so often.
+    pub fn next_sequence(&mut self) -> Result<u64> {
+        let mut sequence = match self.sequence {
+            Some(sequence) => sequence,
+            None => {
+                let limit = self.metadata_read()?.sequence_limit()?.unwrap_or(0);
+                Sequence { next: limit, limit }
+            }
+        };
+
+        if sequence.next == sequence.limit {
+            sequence.limit = sequence
+                .next
+                .checked_add(SEQUENCE_CACHE)
+                .ok_or(Error::SequenceExhausted)?;
+            self.metadata_write()?.set_sequence_limit(sequence.limit)?;
+        }
+        let value = sequence.next;
+        sequence.next += 1;
+        self.sequence = Some(sequence);
+
+        Ok(value)
+    }
+
+    /// Inserts `value` under the next key from the tree's sequence, returning the key. The first
+    /// call on a tree that has never used its sequence starts right after its largest key, and
+    /// keys that were since inserted by hand are skipped.
+    pub fn insert_auto(&mut self, value: V) -> Result<K>
+    where
+        K: SequenceKey,
+    {
+        if self.sequence.is_none() && self.metadata_read()?.sequence_limit()?.is_none() {
+            let start = match self.range(..)?.last().transpose()? {
+                Some((key, _)) => key
+                    .to_sequence()
+                    .checked_add(1)
+                    .ok_or(Error::SequenceExhausted)?,
+                None => 0,
+            };
+            self.sequence = Some(Sequence {
+                next: start,
+                limit: start,
+            });
+        }
+
+        loop {
+            let key = K::from_sequence(self.next_sequence()?).ok_or(Error::SequenceExhausted)?;
+            if self.insert_if_absent(key.clone(), value.clone())?.is_none() {
+                return Ok(key);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::btree::key::KeyU32;
+    use crate::btree::value::ValueBytes;
+    use crate::btree::BTree;
+    use crate::error::Error;
+    use crate::page_fetcher::InMemoryPageFetcher;
+
+    fn value(n: u32) -> ValueBytes {
+        ValueBytes {
+            value: n.to_be_bytes().to_vec(),
+        }
+    }
+
+    #[test]
+    fn insert_auto_resumes_after_reopen() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(256)).unwrap();
+        btree.insert(KeyU32 { key: 9 }, value(9)).unwrap();
+        for expected in 10..20 {
+            assert_eq!(btree.insert_auto(value(expected)).unwrap().key, expected);
+        }
+        // Keys taken by hand are skipped
+        btree.insert(KeyU32 { key: 20 }, value(20)).unwrap();
+        assert_eq!(btree.insert_auto(value(21)).unwrap().key, 21);
+
+        // Reopening, as after a crash, skips the rest of the reserved batch
+        let mut btree: BTree<KeyU32, ValueBytes, _> =
+            BTree::new(btree.into_page_fetcher()).unwrap();
+        let key = btree.insert_auto(value(0)).unwrap().key;
+        assert!(key > 21, "{}", key);
+        assert_eq!(
+            btree.search(KeyU32 { key: 15 }).unwrap().value,
+            Some(value(15))
+        );
+        assert_eq!(btree.range(..).unwrap().count(), 14);
+    }
+
+    #[test]
+    fn sequence_exhausted() {
+        let mut btree = BTree::new(InMemoryPageFetcher::new()).unwrap();
+        btree
+            .insert(KeyU32 { key: u32::MAX - 1 }, value(0))
+            .unwrap();
+        assert_eq!(btree.insert_auto(value(1)).unwrap().key, u32::MAX);
+        assert!(matches!(
+            btree.insert_auto(value(1)),
+            Err(Error::SequenceExhausted)
+        ));
+    }
+}
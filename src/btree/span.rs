@@ -0,0 +1,164 @@
+//! Per-operation `tracing` spans for `insert` and `search`, enabled by the `tracing` feature. Each
+//! span is a `debug` span under the `johndb::span` target, opened in whatever span the caller is
+//! in, so it nests under the embedder's own request spans. It carries the operation, a digest of
+//! the key, and once the operation finishes, how many pages were visited, how many splits were
+//! performed and how long it took.
+//!
+//! Pages visited and splits are read off the fetcher's `Metrics`, so spans of operations running
+//! concurrently on the same tree may count each other's work.
+
+#[cfg(feature = "tracing")]
+use super::export::item_to_bytes;
+use super::key::Key;
+#[cfg(feature = "tracing")]
+use crate::checksum::crc32;
+use crate::metrics::Metrics;
+#[cfg(feature = "tracing")]
+use std::time::Instant;
+#[cfg(feature = "tracing")]
+use tracing::field::Empty;
+#[cfg(feature = "tracing")]
+use tracing::span::EnteredSpan;
+
+#[cfg(feature = "tracing")]
+pub(super) struct OpSpan<'a> {
+    span: EnteredSpan,
+    metrics: &'a Metrics,
+    start: Instant,
+    pool_hits: u64,
+    splits: u64,
+}
+
+#[cfg(feature = "tracing")]
+impl<'a> OpSpan<'a> {
+    pub(super) fn enter<K: Key>(op: &'static str, key: &K, metrics: &'a Metrics) -> Self {
+        let key_digest = crc32(&item_to_bytes(key));
+        let span = tracing::debug_span!(
+            target: "johndb::span",
+            "btree",
+            op,
+            key_digest = tracing::field::display(format_args!("{:08x}", key_digest)),
+            pages_visited = Empty,
+            splits = Empty,
+            latency_us = Empty,
+        );
+        OpSpan {
+            span: span.entered(),
+            metrics,
+            start: Instant::now(),
+            pool_hits: metrics.pool_hits.get(),
+            splits: splits(metrics),
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+impl Drop for OpSpan<'_> {
+    fn drop(&mut self) {
+        let pages_visited = self.metrics.pool_hits.get() - self.pool_hits;
+        self.span.record("pages_visited", pages_visited);
+        self.span
+            .record("splits", splits(self.metrics) - self.splits);
+        self.span
+            .record("latency_us", self.start.elapsed().as_micros() as u64);
+    }
+}
+
+#[cfg(feature = "tracing")]
+fn splits(metrics: &Metrics) -> u64 {
+    metrics.leaf_splits.get() + metrics.internal_splits.get()
+}
+
+/// Does nothing, so that call sites don't need to care whether the feature is enabled.
+#[cfg(not(feature = "tracing"))]
+pub(super) struct OpSpan;
+
+#[cfg(not(feature = "tracing"))]
+impl OpSpan {
+    pub(super) fn enter<K: Key>(_op: &'static str, _key: &K, _metrics: &Metrics) -> Self {
+        OpSpan
+    }
+}
+
+#[cfg(all(test, feature = "tracing"))]
+mod tests {
+    use crate::btree::key::KeyU64;
+    use crate::btree::value::ValueTupleId;
+    use crate::btree::BTree;
+    use crate::page_fetcher::InMemoryPageFetcher;
+    use std::fmt::Debug;
+    use std::sync::Mutex;
+    use tracing::field::Field;
+    use tracing::field::Visit;
+    use tracing::span::Attributes;
+    use tracing::span::Id;
+    use tracing::span::Record;
+    use tracing::Event;
+    use tracing::Metadata;
+    use tracing::Subscriber;
+
+    /// Records the fields of every span as `name=value` strings, one list per span.
+    #[derive(Default)]
+    struct FieldRecorder {
+        spans: Mutex<Vec<Vec<String>>>,
+    }
+
+    struct Fields<'a>(&'a mut Vec<String>);
+
+    impl Visit for Fields<'_> {
+        fn record_debug(&mut self, field: &Field, value: &dyn Debug) {
+            self.0.push(format!("{}={:?}", field.name(), value));
+        }
+    }
+
+    impl Subscriber for &'static FieldRecorder {
+        fn enabled(&self, _metadata: &Metadata<'_>) -> bool {
+            true
+        }
+
+        fn new_span(&self, span: &Attributes<'_>) -> Id {
+            let mut spans = self.spans.lock().unwrap();
+            let mut fields = Vec::new();
+            span.record(&mut Fields(&mut fields));
+            spans.push(fields);
+            Id::from_u64(spans.len() as u64)
+        }
+
+        fn record(&self, span: &Id, values: &Record<'_>) {
+            let mut spans = self.spans.lock().unwrap();
+            values.record(&mut Fields(&mut spans[span.into_u64() as usize - 1]));
+        }
+
+        fn record_follows_from(&self, _span: &Id, _follows: &Id) {}
+
+        fn event(&self, _event: &Event<'_>) {}
+
+        fn enter(&self, _span: &Id) {}
+
+        fn exit(&self, _span: &Id) {}
+    }
+
+    #[test]
+    fn operations_open_spans() {
+        let recorder: &'static FieldRecorder = Box::leak(Box::default());
+        let value = ValueTupleId {
+            page_no: 1,
+            offset: 1,
+        };
+        tracing::subscriber::with_default(recorder, || {
+            let mut btree = BTree::new(InMemoryPageFetcher::new()).unwrap();
+            btree.insert(KeyU64 { key: 7 }, value).unwrap();
+            btree.search(KeyU64 { key: 7 }).unwrap();
+        });
+
+        let spans = recorder.spans.lock().unwrap();
+        assert_eq!(spans.len(), 2, "{:?}", spans);
+        for (span, op) in spans.iter().zip(["insert", "search"]) {
+            assert_eq!(span[0], format!("op={:?}", op));
+            assert!(span[1].starts_with("key_digest="), "{:?}", span);
+            for field in ["pages_visited=", "splits=0", "latency_us="] {
+                assert!(span.iter().any(|f| f.starts_with(field)), "{:?}", span);
+            }
+        }
+    }
+}
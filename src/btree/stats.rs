@@ -0,0 +1,331 @@
+//! Statistics over a sample of a tree's entries, for estimating how many entries a predicate
+//! matches without reading them. `BTree::collect_stats` reads every `n`th leaf and stores what it
+//! found in a chain of overflow pages linked from the tree's metadata, where `BTree::key_stats`
+//! finds it again.
+
+use super::export::item_from_bytes;
+use super::export::item_to_bytes;
+use super::key::Key;
+use super::leaf_node::LeafNodeItemData;
+use super::metadata_node::MetadataRead;
+use super::node::NodeRead;
+use super::value::Value;
+use crate::error::Error;
+use crate::error::Result;
+use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use crate::planner::Histogram;
+use log::debug;
+use std::convert::TryInto;
+use std::mem::size_of;
+
+/// Buckets of the histogram over the sampled keys.
+const HISTOGRAM_BUCKETS: usize = 100;
+
+/// A sample in which at least this fraction of the values is distinct is taken to come from a
+/// column of mostly distinct values, whose distinct count grows with the number of entries.
+const DISTINCT_SCALE_FRACTION: f64 = 0.9;
+
+/// Bits of the hash picking a HyperLogLog register, for 4096 registers and a standard error of
+/// about 1.6%.
+const HLL_PRECISION: u32 = 12;
+
+/// Estimates of a tree's contents, see `BTree::collect_stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct KeyStats<K> {
+    pub entry_cnt: u64,
+    pub distinct_key_cnt: u64,
+    pub distinct_value_cnt: u64,
+    /// Entries the estimates were made from.
+    pub sampled_cnt: u64,
+    /// An equi-depth histogram over the sampled keys.
+    pub histogram: Histogram<K>,
+}
+
+impl<K: Key> KeyStats<K> {
+    fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        for cnt in [
+            self.entry_cnt,
+            self.distinct_key_cnt,
+            self.distinct_value_cnt,
+            self.sampled_cnt,
+        ] {
+            bytes.extend_from_slice(&cnt.to_le_bytes());
+        }
+        bytes.extend_from_slice(&(self.histogram.bounds.len() as u32).to_le_bytes());
+        for bound in &self.histogram.bounds {
+            let bound = item_to_bytes(bound);
+            bytes.extend_from_slice(&(bound.len() as u32).to_le_bytes());
+            bytes.extend_from_slice(&bound);
+        }
+        bytes
+    }
+
+    fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let mut cnts = [0u64; 4];
+        for cnt in cnts.iter_mut() {
+            *cnt = u64::from_le_bytes(take(&mut bytes, size_of::<u64>())?.try_into().unwrap());
+        }
+        let bound_cnt = read_u32(&mut bytes)?;
+        let mut bounds = Vec::new();
+        for _ in 0..bound_cnt {
+            let size = read_u32(&mut bytes)? as usize;
+            bounds.push(item_from_bytes::<K>(take(&mut bytes, size)?)?);
+        }
+        if !bytes.is_empty() {
+            return Err(Error::corruption(format!(
+                "{} bytes left after the statistics",
+                bytes.len()
+            )));
+        }
+        let [entry_cnt, distinct_key_cnt, distinct_value_cnt, sampled_cnt] = cnts;
+        Ok(KeyStats {
+            entry_cnt,
+            distinct_key_cnt,
+            distinct_value_cnt,
+            sampled_cnt,
+            histogram: Histogram { bounds },
+        })
+    }
+}
+
+fn take<'a>(bytes: &mut &'a [u8], size: usize) -> Result<&'a [u8]> {
+    if bytes.len() < size {
+        return Err(Error::corruption("statistics are truncated".to_string()));
+    }
+    let (taken, rest) = bytes.split_at(size);
+    *bytes = rest;
+    Ok(taken)
+}
+
+fn read_u32(bytes: &mut &[u8]) -> Result<u32> {
+    Ok(u32::from_le_bytes(
+        take(bytes, size_of::<u32>())?.try_into().unwrap(),
+    ))
+}
+
+impl<K, V, PageFetcher> super::BTree<K, V, PageFetcher>
+where
+    K: Key,
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Estimates the tree's entry count, its distinct keys and values and the distribution of
+    /// its keys from about `sample_rate` of its leaves, and stores the result for `key_stats`,
+    /// replacing what was stored before. A rate of 1 reads every leaf, which makes the counts
+    /// exact up to the distinct counts' HyperLogLog error.
+    ///
+    /// Every leaf's header is still read to follow the sibling links, but only the sampled ones
+    /// have their items decoded.
+    pub fn collect_stats(&self, sample_rate: f64) -> Result<KeyStats<K>> {
+        let stats = self
+            .sample_stats(sample_rate)
+            .map_err(|err| err.context("collect_stats"))?;
+
+        let stats_no = self.write_overflow(&stats.to_bytes())?;
+        let old_no = {
+            let mut metadata = self.metadata_write()?;
+            let old_no = metadata.stats_no()?;
+            metadata.set_stats_no(stats_no)?;
+            old_no
+        };
+        if let Some(old_no) = old_no {
+            self.free_overflow(old_no)?;
+        }
+        Ok(stats)
+    }
+
+    /// The statistics last stored by `collect_stats`, if any.
+    pub fn key_stats(&self) -> Result<Option<KeyStats<K>>> {
+        let stats_no = self.metadata_read()?.stats_no()?;
+        stats_no
+            .map(|stats_no| {
+                KeyStats::from_bytes(&self.read_overflow(stats_no)?)
+                    .map_err(|err| err.on_page(stats_no))
+            })
+            .transpose()
+    }
+
+    fn sample_stats(&self, sample_rate: f64) -> Result<KeyStats<K>> {
+        let step = match sample_rate > 0.0 {
+            true => (1.0 / sample_rate.min(1.0)).round() as usize,
+            false => usize::MAX,
+        };
+        let mut keys = Vec::new();
+        let mut distinct_keys = HyperLogLog::new();
+        let mut distinct_values = HyperLogLog::new();
+        let mut leaf_cnt = 0;
+        let mut sampled_leaf_cnt = 0;
+
+        let mut next_leaf_no = self.find_leaf_no(None)?;
+        while let Some(leaf_no) = next_leaf_no {
+            let leaf = super::leaf_node::fetch_page_read::<PageFetcher, K, V>(
+                &self.page_fetcher,
+                leaf_no,
+            )?;
+            if leaf_cnt % step == 0 {
+                let mut entries = leaf
+                    .page_ref()
+                    .item_refs_from::<LeafNodeItemData<K, V>>(1)
+                    .map(|item| (item.key(), item.value_bytes()))
+                    .collect::<Vec<_>>();
+                if !leaf.special_data().is_sorted() {
+                    entries.sort_by(|x, y| x.0.cmp(&y.0));
+                }
+                for (key, value) in entries {
+                    distinct_keys.insert(&item_to_bytes(&key));
+                    distinct_values.insert(value);
+                    keys.push(key);
+                }
+                sampled_leaf_cnt += 1;
+            }
+            leaf_cnt += 1;
+            next_leaf_no = leaf.special_data().right_sibling().get();
+        }
+
+        let sampled_cnt = keys.len() as u64;
+        let entry_cnt = match sampled_leaf_cnt {
+            0 => 0,
+            _ => (sampled_cnt as f64 * leaf_cnt as f64 / sampled_leaf_cnt as f64).round() as u64,
+        };
+        let stats = KeyStats {
+            entry_cnt,
+            distinct_key_cnt: scale_distinct(distinct_keys.estimate(), sampled_cnt, entry_cnt),
+            distinct_value_cnt: scale_distinct(distinct_values.estimate(), sampled_cnt, entry_cnt),
+            sampled_cnt,
+            histogram: Histogram::from_sorted(keys, HISTOGRAM_BUCKETS),
+        };
+        debug!(
+            "[collect_stats] Sampled {} of {} leaves: {} entries, {} distinct keys",
+            sampled_leaf_cnt, leaf_cnt, stats.entry_cnt, stats.distinct_key_cnt
+        );
+        Ok(stats)
+    }
+}
+
+/// Extrapolates the `distinct` values found among `sampled_cnt` entries to all `entry_cnt` of
+/// them. Mostly distinct samples are scaled up in proportion, while samples with many repeats are
+/// assumed to have seen most of the values there are already.
+fn scale_distinct(distinct: f64, sampled_cnt: u64, entry_cnt: u64) -> u64 {
+    if sampled_cnt == 0 {
+        return 0;
+    }
+    let distinct = distinct.min(sampled_cnt as f64);
+    let estimate = match distinct / sampled_cnt as f64 >= DISTINCT_SCALE_FRACTION {
+        true => distinct * entry_cnt as f64 / sampled_cnt as f64,
+        false => distinct,
+    };
+    (estimate.round() as u64).clamp(1, entry_cnt.max(1))
+}
+
+/// Counts distinct byte strings in constant space: each string's hash goes to one of the
+/// registers, which keeps the longest run of leading zeros seen among the rest of the hashes.
+struct HyperLogLog {
+    registers: Vec<u8>,
+}
+
+impl HyperLogLog {
+    fn new() -> Self {
+        HyperLogLog {
+            registers: vec![0; 1 << HLL_PRECISION],
+        }
+    }
+
+    fn insert(&mut self, bytes: &[u8]) {
+        let hash = hash64(bytes);
+        let idx = (hash >> (64 - HLL_PRECISION)) as usize;
+        // The sentinel bit caps the run at the bits left after the register index
+        let rest = (hash << HLL_PRECISION) | (1 << (HLL_PRECISION - 1));
+        let rank = rest.leading_zeros() as u8 + 1;
+        self.registers[idx] = self.registers[idx].max(rank);
+    }
+
+    fn estimate(&self) -> f64 {
+        let m = self.registers.len() as f64;
+        let alpha = 0.7213 / (1.0 + 1.079 / m);
+        let sum = self
+            .registers
+            .iter()
+            .map(|rank| 2f64.powi(-(*rank as i32)))
+            .sum::<f64>();
+        let raw = alpha * m * m / sum;
+        // Small counts leave registers empty, and are better estimated from how many
+        let empty = self.registers.iter().filter(|rank| **rank == 0).count();
+        match raw <= 2.5 * m && empty > 0 {
+            true => m * (m / empty as f64).ln(),
+            false => raw,
+        }
+    }
+}
+
+/// FNV-1a followed by a 64-bit finalizer, since HyperLogLog needs every bit of the hash to be
+/// well mixed.
+fn hash64(bytes: &[u8]) -> u64 {
+    let mut hash = bytes.iter().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ *byte as u64).wrapping_mul(0x100000001b3)
+    });
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xff51afd7ed558ccd);
+    hash ^= hash >> 33;
+    hash = hash.wrapping_mul(0xc4ceb9fe1a85ec53);
+    hash ^ (hash >> 33)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::HyperLogLog;
+    use crate::btree::key::KeyU32;
+    use crate::btree::value::ValueTupleId;
+    use crate::btree::BTree;
+    use crate::page_fetcher::InMemoryPageFetcher;
+
+    #[test]
+    fn hyperloglog_estimates() {
+        let mut hll = HyperLogLog::new();
+        assert_eq!(hll.estimate(), 0.0);
+        for i in 0..100_000u32 {
+            hll.insert(&i.to_be_bytes());
+            hll.insert(&i.to_be_bytes());
+        }
+        let estimate = hll.estimate();
+        assert!((95_000.0..105_000.0).contains(&estimate), "{}", estimate);
+    }
+
+    #[test]
+    fn sampled_and_stored() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(256)).unwrap();
+        assert_eq!(btree.key_stats().unwrap(), None);
+        for key in 0..20_000u32 {
+            let value = ValueTupleId {
+                page_no: key % 10,
+                offset: 0,
+            };
+            btree.insert(KeyU32 { key }, value).unwrap();
+        }
+
+        let exact = btree.collect_stats(1.0).unwrap();
+        assert_eq!(exact.entry_cnt, 20_000);
+        assert_eq!(exact.sampled_cnt, 20_000);
+        assert_eq!(exact.distinct_value_cnt, 10);
+        let distinct = exact.distinct_key_cnt as f64;
+        assert!((19_000.0..21_000.0).contains(&distinct), "{}", distinct);
+        assert_eq!(exact.histogram.bounds.len(), 100);
+        assert_eq!(exact.histogram.bounds[49], KeyU32 { key: 9_999 });
+
+        let sampled = btree.collect_stats(0.1).unwrap();
+        assert!(sampled.sampled_cnt < 4_000, "{}", sampled.sampled_cnt);
+        let entries = sampled.entry_cnt as f64;
+        assert!((16_000.0..24_000.0).contains(&entries), "{}", entries);
+        assert_eq!(sampled.distinct_value_cnt, 10);
+        let half = sampled
+            .histogram
+            .range_selectivity(&(KeyU32 { key: 10_000 }..));
+        assert!((0.4..0.6).contains(&half), "{}", half);
+
+        // The old statistics' page went back to the free list
+        assert_eq!(btree.page_fetcher().free_page_cnt(), 1);
+        let btree: BTree<KeyU32, ValueTupleId, _> =
+            BTree::open(btree.into_page_fetcher(), 0).unwrap();
+        assert_eq!(btree.key_stats().unwrap(), Some(sampled));
+    }
+}
@@ -0,0 +1,246 @@
+//! Timestamp keys and helpers for trees holding time series. Readings inserted as they're taken
+//! all land in the rightmost leaf, which sequential splits leave full, see
+//! `BTreePageData::is_sequential`, so such trees stay dense and recent readings stay together for
+//! range scans. `bucket_aggregate` rolls readings up per interval, and `delete_older_than`
+//! enforces a retention period.
+
+use super::aggregate::Accumulator;
+use super::aggregate::AggFn;
+use super::key::Key;
+use super::key::KeyI64;
+use super::value::Value;
+use crate::error::Result;
+use crate::page::Item;
+use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use std::borrow::Cow;
+use std::convert::TryFrom;
+use std::ops::RangeBounds;
+use std::time::Duration;
+use std::time::SystemTime;
+use std::time::UNIX_EPOCH;
+
+/// Nanoseconds since the Unix epoch, which covers the years 1677 to 2262. Stored like `KeyI64`.
+#[derive(Debug, PartialOrd, Ord, PartialEq, Eq, Copy, Clone, Hash)]
+pub struct KeyTimestamp {
+    pub key: i64,
+}
+
+/// Durations too long to fit saturate at `i64::MAX` nanoseconds, about 292 years.
+fn duration_nanos(duration: Duration) -> i64 {
+    i64::try_from(duration.as_nanos()).unwrap_or(i64::MAX)
+}
+
+impl KeyTimestamp {
+    pub fn now() -> Self {
+        Self::from(SystemTime::now())
+    }
+
+    pub fn to_system_time(&self) -> SystemTime {
+        match self.key >= 0 {
+            true => UNIX_EPOCH + Duration::from_nanos(self.key as u64),
+            false => UNIX_EPOCH - Duration::from_nanos(self.key.unsigned_abs()),
+        }
+    }
+
+    pub fn saturating_sub(&self, duration: Duration) -> Self {
+        Self {
+            key: self.key.saturating_sub(duration_nanos(duration)),
+        }
+    }
+
+    /// The start of the `width` long interval the timestamp falls in. Intervals are aligned to
+    /// the epoch, so e.g. day long ones start at midnight UTC.
+    pub fn bucket(&self, width: Duration) -> Self {
+        let width = duration_nanos(width);
+        assert!(width > 0, "bucket width must be at least a nanosecond");
+        Self {
+            key: self.key - self.key.rem_euclid(width),
+        }
+    }
+}
+
+impl From<i64> for KeyTimestamp {
+    fn from(key: i64) -> Self {
+        Self { key }
+    }
+}
+
+/// Times outside the representable range saturate.
+impl From<SystemTime> for KeyTimestamp {
+    fn from(time: SystemTime) -> Self {
+        let key = match time.duration_since(UNIX_EPOCH) {
+            Ok(since) => duration_nanos(since),
+            Err(err) => duration_nanos(err.duration()).saturating_neg(),
+        };
+        Self { key }
+    }
+}
+
+impl Key for KeyTimestamp {
+    fn max_key() -> Self {
+        Self { key: i64::MAX }
+    }
+
+    fn type_name() -> Cow<'static, str> {
+        Cow::Borrowed("johndb::btree::time_series::KeyTimestamp")
+    }
+}
+
+impl Item for KeyTimestamp {
+    fn size(&self) -> usize {
+        KeyI64 { key: self.key }.size()
+    }
+
+    fn align() -> usize {
+        KeyI64::align()
+    }
+
+    fn is_fixed_size() -> bool {
+        true
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        KeyI64 { key: self.key }.write(buffer)
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        Self {
+            key: KeyI64::read(bytes).key,
+        }
+    }
+}
+
+impl<V, PageFetcher> super::BTree<KeyTimestamp, V, PageFetcher>
+where
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Computes `agg` separately for each `width` long interval within `range`, see `aggregate`
+    /// and `KeyTimestamp::bucket`. Returns the start of each interval with its result, in order,
+    /// leaving out intervals without any values.
+    pub fn bucket_aggregate<R, F>(
+        &self,
+        range: R,
+        width: Duration,
+        agg: AggFn,
+        mut extract: F,
+    ) -> Result<Vec<(KeyTimestamp, i128)>>
+    where
+        R: RangeBounds<KeyTimestamp>,
+        F: FnMut(&KeyTimestamp, &[u8]) -> Option<i64>,
+    {
+        let mut buckets = Vec::new();
+        let mut current: Option<(KeyTimestamp, Accumulator)> = None;
+        self.range_visit(range, |entry| {
+            let bucket = entry.key().bucket(width);
+            match &mut current {
+                Some((start, _)) if *start == bucket => {}
+                _ => {
+                    let done = current.replace((bucket, Accumulator::new(agg)));
+                    buckets.extend(done.and_then(|(start, acc)| Some((start, acc.finish()?))));
+                }
+            }
+            if let Some(value) = extract(entry.key(), entry.value_bytes()) {
+                current.as_mut().unwrap().1.add(value);
+            }
+            true
+        })?;
+        buckets.extend(current.and_then(|(start, acc)| Some((start, acc.finish()?))));
+
+        // Count is 0 rather than `None` for intervals whose entries had no values
+        buckets.retain(|(_, result)| agg != AggFn::Count || *result > 0);
+        Ok(buckets)
+    }
+
+    /// Removes every entry more than `max_age` older than `now`, returning how many there were.
+    /// See `delete_range`.
+    pub fn delete_older_than(&mut self, max_age: Duration, now: KeyTimestamp) -> Result<u64> {
+        self.delete_range(..now.saturating_sub(max_age))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::KeyTimestamp;
+    use crate::btree::aggregate::AggFn;
+    use crate::btree::value::ValueBytes;
+    use crate::btree::BTree;
+    use crate::page::Item;
+    use crate::page_fetcher::InMemoryPageFetcher;
+    use std::convert::TryInto;
+    use std::time::Duration;
+    use std::time::UNIX_EPOCH;
+
+    const MINUTE: i64 = 60_000_000_000;
+
+    #[test]
+    fn timestamps() {
+        let hour = Duration::from_secs(3600);
+        assert_eq!(
+            KeyTimestamp::from(90 * MINUTE).bucket(hour).key,
+            60 * MINUTE
+        );
+        assert_eq!(KeyTimestamp::from(-MINUTE).bucket(hour).key, -60 * MINUTE);
+
+        for key in [-MINUTE - 1, 0, 1_700_000_000_123_456_789] {
+            let timestamp = KeyTimestamp::from(key);
+            assert_eq!(KeyTimestamp::from(timestamp.to_system_time()), timestamp);
+        }
+        let before_epoch = UNIX_EPOCH - Duration::from_secs(1);
+        assert_eq!(KeyTimestamp::from(before_epoch).key, -1_000_000_000);
+
+        let (mut earlier, mut later) = ([0u8; 8], [0u8; 8]);
+        KeyTimestamp::from(-1).write(&mut earlier);
+        KeyTimestamp::from(1).write(&mut later);
+        assert!(earlier < later);
+        assert_eq!(KeyTimestamp::read(&later), KeyTimestamp::from(1));
+    }
+
+    #[test]
+    fn buckets_and_retention() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(64)).unwrap();
+        // A reading every minute for a day, of the minute within the hour
+        for minute in 0..24 * 60i64 {
+            let value = (minute % 60).to_be_bytes().to_vec();
+            btree
+                .insert(KeyTimestamp::from(minute * MINUTE), ValueBytes { value })
+                .unwrap();
+        }
+        let reading =
+            |_: &KeyTimestamp, value: &[u8]| Some(i64::from_be_bytes(value.try_into().unwrap()));
+        let hour = Duration::from_secs(3600);
+
+        let sums = btree
+            .bucket_aggregate(.., hour, AggFn::Sum, reading)
+            .unwrap();
+        assert_eq!(sums.len(), 24);
+        assert!(sums.iter().all(|(_, sum)| *sum == (0..60).sum::<i128>()));
+        assert_eq!(sums[3].0, KeyTimestamp::from(180 * MINUTE));
+
+        let range = KeyTimestamp::from(90 * MINUTE)..KeyTimestamp::from(180 * MINUTE);
+        let maxes = btree
+            .bucket_aggregate(range.clone(), hour, AggFn::Max, reading)
+            .unwrap();
+        assert_eq!(
+            maxes,
+            vec![
+                (KeyTimestamp::from(60 * MINUTE), 59),
+                (KeyTimestamp::from(120 * MINUTE), 59)
+            ]
+        );
+        let odd = |key: &KeyTimestamp, value: &[u8]| reading(key, value).filter(|m| m % 2 == 1);
+        let counts = btree
+            .bucket_aggregate(range, Duration::from_secs(120), AggFn::Count, odd)
+            .unwrap();
+        assert_eq!(counts.len(), 45);
+        assert!(counts.iter().all(|(_, cnt)| *cnt == 1));
+
+        // Keep the last 6 hours
+        let now = KeyTimestamp::from(24 * 60 * MINUTE);
+        let removed = btree.delete_older_than(6 * hour, now).unwrap();
+        assert_eq!(removed, 18 * 60);
+        let first = btree.range(..).unwrap().next().unwrap().unwrap().0;
+        assert_eq!(first, KeyTimestamp::from(18 * 60 * MINUTE));
+        btree.verify().unwrap();
+    }
+}
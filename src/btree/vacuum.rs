@@ -0,0 +1,638 @@
+use super::heap::vacuum_heap_page;
+use super::heap::TxnId;
+use super::internal_node;
+use super::internal_node::InternalNodeRead;
+use super::internal_node::InternalNodeWriteLock;
+use super::key::Key;
+use super::leaf_node;
+use super::metadata_node::MetadataRead;
+use super::node::NodeItem;
+use super::node::NodeRead;
+use super::node::NodeWrite;
+use super::node::Separator;
+use super::value::Value;
+use super::BTreePageData;
+use super::NodeType;
+use crate::error::Error;
+use crate::error::Result;
+use crate::page::Page;
+use crate::page::PageNo;
+use crate::page::ITEM_POINTER_SIZE;
+use crate::page::PAGE_DATA_SIZE;
+use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use log::debug;
+use std::cell::Cell;
+use std::cell::OnceCell;
+use std::mem::size_of;
+
+/// Nodes are compacted once this many bytes of their item data are dead.
+const COMPACT_DEAD_SPACE: usize = PAGE_DATA_SIZE / 16;
+
+/// Siblings are merged if their live items fit in this fraction of a page, which leaves the merged
+/// node some room for inserts before it has to split again.
+const MERGE_FILL: f64 = 0.75;
+
+/// Counts of the work a vacuum has done so far.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct VacuumStats {
+    pub pages_scanned: usize,
+    /// Pages rebuilt to reclaim the space left behind by removed items.
+    pub pages_compacted: usize,
+    /// Nodes merged into their left sibling.
+    pub nodes_merged: usize,
+    /// Pages returned to the page fetcher's free list.
+    pub pages_freed: usize,
+    /// Heap tuples shrunk down to their header.
+    pub tuples_removed: usize,
+    /// Tombstones left behind by `BTree::delete_logically`.
+    pub tombstones_removed: usize,
+    /// Index entries newly hinted to point at dead heap tuples, see
+    /// `ValueTupleId::is_hinted_dead`.
+    pub tuples_hinted: usize,
+}
+
+impl VacuumStats {
+    pub(crate) fn add(&mut self, other: &VacuumStats) {
+        self.pages_scanned += other.pages_scanned;
+        self.pages_compacted += other.pages_compacted;
+        self.nodes_merged += other.nodes_merged;
+        self.pages_freed += other.pages_freed;
+        self.tuples_removed += other.tuples_removed;
+        self.tombstones_removed += other.tombstones_removed;
+        self.tuples_hinted += other.tuples_hinted;
+    }
+}
+
+/// A vacuum run over a tree, advanced by `BTree::vacuum_step` a bounded number of pages at a time
+/// so that it can be interleaved with other operations.
+///
+/// Each internal level is walked left to right, starting with the one right above the leaves.
+/// Leaves lose their tombstones, see `Value::tombstone`, children with enough dead space are
+/// compacted, and a child is merged into its left sibling if
+/// both share the parent and fit in one page. Roots left with a single child are then collapsed,
+/// and finally heap pages are vacuumed if a horizon was given. With a horizon, leaf entries
+/// pointing at tuples dead below it are also hinted as such on the way, see
+/// `ValueTupleId::is_hinted_dead`.
+pub struct Vacuum {
+    horizon: Option<TxnId>,
+    phase: Phase,
+    stats: VacuumStats,
+    /// The tree's dead item count when the vacuum started, taken off it once the vacuum is done.
+    dead_item_cnt: Option<u64>,
+}
+
+enum Phase {
+    Start,
+    /// `levels` holds the leftmost node of each internal level still to do, the topmost first.
+    Levels {
+        levels: Vec<u32>,
+        next_parent_no: Option<u32>,
+    },
+    Root,
+    Heap {
+        horizon: TxnId,
+        next_page_no: u32,
+    },
+    Done,
+}
+
+impl Vacuum {
+    /// Vacuums the tree's nodes, leaving heap pages alone.
+    pub fn new() -> Self {
+        Vacuum {
+            horizon: None,
+            phase: Phase::Start,
+            stats: VacuumStats::default(),
+            dead_item_cnt: None,
+        }
+    }
+
+    /// Also removes heap tuples deleted by a transaction below `horizon`, see
+    /// `BTree::vacuum_tuple`. Heap pages aren't linked to a tree, so this covers the heap pages of
+    /// every tree sharing the page fetcher.
+    pub fn with_heap(horizon: TxnId) -> Self {
+        Vacuum {
+            horizon: Some(horizon),
+            ..Self::new()
+        }
+    }
+
+    pub fn stats(&self) -> VacuumStats {
+        self.stats
+    }
+
+    pub fn is_done(&self) -> bool {
+        matches!(self.phase, Phase::Done)
+    }
+}
+
+impl Default for Vacuum {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<K, V, PageFetcher> super::BTree<K, V, PageFetcher>
+where
+    K: Key,
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Runs `vacuum` to completion, returning what it did.
+    pub fn vacuum(&mut self, mut vacuum: Vacuum) -> Result<VacuumStats> {
+        self.vacuum_step(&mut vacuum, usize::MAX)?;
+        Ok(vacuum.stats())
+    }
+
+    /// Advances `vacuum` until it has scanned about `max_pages` more pages, returning whether
+    /// it's done. Internal nodes are processed whole, so a step may scan up to one node's children
+    /// past `max_pages`. A vacuum that fails is over, and has to be started again.
+    pub fn vacuum_step(&mut self, vacuum: &mut Vacuum, max_pages: usize) -> Result<bool> {
+        self.vacuum_step_inner(vacuum, max_pages.max(1))
+            .map_err(|err| err.context("vacuum"))
+    }
+
+    fn vacuum_step_inner(&mut self, vacuum: &mut Vacuum, max_pages: usize) -> Result<bool> {
+        let start = vacuum.stats.pages_scanned;
+        while !vacuum.is_done() && vacuum.stats.pages_scanned - start < max_pages {
+            let stats = &mut vacuum.stats;
+            vacuum.phase = match std::mem::replace(&mut vacuum.phase, Phase::Done) {
+                Phase::Start => {
+                    vacuum.dead_item_cnt = Some(self.dead_item_cnt()?);
+                    Phase::Levels {
+                        levels: self.internal_levels()?,
+                        next_parent_no: None,
+                    }
+                }
+                Phase::Levels {
+                    mut levels,
+                    next_parent_no: None,
+                } => match levels.pop() {
+                    Some(next_parent_no) => Phase::Levels {
+                        levels,
+                        next_parent_no: Some(next_parent_no),
+                    },
+                    None => Phase::Root,
+                },
+                Phase::Levels {
+                    levels,
+                    next_parent_no: Some(next_parent_no),
+                } => Phase::Levels {
+                    levels,
+                    next_parent_no: self.vacuum_parent(next_parent_no, vacuum.horizon, stats)?,
+                },
+                Phase::Root => {
+                    self.collapse_root(vacuum.horizon, stats)?;
+                    match vacuum.horizon {
+                        Some(horizon) => Phase::Heap {
+                            horizon,
+                            next_page_no: 0,
+                        },
+                        None => Phase::Done,
+                    }
+                }
+                Phase::Heap {
+                    horizon,
+                    next_page_no,
+                } => match next_page_no as usize >= self.page_fetcher.page_cnt() {
+                    true => Phase::Done,
+                    false => {
+                        self.vacuum_heap(next_page_no, horizon, stats)?;
+                        Phase::Heap {
+                            horizon,
+                            next_page_no: next_page_no + 1,
+                        }
+                    }
+                },
+                Phase::Done => Phase::Done,
+            };
+        }
+
+        // Items deleted while the vacuum ran may have been missed, so they're still counted
+        match (vacuum.is_done(), vacuum.dead_item_cnt) {
+            (true, Some(vacuumed_cnt)) if vacuumed_cnt > 0 => {
+                vacuum.dead_item_cnt = None;
+                let mut metadata = self.metadata_write()?;
+                let cnt = metadata.dead_item_cnt()?;
+                metadata.set_dead_item_cnt(cnt.saturating_sub(vacuumed_cnt))?;
+            }
+            _ => {}
+        }
+
+        debug!("[vacuum_step] {:?}", vacuum.stats);
+        Ok(vacuum.is_done())
+    }
+
+    /// The leftmost node of each internal level, the root's first.
+    fn internal_levels(&self) -> Result<Vec<u32>> {
+        let mut levels = Vec::new();
+        let mut page_no = match self.metadata_read()?.root_no()? {
+            Some(root_no) => root_no,
+            None => return Ok(levels),
+        };
+        while matches!(self.node_type(page_no)?, NodeType::Internal) {
+            levels.push(page_no);
+            let node = internal_node::fetch_page_read::<_, K>(&self.page_fetcher, page_no)?;
+            page_no = node
+                .first_child_ptr()
+                .ok_or_else(|| Error::page_corruption(page_no, "internal node has no downlinks"))?;
+        }
+
+        Ok(levels)
+    }
+
+    fn node_type(&self, page_no: u32) -> Result<NodeType> {
+        let page = self.page_fetcher.fetch_page_read(page_no)?;
+        BTreePageData::checked_node_type(page_no, &page)
+    }
+
+    /// Compacts and merges the children of `parent_no`, returning its right sibling if it has one.
+    /// Leaf entries are hinted against the heap `horizon` if there is one.
+    pub(super) fn vacuum_parent(
+        &self,
+        parent_no: u32,
+        horizon: Option<TxnId>,
+        stats: &mut VacuumStats,
+    ) -> Result<Option<u32>> {
+        let mut parent = internal_node::fetch_page_write::<_, K>(&self.page_fetcher, parent_no)?;
+        stats.pages_scanned += 1;
+        let right_sibling_no = parent.special_data().right_sibling().get();
+        let first_child_no = match parent.first_child_ptr() {
+            Some(first_child_no) => first_child_no,
+            None => return Ok(right_sibling_no),
+        };
+
+        let page_fetcher = &self.page_fetcher;
+        match self.node_type(first_child_no)? {
+            NodeType::Leaf => {
+                let tombstones_removed = Cell::new(0);
+                let tuples_hinted = Cell::new(0);
+                self.vacuum_children(&mut parent, stats, |page_no| {
+                    let mut leaf = leaf_node::fetch_page_write::<_, K, V>(page_fetcher, page_no)?;
+                    if V::tombstone().is_some() {
+                        tombstones_removed.set(tombstones_removed.get() + leaf.remove_tombstones());
+                    }
+                    if let Some(horizon) = horizon {
+                        let hinted = leaf.hint_dead_tuples(|id| self.is_tuple_dead(id, horizon))?;
+                        tuples_hinted.set(tuples_hinted.get() + hinted);
+                    }
+                    Ok(leaf)
+                })?;
+                stats.tombstones_removed += tombstones_removed.get();
+                stats.tuples_hinted += tuples_hinted.get();
+            }
+            _ => self.vacuum_children(&mut parent, stats, |page_no| {
+                internal_node::fetch_page_write::<_, K>(page_fetcher, page_no)
+            })?,
+        }
+
+        Ok(right_sibling_no)
+    }
+
+    /// Walks `parent`'s downlinks in order, compacting each child and merging it into the one
+    /// before it if they're siblings and fit in one page together.
+    fn vacuum_children<I, N, F>(
+        &self,
+        parent: &mut InternalNodeWriteLock<'_, K>,
+        stats: &mut VacuumStats,
+        fetch: F,
+    ) -> Result<()>
+    where
+        I: NodeItem,
+        N: NodeWrite<I>,
+        F: Fn(u32) -> Result<N>,
+    {
+        let mut left = fetch(parent.downlink(1).page_no)?;
+        stats.pages_scanned += 1;
+        compact_node(&mut left, stats)?;
+
+        // `left` is always the child downlink `idx` points at
+        let mut idx = 1;
+        while idx + 1 < parent.page_ref().item_cnt() {
+            let right_no = parent.downlink(idx + 1).page_no;
+            let mut right = fetch(right_no)?;
+            stats.pages_scanned += 1;
+
+            if left.special_data().right_sibling() == PageNo::new(right_no)
+                && merge_nodes(&mut left, &right)?
+            {
+                debug!(
+                    "[vacuum_children ({})] Merged page {} into page {}",
+                    parent.page_no(),
+                    right_no,
+                    left.page_no()
+                );
+                drop(right);
+                // The merged node takes over the right node's downlink, and with it its separator
+                parent.page_ref_mut().remove_item_v2(idx);
+                parent.replace_downlink(right_no, left.page_no())?;
+                self.page_fetcher.free_page(right_no)?;
+                stats.nodes_merged += 1;
+                stats.pages_freed += 1;
+                continue;
+            }
+
+            compact_node(&mut right, stats)?;
+            left = right;
+            idx += 1;
+        }
+
+        Ok(())
+    }
+
+    /// Replaces the root with its only child for as long as it has just one, then compacts it,
+    /// hinting its entries against the heap `horizon` if it's a leaf and there is one.
+    pub(super) fn collapse_root(
+        &self,
+        horizon: Option<TxnId>,
+        stats: &mut VacuumStats,
+    ) -> Result<()> {
+        loop {
+            let mut metadata = self.metadata_write()?;
+            let root_no = match metadata.root_no()? {
+                Some(root_no) => root_no,
+                None => return Ok(()),
+            };
+            stats.pages_scanned += 1;
+            if matches!(self.node_type(root_no)?, NodeType::Leaf) {
+                let mut root = leaf_node::fetch_page_write::<_, K, V>(&self.page_fetcher, root_no)?;
+                if V::tombstone().is_some() {
+                    stats.tombstones_removed += root.remove_tombstones();
+                }
+                if let Some(horizon) = horizon {
+                    stats.tuples_hinted +=
+                        root.hint_dead_tuples(|id| self.is_tuple_dead(id, horizon))?;
+                }
+                return compact_node(&mut root, stats);
+            }
+
+            let mut root = internal_node::fetch_page_write::<_, K>(&self.page_fetcher, root_no)?;
+            if root.page_ref().item_cnt() != 2 {
+                return compact_node(&mut root, stats);
+            }
+            let child_no = root.downlink(1).page_no;
+            drop(root);
+            metadata.set_root_no(child_no)?;
+            drop(metadata);
+
+            debug!(
+                "[collapse_root] Replaced root {} with {}",
+                root_no, child_no
+            );
+            self.page_fetcher.free_page(root_no)?;
+            stats.pages_freed += 1;
+        }
+    }
+
+    fn vacuum_heap(&self, page_no: u32, horizon: TxnId, stats: &mut VacuumStats) -> Result<()> {
+        stats.pages_scanned += 1;
+        let is_heap = {
+            let page = self.page_fetcher.fetch_page_read(page_no)?;
+            BTreePageData::check(&page).is_ok()
+                && matches!(
+                    page.special_data::<BTreePageData>().node_type,
+                    NodeType::Heap
+                )
+        };
+        if !is_heap {
+            return Ok(());
+        }
+
+        let (removed, free) = {
+            let mut page = self.page_fetcher.fetch_page_write(page_no)?;
+            let removed = vacuum_heap_page(page_no, &mut page, horizon)?;
+            (removed, page.free_space())
+        };
+        if removed > 0 {
+            stats.tuples_removed += removed;
+            stats.pages_compacted += 1;
+        }
+        self.record_free_space(page_no, free)
+    }
+}
+
+fn compact_node<I, N>(node: &mut N, stats: &mut VacuumStats) -> Result<()>
+where
+    I: NodeItem,
+    N: NodeWrite<I>,
+{
+    if node.page_ref().dead_space() >= COMPACT_DEAD_SPACE {
+        node.page_ref_mut().compact::<I>()?;
+        stats.pages_compacted += 1;
+    }
+    Ok(())
+}
+
+/// Moves `right`'s items onto the end of `left`, which takes over `right`'s separator and right
+/// sibling. Returns false, leaving both nodes as they were, if they're unsorted or too full.
+fn merge_nodes<I, N>(left: &mut N, right: &N) -> Result<bool>
+where
+    I: NodeItem,
+    N: NodeWrite<I>,
+{
+    let live_size = |page: &Page| {
+        page.item_cnt() * ITEM_POINTER_SIZE + page.item_data_size() - page.dead_space()
+    };
+    let usable = PAGE_DATA_SIZE - size_of::<BTreePageData>();
+    if !left.special_data().is_sorted()
+        || !right.special_data().is_sorted()
+        || (live_size(left.page_ref()) + live_size(right.page_ref())) as f64
+            > usable as f64 * MERGE_FILL
+    {
+        return Ok(false);
+    }
+
+    let separator = right.separator().clone();
+    let mut merged = match merged_page::<I>(left.page_ref(), right.page_ref(), &separator) {
+        Ok(merged) => merged,
+        Err(Error::PageFull) => return Ok(false),
+        Err(err) => return Err(err),
+    };
+    merged
+        .special_data_mut::<BTreePageData>()
+        .set_right_sibling(right.special_data().right_sibling());
+
+    *left.page_ref_mut() = merged;
+    *left.separator_cache_mut() = OnceCell::from(separator);
+    Ok(true)
+}
+
+fn merged_page<I: NodeItem>(
+    left: &Page,
+    right: &Page,
+    separator: &Separator<I::Key>,
+) -> Result<Page> {
+    let mut merged = *left;
+    merged.remove_item_v2(0);
+    merged.compact::<I>()?;
+    separator.write(&mut merged)?;
+
+    let mut right = *right;
+    right.move_items_to::<I>(1, &mut merged)?;
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Vacuum;
+    use crate::btree::heap::Snapshot;
+    use crate::btree::key::KeyBytes;
+    use crate::btree::key::KeyU32;
+    use crate::btree::value::ValueBytes;
+    use crate::btree::value::ValueTupleId;
+    use crate::btree::BTree;
+    use crate::page_fetcher::InMemoryPageFetcher;
+    use crate::page_fetcher::PageFetcher;
+
+    fn value(key: u32) -> ValueTupleId {
+        ValueTupleId {
+            page_no: key,
+            offset: 0,
+        }
+    }
+
+    fn leaf_cnt<P: PageFetcher>(btree: &BTree<KeyU32, ValueTupleId, P>) -> usize {
+        btree.analyze().unwrap().levels.last().unwrap().page_cnt
+    }
+
+    #[test]
+    fn merge_and_reuse_pages() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(256)).unwrap();
+        for key in 0..20000 {
+            btree.insert(KeyU32 { key }, value(key)).unwrap();
+        }
+        for key in (0..20000).filter(|key| key % 10 != 0) {
+            btree.delete(KeyU32 { key }).unwrap();
+        }
+        let leaves = leaf_cnt(&btree);
+        let page_cnt = btree.page_fetcher().page_cnt();
+
+        let stats = btree.vacuum(Vacuum::new()).unwrap();
+        assert!(stats.nodes_merged > 0);
+        // Free pages left at the end are dropped rather than kept on the free list
+        let truncated = page_cnt - btree.page_fetcher().page_cnt();
+        assert_eq!(
+            stats.pages_freed,
+            btree.page_fetcher().free_page_cnt() + truncated
+        );
+        assert!(leaf_cnt(&btree) * 4 < leaves);
+        assert!(btree.verify().unwrap().is_ok());
+        for key in 0..20000 {
+            let found = btree.search(KeyU32 { key }).unwrap().value;
+            assert_eq!(found, Some(value(key)).filter(|_| key % 10 == 0));
+        }
+
+        // Splits take pages off the free list before allocating new ones
+        let page_cnt = btree.page_fetcher().page_cnt();
+        for key in (0..20000).filter(|key| key % 10 == 1) {
+            btree.insert(KeyU32 { key }, value(key)).unwrap();
+        }
+        assert_eq!(btree.page_fetcher().page_cnt(), page_cnt);
+        assert!(btree.page_fetcher().free_page_cnt() < stats.pages_freed - truncated);
+        assert!(btree.verify().unwrap().is_ok());
+    }
+
+    #[test]
+    fn bounded_steps() {
+        // Long keys, so that the leaves have several parents
+        let key = |i: u32| KeyBytes {
+            key: format!("{:05}", i).repeat(40).into_bytes(),
+        };
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(1024)).unwrap();
+        for i in 0..5000 {
+            btree.insert(key(i), ValueBytes { value: vec![] }).unwrap();
+        }
+        for i in 0..4500 {
+            btree.delete(key(i)).unwrap();
+        }
+
+        assert_eq!(btree.dead_item_cnt().unwrap(), 4500);
+
+        let mut vacuum = Vacuum::new();
+        let mut steps = 0;
+        let mut next = 5000;
+        while !btree.vacuum_step(&mut vacuum, 8).unwrap() {
+            steps += 1;
+            // Other operations can run between steps
+            btree
+                .insert(key(next), ValueBytes { value: vec![] })
+                .unwrap();
+            next += 1;
+            if steps == 1 {
+                btree.delete(key(4500)).unwrap();
+            }
+        }
+        assert!(steps > 1);
+        assert!(vacuum.stats().pages_freed > 0);
+        assert!(btree.verify().unwrap().is_ok());
+        assert_eq!(btree.analyze().unwrap().entry_cnt(), (next - 4501) as usize);
+        // The delete made while the vacuum ran is still counted
+        assert_eq!(btree.dead_item_cnt().unwrap(), 1);
+    }
+
+    #[test]
+    fn collapse_root() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(256)).unwrap();
+        for key in 0..5000 {
+            btree.insert(KeyU32 { key }, value(key)).unwrap();
+        }
+        assert!(btree.analyze().unwrap().height() > 1);
+        for key in 1..5000 {
+            btree.delete(KeyU32 { key }).unwrap();
+        }
+
+        btree.vacuum(Vacuum::new()).unwrap();
+        let stats = btree.analyze().unwrap();
+        assert_eq!(stats.height(), 1);
+        assert_eq!(stats.entry_cnt(), 1);
+        assert!(btree.verify().unwrap().is_ok());
+        assert_eq!(
+            btree.search(KeyU32 { key: 0 }).unwrap().value,
+            Some(value(0))
+        );
+    }
+
+    #[test]
+    fn heap_tuples() {
+        let mut btree: BTree<KeyU32, ValueTupleId, _> =
+            BTree::new(InMemoryPageFetcher::new()).unwrap();
+        let ids = (0..100u64)
+            .map(|i| btree.insert_tuple_in(1, &[i as u8; 200]).unwrap())
+            .collect::<Vec<_>>();
+        for (i, id) in ids.iter().enumerate() {
+            match i % 3 {
+                0 => drop(btree.delete_tuple(*id).unwrap()),
+                1 => drop(btree.delete_tuple_in(*id, 10 + i as u64).unwrap()),
+                _ => {}
+            }
+        }
+
+        // Only deletes by transactions below the horizon are removed
+        let stats = btree.vacuum(Vacuum::with_heap(50)).unwrap();
+        let removed = (0..100).filter(|i| i % 3 == 0 || (i % 3 == 1 && 10 + i < 50));
+        assert_eq!(stats.tuples_removed, removed.count());
+
+        let old = Snapshot {
+            current: 2,
+            xmax: 3,
+            in_progress: vec![],
+        };
+        for (i, id) in ids.iter().enumerate() {
+            let expected = match i % 3 {
+                0 => None,
+                1 if 10 + i < 50 => None,
+                _ => Some(vec![i as u8; 200]),
+            };
+            assert_eq!(btree.get_tuple_in(*id, &old).unwrap(), expected);
+        }
+
+        // The removed tuples' slots stay, but their space is reclaimed
+        assert_eq!(
+            btree.vacuum(Vacuum::with_heap(50)).unwrap().tuples_removed,
+            0
+        );
+        let id = ids[0];
+        let page = btree.page_fetcher().fetch_page_read(id.page_no).unwrap();
+        assert!(page.dead_space() < 200);
+    }
+}
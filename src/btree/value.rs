@@ -1,8 +1,62 @@
+use crate::encoding;
+use crate::numeric::Numeric;
 use crate::page::Item;
+use std::borrow::Cow;
+use std::convert::TryInto;
 use std::fmt::Debug;
 use std::mem::size_of;
 
-pub trait Value: Item + Ord + Copy + Clone + Debug {}
+pub trait Value: Item + Clone + Debug {
+    /// See `Key::type_name`.
+    fn type_name() -> Cow<'static, str> {
+        Cow::Borrowed(std::any::type_name::<Self>())
+    }
+
+    /// How values too large to store inline are moved to overflow pages, see `Overflow`. `None`
+    /// stores every value inline.
+    fn overflow() -> Option<Overflow<Self>> {
+        None
+    }
+
+    /// The first page of the overflow chain this value points to, if it's an overflow pointer.
+    fn overflow_no(&self) -> Option<u32> {
+        None
+    }
+
+    /// The value recording that a key was deleted, for types that support logical deletes, see
+    /// `ValueMaybe`. Reads treat keys with a tombstone as absent, and `vacuum` removes them.
+    fn tombstone() -> Option<Self> {
+        None
+    }
+
+    /// Whether `bytes` encode the `tombstone`.
+    fn is_tombstone(bytes: &[u8]) -> bool {
+        let _ = bytes;
+        false
+    }
+
+    /// The heap tuple `bytes` point at, for values carrying hints about its visibility, which
+    /// `vacuum` sets, see `ValueTupleId::is_hinted_dead`.
+    fn tuple_id(bytes: &[u8]) -> Option<ValueTupleId> {
+        let _ = bytes;
+        None
+    }
+}
+
+/// Values whose encoding takes more than `inline_limit` bytes are moved to a chain of overflow
+/// pages when they're stored, leaving the value `pointer` builds from the chain's first page in
+/// the leaf in their place. Reads follow the pointer, so callers get the whole value back.
+pub struct Overflow<V> {
+    pub inline_limit: usize,
+    pub pointer: fn(u32) -> V,
+}
+
+pub(super) const TUPLE_ID_SIZE: usize = 8;
+
+/// The byte of a stored `ValueTupleId` holding hint bits about its tuple.
+const HINTS_OFFSET: usize = 6;
+/// Hint bit set once the tuple is dead to every snapshot that may still scan the index.
+const HINT_DEAD: u8 = 1;
 
 #[derive(Debug, Copy, Clone, Ord, PartialOrd, PartialEq, Eq)]
 pub struct ValueTupleId {
@@ -10,33 +64,423 @@ pub struct ValueTupleId {
     pub offset: u16,
 }
 
-impl Value for ValueTupleId {}
+impl ValueTupleId {
+    /// Whether the stored id `bytes` was hinted by a vacuum with a heap horizon to point at a
+    /// tuple removed or deleted below the horizon, which no snapshot sees, so index scans can
+    /// skip it without fetching the tuple. Entries written since carry no hints, and a tuple
+    /// never becomes visible again once dead, so a hint never goes stale. Hints may be dropped
+    /// when a leaf's entries are rewritten, e.g. by a split, until the next vacuum.
+    pub fn is_hinted_dead(bytes: &[u8]) -> bool {
+        bytes
+            .get(HINTS_OFFSET)
+            .is_some_and(|hints| hints & HINT_DEAD != 0)
+    }
+
+    pub(super) fn hint_dead(bytes: &mut [u8]) {
+        bytes[HINTS_OFFSET] |= HINT_DEAD;
+    }
+}
+
+impl Value for ValueTupleId {
+    fn type_name() -> Cow<'static, str> {
+        Cow::Borrowed("johndb::btree::value::ValueTupleId")
+    }
+
+    fn tuple_id(bytes: &[u8]) -> Option<ValueTupleId> {
+        Some(Self::read(bytes))
+    }
+}
 
+/// Stored as the page number and the offset, both little-endian, followed by a byte of hint bits
+/// and a zero byte. The hints start out clear and aren't part of the decoded value.
 impl Item for ValueTupleId {
     fn size(&self) -> usize {
-        size_of::<Self>()
+        TUPLE_ID_SIZE
     }
 
     fn align() -> usize {
-        std::mem::align_of::<Self>()
+        4
     }
 
     fn is_fixed_size() -> bool {
         true
     }
 
-    unsafe fn write(&self, buffer: *mut u8) {
-        *(buffer as *mut ValueTupleId) = (*self).clone();
+    fn write(&self, buffer: &mut [u8]) {
+        buffer[0..4].copy_from_slice(&self.page_no.to_le_bytes());
+        buffer[4..6].copy_from_slice(&self.offset.to_le_bytes());
+        buffer[6..TUPLE_ID_SIZE].fill(0);
     }
 
-    unsafe fn read(buffer: *const u8, size: usize) -> Self {
+    fn read(bytes: &[u8]) -> Self {
         assert!(
-            size == size_of::<Self>(),
-            "size {} != size_of::<Self> {}",
-            size,
-            size_of::<Self>(),
+            bytes.len() == TUPLE_ID_SIZE,
+            "size {} != TUPLE_ID_SIZE {}",
+            bytes.len(),
+            TUPLE_ID_SIZE,
         );
 
-        *(buffer as *mut Self).clone()
+        Self {
+            page_no: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+            offset: u16::from_le_bytes(bytes[4..6].try_into().unwrap()),
+        }
+    }
+}
+
+/// Variable length value stored inline in the leaf item.
+#[derive(Debug, Clone, Ord, PartialOrd, PartialEq, Eq)]
+pub struct ValueBytes {
+    pub value: Vec<u8>,
+}
+
+impl Value for ValueBytes {
+    fn type_name() -> Cow<'static, str> {
+        Cow::Borrowed("johndb::btree::value::ValueBytes")
+    }
+}
+
+impl Item for ValueBytes {
+    fn size(&self) -> usize {
+        self.value.len()
+    }
+
+    fn align() -> usize {
+        1
+    }
+
+    fn is_fixed_size() -> bool {
+        false
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        buffer.copy_from_slice(&self.value);
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        Self {
+            value: bytes.to_vec(),
+        }
+    }
+}
+
+/// Values up to this many bytes are stored inline by `ValueLarge`.
+pub const VALUE_INLINE_LIMIT: usize = 1024;
+
+const VALUE_LARGE_INLINE: u8 = 0;
+const VALUE_LARGE_OVERFLOW: u8 = 1;
+
+/// Variable length value of any size. Values of up to `VALUE_INLINE_LIMIT` bytes are stored inline
+/// like `ValueBytes`, larger ones in overflow pages, see `Value::overflow`.
+///
+/// Stored with a leading tag byte, followed by the value if it's inline, or the big-endian number
+/// of the first overflow page otherwise. Scans that look at values in place, like `range_visit`
+/// and `scan_filter`, see this stored form.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueLarge {
+    pub value: Vec<u8>,
+    overflow_no: Option<u32>,
+}
+
+impl From<Vec<u8>> for ValueLarge {
+    fn from(value: Vec<u8>) -> Self {
+        Self {
+            value,
+            overflow_no: None,
+        }
+    }
+}
+
+impl ValueLarge {
+    fn overflow_pointer(first_page_no: u32) -> Self {
+        Self {
+            value: Vec::new(),
+            overflow_no: Some(first_page_no),
+        }
+    }
+}
+
+impl Value for ValueLarge {
+    fn type_name() -> Cow<'static, str> {
+        Cow::Borrowed("johndb::btree::value::ValueLarge")
+    }
+
+    fn overflow() -> Option<Overflow<Self>> {
+        Some(Overflow {
+            inline_limit: 1 + VALUE_INLINE_LIMIT,
+            pointer: Self::overflow_pointer,
+        })
+    }
+
+    fn overflow_no(&self) -> Option<u32> {
+        self.overflow_no
+    }
+}
+
+impl Item for ValueLarge {
+    fn size(&self) -> usize {
+        match self.overflow_no {
+            Some(_) => 1 + size_of::<u32>(),
+            None => 1 + self.value.len(),
+        }
+    }
+
+    fn align() -> usize {
+        1
+    }
+
+    fn is_fixed_size() -> bool {
+        false
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        match self.overflow_no {
+            Some(page_no) => {
+                buffer[0] = VALUE_LARGE_OVERFLOW;
+                buffer[1..].copy_from_slice(&page_no.to_be_bytes());
+            }
+            None => {
+                buffer[0] = VALUE_LARGE_INLINE;
+                buffer[1..].copy_from_slice(&self.value);
+            }
+        }
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        match bytes.split_first() {
+            Some((&VALUE_LARGE_OVERFLOW, page_no)) => {
+                Self::overflow_pointer(u32::from_be_bytes(page_no.try_into().unwrap()))
+            }
+            Some((&VALUE_LARGE_INLINE, value)) => Self::from(value.to_vec()),
+            _ => panic!("invalid ValueLarge encoding {:?}", bytes),
+        }
+    }
+}
+
+const VALUE_MAYBE_TOMBSTONE: u8 = 0;
+const VALUE_MAYBE_LIVE: u8 = 1;
+
+/// A value, or a tombstone recording that its key was deleted, see `BTree::delete_logically`.
+///
+/// Stored as a tag byte, followed by the value's encoding unless it's a tombstone.
+#[derive(Debug, Clone, PartialEq)]
+pub enum ValueMaybe<V> {
+    Live(V),
+    Tombstone,
+}
+
+impl<V: Value> From<V> for ValueMaybe<V> {
+    fn from(value: V) -> Self {
+        ValueMaybe::Live(value)
+    }
+}
+
+impl<V: Value> Value for ValueMaybe<V> {
+    fn type_name() -> Cow<'static, str> {
+        Cow::Owned(format!(
+            "johndb::btree::value::ValueMaybe<{}>",
+            V::type_name()
+        ))
+    }
+
+    fn overflow() -> Option<Overflow<Self>> {
+        V::overflow().map(|overflow| Overflow {
+            inline_limit: 1 + overflow.inline_limit,
+            pointer: |first_page_no| {
+                let overflow = V::overflow().unwrap();
+                ValueMaybe::Live((overflow.pointer)(first_page_no))
+            },
+        })
+    }
+
+    fn overflow_no(&self) -> Option<u32> {
+        match self {
+            ValueMaybe::Live(value) => value.overflow_no(),
+            ValueMaybe::Tombstone => None,
+        }
+    }
+
+    fn tombstone() -> Option<Self> {
+        Some(ValueMaybe::Tombstone)
+    }
+
+    fn is_tombstone(bytes: &[u8]) -> bool {
+        bytes.first() == Some(&VALUE_MAYBE_TOMBSTONE)
+    }
+}
+
+impl<V: Value> Item for ValueMaybe<V> {
+    fn size(&self) -> usize {
+        match self {
+            ValueMaybe::Live(value) => 1 + value.size(),
+            ValueMaybe::Tombstone => 1,
+        }
+    }
+
+    fn align() -> usize {
+        1
+    }
+
+    fn is_fixed_size() -> bool {
+        false
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        match self {
+            ValueMaybe::Live(value) => {
+                buffer[0] = VALUE_MAYBE_LIVE;
+                value.write(&mut buffer[1..]);
+            }
+            ValueMaybe::Tombstone => buffer[0] = VALUE_MAYBE_TOMBSTONE,
+        }
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        match bytes.split_first() {
+            Some((&VALUE_MAYBE_LIVE, value)) => ValueMaybe::Live(V::read(value)),
+            Some((&VALUE_MAYBE_TOMBSTONE, [])) => ValueMaybe::Tombstone,
+            _ => panic!("invalid ValueMaybe encoding {:?}", bytes),
+        }
+    }
+}
+
+/// Decimal value, see `Numeric`. Stored in its order-preserving encoding.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ValueNumeric {
+    pub value: Numeric,
+}
+
+impl From<Numeric> for ValueNumeric {
+    fn from(value: Numeric) -> Self {
+        Self { value }
+    }
+}
+
+impl Value for ValueNumeric {
+    fn type_name() -> Cow<'static, str> {
+        Cow::Borrowed("johndb::btree::value::ValueNumeric")
+    }
+}
+
+impl Item for ValueNumeric {
+    fn size(&self) -> usize {
+        encoding::encode(&self.value).len()
+    }
+
+    fn align() -> usize {
+        1
+    }
+
+    fn is_fixed_size() -> bool {
+        false
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        buffer.copy_from_slice(&encoding::encode(&self.value));
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        Self {
+            value: encoding::decode(bytes).expect("value isn't a valid numeric"),
+        }
+    }
+}
+
+/// Any serde type as a value, encoded with bincode. Handy when the values aren't plain bytes, e.g.
+/// `BTree` with `ValueSerde<MyStruct>` values.
+#[cfg(feature = "serde")]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueSerde<T> {
+    pub value: T,
+}
+
+#[cfg(feature = "serde")]
+impl<T> From<T> for ValueSerde<T> {
+    fn from(value: T) -> Self {
+        Self { value }
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T> Value for ValueSerde<T>
+where
+    T: serde::Serialize + serde::de::DeserializeOwned + Clone + Debug,
+{
+    /// `T` has no name of its own to go by, so renaming or moving it still changes this one.
+    fn type_name() -> Cow<'static, str> {
+        Cow::Owned(format!(
+            "johndb::btree::value::ValueSerde<{}>",
+            std::any::type_name::<T>()
+        ))
+    }
+}
+
+#[cfg(feature = "serde")]
+impl<T> Item for ValueSerde<T>
+where
+    T: serde::Serialize + serde::de::DeserializeOwned + Clone + Debug,
+{
+    fn size(&self) -> usize {
+        // TODO: Cache the encoding, we end up serializing the value a few times per insert.
+        bincode::serialized_size(&self.value).expect("value can't be serialized") as usize
+    }
+
+    fn align() -> usize {
+        1
+    }
+
+    fn is_fixed_size() -> bool {
+        false
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        bincode::serialize_into(buffer, &self.value).expect("value can't be serialized");
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        Self {
+            value: bincode::deserialize(bytes).expect("value can't be deserialized"),
+        }
+    }
+}
+
+#[cfg(all(test, feature = "serde"))]
+mod tests {
+    use super::ValueSerde;
+    use crate::btree::key::KeyU64;
+    use crate::btree::BTree;
+    use crate::page_fetcher::InMemoryPageFetcher;
+    use serde::Deserialize;
+    use serde::Serialize;
+
+    #[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+    struct Account {
+        name: String,
+        balance: f64,
+        tags: Vec<String>,
+    }
+
+    #[test]
+    fn serde_values() {
+        let mut btree = BTree::new(InMemoryPageFetcher::new()).unwrap();
+        for i in 0..500u64 {
+            let account = Account {
+                name: format!("account-{}", i),
+                balance: i as f64 / 2.0,
+                tags: vec!["a".to_string(); (i % 4) as usize],
+            };
+            btree
+                .insert(KeyU64::from(i), ValueSerde::from(account))
+                .unwrap();
+        }
+
+        let found = btree.search(KeyU64::from(321)).unwrap().value.unwrap();
+        assert_eq!(
+            found.value,
+            Account {
+                name: "account-321".to_string(),
+                balance: 160.5,
+                tags: vec!["a".to_string()],
+            }
+        );
     }
 }
@@ -0,0 +1,600 @@
+use super::internal_node::InternalNodeItemData;
+use super::key::Key;
+use super::leaf_node::LeafNodeItemData;
+use super::metadata_node::MetadataRead;
+use super::node::check_node_items;
+use super::node::Separator;
+use super::value::Value;
+use super::BTreePageData;
+use super::NodeType;
+use crate::error::Result;
+use crate::page::PageNo;
+use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use std::collections::HashSet;
+use std::fmt;
+
+/// The outcome of `BTree::verify`.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct VerifyReport {
+    /// Pages reached through downlinks from the root.
+    pub pages_checked: usize,
+    pub violations: Vec<Violation>,
+}
+
+impl VerifyReport {
+    pub fn is_ok(&self) -> bool {
+        self.violations.is_empty()
+    }
+}
+
+/// A broken invariant found on page `page_no`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub page_no: u32,
+    pub kind: ViolationKind,
+}
+
+/// Keys are reported through their `Debug` representation so reports don't depend on the tree's
+/// key type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ViolationKind {
+    /// The page couldn't be fetched.
+    Unreadable(String),
+    /// The page header or item pointers are malformed, so its items weren't looked at.
+    BadLayout(String),
+    /// Pages reachable from the root must be leaf or internal nodes.
+    WrongNodeType(String),
+    /// The page's separator isn't the key it should be: its downlink's key in the parent (or
+    /// unbounded for the rightmost pages), or its own largest downlink key for internal nodes.
+    SeparatorMismatch {
+        expected: String,
+        found: String,
+    },
+    /// An item's key lies outside the key range the parent assigned to the page.
+    KeyOutOfRange(String),
+    DuplicateKey(String),
+    /// The page is marked as keeping its items in key order, but they're out of order.
+    Unsorted,
+    /// An internal node without any downlinks.
+    NoDownlinks,
+    /// Leaves must all be at the same depth.
+    UnevenDepth {
+        expected: usize,
+        found: usize,
+    },
+    /// The page's right sibling link doesn't point at the next page on its level.
+    SiblingMismatch {
+        expected: PageNo,
+        found: PageNo,
+    },
+    /// The page is reachable through more than one downlink.
+    MultipleParents,
+    /// Walking the leaf level through sibling links found a different number of entries than
+    /// walking it through downlinks.
+    EntryCountMismatch {
+        via_downlinks: usize,
+        via_siblings: usize,
+    },
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "page {}: ", self.page_no)?;
+        match &self.kind {
+            ViolationKind::Unreadable(err) => write!(f, "unreadable: {}", err),
+            ViolationKind::BadLayout(err) => write!(f, "bad layout: {}", err),
+            ViolationKind::WrongNodeType(found) => {
+                write!(f, "expected a leaf or internal node, found {}", found)
+            }
+            ViolationKind::SeparatorMismatch { expected, found } => {
+                write!(f, "separator is {} but should be {}", found, expected)
+            }
+            ViolationKind::KeyOutOfRange(key) => {
+                write!(f, "key {} is outside the page's key range", key)
+            }
+            ViolationKind::DuplicateKey(key) => write!(f, "key {} appears more than once", key),
+            ViolationKind::Unsorted => write!(f, "items are out of order on a sorted page"),
+            ViolationKind::NoDownlinks => write!(f, "internal node has no downlinks"),
+            ViolationKind::UnevenDepth { expected, found } => write!(
+                f,
+                "leaf is at depth {} while other leaves are at depth {}",
+                found, expected
+            ),
+            ViolationKind::SiblingMismatch { expected, found } => write!(
+                f,
+                "right sibling is {} but the next page on its level is {}",
+                found, expected
+            ),
+            ViolationKind::MultipleParents => write!(f, "page has more than one downlink"),
+            ViolationKind::EntryCountMismatch {
+                via_downlinks,
+                via_siblings,
+            } => write!(
+                f,
+                "leaves hold {} entries through downlinks but {} through sibling links",
+                via_downlinks, via_siblings
+            ),
+        }
+    }
+}
+
+impl<K, V, PageFetcher> super::BTree<K, V, PageFetcher>
+where
+    K: Key,
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Walks the whole tree checking its invariants, in the spirit of PostgreSQL's amcheck:
+    ///
+    /// * every page's separator matches the downlink pointing at it, and internal nodes'
+    ///   separators match their largest downlink,
+    /// * every key lies within the range its page was assigned by its parent, with no duplicates,
+    ///   and in key order on pages marked sorted,
+    /// * every level's right sibling links chain its pages in key order, ending with 0,
+    /// * all leaves are at the same depth, and the leaf level holds as many entries through
+    ///   sibling links as through downlinks.
+    ///
+    /// Violations are collected into the report rather than returned as errors, so a damaged tree
+    /// can be inspected as a whole. Pages are read-locked one at a time, so the tree shouldn't be
+    /// modified concurrently. Only failing to read the metadata page is an error.
+    pub fn verify(&self) -> Result<VerifyReport> {
+        let metadata = self.metadata_read()?;
+        let root_no = match metadata.root_no()? {
+            Some(root_no) => root_no,
+            None => return Ok(VerifyReport::default()),
+        };
+        drop(metadata);
+
+        let mut verifier = Verifier {
+            btree: self,
+            report: VerifyReport::default(),
+            visited: HashSet::new(),
+            levels: Vec::new(),
+            leaf_depth: None,
+            leaf_entry_cnt: 0,
+        };
+        verifier.visit(root_no, None, Separator::Unbounded, 0);
+        verifier.check_levels();
+        verifier.check_leaf_chain();
+
+        Ok(verifier.report)
+    }
+}
+
+struct Verifier<'a, K, V, PageFetcher>
+where
+    K: Key,
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    btree: &'a super::BTree<K, V, PageFetcher>,
+    report: VerifyReport,
+    visited: HashSet<u32>,
+    /// `(page_no, right_sibling)` of every page on each level, left to right.
+    levels: Vec<Vec<(u32, PageNo)>>,
+    leaf_depth: Option<usize>,
+    leaf_entry_cnt: usize,
+}
+
+impl<'a, K, V, PageFetcher> Verifier<'a, K, V, PageFetcher>
+where
+    K: Key,
+    V: Value,
+    PageFetcher: PageFetcherTrait,
+{
+    fn violation(&mut self, page_no: u32, kind: ViolationKind) {
+        self.report.violations.push(Violation { page_no, kind });
+    }
+
+    /// Checks page `page_no`, which its parent assigned the keys in `[lower, separator)`, then
+    /// its children.
+    fn visit(&mut self, page_no: u32, lower: Option<K>, separator: Separator<K>, depth: usize) {
+        if !self.visited.insert(page_no) {
+            self.violation(page_no, ViolationKind::MultipleParents);
+            return;
+        }
+
+        let page = match self.btree.page_fetcher.fetch_page_read(page_no) {
+            Ok(page) => page,
+            Err(err) => {
+                self.violation(page_no, ViolationKind::Unreadable(err.to_string()));
+                return;
+            }
+        };
+        if let Err(err) = page.check_layout() {
+            self.violation(page_no, ViolationKind::BadLayout(err.to_string()));
+            return;
+        }
+        if let Err(err) = BTreePageData::check(&page) {
+            self.violation(page_no, ViolationKind::BadLayout(err.to_string()));
+            return;
+        }
+        self.report.pages_checked += 1;
+
+        let special_data = page.special_data::<BTreePageData>().clone();
+        if self.levels.len() <= depth {
+            self.levels.push(Vec::new());
+        }
+        self.levels[depth].push((page_no, special_data.right_sibling()));
+
+        let items = match special_data.node_type {
+            NodeType::Leaf => check_node_items::<LeafNodeItemData<K, V>>(&page),
+            NodeType::Internal => check_node_items::<InternalNodeItemData<K>>(&page),
+            ref other => {
+                self.violation(
+                    page_no,
+                    ViolationKind::WrongNodeType(format!("{:?}", other)),
+                );
+                return;
+            }
+        };
+        if let Err(err) = items {
+            self.violation(page_no, ViolationKind::BadLayout(err.to_string()));
+            return;
+        }
+
+        let found = Separator::<K>::read(&page);
+        if found != separator {
+            self.violation(
+                page_no,
+                ViolationKind::SeparatorMismatch {
+                    expected: format!("{:?}", separator),
+                    found: format!("{:?}", found),
+                },
+            );
+        }
+
+        match special_data.node_type {
+            NodeType::Leaf => {
+                let mut keys = page
+                    .items_iter_from_v2::<LeafNodeItemData<K, V>>(1)
+                    .map(|item| item.key)
+                    .collect::<Vec<_>>();
+                drop(page);
+
+                if special_data.is_sorted() && keys.windows(2).any(|pair| pair[0] > pair[1]) {
+                    self.violation(page_no, ViolationKind::Unsorted);
+                }
+                match self.leaf_depth {
+                    None => self.leaf_depth = Some(depth),
+                    Some(expected) if expected != depth => self.violation(
+                        page_no,
+                        ViolationKind::UnevenDepth {
+                            expected,
+                            found: depth,
+                        },
+                    ),
+                    Some(_) => {}
+                }
+
+                keys.sort();
+                self.leaf_entry_cnt += keys.len();
+                for key in keys.iter() {
+                    if lower.as_ref().is_some_and(|lower| key < lower) || !found.is_above(key) {
+                        self.violation(page_no, ViolationKind::KeyOutOfRange(format!("{:?}", key)));
+                    }
+                }
+                self.check_duplicates(page_no, &keys);
+            }
+            NodeType::Internal => {
+                let mut downlinks = page
+                    .items_iter_from_v2::<InternalNodeItemData<K>>(1)
+                    .collect::<Vec<_>>();
+                drop(page);
+
+                if special_data.is_sorted()
+                    && downlinks.windows(2).any(|pair| pair[0].key > pair[1].key)
+                {
+                    self.violation(page_no, ViolationKind::Unsorted);
+                }
+                downlinks.sort_by(|x, y| x.key.cmp(&y.key));
+                // The separators of the children, where the last child of an unbounded node is
+                // unbounded too, as long as its downlink has the placeholder key
+                let cnt = downlinks.len();
+                let bounds = downlinks
+                    .iter()
+                    .enumerate()
+                    .map(|(idx, downlink)| {
+                        let unbounded = idx + 1 == cnt
+                            && found == Separator::Unbounded
+                            && downlink.key == K::max_key();
+                        match unbounded {
+                            true => Separator::Unbounded,
+                            false => Separator::Key(downlink.key.clone()),
+                        }
+                    })
+                    .collect::<Vec<_>>();
+                let last = match bounds.last() {
+                    Some(last) => last.clone(),
+                    None => {
+                        self.violation(page_no, ViolationKind::NoDownlinks);
+                        return;
+                    }
+                };
+                if last != found {
+                    self.violation(
+                        page_no,
+                        ViolationKind::SeparatorMismatch {
+                            expected: format!("{:?}", last),
+                            found: format!("{:?}", found),
+                        },
+                    );
+                }
+
+                for bound in bounds.iter() {
+                    if lower.as_ref().is_some_and(|lower| !bound.is_above(lower)) || *bound > found
+                    {
+                        self.violation(
+                            page_no,
+                            ViolationKind::KeyOutOfRange(format!("{:?}", bound.stored_key())),
+                        );
+                    }
+                }
+                self.check_duplicates(page_no, &bounds);
+
+                let mut child_lower = lower;
+                for (downlink, bound) in downlinks.into_iter().zip(bounds) {
+                    self.visit(downlink.page_no, child_lower, bound, depth + 1);
+                    child_lower = Some(downlink.key);
+                }
+            }
+            _ => unreachable!("checked above"),
+        }
+    }
+
+    /// `keys` must be sorted.
+    fn check_duplicates<T: PartialEq + fmt::Debug>(&mut self, page_no: u32, keys: &[T]) {
+        for pair in keys.windows(2) {
+            if pair[0] == pair[1] {
+                self.violation(
+                    page_no,
+                    ViolationKind::DuplicateKey(format!("{:?}", pair[0])),
+                );
+            }
+        }
+    }
+
+    /// Checks that the right sibling links of every level match the order downlinks visited
+    /// its pages in.
+    fn check_levels(&mut self) {
+        let levels = std::mem::take(&mut self.levels);
+        for level in levels.iter() {
+            let next_page_nos = level
+                .iter()
+                .skip(1)
+                .map(|(page_no, _)| PageNo::new(*page_no));
+            for (&(page_no, right_sibling), expected) in level
+                .iter()
+                .zip(next_page_nos.chain(std::iter::once(PageNo::INVALID)))
+            {
+                if right_sibling != expected {
+                    self.violation(
+                        page_no,
+                        ViolationKind::SiblingMismatch {
+                            expected,
+                            found: right_sibling,
+                        },
+                    );
+                }
+            }
+        }
+        self.levels = levels;
+    }
+
+    /// Counts the leaf level's entries by following sibling links from the leftmost leaf.
+    fn check_leaf_chain(&mut self) {
+        let first_leaf_no = match self.leaf_depth.and_then(|depth| self.levels[depth].first()) {
+            Some(&(page_no, _)) => page_no,
+            None => return,
+        };
+
+        let mut seen = HashSet::new();
+        let mut entry_cnt = 0;
+        let mut next_page_no = Some(first_leaf_no);
+        while let Some(page_no) = next_page_no.filter(|page_no| seen.insert(*page_no)) {
+            let page = match self.btree.page_fetcher.fetch_page_read(page_no) {
+                Ok(page) => page,
+                // Anything reachable through downlinks has been reported already
+                Err(_) => break,
+            };
+            if page.check_layout().is_err() || BTreePageData::check(&page).is_err() {
+                break;
+            }
+            let special_data = page.special_data::<BTreePageData>();
+            if !matches!(special_data.node_type, NodeType::Leaf) {
+                break;
+            }
+            entry_cnt += page.item_cnt().saturating_sub(1);
+            next_page_no = special_data.right_sibling().get();
+        }
+
+        if entry_cnt != self.leaf_entry_cnt {
+            self.violation(
+                first_leaf_no,
+                ViolationKind::EntryCountMismatch {
+                    via_downlinks: self.leaf_entry_cnt,
+                    via_siblings: entry_cnt,
+                },
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ViolationKind;
+    use crate::btree::key::KeyU32;
+    use crate::btree::leaf_node::LeafNodeItemData;
+    use crate::btree::value::ValueTupleId;
+    use crate::btree::BTree;
+    use crate::btree::BTreePageData;
+    use crate::btree::NodeType;
+    use crate::page::Page;
+    use crate::page::PageNo;
+    use crate::page::PAGE_SIZE;
+    use crate::page_fetcher::InMemoryPageFetcher;
+    use crate::page_fetcher::PageFetcher;
+
+    fn setup_btree() -> BTree<KeyU32, ValueTupleId, InMemoryPageFetcher> {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(64)).unwrap();
+        for i in 0..3000u32 {
+            let key = (i * 7919) % 3000;
+            btree
+                .insert(
+                    KeyU32 { key },
+                    ValueTupleId {
+                        page_no: key,
+                        offset: 0,
+                    },
+                )
+                .unwrap();
+        }
+        btree
+    }
+
+    /// Page numbers of the leaves, left to right.
+    fn leaf_page_nos(btree: &BTree<KeyU32, ValueTupleId, InMemoryPageFetcher>) -> Vec<u32> {
+        let mut page_nos = Vec::new();
+        let mut next_page_no = btree.find_leaf_no(None).unwrap();
+        while let Some(page_no) = next_page_no {
+            page_nos.push(page_no);
+            let page = btree.page_fetcher.fetch_page_read(page_no).unwrap();
+            next_page_no = page.special_data::<BTreePageData>().right_sibling().get();
+        }
+        page_nos
+    }
+
+    #[test]
+    fn healthy_tree() {
+        let empty: BTree<KeyU32, ValueTupleId, _> = BTree::new(InMemoryPageFetcher::new()).unwrap();
+        assert!(empty.verify().unwrap().is_ok());
+
+        let btree = setup_btree();
+        let report = btree.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.violations);
+        assert_eq!(
+            report.pages_checked,
+            btree.page_fetcher.page_cnt() - 1,
+            "every page but the metadata is reachable"
+        );
+    }
+
+    #[test]
+    fn detects_broken_siblings_and_keys() {
+        let btree = setup_btree();
+        let leaves = leaf_page_nos(&btree);
+        assert!(leaves.len() > 2);
+
+        // Skip over the second leaf
+        {
+            let mut page = btree.page_fetcher.fetch_page_write(leaves[0]).unwrap();
+            page.special_data_mut::<BTreePageData>()
+                .set_right_sibling(PageNo::new(leaves[2]));
+        }
+        // Sneak a key into a leaf whose range doesn't cover it
+        {
+            let mut page = btree.page_fetcher.fetch_page_write(leaves[1]).unwrap();
+            page.add_item_v2(&LeafNodeItemData {
+                key: KeyU32 { key: 100000 },
+                value: ValueTupleId {
+                    page_no: 0,
+                    offset: 0,
+                },
+            })
+            .unwrap();
+        }
+        // Move a leaf's first item to its end
+        {
+            let mut page = btree.page_fetcher.fetch_page_write(leaves[2]).unwrap();
+            let first = page.get_item_v2::<LeafNodeItemData<KeyU32, ValueTupleId>>(1);
+            page.remove_item_v2(1);
+            page.add_item_v2(&first).unwrap();
+        }
+
+        let kinds = btree
+            .verify()
+            .unwrap()
+            .violations
+            .into_iter()
+            .map(|violation| (violation.page_no, violation.kind))
+            .collect::<Vec<_>>();
+        assert!(kinds.contains(&(
+            leaves[0],
+            ViolationKind::SiblingMismatch {
+                expected: PageNo::new(leaves[1]),
+                found: PageNo::new(leaves[2]),
+            }
+        )));
+        assert!(kinds.contains(&(
+            leaves[1],
+            ViolationKind::KeyOutOfRange("KeyU32 { key: 100000 }".to_string())
+        )));
+        assert!(kinds.contains(&(leaves[2], ViolationKind::Unsorted)));
+        assert!(kinds
+            .iter()
+            .any(|(_, kind)| matches!(kind, ViolationKind::EntryCountMismatch { .. })));
+    }
+
+    #[test]
+    fn detects_wrong_node_type() {
+        let btree = setup_btree();
+        let leaves = leaf_page_nos(&btree);
+        {
+            let mut page = btree.page_fetcher.fetch_page_write(leaves[1]).unwrap();
+            page.special_data_mut::<BTreePageData>().node_type = NodeType::Overflow;
+        }
+
+        let report = btree.verify().unwrap();
+        assert!(report
+            .violations
+            .iter()
+            .any(|violation| violation.page_no == leaves[1]
+                && matches!(violation.kind, ViolationKind::WrongNodeType(_))));
+        assert!(report.violations[0].to_string().starts_with("page "));
+    }
+
+    #[test]
+    fn corrupted_pages_dont_panic() {
+        let btree = setup_btree();
+        let mut state = 0x9E37_79B9_7F4A_7C15u64;
+        let mut next = || {
+            // splitmix64
+            state = state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+            (z ^ (z >> 31)) as usize
+        };
+
+        let page_cnt = btree.page_fetcher.page_cnt() as u32;
+        for round in 0..500 {
+            let page_no = 1 + (next() % (page_cnt as usize - 1)) as u32;
+            let original = btree
+                .page_fetcher
+                .fetch_page_read(page_no)
+                .unwrap()
+                .as_bytes()
+                .to_vec();
+            {
+                let mut bytes = original.clone();
+                // Mostly flip a few bytes, which is likelier to get past the cheap checks, but
+                // occasionally overwrite the whole page
+                if round % 50 == 0 {
+                    bytes.iter_mut().for_each(|byte| *byte = next() as u8);
+                } else {
+                    for _ in 0..1 + next() % 4 {
+                        bytes[next() % PAGE_SIZE] ^= 1 << (next() % 8);
+                    }
+                }
+                **btree.page_fetcher.fetch_page_write(page_no).unwrap() = Page::from_bytes(&bytes);
+            }
+
+            btree.verify().unwrap();
+            btree.dump_page(page_no, true).unwrap();
+            let _ = btree.analyze();
+
+            **btree.page_fetcher.fetch_page_write(page_no).unwrap() = Page::from_bytes(&original);
+        }
+        assert!(btree.verify().unwrap().is_ok());
+    }
+}
@@ -0,0 +1,644 @@
+//! Named trees sharing a database's file. The catalog is itself a tree, mapping each name to the
+//! tree's metadata page number, its key and value types and the options it was created with. Its
+//! own metadata page number is attached to the metadata page of the database's main tree, so a
+//! database without named trees has no catalog at all.
+//!
+//! Trees may also be given a `TreeSchema`, which is stored in the catalog entry and checked on
+//! every write.
+
+use crate::btree::analyze::TreeStats;
+use crate::btree::key::Key;
+use crate::btree::key::KeyBytes;
+use crate::btree::stats::KeyStats;
+use crate::btree::vacuum::Vacuum;
+use crate::btree::value::Value;
+use crate::btree::value::ValueBytes;
+use crate::btree::verify::VerifyReport;
+use crate::btree::BTree;
+use crate::database::autovacuum;
+use crate::database::delete_entry;
+use crate::database::free_value;
+use crate::database::from_internal_key;
+use crate::database::get_entry;
+use crate::database::key_stats;
+use crate::database::put_entry;
+use crate::database::range_entries;
+use crate::database::Range;
+use crate::database::Tree;
+use crate::database::DEFAULT_AUTOVACUUM_THRESHOLD;
+use crate::database::MAX_ITEM_SIZE;
+use crate::encoding::encode;
+use crate::encoding::Decode;
+use crate::encoding::Encode;
+use crate::error::Error;
+use crate::error::Result;
+use crate::file_page_fetcher::FilePageFetcher;
+use crate::row::ColumnType;
+use crate::row::Schema;
+use std::ops::RangeBounds;
+
+/// Options a named tree is created with, stored in the catalog.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeOptions {
+    /// Values are stored inline in the leaves when the key and value together take at most this
+    /// many bytes, and in overflow pages otherwise. Capped at the limit the database's own
+    /// entries use.
+    pub inline_limit: usize,
+    /// Checked against every key and value put into the tree. Trees without a schema accept any
+    /// bytes.
+    pub schema: Option<TreeSchema>,
+    /// Values of at least this many bytes are compressed, see `Options::compression_threshold`.
+    pub compression_threshold: Option<usize>,
+    /// The tree is vacuumed once this many entries were deleted or replaced since it last was, see
+    /// `Options::autovacuum_threshold`.
+    pub autovacuum_threshold: Option<u64>,
+}
+
+impl Default for TreeOptions {
+    fn default() -> Self {
+        TreeOptions {
+            inline_limit: MAX_ITEM_SIZE,
+            schema: None,
+            compression_threshold: None,
+            autovacuum_threshold: Some(DEFAULT_AUTOVACUUM_THRESHOLD),
+        }
+    }
+}
+
+/// What a named tree's keys and values must look like.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TreeSchema {
+    /// Keys must be valid values of this type, see `ColumnType::check_value`.
+    pub key_type: ColumnType,
+    /// Values must be rows of this schema, see `Schema::validate`.
+    pub columns: Schema,
+    /// If set, putting a key that's already present fails with `Error::DuplicateKey` instead of
+    /// replacing its value.
+    pub unique: bool,
+}
+
+impl TreeSchema {
+    fn check(&self, key: &[u8], value: &[u8]) -> Result<()> {
+        self.key_type.check_value(key)?;
+        self.columns.validate(value)
+    }
+}
+
+impl Encode for TreeSchema {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        (self.key_type as u8, &self.columns, self.unique).encode_to(buf);
+    }
+}
+
+impl Decode for TreeSchema {
+    fn decode_from(buf: &mut &[u8]) -> Result<Self> {
+        let key_type = u8::decode_from(buf)?;
+        let columns = Schema::decode_from(buf)?;
+        Ok(TreeSchema {
+            key_type: ColumnType::from_tag(key_type)?,
+            columns,
+            unique: bool::decode_from(buf)?,
+        })
+    }
+}
+
+/// A catalog entry, encoded with `crate::encoding` as a tuple of its fields followed by the
+/// optional schema and compression threshold.
+#[derive(Debug, Clone, PartialEq)]
+struct CatalogEntry {
+    metadata_no: u32,
+    key_type: String,
+    value_type: String,
+    options: TreeOptions,
+}
+
+impl CatalogEntry {
+    fn to_bytes(&self) -> Vec<u8> {
+        encode(&(
+            (
+                self.metadata_no,
+                &self.key_type,
+                &self.value_type,
+                self.options.inline_limit as u64,
+            ),
+            &self.options.schema,
+            self.options
+                .compression_threshold
+                .map(|threshold| threshold as u64),
+            self.options.autovacuum_threshold,
+        ))
+    }
+
+    fn from_bytes(mut bytes: &[u8]) -> Result<Self> {
+        let (metadata_no, key_type, value_type, inline_limit) =
+            <(u32, String, String, u64)>::decode_from(&mut bytes)?;
+        // Entries written before schemas were added end here
+        let schema = match bytes.is_empty() {
+            true => None,
+            false => Option::<TreeSchema>::decode_from(&mut bytes)?,
+        };
+        // And those written before compression was added here
+        let compression_threshold = match bytes.is_empty() {
+            true => None,
+            false => Option::<u64>::decode_from(&mut bytes)?.map(|threshold| threshold as usize),
+        };
+        // And those written before autovacuum was added here
+        let autovacuum_threshold = match bytes.is_empty() {
+            true => Some(DEFAULT_AUTOVACUUM_THRESHOLD),
+            false => Option::<u64>::decode_from(&mut bytes)?,
+        };
+        Ok(CatalogEntry {
+            metadata_no,
+            key_type,
+            value_type,
+            options: TreeOptions {
+                inline_limit: inline_limit as usize,
+                schema,
+                compression_threshold,
+                autovacuum_threshold,
+            },
+        })
+    }
+}
+
+pub(crate) struct Catalog<'a> {
+    btree: Tree<&'a FilePageFetcher>,
+}
+
+impl<'a> Catalog<'a> {
+    /// Opens the catalog attached to `main`, creating it first if `create` is set. Returns `None`
+    /// if there's no catalog and `create` isn't set.
+    pub(crate) fn attached_to(main: &'a Tree, create: bool) -> Result<Option<Self>> {
+        let page_fetcher = main.page_fetcher();
+        let btree = match main.attached_no()? {
+            Some(catalog_no) => BTree::open(page_fetcher, catalog_no)?,
+            None if create => {
+                let btree = BTree::create(page_fetcher)?;
+                main.set_attached_no(btree.metadata_no())?;
+                btree
+            }
+            None => return Ok(None),
+        };
+        Ok(Some(Catalog { btree }))
+    }
+
+    /// Checks the catalog's own tree, see `BTree::verify`.
+    pub(crate) fn verify(&self) -> Result<VerifyReport> {
+        self.btree.verify()
+    }
+
+    pub(crate) fn create_tree(&mut self, name: &str, options: TreeOptions) -> Result<()> {
+        if get_entry(&self.btree, name.as_bytes())?.is_some() {
+            return Err(Error::TreeExists(name.to_string()));
+        }
+
+        let tree = Tree::create(self.btree.page_fetcher())?;
+        let entry = CatalogEntry {
+            metadata_no: tree.metadata_no(),
+            key_type: KeyBytes::type_name().to_string(),
+            value_type: ValueBytes::type_name().to_string(),
+            options,
+        };
+        put_entry(
+            &mut self.btree,
+            MAX_ITEM_SIZE,
+            None,
+            name.as_bytes(),
+            &entry.to_bytes(),
+        )
+    }
+
+    pub(crate) fn open_tree(&self, name: &str) -> Result<NamedTree<'a>> {
+        let entry = self.entry(name)?;
+        if entry.key_type != KeyBytes::type_name() || entry.value_type != ValueBytes::type_name() {
+            return Err(Error::TypeMismatch(format!(
+                "tree {} holds ({}, {})",
+                name, entry.key_type, entry.value_type
+            )));
+        }
+
+        Ok(NamedTree {
+            name: name.to_string(),
+            btree: BTree::open(*self.btree.page_fetcher(), entry.metadata_no)?,
+            options: entry.options,
+        })
+    }
+
+    /// Like `open_tree`, but fails with `Error::TypeMismatch` unless the tree was created with
+    /// `schema`.
+    pub(crate) fn open_tree_with_schema(
+        &self,
+        name: &str,
+        schema: &TreeSchema,
+    ) -> Result<NamedTree<'a>> {
+        let tree = self.open_tree(name)?;
+        if tree.options.schema.as_ref() != Some(schema) {
+            return Err(Error::TypeMismatch(format!(
+                "tree {} has schema {:?}",
+                name, tree.options.schema
+            )));
+        }
+        Ok(tree)
+    }
+
+    pub(crate) fn drop_tree(&mut self, name: &str) -> Result<()> {
+        let entry = self.entry(name)?;
+        let tree = Tree::open(*self.btree.page_fetcher(), entry.metadata_no)?;
+        // The entry goes first, so that failing to free the tree's pages leaks them rather than
+        // leaving the catalog pointing at freed pages
+        delete_entry(&mut self.btree, name.as_bytes())?;
+        tree.destroy(|tree, value| free_value(tree, &value.value))
+    }
+
+    /// Advances `vacuum` over the catalog's own tree for `tree_idx` 0, and over the named tree
+    /// `tree_idx - 1` in name order otherwise. Returns whether it's done, or `None` if there's no
+    /// such tree.
+    pub(crate) fn vacuum_step(
+        &mut self,
+        tree_idx: usize,
+        vacuum: &mut Vacuum,
+        max_pages: usize,
+    ) -> Result<Option<bool>> {
+        if tree_idx == 0 {
+            return self.btree.vacuum_step(vacuum, max_pages).map(Some);
+        }
+        match self.names()?.get(tree_idx - 1) {
+            Some(name) => {
+                let mut tree = self.open_tree(name)?;
+                tree.btree.vacuum_step(vacuum, max_pages).map(Some)
+            }
+            None => Ok(None),
+        }
+    }
+
+    pub(crate) fn names(&self) -> Result<Vec<String>> {
+        self.btree
+            .range(..)?
+            .map(|entry| {
+                let (name, _) = entry?;
+                String::from_utf8(from_internal_key(name))
+                    .map_err(|_| Error::corruption("tree name isn't UTF-8".to_string()))
+            })
+            .collect()
+    }
+
+    fn entry(&self, name: &str) -> Result<CatalogEntry> {
+        let bytes = get_entry(&self.btree, name.as_bytes())?
+            .ok_or_else(|| Error::TreeNotFound(name.to_string()))?;
+        CatalogEntry::from_bytes(&bytes)
+    }
+}
+
+/// A tree created with `Database::create_tree`, with the same interface as the database's own
+/// entries. Changes become durable along with the rest of the database.
+pub struct NamedTree<'a> {
+    name: String,
+    btree: Tree<&'a FilePageFetcher>,
+    options: TreeOptions,
+}
+
+impl<'a> NamedTree<'a> {
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn options(&self) -> &TreeOptions {
+        &self.options
+    }
+
+    /// Sets `key` to `value`, replacing any existing value. If the tree has a schema, the key and
+    /// value are checked against it first and an existing value is only replaced if the schema
+    /// doesn't require unique keys.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        if let Some(schema) = &self.options.schema {
+            schema.check(key, value)?;
+            if schema.unique && self.get(key)?.is_some() {
+                return Err(Error::DuplicateKey);
+            }
+        }
+        put_entry(
+            &mut self.btree,
+            self.options.inline_limit,
+            self.options.compression_threshold,
+            key,
+            value,
+        )?;
+        autovacuum(&mut self.btree, self.options.autovacuum_threshold)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        get_entry(&self.btree, key)
+    }
+
+    /// Removes `key`, returning its previous value if there was one.
+    pub fn delete(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let value = delete_entry(&mut self.btree, key)?;
+        autovacuum(&mut self.btree, self.options.autovacuum_threshold)?;
+        Ok(value)
+    }
+
+    /// The statistics last collected over the tree's entries, see `Database::key_stats`.
+    pub fn key_stats(&self) -> Result<Option<KeyStats<Vec<u8>>>> {
+        key_stats(&self.btree)
+    }
+
+    /// Entries deleted or replaced since the tree was last vacuumed, see
+    /// `TreeOptions::autovacuum_threshold`.
+    pub fn dead_entry_cnt(&self) -> Result<u64> {
+        self.btree.dead_item_cnt()
+    }
+
+    /// Iterates over the entries whose keys fall within `range`, in ascending key order.
+    pub fn range<'b, R>(&self, range: R) -> Result<Range<'_, &'a FilePageFetcher>>
+    where
+        R: RangeBounds<&'b [u8]>,
+    {
+        range_entries(&self.btree, range)
+    }
+
+    /// Per-level statistics of the tree, see `BTree::analyze`.
+    pub fn stats(&self) -> Result<TreeStats> {
+        self.btree.analyze()
+    }
+
+    pub fn verify(&self) -> Result<VerifyReport> {
+        self.btree.verify()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::TreeOptions;
+    use super::TreeSchema;
+    use crate::database::Database;
+    use crate::database::Options;
+    use crate::error::Error;
+    use crate::page::PAGE_SIZE;
+    use crate::row::Column;
+    use crate::row::ColumnType;
+    use crate::row::Field;
+    use crate::row::Schema;
+
+    #[test]
+    fn named_trees() {
+        let path = std::env::temp_dir().join(format!("johndb-catalog-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let big = vec![3; 100];
+        {
+            let mut db = Database::open(&path, Options::default()).unwrap();
+            assert!(db.tree_names().unwrap().is_empty());
+            db.put(b"main", b"entry").unwrap();
+
+            db.create_tree(
+                "users",
+                TreeOptions {
+                    compression_threshold: Some(1024),
+                    ..TreeOptions::default()
+                },
+            )
+            .unwrap();
+            db.create_tree(
+                "orders",
+                TreeOptions {
+                    inline_limit: 16,
+                    ..TreeOptions::default()
+                },
+            )
+            .unwrap();
+            assert!(matches!(
+                db.create_tree("users", TreeOptions::default()),
+                Err(Error::TreeExists(_))
+            ));
+
+            let mut users = db.open_tree("users").unwrap();
+            let mut orders = db.open_tree("orders").unwrap();
+            for i in 0..500u32 {
+                users.put(&i.to_be_bytes(), b"user").unwrap();
+                orders.put(&i.to_be_bytes(), &big).unwrap();
+            }
+            assert_eq!(orders.options().inline_limit, 16);
+            assert_eq!(
+                users.get(&7u32.to_be_bytes()).unwrap(),
+                Some(b"user".to_vec())
+            );
+            assert_eq!(db.range(..).unwrap().count(), 1);
+            db.close().unwrap();
+        }
+
+        let mut db = Database::open(&path, Options::default()).unwrap();
+        assert_eq!(db.tree_names().unwrap(), vec!["orders", "users"]);
+        assert_eq!(db.get(b"main").unwrap(), Some(b"entry".to_vec()));
+        assert_eq!(
+            db.open_tree("users")
+                .unwrap()
+                .options()
+                .compression_threshold,
+            Some(1024)
+        );
+        {
+            let orders = db.open_tree("orders").unwrap();
+            assert_eq!(orders.range(..).unwrap().count(), 500);
+            assert_eq!(orders.get(&9u32.to_be_bytes()).unwrap(), Some(big));
+        }
+
+        db.drop_tree("orders").unwrap();
+        assert!(matches!(
+            db.open_tree("orders"),
+            Err(Error::TreeNotFound(_))
+        ));
+        assert!(matches!(
+            db.drop_tree("orders"),
+            Err(Error::TreeNotFound(_))
+        ));
+        assert_eq!(db.tree_names().unwrap(), vec!["users"]);
+        assert!(db.check().unwrap().is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dropped_trees_are_freed() {
+        let path = std::env::temp_dir().join(format!("johndb-drop-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let live_page_cnt =
+            |db: &Database| db.page_fetcher().page_cnt() - db.page_fetcher().free_page_cnt();
+        let mut db = Database::open(&path, Options::default()).unwrap();
+        db.put(b"main", b"entry").unwrap();
+        db.create_tree("kept", TreeOptions::default()).unwrap();
+        db.open_tree("kept").unwrap().put(b"a", b"1").unwrap();
+        let live = live_page_cnt(&db);
+
+        // Enough entries for more than one level, with every tenth value in overflow pages
+        let fill = |db: &Database| {
+            db.create_tree(
+                "big",
+                TreeOptions {
+                    inline_limit: 64,
+                    ..TreeOptions::default()
+                },
+            )
+            .unwrap();
+            let mut big = db.open_tree("big").unwrap();
+            for i in 0..5000u32 {
+                let value = match i % 10 {
+                    0 => vec![7; 200],
+                    _ => vec![7; 8],
+                };
+                big.put(&i.to_be_bytes(), &value).unwrap();
+            }
+            assert!(big.stats().unwrap().height() > 1);
+        };
+        fill(&db);
+        let filled = live_page_cnt(&db);
+        assert!(filled > live + 500, "{} {}", filled, live);
+
+        db.drop_tree("big").unwrap();
+        assert_eq!(live_page_cnt(&db), live);
+        assert!(db.check().unwrap().is_ok());
+
+        // The freed pages are reused rather than growing the file
+        fill(&db);
+        assert_eq!(db.page_fetcher().page_cnt(), filled);
+        db.drop_tree("big").unwrap();
+
+        // and dropped off its end once flushed
+        db.close().unwrap();
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            (live * PAGE_SIZE) as u64
+        );
+        let db = Database::open(&path, Options::default()).unwrap();
+        assert_eq!(db.tree_names().unwrap(), vec!["kept"]);
+        assert_eq!(
+            db.open_tree("kept").unwrap().get(b"a").unwrap(),
+            Some(b"1".to_vec())
+        );
+        assert!(db.check().unwrap().is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn independent_key_spaces() {
+        let path = std::env::temp_dir().join(format!("johndb-keyspaces-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let db = Database::open(&path, Options::default()).unwrap();
+
+        // Trees can be created while others are open, and the same key lives on in each
+        let mut trees = ["a", "b", "c"]
+            .iter()
+            .map(|name| db.tree(name).unwrap())
+            .collect::<Vec<_>>();
+        for i in 0..1000u32 {
+            for (idx, tree) in trees.iter_mut().enumerate() {
+                tree.put(&i.to_be_bytes(), tree.name().repeat(idx + 1).as_bytes())
+                    .unwrap();
+            }
+        }
+        trees[1].delete(&5u32.to_be_bytes()).unwrap();
+
+        let b = db.tree("b").unwrap();
+        assert_eq!(b.get(&5u32.to_be_bytes()).unwrap(), None);
+        assert_eq!(
+            trees[2].get(&5u32.to_be_bytes()).unwrap(),
+            Some(b"ccc".to_vec())
+        );
+        assert_eq!(b.stats().unwrap().entry_cnt(), 999);
+
+        // Every tree's pages are checked, on top of the catalog's and the main tree's
+        let report = db.check().unwrap();
+        assert!(report.is_ok(), "{:?}", report.violations);
+        let tree_pages = trees
+            .iter()
+            .map(|tree| {
+                tree.stats()
+                    .unwrap()
+                    .levels
+                    .iter()
+                    .map(|level| level.page_cnt)
+                    .sum::<usize>()
+            })
+            .sum::<usize>();
+        assert!(report.pages_checked > tree_pages);
+
+        drop(trees);
+        drop(b);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn schema_checked_on_put_and_open() {
+        let path = std::env::temp_dir().join(format!("johndb-schema-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let columns = Schema::new(vec![
+            Column {
+                name: "name".to_string(),
+                column_type: ColumnType::Text,
+                nullable: false,
+            },
+            Column {
+                name: "age".to_string(),
+                column_type: ColumnType::U32,
+                nullable: true,
+            },
+        ]);
+        let schema = TreeSchema {
+            key_type: ColumnType::U64,
+            columns: columns.clone(),
+            unique: true,
+        };
+        let row = columns
+            .encode(&[Field::Text("ann".to_string()), Field::Null])
+            .unwrap();
+        let key = 1u64.to_be_bytes();
+        {
+            let db = Database::open(&path, Options::default()).unwrap();
+            db.create_tree(
+                "people",
+                TreeOptions {
+                    schema: Some(schema.clone()),
+                    ..TreeOptions::default()
+                },
+            )
+            .unwrap();
+            let mut people = db.open_tree("people").unwrap();
+            people.put(&key, &row).unwrap();
+            assert!(matches!(people.put(&key, &row), Err(Error::DuplicateKey)));
+            assert!(matches!(
+                people.put(&1u32.to_be_bytes(), &row),
+                Err(Error::TypeMismatch(_))
+            ));
+            // A row with the same layout but a NULL name, written by a schema that allows it
+            let mut lax = columns.columns().to_vec();
+            lax[0].nullable = true;
+            let no_name = Schema::new(lax)
+                .encode(&[Field::Null, Field::U32(3)])
+                .unwrap();
+            assert!(matches!(
+                people.put(&2u64.to_be_bytes(), &no_name),
+                Err(Error::TypeMismatch(_))
+            ));
+            assert!(matches!(
+                people.put(&3u64.to_be_bytes(), b"junk"),
+                Err(Error::TypeMismatch(_))
+            ));
+            db.close().unwrap();
+        }
+
+        // The schema survives reopening, and is compared against the one the caller expects
+        let db = Database::open(&path, Options::default()).unwrap();
+        let people = db.open_tree_with_schema("people", &schema).unwrap();
+        assert_eq!(people.get(&key).unwrap(), Some(row));
+        let other = TreeSchema {
+            unique: false,
+            ..schema
+        };
+        assert!(matches!(
+            db.open_tree_with_schema("people", &other),
+            Err(Error::TypeMismatch(_))
+        ));
+
+        drop(people);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
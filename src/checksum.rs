@@ -0,0 +1,93 @@
+//! CRC-32 (IEEE 802.3, the polynomial used by zlib and PNG) for detecting torn or corrupted
+//! data written outside of pages, and CRC-32C (Castagnoli, as used by iSCSI and ext4) for the
+//! write-ahead log.
+
+const POLYNOMIAL: u32 = 0xEDB8_8320;
+const POLYNOMIAL_C: u32 = 0x82F6_3B78;
+
+const TABLE: [u32; 256] = build_table(POLYNOMIAL);
+const TABLE_C: [u32; 256] = build_table(POLYNOMIAL_C);
+
+const fn build_table(polynomial: u32) -> [u32; 256] {
+    let mut table = [0u32; 256];
+    let mut i = 0;
+    while i < 256 {
+        let mut crc = i as u32;
+        let mut bit = 0;
+        while bit < 8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ polynomial
+            } else {
+                crc >> 1
+            };
+            bit += 1;
+        }
+        table[i] = crc;
+        i += 1;
+    }
+    table
+}
+
+/// An incremental CRC-32, for checksumming data that's produced in pieces.
+#[derive(Debug, Clone)]
+pub struct Crc32 {
+    crc: u32,
+}
+
+impl Crc32 {
+    pub fn new() -> Self {
+        Crc32 { crc: !0 }
+    }
+
+    pub fn update(&mut self, bytes: &[u8]) {
+        self.crc = update(&TABLE, self.crc, bytes);
+    }
+
+    pub fn finish(&self) -> u32 {
+        !self.crc
+    }
+}
+
+impl Default for Crc32 {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+fn update(table: &[u32; 256], mut crc: u32, bytes: &[u8]) -> u32 {
+    for byte in bytes.iter() {
+        crc = table[((crc ^ *byte as u32) & 0xFF) as usize] ^ (crc >> 8);
+    }
+    crc
+}
+
+pub fn crc32(bytes: &[u8]) -> u32 {
+    let mut crc = Crc32::new();
+    crc.update(bytes);
+    crc.finish()
+}
+
+pub fn crc32c(bytes: &[u8]) -> u32 {
+    !update(&TABLE_C, !0, bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::crc32;
+    use super::crc32c;
+    use super::Crc32;
+
+    #[test]
+    fn known_values() {
+        assert_eq!(crc32(b""), 0);
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+
+        let mut crc = Crc32::new();
+        crc.update(b"1234");
+        crc.update(b"56789");
+        assert_eq!(crc.finish(), 0xCBF4_3926);
+
+        assert_eq!(crc32c(b""), 0);
+        assert_eq!(crc32c(b"123456789"), 0xE306_9283);
+    }
+}
@@ -0,0 +1,216 @@
+//! Compression for large values, see `Options::compression_threshold`.
+//!
+//! `Codec::Lz` is a small LZ77 variant in the spirit of LZ4's block format. The compressed bytes
+//! are a sequence of a token, literals and a match:
+//!
+//! ```text
+//! token: u8        high nibble: literal length, low nibble: match length - 4
+//! [u8]             literal length - 15 in 255s and a final remainder, if the nibble is 15
+//! literals
+//! offset: u16      big-endian distance back to the match, absent after the last literals
+//! [u8]             match length - 19 like the literal length, if the nibble is 15
+//! ```
+//!
+//! The last sequence only has literals, which may be empty.
+
+use crate::error::Error;
+use crate::error::Result;
+
+const MIN_MATCH: usize = 4;
+const MAX_OFFSET: usize = u16::MAX as usize;
+const HASH_BITS: u32 = 12;
+const NIBBLE_MAX: usize = 15;
+
+/// How a value was compressed, stored next to it so that the codec can change without breaking
+/// existing files.
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[repr(u8)]
+pub enum Codec {
+    Lz = 1,
+}
+
+impl Codec {
+    pub fn from_tag(tag: u8) -> Result<Self> {
+        match tag {
+            1 => Ok(Codec::Lz),
+            _ => Err(Error::corruption(format!("unknown codec {}", tag))),
+        }
+    }
+
+    pub fn compress(self, input: &[u8]) -> Vec<u8> {
+        match self {
+            Codec::Lz => lz_compress(input),
+        }
+    }
+
+    /// Decompresses `input`, which must decompress to exactly `size` bytes.
+    pub fn decompress(self, input: &[u8], size: usize) -> Result<Vec<u8>> {
+        match self {
+            Codec::Lz => lz_decompress(input, size),
+        }
+    }
+}
+
+fn hash(bytes: &[u8]) -> usize {
+    let word = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+    (word.wrapping_mul(2_654_435_761) >> (32 - HASH_BITS)) as usize
+}
+
+fn lz_compress(input: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(input.len() / 2 + 16);
+    // The last position plus one at which each hash was seen, 0 meaning never
+    let mut table = vec![0usize; 1 << HASH_BITS];
+    let mut literal_start = 0;
+    let mut pos = 0;
+    while pos + MIN_MATCH <= input.len() {
+        let hash = hash(&input[pos..]);
+        let candidate = table[hash];
+        table[hash] = pos + 1;
+
+        let start = match candidate {
+            0 => None,
+            candidate => Some(candidate - 1),
+        }
+        .filter(|&start| pos - start <= MAX_OFFSET)
+        .filter(|&start| input[start..start + MIN_MATCH] == input[pos..pos + MIN_MATCH]);
+        match start {
+            Some(start) => {
+                let mut len = MIN_MATCH;
+                while pos + len < input.len() && input[start + len] == input[pos + len] {
+                    len += 1;
+                }
+                write_sequence(
+                    &mut out,
+                    &input[literal_start..pos],
+                    Some((pos - start, len)),
+                );
+                pos += len;
+                literal_start = pos;
+            }
+            None => pos += 1,
+        }
+    }
+    write_sequence(&mut out, &input[literal_start..], None);
+    out
+}
+
+fn write_sequence(out: &mut Vec<u8>, literals: &[u8], matched: Option<(usize, usize)>) {
+    let match_len = matched.map(|(_, len)| len - MIN_MATCH).unwrap_or(0);
+    out.push((literals.len().min(NIBBLE_MAX) << 4 | match_len.min(NIBBLE_MAX)) as u8);
+    write_length(out, literals.len());
+    out.extend_from_slice(literals);
+    if let Some((offset, _)) = matched {
+        out.extend_from_slice(&(offset as u16).to_be_bytes());
+        write_length(out, match_len);
+    }
+}
+
+fn write_length(out: &mut Vec<u8>, len: usize) {
+    if len < NIBBLE_MAX {
+        return;
+    }
+    let mut rest = len - NIBBLE_MAX;
+    while rest >= 255 {
+        out.push(255);
+        rest -= 255;
+    }
+    out.push(rest as u8);
+}
+
+fn lz_decompress(mut input: &[u8], size: usize) -> Result<Vec<u8>> {
+    let mut out = Vec::with_capacity(size);
+    loop {
+        let token = take(&mut input, 1)?[0] as usize;
+        let literal_len = read_length(&mut input, token >> 4)?;
+        if out.len() + literal_len > size {
+            return Err(malformed());
+        }
+        out.extend_from_slice(take(&mut input, literal_len)?);
+        if input.is_empty() {
+            break;
+        }
+
+        let offset = take(&mut input, 2)?;
+        let offset = u16::from_be_bytes([offset[0], offset[1]]) as usize;
+        let match_len = read_length(&mut input, token & 0xF)? + MIN_MATCH;
+        if offset == 0 || offset > out.len() || out.len() + match_len > size {
+            return Err(malformed());
+        }
+        // Matches may overlap the bytes they produce, so they're copied one byte at a time
+        let start = out.len() - offset;
+        for i in start..start + match_len {
+            out.push(out[i]);
+        }
+    }
+
+    match out.len() == size {
+        true => Ok(out),
+        false => Err(malformed()),
+    }
+}
+
+fn read_length(input: &mut &[u8], nibble: usize) -> Result<usize> {
+    let mut len = nibble;
+    if nibble == NIBBLE_MAX {
+        loop {
+            let byte = take(input, 1)?[0];
+            len += byte as usize;
+            if byte != 255 {
+                break;
+            }
+        }
+    }
+    Ok(len)
+}
+
+fn take<'a>(input: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if input.len() < len {
+        return Err(malformed());
+    }
+    let (taken, rest) = input.split_at(len);
+    *input = rest;
+    Ok(taken)
+}
+
+fn malformed() -> Error {
+    Error::corruption("malformed compressed value".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Codec;
+
+    #[test]
+    fn round_trip() {
+        let json = (0..500)
+            .map(|i| {
+                format!(
+                    "{{\"id\":{},\"name\":\"user-{}\",\"active\":true}}",
+                    i,
+                    i % 7
+                )
+            })
+            .collect::<String>();
+        let inputs = vec![
+            Vec::new(),
+            b"abc".to_vec(),
+            vec![7; 100000],
+            (0..70000u32).map(|i| (i * 31 % 251) as u8).collect(),
+            json.into_bytes(),
+        ];
+        for input in inputs {
+            let compressed = Codec::Lz.compress(&input);
+            assert_eq!(
+                Codec::Lz.decompress(&compressed, input.len()).unwrap(),
+                input
+            );
+        }
+
+        let compressed = Codec::Lz.compress(&[7; 100000]);
+        assert!(compressed.len() < 500);
+        assert!(Codec::Lz.decompress(&compressed, 99999).is_err());
+        assert!(Codec::Lz
+            .decompress(&compressed[..compressed.len() - 1], 100000)
+            .is_err());
+    }
+}
@@ -0,0 +1,223 @@
+//! A crash consistency harness for the file backend, in the spirit of CrashMonkey and ALICE. A
+//! workload runs against a `Database` while its file's writes are recorded, see
+//! `FilePageFetcher::record_writes`. Every prefix of those writes is then replayed onto a copy of
+//! the file as it was before the workload, as if the process had died right after them, and the
+//! copy is opened and checked.
+//!
+//! Writes are replayed in the order they were issued and each one lands whole, so torn pages and
+//! reordering by the disk aren't explored.
+//!
+//! Files written in place only recover intact at the points where a flush completed, while
+//! shadow paged ones, see `crate::shadow`, must recover to the last completed flush from anywhere.
+
+use crate::database::Database;
+use crate::database::Options;
+use crate::error::Error;
+use crate::error::Result;
+use crate::file_page_fetcher::FileWrite;
+use log::debug;
+use std::collections::BTreeMap;
+use std::fs;
+use std::path::Path;
+
+type Contents = BTreeMap<Vec<u8>, Vec<u8>>;
+
+#[derive(Debug, Clone)]
+enum Step {
+    Put(Vec<u8>, Vec<u8>),
+    Delete(Vec<u8>),
+    Flush,
+}
+
+/// What a restart found after a crash partway through a workload.
+#[derive(Debug)]
+struct CrashPoint {
+    /// Writes that reached the file.
+    writes: usize,
+    /// Syncs that had completed. Shadow paged flushes sync twice.
+    syncs: usize,
+    /// Whether the crash came right after a sync.
+    at_sync: bool,
+    /// Which flush the recovered database holds exactly what was flushed by, 0 being the empty
+    /// database before the first one.
+    recovered: Option<usize>,
+    /// Why recovery failed: the database didn't open or failed `Database::check`.
+    error: Option<String>,
+}
+
+fn options(shadow_paging: bool) -> Options {
+    Options {
+        create_if_missing: false,
+        autovacuum_threshold: None,
+        shadow_paging,
+        ..Options::default()
+    }
+}
+
+/// Runs `steps` against a new database in `dir` and recovers from a crash after each of its
+/// writes in turn, returning one `CrashPoint` per prefix of the writes, starting with the empty
+/// one.
+fn explore(dir: &Path, steps: &[Step], shadow_paging: bool) -> Result<Vec<CrashPoint>> {
+    let _ = fs::remove_dir_all(dir);
+    fs::create_dir_all(dir)?;
+    let path = dir.join("workload.db");
+    let mut db = Database::open(
+        &path,
+        Options {
+            create_if_missing: true,
+            ..options(shadow_paging)
+        },
+    )?;
+    let mut image = fs::read(&path)?;
+
+    // The contents as of each flush, starting with the empty database
+    let mut model = Contents::new();
+    let mut flushed = vec![model.clone()];
+    db.page_fetcher().record_writes();
+    for step in steps {
+        match step {
+            Step::Put(key, value) => {
+                db.put(key, value)?;
+                model.insert(key.clone(), value.clone());
+            }
+            Step::Delete(key) => {
+                db.delete(key)?;
+                model.remove(key);
+            }
+            Step::Flush => {
+                db.flush()?;
+                flushed.push(model.clone());
+            }
+        }
+    }
+    let writes = db.page_fetcher().take_writes();
+    drop(db);
+
+    let crash_path = dir.join("crash.db");
+    let mut syncs = 0;
+    let mut points = Vec::with_capacity(writes.len() + 1);
+    for idx in 0..=writes.len() {
+        let at_sync = match idx.checked_sub(1).map(|last| &writes[last]) {
+            None => true,
+            Some(FileWrite::Write { offset, data }) => {
+                let offset = *offset as usize;
+                if image.len() < offset + data.len() {
+                    image.resize(offset + data.len(), 0);
+                }
+                image[offset..offset + data.len()].copy_from_slice(data);
+                false
+            }
+            Some(FileWrite::SetLen(len)) => {
+                image.resize(*len as usize, 0);
+                false
+            }
+            Some(FileWrite::Sync) => {
+                syncs += 1;
+                true
+            }
+        };
+        fs::write(&crash_path, &image)?;
+        let (recovered, error) = match recover(&crash_path, shadow_paging) {
+            Ok(found) => (flushed.iter().position(|contents| *contents == found), None),
+            Err(err) => (None, Some(err.to_string())),
+        };
+        points.push(CrashPoint {
+            writes: idx,
+            syncs,
+            at_sync,
+            recovered,
+            error,
+        });
+    }
+
+    fs::remove_dir_all(dir)?;
+    Ok(points)
+}
+
+/// Opens and checks the database at `path`, returning its contents.
+fn recover(path: &Path, shadow_paging: bool) -> Result<Contents> {
+    let db = Database::open(path, options(shadow_paging))?;
+    let report = db.check()?;
+    if !report.is_ok() {
+        return Err(Error::corruption(format!("{:?}", report.violations)));
+    }
+    let contents = db.range(..)?.collect::<Result<Contents>>()?;
+    Ok(contents)
+}
+
+/// Fills leaves enough to split them, with every tenth value in overflow pages, then deletes and
+/// replaces some entries, flushing after each phase.
+fn workload() -> Vec<Step> {
+    let key = |i: u32| format!("key-{:04}", i).into_bytes();
+    let mut steps = Vec::new();
+    // Enough entries to split leaves, with every tenth value in overflow pages
+    for i in 0..400 {
+        let len = match i % 10 {
+            0 => 3000,
+            _ => 20 + i as usize % 50,
+        };
+        steps.push(Step::Put(key(i), vec![i as u8; len]));
+    }
+    steps.push(Step::Flush);
+    for i in (0..400).step_by(3) {
+        steps.push(Step::Delete(key(i)));
+    }
+    steps.push(Step::Put(key(7), b"replaced".to_vec()));
+    steps.push(Step::Flush);
+    // Dropping the overflow values frees pages at the end of the file, which truncates it
+    for i in (0..400).filter(|i| i % 10 == 0) {
+        steps.push(Step::Delete(key(i)));
+    }
+    steps.push(Step::Flush);
+    steps
+}
+
+#[test]
+fn flushed_states_survive_crashes() {
+    let dir = std::env::temp_dir().join(format!("johndb-crash-{}", std::process::id()));
+    let points = explore(&dir, &workload(), false).unwrap();
+
+    let synced = points
+        .iter()
+        .filter(|point| point.at_sync)
+        .collect::<Vec<_>>();
+    assert_eq!(
+        synced.iter().map(|point| point.syncs).collect::<Vec<_>>(),
+        [0, 1, 2, 3]
+    );
+    for point in synced {
+        assert_eq!(point.recovered, Some(point.syncs), "{:?}", point);
+    }
+    // Flushes overwrite pages in place, so a crash partway through one can leave a mix of old
+    // and new pages behind
+    let torn = points
+        .iter()
+        .filter(|point| point.error.is_some())
+        .collect::<Vec<_>>();
+    debug!(
+        "{} of {} crash points weren't recoverable, the first after {} writes: {:?}",
+        torn.len(),
+        points.len(),
+        torn.first().map_or(0, |point| point.writes),
+        torn.first().and_then(|point| point.error.as_ref())
+    );
+}
+
+#[test]
+fn shadow_paging_survives_every_crash() {
+    let dir = std::env::temp_dir().join(format!("johndb-crash-shadow-{}", std::process::id()));
+    let points = explore(&dir, &workload(), true).unwrap();
+
+    let mut last = 0;
+    for point in points.iter() {
+        assert!(point.error.is_none(), "{:?}", point);
+        let recovered = point.recovered.unwrap_or_else(|| panic!("{:?}", point));
+        assert!(recovered == last || recovered == last + 1, "{:?}", point);
+        // A flush syncs its pages and then its meta page
+        if point.at_sync && point.syncs % 2 == 0 {
+            assert_eq!(recovered, point.syncs / 2, "{:?}", point);
+        }
+        last = recovered;
+    }
+    assert_eq!(last, 3);
+}
@@ -0,0 +1,1786 @@
+use crate::btree::analyze::TreeStats;
+use crate::btree::integrity::IntegrityReport;
+use crate::btree::key::KeyBytes;
+use crate::btree::overflow::BlobReader;
+use crate::btree::scan::RangeIter;
+use crate::btree::stats::KeyStats;
+use crate::btree::vacuum::Vacuum;
+use crate::btree::vacuum::VacuumStats;
+use crate::btree::value::ValueBytes;
+use crate::btree::verify::VerifyReport;
+use crate::btree::BTree;
+use crate::catalog::Catalog;
+use crate::catalog::NamedTree;
+use crate::catalog::TreeOptions;
+use crate::catalog::TreeSchema;
+use crate::compress::Codec;
+use crate::error::Error;
+use crate::error::Result;
+use crate::events::EventListener;
+use crate::export::DumpReader;
+use crate::export::DumpWriter;
+use crate::file_page_fetcher::FilePageFetcher;
+use crate::metrics::HotPage;
+use crate::metrics::MemoryUsage;
+use crate::metrics::MetricsSnapshot;
+use crate::page::PAGE_DATA_SIZE;
+use crate::page_fetcher::InMemoryPageFetcher;
+use crate::page_fetcher::PageFetcher;
+use crate::planner::Histogram;
+use crate::shadow::DirtyPages;
+use crate::shadow::ShadowState;
+use crate::snapshot::read_snapshot;
+use crate::snapshot::write_snapshot;
+use crate::sst::SstReader;
+use crate::sst::SstWriter;
+use log::debug;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Seek;
+use std::io::Write;
+use std::mem::size_of;
+use std::ops::Bound;
+use std::ops::RangeBounds;
+use std::path::Path;
+use std::sync::Arc;
+
+/// Every user key is stored behind this prefix so that it sorts below `KeyBytes::max_key()`, even
+/// if it starts with `0xFF` bytes.
+const KEY_PREFIX: u8 = 0x00;
+
+/// Upper bound on the combined key and value size stored inline in a leaf, leaving room for at
+/// least a few items per page so a split always has something to move. Larger values are moved to
+/// overflow pages.
+pub(crate) const MAX_ITEM_SIZE: usize = PAGE_DATA_SIZE / 4;
+
+/// Stored values start with one of these tags. Inline values follow the tag directly, while
+/// overflow values store the big-endian number of the first overflow page. Compressed values
+/// store their codec and big-endian uncompressed size, followed by the compressed bytes stored
+/// like any other value, i.e. inline or in overflow pages. Blobs store the big-endian number of
+/// their first page and their big-endian u64 size, see `Database::put_blob`.
+const VALUE_INLINE: u8 = 0;
+const VALUE_OVERFLOW: u8 = 1;
+const VALUE_COMPRESSED: u8 = 2;
+const VALUE_BLOB: u8 = 3;
+const OVERFLOW_VALUE_SIZE: usize = 1 + size_of::<u32>();
+const COMPRESSED_HEADER_SIZE: usize = 2 + size_of::<u32>();
+const BLOB_VALUE_SIZE: usize = 1 + size_of::<u32>() + size_of::<u64>();
+
+/// Type names tagging `Database::dump` streams. Entries hold the user's keys and values, with
+/// overflow values inlined, rather than the tree's internal representation.
+const DUMP_KEY_TYPE: &str = "johndb::Database key";
+const DUMP_VALUE_TYPE: &str = "johndb::Database value";
+
+/// The default for `Options::autovacuum_threshold` and `TreeOptions::autovacuum_threshold`.
+pub const DEFAULT_AUTOVACUUM_THRESHOLD: u64 = 10_000;
+
+/// Fraction of a tree's leaves sampled to refresh its statistics after it's been autovacuumed.
+const AUTOVACUUM_STATS_SAMPLE_RATE: f64 = 0.1;
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    /// Create the database file if it doesn't exist yet.
+    pub create_if_missing: bool,
+    /// Maximum number of pages kept in memory, which also bounds the size of the file.
+    pub max_pages: usize,
+    /// Bytes of memory the database may hold, see `Database::memory_usage`. Once it's reached,
+    /// writes needing a new page fail with `Error::MemoryBudgetExceeded`, as does opening a file
+    /// that doesn't fit.
+    pub memory_budget: Option<usize>,
+    /// Values of at least this many bytes are compressed, unless that doesn't make them smaller.
+    /// Compressed values are read back the same way regardless of this option.
+    pub compression_threshold: Option<usize>,
+    /// Open the file with `O_DIRECT`, so its pages aren't cached by the OS on top of the
+    /// database's own copy. Only supported on Linux.
+    pub direct_io: bool,
+    /// Once this many entries were deleted or replaced since the database's own tree was last
+    /// vacuumed, the write that crosses it vacuums the tree and refreshes its statistics, see
+    /// `BTree::dead_item_cnt` and `Database::key_stats`. `None` leaves vacuuming to
+    /// `Database::vacuum`.
+    pub autovacuum_threshold: Option<u64>,
+    /// Create the file shadow paged, see `crate::shadow`: flushes write the pages that changed
+    /// elsewhere in the file and then switch over to them at once, so a crash never leaves a mix
+    /// of old and new pages behind, at the cost of a larger file. A file that already exists
+    /// keeps the mode it was created with.
+    pub shadow_paging: bool,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options {
+            create_if_missing: true,
+            max_pages: 1024,
+            memory_budget: None,
+            compression_threshold: None,
+            direct_io: false,
+            autovacuum_threshold: Some(DEFAULT_AUTOVACUUM_THRESHOLD),
+            shadow_paging: false,
+        }
+    }
+}
+
+/// A summary of a database's contents, as reported by `Database::stats`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Stats {
+    pub page_cnt: usize,
+    pub key_cnt: usize,
+    pub tree: TreeStats,
+}
+
+/// Progress of vacuuming a database with `Database::vacuum_step`.
+pub struct DatabaseVacuum {
+    /// 0 for the database's own tree, 1 for the catalog, and then one per named tree in name
+    /// order.
+    tree_idx: usize,
+    tree: Vacuum,
+    /// Totals of the trees already done.
+    done: VacuumStats,
+}
+
+impl DatabaseVacuum {
+    pub fn new() -> Self {
+        DatabaseVacuum {
+            tree_idx: 0,
+            tree: Vacuum::new(),
+            done: VacuumStats::default(),
+        }
+    }
+
+    /// Totals over every tree vacuumed so far.
+    pub fn stats(&self) -> VacuumStats {
+        let mut stats = self.done;
+        stats.add(&self.tree.stats());
+        stats
+    }
+
+    fn next_tree(&mut self) {
+        self.done.add(&self.tree.stats());
+        self.tree = Vacuum::new();
+        self.tree_idx += 1;
+    }
+}
+
+impl Default for DatabaseVacuum {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+pub(crate) type Tree<P = FilePageFetcher> = BTree<KeyBytes, ValueBytes, P>;
+
+/// An ordered key/value store of byte strings, backed by a single file.
+///
+/// Changes are only durable once `flush` or `close` returns.
+pub struct Database {
+    btree: Tree,
+    compression_threshold: Option<usize>,
+    autovacuum_threshold: Option<u64>,
+}
+
+impl Database {
+    /// Opens the database at `path`. A missing or empty file is initialized first if
+    /// `Options::create_if_missing` is set, and is an error otherwise, as is a file whose length
+    /// isn't a whole number of pages.
+    pub fn open<P: AsRef<Path>>(path: P, options: Options) -> Result<Self> {
+        let path = path.as_ref();
+        let initialized = match std::fs::metadata(path) {
+            Ok(metadata) => metadata.len() > 0,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => false,
+            Err(err) => return Err(err.into()),
+        };
+        if !initialized && options.create_if_missing {
+            init_file(path, options.shadow_paging)?;
+        }
+
+        let page_fetcher = FilePageFetcher::open(
+            path,
+            false,
+            options.max_pages,
+            options.memory_budget,
+            options.direct_io,
+        )?;
+        if page_fetcher.page_cnt() == 0 {
+            return Err(Error::corruption(format!(
+                "{} is empty, with no metadata page",
+                path.display()
+            )));
+        }
+        Ok(Database {
+            btree: BTree::open(page_fetcher, 0)?,
+            compression_threshold: options.compression_threshold,
+            autovacuum_threshold: options.autovacuum_threshold,
+        })
+    }
+
+    /// Sets `key` to `value`, replacing any existing value.
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        put_entry(
+            &mut self.btree,
+            MAX_ITEM_SIZE,
+            self.compression_threshold,
+            key,
+            value,
+        )?;
+        autovacuum(&mut self.btree, self.autovacuum_threshold)
+    }
+
+    pub fn get(&self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        get_entry(&self.btree, key)
+    }
+
+    /// Sets `key` to the bytes read from `reader`, replacing any existing value. The bytes are
+    /// streamed into overflow pages a page at a time, each with its own checksum, so values of
+    /// many megabytes never need to be held in memory. Blobs aren't compressed. Returns the
+    /// number of bytes stored.
+    ///
+    /// Blobs are read like any other value, or streamed back with `get_blob`.
+    pub fn put_blob<R: Read>(&mut self, key: &[u8], reader: R) -> Result<u64> {
+        let key_size = key.len() + 1;
+        if key_size + BLOB_VALUE_SIZE > MAX_ITEM_SIZE {
+            return Err(Error::ItemTooLarge(key_size + BLOB_VALUE_SIZE));
+        }
+
+        let (first_page_no, size) = self.btree.write_blob(reader)?;
+        let mut stored = Vec::with_capacity(BLOB_VALUE_SIZE);
+        stored.push(VALUE_BLOB);
+        stored.extend_from_slice(&first_page_no.to_be_bytes());
+        stored.extend_from_slice(&size.to_be_bytes());
+        replace_value(&mut self.btree, key, ValueBytes { value: stored })?;
+        autovacuum(&mut self.btree, self.autovacuum_threshold)?;
+        Ok(size)
+    }
+
+    /// Returns a reader over the value stored under `key`. Blobs stored by `put_blob` are
+    /// streamed out of their pages as they're read, verifying each page's checksum, while other
+    /// values are loaded up front.
+    pub fn get_blob(&self, key: &[u8]) -> Result<Option<Blob<'_>>> {
+        let stored = match self.btree.search(to_internal_key(key))?.value {
+            Some(stored) => stored.value,
+            None => return Ok(None),
+        };
+        let blob = match decode_blob(&stored)? {
+            Some((first_page_no, size)) => Blob::Stream {
+                size,
+                reader: self.btree.blob_reader(first_page_no, size),
+            },
+            None => Blob::Loaded(io::Cursor::new(decode_value(&self.btree, &stored)?)),
+        };
+        Ok(Some(blob))
+    }
+
+    /// Removes `key`, returning its previous value if there was one.
+    pub fn delete(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        let value = delete_entry(&mut self.btree, key)?;
+        autovacuum(&mut self.btree, self.autovacuum_threshold)?;
+        Ok(value)
+    }
+
+    /// The statistics last collected over the database's own entries, see
+    /// `BTree::collect_stats`. They're refreshed whenever the tree is autovacuumed.
+    pub fn key_stats(&self) -> Result<Option<KeyStats<Vec<u8>>>> {
+        key_stats(&self.btree)
+    }
+
+    /// Entries deleted or replaced since the database's own tree was last vacuumed, see
+    /// `Options::autovacuum_threshold`.
+    pub fn dead_entry_cnt(&self) -> Result<u64> {
+        self.btree.dead_item_cnt()
+    }
+
+    /// Iterates over the entries whose keys fall within `range`, in ascending key order.
+    pub fn range<'a, R>(&self, range: R) -> Result<Range<'_>>
+    where
+        R: RangeBounds<&'a [u8]>,
+    {
+        range_entries(&self.btree, range)
+    }
+
+    /// Creates an empty tree called `name`, stored in the same file next to the database's own
+    /// entries and any other named trees. Fails with `Error::TreeExists` if there already is one.
+    ///
+    /// Like writes through a `NamedTree`, this only needs a shared reference, so trees can be
+    /// created while others are open.
+    pub fn create_tree(&self, name: &str, options: TreeOptions) -> Result<()> {
+        Catalog::attached_to(&self.btree, true)?
+            .ok_or_else(|| Error::corruption("catalog wasn't created".to_string()))?
+            .create_tree(name, options)
+    }
+
+    /// Opens the tree called `name`, failing with `Error::TreeNotFound` if there's none.
+    pub fn open_tree(&self, name: &str) -> Result<NamedTree<'_>> {
+        match Catalog::attached_to(&self.btree, false)? {
+            Some(catalog) => catalog.open_tree(name),
+            None => Err(Error::TreeNotFound(name.to_string())),
+        }
+    }
+
+    /// Opens the tree called `name`, failing with `Error::TypeMismatch` unless it was created
+    /// with `schema`. Use this where the code relies on the tree's layout, so that a tree created
+    /// by a different version of it is caught before any of its entries are read.
+    pub fn open_tree_with_schema(&self, name: &str, schema: &TreeSchema) -> Result<NamedTree<'_>> {
+        match Catalog::attached_to(&self.btree, false)? {
+            Some(catalog) => catalog.open_tree_with_schema(name, schema),
+            None => Err(Error::TreeNotFound(name.to_string())),
+        }
+    }
+
+    /// Opens the tree called `name`, creating it with the default `TreeOptions` first if there's
+    /// none.
+    pub fn tree(&self, name: &str) -> Result<NamedTree<'_>> {
+        let catalog = Catalog::attached_to(&self.btree, true)?
+            .ok_or_else(|| Error::corruption("catalog wasn't created".to_string()))?;
+        match catalog.open_tree(name) {
+            Err(Error::TreeNotFound(_)) => {
+                self.create_tree(name, TreeOptions::default())?;
+                self.open_tree(name)
+            }
+            result => result,
+        }
+    }
+
+    /// Removes the tree called `name` from the catalog and returns its pages to the free list,
+    /// failing with `Error::TreeNotFound` if there's none.
+    pub fn drop_tree(&mut self, name: &str) -> Result<()> {
+        match Catalog::attached_to(&self.btree, false)? {
+            Some(mut catalog) => catalog.drop_tree(name),
+            None => Err(Error::TreeNotFound(name.to_string())),
+        }
+    }
+
+    /// The names of every tree created with `create_tree`, in ascending order.
+    pub fn tree_names(&self) -> Result<Vec<String>> {
+        match Catalog::attached_to(&self.btree, false)? {
+            Some(catalog) => catalog.names(),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    pub fn stats(&self) -> Result<Stats> {
+        let tree = self.btree.analyze()?;
+        Ok(Stats {
+            page_cnt: self.btree.page_fetcher().page_cnt(),
+            key_cnt: tree.entry_cnt(),
+            tree,
+        })
+    }
+
+    /// Vacuums the database's tree, the catalog and every named tree, see `Vacuum`, returning
+    /// what was done.
+    pub fn vacuum(&mut self) -> Result<VacuumStats> {
+        let mut vacuum = DatabaseVacuum::new();
+        self.vacuum_step(&mut vacuum, usize::MAX)?;
+        Ok(vacuum.stats())
+    }
+
+    /// Advances `vacuum` by about `max_pages` pages, returning whether every tree is done. See
+    /// `BTree::vacuum_step`.
+    pub fn vacuum_step(&mut self, vacuum: &mut DatabaseVacuum, max_pages: usize) -> Result<bool> {
+        let start = vacuum.stats().pages_scanned;
+        loop {
+            let scanned = vacuum.stats().pages_scanned - start;
+            if scanned >= max_pages.max(1) {
+                return Ok(false);
+            }
+
+            let max_pages = max_pages - scanned;
+            let done = match vacuum.tree_idx {
+                0 => Some(self.btree.vacuum_step(&mut vacuum.tree, max_pages)?),
+                tree_idx => match Catalog::attached_to(&self.btree, false)? {
+                    Some(mut catalog) => {
+                        catalog.vacuum_step(tree_idx - 1, &mut vacuum.tree, max_pages)?
+                    }
+                    None => None,
+                },
+            };
+            match done {
+                Some(true) => vacuum.next_tree(),
+                Some(false) => {}
+                None => return Ok(true),
+            }
+        }
+    }
+
+    /// Registers `listener` to be told about splits, new roots and checkpoints, see
+    /// `EventListener`.
+    pub fn add_listener(&mut self, listener: Arc<dyn EventListener>) {
+        self.btree.add_listener(listener);
+    }
+
+    /// A snapshot of the counters tracking page accesses, splits and lock contention since the
+    /// database was opened.
+    pub fn metrics(&self) -> MetricsSnapshot {
+        self.btree.page_fetcher().metrics().snapshot()
+    }
+
+    /// The `n` most accessed pages since the database was opened, busiest first. Comparing
+    /// them against `dump_page` shows whether e.g. the root or a single leaf is a hotspot.
+    pub fn hot_pages(&self, n: usize) -> Vec<HotPage> {
+        self.btree.page_fetcher().hot_pages(n)
+    }
+
+    /// Halves the access counts behind `hot_pages`, so that calling it periodically weighs
+    /// recent accesses more than old ones.
+    pub fn decay_page_access(&self) {
+        self.btree.page_fetcher().decay_page_access()
+    }
+
+    /// Bytes of memory held by the database's pages and their bookkeeping.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.btree.page_fetcher().memory_usage()
+    }
+
+    /// Verifies the structure of the database's tree, the catalog and every named tree, see
+    /// `BTree::verify`, merging their reports. If they're all intact, every value is also read
+    /// back, including its overflow chain, failing with the first unreadable one.
+    pub fn check(&self) -> Result<VerifyReport> {
+        let mut report = self.btree.verify()?;
+        let mut named_trees = Vec::new();
+        if let Some(catalog) = Catalog::attached_to(&self.btree, false)? {
+            merge_report(&mut report, catalog.verify()?);
+            for name in catalog.names()? {
+                let tree = catalog.open_tree(&name)?;
+                merge_report(&mut report, tree.verify()?);
+                named_trees.push(tree);
+            }
+        }
+
+        if report.is_ok() {
+            for entry in self.range(..)? {
+                entry?;
+            }
+            for tree in named_trees.iter() {
+                for entry in tree.range(..)? {
+                    entry?;
+                }
+            }
+        }
+        Ok(report)
+    }
+
+    /// Like `check`, but reports each violation with its offending keys and a suggested
+    /// remediation, see `BTree::check_invariants`.
+    pub fn check_invariants(&self) -> Result<IntegrityReport> {
+        self.check().map(IntegrityReport::from)
+    }
+
+    /// Writes every entry to `writer` in the portable format described in `crate::export`,
+    /// returning the number of entries written.
+    pub fn dump<W: Write>(&self, writer: W) -> Result<u64> {
+        let mut dump = DumpWriter::new(writer, DUMP_KEY_TYPE, DUMP_VALUE_TYPE)?;
+        for entry in self.range(..)? {
+            let (key, value) = entry?;
+            dump.write_entry(&key, &value)?;
+        }
+        dump.finish()
+    }
+
+    /// Puts every entry of a stream written by `dump`, replacing existing values. Returns the
+    /// number of entries loaded.
+    pub fn load<R: Read>(&mut self, reader: R) -> Result<u64> {
+        let mut dump = DumpReader::new(reader)?;
+        if dump.key_type() != DUMP_KEY_TYPE || dump.value_type() != DUMP_VALUE_TYPE {
+            return Err(Error::TypeMismatch(format!(
+                "dump holds ({}, {}), not a database's entries",
+                dump.key_type(),
+                dump.value_type()
+            )));
+        }
+
+        let mut prev_key: Option<Vec<u8>> = None;
+        let mut cnt = 0;
+        while let Some((key, value)) = dump.next_entry()? {
+            if prev_key.as_ref().is_some_and(|prev_key| *prev_key >= key) {
+                return Err(Error::corruption(format!(
+                    "dump key {:?} is out of order",
+                    key
+                )));
+            }
+            self.put(&key, &value)?;
+            prev_key = Some(key);
+            cnt += 1;
+        }
+        Ok(cnt)
+    }
+
+    /// Writes the entries whose keys fall within `range` to `writer` as a sorted file, see
+    /// `crate::sst`. Returns the number of entries written.
+    pub fn export_sst<'a, R, W>(&self, range: R, writer: W) -> Result<u64>
+    where
+        R: RangeBounds<&'a [u8]>,
+        W: Write,
+    {
+        let mut sst = SstWriter::new(writer);
+        for entry in self.range(range)? {
+            let (key, value) = entry?;
+            sst.write_entry(&key, &value)?;
+        }
+        sst.finish()
+    }
+
+    /// Loads every entry of a sorted file, see `crate::sst`, into the database by building its
+    /// tree's leaves directly, which is much faster than putting the entries one by one. The
+    /// database must not have any entries of its own yet, otherwise this fails with
+    /// `Error::NotEmpty`. Returns the number of entries loaded.
+    pub fn ingest_sst<R: Read + Seek>(&mut self, reader: R) -> Result<u64> {
+        let mut sst = SstReader::open(reader)?;
+        let btree = &self.btree;
+        let compression_threshold = self.compression_threshold;
+        let entries = sst.iter().map(|entry| {
+            let (key, value) = entry?;
+            let stored = store_value(btree, MAX_ITEM_SIZE, compression_threshold, &key, &value)?;
+            Ok((to_internal_key(&key), stored))
+        });
+        btree.bulk_load(entries)
+    }
+
+    /// Writes the database's current state to `path` as a single checksummed file, see
+    /// `crate::snapshot`, which `import_snapshot` turns back into a database. Nothing can modify
+    /// the database while this borrows it, so the snapshot is a consistent point in time, and
+    /// unlike copying the database's file it includes changes that haven't been flushed yet.
+    ///
+    /// The snapshot is written next to `path` and only moved there once it's complete, so a
+    /// failed export never leaves a partial snapshot behind.
+    pub fn export_snapshot<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        let page_fetcher = self.btree.page_fetcher();
+        write_atomically(path.as_ref(), |file| {
+            write_snapshot(page_fetcher, page_fetcher.page_cnt(), BufWriter::new(file))
+        })
+    }
+
+    /// Creates the database at `path` from a snapshot written by `export_snapshot`, checking
+    /// every page's checksum first, and opens it. Fails if there's already a file at `path`.
+    pub fn import_snapshot<S, P>(snapshot_path: S, path: P, options: Options) -> Result<Self>
+    where
+        S: AsRef<Path>,
+        P: AsRef<Path>,
+    {
+        let path = path.as_ref();
+        if path.exists() {
+            return Err(io::Error::new(
+                io::ErrorKind::AlreadyExists,
+                format!("{} already exists", path.display()),
+            )
+            .into());
+        }
+        let snapshot = File::open(snapshot_path)?;
+        write_atomically(path, |file| {
+            read_snapshot(BufReader::new(snapshot), BufWriter::new(file)).map(|_| ())
+        })?;
+        Database::open(path, options)
+    }
+
+    /// Pretty-prints page `page_no`, see `BTree::dump_page`. Keys show up with their internal
+    /// prefix byte and values with their tag byte.
+    pub fn dump_page(&self, page_no: u32, hex: bool) -> Result<String> {
+        self.btree.dump_page(page_no, hex)
+    }
+
+    pub fn flush(&self) -> Result<()> {
+        self.btree.page_fetcher().flush()?;
+        let page_cnt = self.btree.page_fetcher().page_cnt();
+        self.btree
+            .notify(|listener| listener.on_checkpoint(page_cnt));
+        Ok(())
+    }
+
+    /// Flushes all pages to disk and closes the file.
+    pub fn close(self) -> Result<()> {
+        self.flush()
+    }
+
+    /// The file backend, for crash tests to record its writes.
+    #[cfg(test)]
+    pub(crate) fn page_fetcher(&self) -> &FilePageFetcher {
+        self.btree.page_fetcher()
+    }
+}
+
+/// Writes a file at `path` with `write` through a temporary file next to it, which is synced and
+/// then renamed into place, or removed if writing fails.
+fn write_atomically<F>(path: &Path, write: F) -> Result<()>
+where
+    F: FnOnce(&File) -> Result<()>,
+{
+    let mut tmp_path = path.as_os_str().to_owned();
+    tmp_path.push(".tmp");
+    let file = File::create(&tmp_path)?;
+    let result = write(&file)
+        .and_then(|()| Ok(file.sync_all()?))
+        .and_then(|()| Ok(std::fs::rename(&tmp_path, path)?));
+    if result.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    result
+}
+
+/// Writes an empty database to `path` through `write_atomically`, so that its metadata page is
+/// either there in full or the file isn't.
+fn init_file(path: &Path, shadow_paging: bool) -> Result<()> {
+    let btree: Tree<InMemoryPageFetcher> = BTree::new(InMemoryPageFetcher::new())?;
+    let pages = btree.page_fetcher();
+    write_atomically(path, |mut file| {
+        if shadow_paging {
+            return ShadowState::new()
+                .commit(pages, &DirtyPages::new(0), file)
+                .map(|_| ());
+        }
+        for page_no in 0..pages.page_cnt() {
+            file.write_all(pages.fetch_page_read(page_no as u32)?.as_bytes())?;
+        }
+        Ok(())
+    })?;
+    debug!("Initialized {}", path.display());
+    Ok(())
+}
+
+fn merge_report(report: &mut VerifyReport, other: VerifyReport) {
+    report.pages_checked += other.pages_checked;
+    report.violations.extend(other.violations);
+}
+
+/// Sets `key` to `value` in `btree`, compressing the value if it takes at least
+/// `compression_threshold` bytes, and storing it in overflow pages if the key and the (possibly
+/// compressed) value together take more than `inline_limit` bytes.
+pub(crate) fn put_entry<P: PageFetcher>(
+    btree: &mut Tree<P>,
+    inline_limit: usize,
+    compression_threshold: Option<usize>,
+    key: &[u8],
+    value: &[u8],
+) -> Result<()> {
+    let stored = store_value(btree, inline_limit, compression_threshold, key, value)?;
+    replace_value(btree, key, stored)
+}
+
+/// Sets `key` to the already encoded `stored` in `btree`, freeing the pages of the value it
+/// replaces. If that fails, the old value is put back and `stored`'s pages are freed instead, so
+/// a failed write leaves the entry as it was.
+fn replace_value<P: PageFetcher>(
+    btree: &mut Tree<P>,
+    key: &[u8],
+    stored: ValueBytes,
+) -> Result<()> {
+    let replaced = btree.delete(to_internal_key(key)).and_then(|replaced| {
+        match btree.insert(to_internal_key(key), stored.clone()) {
+            Ok(_) => Ok(replaced),
+            Err(err) => {
+                // The old value fits where it was just deleted from
+                if let Some(replaced) = replaced {
+                    btree.insert(to_internal_key(key), replaced)?;
+                }
+                Err(err)
+            }
+        }
+    });
+    match replaced {
+        Ok(Some(replaced)) => free_value(btree, &replaced.value),
+        Ok(None) => Ok(()),
+        Err(err) => {
+            free_value(btree, &stored.value)?;
+            Err(err)
+        }
+    }
+}
+
+/// Encodes `value` as it's stored under `key`, see `put_entry`, writing its overflow pages if it
+/// needs any.
+fn store_value<P: PageFetcher>(
+    btree: &Tree<P>,
+    inline_limit: usize,
+    compression_threshold: Option<usize>,
+    key: &[u8],
+    value: &[u8],
+) -> Result<ValueBytes> {
+    let key_size = key.len() + 1;
+    if key_size + OVERFLOW_VALUE_SIZE > MAX_ITEM_SIZE {
+        return Err(Error::ItemTooLarge(key_size + value.len()));
+    }
+
+    let mut stored = Vec::new();
+    let compressed;
+    let mut value = value;
+    if compression_threshold.is_some_and(|threshold| value.len() >= threshold)
+        && key_size + COMPRESSED_HEADER_SIZE + OVERFLOW_VALUE_SIZE <= MAX_ITEM_SIZE
+    {
+        let codec = Codec::Lz;
+        compressed = codec.compress(value);
+        if compressed.len() + COMPRESSED_HEADER_SIZE < value.len() {
+            stored.push(VALUE_COMPRESSED);
+            stored.push(codec as u8);
+            stored.extend_from_slice(&(value.len() as u32).to_be_bytes());
+            value = &compressed;
+        }
+    }
+
+    if key_size + stored.len() + 1 + value.len() <= inline_limit.min(MAX_ITEM_SIZE) {
+        stored.reserve(value.len() + 1);
+        stored.push(VALUE_INLINE);
+        stored.extend_from_slice(value);
+    } else {
+        let first_page_no = btree.write_overflow(value)?;
+        stored.push(VALUE_OVERFLOW);
+        stored.extend_from_slice(&first_page_no.to_be_bytes());
+    }
+    Ok(ValueBytes { value: stored })
+}
+
+pub(crate) fn get_entry<P: PageFetcher>(btree: &Tree<P>, key: &[u8]) -> Result<Option<Vec<u8>>> {
+    let result = btree.search(to_internal_key(key))?;
+    result
+        .value
+        .map(|value| load_value(btree, value))
+        .transpose()
+}
+
+pub(crate) fn delete_entry<P: PageFetcher>(
+    btree: &mut Tree<P>,
+    key: &[u8],
+) -> Result<Option<Vec<u8>>> {
+    let stored = match btree.delete(to_internal_key(key))? {
+        Some(stored) => stored,
+        None => return Ok(None),
+    };
+    let value = decode_value(btree, &stored.value)?;
+    free_value(btree, &stored.value)?;
+    Ok(Some(value))
+}
+
+/// Vacuums `btree` and refreshes its statistics if at least `threshold` of its items are dead,
+/// see `Options::autovacuum_threshold`.
+pub(crate) fn autovacuum<P: PageFetcher>(
+    btree: &mut Tree<P>,
+    threshold: Option<u64>,
+) -> Result<()> {
+    match threshold {
+        Some(threshold) if btree.dead_item_cnt()? >= threshold => {
+            let stats = btree.vacuum(Vacuum::new())?;
+            debug!("[autovacuum] {:?}", stats);
+            btree.collect_stats(AUTOVACUUM_STATS_SAMPLE_RATE)?;
+            Ok(())
+        }
+        _ => Ok(()),
+    }
+}
+
+/// `btree`'s statistics with the user's keys in the histogram.
+pub(crate) fn key_stats<P: PageFetcher>(btree: &Tree<P>) -> Result<Option<KeyStats<Vec<u8>>>> {
+    Ok(btree.key_stats()?.map(|stats| KeyStats {
+        entry_cnt: stats.entry_cnt,
+        distinct_key_cnt: stats.distinct_key_cnt,
+        distinct_value_cnt: stats.distinct_value_cnt,
+        sampled_cnt: stats.sampled_cnt,
+        histogram: Histogram {
+            bounds: stats
+                .histogram
+                .bounds
+                .into_iter()
+                .map(from_internal_key)
+                .collect(),
+        },
+    }))
+}
+
+pub(crate) fn range_entries<'a, 'b, P, R>(btree: &'b Tree<P>, range: R) -> Result<Range<'b, P>>
+where
+    P: PageFetcher,
+    R: RangeBounds<&'a [u8]>,
+{
+    let start = map_bound(range.start_bound());
+    let end = map_bound(range.end_bound());
+    Ok(Range {
+        btree,
+        iter: btree.range((start, end))?,
+    })
+}
+
+/// A value being read with `Database::get_blob`.
+pub enum Blob<'a> {
+    Stream {
+        size: u64,
+        reader: BlobReader<'a, FilePageFetcher>,
+    },
+    Loaded(io::Cursor<Vec<u8>>),
+}
+
+impl<'a> Blob<'a> {
+    /// The size of the whole value, in bytes.
+    pub fn size(&self) -> u64 {
+        match self {
+            Blob::Stream { size, .. } => *size,
+            Blob::Loaded(cursor) => cursor.get_ref().len() as u64,
+        }
+    }
+}
+
+impl<'a> Read for Blob<'a> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Blob::Stream { reader, .. } => reader.read(buf),
+            Blob::Loaded(cursor) => cursor.read(buf),
+        }
+    }
+}
+
+pub struct Range<'a, P: PageFetcher = FilePageFetcher> {
+    btree: &'a Tree<P>,
+    iter: RangeIter<'a, P, KeyBytes, ValueBytes>,
+}
+
+impl<'a, P: PageFetcher> Iterator for Range<'a, P> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let btree = self.btree;
+        self.iter.next().map(|item| {
+            let (key, value) = item?;
+            Ok((from_internal_key(key), load_value(btree, value)?))
+        })
+    }
+}
+
+/// Decodes a stored value, following its overflow chain if it wasn't stored inline and
+/// decompressing it if it was compressed.
+fn load_value<P: PageFetcher>(btree: &Tree<P>, stored: ValueBytes) -> Result<Vec<u8>> {
+    decode_value(btree, &stored.value)
+}
+
+fn decode_value<P: PageFetcher>(btree: &Tree<P>, stored: &[u8]) -> Result<Vec<u8>> {
+    match stored.split_first() {
+        Some((&VALUE_INLINE, value)) => Ok(value.to_vec()),
+        Some((&VALUE_OVERFLOW, page_no)) => {
+            let page_no = page_no
+                .try_into()
+                .map_err(|_| Error::corruption("malformed overflow value".to_string()))?;
+            btree.read_overflow(u32::from_be_bytes(page_no))
+        }
+        Some((&VALUE_COMPRESSED, rest)) if rest.len() >= COMPRESSED_HEADER_SIZE => {
+            let codec = Codec::from_tag(rest[0])?;
+            let size = u32::from_be_bytes([rest[1], rest[2], rest[3], rest[4]]) as usize;
+            let compressed = decode_value(btree, &rest[COMPRESSED_HEADER_SIZE - 1..])?;
+            codec.decompress(&compressed, size)
+        }
+        Some((&VALUE_BLOB, _)) => {
+            let (first_page_no, size) = decode_blob(stored)?.unwrap();
+            btree.blob_reader(first_page_no, size).read_all()
+        }
+        _ => Err(Error::corruption("unknown value tag".to_string())),
+    }
+}
+
+/// Returns the overflow pages of a stored value that's no longer in the tree to the free list.
+pub(crate) fn free_value<P: PageFetcher>(btree: &Tree<P>, stored: &[u8]) -> Result<()> {
+    match stored.split_first() {
+        Some((&VALUE_OVERFLOW, page_no)) => {
+            let page_no = page_no
+                .try_into()
+                .map_err(|_| Error::corruption("malformed overflow value".to_string()))?;
+            btree.free_overflow(u32::from_be_bytes(page_no))
+        }
+        Some((&VALUE_COMPRESSED, rest)) if rest.len() >= COMPRESSED_HEADER_SIZE => {
+            free_value(btree, &rest[COMPRESSED_HEADER_SIZE - 1..])
+        }
+        Some((&VALUE_BLOB, _)) => {
+            let (first_page_no, _) = decode_blob(stored)?.unwrap();
+            btree.free_overflow(first_page_no)
+        }
+        _ => Ok(()),
+    }
+}
+
+/// Returns the first page and the size of a stored blob, or `None` if the value isn't a blob.
+fn decode_blob(stored: &[u8]) -> Result<Option<(u32, u64)>> {
+    match stored.split_first() {
+        Some((&VALUE_BLOB, rest)) if stored.len() == BLOB_VALUE_SIZE => {
+            let first_page_no = u32::from_be_bytes(rest[..4].try_into().unwrap());
+            let size = u64::from_be_bytes(rest[4..].try_into().unwrap());
+            Ok(Some((first_page_no, size)))
+        }
+        Some((&VALUE_BLOB, _)) => Err(Error::corruption("malformed blob value".to_string())),
+        _ => Ok(None),
+    }
+}
+
+pub(crate) fn to_internal_key(key: &[u8]) -> KeyBytes {
+    let mut internal_key = Vec::with_capacity(key.len() + 1);
+    internal_key.push(KEY_PREFIX);
+    internal_key.extend_from_slice(key);
+    KeyBytes { key: internal_key }
+}
+
+pub(crate) fn from_internal_key(mut key: KeyBytes) -> Vec<u8> {
+    key.key.remove(0);
+    key.key
+}
+
+fn map_bound(bound: Bound<&&[u8]>) -> Bound<KeyBytes> {
+    match bound {
+        Bound::Included(key) => Bound::Included(to_internal_key(key)),
+        Bound::Excluded(key) => Bound::Excluded(to_internal_key(key)),
+        Bound::Unbounded => Bound::Unbounded,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Database;
+    use super::DatabaseVacuum;
+    use super::Options;
+    use crate::catalog::TreeOptions;
+    use crate::error::Error;
+    use crate::events::EventListener;
+    use crate::page::PAGE_SIZE;
+    use std::io::Cursor;
+    use std::io::Read;
+    use std::path::PathBuf;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+
+    fn temp_path(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("johndb-{}-{}", name, std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        path
+    }
+
+    #[test]
+    fn put_get_delete() {
+        let path = temp_path("put_get_delete");
+        let mut db = Database::open(&path, Options::default()).unwrap();
+
+        assert_eq!(db.get(b"a").unwrap(), None);
+        db.put(b"a", b"1").unwrap();
+        db.put(b"", b"empty").unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+        assert_eq!(db.get(b"").unwrap(), Some(b"empty".to_vec()));
+
+        db.put(b"a", b"2").unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"2".to_vec()));
+
+        assert_eq!(db.delete(b"a").unwrap(), Some(b"2".to_vec()));
+        assert_eq!(db.delete(b"a").unwrap(), None);
+        assert_eq!(db.get(b"a").unwrap(), None);
+
+        match db.put(&vec![0; 4096], b"big key") {
+            Err(Error::ItemTooLarge(_)) => {}
+            other => panic!("expected ItemTooLarge, got {:?}", other),
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn reopen_and_range() {
+        let path = temp_path("reopen_and_range");
+        {
+            let mut db = Database::open(&path, Options::default()).unwrap();
+            for i in 0..2000u32 {
+                let key = format!("key-{:05}", i);
+                db.put(key.as_bytes(), &i.to_be_bytes()).unwrap();
+            }
+            assert!(db.metrics().leaf_splits > 0);
+            db.close().unwrap();
+        }
+
+        let db = Database::open(&path, Options::default()).unwrap();
+        let metrics = db.metrics();
+        assert_eq!(metrics.page_reads as usize, db.stats().unwrap().page_cnt);
+        assert_eq!((metrics.page_writes, metrics.leaf_splits), (0, 0));
+        let metadata_reads = db.hot_pages(1)[0].reads;
+        for _ in 0..10 {
+            db.get(b"key-01234").unwrap();
+        }
+        // Every lookup starts from the metadata page
+        let hot = db.hot_pages(2);
+        assert_eq!((hot[0].page_no, hot[0].reads), (0, metadata_reads + 10));
+        assert!(hot[1].reads >= 10);
+        db.decay_page_access();
+        assert_eq!(db.hot_pages(1)[0].reads, (metadata_reads + 10) / 2);
+        assert_eq!(
+            db.get(b"key-01234").unwrap(),
+            Some(1234u32.to_be_bytes().to_vec())
+        );
+
+        let start: &[u8] = b"key-00100";
+        let end: &[u8] = b"key-00200";
+        let keys = db
+            .range(start..end)
+            .unwrap()
+            .map(|item| String::from_utf8(item.unwrap().0).unwrap())
+            .collect::<Vec<_>>();
+        let expected = (100..200)
+            .map(|i| format!("key-{:05}", i))
+            .collect::<Vec<_>>();
+        assert_eq!(keys, expected);
+
+        assert_eq!(db.range::<std::ops::RangeFull>(..).unwrap().count(), 2000);
+        assert_eq!(db.stats().unwrap().key_cnt, 2000);
+        assert!(db.check().unwrap().is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn file_init_and_layout() {
+        let path = temp_path("file_init_and_layout");
+        let no_create = Options {
+            create_if_missing: false,
+            ..Options::default()
+        };
+        match Database::open(&path, no_create.clone()) {
+            Err(Error::Io(err)) => assert_eq!(err.kind(), std::io::ErrorKind::NotFound),
+            other => panic!("expected NotFound, got {:?}", other.map(|_| ())),
+        }
+
+        // An empty file is only initialized if asked to
+        std::fs::File::create(&path).unwrap();
+        match Database::open(&path, no_create.clone()) {
+            Err(Error::Corruption { .. }) => {}
+            other => panic!("expected Corruption, got {:?}", other.map(|_| ())),
+        }
+        // The metadata page is on disk before anything is flushed
+        drop(Database::open(&path, Options::default()).unwrap());
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), PAGE_SIZE as u64);
+        let mut db = Database::open(&path, no_create.clone()).unwrap();
+        db.put(b"a", b"1").unwrap();
+        db.close().unwrap();
+
+        // A partial last page is reported rather than dropped
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len + 100).unwrap();
+        match Database::open(&path, no_create.clone()) {
+            Err(Error::Corruption {
+                page_no: Some(page_no),
+                ..
+            }) => assert_eq!(page_no as u64, len / PAGE_SIZE as u64),
+            other => panic!("expected Corruption, got {:?}", other.map(|_| ())),
+        }
+        file.set_len(len).unwrap();
+        let db = Database::open(&path, no_create).unwrap();
+        assert_eq!(db.get(b"a").unwrap(), Some(b"1".to_vec()));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn corrupted_pages_are_reported() {
+        let path = temp_path("corrupted_pages_are_reported");
+        let mut db = Database::open(&path, Options::default()).unwrap();
+        db.put(b"a", b"1").unwrap();
+        db.close().unwrap();
+        let original = std::fs::read(&path).unwrap();
+
+        // Page 1 is the root leaf. Give it an unknown node type, then a special area of the wrong
+        // size in its header.
+        let node_type = 2 * PAGE_SIZE - 8 + 4;
+        let special_size = PAGE_SIZE + 8;
+        for (offset, bytes) in [
+            (node_type, vec![9]),
+            (special_size, 16u32.to_ne_bytes().to_vec()),
+        ] {
+            let mut corrupted = original.clone();
+            corrupted[offset..offset + bytes.len()].copy_from_slice(&bytes);
+            std::fs::write(&path, &corrupted).unwrap();
+
+            let db = Database::open(&path, Options::default()).unwrap();
+            match db.get(b"a").map_err(|err| err.root_cause().to_string()) {
+                Err(err) => assert!(err.starts_with("corruption detected on page 1"), "{}", err),
+                other => panic!("expected Corruption, got {:?}", other),
+            }
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn direct_io() {
+        let path = temp_path("direct_io");
+        let options = Options {
+            direct_io: true,
+            ..Options::default()
+        };
+        {
+            let mut db = Database::open(&path, options.clone()).unwrap();
+            for i in 0..2000u32 {
+                db.put(format!("key-{:05}", i).as_bytes(), &[i as u8; 50])
+                    .unwrap();
+            }
+            db.close().unwrap();
+        }
+
+        let db = Database::open(&path, options).unwrap();
+        assert_eq!(db.stats().unwrap().key_cnt, 2000);
+        assert_eq!(db.get(b"key-01234").unwrap(), Some(vec![1234u32 as u8; 50]));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn vacuum() {
+        let path = temp_path("vacuum");
+        let page_cnt = {
+            let mut db = Database::open(&path, Options::default()).unwrap();
+            db.create_tree("other", TreeOptions::default()).unwrap();
+            let mut other = db.open_tree("other").unwrap();
+            for i in 0..3000u32 {
+                let key = format!("key-{:05}", i);
+                other.put(key.as_bytes(), &[1; 100]).unwrap();
+                if i % 100 != 0 {
+                    other.delete(key.as_bytes()).unwrap();
+                }
+            }
+            drop(other);
+            for i in 0..3000u32 {
+                db.put(format!("key-{:05}", i).as_bytes(), &[2; 100])
+                    .unwrap();
+            }
+            for i in (0..3000u32).filter(|i| i % 10 != 0) {
+                db.delete(format!("key-{:05}", i).as_bytes()).unwrap();
+            }
+
+            let mut vacuum = DatabaseVacuum::new();
+            while !db.vacuum_step(&mut vacuum, 4).unwrap() {}
+            assert!(vacuum.stats().pages_freed > 0);
+            assert!(db.check().unwrap().is_ok());
+            assert_eq!(db.vacuum().unwrap().pages_freed, 0);
+            let page_cnt = db.stats().unwrap().page_cnt;
+            db.close().unwrap();
+            page_cnt
+        };
+
+        // The freed pages are found again after reopening, and reused
+        let mut db = Database::open(&path, Options::default()).unwrap();
+        for i in (0..3000u32).filter(|i| i % 10 == 1) {
+            db.put(format!("key-{:05}", i).as_bytes(), &[3; 100])
+                .unwrap();
+        }
+        assert_eq!(db.stats().unwrap().page_cnt, page_cnt);
+        assert_eq!(db.get(b"key-00010").unwrap(), Some(vec![2; 100]));
+        assert_eq!(db.get(b"key-00011").unwrap(), Some(vec![3; 100]));
+        assert_eq!(
+            db.open_tree("other").unwrap().get(b"key-00100").unwrap(),
+            Some(vec![1; 100])
+        );
+        assert!(db.check().unwrap().is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn autovacuum() {
+        let path = temp_path("autovacuum");
+        {
+            let options = Options {
+                autovacuum_threshold: Some(1000),
+                ..Options::default()
+            };
+            let mut db = Database::open(&path, options).unwrap();
+            let manual = TreeOptions {
+                autovacuum_threshold: None,
+                ..TreeOptions::default()
+            };
+            db.create_tree("manual", manual).unwrap();
+            let mut manual = db.open_tree("manual").unwrap();
+            for i in 0..3000u32 {
+                let key = format!("key-{:05}", i);
+                manual.put(key.as_bytes(), &[1; 100]).unwrap();
+                manual.delete(key.as_bytes()).unwrap();
+            }
+            drop(manual);
+            for i in 0..3000u32 {
+                let key = format!("key-{:05}", i);
+                db.put(key.as_bytes(), &[2; 100]).unwrap();
+                db.put(key.as_bytes(), &[3; 100]).unwrap();
+            }
+            for i in 0..3000u32 {
+                db.delete(format!("key-{:05}", i).as_bytes()).unwrap();
+            }
+
+            // The last delete reached the threshold again, vacuuming the tree down to one leaf
+            // and refreshing its statistics
+            assert_eq!(db.dead_entry_cnt().unwrap(), 0);
+            assert_eq!(db.key_stats().unwrap().unwrap().entry_cnt, 0);
+            assert_eq!(db.stats().unwrap().tree.height(), 1);
+            db.put(b"key-00000", &[4; 100]).unwrap();
+            assert!(db.check().unwrap().is_ok());
+            db.close().unwrap();
+        }
+
+        // The tree without autovacuum kept its count across reopening
+        let db = Database::open(&path, Options::default()).unwrap();
+        let manual = db.open_tree("manual").unwrap();
+        assert_eq!(manual.options().autovacuum_threshold, None);
+        assert_eq!(manual.dead_entry_cnt().unwrap(), 3000);
+        assert_eq!(manual.key_stats().unwrap(), None);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn memory_budget() {
+        let path = temp_path("memory_budget");
+        let page_size = std::mem::size_of::<crate::page::Page>();
+        let options = Options {
+            max_pages: 64,
+            ..Options::default()
+        };
+        let budget = Database::open(&path, options.clone())
+            .unwrap()
+            .memory_usage()
+            .page_table
+            + 8 * page_size;
+        let _ = std::fs::remove_file(&path);
+
+        let mut db = Database::open(
+            &path,
+            Options {
+                memory_budget: Some(budget),
+                ..options
+            },
+        )
+        .unwrap();
+        let err = (0..10000u32)
+            .map(|i| db.put(format!("key-{:05}", i).as_bytes(), &[0; 100]))
+            .find_map(Result::err)
+            .unwrap();
+        assert!(matches!(err.root_cause(), Error::MemoryBudgetExceeded(_)));
+        let usage = db.memory_usage();
+        // A split is refused up front unless the budget has room for it to reach a new root
+        assert!(usage.page_frames > 4 * page_size);
+        assert!(usage.total() <= budget);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn replace_at_capacity() {
+        let path = temp_path("replace_at_capacity");
+        let options = Options {
+            max_pages: 8,
+            autovacuum_threshold: None,
+            ..Options::default()
+        };
+        let mut db = Database::open(&path, options).unwrap();
+        let key = |i: u32| format!("key-{:05}", i).into_bytes();
+        let mut key_cnt = 0;
+        loop {
+            match db.put(&key(key_cnt), &[1; 100]) {
+                Ok(()) => key_cnt += 1,
+                Err(err) => {
+                    assert!(matches!(err.root_cause(), Error::OutOfPages), "{:?}", err);
+                    break;
+                }
+            }
+        }
+
+        // Growing a value in a full leaf needs a page that isn't there, so the old value stays
+        let mut failed = 0;
+        for i in 0..key_cnt {
+            match db.put(&key(i), &[2; 1500]) {
+                Ok(()) => assert_eq!(db.get(&key(i)).unwrap(), Some(vec![2; 1500])),
+                Err(err) => {
+                    assert!(matches!(err.root_cause(), Error::OutOfPages), "{:?}", err);
+                    assert_eq!(db.get(&key(i)).unwrap(), Some(vec![1; 100]));
+                    failed += 1;
+                }
+            }
+        }
+        assert!(failed > 0);
+        assert!(db.check().unwrap().is_ok());
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn large_values_overflow() {
+        let path = temp_path("large_values_overflow");
+        let big = (0..50000u32).map(|i| (i % 13) as u8).collect::<Vec<_>>();
+        {
+            let mut db = Database::open(&path, Options::default()).unwrap();
+            db.put(b"big", &big).unwrap();
+            db.put(b"small", b"inline").unwrap();
+            db.close().unwrap();
+        }
+
+        let db = Database::open(&path, Options::default()).unwrap();
+        assert_eq!(db.get(b"big").unwrap(), Some(big.clone()));
+        let items = db
+            .range::<std::ops::RangeFull>(..)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(
+            items,
+            vec![
+                (b"big".to_vec(), big),
+                (b"small".to_vec(), b"inline".to_vec())
+            ]
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn large_values_are_freed() {
+        let path = temp_path("large_values_are_freed");
+        let mut db = Database::open(&path, Options::default()).unwrap();
+        let big = |i: u32| {
+            (0..20000u32)
+                .map(|j| (i * 31 + j * 7 % 251) as u8)
+                .collect::<Vec<_>>()
+        };
+        db.put(b"big", &big(0)).unwrap();
+        let page_cnt = db.btree.page_fetcher().page_cnt();
+
+        // Each replaced value's chain is reused by the next one
+        for i in 1..30 {
+            db.put(b"big", &big(i)).unwrap();
+        }
+        assert_eq!(db.get(b"big").unwrap(), Some(big(29)));
+        assert!(
+            db.btree.page_fetcher().page_cnt() <= page_cnt + 3,
+            "{} pages, {} after the first put",
+            db.btree.page_fetcher().page_cnt(),
+            page_cnt
+        );
+
+        // Freed pages at the end of the file are dropped rather than kept on the free list
+        let live_page_cnt = |db: &Database| {
+            db.btree.page_fetcher().page_cnt() - db.btree.page_fetcher().free_page_cnt()
+        };
+        let live = live_page_cnt(&db);
+        assert_eq!(db.delete(b"big").unwrap(), Some(big(29)));
+        assert_eq!(live_page_cnt(&db), live - 3);
+        drop(db);
+
+        // Compressed values moved to overflow pages are freed too
+        let options = Options {
+            compression_threshold: Some(1),
+            ..Options::default()
+        };
+        let compressible = vec![7u8; 1_000_000];
+        let mut db = Database::open(&path, options).unwrap();
+        db.put(b"compressed", &compressible).unwrap();
+        let live = live_page_cnt(&db);
+        assert_eq!(db.delete(b"compressed").unwrap(), Some(compressible));
+        assert!(live_page_cnt(&db) < live);
+
+        // As are blobs
+        let blob = vec![3u8; 20000];
+        db.put_blob(b"blob", &blob[..]).unwrap();
+        let page_cnt = db.btree.page_fetcher().page_cnt();
+        for _ in 0..10 {
+            db.put_blob(b"blob", &blob[..]).unwrap();
+        }
+        assert!(db.btree.page_fetcher().page_cnt() <= page_cnt + 3);
+        let live = live_page_cnt(&db);
+        assert_eq!(db.delete(b"blob").unwrap(), Some(blob));
+        assert_eq!(live_page_cnt(&db), live - 3);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn blobs() {
+        let path = temp_path("blobs");
+        let big = (0..3_000_000u32)
+            .map(|i| (i * 7 % 251) as u8)
+            .collect::<Vec<_>>();
+        {
+            let mut db = Database::open(&path, Options::default()).unwrap();
+            assert_eq!(db.put_blob(b"blob", Cursor::new(&big)).unwrap(), 3_000_000);
+            assert_eq!(db.put_blob(b"empty", std::io::empty()).unwrap(), 0);
+            db.put(b"small", b"inline").unwrap();
+            db.close().unwrap();
+        }
+
+        let mut db = Database::open(&path, Options::default()).unwrap();
+        let mut blob = db.get_blob(b"blob").unwrap().unwrap();
+        assert_eq!(blob.size(), 3_000_000);
+        let mut read = Vec::new();
+        std::io::copy(&mut blob, &mut read).unwrap();
+        assert!(read == big);
+        assert!(db.get(b"blob").unwrap().unwrap() == big);
+        assert_eq!(db.get(b"empty").unwrap(), Some(Vec::new()));
+
+        // Other values can be read as blobs too
+        let mut small = Vec::new();
+        db.get_blob(b"small")
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut small)
+            .unwrap();
+        assert_eq!(small, b"inline");
+        assert!(db.get_blob(b"missing").unwrap().is_none());
+        assert!(db.check().unwrap().is_ok());
+
+        db.put(b"blob", b"replaced").unwrap();
+        assert_eq!(db.get(b"blob").unwrap(), Some(b"replaced".to_vec()));
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn compressed_values() {
+        let path = temp_path("compressed_values");
+        let options = Options {
+            compression_threshold: Some(64),
+            ..Options::default()
+        };
+        let json = (0..2000)
+            .map(|i| format!("{{\"id\":{},\"tags\":[\"a\",\"b\"]}}", i))
+            .collect::<String>()
+            .into_bytes();
+        let random = (0..1000u64)
+            .map(|i| (i.wrapping_mul(6_364_136_223_846_793_005) >> 56) as u8)
+            .collect::<Vec<_>>();
+        {
+            let mut db = Database::open(&path, options.clone()).unwrap();
+            db.put(b"json", &json).unwrap();
+            db.put(b"random", &random).unwrap();
+            db.put(b"short", &[1; 63]).unwrap();
+            db.put(b"zeros", &[0; 1000]).unwrap();
+            db.close().unwrap();
+        }
+
+        // Reading compressed values doesn't depend on the option
+        let db = Database::open(&path, Options::default()).unwrap();
+        assert_eq!(db.get(b"json").unwrap(), Some(json.clone()));
+        assert_eq!(db.get(b"random").unwrap(), Some(random.clone()));
+        assert_eq!(db.get(b"zeros").unwrap(), Some(vec![0; 1000]));
+        let items = db
+            .range::<std::ops::RangeFull>(..)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(items.len(), 4);
+        assert_eq!(items[0], (b"json".to_vec(), json));
+        assert_eq!(items[2], (b"short".to_vec(), vec![1; 63]));
+        assert!(db.check().unwrap().is_ok());
+
+        let uncompressed_path = temp_path("compressed_values_uncompressed");
+        let mut uncompressed = Database::open(&uncompressed_path, Options::default()).unwrap();
+        for (key, value) in items {
+            uncompressed.put(&key, &value).unwrap();
+        }
+        assert!(db.stats().unwrap().page_cnt * 2 < uncompressed.stats().unwrap().page_cnt);
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&uncompressed_path).unwrap();
+    }
+
+    #[test]
+    fn export_and_ingest_sst() {
+        let path = temp_path("export_and_ingest_sst");
+        let mut db = Database::open(&path, Options::default()).unwrap();
+        let big = vec![9; 20000];
+        for i in 0..3000u32 {
+            db.put(format!("key-{:05}", i).as_bytes(), &i.to_be_bytes())
+                .unwrap();
+        }
+        db.put(b"key-big", &big).unwrap();
+
+        let mut sst = Vec::new();
+        let start = b"key-01000".to_vec();
+        let end = b"key-02000".to_vec();
+        assert_eq!(
+            db.export_sst(start.as_slice()..end.as_slice(), &mut sst)
+                .unwrap(),
+            1000
+        );
+        let mut all = Vec::new();
+        assert_eq!(db.export_sst(.., &mut all).unwrap(), 3001);
+        assert!(matches!(
+            db.ingest_sst(Cursor::new(&sst)),
+            Err(Error::NotEmpty)
+        ));
+
+        let ingest_path = temp_path("export_and_ingest_sst_ingested");
+        let mut ingested = Database::open(&ingest_path, Options::default()).unwrap();
+        assert_eq!(ingested.ingest_sst(Cursor::new(&all)).unwrap(), 3001);
+        assert!(ingested.check().unwrap().is_ok());
+        assert_eq!(ingested.get(b"key-big").unwrap(), Some(big));
+        assert_eq!(
+            ingested.get(b"key-01234").unwrap(),
+            Some(1234u32.to_be_bytes().to_vec())
+        );
+        ingested.put(b"key-new", b"new").unwrap();
+        assert_eq!(ingested.range(..).unwrap().count(), 3002);
+        ingested.close().unwrap();
+
+        let ingested = Database::open(&ingest_path, Options::default()).unwrap();
+        assert_eq!(ingested.get(b"key-new").unwrap(), Some(b"new".to_vec()));
+        assert_eq!(
+            ingested.stats().unwrap().key_cnt,
+            db.stats().unwrap().key_cnt + 1
+        );
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&ingest_path).unwrap();
+    }
+
+    #[test]
+    fn export_and_import_snapshot() {
+        let path = temp_path("export_and_import_snapshot");
+        let snapshot_path = temp_path("export_and_import_snapshot.snap");
+        let import_path = temp_path("export_and_import_snapshot_imported");
+        let mut db = Database::open(&path, Options::default()).unwrap();
+        for i in 0..2000u32 {
+            db.put(&i.to_be_bytes(), format!("value-{}", i).as_bytes())
+                .unwrap();
+        }
+        db.tree("named").unwrap().put(b"a", b"b").unwrap();
+
+        // Unflushed changes are part of the snapshot
+        db.export_snapshot(&snapshot_path).unwrap();
+        db.put(b"after", b"snapshot").unwrap();
+
+        let imported =
+            Database::import_snapshot(&snapshot_path, &import_path, Options::default()).unwrap();
+        assert!(imported.check().unwrap().is_ok());
+        assert_eq!(imported.range(..).unwrap().count(), 2000);
+        assert_eq!(
+            imported.get(&7u32.to_be_bytes()).unwrap(),
+            Some(b"value-7".to_vec())
+        );
+        assert_eq!(imported.get(b"after").unwrap(), None);
+        assert_eq!(
+            imported.open_tree("named").unwrap().get(b"a").unwrap(),
+            Some(b"b".to_vec())
+        );
+        drop(imported);
+        assert!(
+            Database::import_snapshot(&snapshot_path, &import_path, Options::default()).is_err()
+        );
+        std::fs::remove_file(&import_path).unwrap();
+
+        let mut bytes = std::fs::read(&snapshot_path).unwrap();
+        bytes[5000] ^= 1;
+        std::fs::write(&snapshot_path, &bytes).unwrap();
+        match Database::import_snapshot(&snapshot_path, &import_path, Options::default()) {
+            Err(Error::Corruption { .. }) => {}
+            Err(err) => panic!("expected Corruption, got {:?}", err),
+            Ok(_) => panic!("imported a corrupted snapshot"),
+        }
+        assert!(!import_path.exists());
+
+        std::fs::remove_file(&path).unwrap();
+        std::fs::remove_file(&snapshot_path).unwrap();
+    }
+
+    #[derive(Default)]
+    struct Recorder {
+        events: Mutex<Vec<String>>,
+    }
+
+    impl EventListener for Recorder {
+        fn on_split(&self, page_no: u32, new_sibling_no: u32, is_leaf: bool) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("split {} {} {}", page_no, new_sibling_no, is_leaf));
+        }
+
+        fn on_new_root(&self, root_no: u32) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("root {}", root_no));
+        }
+
+        fn on_checkpoint(&self, page_cnt: usize) {
+            self.events
+                .lock()
+                .unwrap()
+                .push(format!("checkpoint {}", page_cnt));
+        }
+    }
+
+    #[test]
+    fn event_listener() {
+        let path = temp_path("event_listener");
+        let mut db = Database::open(&path, Options::default()).unwrap();
+        let recorder = Arc::new(Recorder::default());
+        db.add_listener(recorder.clone());
+
+        for i in 0..2000u32 {
+            db.put(format!("key-{:05}", i).as_bytes(), b"value")
+                .unwrap();
+        }
+        db.flush().unwrap();
+
+        let events = recorder.events.lock().unwrap().clone();
+        let splits = events
+            .iter()
+            .filter(|event| event.starts_with("split "))
+            .count();
+        assert_eq!(splits as u64, db.metrics().leaf_splits);
+        // The first leaf split turns the root leaf into two leaves under a new internal root
+        assert_eq!(events[0], "split 1 2 true");
+        assert_eq!(events[1], "root 3");
+        assert_eq!(
+            events.last().unwrap(),
+            &format!("checkpoint {}", db.stats().unwrap().page_cnt)
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn dump_and_load() {
+        let src_path = temp_path("dump_src");
+        let dst_path = temp_path("dump_dst");
+        let big = (0..30000u32).map(|i| (i % 7) as u8).collect::<Vec<_>>();
+
+        let mut src = Database::open(&src_path, Options::default()).unwrap();
+        for i in 0..500u32 {
+            src.put(format!("key-{:03}", i).as_bytes(), &i.to_be_bytes())
+                .unwrap();
+        }
+        src.put(b"big", &big).unwrap();
+        let mut buf = Vec::new();
+        assert_eq!(src.dump(&mut buf).unwrap(), 501);
+
+        let mut dst = Database::open(&dst_path, Options::default()).unwrap();
+        assert_eq!(dst.load(&buf[..]).unwrap(), 501);
+        assert_eq!(dst.get(b"big").unwrap(), Some(big));
+        let entries = |db: &Database| {
+            db.range::<std::ops::RangeFull>(..)
+                .unwrap()
+                .collect::<Result<Vec<_>, _>>()
+                .unwrap()
+        };
+        assert_eq!(entries(&dst), entries(&src));
+
+        buf.truncate(buf.len() - 1);
+        assert!(matches!(dst.load(&buf[..]), Err(Error::Io(_))));
+
+        std::fs::remove_file(&src_path).unwrap();
+        std::fs::remove_file(&dst_path).unwrap();
+    }
+
+    type Entry = (Vec<u8>, Vec<u8>);
+
+    const GOLDEN_OPTIONS: Options = Options {
+        create_if_missing: true,
+        max_pages: 1024,
+        memory_budget: None,
+        compression_threshold: Some(1024),
+        direct_io: false,
+        autovacuum_threshold: None,
+        shadow_paging: false,
+    };
+
+    /// The entries of `testdata/golden.db`: enough small ones for a two level tree, a value in
+    /// overflow pages, a compressed one and a blob. Returns the database's own entries, then the
+    /// `events` tree's.
+    fn golden_entries() -> (Vec<Entry>, Vec<Entry>) {
+        let mut entries = (0..300u32)
+            .map(|i| {
+                let key = format!("key-{:04}", i).into_bytes();
+                (
+                    key,
+                    format!("value-{};", i)
+                        .repeat(i as usize % 7 + 1)
+                        .into_bytes(),
+                )
+            })
+            .collect::<Vec<_>>();
+        // xorshift, so that it doesn't compress
+        let mut state = 0x2545_F491u32;
+        let overflow = (0..20_000)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect::<Vec<_>>();
+        entries.push((b"overflow".to_vec(), overflow));
+        entries.push((b"compressed".to_vec(), b"johndb ".repeat(600)));
+        entries.push((b"blob".to_vec(), (0..30_000u32).map(|i| i as u8).collect()));
+        entries.sort();
+
+        let events = (0..50u64)
+            .map(|i| {
+                (
+                    i.to_be_bytes().to_vec(),
+                    format!("event {}", i).into_bytes(),
+                )
+            })
+            .collect();
+        (entries, events)
+    }
+
+    /// Rewrites `testdata/golden.db`, which should only be needed after a deliberate change to the
+    /// file format: `cargo test write_golden_database -- --ignored`.
+    #[test]
+    #[ignore]
+    fn write_golden_database() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/testdata/golden.db");
+        let _ = std::fs::remove_file(path);
+        let (entries, events) = golden_entries();
+
+        let mut db = Database::open(path, GOLDEN_OPTIONS).unwrap();
+        for (key, value) in entries {
+            match key == b"blob" {
+                true => db.put_blob(&key, &value[..]).map(|_| ()),
+                false => db.put(&key, &value),
+            }
+            .unwrap();
+        }
+        db.create_tree("events", TreeOptions::default()).unwrap();
+        let mut tree = db.open_tree("events").unwrap();
+        for (key, value) in events {
+            tree.put(&key, &value).unwrap();
+        }
+        db.close().unwrap();
+    }
+
+    /// Opens a database written by an earlier build, see `write_golden_database`, so that a change
+    /// to the file format fails here rather than silently misreading users' files.
+    #[test]
+    fn golden_database() {
+        let path = temp_path("golden");
+        std::fs::write(&path, include_bytes!("../testdata/golden.db")).unwrap();
+        let (entries, events) = golden_entries();
+
+        let options = Options {
+            create_if_missing: false,
+            ..GOLDEN_OPTIONS
+        };
+        let db = Database::open(&path, options).unwrap();
+        let report = db.check().unwrap();
+        assert!(report.is_ok(), "{:?}", report.violations);
+        assert!(report.pages_checked > 3);
+        let found = db
+            .range::<std::ops::RangeFull>(..)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert!(found == entries, "the database's entries differ");
+        let mut blob = Vec::new();
+        db.get_blob(b"blob")
+            .unwrap()
+            .unwrap()
+            .read_to_end(&mut blob)
+            .unwrap();
+        assert_eq!(blob.len(), 30_000);
+
+        assert_eq!(db.tree_names().unwrap(), ["events"]);
+        let tree = db.open_tree("events").unwrap();
+        let found = tree
+            .range::<std::ops::RangeFull>(..)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        assert_eq!(found, events);
+        drop(tree);
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,386 @@
+//! Order-preserving ("memcomparable") encoding of typed values into bytes.
+//!
+//! For any two values `a` and `b` of the same type, `encode(a) < encode(b)` byte-wise if and only
+//! if `a < b`. This lets keys be compared with a single memcmp regardless of the types they were
+//! built from, which is what composite keys need.
+//!
+//! * Unsigned integers are written big-endian.
+//! * Signed integers flip the sign bit, then are written big-endian.
+//! * Floats flip the sign bit of positive numbers and every bit of negative ones (so `-0.0` sorts
+//!   below `0.0` and NaNs sort at the ends).
+//! * Byte strings escape `0x00` as `0x00 0xFF` and are terminated by `0x00 0x01`, so a string
+//!   sorts below any longer string it's a prefix of, even inside a tuple.
+//! * Tuples concatenate their fields' encodings.
+//! * `Desc` inverts every bit of its value's encoding, so it sorts in descending order. That only
+//!   works because no encoding above is a prefix of another encoding of the same type.
+
+use crate::error::Error;
+use crate::error::Result;
+use std::cmp::Ordering;
+use std::convert::TryInto;
+
+const ESCAPE: u8 = 0x00;
+const ESCAPED_ZERO: u8 = 0xFF;
+const TERMINATOR: u8 = 0x01;
+
+pub trait Encode {
+    fn encode_to(&self, buf: &mut Vec<u8>);
+}
+
+pub trait Decode: Sized {
+    /// Decodes a value from the front of `buf`, advancing it past the consumed bytes.
+    fn decode_from(buf: &mut &[u8]) -> Result<Self>;
+}
+
+pub fn encode<T: Encode + ?Sized>(value: &T) -> Vec<u8> {
+    let mut buf = Vec::new();
+    value.encode_to(&mut buf);
+    buf
+}
+
+/// Decodes a whole buffer, failing if any bytes are left over.
+pub fn decode<T: Decode>(mut buf: &[u8]) -> Result<T> {
+    let value = T::decode_from(&mut buf)?;
+    if !buf.is_empty() {
+        return Err(Error::corruption(format!(
+            "{} trailing bytes after decoded value",
+            buf.len()
+        )));
+    }
+    Ok(value)
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(Error::corruption(format!(
+            "expected {} more bytes, found {}",
+            len,
+            buf.len()
+        )));
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Ok(head)
+}
+
+macro_rules! unsigned_encoding {
+    ($($int:ty),*) => {
+        $(
+            impl Encode for $int {
+                fn encode_to(&self, buf: &mut Vec<u8>) {
+                    buf.extend_from_slice(&self.to_be_bytes());
+                }
+            }
+
+            impl Decode for $int {
+                fn decode_from(buf: &mut &[u8]) -> Result<Self> {
+                    let bytes = take(buf, std::mem::size_of::<$int>())?;
+                    Ok(<$int>::from_be_bytes(bytes.try_into().unwrap()))
+                }
+            }
+        )*
+    };
+}
+
+unsigned_encoding!(u8, u16, u32, u64, u128);
+
+macro_rules! signed_encoding {
+    ($($int:ty => $uint:ty),*) => {
+        $(
+            impl Encode for $int {
+                fn encode_to(&self, buf: &mut Vec<u8>) {
+                    let flipped = (*self as $uint) ^ (1 << (<$uint>::BITS - 1));
+                    flipped.encode_to(buf);
+                }
+            }
+
+            impl Decode for $int {
+                fn decode_from(buf: &mut &[u8]) -> Result<Self> {
+                    let flipped = <$uint>::decode_from(buf)?;
+                    Ok((flipped ^ (1 << (<$uint>::BITS - 1))) as $int)
+                }
+            }
+        )*
+    };
+}
+
+signed_encoding!(i8 => u8, i16 => u16, i32 => u32, i64 => u64, i128 => u128);
+
+macro_rules! float_encoding {
+    ($($float:ty => $uint:ty),*) => {
+        $(
+            impl Encode for $float {
+                fn encode_to(&self, buf: &mut Vec<u8>) {
+                    let bits = self.to_bits();
+                    let sign_bit = 1 << (<$uint>::BITS - 1);
+                    let flipped = if bits & sign_bit == 0 { bits ^ sign_bit } else { !bits };
+                    flipped.encode_to(buf);
+                }
+            }
+
+            impl Decode for $float {
+                fn decode_from(buf: &mut &[u8]) -> Result<Self> {
+                    let flipped = <$uint>::decode_from(buf)?;
+                    let sign_bit = 1 << (<$uint>::BITS - 1);
+                    let bits = if flipped & sign_bit != 0 { flipped ^ sign_bit } else { !flipped };
+                    Ok(<$float>::from_bits(bits))
+                }
+            }
+        )*
+    };
+}
+
+float_encoding!(f32 => u32, f64 => u64);
+
+impl Encode for bool {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        (*self as u8).encode_to(buf);
+    }
+}
+
+impl Decode for bool {
+    fn decode_from(buf: &mut &[u8]) -> Result<Self> {
+        match u8::decode_from(buf)? {
+            0 => Ok(false),
+            1 => Ok(true),
+            other => Err(Error::corruption(format!("invalid bool byte {}", other))),
+        }
+    }
+}
+
+impl Encode for [u8] {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        for byte in self.iter() {
+            if *byte == ESCAPE {
+                buf.push(ESCAPE);
+                buf.push(ESCAPED_ZERO);
+            } else {
+                buf.push(*byte);
+            }
+        }
+        buf.push(ESCAPE);
+        buf.push(TERMINATOR);
+    }
+}
+
+impl Encode for Vec<u8> {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.as_slice().encode_to(buf);
+    }
+}
+
+impl Decode for Vec<u8> {
+    fn decode_from(buf: &mut &[u8]) -> Result<Self> {
+        let mut bytes = Vec::new();
+        loop {
+            match take(buf, 1)?[0] {
+                ESCAPE => match take(buf, 1)?[0] {
+                    ESCAPED_ZERO => bytes.push(ESCAPE),
+                    TERMINATOR => return Ok(bytes),
+                    other => {
+                        return Err(Error::corruption(format!(
+                            "invalid escape sequence 0x00 0x{:02x}",
+                            other
+                        )))
+                    }
+                },
+                byte => bytes.push(byte),
+            }
+        }
+    }
+}
+
+impl Encode for str {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.as_bytes().encode_to(buf);
+    }
+}
+
+impl Encode for String {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        self.as_bytes().encode_to(buf);
+    }
+}
+
+impl Decode for String {
+    fn decode_from(buf: &mut &[u8]) -> Result<Self> {
+        String::from_utf8(Vec::<u8>::decode_from(buf)?)
+            .map_err(|err| Error::corruption(format!("invalid utf-8 in string: {}", err)))
+    }
+}
+
+/// `None` sorts below every `Some`.
+impl<T: Encode> Encode for Option<T> {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        match self {
+            None => buf.push(0),
+            Some(value) => {
+                buf.push(1);
+                value.encode_to(buf);
+            }
+        }
+    }
+}
+
+impl<T: Decode> Decode for Option<T> {
+    fn decode_from(buf: &mut &[u8]) -> Result<Self> {
+        match u8::decode_from(buf)? {
+            0 => Ok(None),
+            1 => Ok(Some(T::decode_from(buf)?)),
+            other => Err(Error::corruption(format!("invalid option tag {}", other))),
+        }
+    }
+}
+
+impl<T: Encode + ?Sized> Encode for &T {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        (**self).encode_to(buf);
+    }
+}
+
+macro_rules! tuple_encoding {
+    ($($name:ident),+) => {
+        impl<$($name: Encode),+> Encode for ($($name,)+) {
+            #[allow(non_snake_case)]
+            fn encode_to(&self, buf: &mut Vec<u8>) {
+                let ($($name,)+) = self;
+                $($name.encode_to(buf);)+
+            }
+        }
+
+        impl<$($name: Decode),+> Decode for ($($name,)+) {
+            fn decode_from(buf: &mut &[u8]) -> Result<Self> {
+                Ok(($($name::decode_from(buf)?,)+))
+            }
+        }
+    };
+}
+
+/// Sorts `T` in descending order, e.g. `(user_id, Desc(created_at))` keys list each user's newest
+/// items first. Compares in the same order it encodes, like `std::cmp::Reverse`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct Desc<T>(pub T);
+
+impl<T: PartialOrd> PartialOrd for Desc<T> {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        other.0.partial_cmp(&self.0)
+    }
+}
+
+impl<T: Ord> Ord for Desc<T> {
+    fn cmp(&self, other: &Self) -> Ordering {
+        other.0.cmp(&self.0)
+    }
+}
+
+impl<T: Encode> Encode for Desc<T> {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        let start = buf.len();
+        self.0.encode_to(buf);
+        for byte in buf[start..].iter_mut() {
+            *byte = !*byte;
+        }
+    }
+}
+
+impl<T: Decode> Decode for Desc<T> {
+    /// Inverts the rest of `buf` since `T`'s length isn't known until it's decoded, so decoding is
+    /// linear in what follows the value rather than in the value.
+    fn decode_from(buf: &mut &[u8]) -> Result<Self> {
+        let inverted = buf.iter().map(|byte| !byte).collect::<Vec<_>>();
+        let mut rest = inverted.as_slice();
+        let value = T::decode_from(&mut rest)?;
+        take(buf, inverted.len() - rest.len())?;
+        Ok(Desc(value))
+    }
+}
+
+tuple_encoding!(A);
+tuple_encoding!(A, B);
+tuple_encoding!(A, B, C);
+tuple_encoding!(A, B, C, D);
+
+#[cfg(test)]
+mod tests {
+    use super::decode;
+    use super::encode;
+    use super::Decode;
+    use super::Desc;
+    use super::Encode;
+    use std::fmt::Debug;
+
+    /// Checks that `values`, which must already be in ascending order, encode in the same order
+    /// and round trip.
+    fn assert_ordered<T: Encode + Decode + Debug + PartialEq>(values: &[T]) {
+        for pair in values.windows(2) {
+            assert!(encode(&pair[0]) < encode(&pair[1]), "{:?}", pair);
+        }
+        for value in values.iter() {
+            assert_eq!(decode::<T>(&encode(value)).unwrap(), *value);
+        }
+    }
+
+    #[test]
+    fn integers() {
+        assert_ordered(&[0u32, 1, 255, 256, u32::MAX]);
+        assert_ordered(&[i64::MIN, -256, -1, 0, 1, 256, i64::MAX]);
+        assert_ordered(&[i8::MIN, -1, 0, i8::MAX]);
+    }
+
+    #[test]
+    fn floats() {
+        assert_ordered(&[
+            f64::NEG_INFINITY,
+            -1e10,
+            -1.5,
+            -0.0,
+            0.0,
+            1e-10,
+            2.5,
+            f64::INFINITY,
+        ]);
+    }
+
+    #[test]
+    fn strings_and_tuples() {
+        assert_ordered(&[
+            "".to_string(),
+            "\0".to_string(),
+            "\0\0".to_string(),
+            "a".to_string(),
+            "a\0b".to_string(),
+            "ab".to_string(),
+            "b".to_string(),
+        ]);
+        assert_ordered(&[
+            ("a".to_string(), 2u32),
+            ("a".to_string(), 10u32),
+            ("ab".to_string(), 0u32),
+            ("b".to_string(), 0u32),
+        ]);
+        assert_ordered(&[None, Some(-1i32), Some(0), Some(1)]);
+        assert!(decode::<String>(&[b'a', 0x00]).is_err());
+    }
+
+    #[test]
+    fn descending() {
+        assert_ordered(&[Desc(u32::MAX), Desc(256), Desc(1), Desc(0)]);
+        assert_ordered(&[Desc(1.5f64), Desc(-0.0), Desc(f64::NEG_INFINITY)]);
+        // A string still sorts next to the strings it's a prefix of
+        assert_ordered(&[
+            Desc("b".to_string()),
+            Desc("ab".to_string()),
+            Desc("a\0".to_string()),
+            Desc("a".to_string()),
+            Desc("".to_string()),
+        ]);
+        assert_ordered(&[
+            (1u32, Desc("b".to_string()), 0i64),
+            (1u32, Desc("a".to_string()), -1i64),
+            (1u32, Desc("a".to_string()), 5i64),
+            (2u32, Desc("z".to_string()), 0i64),
+        ]);
+        assert_ordered(&[Desc(Some(1i32)), Desc(Some(-1)), Desc(None)]);
+        assert!(decode::<Desc<String>>(&[!b'a', !0x00]).is_err());
+    }
+}
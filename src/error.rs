@@ -0,0 +1,183 @@
+use std::fmt;
+use std::io;
+
+pub type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug)]
+pub enum Error {
+    /// Underlying I/O failure while reading or writing pages.
+    Io(io::Error),
+    /// On-page data doesn't match what we expect, e.g. a node of the wrong type or a downlink we
+    /// can't find. `page_no` is the page the problem was found on, when it's known.
+    Corruption {
+        page_no: Option<u32>,
+        detail: String,
+    },
+    /// Not enough free space in the page to add the item.
+    PageFull,
+    /// A page lock was poisoned by a panicking holder.
+    Lock,
+    /// The page fetcher has no such page.
+    PageNotFound(u32),
+    /// The page fetcher can't hand out any more pages.
+    OutOfPages,
+    /// Allocating another page would take the memory in use past the configured budget, in
+    /// bytes.
+    MemoryBudgetExceeded(usize),
+    /// The key/value pair is too large to store in a single page.
+    ItemTooLarge(usize),
+    /// The tree was created with different key or value types than it's being opened with.
+    TypeMismatch(String),
+    /// A tree's sequence ran out of keys, see `BTree::insert_auto`.
+    SequenceExhausted,
+    /// The tree already has entries, so it can't be bulk loaded, see `BTree::bulk_load`.
+    NotEmpty,
+    /// There's already a tree with this name in the catalog.
+    TreeExists(String),
+    /// There's no tree with this name in the catalog.
+    TreeNotFound(String),
+    /// The key is already present in a tree whose schema requires unique keys.
+    DuplicateKey,
+    /// The SQL statement isn't valid or refers to columns the table doesn't have.
+    Sql(String),
+    /// An expression couldn't be evaluated, e.g. because it divides by zero, see `Expr::eval`.
+    Eval(String),
+    /// Text isn't valid JSON, or a path isn't a valid JSON path, see `json`.
+    Json(String),
+    /// A message sent to or received from a server is malformed, see `server`.
+    Protocol(String),
+    /// `source` was raised while running the tree operation `op`, e.g. "insert".
+    Context {
+        op: &'static str,
+        source: Box<Error>,
+    },
+}
+
+impl Error {
+    pub fn corruption<S: Into<String>>(detail: S) -> Self {
+        Error::Corruption {
+            page_no: None,
+            detail: detail.into(),
+        }
+    }
+
+    pub fn page_corruption<S: Into<String>>(page_no: u32, detail: S) -> Self {
+        Error::Corruption {
+            page_no: Some(page_no),
+            detail: detail.into(),
+        }
+    }
+
+    /// Attributes a corruption error to `page_no`, unless it's already attributed to a page.
+    pub(crate) fn on_page(self, page_no: u32) -> Self {
+        match self {
+            Error::Corruption {
+                page_no: None,
+                detail,
+            } => Error::page_corruption(page_no, detail),
+            other => other,
+        }
+    }
+
+    /// Wraps the error with the tree operation it was raised by. Errors that already name an
+    /// operation are left alone, so the innermost one wins.
+    pub(crate) fn context(self, op: &'static str) -> Self {
+        match self {
+            Error::Context { .. } => self,
+            other => Error::Context {
+                op,
+                source: Box::new(other),
+            },
+        }
+    }
+
+    /// The underlying error, with any operation context stripped off. Match on this rather than
+    /// the error itself to tell kinds of errors apart.
+    pub fn root_cause(&self) -> &Error {
+        match self {
+            Error::Context { source, .. } => source.root_cause(),
+            other => other,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::Io(err) => write!(f, "I/O error: {}", err),
+            Error::Corruption {
+                page_no: Some(page_no),
+                detail,
+            } => write!(f, "corruption detected on page {}: {}", page_no, detail),
+            Error::Corruption {
+                page_no: None,
+                detail,
+            } => write!(f, "corruption detected: {}", detail),
+            Error::PageFull => write!(f, "not enough space left in page"),
+            Error::Lock => write!(f, "page lock poisoned"),
+            Error::PageNotFound(page_no) => write!(f, "page {} not found", page_no),
+            Error::OutOfPages => write!(f, "no free pages left"),
+            Error::MemoryBudgetExceeded(budget) => {
+                write!(f, "memory budget of {} bytes exceeded", budget)
+            }
+            Error::ItemTooLarge(size) => write!(f, "item of {} bytes is too large", size),
+            Error::TypeMismatch(detail) => write!(f, "type mismatch: {}", detail),
+            Error::SequenceExhausted => write!(f, "sequence exhausted"),
+            Error::NotEmpty => write!(f, "tree isn't empty"),
+            Error::TreeExists(name) => write!(f, "tree {} already exists", name),
+            Error::TreeNotFound(name) => write!(f, "tree {} not found", name),
+            Error::DuplicateKey => write!(f, "duplicate key"),
+            Error::Sql(detail) => write!(f, "sql: {}", detail),
+            Error::Eval(detail) => write!(f, "evaluation failed: {}", detail),
+            Error::Json(detail) => write!(f, "json: {}", detail),
+            Error::Protocol(detail) => write!(f, "protocol error: {}", detail),
+            Error::Context { op, source } => write!(f, "{} failed: {}", op, source),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Io(err) => Some(err),
+            Error::Context { source, .. } => Some(source.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        Error::Io(err)
+    }
+}
+
+impl<T> From<std::sync::PoisonError<T>> for Error {
+    fn from(_err: std::sync::PoisonError<T>) -> Self {
+        Error::Lock
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Error;
+
+    #[test]
+    fn context_keeps_page_and_innermost_op() {
+        let err = Error::corruption("bad separator")
+            .on_page(7)
+            .context("search")
+            .context("insert");
+        assert_eq!(
+            err.to_string(),
+            "search failed: corruption detected on page 7: bad separator"
+        );
+        assert!(matches!(
+            err.root_cause(),
+            Error::Corruption {
+                page_no: Some(7),
+                ..
+            }
+        ));
+    }
+}
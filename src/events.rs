@@ -0,0 +1,24 @@
+//! Callbacks for structural events in a database, e.g. for invalidating caches or debugging
+//! without patching the crate. Listeners are registered with `Database::add_listener` (or
+//! `BTree::add_listener`) and called synchronously on the thread causing the event, often while
+//! page locks are held, so they must be quick and must not call back into the database.
+
+/// Every callback does nothing by default, so listeners only implement the ones they care about.
+pub trait EventListener: Send + Sync {
+    /// `page_no` was split, moving its upper half to the new right sibling `new_sibling_no`.
+    fn on_split(&self, _page_no: u32, _new_sibling_no: u32, _is_leaf: bool) {}
+
+    /// The root was split and `root_no` is the new root, one level above the old one.
+    fn on_new_root(&self, _root_no: u32) {}
+
+    /// `page_no` was dropped from memory. Every page stays in memory until the database is
+    /// closed, so this isn't called yet.
+    fn on_page_evicted(&self, _page_no: u32) {}
+
+    /// A flush made all `page_cnt` pages durable.
+    fn on_checkpoint(&self, _page_cnt: usize) {}
+
+    /// Replaying changes after a crash has made progress. Changes are only durable once flushed
+    /// and there's no log to replay, so this isn't called yet.
+    fn on_recovery_progress(&self, _done: u64, _total: u64) {}
+}
@@ -0,0 +1,247 @@
+//! A portable stream of sorted key/value entries, used to dump a tree and load it back into one
+//! with a different page layout. Unlike pages, nothing in the stream depends on the page size or
+//! on where items sit within a page, which makes it the migration path between on-disk formats.
+//!
+//! All integers are big-endian. The stream consists of:
+//!
+//! * A header: the magic bytes `JOHNDUMP`, the format version as a u32, then the key and value
+//!   type names, each as a u16 length followed by UTF-8 bytes, then a CRC-32 of all the
+//!   preceding header bytes.
+//! * One record per entry: the tag byte `1`, the key and the value, each as a u32 length followed
+//!   by its bytes, then a CRC-32 of the record's preceding bytes.
+//! * A trailer: the tag byte `0`, the number of records as a u64, then a CRC-32 of both.
+
+use crate::checksum::crc32;
+use crate::error::Error;
+use crate::error::Result;
+use std::convert::TryInto;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+const MAGIC: &[u8; 8] = b"JOHNDUMP";
+pub const FORMAT_VERSION: u32 = 1;
+
+const TAG_END: u8 = 0;
+const TAG_RECORD: u8 = 1;
+
+pub struct DumpWriter<W: Write> {
+    writer: W,
+    record_cnt: u64,
+}
+
+impl<W: Write> DumpWriter<W> {
+    /// Writes the header, tagging the stream with the type names of the entries it will hold.
+    pub fn new(mut writer: W, key_type: &str, value_type: &str) -> Result<Self> {
+        let mut header = MAGIC.to_vec();
+        header.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+        for type_name in [key_type, value_type].iter() {
+            let len: u16 = type_name.len().try_into().map_err(|_| {
+                Error::corruption(format!("type name {} is too long to dump", type_name))
+            })?;
+            header.extend_from_slice(&len.to_be_bytes());
+            header.extend_from_slice(type_name.as_bytes());
+        }
+        header.extend_from_slice(&crc32(&header).to_be_bytes());
+        writer.write_all(&header)?;
+
+        Ok(DumpWriter {
+            writer,
+            record_cnt: 0,
+        })
+    }
+
+    /// Appends an entry. Entries are expected in ascending key order.
+    pub fn write_entry(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let mut record = Vec::with_capacity(key.len() + value.len() + 13);
+        record.push(TAG_RECORD);
+        for bytes in [key, value].iter() {
+            let len: u32 = bytes
+                .len()
+                .try_into()
+                .map_err(|_| Error::ItemTooLarge(bytes.len()))?;
+            record.extend_from_slice(&len.to_be_bytes());
+            record.extend_from_slice(bytes);
+        }
+        record.extend_from_slice(&crc32(&record).to_be_bytes());
+        self.writer.write_all(&record)?;
+
+        self.record_cnt += 1;
+        Ok(())
+    }
+
+    /// Writes the trailer and flushes the underlying writer, returning the number of entries
+    /// written. A stream without a trailer is rejected when loading.
+    pub fn finish(mut self) -> Result<u64> {
+        let mut trailer = vec![TAG_END];
+        trailer.extend_from_slice(&self.record_cnt.to_be_bytes());
+        trailer.extend_from_slice(&crc32(&trailer).to_be_bytes());
+        self.writer.write_all(&trailer)?;
+        self.writer.flush()?;
+        Ok(self.record_cnt)
+    }
+}
+
+pub struct DumpReader<R: Read> {
+    reader: R,
+    key_type: String,
+    value_type: String,
+    record_cnt: u64,
+    finished: bool,
+}
+
+impl<R: Read> DumpReader<R> {
+    /// Reads and checks the header.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut header = read_vec(&mut reader, MAGIC.len() + 4)?;
+        if &header[..MAGIC.len()] != MAGIC {
+            return Err(Error::corruption("not a johndb dump".to_string()));
+        }
+        let version = u32::from_be_bytes(header[MAGIC.len()..].try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(Error::corruption(format!(
+                "unsupported dump format version {}",
+                version
+            )));
+        }
+
+        let mut type_names = Vec::with_capacity(2);
+        for _ in 0..2 {
+            let len = read_vec(&mut reader, 2)?;
+            header.extend_from_slice(&len);
+            let name = read_vec(&mut reader, u16::from_be_bytes([len[0], len[1]]) as usize)?;
+            header.extend_from_slice(&name);
+            type_names.push(
+                String::from_utf8(name).map_err(|_| {
+                    Error::corruption("dump type name isn't valid utf-8".to_string())
+                })?,
+            );
+        }
+        check_crc(&mut reader, &header, "header")?;
+
+        let value_type = type_names.pop().unwrap();
+        let key_type = type_names.pop().unwrap();
+        Ok(DumpReader {
+            reader,
+            key_type,
+            value_type,
+            record_cnt: 0,
+            finished: false,
+        })
+    }
+
+    pub fn key_type(&self) -> &str {
+        &self.key_type
+    }
+
+    pub fn value_type(&self) -> &str {
+        &self.value_type
+    }
+
+    /// Reads the next entry, or `None` once the trailer has been read and checked.
+    pub fn next_entry(&mut self) -> Result<Option<(Vec<u8>, Vec<u8>)>> {
+        if self.finished {
+            return Ok(None);
+        }
+
+        let tag = read_vec(&mut self.reader, 1)?;
+        match tag[0] {
+            TAG_RECORD => {
+                let mut record = tag;
+                let mut fields = Vec::with_capacity(2);
+                for _ in 0..2 {
+                    let len = read_vec(&mut self.reader, 4)?;
+                    record.extend_from_slice(&len);
+                    let bytes = read_vec(
+                        &mut self.reader,
+                        u32::from_be_bytes(len[..].try_into().unwrap()) as usize,
+                    )?;
+                    record.extend_from_slice(&bytes);
+                    fields.push(bytes);
+                }
+                check_crc(&mut self.reader, &record, "record")?;
+
+                self.record_cnt += 1;
+                let value = fields.pop().unwrap();
+                let key = fields.pop().unwrap();
+                Ok(Some((key, value)))
+            }
+            TAG_END => {
+                let mut trailer = tag;
+                trailer.extend_from_slice(&read_vec(&mut self.reader, 8)?);
+                check_crc(&mut self.reader, &trailer, "trailer")?;
+
+                let record_cnt = u64::from_be_bytes(trailer[1..].try_into().unwrap());
+                if record_cnt != self.record_cnt {
+                    return Err(Error::corruption(format!(
+                        "dump trailer expects {} records, found {}",
+                        record_cnt, self.record_cnt
+                    )));
+                }
+                self.finished = true;
+                Ok(None)
+            }
+            other => Err(Error::corruption(format!("unknown dump tag {}", other))),
+        }
+    }
+}
+
+/// Reads exactly `len` bytes. The buffer grows as bytes arrive rather than being allocated up
+/// front, so a corrupted length can't trigger a huge allocation.
+pub(crate) fn read_vec<R: Read>(reader: &mut R, len: usize) -> Result<Vec<u8>> {
+    let mut buf = Vec::new();
+    reader.by_ref().take(len as u64).read_to_end(&mut buf)?;
+    if buf.len() != len {
+        return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+    }
+    Ok(buf)
+}
+
+fn check_crc<R: Read>(reader: &mut R, bytes: &[u8], what: &str) -> Result<()> {
+    let crc = read_vec(reader, 4)?;
+    if u32::from_be_bytes(crc[..].try_into().unwrap()) != crc32(bytes) {
+        return Err(Error::corruption(format!(
+            "dump {} checksum mismatch",
+            what
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::DumpReader;
+    use super::DumpWriter;
+    use crate::error::Error;
+
+    #[test]
+    fn round_trip_and_corruption() {
+        let mut buf = Vec::new();
+        let mut writer = DumpWriter::new(&mut buf, "key", "value").unwrap();
+        writer.write_entry(b"a", b"1").unwrap();
+        writer.write_entry(b"b", b"").unwrap();
+        assert_eq!(writer.finish().unwrap(), 2);
+
+        let mut reader = DumpReader::new(&buf[..]).unwrap();
+        assert_eq!((reader.key_type(), reader.value_type()), ("key", "value"));
+        assert_eq!(
+            reader.next_entry().unwrap(),
+            Some((b"a".to_vec(), b"1".to_vec()))
+        );
+        assert_eq!(reader.next_entry().unwrap(), Some((b"b".to_vec(), vec![])));
+        assert_eq!(reader.next_entry().unwrap(), None);
+
+        // Flip a bit in the first record's value
+        let mut corrupted = buf.clone();
+        let first_record = buf.len() - 13 - 14 - 15;
+        corrupted[first_record + 10] ^= 1;
+        let mut reader = DumpReader::new(&corrupted[..]).unwrap();
+        assert!(matches!(reader.next_entry(), Err(Error::Corruption { .. })));
+
+        // A stream cut short before its trailer
+        let mut reader = DumpReader::new(&buf[..buf.len() - 13]).unwrap();
+        reader.next_entry().unwrap();
+        reader.next_entry().unwrap();
+        assert!(matches!(reader.next_entry(), Err(Error::Io(_))));
+    }
+}
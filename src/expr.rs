@@ -0,0 +1,641 @@
+//! Expressions over the fields of a row, e.g. `age >= 18 AND nickname IS NOT NULL`, evaluated
+//! against rows of the `row` format. Unlike the closures `scan_filter` takes, expressions can be
+//! persisted with `crate::encoding`.
+//!
+//! Evaluation follows SQL: comparisons and arithmetic on a NULL yield NULL, `And` and `Or` use
+//! three-valued logic, and a row only `matches` if the expression evaluates to true. Integers of
+//! different types are compared and combined by value, and with floats as floats.
+
+use crate::encoding::Decode;
+use crate::encoding::Encode;
+use crate::error::Error;
+use crate::error::Result;
+use crate::planner::KeyPredicate;
+use crate::row::Field;
+use crate::row::RowRef;
+use crate::row::Schema;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::ops::Bound;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CmpOp {
+    Eq,
+    Ne,
+    Lt,
+    Le,
+    Gt,
+    Ge,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArithOp {
+    Add,
+    Sub,
+    Mul,
+    Div,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Expr {
+    /// The field of the column at this index in the row's schema.
+    Column(usize),
+    Const(Field),
+    /// A placeholder for the value at this index of the parameters given to `bind`.
+    Param(usize),
+    Cmp(CmpOp, Box<Expr>, Box<Expr>),
+    Arith(ArithOp, Box<Expr>, Box<Expr>),
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    IsNull(Box<Expr>),
+}
+
+impl Expr {
+    /// Refers to the column called `name`, failing with `Error::TypeMismatch` if `schema` has no
+    /// such column.
+    pub fn column(schema: &Schema, name: &str) -> Result<Expr> {
+        schema
+            .column_idx(name)
+            .map(Expr::Column)
+            .ok_or_else(|| Error::TypeMismatch(format!("no column {}", name)))
+    }
+
+    pub fn cmp(op: CmpOp, lhs: Expr, rhs: Expr) -> Expr {
+        Expr::Cmp(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    pub fn arith(op: ArithOp, lhs: Expr, rhs: Expr) -> Expr {
+        Expr::Arith(op, Box::new(lhs), Box::new(rhs))
+    }
+
+    pub fn and(self, rhs: Expr) -> Expr {
+        Expr::And(Box::new(self), Box::new(rhs))
+    }
+
+    pub fn or(self, rhs: Expr) -> Expr {
+        Expr::Or(Box::new(self), Box::new(rhs))
+    }
+
+    pub fn is_null(self) -> Expr {
+        Expr::IsNull(Box::new(self))
+    }
+
+    /// Checks that every column the expression refers to exists in `schema`, which should be done
+    /// before evaluating an expression that wasn't built against that schema, e.g. one loaded
+    /// from disk.
+    pub fn check(&self, schema: &Schema) -> Result<()> {
+        match self {
+            Expr::Column(idx) if *idx >= schema.columns().len() => {
+                Err(Error::TypeMismatch(format!(
+                    "no column {} in a {} column schema",
+                    idx,
+                    schema.columns().len()
+                )))
+            }
+            Expr::Column(_) | Expr::Const(_) | Expr::Param(_) => Ok(()),
+            Expr::Cmp(_, lhs, rhs)
+            | Expr::Arith(_, lhs, rhs)
+            | Expr::And(lhs, rhs)
+            | Expr::Or(lhs, rhs) => {
+                lhs.check(schema)?;
+                rhs.check(schema)
+            }
+            Expr::Not(expr) | Expr::IsNull(expr) => expr.check(schema),
+        }
+    }
+
+    /// Replaces every `Param` with the corresponding value of `params`.
+    pub fn bind(&self, params: &[Field]) -> Result<Expr> {
+        let bind = |expr: &Expr| expr.bind(params).map(Box::new);
+        let bound = match self {
+            Expr::Param(idx) => Expr::Const(params.get(*idx).cloned().ok_or_else(|| {
+                Error::Eval(format!(
+                    "parameter {} is missing, {} given",
+                    idx + 1,
+                    params.len()
+                ))
+            })?),
+            Expr::Column(_) | Expr::Const(_) => self.clone(),
+            Expr::Cmp(op, lhs, rhs) => Expr::Cmp(*op, bind(lhs)?, bind(rhs)?),
+            Expr::Arith(op, lhs, rhs) => Expr::Arith(*op, bind(lhs)?, bind(rhs)?),
+            Expr::And(lhs, rhs) => Expr::And(bind(lhs)?, bind(rhs)?),
+            Expr::Or(lhs, rhs) => Expr::Or(bind(lhs)?, bind(rhs)?),
+            Expr::Not(expr) => Expr::Not(bind(expr)?),
+            Expr::IsNull(expr) => Expr::IsNull(bind(expr)?),
+        };
+        Ok(bound)
+    }
+
+    /// Evaluates the expression against `row`. Fails with `Error::TypeMismatch` if an operator
+    /// is applied to fields it doesn't support, and with `Error::Eval` on overflow, division by
+    /// zero or an unbound parameter.
+    pub fn eval(&self, row: &RowRef<'_>) -> Result<Field> {
+        let field = match self {
+            Expr::Column(idx) if *idx >= row.column_cnt() => {
+                return Err(Error::TypeMismatch(format!(
+                    "no column {} in a {} column row",
+                    idx,
+                    row.column_cnt()
+                )))
+            }
+            Expr::Column(idx) => row.get(*idx)?,
+            Expr::Const(field) => field.clone(),
+            Expr::Param(idx) => {
+                return Err(Error::Eval(format!("parameter {} isn't bound", idx + 1)))
+            }
+            Expr::Cmp(op, lhs, rhs) => match (lhs.eval(row)?, rhs.eval(row)?) {
+                (Field::Null, _) | (_, Field::Null) => Field::Null,
+                (lhs, rhs) => {
+                    let ord = compare(&lhs, &rhs)?;
+                    Field::Bool(holds(*op, ord))
+                }
+            },
+            Expr::Arith(op, lhs, rhs) => match (lhs.eval(row)?, rhs.eval(row)?) {
+                (Field::Null, _) | (_, Field::Null) => Field::Null,
+                (lhs, rhs) => arith(*op, &lhs, &rhs)?,
+            },
+            Expr::And(lhs, rhs) => match truth(lhs.eval(row)?)? {
+                Some(false) => Field::Bool(false),
+                lhs => match (lhs, truth(rhs.eval(row)?)?) {
+                    (_, Some(false)) => Field::Bool(false),
+                    (Some(true), Some(true)) => Field::Bool(true),
+                    _ => Field::Null,
+                },
+            },
+            Expr::Or(lhs, rhs) => match truth(lhs.eval(row)?)? {
+                Some(true) => Field::Bool(true),
+                lhs => match (lhs, truth(rhs.eval(row)?)?) {
+                    (_, Some(true)) => Field::Bool(true),
+                    (Some(false), Some(false)) => Field::Bool(false),
+                    _ => Field::Null,
+                },
+            },
+            Expr::Not(expr) => match truth(expr.eval(row)?)? {
+                Some(value) => Field::Bool(!value),
+                None => Field::Null,
+            },
+            Expr::IsNull(expr) => Field::Bool(expr.eval(row)? == Field::Null),
+        };
+        Ok(field)
+    }
+
+    /// Whether the expression evaluates to true for `row`. NULL counts as false, like in a
+    /// `WHERE` clause, and anything other than a bool fails with `Error::TypeMismatch`.
+    pub fn matches(&self, row: &RowRef<'_>) -> Result<bool> {
+        Ok(truth(self.eval(row)?)? == Some(true))
+    }
+
+    /// Whether every row the expression matches is sure to be matched by `other` as well. Only
+    /// recognizes what a planner needs to use a partial index, see `Table::create_index`: `other`
+    /// being made of the expression's conjuncts, and comparisons of a column with a constant
+    /// implying looser ones, e.g. `age > 30` implying `age >= 18` and `age IS NOT NULL`. False
+    /// when unsure.
+    pub fn implies(&self, other: &Expr) -> bool {
+        if self == other {
+            return true;
+        }
+        match (self, other) {
+            (_, Expr::And(lhs, rhs)) => self.implies(lhs) && self.implies(rhs),
+            (Expr::Or(lhs, rhs), _) => lhs.implies(other) && rhs.implies(other),
+            (Expr::And(lhs, rhs), _) => lhs.implies(other) || rhs.implies(other),
+            (_, Expr::Or(lhs, rhs)) => self.implies(lhs) || self.implies(rhs),
+            _ => {
+                let (column, op, value) = match self.column_cmp() {
+                    Some(cmp) => cmp,
+                    None => return false,
+                };
+                match (other, other.column_cmp()) {
+                    // A comparison is never true for NULL
+                    (Expr::Not(expr), _) => {
+                        matches!(expr.as_ref(), Expr::IsNull(expr) if **expr == Expr::Column(column))
+                    }
+                    (_, Some((other_column, other_op, bound))) if other_column == column => {
+                        cmp_implies(op, value, other_op, bound)
+                    }
+                    _ => false,
+                }
+            }
+        }
+    }
+
+    /// The values of column `column` a row must have for the expression to match it, gathered
+    /// from its conjuncts comparing the column with a constant, or `None` if there are no such
+    /// conjuncts. An equality wins over bounds, and of several bounds on the same side any one
+    /// may be kept, so rows within the range still need checking against the expression.
+    pub fn key_predicate(&self, column: usize) -> Option<KeyPredicate<Field>> {
+        let mut conjuncts = vec![self];
+        let mut start = Bound::Unbounded;
+        let mut end = Bound::Unbounded;
+        while let Some(expr) = conjuncts.pop() {
+            if let Expr::And(lhs, rhs) = expr {
+                conjuncts.push(rhs);
+                conjuncts.push(lhs);
+                continue;
+            }
+            match expr.column_cmp() {
+                Some((idx, op, value)) if idx == column => match op {
+                    CmpOp::Eq => return Some(KeyPredicate::Eq(value.clone())),
+                    CmpOp::Gt => start = Bound::Excluded(value.clone()),
+                    CmpOp::Ge => start = Bound::Included(value.clone()),
+                    CmpOp::Lt => end = Bound::Excluded(value.clone()),
+                    CmpOp::Le => end = Bound::Included(value.clone()),
+                    CmpOp::Ne => {}
+                },
+                _ => {}
+            }
+        }
+        match (&start, &end) {
+            (Bound::Unbounded, Bound::Unbounded) => None,
+            _ => Some(KeyPredicate::Range(start, end)),
+        }
+    }
+
+    /// The column, operator and constant of a comparison between a column and a constant, with
+    /// the operator flipped if the constant comes first.
+    fn column_cmp(&self) -> Option<(usize, CmpOp, &Field)> {
+        let (op, lhs, rhs) = match self {
+            Expr::Cmp(op, lhs, rhs) => (*op, lhs.as_ref(), rhs.as_ref()),
+            _ => return None,
+        };
+        match (lhs, rhs) {
+            (Expr::Column(column), Expr::Const(value)) => Some((*column, op, value)),
+            (Expr::Const(value), Expr::Column(column)) => {
+                let flipped = match op {
+                    CmpOp::Lt => CmpOp::Gt,
+                    CmpOp::Le => CmpOp::Ge,
+                    CmpOp::Gt => CmpOp::Lt,
+                    CmpOp::Ge => CmpOp::Le,
+                    CmpOp::Eq | CmpOp::Ne => op,
+                };
+                Some((*column, flipped, value))
+            }
+            _ => None,
+        }
+    }
+}
+
+impl std::ops::Not for Expr {
+    type Output = Expr;
+
+    fn not(self) -> Expr {
+        Expr::Not(Box::new(self))
+    }
+}
+
+/// Whether `op` holds between two values that compare as `ord`.
+fn holds(op: CmpOp, ord: Ordering) -> bool {
+    match op {
+        CmpOp::Eq => ord == Ordering::Equal,
+        CmpOp::Ne => ord != Ordering::Equal,
+        CmpOp::Lt => ord == Ordering::Less,
+        CmpOp::Le => ord != Ordering::Greater,
+        CmpOp::Gt => ord == Ordering::Greater,
+        CmpOp::Ge => ord != Ordering::Less,
+    }
+}
+
+/// Whether `x op value` implies `x other_op bound` for every `x`.
+fn cmp_implies(op: CmpOp, value: &Field, other_op: CmpOp, bound: &Field) -> bool {
+    let ord = match compare(value, bound) {
+        Ok(ord) => ord,
+        Err(_) => return false,
+    };
+    match (op, other_op) {
+        (CmpOp::Eq, _) => holds(other_op, ord),
+        (CmpOp::Ne, CmpOp::Ne) => ord == Ordering::Equal,
+        (CmpOp::Lt, CmpOp::Lt | CmpOp::Le | CmpOp::Ne) | (CmpOp::Le, CmpOp::Le) => {
+            ord != Ordering::Greater
+        }
+        (CmpOp::Le, CmpOp::Lt | CmpOp::Ne) => ord == Ordering::Less,
+        (CmpOp::Gt, CmpOp::Gt | CmpOp::Ge | CmpOp::Ne) | (CmpOp::Ge, CmpOp::Ge) => {
+            ord != Ordering::Less
+        }
+        (CmpOp::Ge, CmpOp::Gt | CmpOp::Ne) => ord == Ordering::Greater,
+        _ => false,
+    }
+}
+
+/// The value of a boolean field, or `None` for NULL.
+fn truth(field: Field) -> Result<Option<bool>> {
+    match field {
+        Field::Bool(value) => Ok(Some(value)),
+        Field::Null => Ok(None),
+        other => Err(Error::TypeMismatch(format!("{:?} isn't a bool", other))),
+    }
+}
+
+/// A number that can take part in comparisons and arithmetic with other numbers.
+enum Number {
+    Int(i128),
+    Float(f64),
+}
+
+fn number(field: &Field) -> Option<Number> {
+    match field {
+        Field::I32(value) => Some(Number::Int(*value as i128)),
+        Field::I64(value) => Some(Number::Int(*value as i128)),
+        Field::U32(value) => Some(Number::Int(*value as i128)),
+        Field::U64(value) => Some(Number::Int(*value as i128)),
+        Field::F64(value) => Some(Number::Float(*value)),
+        _ => None,
+    }
+}
+
+fn compare(lhs: &Field, rhs: &Field) -> Result<Ordering> {
+    let ord = match (lhs, rhs) {
+        (Field::Bool(lhs), Field::Bool(rhs)) => lhs.cmp(rhs),
+        (Field::Text(lhs), Field::Text(rhs)) => lhs.cmp(rhs),
+        (Field::Bytes(lhs), Field::Bytes(rhs)) => lhs.cmp(rhs),
+        _ => match (number(lhs), number(rhs)) {
+            (Some(Number::Int(lhs)), Some(Number::Int(rhs))) => lhs.cmp(&rhs),
+            (Some(lhs), Some(rhs)) => as_f64(lhs).total_cmp(&as_f64(rhs)),
+            _ => {
+                return Err(Error::TypeMismatch(format!(
+                    "can't compare {:?} with {:?}",
+                    lhs, rhs
+                )))
+            }
+        },
+    };
+    Ok(ord)
+}
+
+fn as_f64(number: Number) -> f64 {
+    match number {
+        Number::Int(value) => value as f64,
+        Number::Float(value) => value,
+    }
+}
+
+/// Integers of the same type stay that type, integers of different types become `I64`, and
+/// anything involving a float becomes `F64`.
+fn arith(op: ArithOp, lhs: &Field, rhs: &Field) -> Result<Field> {
+    let (lhs_num, rhs_num) = match (number(lhs), number(rhs)) {
+        (Some(lhs), Some(rhs)) => (lhs, rhs),
+        _ => {
+            return Err(Error::TypeMismatch(format!(
+                "can't apply {:?} to {:?} and {:?}",
+                op, lhs, rhs
+            )))
+        }
+    };
+
+    let (lhs_int, rhs_int) = match (lhs_num, rhs_num) {
+        (Number::Int(lhs), Number::Int(rhs)) => (lhs, rhs),
+        (lhs, rhs) => {
+            let (lhs, rhs) = (as_f64(lhs), as_f64(rhs));
+            return Ok(Field::F64(match op {
+                ArithOp::Add => lhs + rhs,
+                ArithOp::Sub => lhs - rhs,
+                ArithOp::Mul => lhs * rhs,
+                ArithOp::Div => lhs / rhs,
+            }));
+        }
+    };
+    if op == ArithOp::Div && rhs_int == 0 {
+        return Err(Error::Eval("division by zero".to_string()));
+    }
+    let value = match op {
+        ArithOp::Add => lhs_int + rhs_int,
+        ArithOp::Sub => lhs_int - rhs_int,
+        ArithOp::Mul => lhs_int.checked_mul(rhs_int).unwrap_or(i128::MAX),
+        ArithOp::Div => lhs_int / rhs_int,
+    };
+
+    let overflow = || Error::Eval(format!("{:?} of {:?} and {:?} overflows", op, lhs, rhs));
+    let field = match (lhs, rhs) {
+        (Field::I32(_), Field::I32(_)) => Field::I32(i32::try_from(value).map_err(|_| overflow())?),
+        (Field::U32(_), Field::U32(_)) => Field::U32(u32::try_from(value).map_err(|_| overflow())?),
+        (Field::U64(_), Field::U64(_)) => Field::U64(u64::try_from(value).map_err(|_| overflow())?),
+        _ => Field::I64(i64::try_from(value).map_err(|_| overflow())?),
+    };
+    Ok(field)
+}
+
+/// Expressions are encoded with `crate::encoding` as a tag byte per node followed by its operands.
+impl Encode for Expr {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        match self {
+            Expr::Column(idx) => (0u8, *idx as u32).encode_to(buf),
+            Expr::Const(field) => (1u8, field).encode_to(buf),
+            Expr::Param(idx) => (2u8, *idx as u32).encode_to(buf),
+            Expr::Cmp(op, lhs, rhs) => (3u8, *op as u8, lhs.as_ref(), rhs.as_ref()).encode_to(buf),
+            Expr::Arith(op, lhs, rhs) => {
+                (4u8, *op as u8, lhs.as_ref(), rhs.as_ref()).encode_to(buf)
+            }
+            Expr::And(lhs, rhs) => (5u8, lhs.as_ref(), rhs.as_ref()).encode_to(buf),
+            Expr::Or(lhs, rhs) => (6u8, lhs.as_ref(), rhs.as_ref()).encode_to(buf),
+            Expr::Not(expr) => (7u8, expr.as_ref()).encode_to(buf),
+            Expr::IsNull(expr) => (8u8, expr.as_ref()).encode_to(buf),
+        }
+    }
+}
+
+impl Decode for Expr {
+    fn decode_from(buf: &mut &[u8]) -> Result<Self> {
+        let operand = |buf: &mut &[u8]| Expr::decode_from(buf).map(Box::new);
+        let expr = match u8::decode_from(buf)? {
+            0 => Expr::Column(u32::decode_from(buf)? as usize),
+            1 => Expr::Const(Field::decode_from(buf)?),
+            2 => Expr::Param(u32::decode_from(buf)? as usize),
+            3 => {
+                let op = match u8::decode_from(buf)? {
+                    0 => CmpOp::Eq,
+                    1 => CmpOp::Ne,
+                    2 => CmpOp::Lt,
+                    3 => CmpOp::Le,
+                    4 => CmpOp::Gt,
+                    5 => CmpOp::Ge,
+                    tag => return Err(Error::corruption(format!("unknown comparison {}", tag))),
+                };
+                Expr::Cmp(op, operand(buf)?, operand(buf)?)
+            }
+            4 => {
+                let op = match u8::decode_from(buf)? {
+                    0 => ArithOp::Add,
+                    1 => ArithOp::Sub,
+                    2 => ArithOp::Mul,
+                    3 => ArithOp::Div,
+                    tag => return Err(Error::corruption(format!("unknown operator {}", tag))),
+                };
+                Expr::Arith(op, operand(buf)?, operand(buf)?)
+            }
+            5 => Expr::And(operand(buf)?, operand(buf)?),
+            6 => Expr::Or(operand(buf)?, operand(buf)?),
+            7 => Expr::Not(operand(buf)?),
+            8 => Expr::IsNull(operand(buf)?),
+            tag => return Err(Error::corruption(format!("unknown expression {}", tag))),
+        };
+        Ok(expr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::ArithOp;
+    use super::CmpOp;
+    use super::Expr;
+    use crate::encoding::decode;
+    use crate::encoding::encode;
+    use crate::error::Error;
+    use crate::planner::KeyPredicate;
+    use crate::row::Column;
+    use crate::row::ColumnType;
+    use crate::row::Field;
+    use crate::row::Schema;
+    use std::ops::Bound;
+
+    fn schema() -> Schema {
+        let column = |name: &str, column_type| Column {
+            name: name.to_string(),
+            column_type,
+            nullable: true,
+        };
+        Schema::new(vec![
+            column("id", ColumnType::U64),
+            column("name", ColumnType::Text),
+            column("age", ColumnType::I32),
+            column("score", ColumnType::F64),
+        ])
+    }
+
+    #[test]
+    fn evaluates_against_rows() {
+        let schema = schema();
+        let ada = schema
+            .encode(&[
+                Field::U64(1),
+                Field::Text("Ada".to_string()),
+                Field::I32(36),
+                Field::Null,
+            ])
+            .unwrap();
+        let ada = schema.row_ref(&ada).unwrap();
+        let col = |name| Expr::column(&schema, name).unwrap();
+
+        // age + 4 >= 40 AND name <> 'Bob'
+        let adult = Expr::cmp(
+            CmpOp::Ge,
+            Expr::arith(ArithOp::Add, col("age"), Expr::Const(Field::I32(4))),
+            Expr::Const(Field::I64(40)),
+        )
+        .and(Expr::cmp(
+            CmpOp::Ne,
+            col("name"),
+            Expr::Const(Field::Text("Bob".to_string())),
+        ));
+        assert!(adult.matches(&ada).unwrap());
+        assert_eq!(
+            Expr::arith(ArithOp::Mul, col("id"), Expr::Const(Field::F64(0.5)))
+                .eval(&ada)
+                .unwrap(),
+            Field::F64(0.5)
+        );
+
+        // Comparisons with NULL are unknown, which only OR with true turns into a match
+        let high_score = Expr::cmp(CmpOp::Gt, col("score"), Expr::Const(Field::F64(1.0)));
+        assert_eq!(high_score.eval(&ada).unwrap(), Field::Null);
+        assert!(!high_score.matches(&ada).unwrap());
+        assert!(!(!high_score.clone()).matches(&ada).unwrap());
+        assert!(high_score.clone().or(adult).matches(&ada).unwrap());
+        assert!(col("score").is_null().matches(&ada).unwrap());
+
+        let param = Expr::cmp(CmpOp::Eq, col("name"), Expr::Param(0));
+        assert!(matches!(param.matches(&ada), Err(Error::Eval(_))));
+        let bound = param.bind(&[Field::Text("Ada".to_string())]).unwrap();
+        assert!(bound.matches(&ada).unwrap());
+
+        assert!(matches!(
+            Expr::cmp(CmpOp::Eq, col("name"), col("age")).eval(&ada),
+            Err(Error::TypeMismatch(_))
+        ));
+        assert!(matches!(
+            Expr::arith(ArithOp::Div, col("age"), Expr::Const(Field::I32(0))).eval(&ada),
+            Err(Error::Eval(_))
+        ));
+        assert!(matches!(
+            Expr::arith(ArithOp::Mul, col("age"), Expr::Const(Field::I32(i32::MAX))).eval(&ada),
+            Err(Error::Eval(_))
+        ));
+        assert!(matches!(
+            Expr::Column(4).check(&schema),
+            Err(Error::TypeMismatch(_))
+        ));
+    }
+
+    #[test]
+    fn implication_and_key_predicates() {
+        let schema = schema();
+        let col = |name| Expr::column(&schema, name).unwrap();
+        let cmp = |op, name, value| Expr::cmp(op, col(name), Expr::Const(value));
+        let active = cmp(CmpOp::Eq, "name", Field::Text("active".to_string()));
+        let adult = cmp(CmpOp::Ge, "age", Field::I32(18));
+
+        assert!(active.implies(&active));
+        assert!(active.clone().and(adult.clone()).implies(&active));
+        assert!(adult.clone().and(active.clone()).implies(&active));
+        assert!(!active.clone().or(adult.clone()).implies(&active));
+        assert!(active.implies(&active.clone().or(adult.clone())));
+        assert!(!active.implies(&adult));
+
+        // Tighter comparisons imply looser ones, whichever side the constant is on
+        let older = Expr::cmp(CmpOp::Lt, Expr::Const(Field::I64(30)), col("age"));
+        assert!(older.implies(&adult));
+        assert!(older.implies(&!col("age").is_null()));
+        assert!(cmp(CmpOp::Eq, "age", Field::I32(18)).implies(&adult));
+        assert!(cmp(CmpOp::Gt, "age", Field::F64(17.5)).implies(&cmp(
+            CmpOp::Gt,
+            "age",
+            Field::I32(17)
+        )));
+        assert!(!cmp(CmpOp::Gt, "age", Field::I32(17)).implies(&adult.clone().and(older)));
+        assert!(cmp(CmpOp::Lt, "age", Field::I32(10)).implies(&cmp(
+            CmpOp::Ne,
+            "age",
+            Field::I32(10)
+        )));
+        assert!(!cmp(CmpOp::Le, "age", Field::I32(10)).implies(&cmp(
+            CmpOp::Ne,
+            "age",
+            Field::I32(10)
+        )));
+        assert!(!cmp(CmpOp::Ge, "score", Field::F64(18.0)).implies(&adult));
+        assert!(!cmp(CmpOp::Ge, "name", Field::Text("x".to_string())).implies(&adult));
+
+        let query = active
+            .clone()
+            .and(cmp(CmpOp::Lt, "age", Field::I32(65)).and(adult.clone()));
+        assert_eq!(
+            query.key_predicate(2),
+            Some(KeyPredicate::Range(
+                Bound::Included(Field::I32(18)),
+                Bound::Excluded(Field::I32(65))
+            ))
+        );
+        assert_eq!(
+            query.key_predicate(1),
+            Some(KeyPredicate::Eq(Field::Text("active".to_string())))
+        );
+        assert_eq!(query.key_predicate(0), None);
+        assert_eq!(active.or(adult).key_predicate(2), None);
+    }
+
+    #[test]
+    fn round_trips_through_encoding() {
+        let schema = schema();
+        let expr = Expr::cmp(
+            CmpOp::Lt,
+            Expr::arith(
+                ArithOp::Sub,
+                Expr::column(&schema, "score").unwrap(),
+                Expr::Const(Field::F64(-1.5)),
+            ),
+            Expr::Param(0),
+        )
+        .or(Expr::Const(Field::Text("x".to_string())).is_null())
+        .and(!Expr::Const(Field::Bytes(vec![0, 1])).is_null());
+        assert_eq!(decode::<Expr>(&encode(&expr)).unwrap(), expr);
+        assert!(decode::<Expr>(&[9]).is_err());
+    }
+}
@@ -0,0 +1,228 @@
+//! A page fetcher that fails on cue, for unit testing how trees and the code on top of them handle
+//! errors. Unlike `SimPageFetcher`, which injects faults at random, faults are scripted against
+//! particular calls, e.g. the third page read from now, so a test can aim at one step of an
+//! operation.
+//!
+//! Built for this crate's tests, and exported for other crates' with the `test-util` feature.
+
+use crate::error::Error;
+use crate::error::Result;
+use crate::metrics::Metrics;
+use crate::page_fetcher::PageFetcher;
+use crate::page_fetcher::PagePtr;
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::io;
+use std::sync::RwLockReadGuard;
+use std::sync::RwLockWriteGuard;
+use std::time::Duration;
+
+/// The kinds of `PageFetcher` calls a fault can be aimed at.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Call {
+    /// `fetch_page_read`.
+    Read,
+    /// `fetch_page_write`.
+    Write,
+    NewPage,
+    FreePage,
+}
+
+const CALL_KINDS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The call fails with an I/O error.
+    Io,
+    /// The call fails with `Error::Lock`, as if the page's lock had been poisoned by a panicking
+    /// holder.
+    PoisonedLock,
+    /// The call sleeps this long and then goes ahead.
+    Delay(Duration),
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Scripted {
+    call: Call,
+    /// The call's ordinal, counting calls of its kind from 1, or `None` for every call.
+    nth: Option<u64>,
+    fault: Fault,
+}
+
+/// Wraps another fetcher, passing calls through unless a scripted fault applies to them.
+pub struct FaultyPageFetcher<F: PageFetcher> {
+    inner: F,
+    calls: Cell<[u64; CALL_KINDS]>,
+    script: RefCell<Vec<Scripted>>,
+}
+
+impl<F: PageFetcher> FaultyPageFetcher<F> {
+    pub fn new(inner: F) -> Self {
+        FaultyPageFetcher {
+            inner,
+            calls: Cell::new([0; CALL_KINDS]),
+            script: RefCell::new(Vec::new()),
+        }
+    }
+
+    /// Injects `fault` into the `nth` call of kind `call` from now on, counting from 1. The fault
+    /// is dropped from the script once it's been injected.
+    pub fn fault_nth(&self, call: Call, nth: u64, fault: Fault) {
+        assert!(nth > 0, "calls are counted from 1");
+        self.script.borrow_mut().push(Scripted {
+            call,
+            nth: Some(self.calls(call) + nth),
+            fault,
+        });
+    }
+
+    /// Injects `fault` into every call of kind `call` until `clear_faults`.
+    pub fn fault_all(&self, call: Call, fault: Fault) {
+        self.script.borrow_mut().push(Scripted {
+            call,
+            nth: None,
+            fault,
+        });
+    }
+
+    /// Drops every fault that hasn't been injected yet.
+    pub fn clear_faults(&self) {
+        self.script.borrow_mut().clear();
+    }
+
+    /// The number of calls of kind `call` made so far, including those that failed.
+    pub fn calls(&self, call: Call) -> u64 {
+        self.calls.get()[call as usize]
+    }
+
+    pub fn inner(&self) -> &F {
+        &self.inner
+    }
+
+    pub fn into_inner(self) -> F {
+        self.inner
+    }
+
+    /// Counts the call and injects the first fault scripted for it, if any.
+    fn enter(&self, call: Call) -> Result<()> {
+        let mut calls = self.calls.get();
+        calls[call as usize] += 1;
+        self.calls.set(calls);
+        let nth = calls[call as usize];
+
+        let fault = {
+            let mut script = self.script.borrow_mut();
+            let idx = script.iter().position(|scripted| {
+                scripted.call == call && scripted.nth.is_none_or(|at| at == nth)
+            });
+            match idx {
+                Some(idx) if script[idx].nth.is_some() => script.remove(idx).fault,
+                Some(idx) => script[idx].fault,
+                None => return Ok(()),
+            }
+        };
+        match fault {
+            Fault::Io => {
+                Err(io::Error::other(format!("injected fault in {:?} {}", call, nth)).into())
+            }
+            Fault::PoisonedLock => Err(Error::Lock),
+            Fault::Delay(delay) => {
+                std::thread::sleep(delay);
+                Ok(())
+            }
+        }
+    }
+}
+
+impl<F: PageFetcher> PageFetcher for FaultyPageFetcher<F> {
+    fn fetch_page_read(&self, page_no: u32) -> Result<RwLockReadGuard<'_, PagePtr>> {
+        self.enter(Call::Read)?;
+        self.inner.fetch_page_read(page_no)
+    }
+
+    fn fetch_page_write(&self, page_no: u32) -> Result<RwLockWriteGuard<'_, PagePtr>> {
+        self.enter(Call::Write)?;
+        self.inner.fetch_page_write(page_no)
+    }
+
+    fn new_page<T: Sized>(&self, special_data: T) -> Result<(u32, RwLockWriteGuard<'_, PagePtr>)> {
+        self.enter(Call::NewPage)?;
+        self.inner.new_page(special_data)
+    }
+
+    fn free_page(&self, page_no: u32) -> Result<()> {
+        self.enter(Call::FreePage)?;
+        self.inner.free_page(page_no)
+    }
+
+    fn check_room(&self, page_cnt: usize) -> Result<()> {
+        self.inner.check_room(page_cnt)
+    }
+
+    fn page_cnt(&self) -> usize {
+        self.inner.page_cnt()
+    }
+
+    fn metrics(&self) -> &Metrics {
+        self.inner.metrics()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Call;
+    use super::Fault;
+    use super::FaultyPageFetcher;
+    use crate::btree::key::KeyU64;
+    use crate::btree::value::ValueTupleId;
+    use crate::btree::BTree;
+    use crate::error::Error;
+    use crate::page_fetcher::InMemoryPageFetcher;
+    use std::time::Duration;
+    use std::time::Instant;
+
+    #[test]
+    fn scripted_faults() {
+        let value = |key: u64| ValueTupleId {
+            page_no: key as u32,
+            offset: 0,
+        };
+        let fetcher = FaultyPageFetcher::new(InMemoryPageFetcher::with_capacity(64));
+        let mut btree = BTree::new(fetcher).unwrap();
+        for key in 0..2000 {
+            btree.insert(KeyU64 { key }, value(key)).unwrap();
+        }
+
+        // A lookup reads the metadata, the root and then a leaf
+        btree.page_fetcher().fault_nth(Call::Read, 3, Fault::Io);
+        let reads = btree.page_fetcher().calls(Call::Read);
+        let err = btree.search(KeyU64 { key: 5 }).unwrap_err();
+        assert!(matches!(err.root_cause(), Error::Io(_)), "{}", err);
+        assert_eq!(btree.page_fetcher().calls(Call::Read), reads + 3);
+        assert_eq!(
+            btree.search(KeyU64 { key: 5 }).unwrap().value,
+            Some(value(5))
+        );
+
+        // Failing to lock the leaf leaves the tree as it was
+        btree
+            .page_fetcher()
+            .fault_nth(Call::Write, 1, Fault::PoisonedLock);
+        let err = btree.insert(KeyU64 { key: 5000 }, value(0)).unwrap_err();
+        assert!(matches!(err.root_cause(), Error::Lock), "{}", err);
+        assert_eq!(btree.search(KeyU64 { key: 5000 }).unwrap().value, None);
+        assert!(btree.verify().unwrap().is_ok());
+
+        btree
+            .page_fetcher()
+            .fault_all(Call::Write, Fault::Delay(Duration::from_millis(5)));
+        let start = Instant::now();
+        btree.insert(KeyU64 { key: 5000 }, value(0)).unwrap();
+        assert!(start.elapsed() >= Duration::from_millis(5));
+        btree.page_fetcher().clear_faults();
+        assert_eq!(
+            btree.search(KeyU64 { key: 5000 }).unwrap().value,
+            Some(value(0))
+        );
+    }
+}
@@ -0,0 +1,423 @@
+use crate::error::Error;
+use crate::error::Result;
+use crate::metrics::HotPage;
+use crate::metrics::MemoryUsage;
+use crate::metrics::Metrics;
+use crate::page::Page;
+use crate::page::PAGE_SIZE;
+use crate::page_fetcher::InMemoryPageFetcher;
+use crate::page_fetcher::PageFetcher;
+use crate::page_fetcher::PagePtr;
+use crate::shadow;
+use crate::shadow::DirtyPages;
+use crate::shadow::PageSink;
+use crate::shadow::ShadowState;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring::Uring;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+use crate::uring::BATCH_SIZE;
+use log::debug;
+use std::fs::File;
+use std::fs::OpenOptions;
+#[cfg(not(target_os = "linux"))]
+use std::io;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+#[cfg(target_os = "linux")]
+use std::os::unix::fs::OpenOptionsExt;
+use std::path::Path;
+use std::sync::Mutex;
+use std::sync::RwLockReadGuard;
+use std::sync::RwLockWriteGuard;
+
+/// Keeps every page of a single file in memory, and writes them all back out on `flush`.
+///
+/// TODO: Only write back dirty pages in place too, and evict pages once we have a real buffer
+/// pool.
+///
+/// With the `io-uring` feature on Linux, pages are loaded and flushed in batches through
+/// io_uring, falling back to synchronous I/O if the kernel doesn't allow it.
+///
+/// Files created shadow paged, see `crate::shadow`, are flushed by writing the pages marked dirty
+/// since the last flush elsewhere in the file instead, always synchronously.
+pub struct FilePageFetcher {
+    file: File,
+    pages: InMemoryPageFetcher,
+    /// Set for shadow paged files.
+    shadow: Option<Mutex<ShadowState>>,
+    /// Pages written since the last flush. Only tracked for shadow paged files.
+    dirty: DirtyPages,
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    uring: Option<Mutex<Uring>>,
+    /// Writes made since `record_writes`, if it was called.
+    write_log: Mutex<Option<Vec<FileWrite>>>,
+}
+
+/// A change made to the file, as recorded by `FilePageFetcher::record_writes`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FileWrite {
+    /// `data` was written at byte `offset`.
+    Write { offset: u64, data: Vec<u8> },
+    /// The file was truncated to `len` bytes.
+    SetLen(u64),
+    /// Everything written before is durable.
+    Sync,
+}
+
+impl FilePageFetcher {
+    pub fn open<P: AsRef<Path>>(
+        path: P,
+        create_if_missing: bool,
+        max_pages: usize,
+        memory_budget: Option<usize>,
+        direct_io: bool,
+    ) -> Result<Self> {
+        let mut options = OpenOptions::new();
+        options.read(true).write(true).create(create_if_missing);
+        if direct_io {
+            set_direct_io(&mut options)?;
+        }
+        let mut file = options.open(path)?;
+
+        let file_len = file.metadata()?.len() as usize;
+        let page_cnt = file_len / PAGE_SIZE;
+        // A write of the last page that didn't complete, or a file cut short by copying it
+        let partial = file_len % PAGE_SIZE;
+        if partial != 0 {
+            return Err(Error::page_corruption(
+                page_cnt as u32,
+                format!(
+                    "file of {} bytes ends with a partial page of {} bytes",
+                    file_len, partial
+                ),
+            ));
+        }
+
+        let mut pages = InMemoryPageFetcher::with_capacity(max_pages);
+        pages.set_memory_budget(memory_budget);
+        if shadow::is_shadow_paged(&file, page_cnt)? {
+            let shadow = ShadowState::load(&file, page_cnt, max_pages, &pages)?;
+            pages.rebuild_free_list()?;
+            pages.metrics().page_reads.add(pages.page_cnt() as u64);
+            debug!("Loaded {} shadow paged pages", pages.page_cnt());
+            return Ok(FilePageFetcher {
+                file,
+                pages,
+                shadow: Some(Mutex::new(shadow)),
+                dirty: DirtyPages::new(max_pages),
+                #[cfg(all(feature = "io-uring", target_os = "linux"))]
+                uring: None,
+                write_log: Mutex::new(None),
+            });
+        }
+
+        if page_cnt > max_pages {
+            return Err(Error::OutOfPages);
+        }
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        let uring = Uring::new();
+        #[cfg(all(feature = "io-uring", target_os = "linux"))]
+        let loaded = match uring {
+            Some(mut uring) => {
+                let mut frames = vec![Page::new(0); BATCH_SIZE];
+                for first_page_no in (0..page_cnt).step_by(BATCH_SIZE) {
+                    let batch_cnt = BATCH_SIZE.min(page_cnt - first_page_no);
+                    let batch = &mut frames[..batch_cnt];
+                    uring.read_pages(&file, first_page_no, batch)?;
+                    for page in batch.iter() {
+                        pages.push_page(page)?;
+                    }
+                }
+                Some(Mutex::new(uring))
+            }
+            None => None,
+        };
+        #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+        let loaded: Option<()> = None;
+
+        if loaded.is_none() {
+            // Read straight into a page frame, which is aligned enough for direct I/O
+            let mut frame = Page::new(0);
+            file.seek(SeekFrom::Start(0))?;
+            for _ in 0..page_cnt {
+                file.read_exact(frame.as_bytes_mut())?;
+                pages.push_page(&frame)?;
+            }
+        }
+        pages.rebuild_free_list()?;
+        pages.metrics().page_reads.add(page_cnt as u64);
+        debug!("Loaded {} pages", page_cnt);
+
+        Ok(FilePageFetcher {
+            file,
+            pages,
+            shadow: None,
+            dirty: DirtyPages::new(0),
+            #[cfg(all(feature = "io-uring", target_os = "linux"))]
+            uring: loaded,
+            write_log: Mutex::new(None),
+        })
+    }
+
+    pub fn page_cnt(&self) -> usize {
+        self.pages.page_cnt()
+    }
+
+    pub fn hot_pages(&self, n: usize) -> Vec<HotPage> {
+        self.pages.hot_pages(n)
+    }
+
+    pub fn decay_page_access(&self) {
+        self.pages.decay_page_access()
+    }
+
+    pub fn memory_usage(&self) -> MemoryUsage {
+        self.pages.memory_usage()
+    }
+
+    pub fn free_page_cnt(&self) -> usize {
+        self.pages.free_page_cnt()
+    }
+
+    /// Starts logging every change made to the file, so a crash test can replay them up to any
+    /// point, see `take_writes`. Pages are written one at a time while recording, even through
+    /// io_uring, so that each page write is logged on its own.
+    pub fn record_writes(&self) {
+        *self.write_log.lock().expect("write log lock poisoned") = Some(Vec::new());
+    }
+
+    /// Stops recording, returning the changes made since `record_writes` in the order they were
+    /// made.
+    pub fn take_writes(&self) -> Vec<FileWrite> {
+        let mut write_log = self.write_log.lock().expect("write log lock poisoned");
+        write_log.take().unwrap_or_default()
+    }
+
+    fn log_write<F: FnOnce() -> FileWrite>(&self, write: F) {
+        if let Some(write_log) = self
+            .write_log
+            .lock()
+            .expect("write log lock poisoned")
+            .as_mut()
+        {
+            write_log.push(write());
+        }
+    }
+
+    /// Writes every page back to the file, first shrinking it if pages were dropped off the end
+    /// since, see `PageFetcher::free_page`. A shadow paged file is committed instead.
+    pub fn flush(&self) -> Result<()> {
+        if let Some(shadow) = &self.shadow {
+            let mut shadow = shadow.lock().expect("shadow state lock poisoned");
+            let written = shadow.commit(&self.pages, &self.dirty, self)?;
+            self.pages.metrics().page_writes.add(written as u64);
+            debug!("Committed {} changed pages", written);
+            return Ok(());
+        }
+        let len = (self.pages.page_cnt() * PAGE_SIZE) as u64;
+        if self.file.metadata()?.len() > len {
+            self.file.set_len(len)?;
+            self.log_write(|| FileWrite::SetLen(len));
+            debug!("Truncated the file to {} pages", self.pages.page_cnt());
+        }
+        self.write_pages()?;
+        self.file.sync_all()?;
+        self.log_write(|| FileWrite::Sync);
+        self.pages
+            .metrics()
+            .page_writes
+            .add(self.pages.page_cnt() as u64);
+        debug!("Flushed {} pages", self.pages.page_cnt());
+        Ok(())
+    }
+
+    #[cfg(all(feature = "io-uring", target_os = "linux"))]
+    fn write_pages(&self) -> Result<()> {
+        let recording = self
+            .write_log
+            .lock()
+            .expect("write log lock poisoned")
+            .is_some();
+        let mut uring = match &self.uring {
+            Some(uring) if !recording => uring.lock().expect("io_uring lock poisoned"),
+            _ => return self.write_pages_sync(),
+        };
+        let page_cnt = self.pages.page_cnt();
+        for first_page_no in (0..page_cnt).step_by(BATCH_SIZE) {
+            // Each batch's pages stay read-locked until they've been written
+            let batch = (first_page_no..page_cnt.min(first_page_no + BATCH_SIZE))
+                .map(|page_no| self.pages.fetch_page_read(page_no as u32))
+                .collect::<Result<Vec<_>>>()?;
+            let frames = batch.iter().map(|page| &***page).collect::<Vec<_>>();
+            uring.write_pages(&self.file, first_page_no, &frames)?;
+        }
+        Ok(())
+    }
+
+    #[cfg(not(all(feature = "io-uring", target_os = "linux")))]
+    fn write_pages(&self) -> Result<()> {
+        self.write_pages_sync()
+    }
+
+    fn write_pages_sync(&self) -> Result<()> {
+        let mut file = &self.file;
+        file.seek(SeekFrom::Start(0))?;
+        for page_no in 0..self.pages.page_cnt() {
+            let page = self.pages.fetch_page_read(page_no as u32)?;
+            file.write_all(page.as_bytes())?;
+            self.log_write(|| FileWrite::Write {
+                offset: (page_no * PAGE_SIZE) as u64,
+                data: page.as_bytes().to_vec(),
+            });
+        }
+        Ok(())
+    }
+}
+
+impl PageSink for FilePageFetcher {
+    fn write_page(&self, page_no: u32, page: &Page) -> Result<()> {
+        self.file.write_page(page_no, page)?;
+        self.log_write(|| FileWrite::Write {
+            offset: u64::from(page_no) * PAGE_SIZE as u64,
+            data: page.as_bytes().to_vec(),
+        });
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
+        self.file.sync()?;
+        self.log_write(|| FileWrite::Sync);
+        Ok(())
+    }
+
+    fn truncate(&self, page_cnt: u32) -> Result<()> {
+        self.file.truncate(page_cnt)?;
+        self.log_write(|| FileWrite::SetLen(u64::from(page_cnt) * PAGE_SIZE as u64));
+        Ok(())
+    }
+}
+
+/// Bypasses the OS page cache, since every page is already held in memory. Reads and writes then
+/// have to be done on page-aligned buffers, which page frames are.
+#[cfg(target_os = "linux")]
+fn set_direct_io(options: &mut OpenOptions) -> Result<()> {
+    options.custom_flags(libc::O_DIRECT);
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_direct_io(_options: &mut OpenOptions) -> Result<()> {
+    Err(Error::Io(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "direct I/O is only supported on Linux",
+    )))
+}
+
+impl PageFetcher for FilePageFetcher {
+    fn fetch_page_read(&self, page_no: u32) -> Result<RwLockReadGuard<'_, PagePtr>> {
+        self.pages.fetch_page_read(page_no)
+    }
+
+    fn fetch_page_write(&self, page_no: u32) -> Result<RwLockWriteGuard<'_, PagePtr>> {
+        let page = self.pages.fetch_page_write(page_no)?;
+        self.dirty.mark(page_no);
+        Ok(page)
+    }
+
+    fn new_page<T: Sized>(&self, special_data: T) -> Result<(u32, RwLockWriteGuard<'_, PagePtr>)> {
+        let (page_no, page) = self.pages.new_page(special_data)?;
+        self.dirty.mark(page_no);
+        Ok((page_no, page))
+    }
+
+    fn free_page(&self, page_no: u32) -> Result<()> {
+        self.pages.free_page(page_no)?;
+        self.dirty.mark(page_no);
+        Ok(())
+    }
+
+    fn check_room(&self, page_cnt: usize) -> Result<()> {
+        self.pages.check_room(page_cnt)
+    }
+
+    fn page_cnt(&self) -> usize {
+        self.pages.page_cnt()
+    }
+
+    fn metrics(&self) -> &Metrics {
+        self.pages.metrics()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::FilePageFetcher;
+    use crate::page::PAGE_SIZE;
+    use crate::page_fetcher::PageFetcher;
+
+    #[test]
+    fn flush_and_reopen() {
+        let path = std::env::temp_dir().join(format!("johndb-fetcher-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        // More pages than fit in a single io_uring batch
+        let page_cnt = 150u32;
+        {
+            let fetcher = FilePageFetcher::open(&path, true, 256, None, false).unwrap();
+            for i in 0..page_cnt {
+                let page_no = fetcher.new_page(u64::from(i) * 7).unwrap().0;
+                assert_eq!(page_no, i);
+            }
+            fetcher.flush().unwrap();
+        }
+
+        let fetcher = FilePageFetcher::open(&path, false, 256, None, false).unwrap();
+        assert_eq!(fetcher.page_cnt(), page_cnt as usize);
+        for i in 0..page_cnt {
+            let page = fetcher.fetch_page_read(i).unwrap();
+            assert_eq!(*page.special_data::<u64>(), u64::from(i) * 7);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn truncate_freed_tail() {
+        let path = std::env::temp_dir().join(format!("johndb-truncate-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+
+        {
+            let fetcher = FilePageFetcher::open(&path, true, 16, None, false).unwrap();
+            for i in 0..10u32 {
+                assert_eq!(fetcher.new_page(u64::from(i)).unwrap().0, i);
+            }
+            fetcher.flush().unwrap();
+            for page_no in [3, 9, 7] {
+                fetcher.free_page(page_no).unwrap();
+            }
+            assert_eq!(fetcher.page_cnt(), 9);
+            // Freeing page 8 leaves 7 to 9 free at the end
+            fetcher.free_page(8).unwrap();
+            assert_eq!(fetcher.page_cnt(), 7);
+            assert_eq!(fetcher.free_page_cnt(), 1);
+            fetcher.flush().unwrap();
+        }
+        assert_eq!(
+            std::fs::metadata(&path).unwrap().len(),
+            7 * PAGE_SIZE as u64
+        );
+
+        let fetcher = FilePageFetcher::open(&path, false, 16, None, false).unwrap();
+        assert_eq!(fetcher.page_cnt(), 7);
+        assert_eq!(fetcher.new_page(0u64).unwrap().0, 3);
+        assert_eq!(fetcher.new_page(0u64).unwrap().0, 7);
+        assert_eq!(
+            *fetcher.fetch_page_read(6).unwrap().special_data::<u64>(),
+            6
+        );
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
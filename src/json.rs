@@ -0,0 +1,984 @@
+//! JSON documents as values, stored in a compact binary encoding that paths like `$.a.b[2]` can
+//! be evaluated against without decoding the rest of the document, see `ValueJson` and
+//! `BTree::get_path`. `JsonIndex` indexes the documents of a tree on the value at a path.
+//!
+//! Every value starts with a tag byte. Lengths and counts are big-endian u32s:
+//!
+//! ```text
+//! null, false, true    tag
+//! number               tag, f64 as big-endian bits
+//! string               tag, byte length, UTF-8 bytes
+//! array                tag, body length, element count, elements
+//! object               tag, body length, member count, members: key length, key, value
+//! ```
+//!
+//! Arrays and objects record the length of their body so that they can be skipped over in one
+//! step while looking for the members and elements a path names.
+
+use crate::btree::export::item_from_bytes;
+use crate::btree::export::item_to_bytes;
+use crate::btree::key::Key;
+use crate::btree::key::KeyEncoded;
+use crate::btree::value::Value;
+use crate::btree::value::ValueBytes;
+use crate::btree::BTree;
+use crate::encoding::encode;
+use crate::encoding::Encode;
+use crate::error::Error;
+use crate::error::Result;
+use crate::page::Item;
+use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use std::borrow::Cow;
+use std::convert::TryInto;
+use std::fmt;
+use std::marker::PhantomData;
+
+const TAG_NULL: u8 = 0;
+const TAG_FALSE: u8 = 1;
+const TAG_TRUE: u8 = 2;
+const TAG_NUMBER: u8 = 3;
+const TAG_STRING: u8 = 4;
+const TAG_ARRAY: u8 = 5;
+const TAG_OBJECT: u8 = 6;
+
+/// How deeply arrays and objects may nest in parsed text, so that parsing can't overflow the
+/// stack.
+const MAX_DEPTH: usize = 128;
+
+/// A decoded JSON value. Object members keep their order in the text.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    /// Parses JSON text, failing with `Error::Json` if it isn't valid.
+    pub fn parse(text: &str) -> Result<Self> {
+        let mut parser = Parser {
+            text: text.as_bytes(),
+            pos: 0,
+        };
+        let json = parser.value(0)?;
+        parser.skip_whitespace();
+        match parser.pos == parser.text.len() {
+            true => Ok(json),
+            false => Err(parser.error("trailing characters")),
+        }
+    }
+
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        match self {
+            Json::Null => buf.push(TAG_NULL),
+            Json::Bool(false) => buf.push(TAG_FALSE),
+            Json::Bool(true) => buf.push(TAG_TRUE),
+            Json::Number(number) => {
+                buf.push(TAG_NUMBER);
+                buf.extend_from_slice(&number.to_bits().to_be_bytes());
+            }
+            Json::String(string) => {
+                buf.push(TAG_STRING);
+                put_bytes(buf, string.as_bytes());
+            }
+            Json::Array(elements) => {
+                let start = begin_container(buf, TAG_ARRAY, elements.len());
+                for element in elements {
+                    element.encode_to(buf);
+                }
+                end_container(buf, start);
+            }
+            Json::Object(members) => {
+                let start = begin_container(buf, TAG_OBJECT, members.len());
+                for (key, value) in members {
+                    put_bytes(buf, key.as_bytes());
+                    value.encode_to(buf);
+                }
+                end_container(buf, start);
+            }
+        }
+    }
+
+    /// The order-preserving encoding `JsonIndex` keys scalars by: nulls, then booleans, then
+    /// numbers, then strings. Arrays and objects aren't indexed.
+    fn index_encoding(&self) -> Option<Vec<u8>> {
+        match self {
+            Json::Null => Some(encode(&(0u8,))),
+            Json::Bool(value) => Some(encode(&(1u8, *value))),
+            Json::Number(value) => Some(encode(&(2u8, *value))),
+            Json::String(value) => Some(encode(&(3u8, value.as_str()))),
+            Json::Array(_) | Json::Object(_) => None,
+        }
+    }
+}
+
+/// Compact JSON text. Numbers that can't be represented in JSON, i.e. NaNs and infinities, are
+/// written as `null`.
+impl fmt::Display for Json {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Json::Null => write!(f, "null"),
+            Json::Bool(value) => write!(f, "{}", value),
+            Json::Number(value) if !value.is_finite() => write!(f, "null"),
+            Json::Number(value) => write!(f, "{}", value),
+            Json::String(value) => write_string(f, value),
+            Json::Array(elements) => {
+                write!(f, "[")?;
+                for (i, element) in elements.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write!(f, "{}", element)?;
+                }
+                write!(f, "]")
+            }
+            Json::Object(members) => {
+                write!(f, "{{")?;
+                for (i, (key, value)) in members.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ",")?;
+                    }
+                    write_string(f, key)?;
+                    write!(f, ":{}", value)?;
+                }
+                write!(f, "}}")
+            }
+        }
+    }
+}
+
+fn write_string(f: &mut fmt::Formatter<'_>, value: &str) -> fmt::Result {
+    write!(f, "\"")?;
+    for c in value.chars() {
+        match c {
+            '"' => write!(f, "\\\"")?,
+            '\\' => write!(f, "\\\\")?,
+            '\n' => write!(f, "\\n")?,
+            '\r' => write!(f, "\\r")?,
+            '\t' => write!(f, "\\t")?,
+            c if (c as u32) < 0x20 => write!(f, "\\u{:04x}", c as u32)?,
+            c => write!(f, "{}", c)?,
+        }
+    }
+    write!(f, "\"")
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+/// Writes a container's header with a placeholder body length, returning where its body starts.
+fn begin_container(buf: &mut Vec<u8>, tag: u8, cnt: usize) -> usize {
+    buf.push(tag);
+    buf.extend_from_slice(&[0; 4]);
+    buf.extend_from_slice(&(cnt as u32).to_be_bytes());
+    buf.len() - 4
+}
+
+fn end_container(buf: &mut [u8], start: usize) {
+    let len = (buf.len() - start) as u32;
+    buf[start - 4..start].copy_from_slice(&len.to_be_bytes());
+}
+
+struct Parser<'a> {
+    text: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, detail: &str) -> Error {
+        Error::Json(format!("{} at offset {}", detail, self.pos))
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ' | b'\t' | b'\n' | b'\r') = self.text.get(self.pos) {
+            self.pos += 1;
+        }
+    }
+
+    fn peek(&mut self) -> Option<u8> {
+        self.skip_whitespace();
+        self.text.get(self.pos).copied()
+    }
+
+    fn expect(&mut self, expected: u8) -> Result<()> {
+        match self.peek() == Some(expected) {
+            true => {
+                self.pos += 1;
+                Ok(())
+            }
+            false => Err(self.error(&format!("expected '{}'", expected as char))),
+        }
+    }
+
+    fn value(&mut self, depth: usize) -> Result<Json> {
+        if depth > MAX_DEPTH {
+            return Err(self.error("too deeply nested"));
+        }
+        match self.peek() {
+            Some(b'n') => self.literal("null", Json::Null),
+            Some(b't') => self.literal("true", Json::Bool(true)),
+            Some(b'f') => self.literal("false", Json::Bool(false)),
+            Some(b'"') => Ok(Json::String(self.string()?)),
+            Some(b'[') => {
+                self.pos += 1;
+                let mut elements = Vec::new();
+                if self.peek() == Some(b']') {
+                    self.pos += 1;
+                    return Ok(Json::Array(elements));
+                }
+                loop {
+                    elements.push(self.value(depth + 1)?);
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b']')?;
+                Ok(Json::Array(elements))
+            }
+            Some(b'{') => {
+                self.pos += 1;
+                let mut members = Vec::new();
+                if self.peek() == Some(b'}') {
+                    self.pos += 1;
+                    return Ok(Json::Object(members));
+                }
+                loop {
+                    if self.peek() != Some(b'"') {
+                        return Err(self.error("expected a member name"));
+                    }
+                    let key = self.string()?;
+                    self.expect(b':')?;
+                    members.push((key, self.value(depth + 1)?));
+                    match self.peek() {
+                        Some(b',') => self.pos += 1,
+                        _ => break,
+                    }
+                }
+                self.expect(b'}')?;
+                Ok(Json::Object(members))
+            }
+            Some(b'-' | b'0'..=b'9') => self.number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of text")),
+        }
+    }
+
+    fn literal(&mut self, literal: &str, json: Json) -> Result<Json> {
+        match self.text[self.pos..].starts_with(literal.as_bytes()) {
+            true => {
+                self.pos += literal.len();
+                Ok(json)
+            }
+            false => Err(self.error("invalid literal")),
+        }
+    }
+
+    fn number(&mut self) -> Result<Json> {
+        let start = self.pos;
+        let digits = |parser: &mut Self| {
+            let start = parser.pos;
+            while let Some(b'0'..=b'9') = parser.text.get(parser.pos) {
+                parser.pos += 1;
+            }
+            parser.pos - start
+        };
+
+        if self.text[self.pos] == b'-' {
+            self.pos += 1;
+        }
+        let int_digits = digits(self);
+        if int_digits == 0 || (int_digits > 1 && self.text[self.pos - int_digits] == b'0') {
+            return Err(self.error("invalid number"));
+        }
+        if self.text.get(self.pos) == Some(&b'.') {
+            self.pos += 1;
+            if digits(self) == 0 {
+                return Err(self.error("invalid number"));
+            }
+        }
+        if let Some(b'e' | b'E') = self.text.get(self.pos) {
+            self.pos += 1;
+            if let Some(b'+' | b'-') = self.text.get(self.pos) {
+                self.pos += 1;
+            }
+            if digits(self) == 0 {
+                return Err(self.error("invalid number"));
+            }
+        }
+
+        // Only ASCII was consumed, so this can't fail
+        let text = std::str::from_utf8(&self.text[start..self.pos]).unwrap();
+        text.parse()
+            .map(Json::Number)
+            .map_err(|_| self.error("invalid number"))
+    }
+
+    fn string(&mut self) -> Result<String> {
+        // The opening quote was already peeked at
+        self.pos += 1;
+        let mut bytes = Vec::new();
+        loop {
+            let byte = match self.text.get(self.pos) {
+                Some(byte) => *byte,
+                None => return Err(self.error("unterminated string")),
+            };
+            self.pos += 1;
+            match byte {
+                b'"' => break,
+                b'\\' => {
+                    let escaped = self.text.get(self.pos).copied();
+                    self.pos += 1;
+                    let c = match escaped {
+                        Some(b'"') => '"',
+                        Some(b'\\') => '\\',
+                        Some(b'/') => '/',
+                        Some(b'b') => '\u{8}',
+                        Some(b'f') => '\u{c}',
+                        Some(b'n') => '\n',
+                        Some(b'r') => '\r',
+                        Some(b't') => '\t',
+                        Some(b'u') => self.unicode_escape()?,
+                        _ => return Err(self.error("invalid escape")),
+                    };
+                    let mut utf8 = [0; 4];
+                    bytes.extend_from_slice(c.encode_utf8(&mut utf8).as_bytes());
+                }
+                byte if byte < 0x20 => return Err(self.error("control character in string")),
+                byte => bytes.push(byte),
+            }
+        }
+        // The text came from a `&str` and escapes are pushed as whole characters
+        Ok(String::from_utf8(bytes).unwrap())
+    }
+
+    /// Reads the hex digits of a `\u` escape, and those of the low surrogate that must follow a
+    /// high one.
+    fn unicode_escape(&mut self) -> Result<char> {
+        let high = self.hex4()?;
+        let code = match high {
+            0xD800..=0xDBFF => {
+                if !self.text[self.pos..].starts_with(b"\\u") {
+                    return Err(self.error("unpaired surrogate"));
+                }
+                self.pos += 2;
+                let low = self.hex4()?;
+                if !(0xDC00..=0xDFFF).contains(&low) {
+                    return Err(self.error("unpaired surrogate"));
+                }
+                0x10000 + ((high - 0xD800) << 10) + (low - 0xDC00)
+            }
+            code => code,
+        };
+        char::from_u32(code).ok_or_else(|| self.error("unpaired surrogate"))
+    }
+
+    fn hex4(&mut self) -> Result<u32> {
+        let digits = self
+            .text
+            .get(self.pos..self.pos + 4)
+            .and_then(|digits| std::str::from_utf8(digits).ok())
+            .and_then(|digits| u32::from_str_radix(digits, 16).ok())
+            .ok_or_else(|| self.error("invalid \\u escape"))?;
+        self.pos += 4;
+        Ok(digits)
+    }
+}
+
+/// One step of a `JsonPath`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PathSegment {
+    /// `.name` or `["name"]`, the value of an object's member.
+    Member(String),
+    /// `[n]`, the `n`th element of an array, counting from 0.
+    Element(u32),
+}
+
+/// A path into a JSON document, such as `$.orders[0]["unit price"]`: a `$` followed by any
+/// number of `.name`, `["name"]` and `[n]` steps. Names after a `.` are made up of letters,
+/// digits and underscores.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct JsonPath {
+    segments: Vec<PathSegment>,
+}
+
+impl JsonPath {
+    /// Parses a path, failing with `Error::Json` if it isn't valid.
+    pub fn parse(path: &str) -> Result<Self> {
+        let error = || Error::Json(format!("invalid path {:?}", path));
+        let mut rest = path.strip_prefix('$').ok_or_else(error)?;
+        let mut segments = Vec::new();
+        while !rest.is_empty() {
+            if let Some(after_dot) = rest.strip_prefix('.') {
+                let len = after_dot
+                    .find(|c: char| !c.is_alphanumeric() && c != '_')
+                    .unwrap_or(after_dot.len());
+                if len == 0 {
+                    return Err(error());
+                }
+                segments.push(PathSegment::Member(after_dot[..len].to_string()));
+                rest = &after_dot[len..];
+            } else if let Some(after_bracket) = rest.strip_prefix('[') {
+                let end = after_bracket.find(']').ok_or_else(error)?;
+                let inner = &after_bracket[..end];
+                let segment = match inner.strip_prefix('"') {
+                    Some(quoted) => match Json::parse(inner) {
+                        Ok(Json::String(name)) if quoted.ends_with('"') => {
+                            PathSegment::Member(name)
+                        }
+                        _ => return Err(error()),
+                    },
+                    None => PathSegment::Element(inner.parse().map_err(|_| error())?),
+                };
+                segments.push(segment);
+                rest = &after_bracket[end + 1..];
+            } else {
+                return Err(error());
+            }
+        }
+        Ok(JsonPath { segments })
+    }
+
+    pub fn segments(&self) -> &[PathSegment] {
+        &self.segments
+    }
+
+    /// Finds the value at the path within an encoded document, skipping over everything else.
+    /// Returns `None` if the document doesn't have it.
+    fn find<'a>(&self, doc: &'a [u8]) -> Result<Option<&'a [u8]>> {
+        let mut value = doc;
+        for segment in self.segments.iter() {
+            let (tag, mut body, cnt) = match value.first() {
+                Some(&tag @ (TAG_ARRAY | TAG_OBJECT)) => {
+                    let mut header = &value[1..];
+                    let len = take_u32(&mut header)? as usize;
+                    let cnt = take_u32(&mut header)?;
+                    let len = len.checked_sub(4).ok_or_else(malformed)?;
+                    (tag, take(&mut header, len)?, cnt)
+                }
+                Some(_) => return Ok(None),
+                None => return Err(malformed()),
+            };
+
+            let found = match (tag, segment) {
+                (TAG_ARRAY, PathSegment::Element(idx)) if *idx < cnt => {
+                    for _ in 0..*idx {
+                        skip_value(&mut body)?;
+                    }
+                    Some(body)
+                }
+                (TAG_OBJECT, PathSegment::Member(name)) => {
+                    let mut found = None;
+                    for _ in 0..cnt {
+                        let key = take_bytes(&mut body)?;
+                        if key == name.as_bytes() {
+                            found = Some(body);
+                            break;
+                        }
+                        skip_value(&mut body)?;
+                    }
+                    found
+                }
+                _ => None,
+            };
+            match found {
+                Some(found) => value = found,
+                None => return Ok(None),
+            }
+        }
+
+        // Trim the value to its own bytes
+        let mut rest = value;
+        skip_value(&mut rest)?;
+        Ok(Some(&value[..value.len() - rest.len()]))
+    }
+}
+
+impl fmt::Display for JsonPath {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "$")?;
+        for segment in self.segments.iter() {
+            match segment {
+                PathSegment::Member(name)
+                    if !name.is_empty()
+                        && name.chars().all(|c| c.is_alphanumeric() || c == '_') =>
+                {
+                    write!(f, ".{}", name)?
+                }
+                PathSegment::Member(name) => write!(f, "[{}]", Json::String(name.clone()))?,
+                PathSegment::Element(idx) => write!(f, "[{}]", idx)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+fn malformed() -> Error {
+    Error::corruption("malformed json value".to_string())
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(malformed());
+    }
+    let (head, tail) = buf.split_at(len);
+    *buf = tail;
+    Ok(head)
+}
+
+fn take_u32(buf: &mut &[u8]) -> Result<u32> {
+    Ok(u32::from_be_bytes(take(buf, 4)?.try_into().unwrap()))
+}
+
+fn take_bytes<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let len = take_u32(buf)? as usize;
+    take(buf, len)
+}
+
+/// Advances `buf` past the encoded value at its front, without looking inside arrays and
+/// objects.
+fn skip_value(buf: &mut &[u8]) -> Result<()> {
+    match take(buf, 1)?[0] {
+        TAG_NULL | TAG_FALSE | TAG_TRUE => {}
+        TAG_NUMBER => {
+            take(buf, 8)?;
+        }
+        TAG_STRING | TAG_ARRAY | TAG_OBJECT => {
+            take_bytes(buf)?;
+        }
+        tag => return Err(Error::corruption(format!("unknown json tag {}", tag))),
+    }
+    Ok(())
+}
+
+fn decode_value(buf: &mut &[u8]) -> Result<Json> {
+    let json = match take(buf, 1)?[0] {
+        TAG_NULL => Json::Null,
+        TAG_FALSE => Json::Bool(false),
+        TAG_TRUE => Json::Bool(true),
+        TAG_NUMBER => Json::Number(f64::from_bits(u64::from_be_bytes(
+            take(buf, 8)?.try_into().unwrap(),
+        ))),
+        TAG_STRING => Json::String(decode_string(take_bytes(buf)?)?),
+        tag @ (TAG_ARRAY | TAG_OBJECT) => {
+            let mut body = take_bytes(buf)?;
+            let cnt = take_u32(&mut body)?;
+            let json = match tag {
+                TAG_ARRAY => Json::Array(
+                    (0..cnt)
+                        .map(|_| decode_value(&mut body))
+                        .collect::<Result<_>>()?,
+                ),
+                _ => Json::Object(
+                    (0..cnt)
+                        .map(|_| {
+                            let key = decode_string(take_bytes(&mut body)?)?;
+                            Ok((key, decode_value(&mut body)?))
+                        })
+                        .collect::<Result<_>>()?,
+                ),
+            };
+            if !body.is_empty() {
+                return Err(malformed());
+            }
+            json
+        }
+        tag => return Err(Error::corruption(format!("unknown json tag {}", tag))),
+    };
+    Ok(json)
+}
+
+fn decode_string(bytes: &[u8]) -> Result<String> {
+    String::from_utf8(bytes.to_vec()).map_err(|_| malformed())
+}
+
+fn decode(mut bytes: &[u8]) -> Result<Json> {
+    let json = decode_value(&mut bytes)?;
+    match bytes.is_empty() {
+        true => Ok(json),
+        false => Err(malformed()),
+    }
+}
+
+/// A JSON document as a value, stored in the binary encoding described in `crate::json`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ValueJson {
+    bytes: Vec<u8>,
+}
+
+impl ValueJson {
+    /// Parses JSON text into a document, failing with `Error::Json` if it isn't valid.
+    pub fn parse(text: &str) -> Result<Self> {
+        Ok(Self::from(&Json::parse(text)?))
+    }
+
+    /// The encoded document.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes
+    }
+
+    /// Decodes the whole document.
+    pub fn to_json(&self) -> Result<Json> {
+        decode(&self.bytes)
+    }
+
+    /// Decodes just the value at `path`, or returns `None` if the document doesn't have it.
+    pub fn get(&self, path: &JsonPath) -> Result<Option<Json>> {
+        get_encoded(&self.bytes, path)
+    }
+
+    /// Like `get`, parsing `path` first.
+    pub fn get_path(&self, path: &str) -> Result<Option<Json>> {
+        self.get(&JsonPath::parse(path)?)
+    }
+}
+
+impl From<&Json> for ValueJson {
+    fn from(json: &Json) -> Self {
+        let mut bytes = Vec::new();
+        json.encode_to(&mut bytes);
+        Self { bytes }
+    }
+}
+
+fn get_encoded(doc: &[u8], path: &JsonPath) -> Result<Option<Json>> {
+    path.find(doc)?.map(decode).transpose()
+}
+
+impl Value for ValueJson {
+    fn type_name() -> Cow<'static, str> {
+        Cow::Borrowed("johndb::json::ValueJson")
+    }
+}
+
+impl Item for ValueJson {
+    fn size(&self) -> usize {
+        self.bytes.len()
+    }
+
+    fn align() -> usize {
+        1
+    }
+
+    fn is_fixed_size() -> bool {
+        false
+    }
+
+    fn write(&self, buffer: &mut [u8]) {
+        buffer.copy_from_slice(&self.bytes);
+    }
+
+    fn read(bytes: &[u8]) -> Self {
+        Self {
+            bytes: bytes.to_vec(),
+        }
+    }
+}
+
+impl<K, PageFetcher> BTree<K, ValueJson, PageFetcher>
+where
+    K: Key,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Returns the value at `path` in the document stored under `key`, e.g. `$.a.b`, or `None`
+    /// if there's no such document or it doesn't have the path. The path is evaluated on the
+    /// document's bytes in the leaf, so only the value it finds is decoded.
+    pub fn get_path(&self, key: K, path: &str) -> Result<Option<Json>> {
+        let path = JsonPath::parse(path)?;
+        let mut result = Ok(None);
+        self.range_visit(key.clone()..=key, |entry| {
+            result = get_encoded(entry.value_bytes(), &path);
+            false
+        })?;
+        result
+    }
+}
+
+/// Bytes that are already in the order-preserving encoding.
+pub(crate) struct Encoded<'a>(pub(crate) &'a [u8]);
+
+impl Encode for Encoded<'_> {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        buf.extend_from_slice(self.0);
+    }
+}
+
+/// A secondary index over the documents of a `BTree<K, ValueJson>`, mapping the value at a path
+/// in each document to the document's key. Only documents where the path leads to a null,
+/// boolean, number or string are indexed.
+///
+/// The index is a tree of its own, which may share the documents' page fetcher, see
+/// `BTree::create`. It isn't updated by the documents' tree: `insert` and `delete` must be called
+/// alongside every change to it, with the documents involved.
+pub struct JsonIndex<K, PageFetcher>
+where
+    K: Key,
+    PageFetcher: PageFetcherTrait,
+{
+    path: JsonPath,
+    // Keyed by the indexed value followed by the document's key, so that keys are unique, with
+    // the document's key as the value to avoid decoding it back out of the index key
+    tree: BTree<KeyEncoded, ValueBytes, PageFetcher>,
+    phantom: PhantomData<K>,
+}
+
+impl<K, PageFetcher> JsonIndex<K, PageFetcher>
+where
+    K: Key,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Creates an empty index on `path`, on a newly allocated page of `page_fetcher`.
+    pub fn create(page_fetcher: PageFetcher, path: &str) -> Result<Self> {
+        Ok(JsonIndex {
+            path: JsonPath::parse(path)?,
+            tree: BTree::create(page_fetcher)?,
+            phantom: PhantomData,
+        })
+    }
+
+    /// Opens the index whose tree's metadata is on `metadata_no`, which must have been created
+    /// on the same `path`.
+    pub fn open(page_fetcher: PageFetcher, metadata_no: u32, path: &str) -> Result<Self> {
+        Ok(JsonIndex {
+            path: JsonPath::parse(path)?,
+            tree: BTree::open(page_fetcher, metadata_no)?,
+            phantom: PhantomData,
+        })
+    }
+
+    pub fn path(&self) -> &JsonPath {
+        &self.path
+    }
+
+    /// The page holding the index tree's metadata, see `open`.
+    pub fn metadata_no(&self) -> u32 {
+        self.tree.metadata_no()
+    }
+
+    fn index_key(&self, key: &K, doc: &ValueJson) -> Result<Option<KeyEncoded>> {
+        let value = match doc
+            .get(&self.path)?
+            .and_then(|value| value.index_encoding())
+        {
+            Some(value) => value,
+            None => return Ok(None),
+        };
+        Ok(Some(KeyEncoded::new(&(
+            Encoded(&value),
+            item_to_bytes(key),
+        ))))
+    }
+
+    /// Indexes every document in `docs`. Returns the number of documents indexed.
+    pub fn build<P: PageFetcherTrait>(&mut self, docs: &BTree<K, ValueJson, P>) -> Result<u64> {
+        let mut cnt = 0;
+        for entry in docs.range(..)? {
+            let (key, doc) = entry?;
+            if self.insert(&key, &doc)? {
+                cnt += 1;
+            }
+        }
+        Ok(cnt)
+    }
+
+    /// Indexes `doc`, stored under `key`. Returns whether the path led to an indexable value.
+    pub fn insert(&mut self, key: &K, doc: &ValueJson) -> Result<bool> {
+        match self.index_key(key, doc)? {
+            Some(index_key) => {
+                let value = ValueBytes {
+                    value: item_to_bytes(key),
+                };
+                self.tree.insert(index_key, value)?;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Removes `doc`, which was stored under `key`, from the index.
+    pub fn delete(&mut self, key: &K, doc: &ValueJson) -> Result<()> {
+        if let Some(index_key) = self.index_key(key, doc)? {
+            self.tree.delete(index_key)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the keys of the documents whose value at the path equals `value`, in ascending
+    /// order. Arrays and objects never match.
+    pub fn lookup(&self, value: &Json) -> Result<Vec<K>> {
+        let prefix = match value.index_encoding() {
+            Some(prefix) => prefix,
+            None => return Ok(Vec::new()),
+        };
+        // Every index key for `value` starts with its encoding, and the encoding starts with a
+        // type byte that's never 0xFF, so bumping the last byte below 0xFF gives an upper bound
+        let mut end = prefix.clone();
+        while end.last() == Some(&0xFF) {
+            end.pop();
+        }
+        *end.last_mut().unwrap() += 1;
+
+        let start = KeyEncoded::new(&Encoded(&prefix));
+        let end = KeyEncoded::new(&Encoded(&end));
+        // Keys are ordered by their item encodings within the index, which needn't match `K`'s
+        // order
+        let mut keys = self
+            .tree
+            .range(start..end)?
+            .map(|entry| item_from_bytes(&entry?.1.value))
+            .collect::<Result<Vec<K>>>()?;
+        keys.sort();
+        Ok(keys)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Json;
+    use super::JsonIndex;
+    use super::JsonPath;
+    use super::ValueJson;
+    use crate::btree::key::KeyU32;
+    use crate::btree::BTree;
+    use crate::error::Error;
+    use crate::page_fetcher::InMemoryPageFetcher;
+
+    #[test]
+    fn parse_and_display() {
+        let text = r#"{"a":{"b":[1,2.5,-3e2,true,null]},"s":"q\"\\\n\u00e9\ud83d\ude00"}"#;
+        let json = Json::parse(text).unwrap();
+        assert_eq!(
+            json.to_string(),
+            r#"{"a":{"b":[1,2.5,-300,true,null]},"s":"q\"\\\né😀"}"#
+        );
+        let value = ValueJson::from(&json);
+        assert_eq!(value.to_json().unwrap(), json);
+        assert_eq!(Json::parse(" [ ] ").unwrap(), Json::Array(Vec::new()));
+
+        for invalid in [
+            "",
+            "{",
+            "[1,]",
+            "01",
+            "1.",
+            "\"\\x\"",
+            "tru",
+            "{} x",
+            "\"\\ud800\"",
+        ] {
+            assert!(
+                matches!(Json::parse(invalid), Err(Error::Json(_))),
+                "{}",
+                invalid
+            );
+        }
+        let deep = "[".repeat(1000) + &"]".repeat(1000);
+        assert!(matches!(Json::parse(&deep), Err(Error::Json(_))));
+    }
+
+    #[test]
+    fn paths() {
+        let doc = ValueJson::parse(
+            r#"{"user": {"name": "ann", "tags": ["x", "y"]}, "unit price": 3, "n": null}"#,
+        )
+        .unwrap();
+        let get = |path| doc.get_path(path).unwrap();
+        assert_eq!(get("$.user.name"), Some(Json::String("ann".to_string())));
+        assert_eq!(get("$.user.tags[1]"), Some(Json::String("y".to_string())));
+        assert_eq!(get("$[\"unit price\"]"), Some(Json::Number(3.0)));
+        assert_eq!(get("$.n"), Some(Json::Null));
+        assert_eq!(get("$"), Some(doc.to_json().unwrap()));
+        assert_eq!(get("$.user.tags[2]"), None);
+        assert_eq!(get("$.user.name.first"), None);
+        assert_eq!(get("$.missing"), None);
+        assert_eq!(get("$.user[0]"), None);
+
+        for invalid in ["", "a.b", "$.", "$[", "$[x]", "$[\"a]", "$..a"] {
+            assert!(
+                matches!(JsonPath::parse(invalid), Err(Error::Json(_))),
+                "{}",
+                invalid
+            );
+        }
+        let path = JsonPath::parse("$.a[\"b c\"][3]").unwrap();
+        assert_eq!(JsonPath::parse(&path.to_string()).unwrap(), path);
+    }
+
+    #[test]
+    fn get_path() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(64)).unwrap();
+        for key in 0..1000u32 {
+            let doc = format!(r#"{{"id":{},"owner":{{"name":"user-{}"}}}}"#, key, key % 10);
+            btree
+                .insert(KeyU32 { key }, ValueJson::parse(&doc).unwrap())
+                .unwrap();
+        }
+        assert_eq!(
+            btree.get_path(KeyU32 { key: 123 }, "$.owner.name").unwrap(),
+            Some(Json::String("user-3".to_string()))
+        );
+        assert_eq!(btree.get_path(KeyU32 { key: 123 }, "$.x").unwrap(), None);
+        assert_eq!(btree.get_path(KeyU32 { key: 5000 }, "$.id").unwrap(), None);
+        assert!(btree.get_path(KeyU32 { key: 1 }, "id").is_err());
+    }
+
+    #[test]
+    fn path_index() {
+        let page_fetcher = InMemoryPageFetcher::with_capacity(128);
+        let mut docs = BTree::new(&page_fetcher).unwrap();
+        let doc = |key: u32| {
+            let status = match key % 4 {
+                0 => "null".to_string(),
+                1 => "[1]".to_string(),
+                _ => format!("\"status-{}\"", key % 4),
+            };
+            ValueJson::parse(&format!(r#"{{"status":{},"size":{}}}"#, status, key % 3)).unwrap()
+        };
+        for key in 0..300u32 {
+            docs.insert(KeyU32 { key }, doc(key)).unwrap();
+        }
+
+        let mut by_status = JsonIndex::create(&page_fetcher, "$.status").unwrap();
+        assert_eq!(by_status.build(&docs).unwrap(), 225);
+        let matching = by_status
+            .lookup(&Json::String("status-2".to_string()))
+            .unwrap();
+        assert_eq!(
+            matching,
+            (0..300)
+                .filter(|key| key % 4 == 2)
+                .map(|key| KeyU32 { key })
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(by_status.lookup(&Json::Null).unwrap().len(), 75);
+        assert!(by_status
+            .lookup(&Json::Array(vec![Json::Number(1.0)]))
+            .unwrap()
+            .is_empty());
+
+        let mut by_size = JsonIndex::create(&page_fetcher, "$.size").unwrap();
+        by_size.build(&docs).unwrap();
+        let old = docs.delete(KeyU32 { key: 3 }).unwrap().unwrap();
+        by_size.delete(&KeyU32 { key: 3 }, &old).unwrap();
+        let new = ValueJson::parse(r#"{"size":7}"#).unwrap();
+        docs.insert(KeyU32 { key: 4 }, new.clone()).unwrap();
+        by_size.delete(&KeyU32 { key: 4 }, &doc(4)).unwrap();
+        by_size.insert(&KeyU32 { key: 4 }, &new).unwrap();
+        assert_eq!(by_size.lookup(&Json::Number(0.0)).unwrap().len(), 99);
+        assert_eq!(
+            by_size.lookup(&Json::Number(7.0)).unwrap(),
+            vec![KeyU32 { key: 4 }]
+        );
+
+        // The index is found again through its metadata page
+        let metadata_no = by_size.metadata_no();
+        drop(by_size);
+        let by_size = JsonIndex::<KeyU32, _>::open(&page_fetcher, metadata_no, "$.size").unwrap();
+        assert_eq!(by_size.lookup(&Json::Number(2.0)).unwrap().len(), 100);
+    }
+}
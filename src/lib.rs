@@ -1,11 +1,59 @@
 // TODO: Figure out how to get rid of these dead code errors. Drives me crazy.
 
 pub mod btree;
+pub mod catalog;
+pub mod checksum;
+pub mod compress;
+#[cfg(test)]
+mod crash_test;
+pub mod database;
+pub mod encoding;
+pub mod error;
+pub mod events;
+pub mod export;
+pub mod expr;
+#[cfg(any(test, feature = "test-util"))]
+pub mod faulty_page_fetcher;
+pub mod file_page_fetcher;
+pub mod json;
 pub mod mem;
+pub mod metrics;
+pub mod net;
+pub mod numeric;
 pub mod page;
 pub mod page_fetcher;
+#[cfg(feature = "parquet")]
+pub mod parquet_export;
+pub mod planner;
+#[cfg(feature = "metrics-prometheus")]
+pub mod prometheus;
+pub mod row;
+pub mod server;
+mod shadow;
+pub mod sim_page_fetcher;
+pub mod snapshot;
+#[cfg(feature = "sql")]
+pub mod sql;
+pub mod sst;
+pub mod table;
+#[cfg(all(feature = "io-uring", target_os = "linux"))]
+mod uring;
+pub mod wal;
 extern crate log;
 
+pub use catalog::NamedTree;
+pub use catalog::TreeOptions;
+pub use catalog::TreeSchema;
+pub use database::Database;
+pub use database::Options;
+pub use database::Stats;
+pub use error::Error;
+pub use error::Result;
+pub use events::EventListener;
+pub use metrics::MemoryUsage;
+pub use metrics::MetricsSnapshot;
+pub use metrics::OpStatsSnapshot;
+
 #[cfg(test)]
 #[ctor::ctor]
 fn init_log() {
@@ -0,0 +1,232 @@
+//! Counters tracking what the storage engine is doing. They're plain relaxed atomics, cheap enough
+//! to bump on every page access, and are read through a point-in-time `MetricsSnapshot`.
+
+use std::sync::atomic::AtomicU64;
+use std::sync::atomic::Ordering;
+
+#[derive(Debug, Default)]
+pub struct Counter(AtomicU64);
+
+impl Counter {
+    pub fn inc(&self) {
+        self.add(1);
+    }
+
+    pub fn add(&self, n: u64) {
+        self.0.fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub fn get(&self) -> u64 {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    /// Halves the count, so that old activity weighs less than recent activity.
+    pub fn decay(&self) {
+        let _ = self
+            .0
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |n| Some(n / 2));
+    }
+}
+
+/// The counters shared by a page fetcher and the tree on top of it, see `PageFetcher::metrics`.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    /// Pages read from disk.
+    pub page_reads: Counter,
+    /// Pages written to disk.
+    pub page_writes: Counter,
+    /// Page fetches served from memory.
+    pub pool_hits: Counter,
+    /// Page fetches that had to go to disk. Every page is loaded when the file is opened, so this
+    /// stays 0 until pages can be evicted.
+    pub pool_misses: Counter,
+    pub leaf_splits: Counter,
+    pub internal_splits: Counter,
+    /// Steps taken to a right sibling because a concurrent split moved the key range being
+    /// looked for.
+    pub move_rights: Counter,
+    /// Page lock acquisitions that had to wait for another holder.
+    pub lock_waits: Counter,
+}
+
+impl Metrics {
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        MetricsSnapshot {
+            page_reads: self.page_reads.get(),
+            page_writes: self.page_writes.get(),
+            pool_hits: self.pool_hits.get(),
+            pool_misses: self.pool_misses.get(),
+            leaf_splits: self.leaf_splits.get(),
+            internal_splits: self.internal_splits.get(),
+            move_rights: self.move_rights.get(),
+            lock_waits: self.lock_waits.get(),
+        }
+    }
+}
+
+/// The values of every counter in `Metrics` at one point in time. Counters are read one by one,
+/// so a snapshot taken while other threads are busy may be slightly inconsistent.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MetricsSnapshot {
+    pub page_reads: u64,
+    pub page_writes: u64,
+    pub pool_hits: u64,
+    pub pool_misses: u64,
+    pub leaf_splits: u64,
+    pub internal_splits: u64,
+    pub move_rights: u64,
+    pub lock_waits: u64,
+}
+
+/// Operations run against a single tree since it was opened, see `BTree::op_stats`. Unlike
+/// `Metrics`, these aren't shared with other trees on the same page fetcher.
+#[derive(Debug, Default)]
+pub struct OpStats {
+    /// Calls to `insert`, `insert_if_absent` and `get_or_insert_with`, including ones that found
+    /// the key already present.
+    pub inserts: Counter,
+    pub deletes: Counter,
+    pub searches: Counter,
+    /// Calls to `range` and `range_visit`.
+    pub scans: Counter,
+    /// Leaf and internal page splits.
+    pub splits: Counter,
+}
+
+impl OpStats {
+    pub fn snapshot(&self) -> OpStatsSnapshot {
+        OpStatsSnapshot {
+            inserts: self.inserts.get(),
+            deletes: self.deletes.get(),
+            searches: self.searches.get(),
+            scans: self.scans.get(),
+            splits: self.splits.get(),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct OpStatsSnapshot {
+    pub inserts: u64,
+    pub deletes: u64,
+    pub searches: u64,
+    pub scans: u64,
+    pub splits: u64,
+}
+
+/// How often a single page has been fetched, see `InMemoryPageFetcher::hot_pages`.
+#[derive(Debug, Default)]
+pub struct PageAccess {
+    pub reads: Counter,
+    /// Write fetches, including the one creating the page.
+    pub writes: Counter,
+}
+
+impl PageAccess {
+    pub fn decay(&self) {
+        self.reads.decay();
+        self.writes.decay();
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct HotPage {
+    pub page_no: u32,
+    pub reads: u64,
+    pub writes: u64,
+}
+
+/// Bytes of memory held on behalf of a page fetcher, see `InMemoryPageFetcher::memory_usage`.
+/// There's no log or transactions yet, so page frames are the only buffers to account for.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MemoryUsage {
+    /// Pages allocated so far. Frames are allocated lazily, one page at a time.
+    pub page_frames: usize,
+    /// Locks and access counters, allocated up front for every page the fetcher may hand out.
+    pub page_table: usize,
+}
+
+impl MemoryUsage {
+    pub fn total(&self) -> usize {
+        self.page_frames + self.page_table
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::btree::key::KeyBytes;
+    use crate::btree::value::ValueBytes;
+    use crate::btree::BTree;
+    use crate::page_fetcher::InMemoryPageFetcher;
+    use crate::page_fetcher::PageFetcher;
+
+    #[test]
+    fn counts_splits_and_fetches() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(1024)).unwrap();
+        for i in 0..5000u32 {
+            // Large keys keep the fanout low, so internal nodes split too
+            let mut key = vec![0; 200];
+            key[..4].copy_from_slice(&((i * 7919) % 5000).to_be_bytes());
+            btree
+                .insert(KeyBytes { key }, ValueBytes { value: vec![] })
+                .unwrap();
+        }
+
+        let metrics = btree.page_fetcher().metrics().snapshot();
+        let stats = btree.analyze().unwrap();
+        assert!(stats.height() >= 3);
+        // Each split adds a page to its level, which started out with a single page
+        let (leaves, internal) = stats.levels.split_last().unwrap();
+        assert_eq!(metrics.leaf_splits as usize, leaves.page_cnt - 1);
+        assert_eq!(
+            metrics.internal_splits as usize,
+            internal
+                .iter()
+                .map(|level| level.page_cnt - 1)
+                .sum::<usize>()
+        );
+        assert!(metrics.pool_hits > 5000);
+        assert_eq!(metrics.pool_misses, 0);
+        assert_eq!(metrics.lock_waits, 0);
+        // Moving right only happens when racing a split, which a single thread never does
+        assert_eq!(metrics.move_rights, 0);
+    }
+
+    #[test]
+    fn counts_operations_per_tree() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(1024)).unwrap();
+        for i in 0..1000u32 {
+            let key = KeyBytes {
+                key: i.to_be_bytes().to_vec(),
+            };
+            btree
+                .insert(
+                    key,
+                    ValueBytes {
+                        value: vec![0; 100],
+                    },
+                )
+                .unwrap();
+        }
+        for i in 0..10u32 {
+            let key = KeyBytes {
+                key: i.to_be_bytes().to_vec(),
+            };
+            btree.search(key.clone()).unwrap();
+            btree.delete(key).unwrap();
+        }
+        assert_eq!(btree.range(..).unwrap().count(), 990);
+
+        let op_stats = btree.op_stats();
+        let metrics = btree.page_fetcher().metrics().snapshot();
+        assert_eq!(op_stats.inserts, 1000);
+        assert_eq!(op_stats.searches, 10);
+        assert_eq!(op_stats.deletes, 10);
+        assert_eq!(op_stats.scans, 1);
+        assert!(op_stats.splits > 0);
+        assert_eq!(
+            op_stats.splits,
+            metrics.leaf_splits + metrics.internal_splits
+        );
+    }
+}
@@ -0,0 +1,414 @@
+//! A RESP2 listener, so that Redis clients can use the database's byte keys for durable storage.
+//! `serve_resp` runs it with a thread per connection, like `server::serve`.
+//!
+//! The supported commands are `GET key`, `SET key value`, `DEL key [key ...]`,
+//! `MGET key [key ...]`, `SCAN cursor [MATCH pattern] [COUNT count]`, `PING [message]` and
+//! `QUIT`. Commands are read either as arrays of bulk strings, which is what client libraries
+//! send, or as inline lines as typed into a terminal. Pipelined commands are answered in order,
+//! with the replies written out once there are no more buffered commands to run.
+//!
+//! `SCAN` cursors are the hex encoding of the key to resume from, or `0` at the start and end of
+//! an iteration. Like with Redis, keys written during an iteration may or may not be returned.
+
+use crate::database::Database;
+use crate::error::Error;
+use crate::error::Result;
+use crate::server::MAX_FRAME_SIZE;
+use std::io::BufRead;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+
+const DEFAULT_SCAN_COUNT: usize = 10;
+
+/// A RESP2 reply.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Reply {
+    Simple(String),
+    Error(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    /// The null bulk string, e.g. for a missing key.
+    Nil,
+    Array(Vec<Reply>),
+}
+
+impl Reply {
+    pub fn write<W: Write>(&self, writer: &mut W) -> Result<()> {
+        match self {
+            Reply::Simple(text) => write!(writer, "+{}\r\n", text)?,
+            Reply::Error(text) => write!(writer, "-{}\r\n", text)?,
+            Reply::Integer(value) => write!(writer, ":{}\r\n", value)?,
+            Reply::Bulk(bytes) => {
+                write!(writer, "${}\r\n", bytes.len())?;
+                writer.write_all(bytes)?;
+                writer.write_all(b"\r\n")?;
+            }
+            Reply::Nil => writer.write_all(b"$-1\r\n")?,
+            Reply::Array(items) => {
+                write!(writer, "*{}\r\n", items.len())?;
+                for item in items.iter() {
+                    item.write(writer)?;
+                }
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Reads the next command's arguments, or `None` if the stream ended before it. Malformed input
+/// fails with `Error::Protocol`.
+pub fn read_command<R: BufRead>(reader: &mut R) -> Result<Option<Vec<Vec<u8>>>> {
+    let line = match read_line(reader)? {
+        Some(line) => line,
+        None => return Ok(None),
+    };
+    if line.first() != Some(&b'*') {
+        let args = line
+            .split(|byte| byte.is_ascii_whitespace())
+            .filter(|arg| !arg.is_empty())
+            .map(|arg| arg.to_vec())
+            .collect();
+        return Ok(Some(args));
+    }
+
+    let cnt = parse_len(&line[1..])?;
+    let mut args = Vec::with_capacity(cnt.min(1024));
+    for _ in 0..cnt {
+        let header = read_line(reader)?.ok_or_else(truncated)?;
+        if header.first() != Some(&b'$') {
+            return Err(Error::Protocol(format!(
+                "expected '$', got '{}'",
+                String::from_utf8_lossy(&header)
+            )));
+        }
+        let len = parse_len(&header[1..])?;
+        let mut arg = vec![0; len + 2];
+        reader.read_exact(&mut arg)?;
+        if !arg.ends_with(b"\r\n") {
+            return Err(Error::Protocol(
+                "bulk string isn't terminated by CRLF".to_string(),
+            ));
+        }
+        arg.truncate(len);
+        args.push(arg);
+    }
+    Ok(Some(args))
+}
+
+/// Reads a line without its CRLF, or `None` if the stream ended before it.
+fn read_line<R: BufRead>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut line = Vec::new();
+    if reader.read_until(b'\n', &mut line)? == 0 {
+        return Ok(None);
+    }
+    if line.pop() != Some(b'\n') {
+        return Err(truncated());
+    }
+    if line.last() == Some(&b'\r') {
+        line.pop();
+    }
+    Ok(Some(line))
+}
+
+fn parse_len(digits: &[u8]) -> Result<usize> {
+    std::str::from_utf8(digits)
+        .ok()
+        .and_then(|digits| digits.parse::<usize>().ok())
+        .filter(|len| *len <= MAX_FRAME_SIZE)
+        .ok_or_else(|| {
+            Error::Protocol(format!(
+                "invalid length '{}'",
+                String::from_utf8_lossy(digits)
+            ))
+        })
+}
+
+fn truncated() -> Error {
+    Error::Protocol("unexpected end of stream".to_string())
+}
+
+/// Runs a command against `db`. Errors, including from the database, are turned into error
+/// replies.
+pub fn handle(db: &Mutex<Database>, args: &[Vec<u8>]) -> Result<Reply> {
+    let name = match args.first() {
+        Some(name) => String::from_utf8_lossy(name).to_ascii_lowercase(),
+        None => return Ok(Reply::Error("ERR empty command".to_string())),
+    };
+    let mut db = db.lock()?;
+    let reply = match run(&mut db, &name, &args[1..]) {
+        Ok(reply) => reply,
+        Err(err) => Reply::Error(format!("ERR {}", err)),
+    };
+    Ok(reply)
+}
+
+fn run(db: &mut Database, name: &str, args: &[Vec<u8>]) -> Result<Reply> {
+    let wrong_arity = || {
+        Reply::Error(format!(
+            "ERR wrong number of arguments for '{}' command",
+            name
+        ))
+    };
+    let reply = match (name, args) {
+        ("ping", []) => Reply::Simple("PONG".to_string()),
+        ("ping", [message]) => Reply::Bulk(message.clone()),
+        ("get", [key]) => match db.get(key)? {
+            Some(value) => Reply::Bulk(value),
+            None => Reply::Nil,
+        },
+        ("set", [key, value]) => {
+            db.put(key, value)?;
+            Reply::Simple("OK".to_string())
+        }
+        ("del", keys) if !keys.is_empty() => {
+            let mut deleted = 0;
+            for key in keys.iter() {
+                if db.delete(key)?.is_some() {
+                    deleted += 1;
+                }
+            }
+            Reply::Integer(deleted)
+        }
+        ("mget", keys) if !keys.is_empty() => Reply::Array(
+            keys.iter()
+                .map(|key| Ok(db.get(key)?.map_or(Reply::Nil, Reply::Bulk)))
+                .collect::<Result<Vec<_>>>()?,
+        ),
+        ("scan", [cursor, options @ ..]) => match scan(db, cursor, options)? {
+            Some(reply) => reply,
+            None => Reply::Error("ERR syntax error".to_string()),
+        },
+        ("ping", _) | ("get", _) | ("set", _) | ("del", _) | ("mget", _) | ("scan", _) => {
+            wrong_arity()
+        }
+        _ => Reply::Error(format!("ERR unknown command '{}'", name)),
+    };
+    Ok(reply)
+}
+
+/// Runs `SCAN`, or returns `None` if its options are malformed.
+fn scan(db: &Database, cursor: &[u8], options: &[Vec<u8>]) -> Result<Option<Reply>> {
+    let start = match cursor {
+        b"0" => Vec::new(),
+        cursor => match from_hex(cursor) {
+            Some(start) => start,
+            None => return Ok(Some(Reply::Error("ERR invalid cursor".to_string()))),
+        },
+    };
+    let mut pattern = None;
+    let mut count = DEFAULT_SCAN_COUNT;
+    for option in options.chunks(2) {
+        match option {
+            [name, value] if name.eq_ignore_ascii_case(b"match") => pattern = Some(value),
+            [name, value] if name.eq_ignore_ascii_case(b"count") => {
+                count = match std::str::from_utf8(value).ok().and_then(|v| v.parse().ok()) {
+                    Some(count) if count > 0 => count,
+                    _ => return Ok(None),
+                }
+            }
+            _ => return Ok(None),
+        }
+    }
+
+    // Like Redis, COUNT bounds the keys looked at rather than the keys returned
+    let mut keys = Vec::new();
+    let mut next = None;
+    let range = db.range((Bound::Included(start.as_slice()), Bound::Unbounded))?;
+    for (idx, entry) in range.enumerate() {
+        let (key, _) = entry?;
+        if idx == count {
+            next = Some(key);
+            break;
+        }
+        let matches = match pattern {
+            Some(pattern) => glob_match(pattern, &key),
+            None => true,
+        };
+        if matches {
+            keys.push(Reply::Bulk(key));
+        }
+    }
+    let cursor = next.map_or_else(|| b"0".to_vec(), |key| to_hex(&key));
+    Ok(Some(Reply::Array(vec![
+        Reply::Bulk(cursor),
+        Reply::Array(keys),
+    ])))
+}
+
+/// Matches `text` against a glob `pattern` supporting `*`, `?` and `\` escapes.
+fn glob_match(pattern: &[u8], text: &[u8]) -> bool {
+    match pattern.split_first() {
+        None => text.is_empty(),
+        Some((b'*', rest)) => (0..=text.len()).any(|skip| glob_match(rest, &text[skip..])),
+        Some((b'?', rest)) => !text.is_empty() && glob_match(rest, &text[1..]),
+        Some((b'\\', [escaped, rest @ ..])) => {
+            text.first() == Some(escaped) && glob_match(rest, &text[1..])
+        }
+        Some((byte, rest)) => text.first() == Some(byte) && glob_match(rest, &text[1..]),
+    }
+}
+
+/// The cursor for resuming at `key`. It's prefixed so it can't be mistaken for the `0` cursor.
+fn to_hex(key: &[u8]) -> Vec<u8> {
+    let mut hex = b"k".to_vec();
+    for byte in key.iter() {
+        hex.extend_from_slice(format!("{:02x}", byte).as_bytes());
+    }
+    hex
+}
+
+fn from_hex(cursor: &[u8]) -> Option<Vec<u8>> {
+    let hex = cursor.strip_prefix(b"k")?;
+    if hex.len() % 2 != 0 {
+        return None;
+    }
+    hex.chunks(2)
+        .map(|pair| {
+            let pair = std::str::from_utf8(pair).ok()?;
+            u8::from_str_radix(pair, 16).ok()
+        })
+        .collect()
+}
+
+/// Accepts connections on `listener` until it fails, serving each on its own thread.
+pub fn serve_resp(listener: TcpListener, db: Arc<Mutex<Database>>) -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let db = db.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr().ok();
+            if let Err(err) = serve_resp_connection(stream, &db) {
+                log::warn!("RESP connection from {:?} failed: {}", peer, err);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Serves commands from `stream` until the client disconnects, sends `QUIT` or sends malformed
+/// input.
+pub fn serve_resp_connection(stream: TcpStream, db: &Mutex<Database>) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    loop {
+        let args = match read_command(&mut reader) {
+            Ok(Some(args)) => args,
+            Ok(None) => break,
+            Err(Error::Protocol(detail)) => {
+                Reply::Error(format!("ERR Protocol error: {}", detail)).write(&mut writer)?;
+                break;
+            }
+            Err(err) => return Err(err),
+        };
+        if args.is_empty() {
+            continue;
+        }
+        if args[0].eq_ignore_ascii_case(b"quit") {
+            Reply::Simple("OK".to_string()).write(&mut writer)?;
+            break;
+        }
+        handle(db, &args)?.write(&mut writer)?;
+        // Pipelined commands still in the buffer are answered in the same write
+        if reader.buffer().is_empty() {
+            writer.flush()?;
+        }
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::glob_match;
+    use super::read_command;
+    use super::serve_resp;
+    use super::Reply;
+    use crate::database::Database;
+    use crate::database::Options;
+    use std::io::Read;
+    use std::io::Write;
+    use std::net::Shutdown;
+    use std::net::TcpListener;
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::thread;
+
+    #[test]
+    fn parses_commands() {
+        let mut input = &b"*2\r\n$3\r\nGET\r\n$4\r\nk\r\nx\r\nPING  hi\r\n*1\r\n$3\r\nab"[..];
+        assert_eq!(
+            read_command(&mut input).unwrap(),
+            Some(vec![b"GET".to_vec(), b"k\r\nx".to_vec()])
+        );
+        assert_eq!(
+            read_command(&mut input).unwrap(),
+            Some(vec![b"PING".to_vec(), b"hi".to_vec()])
+        );
+        assert!(read_command(&mut input).is_err());
+
+        let mut out = Vec::new();
+        Reply::Array(vec![
+            Reply::Bulk(b"v".to_vec()),
+            Reply::Nil,
+            Reply::Integer(2),
+        ])
+        .write(&mut out)
+        .unwrap();
+        assert_eq!(out, b"*3\r\n$1\r\nv\r\n$-1\r\n:2\r\n");
+
+        assert!(glob_match(b"user:*:name", b"user:42:name"));
+        assert!(glob_match(b"a?c\\*", b"abc*"));
+        assert!(!glob_match(b"a?c", b"ac"));
+    }
+
+    #[test]
+    fn serves_pipelined_commands() {
+        let path = std::env::temp_dir().join(format!("johndb-resp-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let db = Database::open(&path, Options::default()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        thread::spawn(move || serve_resp(listener, Arc::new(Mutex::new(db))));
+
+        let mut stream = TcpStream::connect(addr).unwrap();
+        let mut commands = Vec::new();
+        for i in 0..15 {
+            write!(commands, "SET key{:02} v{}\r\n", i, i).unwrap();
+        }
+        commands.extend_from_slice(
+            b"*3\r\n$4\r\nMGET\r\n$5\r\nkey03\r\n$4\r\nnope\r\n\
+              DEL key00 key01 nope\r\n\
+              SCAN 0 MATCH key1* COUNT 5\r\n\
+              GET\r\n\
+              FLUSHALL\r\n\
+              QUIT\r\n",
+        );
+        stream.write_all(&commands).unwrap();
+        stream.shutdown(Shutdown::Write).unwrap();
+        let mut replies = String::new();
+        stream.read_to_string(&mut replies).unwrap();
+
+        let expected = format!(
+            "{}*2\r\n$2\r\nv3\r\n$-1\r\n\
+             :2\r\n\
+             *2\r\n${}\r\nk{}\r\n*0\r\n\
+             -ERR wrong number of arguments for 'get' command\r\n\
+             -ERR unknown command 'flushall'\r\n\
+             +OK\r\n",
+            "+OK\r\n".repeat(15),
+            1 + 2 * 5,
+            // key02..key06 are looked at, and key07 is where the next call resumes
+            "6b65793037"
+        );
+        assert_eq!(replies, expected);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
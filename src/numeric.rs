@@ -0,0 +1,608 @@
+//! Arbitrary precision decimal numbers, for amounts that floats can't represent exactly.
+//!
+//! `Numeric` encodes order-preservingly, see `crate::encoding`, so it can be used in keys through
+//! `KeyEncoded`, alone or in a tuple, and stored as a value with `ValueNumeric`. Numbers are kept
+//! normalized, so `1.50` and `1.5` are the same number and encode the same way.
+//!
+//! The encoding is a tag byte for the sign, then for nonzero numbers the decimal exponent and the
+//! digits, two per byte as `10 * d1 + d2 + 1`, terminated by a zero byte. Negative numbers invert
+//! every byte after the tag, so larger magnitudes sort lower.
+
+use crate::encoding::Decode;
+use crate::encoding::Encode;
+use crate::error::Error;
+use crate::error::Result;
+use std::cmp::Ordering;
+use std::convert::TryFrom;
+use std::fmt;
+use std::ops::Add;
+use std::ops::Mul;
+use std::ops::Neg;
+use std::ops::Sub;
+
+const TAG_NEGATIVE: u8 = 0x01;
+const TAG_ZERO: u8 = 0x02;
+const TAG_POSITIVE: u8 = 0x03;
+const DIGITS_END: u8 = 0x00;
+
+/// A decimal number of any size and precision: `0.d1 d2 ... dn * 10^exponent`, negated if
+/// `negative`. The digits have no leading or trailing zeros, and zero has none at all.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Numeric {
+    negative: bool,
+    digits: Vec<u8>,
+    exponent: i32,
+}
+
+impl Numeric {
+    pub fn zero() -> Self {
+        Self {
+            negative: false,
+            digits: Vec::new(),
+            exponent: 0,
+        }
+    }
+
+    /// Parses decimal notation with an optional exponent, e.g. `-12.50` or `3e-7`.
+    pub fn parse(text: &str) -> Result<Self> {
+        let invalid = || Error::Eval(format!("invalid numeric {:?}", text));
+        let (negative, rest) = match text.as_bytes().first() {
+            Some(b'-') => (true, &text[1..]),
+            Some(b'+') => (false, &text[1..]),
+            _ => (false, text),
+        };
+        let (mantissa, exp) = match rest.find(['e', 'E']) {
+            Some(idx) => (
+                &rest[..idx],
+                rest[idx + 1..].parse::<i64>().map_err(|_| invalid())?,
+            ),
+            None => (rest, 0),
+        };
+        let (int, frac) = mantissa.split_once('.').unwrap_or((mantissa, ""));
+        if int.is_empty() && frac.is_empty()
+            || !int.bytes().chain(frac.bytes()).all(|b| b.is_ascii_digit())
+        {
+            return Err(invalid());
+        }
+
+        let digits = int.bytes().chain(frac.bytes()).map(|b| b - b'0').collect();
+        Self::from_mantissa(negative, digits, exp.saturating_sub(frac.len() as i64))
+            .ok_or_else(invalid)
+    }
+
+    pub fn is_zero(&self) -> bool {
+        self.digits.is_empty()
+    }
+
+    pub fn is_negative(&self) -> bool {
+        self.negative
+    }
+
+    pub fn abs(&self) -> Self {
+        Self {
+            negative: false,
+            ..self.clone()
+        }
+    }
+
+    fn with_sign(mut self, negative: bool) -> Self {
+        self.negative = negative && !self.is_zero();
+        self
+    }
+
+    /// The number of digits after the decimal point, e.g. 2 for `1.25` and 0 for `100`.
+    pub fn scale(&self) -> u32 {
+        self.mantissa_exponent().min(0).unsigned_abs() as u32
+    }
+
+    /// Rounds to `scale` digits after the decimal point, halves away from zero.
+    pub fn round(&self, scale: u32) -> Self {
+        let keep = self.digits.len() as i64 + self.mantissa_exponent() + scale as i64;
+        if keep >= self.digits.len() as i64 {
+            return self.clone();
+        }
+        if keep < 0 {
+            return Self::zero();
+        }
+        let keep = keep as usize;
+        let mut digits = self.digits[..keep].to_vec();
+        if self.digits[keep] >= 5 {
+            digits = add_digits(&digits, &[1]);
+        }
+        Self::from_mantissa(self.negative, digits, -(scale as i64)).unwrap_or_else(Self::zero)
+    }
+
+    /// `self / divisor` rounded to `scale` digits after the decimal point, halves away from zero.
+    /// Fails if `divisor` is zero.
+    pub fn checked_div(&self, divisor: &Numeric, scale: u32) -> Result<Self> {
+        if divisor.is_zero() {
+            return Err(Error::Eval("division by zero".to_string()));
+        }
+        // The quotient is below 10^(self.exponent - divisor.exponent + 1), so if that's below the
+        // rounding digit it rounds to zero
+        let quotient_digits = self.exponent as i64 - divisor.exponent as i64 + scale as i64 + 2;
+        if self.is_zero() || quotient_digits <= 0 {
+            return Ok(Self::zero());
+        }
+
+        // Computes self / divisor * 10^(scale + 1) as an integer, i.e. with one more digit than
+        // needed for rounding
+        let shift = self.mantissa_exponent() - divisor.mantissa_exponent() + scale as i64 + 1;
+        let mut numerator = self.digits.clone();
+        let mut denominator = divisor.digits.clone();
+        match shift >= 0 {
+            true => numerator.resize(numerator.len() + shift as usize, 0),
+            false => denominator.resize(denominator.len() + shift.unsigned_abs() as usize, 0),
+        }
+        let mut quotient = div_digits(&numerator, &denominator);
+        let rounding = quotient.pop().unwrap_or(0);
+        if rounding >= 5 {
+            quotient = add_digits(&quotient, &[1]);
+        }
+        Self::from_mantissa(self.negative != divisor.negative, quotient, -(scale as i64))
+            .ok_or_else(|| Error::Eval("numeric quotient is out of range".to_string()))
+    }
+
+    /// The exponent of the last digit, i.e. the number is the digits as an integer times 10 to
+    /// the power of this.
+    fn mantissa_exponent(&self) -> i64 {
+        self.exponent as i64 - self.digits.len() as i64
+    }
+
+    /// The number `digits * 10^exp`, normalized, or `None` if its exponent is out of range.
+    fn from_mantissa(negative: bool, mut digits: Vec<u8>, mut exp: i64) -> Option<Self> {
+        let leading = digits.iter().take_while(|d| **d == 0).count();
+        digits.drain(..leading);
+        while digits.last() == Some(&0) {
+            digits.pop();
+            exp = exp.saturating_add(1);
+        }
+        if digits.is_empty() {
+            return Some(Self::zero());
+        }
+        Some(Self {
+            negative,
+            exponent: i32::try_from(exp.checked_add(digits.len() as i64)?).ok()?,
+            digits,
+        })
+    }
+
+    /// Both numbers' digits as integers with the same exponent, which is returned with them.
+    fn aligned(&self, other: &Numeric) -> (Vec<u8>, Vec<u8>, i64) {
+        let exp = self.mantissa_exponent().min(other.mantissa_exponent());
+        let pad = |n: &Numeric| {
+            let mut digits = n.digits.clone();
+            digits.resize(digits.len() + (n.mantissa_exponent() - exp) as usize, 0);
+            digits
+        };
+        (pad(self), pad(other), exp)
+    }
+
+    fn cmp_magnitude(&self, other: &Numeric) -> Ordering {
+        match (self.is_zero(), other.is_zero()) {
+            (true, true) => Ordering::Equal,
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+            (false, false) => self
+                .exponent
+                .cmp(&other.exponent)
+                .then_with(|| self.digits.cmp(&other.digits)),
+        }
+    }
+
+    /// Everything after the tag byte for a nonzero number, before negative numbers are inverted.
+    fn encode_magnitude(&self, buf: &mut Vec<u8>) {
+        self.exponent.encode_to(buf);
+        for pair in self.digits.chunks(2) {
+            buf.push(10 * pair[0] + pair.get(1).copied().unwrap_or(0) + 1);
+        }
+        buf.push(DIGITS_END);
+    }
+
+    fn decode_magnitude(buf: &mut &[u8], negative: bool) -> Result<Self> {
+        let mut next = || -> Result<u8> {
+            let byte = u8::decode_from(buf)?;
+            Ok(match negative {
+                true => !byte,
+                false => byte,
+            })
+        };
+        let mut exponent = [0u8; 4];
+        for byte in exponent.iter_mut() {
+            *byte = next()?;
+        }
+        let exponent = i32::decode_from(&mut &exponent[..])?;
+
+        let mut digits = Vec::new();
+        loop {
+            match next()? {
+                DIGITS_END => break,
+                pair @ 1..=100 => digits.extend_from_slice(&[(pair - 1) / 10, (pair - 1) % 10]),
+                other => {
+                    return Err(Error::corruption(format!(
+                        "invalid numeric digit pair {}",
+                        other
+                    )))
+                }
+            }
+        }
+        if digits.last() == Some(&0) {
+            digits.pop();
+        }
+        if digits.first().is_none_or(|d| *d == 0) || digits.last() == Some(&0) {
+            return Err(Error::corruption(format!(
+                "numeric digits {:?} aren't normalized",
+                digits
+            )));
+        }
+        Ok(Self {
+            negative,
+            digits,
+            exponent,
+        })
+    }
+}
+
+/// Sums two integers given as digits, most significant first.
+fn add_digits(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut sum = Vec::with_capacity(a.len().max(b.len()) + 1);
+    let mut carry = 0;
+    let (mut a, mut b) = (a.iter().rev(), b.iter().rev());
+    loop {
+        let (x, y) = (a.next(), b.next());
+        if x.is_none() && y.is_none() {
+            break;
+        }
+        let digit = x.unwrap_or(&0) + y.unwrap_or(&0) + carry;
+        sum.push(digit % 10);
+        carry = digit / 10;
+    }
+    if carry > 0 {
+        sum.push(carry);
+    }
+    sum.reverse();
+    sum
+}
+
+/// Subtracts `b` from `a`, which must be at least as large.
+fn sub_digits(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut diff = Vec::with_capacity(a.len());
+    let mut borrow = 0;
+    let mut b = b.iter().rev();
+    for x in a.iter().rev() {
+        let y = b.next().unwrap_or(&0) + borrow;
+        borrow = (*x < y) as u8;
+        diff.push(*x + 10 * borrow - y);
+    }
+    diff.reverse();
+    diff
+}
+
+/// Compares two integers given as digits, ignoring leading zeros.
+fn cmp_digits(a: &[u8], b: &[u8]) -> Ordering {
+    let strip = |digits: &[u8]| digits.iter().position(|d| *d != 0).unwrap_or(digits.len());
+    let (a, b) = (&a[strip(a)..], &b[strip(b)..]);
+    a.len().cmp(&b.len()).then_with(|| a.cmp(b))
+}
+
+fn mul_digits(a: &[u8], b: &[u8]) -> Vec<u8> {
+    let mut product = vec![0u32; a.len() + b.len()];
+    for (i, x) in a.iter().enumerate().rev() {
+        for (j, y) in b.iter().enumerate().rev() {
+            product[i + j + 1] += (*x as u32) * (*y as u32);
+        }
+    }
+    for idx in (1..product.len()).rev() {
+        product[idx - 1] += product[idx] / 10;
+        product[idx] %= 10;
+    }
+    product.into_iter().map(|d| d as u8).collect()
+}
+
+/// Long division of two integers given as digits, rounding towards zero.
+fn div_digits(numerator: &[u8], denominator: &[u8]) -> Vec<u8> {
+    let mut quotient = Vec::with_capacity(numerator.len());
+    let mut remainder = Vec::with_capacity(denominator.len() + 1);
+    for digit in numerator {
+        remainder.push(*digit);
+        let mut q = 0;
+        while cmp_digits(&remainder, denominator) != Ordering::Less {
+            remainder = sub_digits(&remainder, denominator);
+            q += 1;
+        }
+        quotient.push(q);
+        let leading = remainder.iter().take_while(|d| **d == 0).count();
+        remainder.drain(..leading);
+    }
+    quotient
+}
+
+impl PartialOrd for Numeric {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Numeric {
+    fn cmp(&self, other: &Self) -> Ordering {
+        match (self.negative, other.negative) {
+            (false, false) => self.cmp_magnitude(other),
+            (true, true) => other.cmp_magnitude(self),
+            (true, false) => Ordering::Less,
+            (false, true) => Ordering::Greater,
+        }
+    }
+}
+
+impl From<i64> for Numeric {
+    fn from(value: i64) -> Self {
+        Self::from(value.unsigned_abs()).with_sign(value < 0)
+    }
+}
+
+impl From<u64> for Numeric {
+    fn from(value: u64) -> Self {
+        let digits = value.to_string().bytes().map(|b| b - b'0').collect();
+        Self::from_mantissa(false, digits, 0).unwrap()
+    }
+}
+
+impl fmt::Display for Numeric {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.is_zero() {
+            return write!(f, "0");
+        }
+        if self.negative {
+            write!(f, "-")?;
+        }
+        let digits = self
+            .digits
+            .iter()
+            .map(|d| (b'0' + d) as char)
+            .collect::<String>();
+        let exponent = self.exponent as i64;
+        if exponent <= 0 {
+            write!(
+                f,
+                "0.{}{}",
+                "0".repeat(exponent.unsigned_abs() as usize),
+                digits
+            )
+        } else if exponent >= digits.len() as i64 {
+            write!(
+                f,
+                "{}{}",
+                digits,
+                "0".repeat(exponent as usize - digits.len())
+            )
+        } else {
+            let (int, frac) = digits.split_at(exponent as usize);
+            write!(f, "{}.{}", int, frac)
+        }
+    }
+}
+
+impl Add for &Numeric {
+    type Output = Numeric;
+
+    fn add(self, other: &Numeric) -> Numeric {
+        match (self.is_zero(), other.is_zero()) {
+            (true, _) => return other.clone(),
+            (_, true) => return self.clone(),
+            _ => {}
+        }
+        let (a, b, exp) = self.aligned(other);
+        let (negative, digits) = match (self.negative == other.negative, cmp_digits(&a, &b)) {
+            (true, _) => (self.negative, add_digits(&a, &b)),
+            (false, Ordering::Less) => (other.negative, sub_digits(&b, &a)),
+            (false, _) => (self.negative, sub_digits(&a, &b)),
+        };
+        Numeric::from_mantissa(negative, digits, exp)
+            .expect("sum's exponent is within the operands'")
+    }
+}
+
+impl Sub for &Numeric {
+    type Output = Numeric;
+
+    fn sub(self, other: &Numeric) -> Numeric {
+        self + &-other
+    }
+}
+
+/// Panics if the product's exponent is out of range, like integer overflow.
+impl Mul for &Numeric {
+    type Output = Numeric;
+
+    fn mul(self, other: &Numeric) -> Numeric {
+        Numeric::from_mantissa(
+            self.negative != other.negative,
+            mul_digits(&self.digits, &other.digits),
+            self.mantissa_exponent() + other.mantissa_exponent(),
+        )
+        .expect("numeric product is out of range")
+    }
+}
+
+impl Neg for &Numeric {
+    type Output = Numeric;
+
+    fn neg(self) -> Numeric {
+        self.clone().with_sign(!self.negative)
+    }
+}
+
+impl Encode for Numeric {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        if self.is_zero() {
+            buf.push(TAG_ZERO);
+            return;
+        }
+        match self.negative {
+            true => buf.push(TAG_NEGATIVE),
+            false => buf.push(TAG_POSITIVE),
+        }
+        let start = buf.len();
+        self.encode_magnitude(buf);
+        if self.negative {
+            for byte in buf[start..].iter_mut() {
+                *byte = !*byte;
+            }
+        }
+    }
+}
+
+impl Decode for Numeric {
+    fn decode_from(buf: &mut &[u8]) -> Result<Self> {
+        match u8::decode_from(buf)? {
+            TAG_ZERO => Ok(Self::zero()),
+            TAG_POSITIVE => Self::decode_magnitude(buf, false),
+            TAG_NEGATIVE => Self::decode_magnitude(buf, true),
+            other => Err(Error::corruption(format!("invalid numeric tag {}", other))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Numeric;
+    use crate::btree::key::KeyEncoded;
+    use crate::btree::value::ValueNumeric;
+    use crate::btree::BTree;
+    use crate::encoding::decode;
+    use crate::encoding::encode;
+    use crate::page_fetcher::InMemoryPageFetcher;
+
+    fn num(text: &str) -> Numeric {
+        Numeric::parse(text).unwrap()
+    }
+
+    #[test]
+    fn parse_and_display() {
+        for (text, shown) in [
+            ("0", "0"),
+            ("-0.000", "0"),
+            ("1.50", "1.5"),
+            ("+007", "7"),
+            ("-12.345", "-12.345"),
+            (".25", "0.25"),
+            ("3e-7", "0.0000003"),
+            ("1.2E3", "1200"),
+            (
+                "123456789012345678901234567890.000000000000000000001",
+                "123456789012345678901234567890.000000000000000000001",
+            ),
+        ] {
+            assert_eq!(num(text).to_string(), shown, "{}", text);
+        }
+        for text in ["", "-", ".", "1.2.3", "1e", "abc", "1e99999999999"] {
+            assert!(Numeric::parse(text).is_err(), "{}", text);
+        }
+        assert_eq!(num("1.50"), num("1.5"));
+        assert_eq!(Numeric::from(-120i64), num("-120"));
+        assert_eq!(num("-1.25").scale(), 2);
+        assert_eq!(num("1200").scale(), 0);
+    }
+
+    #[test]
+    fn encoding_preserves_order() {
+        let values = [
+            "-1e300",
+            "-1000",
+            "-999.99",
+            "-1",
+            "-0.5",
+            "-0.49",
+            "-0.001",
+            "0",
+            "0.001",
+            "0.1",
+            "0.1000001",
+            "0.11",
+            "1",
+            "1.5",
+            "9",
+            "10",
+            "99",
+            "100.01",
+            "1e300",
+        ]
+        .iter()
+        .map(|text| num(text))
+        .collect::<Vec<_>>();
+        for pair in values.windows(2) {
+            assert!(pair[0] < pair[1], "{:?}", pair);
+            assert!(encode(&pair[0]) < encode(&pair[1]), "{:?}", pair);
+        }
+        for value in values.iter() {
+            assert_eq!(decode::<Numeric>(&encode(value)).unwrap(), *value);
+        }
+        assert!(decode::<Numeric>(&[0x03, 0x80, 0, 0, 1, 1, 0]).is_err());
+    }
+
+    #[test]
+    fn exact_arithmetic() {
+        // 0.1 + 0.2 is 0.30000000000000004 as floats
+        assert_eq!(&num("0.1") + &num("0.2"), num("0.3"));
+        assert_eq!(&num("1000") - &num("0.01"), num("999.99"));
+        assert_eq!(&num("-5.5") + &num("2.25"), num("-3.25"));
+        assert_eq!(&num("2.5") - &num("2.5"), Numeric::zero());
+        assert_eq!(
+            &num("1e20") + &num("1e-20"),
+            num("100000000000000000000.00000000000000000001")
+        );
+        assert_eq!(&num("-1.5") * &num("0.2"), num("-0.3"));
+        assert_eq!(
+            &num("99999999999999999999") * &num("99999999999999999999"),
+            num("9999999999999999999800000000000000000001")
+        );
+
+        assert_eq!(num("10").checked_div(&num("3"), 4).unwrap(), num("3.3333"));
+        assert_eq!(num("2").checked_div(&num("3"), 2).unwrap(), num("0.67"));
+        assert_eq!(num("-1").checked_div(&num("8"), 2).unwrap(), num("-0.13"));
+        assert_eq!(
+            num("1e10").checked_div(&num("0.5"), 0).unwrap(),
+            num("2e10")
+        );
+        assert_eq!(
+            num("1").checked_div(&num("1e9"), 3).unwrap(),
+            Numeric::zero()
+        );
+        assert!(num("1").checked_div(&Numeric::zero(), 2).is_err());
+
+        assert_eq!(num("2.345").round(2), num("2.35"));
+        assert_eq!(num("-2.345").round(1), num("-2.3"));
+        assert_eq!(num("9.99").round(1), num("10"));
+        assert_eq!(num("0.004").round(2), Numeric::zero());
+        assert_eq!(num("123").round(2), num("123"));
+    }
+
+    #[test]
+    fn range_over_amounts() {
+        let mut btree = BTree::new(InMemoryPageFetcher::with_capacity(256)).unwrap();
+        // Cents from -50.00 to 49.99, in a scrambled order
+        for i in 0..10000i64 {
+            let cents = (i * 7919) % 10000 - 5000;
+            let amount = Numeric::from(cents).checked_div(&num("100"), 2).unwrap();
+            btree
+                .insert(KeyEncoded::new(&amount), ValueNumeric::from(-&amount))
+                .unwrap();
+        }
+
+        let entries = btree
+            .range(KeyEncoded::new(&num("-0.05"))..=KeyEncoded::new(&num("0.1")))
+            .unwrap()
+            .map(|res| {
+                let (key, value) = res.unwrap();
+                let amount = key.decode::<Numeric>().unwrap();
+                assert_eq!(value.value, -&amount);
+                amount.to_string()
+            })
+            .collect::<Vec<_>>();
+        let expected = [
+            "-0.05", "-0.04", "-0.03", "-0.02", "-0.01", "0", "0.01", "0.02", "0.03", "0.04",
+            "0.05", "0.06", "0.07", "0.08", "0.09", "0.1",
+        ];
+        assert_eq!(entries, expected);
+    }
+}
@@ -1,25 +1,124 @@
+//! Slotted pages. A page's in-memory representation is also its on-disk one, so it's laid out the
+//! same on every platform: the header's fields and the item pointers are little-endian integers
+//! at fixed offsets, and are only converted when read or written through their accessors. Items
+//! and special data are responsible for their own encoding, which must likewise not depend on the
+//! platform or on how the compiler lays out structs, see `Item`.
+//!
+//! Item encoding goes through byte slices only, so the page tests also run under Miri:
+//! `cargo +nightly miri test --lib page::`.
+//!
+//! By default, an item's bytes are sliced out of the page without a second bounds check once its
+//! item pointer has been validated, and `from_bytes` copies a page in one go. The `safe-pages`
+//! feature replaces both with plain bounds-checked slice code, for fuzzing, Miri and embedders
+//! who'd rather not trust the validation. Mapping special data onto its type and viewing a whole
+//! page as bytes stay casts either way, as their layout is fixed. `johndb-bench --workload pages`
+//! measures the difference: decoding a full page of `KeyU64` items and rebuilding the page ran
+//! 5-15% slower with the feature on x86-64.
+
+use crate::error::Error;
+use crate::error::Result;
+use crate::mem::align_offset;
 use crate::mem::align_offset_down;
 
+use std::convert::TryInto;
+use std::fmt;
+use std::iter::FusedIterator;
 use std::marker::PhantomData;
+use std::mem::align_of;
 use std::mem::size_of;
-use std::ptr::addr_of;
 
-const PAGE_SIZE: usize = 8192;
+pub const PAGE_SIZE: usize = 8192;
 const PAGE_HEADER_SIZE: usize = size_of::<PageHeader>();
 pub const PAGE_DATA_SIZE: usize = PAGE_SIZE - PAGE_HEADER_SIZE;
-pub const ITEM_POINTER_SIZE: usize = size_of::<ItemPointer>();
+pub const ITEM_POINTER_SIZE: usize = 2 * size_of::<u16>();
+/// The special area is rounded up to a multiple of this many bytes. Pages are aligned to their
+/// size, so it then starts at an offset aligned to it too, and special data that's aligned to at
+/// most this can be mapped onto it in place.
+pub const SPECIAL_ALIGN: usize = 8;
+
+/// The size of the special area holding `special_size` bytes of special data.
+pub fn special_area_size(special_size: usize) -> usize {
+    align_offset(special_size, SPECIAL_ALIGN)
+}
+
+/// A link to a page, e.g. a node's right sibling, or `PageNo::INVALID` if there's none. Page 0
+/// is a page like any other, while `INVALID` is never handed out by a page fetcher.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct PageNo(u32);
+
+impl PageNo {
+    pub const INVALID: PageNo = PageNo(u32::MAX);
+
+    pub fn new(page_no: u32) -> Self {
+        PageNo(page_no)
+    }
+
+    /// The page number, or `None` for `INVALID`.
+    pub fn get(self) -> Option<u32> {
+        self.is_valid().then_some(self.0)
+    }
+
+    pub fn is_valid(self) -> bool {
+        self != Self::INVALID
+    }
+
+    /// The page number as stored on disk, `u32::MAX` for `INVALID`.
+    pub fn to_raw(self) -> u32 {
+        self.0
+    }
+}
+
+impl From<Option<u32>> for PageNo {
+    fn from(page_no: Option<u32>) -> Self {
+        page_no.map_or(Self::INVALID, PageNo)
+    }
+}
+
+impl fmt::Debug for PageNo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.get() {
+            Some(page_no) => write!(f, "PageNo({})", page_no),
+            None => write!(f, "PageNo::INVALID"),
+        }
+    }
+}
+
+impl fmt::Display for PageNo {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.get() {
+            Some(page_no) => write!(f, "{}", page_no),
+            None => write!(f, "none"),
+        }
+    }
+}
 
+/// Something that can be stored in a page. Implementations encode integers in a fixed byte order
+/// (little-endian, or big-endian where the bytes need to sort like the integers) rather than
+/// copying structs, so that a page written on one platform can be read on any other.
 pub trait Item {
     fn size(&self) -> usize;
     fn align() -> usize;
     fn is_fixed_size() -> bool;
-    unsafe fn write(&self, buffer: *mut u8);
-    unsafe fn read(buffer: *const u8, size: usize) -> Self;
+    /// The size of every item of a fixed size type, which nodes lay their items out by. That's
+    /// the type's in-memory size unless its encoding takes a different number of bytes.
+    fn fixed_size() -> usize
+    where
+        Self: Sized,
+    {
+        size_of::<Self>()
+    }
+    /// Encodes the item into `buffer`, which is exactly `self.size()` bytes long. Items are written
+    /// and read through byte slices only, never by casting the page's bytes to a struct, so they
+    /// may sit at any offset within the page.
+    fn write(&self, buffer: &mut [u8]);
+    /// Decodes an item from `bytes`, as produced by `Item::write`. May panic on bytes that weren't,
+    /// so pages that may be corrupted must be checked first, see `node::check_node_items`.
+    fn read(bytes: &[u8]) -> Self;
 }
 
 #[derive(Debug, Copy, Clone)]
 // TODO: Figure out how we can make 8192 a const in the macro world.
-#[repr(align(8192))]
+#[repr(C, align(8192))]
 // TODO: Make all fields private
 pub struct Page {
     pub header: PageHeader,
@@ -27,7 +126,7 @@ pub struct Page {
 }
 
 impl Page {
-    // TODO: Make sure special_size is aligned to 8bytes
+    /// An empty page with room for `special_size` bytes of special data, see `PageHeader::new`.
     pub fn new(special_size: u32) -> Page {
         Page {
             header: PageHeader::new(special_size),
@@ -35,36 +134,90 @@ impl Page {
         }
     }
 
-    pub fn special_data<SpecialData>(&self) -> &SpecialData {
-        assert!(
-            std::mem::size_of::<SpecialData>() == self.header.special_size as usize,
-            "Mismatch on SpecialData size (SpecialData: {}, PageHeader.special_size: {}",
-            std::mem::size_of::<SpecialData>(),
-            self.header.special_size
-        );
+    /// Rebuilds a page from the raw bytes produced by `as_bytes`.
+    pub fn from_bytes(bytes: &[u8]) -> Page {
+        assert_eq!(bytes.len(), PAGE_SIZE);
+        let mut page = Page::new(0);
+        page.copy_from_bytes(bytes);
+        page
+    }
+
+    #[cfg(not(feature = "safe-pages"))]
+    fn copy_from_bytes(&mut self, bytes: &[u8]) {
+        unsafe {
+            std::ptr::copy_nonoverlapping(bytes.as_ptr(), self as *mut Page as *mut u8, PAGE_SIZE);
+        }
+    }
 
-        return unsafe {
-            &*(&self.data[PAGE_DATA_SIZE - self.header.special_size as usize] as *const u8
-                as *const SpecialData)
+    /// Copies the header field by field. Its fields hold the bytes as they are in memory, so they
+    /// are read back in native byte order.
+    #[cfg(feature = "safe-pages")]
+    fn copy_from_bytes(&mut self, bytes: &[u8]) {
+        let (header, data) = bytes.split_at(PAGE_HEADER_SIZE);
+        let field =
+            |idx: usize| u32::from_ne_bytes(header[idx * 4..idx * 4 + 4].try_into().unwrap());
+        self.header = PageHeader {
+            item_upper: field(0),
+            item_lower: field(1),
+            special_size: field(2),
         };
+        self.data.copy_from_slice(data);
+    }
+
+    /// The raw on-disk representation of the page, header included.
+    pub fn as_bytes(&self) -> &[u8] {
+        unsafe { std::slice::from_raw_parts(self as *const Page as *const u8, PAGE_SIZE) }
+    }
+
+    /// Like `as_bytes`, for reading a page in place. The frame is aligned to the page size, so it
+    /// can be handed straight to direct I/O.
+    pub fn as_bytes_mut(&mut self) -> &mut [u8] {
+        unsafe { std::slice::from_raw_parts_mut(self as *mut Page as *mut u8, PAGE_SIZE) }
+    }
+
+    /// The special data at the start of the special area, which is mapped directly onto
+    /// `SpecialData`. It should be `#[repr(C)]` and store its integers in a fixed byte order, like
+    /// `PageHeader`.
+    pub fn special_data<SpecialData>(&self) -> &SpecialData {
+        let offset = self.special_offset::<SpecialData>();
+        unsafe { &*(self.data[offset..].as_ptr() as *const SpecialData) }
     }
 
     pub fn special_data_mut<SpecialData>(&mut self) -> &mut SpecialData {
+        let offset = self.special_offset::<SpecialData>();
+        unsafe { &mut *(self.data[offset..].as_mut_ptr() as *mut SpecialData) }
+    }
+
+    /// Where the special area starts in `data`, after checking that `SpecialData` fits it.
+    fn special_offset<SpecialData>(&self) -> usize {
         assert!(
-            std::mem::size_of::<SpecialData>() == self.header.special_size as usize,
+            special_area_size(size_of::<SpecialData>()) == self.header.special_size(),
             "Mismatch on SpecialData size (SpecialData: {}, PageHeader.special_size: {}",
-            std::mem::size_of::<SpecialData>(),
-            self.header.special_size
+            size_of::<SpecialData>(),
+            self.header.special_size()
+        );
+        assert!(
+            align_of::<SpecialData>() <= SPECIAL_ALIGN,
+            "SpecialData is aligned to {} bytes, more than the special area's {}",
+            align_of::<SpecialData>(),
+            SPECIAL_ALIGN
         );
 
-        return unsafe {
-            &mut *(&mut self.data[PAGE_DATA_SIZE - self.header.special_size as usize] as *mut u8
-                as *mut SpecialData)
-        };
+        let offset = PAGE_DATA_SIZE - self.header.special_size();
+        debug_assert!((PAGE_HEADER_SIZE + offset).is_multiple_of(SPECIAL_ALIGN));
+        offset
+    }
+
+    pub fn items_iter_v2<I: Item>(&self) -> PageItemIteratorV2<'_, I> {
+        PageItemIteratorV2::new(self)
     }
 
-    pub fn items_iter_v2<I: Item>(&self) -> PageItemIteratorV2<I> {
-        return PageItemIteratorV2::new(self);
+    /// Like `items_iter_v2`, but starts at item `start`. The items before it are never decoded,
+    /// so they may be of a different type than `I` (e.g. a node's separator).
+    pub fn items_iter_from_v2<I: Item>(&self, start: usize) -> PageItemIteratorV2<'_, I> {
+        let mut iter = PageItemIteratorV2::new(self);
+        iter.front = start.min(iter.back);
+        iter
     }
 
     pub fn item_cnt(&self) -> usize {
@@ -76,219 +229,290 @@ impl Page {
     }
 
     pub fn zero_out_item_data(&mut self) {
-        for i in 0..(PAGE_DATA_SIZE - (self.header.special_size as usize)) {
+        for i in 0..(PAGE_DATA_SIZE - self.header.special_size()) {
             self.data[i] = 0;
         }
 
-        self.header = PageHeader::new(self.header.special_size);
+        self.header = PageHeader::new(self.header.special_size() as u32);
     }
 
-    #[deprecated]
-    pub fn pop_item(&mut self) -> Result<(), &'static str> {
-        if self.item_cnt() == 0 {
-            return Err("No more left to pop");
+    pub fn add_item_v2<T>(&mut self, item: &T) -> Result<()>
+    where
+        T: Item,
+    {
+        self.insert_item_v2(self.item_cnt(), item)
+    }
+
+    /// Adds `item` as item `idx`, shifting the item pointers from `idx` on up by one. Only the
+    /// pointers move; the item's data goes wherever there's room, like `add_item_v2`.
+    pub fn insert_item_v2<T>(&mut self, idx: usize, item: &T) -> Result<()>
+    where
+        T: Item,
+    {
+        assert!(idx <= self.item_cnt());
+        let (ptr_offset, data_offset) = self.header.allocate(item.size(), T::align())?;
+
+        item.write(&mut self.data[data_offset..data_offset + item.size()]);
+
+        let data_idx = idx * ITEM_POINTER_SIZE;
+        self.data
+            .copy_within(data_idx..ptr_offset, data_idx + ITEM_POINTER_SIZE);
+        ItemPointer {
+            offset: data_offset as u16,
+            size: item.size() as u16,
         }
+        .write(&mut self.data[data_idx..]);
 
-        let item_ptr = unsafe {
-            &*(&self.data[self.header.item_upper as usize - ITEM_POINTER_SIZE] as *const u8
-                as *const ItemPointer) as &ItemPointer
-        };
+        Ok(())
+    }
 
-        self.header.item_upper -= ITEM_POINTER_SIZE as u32;
-        self.header.item_lower += item_ptr.size as u32;
+    /// Appends a copy of `other`'s item `idx` without decoding it. `align` must be the alignment
+    /// of the item's type.
+    fn copy_item_from(&mut self, other: &Page, idx: usize, align: usize) -> Result<()> {
+        let (offset, size) = other.item_pointer(idx);
+        let (ptr_offset, data_offset) = self.header.allocate(size, align)?;
+
+        self.data[data_offset..data_offset + size]
+            .copy_from_slice(&other.data[offset..offset + size]);
+        ItemPointer {
+            offset: data_offset as u16,
+            size: size as u16,
+        }
+        .write(&mut self.data[ptr_offset..]);
 
         Ok(())
     }
 
-    pub fn add_item_v2<T>(&mut self, item: &T) -> Result<(), &'static str>
-    where
-        T: Item,
-    {
-        let (ptr_offset, data_offset) = self.header.add_item_v2(item)?;
+    /// Moves the items from `start` on, in order, to the end of `other` by copying their bytes
+    /// as-is. Their data is left behind in this page as dead space until it's compacted.
+    pub fn move_items_to<I: Item>(&mut self, start: usize, other: &mut Page) -> Result<()> {
+        assert!(start <= self.item_cnt());
+        for idx in start..self.item_cnt() {
+            other.copy_item_from(self, idx, I::align())?;
+        }
+        self.header
+            .set_item_upper((start * ITEM_POINTER_SIZE) as u32);
 
-        let item_data = &mut self.data[data_offset as usize] as *mut u8;
-        let item_ptr = (&mut self.data[ptr_offset as usize] as *mut u8) as *mut ItemPointer;
+        Ok(())
+    }
 
-        unsafe {
-            item.write(item_data);
-            *item_ptr = ItemPointer {
-                size: item.size() as u16,
-                offset: data_offset as u16,
-            };
-        };
+    /// Rebuilds the page from its items, in order and without decoding them, reclaiming the
+    /// dead space left behind by removed or moved items. Every item is aligned to `I::align()`.
+    pub fn compact<I: Item>(&mut self) -> Result<()> {
+        let old = *self;
+        self.zero_out_item_data();
+        for idx in 0..old.item_cnt() {
+            self.copy_item_from(&old, idx, I::align())?;
+        }
 
         Ok(())
     }
 
+    /// Decodes item `idx`. Panics if its item pointer is out of bounds, see `try_get_item_v2`.
     pub fn get_item_v2<I>(&self, idx: usize) -> I
     where
         I: Item,
     {
-        let data_idx = idx * ITEM_POINTER_SIZE;
-        assert!(
-            data_idx < self.header.item_upper as usize,
-            "TODO: Make this return an Option/Result"
-        );
-        unsafe {
-            let item_ptr = &*(addr_of!(self.data[data_idx]) as *const u8 as *const ItemPointer);
-
-            I::read(
-                addr_of!(self.data[item_ptr.offset as usize]),
-                item_ptr.size as usize,
-            )
-        }
+        self.try_get_item_v2(idx)
+            .unwrap_or_else(|err| panic!("{}", err))
     }
 
-    pub fn update_item_v2<T>(&mut self, idx: usize, item: &T)
+    /// Like `get_item_v2`, but returns `Error::Corruption` if item `idx` doesn't exist or its
+    /// item pointer strays outside the item data, e.g. on a page read from a damaged file.
+    pub fn try_get_item_v2<I>(&self, idx: usize) -> Result<I>
     where
-        T: Item,
+        I: Item,
     {
-        // first, find existing item. if it's a larger item, just replace the data. otherwise,
-        // we'll panic
-        // TODO: Shift bytes around for dynamic sizing
-        let data_idx = idx * ITEM_POINTER_SIZE;
-        assert!(data_idx < self.header.item_upper as usize);
-        let item_ptr = unsafe { &*(&self.data[data_idx] as *const u8 as *const ItemPointer) };
-        assert_eq!(
-            (item_ptr.size as usize),
-            item.size(),
-            "TODO: Need to shift bytes around!"
-        );
-        let data_ptr = &mut self.data[item_ptr.offset as usize] as *mut u8;
+        let bytes = self.item_bytes(idx, I::align())?;
+        Ok(I::read(bytes))
+    }
+
+    /// The data of item `idx`, after checking that it lies between the item pointers and the
+    /// special data and is aligned to `align`, so that decoding it never reads outside the page.
+    pub fn item_bytes(&self, idx: usize, align: usize) -> Result<&[u8]> {
+        let item_cnt = self.item_cnt();
+        if idx >= item_cnt || self.header.item_upper() > PAGE_DATA_SIZE {
+            return Err(Error::corruption(format!(
+                "item {} is missing from a page of {} items",
+                idx, item_cnt
+            )));
+        }
 
-        unsafe { item.write(data_ptr) };
-    }
-}
+        let (offset, size) = self.item_pointer(idx);
+        let data_start = self.header.item_upper().max(self.header.item_lower());
+        let data_end = PAGE_DATA_SIZE.saturating_sub(self.header.special_size());
+        if offset < data_start || offset + size > data_end || !offset.is_multiple_of(align) {
+            return Err(Error::corruption(format!(
+                "item {} at offset {} with size {} lies outside the item data",
+                idx, offset, size
+            )));
+        }
+        Ok(self.item_data(offset, size))
+    }
+
+    /// `data[offset..offset + size]`, which the caller has checked lies within `data`.
+    #[cfg(not(feature = "safe-pages"))]
+    fn item_data(&self, offset: usize, size: usize) -> &[u8] {
+        debug_assert!(offset + size <= PAGE_DATA_SIZE);
+        unsafe { self.data.get_unchecked(offset..offset + size) }
+    }
+
+    #[cfg(feature = "safe-pages")]
+    fn item_data(&self, offset: usize, size: usize) -> &[u8] {
+        &self.data[offset..offset + size]
+    }
+
+    /// Checks that the header and item pointers describe a well-formed page, so that its items
+    /// can be read without straying outside the item data region.
+    pub fn check_layout(&self) -> Result<()> {
+        let special_size = self.header.special_size();
+        let item_upper = self.header.item_upper();
+        let item_lower = self.header.item_lower();
+        if special_size > PAGE_DATA_SIZE
+            || !special_size.is_multiple_of(SPECIAL_ALIGN)
+            || !item_upper.is_multiple_of(ITEM_POINTER_SIZE)
+            || item_upper > item_lower
+            || item_lower > PAGE_DATA_SIZE - special_size
+        {
+            return Err(Error::corruption(format!(
+                "invalid page header {:?}",
+                self.header
+            )));
+        }
 
-#[deprecated]
-pub struct ItemData<'a> {
-    pub size: usize,
-    pub raw_data_ptr: *const u8,
-    // We want to bind ItemData's lifetime to at least the Page's lifetime
-    phantom_page: PhantomData<&'a Page>,
-}
+        for idx in 0..self.item_cnt() {
+            self.item_bytes(idx, 1)?;
+        }
 
-impl<'a> ItemData<'a> {
-    pub fn to_data_ref<T: Sized>(&self) -> &T {
-        assert!(self.size == std::mem::size_of::<T>());
+        Ok(())
+    }
+
+    /// The `(offset, size)` of item `idx`'s data within the data region.
+    pub fn item_pointer(&self, idx: usize) -> (usize, usize) {
+        let data_idx = idx * ITEM_POINTER_SIZE;
+        assert!(data_idx < self.header.item_upper());
+        let item_ptr = ItemPointer::read(&self.data[data_idx..]);
 
-        unsafe { &*(self.raw_data_ptr as *const T) }
+        (item_ptr.offset as usize, item_ptr.size as usize)
     }
-}
 
-#[deprecated]
-pub struct PageItemIterator<'a> {
-    page: &'a Page,
-    curr: usize,
-}
+    /// Borrows item `idx` without decoding it, see `ItemRef`. Panics if its item pointer is out
+    /// of bounds, see `try_get_item_ref`.
+    pub fn get_item_ref<I>(&self, idx: usize) -> ItemRef<'_, I>
+    where
+        I: Item,
+    {
+        self.try_get_item_ref(idx)
+            .unwrap_or_else(|err| panic!("{}", err))
+    }
 
-impl<'a> PageItemIterator<'a> {
-    fn new(page: &'a Page) -> Self {
-        PageItemIterator { page, curr: 0 }
+    /// Like `get_item_ref`, but returns `Error::Corruption` for a bad item pointer like
+    /// `try_get_item_v2`.
+    pub fn try_get_item_ref<I>(&self, idx: usize) -> Result<ItemRef<'_, I>>
+    where
+        I: Item,
+    {
+        Ok(ItemRef {
+            // Item 0 of a node is borrowed as a node item to read its key, but it was written as
+            // a key, so only `ItemRef::read` can rely on the item's alignment
+            bytes: self.item_bytes(idx, 1)?,
+            phantom: PhantomData,
+        })
     }
-}
 
-impl<'a> Iterator for PageItemIterator<'a> {
-    type Item = ItemData<'a>;
+    /// Like `items_iter_from_v2`, but yields borrowed `ItemRef`s instead of decoded items.
+    pub fn item_refs_from<I: Item>(&self, start: usize) -> impl Iterator<Item = ItemRef<'_, I>> {
+        (start..self.item_cnt()).map(move |idx| self.get_item_ref(idx))
+    }
 
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.curr < self.page.header.item_cnt() {
-            unsafe {
-                let item_pointer: &ItemPointer = &*(&self.page.data[self.curr * ITEM_POINTER_SIZE]
-                    as *const u8
-                    as *const ItemPointer);
-
-                Some(ItemData {
-                    size: item_pointer.size as usize,
-                    raw_data_ptr: &self.page.data[item_pointer.offset as usize] as *const u8,
-                    phantom_page: PhantomData,
-                })
-            }
-        } else {
-            None
+    /// Removes the item at `idx`, shifting the item pointers after it down by one. The item's data
+    /// is left in place as dead space until the page is rebuilt.
+    pub fn remove_item_v2(&mut self, idx: usize) {
+        let data_idx = idx * ITEM_POINTER_SIZE;
+        let item_upper = self.header.item_upper();
+        assert!(data_idx < item_upper);
+
+        self.data
+            .copy_within(data_idx + ITEM_POINTER_SIZE..item_upper, data_idx);
+        self.header
+            .set_item_upper((item_upper - ITEM_POINTER_SIZE) as u32);
+    }
+
+    /// Bytes between the item pointers and the item data, i.e. the room left for new items and
+    /// their pointers without compacting the page.
+    pub fn free_space(&self) -> usize {
+        self.header
+            .item_lower()
+            .saturating_sub(self.header.item_upper())
+    }
+
+    /// Shrinks item `idx` to its first `size` bytes. The rest of its data is left in place as dead
+    /// space until the page is rebuilt.
+    pub fn truncate_item(&mut self, idx: usize, size: usize) {
+        let (offset, old_size) = self.item_pointer(idx);
+        assert!(size <= old_size);
+        ItemPointer {
+            offset: offset as u16,
+            size: size as u16,
         }
+        .write(&mut self.data[idx * ITEM_POINTER_SIZE..]);
+    }
+
+    /// Bytes in the item data region that no longer belong to a live item (removed items and
+    /// alignment padding). Items on a corrupted page may overlap, in which case this is 0.
+    pub fn dead_space(&self) -> usize {
+        let live: usize = (0..self.item_cnt())
+            .map(|idx| self.item_pointer(idx).1)
+            .sum();
+
+        self.item_data_size().saturating_sub(live)
+    }
+
+    pub fn update_item_v2<T>(&mut self, idx: usize, item: &T)
+    where
+        T: Item,
+    {
+        // first, find existing item. if it's a larger item, just replace the data. otherwise,
+        // we'll panic
+        // TODO: Shift bytes around for dynamic sizing
+        let (offset, size) = self.item_pointer(idx);
+        assert_eq!(size, item.size(), "TODO: Need to shift bytes around!");
+        item.write(&mut self.data[offset..offset + size]);
     }
 }
 
-#[deprecated]
-pub struct PageItemIteratorTyped<'a, Item: 'a>
+/// An item's encoded bytes borrowed straight from the page, so it lives only as long as the page
+/// (and therefore the lock guarding it) is borrowed. Nothing is copied until `read` is called.
+pub struct ItemRef<'a, I>
 where
-    Item: Sized,
+    I: Item,
 {
-    page: &'a Page,
-    forward: usize,
-    back: usize,
-    phantom: PhantomData<Item>,
+    bytes: &'a [u8],
+    phantom: PhantomData<I>,
 }
 
-impl<'a, Item: 'a> PageItemIteratorTyped<'a, Item>
+impl<'a, I> ItemRef<'a, I>
 where
-    Item: Sized,
+    I: Item,
 {
-    fn new(page: &'a Page) -> Self {
-        PageItemIteratorTyped {
-            page,
-            forward: 0,
-            back: 0,
-            phantom: PhantomData,
-        }
-    }
-}
-
-impl<'a, Item: 'a> Iterator for PageItemIteratorTyped<'a, Item> {
-    type Item = ItemDataTyped<'a, Item>;
-
-    fn next(&mut self) -> Option<Self::Item> {
-        if self.forward < self.page.header.item_cnt() {
-            let item_pointer = unsafe {
-                &*(&self.page.data[self.forward * ITEM_POINTER_SIZE] as *const u8
-                    as *const ItemPointer)
-            };
-
-            self.forward += 1;
-
-            Some(ItemDataTyped {
-                size: item_pointer.size as usize,
-                raw_data_ptr: &self.page.data[item_pointer.offset as usize] as *const u8,
-                phantom_page: PhantomData,
-                phantom_item: PhantomData,
-            })
-        } else {
-            None
-        }
+    pub fn bytes(&self) -> &'a [u8] {
+        self.bytes
     }
-}
 
-impl<'a, Item: 'a> DoubleEndedIterator for PageItemIteratorTyped<'a, Item> {
-    fn next_back(&mut self) -> Option<Self::Item> {
-        if self.back < self.page.header.item_cnt() {
-            unsafe {
-                let item_pointer: &ItemPointer = &*(&self.page.data
-                    [(self.page.header.item_cnt() - 1 - self.back) * ITEM_POINTER_SIZE]
-                    as *const u8
-                    as *const ItemPointer);
-
-                self.back += 1;
-
-                Some(ItemDataTyped {
-                    size: item_pointer.size as usize,
-                    raw_data_ptr: &self.page.data[item_pointer.offset as usize] as *const u8,
-                    phantom_page: PhantomData,
-                    phantom_item: PhantomData,
-                })
-            }
-        } else {
-            None
-        }
+    /// Decodes the item, copying it out of the page.
+    pub fn read(&self) -> I {
+        I::read(self.bytes)
     }
 }
 
+/// Iterates over a page's items from either end. The items left are those in `front..back`, so
+/// the two ends stop once they meet.
 pub struct PageItemIteratorV2<'a, I>
 where
     I: Item,
 {
     page: &'a Page,
-    forward: usize,
+    front: usize,
     back: usize,
     phantom: PhantomData<I>,
 }
@@ -300,8 +524,8 @@ where
     fn new(page: &'a Page) -> Self {
         Self {
             page,
-            forward: 0,
-            back: 0,
+            front: 0,
+            back: page.item_cnt(),
             phantom: PhantomData,
         }
     }
@@ -314,14 +538,19 @@ where
     type Item = I;
 
     fn next(&mut self) -> Option<Self::Item> {
-        if self.forward < self.page.header.item_cnt() {
-            let item = self.page.get_item_v2(self.forward);
-            self.forward += 1;
+        if self.front < self.back {
+            let item = self.page.get_item_v2(self.front);
+            self.front += 1;
             Some(item)
         } else {
             None
         }
     }
+
+    fn size_hint(&self) -> (usize, Option<usize>) {
+        let len = self.back - self.front;
+        (len, Some(len))
+    }
 }
 
 impl<'a, I> DoubleEndedIterator for PageItemIteratorV2<'a, I>
@@ -329,32 +558,23 @@ where
     I: Item,
 {
     fn next_back(&mut self) -> Option<Self::Item> {
-        if self.back < self.page.header.item_cnt() {
-            let item = self.page.get_item_v2(self.back);
-            self.back += 1;
-            Some(item)
+        if self.front < self.back {
+            self.back -= 1;
+            Some(self.page.get_item_v2(self.back))
         } else {
             None
         }
     }
 }
 
-pub struct ItemDataTyped<'a, Item: Sized> {
-    size: usize,
-    raw_data_ptr: *const u8,
-    // We want to bind ItemData's lifetime to at least the Page's lifetime
-    phantom_page: PhantomData<&'a Page>,
-    phantom_item: PhantomData<Item>,
-}
+impl<'a, I> ExactSizeIterator for PageItemIteratorV2<'a, I> where I: Item {}
 
-impl<'a, Item: Sized> ItemDataTyped<'a, Item> {
-    // TODO: Refactor this so that Item is just a smart pointer
-    pub fn to_data_ref(&self) -> &Item {
-        unsafe { &*(self.raw_data_ptr as *const Item) }
-    }
-}
+impl<'a, I> FusedIterator for PageItemIteratorV2<'a, I> where I: Item {}
 
-#[derive(Debug, Copy, Clone)]
+/// The first 12 bytes of every page. The fields hold little-endian values whatever the platform,
+/// so they're only accessed through the methods below.
+#[derive(Copy, Clone)]
+#[repr(C)]
 pub struct PageHeader {
     /**
     "Top" of page's data. Starts at 0, and before it are the `ItemPointer`s.
@@ -368,69 +588,107 @@ pub struct PageHeader {
 }
 
 impl PageHeader {
+    /// An empty page's header, with room for `special_size` bytes of special data. The header
+    /// records the size of the whole special area, rounded up by `special_area_size`, so that
+    /// reading the page back from disk finds the special data where it was written.
     pub fn new(special_size: u32) -> Self {
+        let special_size = special_area_size(special_size as usize) as u32;
         PageHeader {
             item_upper: 0,
             // TODO: do idiomatic u32 conversion
-            item_lower: PAGE_DATA_SIZE as u32 - special_size,
-            special_size,
+            item_lower: (PAGE_DATA_SIZE as u32 - special_size).to_le(),
+            special_size: special_size.to_le(),
         }
     }
 
-    fn item_cnt(&self) -> usize {
-        (self.item_upper as usize) / ITEM_POINTER_SIZE
+    pub fn item_upper(&self) -> usize {
+        u32::from_le(self.item_upper) as usize
     }
 
-    fn item_data_size(&self) -> usize {
-        (PAGE_DATA_SIZE - (self.special_size as usize)) - (self.item_lower as usize)
+    pub fn item_lower(&self) -> usize {
+        u32::from_le(self.item_lower) as usize
     }
 
-    fn can_add_item(&self, size: usize) -> bool {
-        ((self.item_lower - self.item_upper) as usize) >= ITEM_POINTER_SIZE + size
+    pub fn special_size(&self) -> usize {
+        u32::from_le(self.special_size) as usize
     }
 
-    fn add_item<Item: Sized>(&mut self) -> Result<(u32, u32), &'static str> {
-        if !self.can_add_item(std::mem::size_of::<Item>()) {
-            return Err("TODO: Can't add item");
-        }
-        let item_ptr_offset = self.item_upper;
+    fn set_item_upper(&mut self, item_upper: u32) {
+        self.item_upper = item_upper.to_le();
+    }
+
+    fn set_item_lower(&mut self, item_lower: u32) {
+        self.item_lower = item_lower.to_le();
+    }
 
-        self.item_upper += ITEM_POINTER_SIZE as u32;
-        self.item_lower -= std::mem::size_of::<Item>() as u32;
+    fn item_cnt(&self) -> usize {
+        self.item_upper() / ITEM_POINTER_SIZE
+    }
 
-        Ok((item_ptr_offset, self.item_lower))
+    fn item_data_size(&self) -> usize {
+        (PAGE_DATA_SIZE - self.special_size()) - self.item_lower()
     }
 
-    fn add_item_v2<I: Item>(&mut self, item: &I) -> Result<(u32, u32), &'static str> {
-        let item_ptr_offset = self.item_upper;
-        let new_item_upper = self.item_upper + ITEM_POINTER_SIZE as u32;
-        let new_item_lower =
-            align_offset_down(self.item_lower as usize - item.size(), I::align()) as u32;
+    /// Reserves room for one more item pointer and `size` bytes of item data aligned to `align`,
+    /// returning their `(pointer offset, data offset)`.
+    fn allocate(&mut self, size: usize, align: usize) -> Result<(usize, usize)> {
+        let item_ptr_offset = self.item_upper();
+        let new_item_upper = item_ptr_offset + ITEM_POINTER_SIZE;
+        if self.item_lower() < size {
+            return Err(Error::PageFull);
+        }
+        let new_item_lower = align_offset_down(self.item_lower() - size, align);
 
         if new_item_upper > new_item_lower {
-            return Err("TODO: Can't add item");
+            return Err(Error::PageFull);
         }
 
-        self.item_upper = new_item_upper;
-        self.item_lower = new_item_lower;
+        self.set_item_upper(new_item_upper as u32);
+        self.set_item_lower(new_item_lower as u32);
 
-        Ok((item_ptr_offset, self.item_lower))
+        Ok((item_ptr_offset, new_item_lower))
     }
 }
 
-// Size is 4
+impl fmt::Debug for PageHeader {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("PageHeader")
+            .field("item_upper", &self.item_upper())
+            .field("item_lower", &self.item_lower())
+            .field("special_size", &self.special_size())
+            .finish()
+    }
+}
+
+/// Where an item's data lies in the page. Stored as two little-endian u16s in front of the item
+/// data, in item order.
 struct ItemPointer {
     // from start of data
     offset: u16,
     size: u16,
 }
 
+impl ItemPointer {
+    fn read(bytes: &[u8]) -> Self {
+        ItemPointer {
+            offset: u16::from_le_bytes(bytes[0..2].try_into().unwrap()),
+            size: u16::from_le_bytes(bytes[2..4].try_into().unwrap()),
+        }
+    }
+
+    fn write(&self, bytes: &mut [u8]) {
+        bytes[0..2].copy_from_slice(&self.offset.to_le_bytes());
+        bytes[2..4].copy_from_slice(&self.size.to_le_bytes());
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::Item;
     use super::Page;
-    use log::debug;
-    use std::mem::size_of;
+    use super::PAGE_DATA_SIZE;
+    use crate::error::Error;
+    use std::convert::TryInto;
 
     // Size is 12
     #[derive(Debug, PartialEq, Clone)]
@@ -459,14 +717,18 @@ mod tests {
             true
         }
 
-        unsafe fn write(&self, buffer: *mut u8) {
-            *(buffer as *mut Self) = self.clone()
+        fn write(&self, buffer: &mut [u8]) {
+            buffer[0..4].copy_from_slice(&self.key.to_le_bytes());
+            buffer[4..8].copy_from_slice(&self.val.to_le_bytes());
         }
 
-        unsafe fn read(buffer: *const u8, size: usize) -> Self {
-            assert!(size == std::mem::size_of::<Self>());
+        fn read(bytes: &[u8]) -> Self {
+            assert!(bytes.len() == std::mem::size_of::<Self>());
 
-            (*(buffer as *mut Self)).clone()
+            TestItem {
+                key: u32::from_le_bytes(bytes[0..4].try_into().unwrap()),
+                val: u32::from_le_bytes(bytes[4..8].try_into().unwrap()),
+            }
         }
     }
 
@@ -482,7 +744,7 @@ mod tests {
                 val: i as u32,
             });
 
-            assert!(matches!(res, Ok(_)));
+            assert!(res.is_ok());
             assert_eq!(page.item_cnt(), i + 1);
         }
 
@@ -491,7 +753,7 @@ mod tests {
 
         assert!(matches!(
             page.add_item_v2(&TestItem { key: 680, val: 680 }),
-            Err(_)
+            Err(Error::PageFull)
         ));
     }
 
@@ -501,11 +763,7 @@ mod tests {
         let (mut page, _special_data) = setup_page();
 
         for i in 0..680 {
-            page.add_item_v2(&TestItem {
-                key: i as u32,
-                val: i + 1 as u32,
-            })
-            .unwrap();
+            page.add_item_v2(&TestItem { key: i, val: i + 1 }).unwrap();
         }
 
         // Test
@@ -523,6 +781,42 @@ mod tests {
         );
     }
 
+    #[test]
+    fn iter_v2_rev() {
+        let (mut page, _special_data) = setup_page();
+        for i in 0..10 {
+            page.add_item_v2(&TestItem { key: i, val: i + 1 }).unwrap();
+        }
+
+        assert_eq!(
+            page.items_iter_v2::<TestItem>()
+                .rev()
+                .map(|i| i.key)
+                .collect::<Vec<u32>>(),
+            (0..10).rev().collect::<Vec<u32>>(),
+        );
+        assert_eq!(
+            page.items_iter_from_v2::<TestItem>(7)
+                .rev()
+                .map(|i| i.key)
+                .collect::<Vec<u32>>(),
+            vec![9, 8, 7],
+        );
+
+        // Both ends stop where they meet, and stay stopped
+        let mut iter = page.items_iter_from_v2::<TestItem>(2);
+        assert_eq!(iter.len(), 8);
+        assert_eq!(iter.next().map(|i| i.key), Some(2));
+        assert_eq!(iter.next_back().map(|i| i.key), Some(9));
+        assert_eq!(iter.len(), 6);
+        let middle = iter.by_ref().map(|i| i.key).collect::<Vec<u32>>();
+        assert_eq!(middle, vec![3, 4, 5, 6, 7, 8]);
+        assert_eq!(iter.len(), 0);
+        assert!(iter.next().is_none());
+        assert!(iter.next_back().is_none());
+        assert_eq!(page.items_iter_from_v2::<TestItem>(20).len(), 0);
+    }
+
     #[test]
     fn update_and_get_item_v2() {
         let (mut page, _special_data) = setup_page();
@@ -542,6 +836,152 @@ mod tests {
         assert_eq!(page.get_item_v2::<TestItem>(34), item,);
     }
 
+    #[test]
+    fn get_item_ref() {
+        let (mut page, _special_data) = setup_page();
+
+        for i in 0..10 {
+            page.add_item_v2(&TestItem { key: i, val: i * 2 }).unwrap();
+        }
+
+        let item_ref = page.get_item_ref::<TestItem>(3);
+        assert_eq!(item_ref.bytes().len(), std::mem::size_of::<TestItem>());
+        assert_eq!(item_ref.read(), TestItem { key: 3, val: 6 });
+        assert_eq!(
+            page.item_refs_from::<TestItem>(8)
+                .map(|item_ref| item_ref.read().key)
+                .collect::<Vec<_>>(),
+            vec![8, 9]
+        );
+    }
+
+    #[test]
+    fn out_of_bounds_item_pointers() {
+        let (mut page, _special_data) = setup_page();
+        for i in 0..3 {
+            page.add_item_v2(&TestItem { key: i, val: i }).unwrap();
+        }
+        assert_eq!(page.try_get_item_v2::<TestItem>(2).unwrap().key, 2);
+        assert!(matches!(
+            page.try_get_item_v2::<TestItem>(3),
+            Err(Error::Corruption { .. })
+        ));
+
+        // Point item 1 past the item data, into the item pointers and misaligned in turn
+        let data_end = (PAGE_DATA_SIZE - page.header.special_size()) as u16;
+        for offset in [data_end - 4, 4, page.item_pointer(1).0 as u16 + 1] {
+            let mut corrupted = page;
+            corrupted.as_bytes_mut()[16..18].copy_from_slice(&offset.to_le_bytes());
+            assert!(matches!(
+                corrupted.try_get_item_v2::<TestItem>(1),
+                Err(Error::Corruption { .. })
+            ));
+            assert_eq!(corrupted.try_get_item_v2::<TestItem>(0).unwrap().key, 0);
+        }
+    }
+
+    #[test]
+    fn remove_item_v2() {
+        let (mut page, _special_data) = setup_page();
+
+        for i in 0..10 {
+            page.add_item_v2(&TestItem { key: i, val: i }).unwrap();
+        }
+        assert_eq!(page.dead_space(), 0);
+
+        page.remove_item_v2(3);
+        page.remove_item_v2(0);
+
+        assert_eq!(page.item_cnt(), 8);
+        assert_eq!(page.dead_space(), 2 * std::mem::size_of::<TestItem>());
+        assert_eq!(
+            page.items_iter_v2::<TestItem>()
+                .map(|i| i.key)
+                .collect::<Vec<u32>>(),
+            vec![1, 2, 4, 5, 6, 7, 8, 9],
+        );
+    }
+
+    #[test]
+    fn insert_item_v2() {
+        let (mut page, _special_data) = setup_page();
+
+        for i in [1, 3, 5] {
+            page.add_item_v2(&TestItem { key: i, val: i }).unwrap();
+        }
+        page.insert_item_v2(0, &TestItem { key: 0, val: 0 })
+            .unwrap();
+        page.insert_item_v2(2, &TestItem { key: 2, val: 2 })
+            .unwrap();
+        page.insert_item_v2(5, &TestItem { key: 6, val: 6 })
+            .unwrap();
+
+        assert_eq!(
+            page.items_iter_v2::<TestItem>()
+                .map(|i| i.key)
+                .collect::<Vec<u32>>(),
+            vec![0, 1, 2, 3, 5, 6],
+        );
+    }
+
+    #[test]
+    fn move_items_to_and_compact() {
+        let (mut page, special_data) = setup_page();
+        let (mut other, _special_data) = setup_page();
+
+        for i in 0..10 {
+            page.add_item_v2(&TestItem { key: i, val: i }).unwrap();
+        }
+        page.move_items_to::<TestItem>(6, &mut other).unwrap();
+        page.remove_item_v2(0);
+
+        assert_eq!(
+            other
+                .items_iter_v2::<TestItem>()
+                .map(|i| i.key)
+                .collect::<Vec<u32>>(),
+            vec![6, 7, 8, 9],
+        );
+        assert_eq!(other.dead_space(), 0);
+        assert_eq!(page.item_cnt(), 5);
+        assert_eq!(page.dead_space(), 5 * std::mem::size_of::<TestItem>());
+
+        page.compact::<TestItem>().unwrap();
+        assert_eq!(page.dead_space(), 0);
+        assert_eq!(*page.special_data::<TestSpecialData>(), special_data);
+        assert_eq!(
+            page.items_iter_v2::<TestItem>()
+                .map(|i| i.key)
+                .collect::<Vec<u32>>(),
+            vec![1, 2, 3, 4, 5],
+        );
+    }
+
+    #[test]
+    fn special_data_alignment() {
+        #[repr(C, align(8))]
+        struct Aligned {
+            val: u64,
+        }
+
+        // TestSpecialData's 12 bytes are rounded up, so the special area still starts 8-aligned
+        let (page, _special_data) = setup_page();
+        assert_eq!(page.header.special_size(), 16);
+        let special_data = page.special_data::<TestSpecialData>();
+        assert_eq!(special_data as *const TestSpecialData as usize % 8, 0);
+
+        let mut page = Page::new(std::mem::size_of::<Aligned>() as u32);
+        page.special_data_mut::<Aligned>().val = u64::MAX;
+        let special_data = page.special_data::<Aligned>();
+        assert_eq!(special_data as *const Aligned as usize % 8, 0);
+        assert_eq!(special_data.val, u64::MAX);
+
+        // The special size survives a round trip through the page's bytes
+        let page = Page::from_bytes(page.as_bytes());
+        assert_eq!(page.special_data::<Aligned>().val, u64::MAX);
+        assert!(page.check_layout().is_ok());
+    }
+
     fn setup_page() -> (Page, TestSpecialData) {
         let mut page = Page::new(std::mem::size_of::<TestSpecialData>() as u32);
         let special_data = TestSpecialData {
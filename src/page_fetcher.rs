@@ -1,7 +1,15 @@
+use crate::error::Error;
+use crate::error::Result;
+use crate::metrics::HotPage;
+use crate::metrics::MemoryUsage;
+use crate::metrics::Metrics;
+use crate::metrics::PageAccess;
 use crate::page::Page;
 use crate::page::PageHeader;
 use log::debug;
 use std::cell::Cell;
+use std::cell::RefCell;
+use std::collections::HashSet;
 
 // TODO: Refactor to remove the <T> out.
 #[derive(Debug)]
@@ -9,6 +17,10 @@ pub struct PagePtr {
     val: *mut Page,
 }
 
+// A `PagePtr` owns the page it points to like a `Box<Page>` would (pages are only ever freed by
+// the fetcher that allocated them), so it's safe to hand over to another thread.
+unsafe impl Send for PagePtr {}
+
 impl PagePtr {
     fn new(val: *mut Page) -> Self {
         PagePtr { val }
@@ -20,6 +32,7 @@ use std::ops::DerefMut;
 use std::sync::RwLock;
 use std::sync::RwLockReadGuard;
 use std::sync::RwLockWriteGuard;
+use std::sync::TryLockError;
 
 impl Deref for PagePtr {
     type Target = Page;
@@ -37,76 +50,313 @@ impl DerefMut for PagePtr {
 
 pub trait PageFetcher {
     // TODO: Replace PagePtr with a read-only smart ptr
-    fn fetch_page_read(&self, page_no: u32) -> Option<RwLockReadGuard<PagePtr>>;
-    fn fetch_page_write(&self, page_no: u32) -> Option<RwLockWriteGuard<PagePtr>>;
+    fn fetch_page_read(&self, page_no: u32) -> Result<RwLockReadGuard<'_, PagePtr>>;
+    fn fetch_page_write(&self, page_no: u32) -> Result<RwLockWriteGuard<'_, PagePtr>>;
+
+    fn new_page<T: Sized>(&self, special_data: T) -> Result<(u32, RwLockWriteGuard<'_, PagePtr>)>;
+
+    /// Returns `page_no` to the free list, for `new_page` to hand out again. The page is reset to
+    /// an empty page without special data, which is what marks it as free on disk. The caller
+    /// must make sure nothing links to it anymore. Free pages left at the end are dropped, so
+    /// `page_cnt` shrinks back.
+    fn free_page(&self, page_no: u32) -> Result<()>;
+
+    /// Fails with the error `new_page` would fail with unless it can hand out that many more
+    /// pages, so that a change needing several new pages, like a split propagating up the tree,
+    /// doesn't get stuck half way. Fetchers that can't tell ahead of time succeed.
+    fn check_room(&self, _page_cnt: usize) -> Result<()> {
+        Ok(())
+    }
+
+    /// The number of pages allocated so far, free ones included. Pages are numbered from 0.
+    fn page_cnt(&self) -> usize;
+
+    /// The counters for this fetcher and the tree on top of it.
+    fn metrics(&self) -> &Metrics;
+}
+
+/// Lets several trees share one fetcher, e.g. the trees of a catalog stored in the same file.
+impl<P: PageFetcher> PageFetcher for &P {
+    fn fetch_page_read(&self, page_no: u32) -> Result<RwLockReadGuard<'_, PagePtr>> {
+        (**self).fetch_page_read(page_no)
+    }
+
+    fn fetch_page_write(&self, page_no: u32) -> Result<RwLockWriteGuard<'_, PagePtr>> {
+        (**self).fetch_page_write(page_no)
+    }
+
+    fn new_page<T: Sized>(&self, special_data: T) -> Result<(u32, RwLockWriteGuard<'_, PagePtr>)> {
+        (**self).new_page(special_data)
+    }
+
+    fn free_page(&self, page_no: u32) -> Result<()> {
+        (**self).free_page(page_no)
+    }
+
+    fn check_room(&self, page_cnt: usize) -> Result<()> {
+        (**self).check_room(page_cnt)
+    }
+
+    fn page_cnt(&self) -> usize {
+        (**self).page_cnt()
+    }
 
-    fn new_page<T: Sized>(&self, special_data: T) -> (u32, RwLockWriteGuard<PagePtr>);
+    fn metrics(&self) -> &Metrics {
+        (**self).metrics()
+    }
 }
 
 pub struct InMemoryPageFetcher {
-    pub pages: Box<[Page; 16]>,
     pub used_cnt: Cell<usize>,
+    /// One slot per page we're allowed to hand out. Pages are allocated lazily, so the
+    /// `PagePtr` of a slot past `used_cnt` is null.
     pub rw_locks: Vec<RwLock<PagePtr>>,
+    metrics: Metrics,
+    /// One entry per slot in `rw_locks`.
+    page_access: Vec<PageAccess>,
+    /// Bytes `memory_usage` may grow to before allocating pages fails.
+    memory_budget: Option<usize>,
+    /// Pages returned with `free_page`, reused last in first out.
+    free_pages: RefCell<Vec<u32>>,
 }
 
 impl InMemoryPageFetcher {
     pub fn new() -> Self {
-        let mut pages = Box::new([Page::new(0); 16]);
-        let mut rw_locks = Vec::with_capacity(10);
-        for ele in pages.iter_mut() {
-            rw_locks.push(RwLock::new(PagePtr::new(ele as *mut Page)));
+        Self::with_capacity(16)
+    }
+
+    pub fn with_capacity(capacity: usize) -> Self {
+        let mut rw_locks = Vec::with_capacity(capacity);
+        for _ in 0..capacity {
+            rw_locks.push(RwLock::new(PagePtr::new(std::ptr::null_mut())));
         }
         InMemoryPageFetcher {
-            pages,
             used_cnt: Cell::new(0),
             rw_locks,
+            metrics: Metrics::default(),
+            page_access: (0..capacity).map(|_| PageAccess::default()).collect(),
+            memory_budget: None,
+            free_pages: RefCell::new(Vec::new()),
+        }
+    }
+
+    pub fn page_cnt(&self) -> usize {
+        self.used_cnt.get()
+    }
+
+    /// The number of pages on the free list.
+    pub fn free_page_cnt(&self) -> usize {
+        self.free_pages.borrow().len()
+    }
+
+    /// Puts every page without special data, i.e. every page freed with `free_page`, on the free
+    /// list. Called once the pages of a file have been loaded with `push_page`.
+    pub fn rebuild_free_list(&self) -> Result<()> {
+        let mut free_pages = Vec::new();
+        for page_no in 0..self.page_cnt() {
+            if self.rw_locks[page_no].read()?.header.special_size() == 0 {
+                free_pages.push(page_no as u32);
+            }
+        }
+        // Hand out the lowest page numbers first
+        free_pages.reverse();
+        *self.free_pages.borrow_mut() = free_pages;
+        self.truncate_free_tail()
+    }
+
+    /// Drops the free pages at the end, taking them off the free list, so that the page count
+    /// shrinks back and e.g. a file holding the pages can too.
+    fn truncate_free_tail(&self) -> Result<()> {
+        let old_cnt = self.used_cnt.get();
+        let mut free_pages = self.free_pages.borrow_mut();
+        let free = free_pages.iter().copied().collect::<HashSet<_>>();
+        let mut page_cnt = old_cnt;
+        while page_cnt > 0 && free.contains(&(page_cnt as u32 - 1)) {
+            page_cnt -= 1;
+            let mut rw_lock = self.rw_locks[page_cnt].write()?;
+            drop(unsafe { Box::from_raw(rw_lock.val) });
+            rw_lock.val = std::ptr::null_mut();
+        }
+        if page_cnt < old_cnt {
+            free_pages.retain(|page_no| (*page_no as usize) < page_cnt);
+            self.used_cnt.set(page_cnt);
+            debug!("Truncated free pages {} to {}", page_cnt, old_cnt - 1);
+        }
+        Ok(())
+    }
+
+    /// The `n` most fetched pages, by reads and writes combined, busiest first.
+    pub fn hot_pages(&self, n: usize) -> Vec<HotPage> {
+        let mut pages = self.page_access[..self.page_cnt()]
+            .iter()
+            .enumerate()
+            .map(|(page_no, access)| HotPage {
+                page_no: page_no as u32,
+                reads: access.reads.get(),
+                writes: access.writes.get(),
+            })
+            .collect::<Vec<_>>();
+        pages.sort_by_key(|page| (std::cmp::Reverse(page.reads + page.writes), page.page_no));
+        pages.truncate(n);
+        pages
+    }
+
+    /// Halves every page's access counts, so that `hot_pages` favors recent activity. Call it
+    /// periodically to get an exponentially decayed view.
+    pub fn decay_page_access(&self) {
+        self.page_access.iter().for_each(PageAccess::decay);
+    }
+
+    /// Bytes held by allocated pages and the per-page bookkeeping.
+    pub fn memory_usage(&self) -> MemoryUsage {
+        MemoryUsage {
+            page_frames: self.page_cnt() * std::mem::size_of::<Page>(),
+            page_table: self.rw_locks.len()
+                * (std::mem::size_of::<RwLock<PagePtr>>() + std::mem::size_of::<PageAccess>()),
+        }
+    }
+
+    /// Makes allocating a page fail with `Error::MemoryBudgetExceeded` once it would take
+    /// `memory_usage` past `budget` bytes. Pages are never evicted, so a full budget stays full.
+    pub fn set_memory_budget(&mut self, budget: Option<usize>) {
+        self.memory_budget = budget;
+    }
+
+    /// Appends a copy of `page` as the next page, e.g. when loading pages from disk.
+    pub fn push_page(&self, page: &Page) -> Result<u32> {
+        let (page_no, mut lock) = self.allocate_page()?;
+        **lock = *page;
+        Ok(page_no)
+    }
+
+    fn allocate_page(&self) -> Result<(u32, RwLockWriteGuard<'_, PagePtr>)> {
+        self.check_allocatable(1)?;
+        let page_no = self.used_cnt.get();
+
+        let mut rw_lock = self.rw_locks[page_no].write()?;
+        if rw_lock.val.is_null() {
+            rw_lock.val = Box::into_raw(Box::new(Page::new(0)));
+        }
+        self.used_cnt.set(page_no + 1);
+
+        Ok((page_no as u32, rw_lock))
+    }
+
+    /// Whether `page_cnt` pages past the allocated ones fit in the pool and the memory budget.
+    fn check_allocatable(&self, page_cnt: usize) -> Result<()> {
+        if self.used_cnt.get() + page_cnt > self.rw_locks.len() {
+            // TODO: Evict or grow the pool instead of giving up.
+            return Err(Error::OutOfPages);
+        }
+        if let Some(budget) = self.memory_budget {
+            if self.memory_usage().total() + page_cnt * std::mem::size_of::<Page>() > budget {
+                return Err(Error::MemoryBudgetExceeded(budget));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Drop for InMemoryPageFetcher {
+    fn drop(&mut self) {
+        for rw_lock in self.rw_locks.iter_mut() {
+            let page_ptr = match rw_lock.get_mut() {
+                Ok(page_ptr) => page_ptr,
+                Err(poisoned) => poisoned.into_inner(),
+            };
+            if !page_ptr.val.is_null() {
+                drop(unsafe { Box::from_raw(page_ptr.val) });
+                page_ptr.val = std::ptr::null_mut();
+            }
         }
     }
 }
 
-impl<'a> PageFetcher for InMemoryPageFetcher {
-    fn fetch_page_read(&self, page_no: u32) -> Option<RwLockReadGuard<PagePtr>> {
+impl Default for InMemoryPageFetcher {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl PageFetcher for InMemoryPageFetcher {
+    fn fetch_page_read(&self, page_no: u32) -> Result<RwLockReadGuard<'_, PagePtr>> {
         if self.used_cnt.get() <= page_no as usize {
-            return None;
+            return Err(Error::PageNotFound(page_no));
         }
 
         debug!("Acquiring read lock for {}", page_no);
-        self.rw_locks
-            .get(page_no as usize)
-            .map(|rw_lock| (*rw_lock).read().unwrap())
+        self.metrics.pool_hits.inc();
+        self.page_access[page_no as usize].reads.inc();
+        let rw_lock = &self.rw_locks[page_no as usize];
+        match rw_lock.try_read() {
+            Ok(guard) => Ok(guard),
+            Err(TryLockError::WouldBlock) => {
+                self.metrics.lock_waits.inc();
+                Ok(rw_lock.read()?)
+            }
+            Err(TryLockError::Poisoned(_)) => Err(Error::Lock),
+        }
     }
 
-    fn fetch_page_write(&self, page_no: u32) -> Option<RwLockWriteGuard<PagePtr>> {
+    fn fetch_page_write(&self, page_no: u32) -> Result<RwLockWriteGuard<'_, PagePtr>> {
         if self.used_cnt.get() <= page_no as usize {
-            return None;
+            return Err(Error::PageNotFound(page_no));
         }
         debug!("Acquiring write lock for {}", page_no);
-        return self
-            .rw_locks
-            .get(page_no as usize)
-            .map(|rw_lock| (*rw_lock).write().unwrap());
-    }
-
-    fn new_page<T: Sized>(&self, special_data: T) -> (u32, RwLockWriteGuard<PagePtr>) {
-        if self.used_cnt.get() == self.pages.len() {
-            panic!("TODO: Need to do more than this!")
+        self.metrics.pool_hits.inc();
+        self.page_access[page_no as usize].writes.inc();
+        let rw_lock = &self.rw_locks[page_no as usize];
+        match rw_lock.try_write() {
+            Ok(guard) => Ok(guard),
+            Err(TryLockError::WouldBlock) => {
+                self.metrics.lock_waits.inc();
+                Ok(rw_lock.write()?)
+            }
+            Err(TryLockError::Poisoned(_)) => Err(Error::Lock),
         }
-        self.used_cnt.set(self.used_cnt.get() + 1);
+    }
 
-        let mut rw_lock = self
-            .rw_locks
-            .get(self.used_cnt.get() - 1)
-            .map(|rw_lock| rw_lock.write().unwrap())
-            .unwrap();
+    fn new_page<T: Sized>(&self, special_data: T) -> Result<(u32, RwLockWriteGuard<'_, PagePtr>)> {
+        let free_page_no = self.free_pages.borrow_mut().pop();
+        let (page_no, mut rw_lock) = match free_page_no {
+            Some(page_no) => (page_no, self.rw_locks[page_no as usize].write()?),
+            None => self.allocate_page()?,
+        };
 
         rw_lock.header = PageHeader::new(std::mem::size_of::<T>() as u32);
         // Zero out the data just to be safe.
         rw_lock.data.iter_mut().for_each(|m| *m = 0);
         *rw_lock.special_data_mut::<T>() = special_data;
-        let page_no = (self.used_cnt.get() - 1) as u32;
-
         debug!("Initializing new page {} with write lock", page_no);
+        self.page_access[page_no as usize].writes.inc();
+
+        Ok((page_no, rw_lock))
+    }
+
+    fn free_page(&self, page_no: u32) -> Result<()> {
+        let mut page = self.fetch_page_write(page_no)?;
+        if page.header.special_size() == 0 {
+            return Err(Error::page_corruption(page_no, "page is already free"));
+        }
+        **page = Page::new(0);
+        drop(page);
+        debug!("Freed page {}", page_no);
+        self.free_pages.borrow_mut().push(page_no);
+        match page_no as usize + 1 == self.used_cnt.get() {
+            true => self.truncate_free_tail(),
+            false => Ok(()),
+        }
+    }
+
+    fn check_room(&self, page_cnt: usize) -> Result<()> {
+        self.check_allocatable(page_cnt.saturating_sub(self.free_page_cnt()))
+    }
+
+    fn page_cnt(&self) -> usize {
+        self.used_cnt.get()
+    }
 
-        return (page_no, rw_lock);
+    fn metrics(&self) -> &Metrics {
+        &self.metrics
     }
 }
@@ -0,0 +1,222 @@
+//! Writes rows as Parquet files, for loading johndb data straight into DuckDB, pandas and the
+//! like, see `Table::export_parquet`. Enabled by the `parquet` feature.
+//!
+//! Every column of the row schema becomes a Parquet column of the same name, optional if the
+//! column is nullable:
+//!
+//! | `ColumnType` | Parquet                     |
+//! |--------------|-----------------------------|
+//! | `Bool`       | `BOOLEAN`                   |
+//! | `I32`        | `INT32`                     |
+//! | `I64`        | `INT64`                     |
+//! | `U32`        | `INT32`, unsigned `INTEGER` |
+//! | `U64`        | `INT64`, unsigned `INTEGER` |
+//! | `F64`        | `DOUBLE`                    |
+//! | `Bytes`      | `BYTE_ARRAY`                |
+//! | `Text`       | `BYTE_ARRAY`, `STRING`      |
+//!
+//! Pages are written uncompressed, in row groups of at most `ROW_GROUP_SIZE` rows.
+
+use crate::error::Error;
+use crate::error::Result;
+use crate::row::Column;
+use crate::row::ColumnType;
+use crate::row::Field;
+use crate::row::Schema;
+use parquet::basic::LogicalType;
+use parquet::basic::Repetition;
+use parquet::basic::Type as PhysicalType;
+use parquet::data_type::BoolType;
+use parquet::data_type::ByteArray;
+use parquet::data_type::ByteArrayType;
+use parquet::data_type::DoubleType;
+use parquet::data_type::Int32Type;
+use parquet::data_type::Int64Type;
+use parquet::errors::ParquetError;
+use parquet::file::properties::WriterProperties;
+use parquet::file::writer::SerializedFileWriter;
+use parquet::schema::types::Type;
+use std::io;
+use std::io::Write;
+use std::sync::Arc;
+
+const ROW_GROUP_SIZE: usize = 64 << 10;
+
+/// Writes `rows`, encoded with `schema`, to `writer` as a Parquet file. Returns the number of
+/// rows written.
+pub(crate) fn write_parquet<W, I>(schema: &Schema, rows: I, writer: W) -> Result<u64>
+where
+    W: Write + Send,
+    I: IntoIterator<Item = Result<Vec<u8>>>,
+{
+    let fields = schema
+        .columns()
+        .iter()
+        .map(|column| parquet_type(column).map(Arc::new))
+        .collect::<Result<Vec<_>>>()?;
+    let message = Type::group_type_builder("schema")
+        .with_fields(fields)
+        .build()
+        .map_err(parquet_error)?;
+    let properties = WriterProperties::builder().build();
+    let mut writer = SerializedFileWriter::new(writer, Arc::new(message), Arc::new(properties))
+        .map_err(parquet_error)?;
+
+    let mut buffers = schema
+        .columns()
+        .iter()
+        .map(ColumnBuffer::new)
+        .collect::<Vec<_>>();
+    let mut buffered = 0;
+    let mut cnt = 0;
+    for row in rows {
+        let fields = schema.decode(&row?)?;
+        for (buffer, field) in buffers.iter_mut().zip(fields) {
+            buffer.push(field)?;
+        }
+        buffered += 1;
+        cnt += 1;
+        if buffered == ROW_GROUP_SIZE {
+            write_row_group(&mut writer, &mut buffers)?;
+            buffered = 0;
+        }
+    }
+    if buffered > 0 {
+        write_row_group(&mut writer, &mut buffers)?;
+    }
+
+    writer.close().map_err(parquet_error)?;
+    Ok(cnt)
+}
+
+fn parquet_type(column: &Column) -> Result<Type> {
+    let unsigned = |bit_width| LogicalType::Integer {
+        bit_width,
+        is_signed: false,
+    };
+    let (physical_type, logical_type) = match column.column_type {
+        ColumnType::Bool => (PhysicalType::BOOLEAN, None),
+        ColumnType::I32 => (PhysicalType::INT32, None),
+        ColumnType::I64 => (PhysicalType::INT64, None),
+        ColumnType::U32 => (PhysicalType::INT32, Some(unsigned(32))),
+        ColumnType::U64 => (PhysicalType::INT64, Some(unsigned(64))),
+        ColumnType::F64 => (PhysicalType::DOUBLE, None),
+        ColumnType::Bytes => (PhysicalType::BYTE_ARRAY, None),
+        ColumnType::Text => (PhysicalType::BYTE_ARRAY, Some(LogicalType::String)),
+    };
+    let repetition = match column.nullable {
+        true => Repetition::OPTIONAL,
+        false => Repetition::REQUIRED,
+    };
+    Type::primitive_type_builder(&column.name, physical_type)
+        .with_repetition(repetition)
+        .with_logical_type(logical_type)
+        .build()
+        .map_err(parquet_error)
+}
+
+/// The values of one column of the rows in the current row group. Nulls are only recorded in
+/// the definition levels, as Parquet expects.
+struct ColumnBuffer {
+    values: Values,
+    def_levels: Vec<i16>,
+}
+
+enum Values {
+    Bool(Vec<bool>),
+    I32(Vec<i32>),
+    I64(Vec<i64>),
+    F64(Vec<f64>),
+    Bytes(Vec<ByteArray>),
+}
+
+impl ColumnBuffer {
+    fn new(column: &Column) -> Self {
+        let values = match column.column_type {
+            ColumnType::Bool => Values::Bool(Vec::new()),
+            ColumnType::I32 | ColumnType::U32 => Values::I32(Vec::new()),
+            ColumnType::I64 | ColumnType::U64 => Values::I64(Vec::new()),
+            ColumnType::F64 => Values::F64(Vec::new()),
+            ColumnType::Bytes | ColumnType::Text => Values::Bytes(Vec::new()),
+        };
+        ColumnBuffer {
+            values,
+            def_levels: Vec::new(),
+        }
+    }
+
+    fn push(&mut self, field: Field) -> Result<()> {
+        // Unsigned integers are stored as the signed integers with the same bits, as Parquet
+        // does for its unsigned logical types
+        match (&mut self.values, field) {
+            (_, Field::Null) => {
+                self.def_levels.push(0);
+                return Ok(());
+            }
+            (Values::Bool(values), Field::Bool(value)) => values.push(value),
+            (Values::I32(values), Field::I32(value)) => values.push(value),
+            (Values::I32(values), Field::U32(value)) => values.push(value as i32),
+            (Values::I64(values), Field::I64(value)) => values.push(value),
+            (Values::I64(values), Field::U64(value)) => values.push(value as i64),
+            (Values::F64(values), Field::F64(value)) => values.push(value),
+            (Values::Bytes(values), Field::Bytes(value)) => values.push(value.into()),
+            (Values::Bytes(values), Field::Text(value)) => values.push(value.as_str().into()),
+            (_, field) => {
+                return Err(Error::corruption(format!(
+                    "row field {:?} doesn't match its column",
+                    field
+                )))
+            }
+        }
+        self.def_levels.push(1);
+        Ok(())
+    }
+}
+
+fn write_row_group<W: Write + Send>(
+    writer: &mut SerializedFileWriter<W>,
+    buffers: &mut [ColumnBuffer],
+) -> Result<()> {
+    let mut row_group = writer.next_row_group().map_err(parquet_error)?;
+    for buffer in buffers.iter_mut() {
+        let mut column = row_group
+            .next_column()
+            .map_err(parquet_error)?
+            .ok_or_else(|| Error::corruption("parquet schema is missing a column".to_string()))?;
+        let def_levels = Some(buffer.def_levels.as_slice());
+        let written = match &buffer.values {
+            Values::Bool(values) => column
+                .typed::<BoolType>()
+                .write_batch(values, def_levels, None),
+            Values::I32(values) => column
+                .typed::<Int32Type>()
+                .write_batch(values, def_levels, None),
+            Values::I64(values) => column
+                .typed::<Int64Type>()
+                .write_batch(values, def_levels, None),
+            Values::F64(values) => column
+                .typed::<DoubleType>()
+                .write_batch(values, def_levels, None),
+            Values::Bytes(values) => column
+                .typed::<ByteArrayType>()
+                .write_batch(values, def_levels, None),
+        };
+        written.map_err(parquet_error)?;
+        column.close().map_err(parquet_error)?;
+
+        buffer.def_levels.clear();
+        match &mut buffer.values {
+            Values::Bool(values) => values.clear(),
+            Values::I32(values) => values.clear(),
+            Values::I64(values) => values.clear(),
+            Values::F64(values) => values.clear(),
+            Values::Bytes(values) => values.clear(),
+        }
+    }
+    row_group.close().map_err(parquet_error)?;
+    Ok(())
+}
+
+fn parquet_error(err: ParquetError) -> Error {
+    Error::Io(io::Error::other(err))
+}
@@ -0,0 +1,301 @@
+//! Cost-based choice of how to find the rows matching a predicate. Every index that could serve
+//! the predicate is turned into a `Candidate` with the fraction of rows it's expected to return,
+//! estimated from the index's `IndexStats`, and `choose` picks the cheapest one, falling back to
+//! a full scan when none beats reading every page.
+//!
+//! Costs are counted in page reads, with reads that jump around (heap fetches through an index)
+//! weighing more than reads in order.
+
+use std::fmt;
+use std::ops::Bound;
+use std::ops::RangeBounds;
+
+/// Cost of reading a page that follows the previous one, e.g. the next leaf of a scan.
+pub const SEQ_PAGE_COST: f64 = 1.0;
+/// Cost of reading a page anywhere else, e.g. fetching a row from the heap through an index.
+pub const RANDOM_PAGE_COST: f64 = 4.0;
+
+/// A predicate on an index's keys.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum KeyPredicate<K> {
+    Eq(K),
+    Range(Bound<K>, Bound<K>),
+}
+
+/// An equi-depth histogram: each bucket holds about the same number of keys, and `bounds` holds
+/// the largest key of each bucket in ascending order.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Histogram<K> {
+    pub bounds: Vec<K>,
+}
+
+impl<K: Ord> Histogram<K> {
+    /// Builds a histogram of at most `bucket_cnt` buckets from `keys`, which must be sorted.
+    pub fn from_sorted(keys: Vec<K>, bucket_cnt: usize) -> Self {
+        let bucket_cnt = bucket_cnt.max(1).min(keys.len());
+        if bucket_cnt == 0 {
+            return Histogram { bounds: Vec::new() };
+        }
+        let key_cnt = keys.len();
+        let ends = (1..=bucket_cnt)
+            .map(|bucket| bucket * key_cnt / bucket_cnt - 1)
+            .collect::<Vec<_>>();
+        let bounds = keys
+            .into_iter()
+            .enumerate()
+            .filter(|(idx, _)| ends.binary_search(idx).is_ok())
+            .map(|(_, key)| key)
+            .collect();
+        Histogram { bounds }
+    }
+
+    /// The estimated fraction of keys within `range`: whole buckets count fully, and the buckets
+    /// the range starts and ends in count half.
+    pub fn range_selectivity<R: RangeBounds<K>>(&self, range: &R) -> f64 {
+        if self.bounds.is_empty() {
+            return 0.0;
+        }
+        // Buckets are numbered by position, bucket `idx` holding keys up to `bounds[idx]`
+        let first = match range.start_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => {
+                self.bounds.partition_point(|bound| bound < key)
+            }
+            Bound::Unbounded => 0,
+        };
+        let last = match range.end_bound() {
+            Bound::Included(key) | Bound::Excluded(key) => {
+                self.bounds.partition_point(|bound| bound < key)
+            }
+            Bound::Unbounded => self.bounds.len(),
+        };
+        if first >= self.bounds.len() || last < first {
+            return 0.0;
+        }
+
+        let last = last.min(self.bounds.len() - 1);
+        let mut buckets = (last - first + 1) as f64;
+        if !matches!(range.start_bound(), Bound::Unbounded) {
+            buckets -= 0.5;
+        }
+        if !matches!(range.end_bound(), Bound::Unbounded) {
+            buckets -= 0.5;
+        }
+        (buckets.max(0.5) / self.bounds.len() as f64).min(1.0)
+    }
+}
+
+/// What the planner knows about an index.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexStats<K> {
+    pub entry_cnt: u64,
+    pub distinct_cnt: u64,
+    /// Pages read to get from the root to a leaf.
+    pub height: u64,
+    pub leaf_page_cnt: u64,
+    /// How closely the order of the rows in the heap follows the index, from 0 (unrelated) to 1
+    /// (the same order).
+    pub correlation: f64,
+    pub histogram: Histogram<K>,
+}
+
+impl<K: Ord> IndexStats<K> {
+    /// The estimated fraction of the index's entries matching `predicate`.
+    pub fn selectivity(&self, predicate: &KeyPredicate<K>) -> f64 {
+        match predicate {
+            KeyPredicate::Eq(_) if self.distinct_cnt == 0 => 0.0,
+            KeyPredicate::Eq(_) => 1.0 / self.distinct_cnt as f64,
+            KeyPredicate::Range(start, end) => self
+                .histogram
+                .range_selectivity(&(start.as_ref(), end.as_ref())),
+        }
+    }
+
+    /// A candidate for looking up `predicate` through this index.
+    pub fn candidate(&self, path: AccessPath, predicate: &KeyPredicate<K>) -> Candidate {
+        Candidate {
+            path,
+            selectivity: self.selectivity(predicate),
+            height: self.height,
+            leaf_page_cnt: self.leaf_page_cnt,
+            correlation: self.correlation,
+        }
+    }
+}
+
+/// What the planner knows about a table.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TableStats {
+    pub row_cnt: u64,
+    pub heap_page_cnt: u64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AccessPath {
+    /// Look up the rows through the primary index.
+    PrimaryIndex,
+    /// Look up the rows through the named secondary index, then fetch them from the heap.
+    SecondaryIndex(String),
+    /// Read every row and check the predicate against each.
+    FullScan,
+}
+
+/// An index that can serve the predicate, see `IndexStats::candidate`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub path: AccessPath,
+    /// The fraction of rows the index is expected to return.
+    pub selectivity: f64,
+    pub height: u64,
+    pub leaf_page_cnt: u64,
+    pub correlation: f64,
+}
+
+/// The chosen access path along with the estimates it was chosen by.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Plan {
+    pub path: AccessPath,
+    pub estimated_rows: f64,
+    pub cost: f64,
+}
+
+impl fmt::Display for Plan {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.path {
+            AccessPath::PrimaryIndex => write!(f, "primary index")?,
+            AccessPath::SecondaryIndex(name) => write!(f, "index {} + heap fetch", name)?,
+            AccessPath::FullScan => write!(f, "full scan")?,
+        }
+        write!(
+            f,
+            " (rows={:.0} cost={:.1})",
+            self.estimated_rows, self.cost
+        )
+    }
+}
+
+/// Picks the cheapest way to read the rows of `table` matched by `candidates`, or a full scan
+/// if that's cheaper. `full_scan_rows` is the number of rows a full scan would return, i.e. all
+/// of them unless the predicate can be estimated otherwise.
+pub fn choose(table: &TableStats, candidates: &[Candidate], full_scan_rows: f64) -> Plan {
+    let full_scan = Plan {
+        path: AccessPath::FullScan,
+        estimated_rows: full_scan_rows,
+        cost: table.heap_page_cnt as f64 * SEQ_PAGE_COST,
+    };
+
+    candidates
+        .iter()
+        .map(|candidate| {
+            let rows = candidate.selectivity * table.row_cnt as f64;
+            let leaves = (candidate.selectivity * candidate.leaf_page_cnt as f64).ceil();
+            // Rows in index order are fetched by reading their share of the heap in order, and
+            // rows in no particular order with a page read each, though never more often than
+            // there are heap pages
+            let heap_pages = table.heap_page_cnt as f64;
+            let in_order = (candidate.selectivity * heap_pages).ceil() * SEQ_PAGE_COST;
+            let scattered = rows.ceil().min(heap_pages) * RANDOM_PAGE_COST;
+            let correlation = candidate.correlation.clamp(0.0, 1.0);
+            Plan {
+                path: candidate.path.clone(),
+                estimated_rows: rows,
+                cost: candidate.height as f64 * RANDOM_PAGE_COST
+                    + leaves * SEQ_PAGE_COST
+                    + correlation * in_order
+                    + (1.0 - correlation) * scattered,
+            }
+        })
+        .fold(full_scan, |best, plan| match plan.cost < best.cost {
+            true => plan,
+            false => best,
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::choose;
+    use super::AccessPath;
+    use super::Histogram;
+    use super::IndexStats;
+    use super::KeyPredicate;
+    use super::TableStats;
+    use std::ops::Bound;
+
+    #[test]
+    fn histogram_selectivity() {
+        let histogram = Histogram::from_sorted((0..1000u32).collect(), 10);
+        assert_eq!(histogram.bounds.len(), 10);
+        assert_eq!(histogram.bounds[0], 99);
+        assert_eq!(histogram.bounds[9], 999);
+
+        assert_eq!(histogram.range_selectivity(&(..)), 1.0);
+        assert_eq!(histogram.range_selectivity(&(2000..)), 0.0);
+        let half = histogram.range_selectivity(&(500..));
+        assert!((0.45..=0.55).contains(&half), "{}", half);
+        let narrow = histogram.range_selectivity(&(420..430));
+        assert!(narrow <= 0.05, "{}", narrow);
+
+        // Skewed keys: most of them are small
+        let mut skewed = (0..900u32).map(|key| key / 100).collect::<Vec<_>>();
+        skewed.extend(1000..1100);
+        let histogram = Histogram::from_sorted(skewed, 10);
+        assert!(histogram.range_selectivity(&(..10)) > 0.8);
+        assert!(histogram.range_selectivity(&(1000..)) < 0.2);
+    }
+
+    #[test]
+    fn picks_cheapest_path() {
+        let table = TableStats {
+            row_cnt: 100_000,
+            heap_page_cnt: 10_000,
+        };
+        let primary = IndexStats {
+            entry_cnt: 100_000,
+            distinct_cnt: 100_000,
+            height: 3,
+            leaf_page_cnt: 400,
+            correlation: 0.0,
+            histogram: Histogram::from_sorted((0..100_000u64).collect(), 100),
+        };
+        let status = IndexStats {
+            entry_cnt: 100_000,
+            distinct_cnt: 4,
+            height: 3,
+            leaf_page_cnt: 300,
+            correlation: 0.0,
+            histogram: Histogram::from_sorted(
+                (0..100_000u64).map(|row| row % 4).collect::<Vec<_>>(),
+                100,
+            ),
+        };
+
+        let lookup = KeyPredicate::Eq(42);
+        let plan = choose(
+            &table,
+            &[primary.candidate(AccessPath::PrimaryIndex, &lookup)],
+            1.0,
+        );
+        assert_eq!(plan.path, AccessPath::PrimaryIndex);
+        assert_eq!(plan.to_string(), "primary index (rows=1 cost=17.0)");
+
+        // A quarter of the rows is cheaper to find by reading all of them
+        let status_index = AccessPath::SecondaryIndex("status".to_string());
+        let plan = choose(
+            &table,
+            &[status.candidate(status_index.clone(), &lookup)],
+            25_000.0,
+        );
+        assert_eq!(plan.path, AccessPath::FullScan);
+
+        let narrow = KeyPredicate::Range(Bound::Included(500), Bound::Excluded(600));
+        let plan = choose(
+            &table,
+            &[
+                status.candidate(status_index, &KeyPredicate::Eq(1)),
+                primary.candidate(AccessPath::PrimaryIndex, &narrow),
+            ],
+            100_000.0,
+        );
+        assert_eq!(plan.path, AccessPath::PrimaryIndex);
+        assert!(plan.estimated_rows < 2000.0, "{}", plan.estimated_rows);
+    }
+}
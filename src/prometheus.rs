@@ -0,0 +1,152 @@
+//! Renders a database's metrics in the Prometheus text exposition format, for the host
+//! application to serve from its own `/metrics` endpoint. Enabled by the `metrics-prometheus`
+//! feature.
+
+use crate::database::Database;
+use crate::error::Result;
+use std::fmt::Write;
+
+/// Renders the counters from `Database::metrics` along with gauges describing the tree's shape
+/// from `Database::stats`. The latter walks every level of the tree, so scraping a large database
+/// isn't free.
+pub fn render(db: &Database) -> Result<String> {
+    let metrics = db.metrics();
+    let stats = db.stats()?;
+
+    let mut out = String::new();
+    let counters = [
+        (
+            "page_reads_total",
+            "Pages read from disk.",
+            metrics.page_reads,
+        ),
+        (
+            "page_writes_total",
+            "Pages written to disk.",
+            metrics.page_writes,
+        ),
+        (
+            "pool_hits_total",
+            "Page fetches served from memory.",
+            metrics.pool_hits,
+        ),
+        (
+            "pool_misses_total",
+            "Page fetches that had to go to disk.",
+            metrics.pool_misses,
+        ),
+        (
+            "leaf_splits_total",
+            "Leaf pages split.",
+            metrics.leaf_splits,
+        ),
+        (
+            "internal_splits_total",
+            "Internal pages split.",
+            metrics.internal_splits,
+        ),
+        (
+            "move_rights_total",
+            "Steps to a right sibling after a concurrent split.",
+            metrics.move_rights,
+        ),
+        (
+            "lock_waits_total",
+            "Page lock acquisitions that had to wait.",
+            metrics.lock_waits,
+        ),
+    ];
+    for (name, help, value) in counters.iter() {
+        header(&mut out, name, help, "counter");
+        writeln!(out, "johndb_{} {}", name, value).unwrap();
+    }
+
+    let gauges = [
+        ("pages", "Pages in the database file.", stats.page_cnt),
+        ("keys", "Keys stored in the tree.", stats.key_cnt),
+        ("tree_height", "Levels in the tree.", stats.tree.height()),
+    ];
+    for (name, help, value) in gauges.iter() {
+        header(&mut out, name, help, "gauge");
+        writeln!(out, "johndb_{} {}", name, value).unwrap();
+    }
+
+    // Levels are numbered from the root down, like `Database::stats`
+    header(&mut out, "level_pages", "Pages on each level.", "gauge");
+    for (depth, level) in stats.tree.levels.iter().enumerate() {
+        writeln!(
+            out,
+            "johndb_level_pages{{level=\"{}\"}} {}",
+            depth, level.page_cnt
+        )
+        .unwrap();
+    }
+    header(
+        &mut out,
+        "level_fill_ratio",
+        "Average fraction of each page's space in use on each level.",
+        "gauge",
+    );
+    for (depth, level) in stats.tree.levels.iter().enumerate() {
+        writeln!(
+            out,
+            "johndb_level_fill_ratio{{level=\"{}\"}} {}",
+            depth,
+            level.avg_fill()
+        )
+        .unwrap();
+    }
+    header(
+        &mut out,
+        "level_dead_bytes",
+        "Bytes left behind by removed items on each level.",
+        "gauge",
+    );
+    for (depth, level) in stats.tree.levels.iter().enumerate() {
+        writeln!(
+            out,
+            "johndb_level_dead_bytes{{level=\"{}\"}} {}",
+            depth, level.dead_space
+        )
+        .unwrap();
+    }
+
+    Ok(out)
+}
+
+fn header(out: &mut String, name: &str, help: &str, kind: &str) {
+    writeln!(out, "# HELP johndb_{} {}", name, help).unwrap();
+    writeln!(out, "# TYPE johndb_{} {}", name, kind).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::render;
+    use crate::database::Database;
+    use crate::database::Options;
+
+    #[test]
+    fn renders_counters_and_levels() {
+        let path = std::env::temp_dir().join(format!("johndb-prometheus-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let mut db = Database::open(&path, Options::default()).unwrap();
+        for i in 0..2000u32 {
+            db.put(format!("key-{:05}", i).as_bytes(), b"value")
+                .unwrap();
+        }
+
+        let text = render(&db).unwrap();
+        assert!(text.contains("# TYPE johndb_leaf_splits_total counter\n"));
+        assert!(text.contains("\njohndb_keys 2000\n"));
+        assert!(text.contains("\njohndb_tree_height 2\n"));
+        assert!(text.contains("\njohndb_level_pages{level=\"0\"} 1\n"));
+        // Every sample line is a name followed by a value
+        for line in text.lines().filter(|line| !line.starts_with('#')) {
+            let (name, value) = line.rsplit_once(' ').unwrap();
+            assert!(name.starts_with("johndb_"), "{}", line);
+            assert!(value.parse::<f64>().is_ok(), "{}", line);
+        }
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
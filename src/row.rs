@@ -0,0 +1,521 @@
+//! A row format for storing structured records, e.g. as heap tuples or values, described by a
+//! `Schema`. Fields can be read straight from the encoded bytes without decoding the whole row.
+//!
+//! All integers are big-endian. A row consists of:
+//!
+//! * A null bitmap with one bit per column, least significant bit first, where a set bit marks
+//!   the column as NULL.
+//! * The fixed section, holding every column in schema order. Fixed-size columns are stored
+//!   inline (zeroed when NULL), while variable length columns store the offset of their bytes
+//!   from the start of the row and their length, both as u32s.
+//! * The varlen section, holding the bytes of the variable length columns back to back.
+
+use crate::encoding::Decode;
+use crate::encoding::Encode;
+use crate::error::Error;
+use crate::error::Result;
+use std::convert::TryInto;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColumnType {
+    Bool,
+    I32,
+    I64,
+    U32,
+    U64,
+    F64,
+    Bytes,
+    /// UTF-8 text.
+    Text,
+}
+
+impl ColumnType {
+    const ALL: [ColumnType; 8] = [
+        ColumnType::Bool,
+        ColumnType::I32,
+        ColumnType::I64,
+        ColumnType::U32,
+        ColumnType::U64,
+        ColumnType::F64,
+        ColumnType::Bytes,
+        ColumnType::Text,
+    ];
+
+    /// Checks that `bytes` hold a single value of this type as encoded in the fixed section,
+    /// or as raw bytes for variable length types. This is how keys of a typed tree are stored.
+    pub fn check_value(self, bytes: &[u8]) -> Result<()> {
+        let ok = match self {
+            ColumnType::Bytes => true,
+            ColumnType::Text => std::str::from_utf8(bytes).is_ok(),
+            ColumnType::Bool => bytes.len() == 1 && bytes[0] <= 1,
+            fixed => bytes.len() == fixed.fixed_size(),
+        };
+        if !ok {
+            return Err(Error::TypeMismatch(format!(
+                "{} bytes aren't a valid {:?}",
+                bytes.len(),
+                self
+            )));
+        }
+        Ok(())
+    }
+
+    pub(crate) fn from_tag(tag: u8) -> Result<Self> {
+        Self::ALL
+            .get(tag as usize)
+            .copied()
+            .ok_or_else(|| Error::corruption(format!("unknown column type {}", tag)))
+    }
+
+    /// Bytes the column takes up in the fixed section.
+    fn fixed_size(self) -> usize {
+        match self {
+            ColumnType::Bool => 1,
+            ColumnType::I32 | ColumnType::U32 => 4,
+            ColumnType::I64 | ColumnType::U64 | ColumnType::F64 => 8,
+            ColumnType::Bytes | ColumnType::Text => 8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Column {
+    pub name: String,
+    pub column_type: ColumnType,
+    pub nullable: bool,
+}
+
+/// A single field's value.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Field {
+    Null,
+    Bool(bool),
+    I32(i32),
+    I64(i64),
+    U32(u32),
+    U64(u64),
+    F64(f64),
+    Bytes(Vec<u8>),
+    Text(String),
+}
+
+impl Field {
+    pub(crate) fn matches(&self, column_type: ColumnType) -> bool {
+        matches!(
+            (self, column_type),
+            (Field::Bool(_), ColumnType::Bool)
+                | (Field::I32(_), ColumnType::I32)
+                | (Field::I64(_), ColumnType::I64)
+                | (Field::U32(_), ColumnType::U32)
+                | (Field::U64(_), ColumnType::U64)
+                | (Field::F64(_), ColumnType::F64)
+                | (Field::Bytes(_), ColumnType::Bytes)
+                | (Field::Text(_), ColumnType::Text)
+        )
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Schema {
+    columns: Vec<Column>,
+    /// Offset of each column's slot in the fixed section, from the start of the row.
+    offsets: Vec<usize>,
+    /// Bytes taken up by the null bitmap and the fixed section.
+    fixed_end: usize,
+}
+
+impl Schema {
+    pub fn new(columns: Vec<Column>) -> Self {
+        let mut offset = columns.len().div_ceil(8);
+        let mut offsets = Vec::with_capacity(columns.len());
+        for column in columns.iter() {
+            offsets.push(offset);
+            offset += column.column_type.fixed_size();
+        }
+        Schema {
+            columns,
+            offsets,
+            fixed_end: offset,
+        }
+    }
+
+    pub fn columns(&self) -> &[Column] {
+        &self.columns
+    }
+
+    /// The index of the column called `name`.
+    pub fn column_idx(&self, name: &str) -> Option<usize> {
+        self.columns.iter().position(|column| column.name == name)
+    }
+
+    /// Encodes `fields`, one per column, failing with `Error::TypeMismatch` if a field doesn't
+    /// match its column's type or is NULL in a column that isn't nullable.
+    pub fn encode(&self, fields: &[Field]) -> Result<Vec<u8>> {
+        if fields.len() != self.columns.len() {
+            return Err(Error::TypeMismatch(format!(
+                "{} fields for {} columns",
+                fields.len(),
+                self.columns.len()
+            )));
+        }
+
+        let mut row = vec![0; self.fixed_end];
+        for (idx, (column, field)) in self.columns.iter().zip(fields.iter()).enumerate() {
+            let offset = self.offsets[idx];
+            let fixed = match field {
+                Field::Null if column.nullable => {
+                    row[idx / 8] |= 1 << (idx % 8);
+                    continue;
+                }
+                Field::Null => {
+                    return Err(Error::TypeMismatch(format!(
+                        "column {} isn't nullable",
+                        column.name
+                    )))
+                }
+                field if !field.matches(column.column_type) => {
+                    return Err(Error::TypeMismatch(format!(
+                        "{:?} doesn't fit column {} of type {:?}",
+                        field, column.name, column.column_type
+                    )))
+                }
+                Field::Bool(value) => vec![*value as u8],
+                Field::I32(value) => value.to_be_bytes().to_vec(),
+                Field::I64(value) => value.to_be_bytes().to_vec(),
+                Field::U32(value) => value.to_be_bytes().to_vec(),
+                Field::U64(value) => value.to_be_bytes().to_vec(),
+                Field::F64(value) => value.to_be_bytes().to_vec(),
+                Field::Bytes(bytes) => append_varlen(&mut row, bytes)?,
+                Field::Text(text) => append_varlen(&mut row, text.as_bytes())?,
+            };
+            row[offset..offset + fixed.len()].copy_from_slice(&fixed);
+        }
+        Ok(row)
+    }
+
+    /// Projects the columns called `names`, in that order, failing with `Error::TypeMismatch` if
+    /// there's no such column.
+    pub fn projection<S: AsRef<str>>(&self, names: &[S]) -> Result<Projection<'_>> {
+        let idxs = names
+            .iter()
+            .map(|name| {
+                self.column_idx(name.as_ref())
+                    .ok_or_else(|| Error::TypeMismatch(format!("no column {}", name.as_ref())))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Projection { schema: self, idxs })
+    }
+
+    /// Checks that `row` is well-formed and has no NULLs in columns that aren't nullable,
+    /// failing with `Error::TypeMismatch` otherwise.
+    pub fn validate(&self, row: &[u8]) -> Result<()> {
+        let row_ref = self
+            .row_ref(row)
+            .map_err(|err| Error::TypeMismatch(err.to_string()))?;
+        for (idx, column) in self.columns.iter().enumerate() {
+            if row_ref.is_null(idx) && !column.nullable {
+                return Err(Error::TypeMismatch(format!(
+                    "column {} isn't nullable",
+                    column.name
+                )));
+            }
+            row_ref
+                .get(idx)
+                .map_err(|err| Error::TypeMismatch(err.to_string()))?;
+        }
+        Ok(())
+    }
+
+    /// Decodes every field of `row`.
+    pub fn decode(&self, row: &[u8]) -> Result<Vec<Field>> {
+        let row = self.row_ref(row)?;
+        (0..self.columns.len()).map(|idx| row.get(idx)).collect()
+    }
+
+    /// Wraps `row` for reading individual fields, after checking it's long enough to hold the
+    /// null bitmap and fixed section.
+    pub fn row_ref<'a>(&'a self, row: &'a [u8]) -> Result<RowRef<'a>> {
+        if row.len() < self.fixed_end {
+            return Err(Error::corruption(format!(
+                "row of {} bytes is shorter than its {} byte fixed section",
+                row.len(),
+                self.fixed_end
+            )));
+        }
+        Ok(RowRef { schema: self, row })
+    }
+}
+
+/// A subset of a schema's columns, decoded from rows without touching the others.
+#[derive(Debug, Clone)]
+pub struct Projection<'a> {
+    schema: &'a Schema,
+    idxs: Vec<usize>,
+}
+
+impl<'a> Projection<'a> {
+    /// The indexes of the projected columns within the schema, in projection order.
+    pub fn column_idxs(&self) -> &[usize] {
+        &self.idxs
+    }
+
+    /// Decodes the projected fields of `row`. Variable length fields of other columns are
+    /// skipped without being read.
+    pub fn decode(&self, row: &[u8]) -> Result<Vec<Field>> {
+        let row_ref = self.schema.row_ref(row)?;
+        self.idxs.iter().map(|idx| row_ref.get(*idx)).collect()
+    }
+}
+
+/// Schemas are encoded with `crate::encoding` as their column count followed by each column's
+/// name, type tag and nullability.
+impl Encode for Schema {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        (self.columns.len() as u32).encode_to(buf);
+        for column in self.columns.iter() {
+            (&column.name, column.column_type as u8, column.nullable).encode_to(buf);
+        }
+    }
+}
+
+impl Decode for Schema {
+    fn decode_from(buf: &mut &[u8]) -> Result<Self> {
+        let column_cnt = u32::decode_from(buf)?;
+        let columns = (0..column_cnt)
+            .map(|_| {
+                let (name, tag, nullable) = <(String, u8, bool)>::decode_from(buf)?;
+                Ok(Column {
+                    name,
+                    column_type: ColumnType::from_tag(tag)?,
+                    nullable,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        Ok(Schema::new(columns))
+    }
+}
+
+/// Fields are encoded with `crate::encoding` as their column type's tag, or `0xFF` for NULL,
+/// followed by the value.
+impl Encode for Field {
+    fn encode_to(&self, buf: &mut Vec<u8>) {
+        let tag = |column_type: ColumnType| column_type as u8;
+        match self {
+            Field::Null => 0xFFu8.encode_to(buf),
+            Field::Bool(value) => (tag(ColumnType::Bool), *value).encode_to(buf),
+            Field::I32(value) => (tag(ColumnType::I32), *value).encode_to(buf),
+            Field::I64(value) => (tag(ColumnType::I64), *value).encode_to(buf),
+            Field::U32(value) => (tag(ColumnType::U32), *value).encode_to(buf),
+            Field::U64(value) => (tag(ColumnType::U64), *value).encode_to(buf),
+            Field::F64(value) => (tag(ColumnType::F64), *value).encode_to(buf),
+            Field::Bytes(bytes) => (tag(ColumnType::Bytes), bytes).encode_to(buf),
+            Field::Text(text) => (tag(ColumnType::Text), text).encode_to(buf),
+        }
+    }
+}
+
+impl Decode for Field {
+    fn decode_from(buf: &mut &[u8]) -> Result<Self> {
+        let tag = u8::decode_from(buf)?;
+        if tag == 0xFF {
+            return Ok(Field::Null);
+        }
+        let field = match ColumnType::from_tag(tag)? {
+            ColumnType::Bool => Field::Bool(bool::decode_from(buf)?),
+            ColumnType::I32 => Field::I32(i32::decode_from(buf)?),
+            ColumnType::I64 => Field::I64(i64::decode_from(buf)?),
+            ColumnType::U32 => Field::U32(u32::decode_from(buf)?),
+            ColumnType::U64 => Field::U64(u64::decode_from(buf)?),
+            ColumnType::F64 => Field::F64(f64::decode_from(buf)?),
+            ColumnType::Bytes => Field::Bytes(Vec::<u8>::decode_from(buf)?),
+            ColumnType::Text => Field::Text(String::decode_from(buf)?),
+        };
+        Ok(field)
+    }
+}
+
+/// Appends `bytes` to the varlen section, returning the column's fixed section slot.
+fn append_varlen(row: &mut Vec<u8>, bytes: &[u8]) -> Result<Vec<u8>> {
+    let offset: u32 = row
+        .len()
+        .try_into()
+        .map_err(|_| Error::ItemTooLarge(row.len()))?;
+    let len: u32 = bytes
+        .len()
+        .try_into()
+        .map_err(|_| Error::ItemTooLarge(bytes.len()))?;
+    row.extend_from_slice(bytes);
+
+    let mut slot = offset.to_be_bytes().to_vec();
+    slot.extend_from_slice(&len.to_be_bytes());
+    Ok(slot)
+}
+
+/// An encoded row borrowed along with its schema.
+#[derive(Debug, Clone, Copy)]
+pub struct RowRef<'a> {
+    schema: &'a Schema,
+    row: &'a [u8],
+}
+
+impl<'a> RowRef<'a> {
+    pub fn column_cnt(&self) -> usize {
+        self.schema.columns.len()
+    }
+
+    pub fn is_null(&self, idx: usize) -> bool {
+        self.row[idx / 8] & (1 << (idx % 8)) != 0
+    }
+
+    /// Decodes the field of column `idx`.
+    ///
+    /// Panics if `idx` is out of bounds.
+    pub fn get(&self, idx: usize) -> Result<Field> {
+        if self.is_null(idx) {
+            return Ok(Field::Null);
+        }
+
+        let column_type = self.schema.columns[idx].column_type;
+        let offset = self.schema.offsets[idx];
+        let fixed = &self.row[offset..offset + column_type.fixed_size()];
+        let field = match column_type {
+            ColumnType::Bool => Field::Bool(fixed[0] != 0),
+            ColumnType::I32 => Field::I32(i32::from_be_bytes(fixed.try_into().unwrap())),
+            ColumnType::I64 => Field::I64(i64::from_be_bytes(fixed.try_into().unwrap())),
+            ColumnType::U32 => Field::U32(u32::from_be_bytes(fixed.try_into().unwrap())),
+            ColumnType::U64 => Field::U64(u64::from_be_bytes(fixed.try_into().unwrap())),
+            ColumnType::F64 => Field::F64(f64::from_be_bytes(fixed.try_into().unwrap())),
+            ColumnType::Bytes => Field::Bytes(self.varlen(fixed)?.to_vec()),
+            ColumnType::Text => Field::Text(
+                String::from_utf8(self.varlen(fixed)?.to_vec())
+                    .map_err(|_| Error::corruption(format!("column {} isn't UTF-8", idx)))?,
+            ),
+        };
+        Ok(field)
+    }
+
+    /// Decodes the field of the column called `name`, or `None` if there's no such column.
+    pub fn get_by_name(&self, name: &str) -> Result<Option<Field>> {
+        self.schema
+            .column_idx(name)
+            .map(|idx| self.get(idx))
+            .transpose()
+    }
+
+    /// Borrows the bytes a variable length column's fixed section `slot` points at.
+    fn varlen(&self, slot: &[u8]) -> Result<&'a [u8]> {
+        let offset = u32::from_be_bytes(slot[..4].try_into().unwrap()) as usize;
+        let len = u32::from_be_bytes(slot[4..].try_into().unwrap()) as usize;
+        if offset < self.schema.fixed_end || offset + len > self.row.len() {
+            return Err(Error::corruption(format!(
+                "field at offset {} with length {} lies outside the row's {} varlen bytes",
+                offset,
+                len,
+                self.row.len() - self.schema.fixed_end
+            )));
+        }
+        Ok(&self.row[offset..offset + len])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Column;
+    use super::ColumnType;
+    use super::Field;
+    use super::Schema;
+    use crate::encoding::Decode;
+    use crate::encoding::Encode;
+    use crate::error::Error;
+
+    fn column(name: &str, column_type: ColumnType, nullable: bool) -> Column {
+        Column {
+            name: name.to_string(),
+            column_type,
+            nullable,
+        }
+    }
+
+    #[test]
+    fn round_trip_with_nulls() {
+        let schema = Schema::new(vec![
+            column("id", ColumnType::U64, false),
+            column("name", ColumnType::Text, false),
+            column("age", ColumnType::I32, true),
+            column("score", ColumnType::F64, true),
+            column("photo", ColumnType::Bytes, true),
+            column("active", ColumnType::Bool, false),
+            column("visits", ColumnType::U32, true),
+            column("balance", ColumnType::I64, true),
+            column("nickname", ColumnType::Text, true),
+        ]);
+        let fields = vec![
+            Field::U64(42),
+            Field::Text("Ada".to_string()),
+            Field::Null,
+            Field::F64(-1.5),
+            Field::Bytes(vec![0, 1, 2, 255]),
+            Field::Bool(true),
+            Field::Null,
+            Field::I64(-7),
+            Field::Text(String::new()),
+        ];
+
+        let row = schema.encode(&fields).unwrap();
+        assert_eq!(schema.decode(&row).unwrap(), fields);
+
+        let row_ref = schema.row_ref(&row).unwrap();
+        assert!(row_ref.is_null(2) && row_ref.is_null(6) && !row_ref.is_null(8));
+        assert_eq!(
+            row_ref.get_by_name("name").unwrap(),
+            Some(Field::Text("Ada".to_string()))
+        );
+        assert_eq!(row_ref.get_by_name("missing").unwrap(), None);
+
+        assert!(matches!(
+            schema.decode(&row[..5]),
+            Err(Error::Corruption { .. })
+        ));
+
+        let mut bytes = Vec::new();
+        schema.encode_to(&mut bytes);
+        assert_eq!(Schema::decode_from(&mut &bytes[..]).unwrap(), schema);
+        assert!(schema.validate(&row).is_ok());
+    }
+
+    #[test]
+    fn rejects_mismatched_fields() {
+        let schema = Schema::new(vec![
+            column("id", ColumnType::U64, false),
+            column("note", ColumnType::Text, true),
+        ]);
+
+        let mismatches = [
+            vec![Field::Null, Field::Null],
+            vec![Field::I64(1), Field::Null],
+            vec![Field::U64(1)],
+            vec![Field::U64(1), Field::Bytes(vec![])],
+        ];
+        for fields in mismatches.iter() {
+            assert!(matches!(schema.encode(fields), Err(Error::TypeMismatch(_))));
+        }
+        assert!(schema.encode(&[Field::U64(1), Field::Null]).is_ok());
+
+        let row = schema
+            .encode(&[Field::U64(7), Field::Text("x".repeat(1000))])
+            .unwrap();
+        let projection = schema.projection(&["id"]).unwrap();
+        assert_eq!(projection.column_idxs(), &[0]);
+        assert_eq!(projection.decode(&row).unwrap(), vec![Field::U64(7)]);
+        // The text column is never looked at, so a bad slot doesn't matter
+        let mut broken = row.clone();
+        let slot = broken.len() - 1000 - 8;
+        broken[slot..slot + 4].copy_from_slice(&u32::MAX.to_be_bytes());
+        assert_eq!(projection.decode(&broken).unwrap(), vec![Field::U64(7)]);
+        assert!(schema.decode(&broken).is_err());
+        assert!(matches!(
+            schema.projection(&["id", "missing"]),
+            Err(Error::TypeMismatch(_))
+        ));
+    }
+}
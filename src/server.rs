@@ -0,0 +1,580 @@
+//! A length-prefixed binary protocol for using a database over TCP, so that processes other than
+//! the one that opened it, and clients not written in Rust, can share it. `serve` runs the server
+//! with a thread per connection, `Client` talks to it.
+//!
+//! All integers are big-endian, and byte strings are a u32 length followed by their bytes. Each
+//! message is framed by a u32 length, followed by that many bytes:
+//!
+//! * A request is an opcode byte followed by its arguments:
+//!   * `1` GET: the key.
+//!   * `2` PUT: the key and the value.
+//!   * `3` DEL: the key.
+//!   * `4` SCAN: the start and end keys, each a flag byte that's `1` if the bound is set followed
+//!     by the key, or just `0` if it isn't, then the maximum number of entries as a u32, `0` for
+//!     no limit. The start key is included and the end key excluded.
+//!   * `5` BATCH: the number of operations as a u32, then each operation as a PUT or DEL request.
+//! * A response is a status byte followed by its payload:
+//!   * `0` OK: the value for GET, the number of entries as a u32 followed by each key and value
+//!     for SCAN, nothing otherwise.
+//!   * `1` NOT FOUND: nothing. Returned by GET and DEL for missing keys.
+//!   * `2` ERROR: the message as a byte string.
+//!
+//! A malformed request gets an ERROR response, after which the server closes the connection.
+
+use crate::database::Database;
+use crate::error::Error;
+use crate::error::Result;
+use std::convert::TryInto;
+use std::io;
+use std::io::BufReader;
+use std::io::BufWriter;
+use std::io::Read;
+use std::io::Write;
+use std::net::TcpListener;
+use std::net::TcpStream;
+use std::net::ToSocketAddrs;
+use std::ops::Bound;
+use std::sync::Arc;
+use std::sync::Mutex;
+use std::thread;
+
+/// Frames larger than this are rejected, so that a bad length can't make the reader allocate
+/// arbitrary amounts of memory.
+pub const MAX_FRAME_SIZE: usize = 64 << 20;
+
+const OP_GET: u8 = 1;
+const OP_PUT: u8 = 2;
+const OP_DEL: u8 = 3;
+const OP_SCAN: u8 = 4;
+const OP_BATCH: u8 = 5;
+
+const STATUS_OK: u8 = 0;
+const STATUS_NOT_FOUND: u8 = 1;
+const STATUS_ERROR: u8 = 2;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Request {
+    Get(Vec<u8>),
+    Put(Vec<u8>, Vec<u8>),
+    Del(Vec<u8>),
+    /// Entries in `[start, end)`, at most `limit` of them if it's not 0.
+    Scan {
+        start: Option<Vec<u8>>,
+        end: Option<Vec<u8>>,
+        limit: u32,
+    },
+    /// PUTs and DELs applied in order, without other requests running in between.
+    Batch(Vec<Request>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Response {
+    Ok,
+    Value(Vec<u8>),
+    Entries(Vec<(Vec<u8>, Vec<u8>)>),
+    NotFound,
+    Error(String),
+}
+
+impl Request {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        self.encode_into(&mut buf)?;
+        Ok(buf)
+    }
+
+    fn encode_into(&self, buf: &mut Vec<u8>) -> Result<()> {
+        match self {
+            Request::Get(key) => {
+                buf.push(OP_GET);
+                put_bytes(buf, key)?;
+            }
+            Request::Put(key, value) => {
+                buf.push(OP_PUT);
+                put_bytes(buf, key)?;
+                put_bytes(buf, value)?;
+            }
+            Request::Del(key) => {
+                buf.push(OP_DEL);
+                put_bytes(buf, key)?;
+            }
+            Request::Scan { start, end, limit } => {
+                buf.push(OP_SCAN);
+                for bound in [start, end].iter() {
+                    match bound {
+                        Some(key) => {
+                            buf.push(1);
+                            put_bytes(buf, key)?;
+                        }
+                        None => buf.push(0),
+                    }
+                }
+                buf.extend_from_slice(&limit.to_be_bytes());
+            }
+            Request::Batch(ops) => {
+                buf.push(OP_BATCH);
+                put_len(buf, ops.len())?;
+                for op in ops.iter() {
+                    match op {
+                        Request::Put(..) | Request::Del(_) => op.encode_into(buf)?,
+                        _ => {
+                            return Err(Error::Protocol(
+                                "batches can only hold PUTs and DELs".to_string(),
+                            ))
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    pub fn decode(buf: &[u8]) -> Result<Self> {
+        let mut reader = FrameReader { buf };
+        let request = reader.request(true)?;
+        reader.finish()?;
+        Ok(request)
+    }
+}
+
+impl Response {
+    pub fn encode(&self) -> Result<Vec<u8>> {
+        let mut buf = Vec::new();
+        match self {
+            Response::Ok => buf.push(STATUS_OK),
+            Response::Value(value) => {
+                buf.push(STATUS_OK);
+                put_bytes(&mut buf, value)?;
+            }
+            Response::Entries(entries) => {
+                buf.push(STATUS_OK);
+                put_len(&mut buf, entries.len())?;
+                for (key, value) in entries.iter() {
+                    put_bytes(&mut buf, key)?;
+                    put_bytes(&mut buf, value)?;
+                }
+            }
+            Response::NotFound => buf.push(STATUS_NOT_FOUND),
+            Response::Error(message) => {
+                buf.push(STATUS_ERROR);
+                put_bytes(&mut buf, message.as_bytes())?;
+            }
+        }
+        Ok(buf)
+    }
+
+    /// Decodes a response to `request`, which determines the shape of an OK payload.
+    pub fn decode(buf: &[u8], request: &Request) -> Result<Self> {
+        let mut reader = FrameReader { buf };
+        let response = match reader.u8()? {
+            STATUS_OK => match request {
+                Request::Get(_) => Response::Value(reader.bytes()?),
+                Request::Scan { .. } => {
+                    let cnt = reader.u32()?;
+                    let mut entries = Vec::new();
+                    for _ in 0..cnt {
+                        entries.push((reader.bytes()?, reader.bytes()?));
+                    }
+                    Response::Entries(entries)
+                }
+                Request::Put(..) | Request::Del(_) | Request::Batch(_) => Response::Ok,
+            },
+            STATUS_NOT_FOUND => Response::NotFound,
+            STATUS_ERROR => Response::Error(String::from_utf8_lossy(&reader.bytes()?).into_owned()),
+            status => return Err(Error::Protocol(format!("unknown status {}", status))),
+        };
+        reader.finish()?;
+        Ok(response)
+    }
+}
+
+/// Runs `request` against `db`. Errors from the database are turned into ERROR responses.
+pub fn handle(db: &Mutex<Database>, request: &Request) -> Result<Response> {
+    let mut db = db.lock()?;
+    Ok(apply(&mut db, request).unwrap_or_else(|err| Response::Error(err.to_string())))
+}
+
+fn apply(db: &mut Database, request: &Request) -> Result<Response> {
+    let response = match request {
+        Request::Get(key) => match db.get(key)? {
+            Some(value) => Response::Value(value),
+            None => Response::NotFound,
+        },
+        Request::Put(key, value) => {
+            db.put(key, value)?;
+            Response::Ok
+        }
+        Request::Del(key) => match db.delete(key)? {
+            Some(_) => Response::Ok,
+            None => Response::NotFound,
+        },
+        Request::Scan { start, end, limit } => {
+            let start = match start {
+                Some(key) => Bound::Included(key.as_slice()),
+                None => Bound::Unbounded,
+            };
+            let end = match end {
+                Some(key) => Bound::Excluded(key.as_slice()),
+                None => Bound::Unbounded,
+            };
+            let limit = match *limit {
+                0 => usize::MAX,
+                limit => limit as usize,
+            };
+            let entries = db
+                .range((start, end))?
+                .take(limit)
+                .collect::<Result<Vec<_>>>()?;
+            Response::Entries(entries)
+        }
+        Request::Batch(ops) => {
+            for op in ops.iter() {
+                apply(db, op)?;
+            }
+            Response::Ok
+        }
+    };
+    Ok(response)
+}
+
+/// Accepts connections on `listener` until it fails, serving each on its own thread. Requests
+/// from all connections are run one at a time.
+pub fn serve(listener: TcpListener, db: Arc<Mutex<Database>>) -> Result<()> {
+    for stream in listener.incoming() {
+        let stream = stream?;
+        let db = db.clone();
+        thread::spawn(move || {
+            let peer = stream.peer_addr().ok();
+            if let Err(err) = serve_connection(stream, &db) {
+                log::warn!("connection from {:?} failed: {}", peer, err);
+            }
+        });
+    }
+    Ok(())
+}
+
+/// Serves requests from `stream` until the client disconnects or sends a malformed request.
+pub fn serve_connection(stream: TcpStream, db: &Mutex<Database>) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = BufWriter::new(stream);
+    while let Some(frame) = read_frame(&mut reader)? {
+        let (response, malformed) = match Request::decode(&frame) {
+            Ok(request) => (handle(db, &request)?, false),
+            Err(err) => (Response::Error(err.to_string()), true),
+        };
+        write_frame(&mut writer, &response.encode()?)?;
+        writer.flush()?;
+        if malformed {
+            break;
+        }
+    }
+    Ok(())
+}
+
+/// A connection to a server started with `serve`.
+pub struct Client {
+    reader: BufReader<TcpStream>,
+    writer: BufWriter<TcpStream>,
+}
+
+impl Client {
+    pub fn connect<A: ToSocketAddrs>(addr: A) -> Result<Self> {
+        let stream = TcpStream::connect(addr)?;
+        stream.set_nodelay(true)?;
+        Ok(Client {
+            reader: BufReader::new(stream.try_clone()?),
+            writer: BufWriter::new(stream),
+        })
+    }
+
+    /// Sends `request` and waits for its response.
+    pub fn request(&mut self, request: &Request) -> Result<Response> {
+        write_frame(&mut self.writer, &request.encode()?)?;
+        self.writer.flush()?;
+        match read_frame(&mut self.reader)? {
+            Some(frame) => Response::decode(&frame, request),
+            None => Err(Error::Protocol("server closed the connection".to_string())),
+        }
+    }
+
+    pub fn get(&mut self, key: &[u8]) -> Result<Option<Vec<u8>>> {
+        match self.request(&Request::Get(key.to_vec()))? {
+            Response::Value(value) => Ok(Some(value)),
+            Response::NotFound => Ok(None),
+            response => unexpected(response),
+        }
+    }
+
+    pub fn put(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        match self.request(&Request::Put(key.to_vec(), value.to_vec()))? {
+            Response::Ok => Ok(()),
+            response => unexpected(response),
+        }
+    }
+
+    /// Removes `key`, returning whether it was there.
+    pub fn delete(&mut self, key: &[u8]) -> Result<bool> {
+        match self.request(&Request::Del(key.to_vec()))? {
+            Response::Ok => Ok(true),
+            Response::NotFound => Ok(false),
+            response => unexpected(response),
+        }
+    }
+
+    pub fn scan(
+        &mut self,
+        start: Option<&[u8]>,
+        end: Option<&[u8]>,
+        limit: u32,
+    ) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let request = Request::Scan {
+            start: start.map(|key| key.to_vec()),
+            end: end.map(|key| key.to_vec()),
+            limit,
+        };
+        match self.request(&request)? {
+            Response::Entries(entries) => Ok(entries),
+            response => unexpected(response),
+        }
+    }
+
+    /// Applies `ops`, which must be PUTs and DELs, in order.
+    pub fn batch(&mut self, ops: Vec<Request>) -> Result<()> {
+        match self.request(&Request::Batch(ops))? {
+            Response::Ok => Ok(()),
+            response => unexpected(response),
+        }
+    }
+}
+
+fn unexpected<T>(response: Response) -> Result<T> {
+    match response {
+        Response::Error(message) => Err(Error::Protocol(message)),
+        response => Err(Error::Protocol(format!(
+            "unexpected response {:?}",
+            response
+        ))),
+    }
+}
+
+/// Reads a frame, or `None` if the stream ended cleanly before it.
+pub fn read_frame<R: Read>(reader: &mut R) -> Result<Option<Vec<u8>>> {
+    let mut len = [0; 4];
+    match reader.read_exact(&mut len) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+        Err(err) => return Err(err.into()),
+    }
+    let len = u32::from_be_bytes(len) as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(Error::Protocol(format!("{} byte frame is too large", len)));
+    }
+    let mut frame = vec![0; len];
+    reader.read_exact(&mut frame)?;
+    Ok(Some(frame))
+}
+
+pub fn write_frame<W: Write>(writer: &mut W, frame: &[u8]) -> Result<()> {
+    if frame.len() > MAX_FRAME_SIZE {
+        return Err(Error::ItemTooLarge(frame.len()));
+    }
+    writer.write_all(&(frame.len() as u32).to_be_bytes())?;
+    writer.write_all(frame)?;
+    Ok(())
+}
+
+fn put_len(buf: &mut Vec<u8>, len: usize) -> Result<()> {
+    let len: u32 = len.try_into().map_err(|_| Error::ItemTooLarge(len))?;
+    buf.extend_from_slice(&len.to_be_bytes());
+    Ok(())
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) -> Result<()> {
+    put_len(buf, bytes.len())?;
+    buf.extend_from_slice(bytes);
+    Ok(())
+}
+
+/// Reads the fields of a frame, failing with `Error::Protocol` if it's cut short.
+struct FrameReader<'a> {
+    buf: &'a [u8],
+}
+
+impl<'a> FrameReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8]> {
+        if self.buf.len() < len {
+            return Err(Error::Protocol("truncated frame".to_string()));
+        }
+        let (taken, rest) = self.buf.split_at(len);
+        self.buf = rest;
+        Ok(taken)
+    }
+
+    fn u8(&mut self) -> Result<u8> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_be_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    fn bytes(&mut self) -> Result<Vec<u8>> {
+        let len = self.u32()? as usize;
+        Ok(self.take(len)?.to_vec())
+    }
+
+    fn bound(&mut self) -> Result<Option<Vec<u8>>> {
+        match self.u8()? {
+            0 => Ok(None),
+            1 => Ok(Some(self.bytes()?)),
+            flag => Err(Error::Protocol(format!("unknown bound flag {}", flag))),
+        }
+    }
+
+    /// Reads a request, which may only be a BATCH if `batch_allowed`.
+    fn request(&mut self, batch_allowed: bool) -> Result<Request> {
+        let request = match self.u8()? {
+            OP_GET => Request::Get(self.bytes()?),
+            OP_PUT => Request::Put(self.bytes()?, self.bytes()?),
+            OP_DEL => Request::Del(self.bytes()?),
+            OP_SCAN => Request::Scan {
+                start: self.bound()?,
+                end: self.bound()?,
+                limit: self.u32()?,
+            },
+            OP_BATCH if batch_allowed => {
+                let cnt = self.u32()?;
+                let mut ops = Vec::new();
+                for _ in 0..cnt {
+                    match self.request(false)? {
+                        op @ Request::Put(..) | op @ Request::Del(_) => ops.push(op),
+                        _ => {
+                            return Err(Error::Protocol(
+                                "batches can only hold PUTs and DELs".to_string(),
+                            ))
+                        }
+                    }
+                }
+                Request::Batch(ops)
+            }
+            op => return Err(Error::Protocol(format!("unknown opcode {}", op))),
+        };
+        Ok(request)
+    }
+
+    fn finish(&self) -> Result<()> {
+        match self.buf.is_empty() {
+            true => Ok(()),
+            false => Err(Error::Protocol(format!(
+                "{} trailing bytes in frame",
+                self.buf.len()
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::serve;
+    use super::Client;
+    use super::Request;
+    use super::Response;
+    use crate::database::Database;
+    use crate::database::Options;
+    use crate::error::Error;
+    use std::io::Read;
+    use std::io::Write;
+    use std::net::TcpListener;
+    use std::net::TcpStream;
+    use std::sync::Arc;
+    use std::sync::Mutex;
+    use std::thread;
+
+    #[test]
+    fn requests_round_trip() {
+        let requests = [
+            Request::Get(b"k".to_vec()),
+            Request::Scan {
+                start: None,
+                end: Some(b"z".to_vec()),
+                limit: 10,
+            },
+            Request::Batch(vec![
+                Request::Put(b"a".to_vec(), b"1".to_vec()),
+                Request::Del(b"b".to_vec()),
+            ]),
+        ];
+        for request in requests.iter() {
+            let frame = request.encode().unwrap();
+            assert_eq!(Request::decode(&frame).unwrap(), *request);
+            assert!(matches!(
+                Request::decode(&frame[..frame.len() - 1]),
+                Err(Error::Protocol(_))
+            ));
+        }
+        let nested = Request::Batch(vec![Request::Batch(vec![])]);
+        assert!(matches!(nested.encode(), Err(Error::Protocol(_))));
+
+        let response = Response::Entries(vec![(b"a".to_vec(), b"1".to_vec())]);
+        assert_eq!(
+            Response::decode(&response.encode().unwrap(), &requests[1]).unwrap(),
+            response
+        );
+    }
+
+    #[test]
+    fn serves_clients() {
+        let path = std::env::temp_dir().join(format!("johndb-server-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let db = Database::open(&path, Options::default()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let db = Arc::new(Mutex::new(db));
+        let server_db = db.clone();
+        thread::spawn(move || serve(listener, server_db));
+
+        let writers = (0..4u8)
+            .map(|thread_no| {
+                thread::spawn(move || {
+                    let mut client = Client::connect(addr).unwrap();
+                    for i in 0..50u8 {
+                        client.put(&[thread_no, i], &[i; 100]).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        writers
+            .into_iter()
+            .for_each(|writer| writer.join().unwrap());
+
+        let mut client = Client::connect(addr).unwrap();
+        assert_eq!(client.get(&[2, 7]).unwrap(), Some(vec![7; 100]));
+        assert_eq!(client.get(b"missing").unwrap(), None);
+        assert!(client.delete(&[2, 7]).unwrap());
+        assert!(!client.delete(&[2, 7]).unwrap());
+
+        let entries = client.scan(Some(&[1]), Some(&[2]), 0).unwrap();
+        assert_eq!(entries.len(), 50);
+        assert_eq!(entries[3], (vec![1, 3], vec![3; 100]));
+        assert_eq!(client.scan(None, None, 5).unwrap().len(), 5);
+
+        client
+            .batch(vec![
+                Request::Del(vec![0, 0]),
+                Request::Put(b"x".to_vec(), b"y".to_vec()),
+            ])
+            .unwrap();
+        assert_eq!(client.get(&[0, 0]).unwrap(), None);
+        assert_eq!(client.get(b"x").unwrap(), Some(b"y".to_vec()));
+
+        // A malformed request gets an error and the connection is closed
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(&[0, 0, 0, 1, 42]).unwrap();
+        let mut response = Vec::new();
+        stream.read_to_end(&mut response).unwrap();
+        assert_eq!(response[4], 2);
+
+        assert_eq!(db.lock().unwrap().get(b"x").unwrap(), Some(b"y".to_vec()));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,530 @@
+//! Shadow paging, an alternative to writing pages back in place (as in LMDB). Pages are never
+//! overwritten: a commit writes the pages that changed since the last one to pages of the file
+//! that no recent commit uses, then a page table mapping every page number to where it's stored,
+//! and only once those are synced, a meta page pointing at the table. Opening the file follows
+//! the newest valid meta page, so a crash at any point leaves either the old or the new commit
+//! behind in full, with nothing to replay.
+//!
+//! The two meta pages are the first two pages of the file: odd generations go in page 0, even
+//! ones in page 1. All integers are little-endian, and a meta page is:
+//!
+//! ```text
+//! magic: [u8; 8]       JOHNDSHD
+//! version: u32
+//! generation: u64      one more than the previous commit's
+//! page_cnt: u32        pages in the database
+//! table_cnt: u32       pages holding the page table
+//! table: [u32]         where each of them is stored, in order
+//! crc: u32             CRC-32C of everything before it
+//! ```
+//!
+//! Each page table page holds the locations of `TABLE_ENTRIES` consecutive pages as u32s. A
+//! commit avoids the pages of the previous commit as well as the last one, so that if the meta
+//! page it writes is torn, the previous meta page still points at intact pages.
+//!
+//! Files written in place start with a btree metadata page, whose first field can't hold the magic
+//! bytes, so the two formats are told apart by their first two pages.
+
+use crate::checksum::crc32c;
+use crate::error::Error;
+use crate::error::Result;
+use crate::page::Page;
+use crate::page::PAGE_SIZE;
+use crate::page_fetcher::InMemoryPageFetcher;
+use crate::page_fetcher::PageFetcher;
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::fs::File;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+const MAGIC: &[u8; 8] = b"JOHNDSHD";
+const FORMAT_VERSION: u32 = 1;
+/// The meta pages come first in the file.
+const META_PAGES: u32 = 2;
+const META_HEADER_SIZE: usize = 28;
+/// Page locations per page table page.
+const TABLE_ENTRIES: usize = PAGE_SIZE / 4;
+const MAX_TABLE_PAGES: usize = (PAGE_SIZE - META_HEADER_SIZE - 4) / 4;
+
+/// Where a commit's writes go.
+pub(crate) trait PageSink {
+    /// Writes `page` as page `page_no` of the file, growing it if needed.
+    fn write_page(&self, page_no: u32, page: &Page) -> Result<()>;
+    /// Makes everything written so far durable.
+    fn sync(&self) -> Result<()>;
+    /// Shrinks the file to `page_cnt` pages.
+    fn truncate(&self, page_cnt: u32) -> Result<()>;
+}
+
+impl PageSink for File {
+    fn write_page(&self, page_no: u32, page: &Page) -> Result<()> {
+        let mut file = self;
+        file.seek(SeekFrom::Start(u64::from(page_no) * PAGE_SIZE as u64))?;
+        file.write_all(page.as_bytes())?;
+        Ok(())
+    }
+
+    fn sync(&self) -> Result<()> {
+        Ok(self.sync_all()?)
+    }
+
+    fn truncate(&self, page_cnt: u32) -> Result<()> {
+        Ok(self.set_len(u64::from(page_cnt) * PAGE_SIZE as u64)?)
+    }
+}
+
+struct Meta {
+    generation: u64,
+    page_cnt: u32,
+    table: Vec<u32>,
+}
+
+impl Meta {
+    fn to_page(&self) -> Page {
+        let mut bytes = vec![0u8; PAGE_SIZE];
+        let mut len = 0;
+        let mut put = |field: &[u8]| {
+            bytes[len..len + field.len()].copy_from_slice(field);
+            len += field.len();
+        };
+        put(MAGIC);
+        put(&FORMAT_VERSION.to_le_bytes());
+        put(&self.generation.to_le_bytes());
+        put(&self.page_cnt.to_le_bytes());
+        put(&(self.table.len() as u32).to_le_bytes());
+        for location in self.table.iter() {
+            put(&location.to_le_bytes());
+        }
+        let crc = crc32c(&bytes[..len]);
+        bytes[len..len + 4].copy_from_slice(&crc.to_le_bytes());
+        Page::from_bytes(&bytes)
+    }
+
+    /// The meta page in `bytes`, or `None` if it's missing, torn or of another version.
+    fn parse(bytes: &[u8]) -> Option<Meta> {
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        if !bytes.starts_with(MAGIC) || u32_at(8) != FORMAT_VERSION {
+            return None;
+        }
+        let table_cnt = u32_at(24) as usize;
+        if table_cnt > MAX_TABLE_PAGES {
+            return None;
+        }
+        let len = META_HEADER_SIZE + table_cnt * 4;
+        if crc32c(&bytes[..len]) != u32_at(len) {
+            return None;
+        }
+        Some(Meta {
+            generation: u64::from_le_bytes(bytes[12..20].try_into().unwrap()),
+            page_cnt: u32_at(20),
+            table: (0..table_cnt)
+                .map(|idx| u32_at(META_HEADER_SIZE + idx * 4))
+                .collect(),
+        })
+    }
+}
+
+/// Whether the file of `file_pages` pages is shadow paged.
+pub(crate) fn is_shadow_paged(file: &File, file_pages: usize) -> Result<bool> {
+    let mut frame = Page::new(0);
+    for page_no in 0..file_pages.min(META_PAGES as usize) {
+        read_page(file, page_no as u32, &mut frame)?;
+        if frame.as_bytes().starts_with(MAGIC) {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+fn read_page(mut file: &File, page_no: u32, frame: &mut Page) -> Result<()> {
+    file.seek(SeekFrom::Start(u64::from(page_no) * PAGE_SIZE as u64))?;
+    file.read_exact(frame.as_bytes_mut())?;
+    Ok(())
+}
+
+/// The pages changed since the last commit. The fetcher marks a page before handing it out for
+/// writing, and a commit clears the mark while it holds the page's read lock, so a change made
+/// after the page was written is always caught by the next commit.
+pub(crate) struct DirtyPages {
+    flags: Vec<AtomicBool>,
+}
+
+impl DirtyPages {
+    /// Tracks pages `0..max_pages`. Marks of other pages are ignored.
+    pub(crate) fn new(max_pages: usize) -> Self {
+        DirtyPages {
+            flags: (0..max_pages).map(|_| AtomicBool::new(false)).collect(),
+        }
+    }
+
+    pub(crate) fn mark(&self, page_no: u32) {
+        if let Some(flag) = self.flags.get(page_no as usize) {
+            flag.store(true, Ordering::SeqCst);
+        }
+    }
+
+    /// Clears the mark of `page_no`, returning whether it was set.
+    fn take(&self, page_no: usize) -> bool {
+        self.flags
+            .get(page_no)
+            .is_some_and(|flag| flag.swap(false, Ordering::SeqCst))
+    }
+}
+
+/// What the last commit wrote where, to plan the next one.
+pub(crate) struct ShadowState {
+    generation: u64,
+    /// Where each page is stored as of the last commit.
+    locations: Vec<u32>,
+    /// Where the last commit's page table is stored.
+    table: Vec<u32>,
+    /// Pages of the file used by the commit before the last one.
+    previous: HashSet<u32>,
+    file_pages: u32,
+}
+
+impl ShadowState {
+    /// The state of a file without any commits yet.
+    pub(crate) fn new() -> Self {
+        ShadowState {
+            generation: 0,
+            locations: Vec::new(),
+            table: Vec::new(),
+            previous: HashSet::new(),
+            file_pages: META_PAGES,
+        }
+    }
+
+    /// Loads the pages of the last commit to the file of `file_pages` pages into `pages`, in
+    /// order. Fails with `Error::OutOfPages` before loading any if the commit holds more than
+    /// `max_pages`.
+    pub(crate) fn load(
+        file: &File,
+        file_pages: usize,
+        max_pages: usize,
+        pages: &InMemoryPageFetcher,
+    ) -> Result<Self> {
+        let mut frame = Page::new(0);
+        let mut metas = Vec::new();
+        for slot in 0..file_pages.min(META_PAGES as usize) {
+            read_page(file, slot as u32, &mut frame)?;
+            metas.extend(Meta::parse(frame.as_bytes()));
+        }
+        metas.sort_by_key(|meta| std::cmp::Reverse(meta.generation));
+        let mut metas = metas.into_iter();
+        let meta = metas
+            .next()
+            .ok_or_else(|| Error::corruption("neither shadow paging meta page is valid"))?;
+        if meta.page_cnt as usize > max_pages {
+            return Err(Error::OutOfPages);
+        }
+
+        let locations = read_table(file, file_pages, &meta)?;
+        for location in locations.iter() {
+            read_page(file, *location, &mut frame)?;
+            pages.push_page(&frame)?;
+        }
+
+        // The previous commit's pages are only worth keeping if its meta page is intact
+        let previous = match metas.next() {
+            Some(previous) => match read_table(file, file_pages, &previous) {
+                Ok(locations) => locations.into_iter().chain(previous.table).collect(),
+                Err(_) => HashSet::new(),
+            },
+            None => HashSet::new(),
+        };
+        Ok(ShadowState {
+            generation: meta.generation,
+            locations,
+            table: meta.table,
+            previous,
+            file_pages: file_pages as u32,
+        })
+    }
+
+    /// Commits `pages` to `sink`, returning how many of them were written. Pages are written if
+    /// they're `dirty` or weren't part of the last commit. If the commit fails, the pages it
+    /// wrote are marked dirty again.
+    pub(crate) fn commit<P: PageFetcher, S: PageSink>(
+        &mut self,
+        pages: &P,
+        dirty: &DirtyPages,
+        sink: &S,
+    ) -> Result<usize> {
+        let mut written = Vec::new();
+        let result = self.commit_pages(pages, dirty, sink, &mut written);
+        if result.is_err() {
+            for page_no in written.iter() {
+                dirty.mark(*page_no);
+            }
+        }
+        result.map(|()| written.len())
+    }
+
+    fn commit_pages<P: PageFetcher, S: PageSink>(
+        &mut self,
+        pages: &P,
+        dirty: &DirtyPages,
+        sink: &S,
+        written: &mut Vec<u32>,
+    ) -> Result<()> {
+        let page_cnt = pages.page_cnt();
+        let table_cnt = page_cnt.div_ceil(TABLE_ENTRIES);
+        if table_cnt > MAX_TABLE_PAGES {
+            return Err(Error::OutOfPages);
+        }
+
+        let reserved = self
+            .locations
+            .iter()
+            .chain(self.table.iter())
+            .chain(self.previous.iter())
+            .copied()
+            .collect::<HashSet<_>>();
+        let mut next = META_PAGES;
+        let mut allocate = || {
+            while reserved.contains(&next) {
+                next += 1;
+            }
+            next += 1;
+            next - 1
+        };
+
+        let mut locations = Vec::with_capacity(page_cnt);
+        for page_no in 0..page_cnt {
+            let page = pages.fetch_page_read(page_no as u32)?;
+            let changed = dirty.take(page_no);
+            let location = match changed || page_no >= self.locations.len() {
+                true => {
+                    written.push(page_no as u32);
+                    let location = allocate();
+                    sink.write_page(location, &page)?;
+                    location
+                }
+                false => self.locations[page_no],
+            };
+            locations.push(location);
+        }
+
+        let mut table = Vec::with_capacity(table_cnt);
+        for chunk in locations.chunks(TABLE_ENTRIES) {
+            let mut bytes = vec![0u8; PAGE_SIZE];
+            for (idx, location) in chunk.iter().enumerate() {
+                bytes[idx * 4..idx * 4 + 4].copy_from_slice(&location.to_le_bytes());
+            }
+            let location = allocate();
+            sink.write_page(location, &Page::from_bytes(&bytes))?;
+            table.push(location);
+        }
+        sink.sync()?;
+
+        let generation = self.generation + 1;
+        let meta = Meta {
+            generation,
+            page_cnt: page_cnt as u32,
+            table,
+        };
+        sink.write_page(((generation + 1) % 2) as u32, &meta.to_page())?;
+        sink.sync()?;
+
+        // From now on, the commit before this one is the previous one, and the rest of the file
+        // past the pages either uses can go
+        self.previous = self
+            .locations
+            .iter()
+            .chain(self.table.iter())
+            .copied()
+            .collect();
+        let file_pages = self.file_pages.max(next);
+        let needed = self
+            .previous
+            .iter()
+            .chain(locations.iter())
+            .chain(meta.table.iter())
+            .map(|location| location + 1)
+            .max()
+            .unwrap_or(0)
+            .max(META_PAGES);
+        self.file_pages = match needed < file_pages {
+            true => {
+                sink.truncate(needed)?;
+                needed
+            }
+            false => file_pages,
+        };
+        self.generation = generation;
+        self.locations = locations;
+        self.table = meta.table;
+        Ok(())
+    }
+}
+
+/// The location of every page of the commit `meta` describes.
+fn read_table(file: &File, file_pages: usize, meta: &Meta) -> Result<Vec<u32>> {
+    let page_cnt = meta.page_cnt as usize;
+    if meta.table.len() != page_cnt.div_ceil(TABLE_ENTRIES) {
+        return Err(Error::corruption(format!(
+            "{} page table pages for {} pages",
+            meta.table.len(),
+            page_cnt
+        )));
+    }
+
+    let mut frame = Page::new(0);
+    let mut locations = Vec::with_capacity(page_cnt);
+    for table_page_no in meta.table.iter() {
+        let entries = TABLE_ENTRIES.min(page_cnt - locations.len());
+        if *table_page_no as usize >= file_pages {
+            return Err(Error::page_corruption(
+                *table_page_no,
+                "page table page is past the end of the file",
+            ));
+        }
+        read_page(file, *table_page_no, &mut frame)?;
+        let bytes = frame.as_bytes();
+        for idx in 0..entries {
+            let location = u32::from_le_bytes(bytes[idx * 4..idx * 4 + 4].try_into().unwrap());
+            if location < META_PAGES || location as usize >= file_pages {
+                return Err(Error::page_corruption(
+                    *table_page_no,
+                    format!(
+                        "page {} is stored at invalid page {}",
+                        locations.len(),
+                        location
+                    ),
+                ));
+            }
+            locations.push(location);
+        }
+    }
+    Ok(locations)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::checksum::crc32c;
+    use crate::database::Database;
+    use crate::database::Options;
+    use crate::error::Error;
+    use crate::file_page_fetcher::FilePageFetcher;
+    use crate::page::Page;
+    use crate::page::PAGE_SIZE;
+    use crate::page_fetcher::PageFetcher;
+    use std::fs;
+    use std::fs::OpenOptions;
+    use std::io::Seek;
+    use std::io::SeekFrom;
+    use std::io::Write;
+
+    #[test]
+    fn torn_meta_page_falls_back() {
+        let path = std::env::temp_dir().join(format!("johndb-shadow-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let options = Options {
+            shadow_paging: true,
+            autovacuum_threshold: None,
+            ..Options::default()
+        };
+
+        // Creating the file commits generation 1, so the flushes commit 2 and 3
+        let mut db = Database::open(&path, options.clone()).unwrap();
+        for i in 0..500u32 {
+            db.put(&i.to_be_bytes(), &[1; 100]).unwrap();
+        }
+        db.flush().unwrap();
+        let file_len = fs::metadata(&path).unwrap().len();
+        for round in 0..20u8 {
+            db.put(&7u32.to_be_bytes(), &[round; 100]).unwrap();
+            db.flush().unwrap();
+        }
+        drop(db);
+        // Old copies of the changed pages are reused rather than piling up
+        assert!(fs::metadata(&path).unwrap().len() <= file_len + 4 * PAGE_SIZE as u64);
+
+        let db = Database::open(&path, options.clone()).unwrap();
+        assert_eq!(db.get(&7u32.to_be_bytes()).unwrap(), Some(vec![19; 100]));
+        drop(db);
+
+        // Generation 22 is in page 1
+        let mut file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(PAGE_SIZE as u64 + 20)).unwrap();
+        file.write_all(&[0xFF]).unwrap();
+        drop(file);
+        let db = Database::open(&path, options).unwrap();
+        assert_eq!(db.get(&7u32.to_be_bytes()).unwrap(), Some(vec![18; 100]));
+        assert!(db.check().unwrap().is_ok());
+        assert_eq!(db.range(..).unwrap().count(), 500);
+
+        fs::remove_file(&path).unwrap();
+    }
+
+    /// Flips bits of `bytes` starting at `offset` so that its CRC-32C stays the same. The CRC of
+    /// the flipped bits is linear in them, so some of any 33 bits add up to no change.
+    fn flip_keeping_crc(bytes: &mut [u8], offset: usize) {
+        let crc = crc32c(bytes);
+        let crc_change = |bit: usize| {
+            let mut flipped = bytes.to_vec();
+            flipped[offset + bit / 8] ^= 1 << (bit % 8);
+            crc32c(&flipped) ^ crc
+        };
+        // Changes with distinct leading bits, highest first, and the bits making them up
+        let mut basis: Vec<(u32, u64)> = Vec::new();
+        for bit in 0..64 {
+            let (mut change, mut bits) = (crc_change(bit), 1u64 << bit);
+            for (basis_change, basis_bits) in basis.iter() {
+                if change ^ basis_change < change {
+                    change ^= basis_change;
+                    bits ^= basis_bits;
+                }
+            }
+            if change == 0 {
+                for bit in (0..64).filter(|bit| bits & (1 << bit) != 0) {
+                    bytes[offset + bit / 8] ^= 1 << (bit % 8);
+                }
+                return;
+            }
+            basis.push((change, bits));
+            basis.sort_by_key(|(change, _)| std::cmp::Reverse(*change));
+        }
+        unreachable!("64 changes of 32 bits are linearly dependent");
+    }
+
+    #[test]
+    fn changes_keeping_the_checksum_are_committed() {
+        let path = std::env::temp_dir().join(format!("johndb-shadow-crc-{}", std::process::id()));
+        let _ = fs::remove_file(&path);
+        let options = Options {
+            shadow_paging: true,
+            ..Options::default()
+        };
+        let mut db = Database::open(&path, options).unwrap();
+        db.put(b"key", b"value").unwrap();
+        db.flush().unwrap();
+
+        let page_fetcher = db.page_fetcher();
+        let mut bytes = page_fetcher.fetch_page_read(0).unwrap().as_bytes().to_vec();
+        let crc = crc32c(&bytes);
+        flip_keeping_crc(&mut bytes, PAGE_SIZE / 2);
+        assert_eq!(crc32c(&bytes), crc);
+        **page_fetcher.fetch_page_write(0).unwrap() = Page::from_bytes(&bytes);
+        db.flush().unwrap();
+        drop(db);
+
+        // Files holding more pages than allowed are rejected up front, as in place ones are
+        let opened = FilePageFetcher::open(&path, false, 1, None, false);
+        assert!(matches!(opened, Err(Error::OutOfPages)));
+        let page_fetcher = FilePageFetcher::open(&path, false, 16, None, false).unwrap();
+        assert_eq!(
+            page_fetcher.fetch_page_read(0).unwrap().as_bytes(),
+            &bytes[..]
+        );
+
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,373 @@
+//! A page fetcher for deterministic simulation testing: it wraps another fetcher and, driven by a
+//! seeded RNG, injects the failures a real disk would produce.
+//!
+//! Writes are delayed: pages are only made durable by `sync`, and a crash throws away everything
+//! written since. Each `sync` is atomic, so a crash never leaves a mix of old and new pages behind.
+
+use crate::error::Error;
+use crate::error::Result;
+use crate::metrics::Metrics;
+use crate::page::Page;
+use crate::page_fetcher::InMemoryPageFetcher;
+use crate::page_fetcher::PageFetcher;
+use crate::page_fetcher::PagePtr;
+use log::debug;
+use std::cell::Cell;
+use std::cell::RefCell;
+use std::io;
+use std::sync::RwLockReadGuard;
+use std::sync::RwLockWriteGuard;
+
+#[derive(Debug, Clone, Default)]
+pub struct SimOptions {
+    /// Seeds the RNG deciding which calls fail, so a run can be replayed exactly.
+    pub seed: u64,
+    /// Chance of `new_page` failing as if the disk were full.
+    pub no_space_rate: f64,
+    /// Chance of `fetch_page_read` failing as if the page had been cut short.
+    pub short_read_rate: f64,
+    /// Crash on the given write, counting from 1, where each `fetch_page_write` and `new_page`
+    /// is a write. Every call after the crash fails.
+    pub crash_at_write: Option<u64>,
+}
+
+/// Counts of the calls made to a `SimPageFetcher` and the faults it injected.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct SimStats {
+    pub reads: u64,
+    pub writes: u64,
+    pub syncs: u64,
+    pub short_reads: u64,
+    pub no_space: u64,
+    pub crashed: bool,
+}
+
+pub struct SimPageFetcher<F: PageFetcher> {
+    inner: F,
+    options: SimOptions,
+    rng: Cell<SimRng>,
+    faults_enabled: Cell<bool>,
+    stats: RefCell<SimStats>,
+    /// The pages as of the last `sync`.
+    durable: RefCell<Vec<Page>>,
+}
+
+impl<F: PageFetcher> SimPageFetcher<F> {
+    /// Wraps `inner`, whose current pages are taken to be durable already.
+    pub fn new(inner: F, options: SimOptions) -> Result<Self> {
+        let fetcher = SimPageFetcher {
+            rng: Cell::new(SimRng::new(options.seed)),
+            inner,
+            options,
+            faults_enabled: Cell::new(true),
+            stats: RefCell::new(SimStats::default()),
+            durable: RefCell::new(Vec::new()),
+        };
+        fetcher.snapshot()?;
+        Ok(fetcher)
+    }
+
+    /// Turns fault injection on or off, e.g. to check invariants without tripping over injected
+    /// read failures. A crashed fetcher stays crashed.
+    pub fn set_faults_enabled(&self, enabled: bool) {
+        self.faults_enabled.set(enabled);
+    }
+
+    /// Makes every page written so far durable.
+    pub fn sync(&self) -> Result<()> {
+        self.check_crashed()?;
+        self.snapshot()?;
+        self.stats.borrow_mut().syncs += 1;
+        Ok(())
+    }
+
+    /// Crashes now, as if the process died. Every call after this fails.
+    pub fn crash(&self) {
+        debug!("Simulated crash");
+        self.stats.borrow_mut().crashed = true;
+    }
+
+    pub fn is_crashed(&self) -> bool {
+        self.stats.borrow().crashed
+    }
+
+    pub fn stats(&self) -> SimStats {
+        self.stats.borrow().clone()
+    }
+
+    /// What a restart would find on disk: a fetcher holding the pages as of the last `sync`.
+    pub fn recover(&self, capacity: usize) -> Result<InMemoryPageFetcher> {
+        let fetcher = InMemoryPageFetcher::with_capacity(capacity);
+        for page in self.durable.borrow().iter() {
+            fetcher.push_page(page)?;
+        }
+        Ok(fetcher)
+    }
+
+    fn snapshot(&self) -> Result<()> {
+        let mut durable = Vec::new();
+        loop {
+            match self.inner.fetch_page_read(durable.len() as u32) {
+                Ok(page) => durable.push(**page),
+                Err(Error::PageNotFound(_)) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        debug!("Synced {} pages", durable.len());
+        *self.durable.borrow_mut() = durable;
+        Ok(())
+    }
+
+    fn check_crashed(&self) -> Result<()> {
+        if self.is_crashed() {
+            return Err(io::Error::other("simulated crash").into());
+        }
+        Ok(())
+    }
+
+    /// Rolls the RNG for a fault that happens with probability `rate`.
+    fn inject(&self, rate: f64) -> bool {
+        if !self.faults_enabled.get() || rate <= 0.0 {
+            return false;
+        }
+        let mut rng = self.rng.get();
+        let hit = rng.next_f64() < rate;
+        self.rng.set(rng);
+        hit
+    }
+
+    fn count_write(&self) -> Result<()> {
+        self.check_crashed()?;
+        let writes = {
+            let mut stats = self.stats.borrow_mut();
+            stats.writes += 1;
+            stats.writes
+        };
+        if self.faults_enabled.get() && self.options.crash_at_write == Some(writes) {
+            self.crash();
+            self.check_crashed()?;
+        }
+        Ok(())
+    }
+}
+
+impl<F: PageFetcher> PageFetcher for SimPageFetcher<F> {
+    fn fetch_page_read(&self, page_no: u32) -> Result<RwLockReadGuard<'_, PagePtr>> {
+        self.check_crashed()?;
+        self.stats.borrow_mut().reads += 1;
+        if self.inject(self.options.short_read_rate) {
+            self.stats.borrow_mut().short_reads += 1;
+            return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+        }
+        self.inner.fetch_page_read(page_no)
+    }
+
+    fn fetch_page_write(&self, page_no: u32) -> Result<RwLockWriteGuard<'_, PagePtr>> {
+        self.count_write()?;
+        self.inner.fetch_page_write(page_no)
+    }
+
+    fn new_page<T: Sized>(&self, special_data: T) -> Result<(u32, RwLockWriteGuard<'_, PagePtr>)> {
+        self.count_write()?;
+        if self.inject(self.options.no_space_rate) {
+            self.stats.borrow_mut().no_space += 1;
+            return Err(io::Error::from(io::ErrorKind::StorageFull).into());
+        }
+        self.inner.new_page(special_data)
+    }
+
+    fn free_page(&self, page_no: u32) -> Result<()> {
+        self.count_write()?;
+        self.inner.free_page(page_no)
+    }
+
+    fn check_room(&self, page_cnt: usize) -> Result<()> {
+        self.inner.check_room(page_cnt)
+    }
+
+    fn page_cnt(&self) -> usize {
+        self.inner.page_cnt()
+    }
+
+    fn metrics(&self) -> &Metrics {
+        self.inner.metrics()
+    }
+}
+
+/// splitmix64, which is plenty for picking faults and keeps runs reproducible across platforms.
+#[derive(Debug, Clone, Copy)]
+struct SimRng {
+    state: u64,
+}
+
+impl SimRng {
+    fn new(seed: u64) -> Self {
+        SimRng { state: seed }
+    }
+
+    fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    fn next_f64(&mut self) -> f64 {
+        (self.next_u64() >> 11) as f64 / (1u64 << 53) as f64
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SimOptions;
+    use super::SimPageFetcher;
+    use super::SimRng;
+    use crate::btree::key::KeyBytes;
+    use crate::btree::value::ValueBytes;
+    use crate::btree::BTree;
+    use crate::error::Error;
+    use crate::page_fetcher::InMemoryPageFetcher;
+    use crate::page_fetcher::PageFetcher;
+    use std::collections::BTreeMap;
+
+    const CAPACITY: usize = 256;
+
+    type Model = BTreeMap<Vec<u8>, Vec<u8>>;
+    type SimTree = BTree<KeyBytes, ValueBytes, SimPageFetcher<InMemoryPageFetcher>>;
+
+    /// Checks the tree against `model` with faults turned off.
+    fn check(btree: &SimTree, model: &Model) {
+        btree.page_fetcher().set_faults_enabled(false);
+        let report = btree.verify().unwrap();
+        assert!(report.is_ok(), "{:?}", report.violations);
+        let entries = btree
+            .range(..)
+            .unwrap()
+            .map(|entry| {
+                let (key, value) = entry.unwrap();
+                (key.key, value.value)
+            })
+            .collect::<Model>();
+        assert_eq!(&entries, model);
+        btree.page_fetcher().set_faults_enabled(true);
+    }
+
+    /// Runs a random workload through a series of crashes. After each crash the tree is rebuilt
+    /// from the last synced pages, and must be intact and hold exactly what was last synced.
+    ///
+    /// The tree can't roll back a half-applied change, so a failed insert or delete is handled
+    /// as a crash too. Failed lookups leave the tree untouched, so the workload carries on.
+    fn run_workload(seed: u64) -> u64 {
+        let mut rng = SimRng::new(seed);
+        let mut synced = Model::new();
+        let mut fetcher = InMemoryPageFetcher::with_capacity(CAPACITY);
+        let mut crashes = 0;
+
+        for _ in 0..20 {
+            let options = SimOptions {
+                seed: rng.next_u64(),
+                no_space_rate: 0.005,
+                short_read_rate: 0.005,
+                crash_at_write: Some(1 + rng.next_u64() % 500),
+            };
+            let sim = SimPageFetcher::new(fetcher, options).unwrap();
+            // Faults are turned back on once the recovered tree has been checked
+            sim.set_faults_enabled(false);
+            let mut btree: SimTree = BTree::new(sim).unwrap();
+            check(&btree, &synced);
+
+            let mut model = synced.clone();
+            loop {
+                let key = vec![0, (rng.next_u64() % 200) as u8];
+                let result = match rng.next_u64() % 10 {
+                    0..=4 => {
+                        let value = vec![key[1]; (rng.next_u64() % 400) as usize];
+                        btree
+                            .insert_if_absent(
+                                KeyBytes { key: key.clone() },
+                                ValueBytes {
+                                    value: value.clone(),
+                                },
+                            )
+                            .map(|_| {
+                                model.entry(key).or_insert(value);
+                            })
+                    }
+                    5..=6 => btree.delete(KeyBytes { key: key.clone() }).map(|deleted| {
+                        assert_eq!(deleted.map(|value| value.value), model.remove(&key));
+                    }),
+                    7..=8 => match btree.search(KeyBytes { key: key.clone() }) {
+                        Ok(found) => {
+                            assert_eq!(
+                                found.value.map(|value| value.value),
+                                model.get(&key).cloned()
+                            );
+                            Ok(())
+                        }
+                        Err(err) => {
+                            assert!(matches!(err.root_cause(), Error::Io(_)), "{}", err);
+                            assert!(!btree.page_fetcher().is_crashed());
+                            continue;
+                        }
+                    },
+                    _ => btree.page_fetcher().sync().map(|_| synced = model.clone()),
+                };
+
+                if let Err(err) = result {
+                    assert!(matches!(err.root_cause(), Error::Io(_)), "{}", err);
+                    btree.page_fetcher().crash();
+                    break;
+                }
+            }
+
+            crashes += 1;
+            fetcher = btree.page_fetcher().recover(CAPACITY).unwrap();
+        }
+
+        let btree: SimTree =
+            BTree::new(SimPageFetcher::new(fetcher, SimOptions::default()).unwrap()).unwrap();
+        check(&btree, &synced);
+        assert!(!synced.is_empty());
+        crashes
+    }
+
+    #[test]
+    fn recovers_from_faults() {
+        for seed in 0..10 {
+            assert_eq!(run_workload(seed), 20);
+        }
+        // The same seed replays the same run
+        assert_eq!(run_workload(7), run_workload(7));
+    }
+
+    #[test]
+    fn injects_faults() {
+        let sim = SimPageFetcher::new(
+            InMemoryPageFetcher::new(),
+            SimOptions {
+                seed: 1,
+                short_read_rate: 1.0,
+                crash_at_write: Some(3),
+                ..SimOptions::default()
+            },
+        )
+        .unwrap();
+        drop(sim.new_page(0u64).unwrap());
+        sim.sync().unwrap();
+        drop(sim.new_page(0u64).unwrap());
+        assert!(matches!(sim.fetch_page_read(0), Err(Error::Io(_))));
+
+        sim.set_faults_enabled(false);
+        drop(sim.fetch_page_read(1).unwrap());
+        sim.set_faults_enabled(true);
+        assert!(matches!(sim.fetch_page_write(0), Err(Error::Io(_))));
+        assert!(sim.is_crashed());
+        assert!(matches!(sim.sync(), Err(Error::Io(_))));
+
+        let stats = sim.stats();
+        assert_eq!((stats.writes, stats.syncs, stats.short_reads), (3, 1, 1));
+        // Only the synced page survives the crash
+        assert_eq!(sim.recover(16).unwrap().page_cnt(), 1);
+    }
+}
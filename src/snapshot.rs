@@ -0,0 +1,93 @@
+//! Self-contained copies of a database's file, see `Database::export_snapshot`. There's no
+//! write-ahead log yet, so a database's pages are its whole state and a snapshot is just those
+//! pages, each with a checksum so that a damaged copy is caught before it's opened.
+//!
+//! All integers are big-endian. The file consists of:
+//!
+//! * A header: the magic bytes `JOHNSNAP`, the format version as a u32, the page size as a u32
+//!   and the number of pages as a u64, then a CRC-32 of the header.
+//! * Every page in order, each followed by a CRC-32 of its bytes.
+
+use crate::checksum::crc32;
+use crate::error::Error;
+use crate::error::Result;
+use crate::export::read_vec;
+use crate::page::PAGE_SIZE;
+use crate::page_fetcher::PageFetcher;
+use std::convert::TryInto;
+use std::io::Read;
+use std::io::Write;
+
+const MAGIC: &[u8; 8] = b"JOHNSNAP";
+pub const FORMAT_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 24;
+
+/// Writes the first `page_cnt` pages of `page_fetcher` to `writer`.
+pub(crate) fn write_snapshot<P, W>(page_fetcher: &P, page_cnt: usize, mut writer: W) -> Result<()>
+where
+    P: PageFetcher,
+    W: Write,
+{
+    let mut header = MAGIC.to_vec();
+    header.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+    header.extend_from_slice(&(PAGE_SIZE as u32).to_be_bytes());
+    header.extend_from_slice(&(page_cnt as u64).to_be_bytes());
+    header.extend_from_slice(&crc32(&header).to_be_bytes());
+    writer.write_all(&header)?;
+
+    for page_no in 0..page_cnt {
+        let page = page_fetcher.fetch_page_read(page_no as u32)?;
+        writer.write_all(page.as_bytes())?;
+        writer.write_all(&crc32(page.as_bytes()).to_be_bytes())?;
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Checks a snapshot read from `reader`, copying its pages to `writer` as they'd be laid out in
+/// a database file. Returns the number of pages copied.
+pub(crate) fn read_snapshot<R, W>(mut reader: R, mut writer: W) -> Result<u64>
+where
+    R: Read,
+    W: Write,
+{
+    let header = read_vec(&mut reader, HEADER_SIZE)?;
+    if &header[..MAGIC.len()] != MAGIC {
+        return Err(Error::corruption("not a johndb snapshot".to_string()));
+    }
+    check_crc(&mut reader, &header, "header")?;
+    let version = u32::from_be_bytes(header[8..12].try_into().unwrap());
+    if version != FORMAT_VERSION {
+        return Err(Error::corruption(format!(
+            "unsupported snapshot format version {}",
+            version
+        )));
+    }
+    let page_size = u32::from_be_bytes(header[12..16].try_into().unwrap());
+    if page_size as usize != PAGE_SIZE {
+        return Err(Error::corruption(format!(
+            "snapshot has {} byte pages, expected {}",
+            page_size, PAGE_SIZE
+        )));
+    }
+
+    let page_cnt = u64::from_be_bytes(header[16..].try_into().unwrap());
+    for _ in 0..page_cnt {
+        let page = read_vec(&mut reader, PAGE_SIZE)?;
+        check_crc(&mut reader, &page, "page")?;
+        writer.write_all(&page)?;
+    }
+    writer.flush()?;
+    Ok(page_cnt)
+}
+
+fn check_crc<R: Read>(reader: &mut R, bytes: &[u8], what: &str) -> Result<()> {
+    let crc = read_vec(reader, 4)?;
+    if u32::from_be_bytes(crc[..].try_into().unwrap()) != crc32(bytes) {
+        return Err(Error::corruption(format!(
+            "snapshot {} checksum mismatch",
+            what
+        )));
+    }
+    Ok(())
+}
@@ -0,0 +1,871 @@
+//! A tiny subset of SQL on top of named trees, enabled by the `sql` feature:
+//!
+//! ```text
+//! CREATE TABLE users (id u64 PRIMARY KEY, name text NOT NULL, age i32)
+//! INSERT INTO users VALUES (1, 'Ada', NULL)
+//! SELECT name, age FROM users WHERE id = 1
+//! SELECT name FROM users WHERE age + 1 >= 18 AND NOT name = 'Bob'
+//! DELETE FROM users WHERE id = 1
+//! ```
+//!
+//! Every table is a named tree with a `TreeSchema`, keyed by its first column. A `WHERE` clause
+//! that only compares that column for equality is looked up by key, any other condition is
+//! compiled to an `Expr` and evaluated against every row. Rows are encoded with `row::Schema`.
+//! Column types are named after `ColumnType`s, with a few of the usual SQL aliases such as `int`
+//! and `varchar`. Negative numbers are written without a space after the `-`, which is otherwise
+//! read as subtraction.
+
+use crate::catalog::NamedTree;
+use crate::catalog::TreeOptions;
+use crate::catalog::TreeSchema;
+use crate::database::Database;
+use crate::error::Error;
+use crate::error::Result;
+use crate::expr::ArithOp;
+use crate::expr::CmpOp;
+use crate::expr::Expr;
+use crate::row::Column;
+use crate::row::ColumnType;
+use crate::row::Field;
+use crate::row::Schema;
+use lrlex::lrlex_mod;
+use lrpar::lrpar_mod;
+use std::fmt;
+
+lrlex_mod!("sql.l");
+lrpar_mod!("sql.y");
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Statement {
+    CreateTable {
+        table: String,
+        columns: Vec<ColumnDef>,
+    },
+    Insert {
+        table: String,
+        values: Vec<Literal>,
+    },
+    /// `columns` is `None` for `SELECT *`.
+    Select {
+        table: String,
+        columns: Option<Vec<String>>,
+        filter: Option<Condition>,
+    },
+    Delete {
+        table: String,
+        filter: Option<Condition>,
+    },
+}
+
+/// A `WHERE` condition as written in the statement, see `Expr` for how it's evaluated.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Condition {
+    Column(String),
+    Literal(Literal),
+    Cmp(CmpOp, Box<Condition>, Box<Condition>),
+    Arith(ArithOp, Box<Condition>, Box<Condition>),
+    And(Box<Condition>, Box<Condition>),
+    Or(Box<Condition>, Box<Condition>),
+    Not(Box<Condition>),
+    IsNull(Box<Condition>),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ColumnDef {
+    pub name: String,
+    pub type_name: String,
+    pub not_null: bool,
+    pub primary_key: bool,
+}
+
+/// A literal as written in the statement. Numbers are kept as text until the type of the column
+/// they're compared with or stored in is known.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Literal {
+    Null,
+    Bool(bool),
+    Number(String),
+    String(String),
+    /// A `?` parameter, see `prepare`.
+    Param,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum QueryResult {
+    Created,
+    Inserted,
+    Deleted(usize),
+    Rows {
+        columns: Vec<String>,
+        rows: Vec<Vec<Field>>,
+    },
+}
+
+/// Prints rows tab-separated under a header line, and a summary line for the other results.
+impl fmt::Display for QueryResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            QueryResult::Created => writeln!(f, "created"),
+            QueryResult::Inserted => writeln!(f, "inserted 1 row"),
+            QueryResult::Deleted(cnt) => writeln!(f, "deleted {} rows", cnt),
+            QueryResult::Rows { columns, rows } => {
+                writeln!(f, "{}", columns.join("\t"))?;
+                for row in rows.iter() {
+                    let fields = row.iter().map(field_to_string).collect::<Vec<_>>();
+                    writeln!(f, "{}", fields.join("\t"))?;
+                }
+                writeln!(f, "({} rows)", rows.len())
+            }
+        }
+    }
+}
+
+/// Parses a single statement, failing with `Error::Sql` if it's not valid.
+pub fn parse(sql: &str) -> Result<Statement> {
+    let lexer_def = sql_l::lexerdef();
+    let lexer = lexer_def.lexer(sql);
+    let (statement, errors) = sql_y::parse(&lexer);
+    if let Some(err) = errors.first() {
+        return Err(Error::Sql(err.pp(&lexer, &sql_y::token_epp)));
+    }
+    match statement {
+        Some(Ok(statement)) => Ok(statement),
+        _ => Err(Error::Sql("couldn't parse statement".to_string())),
+    }
+}
+
+/// Parses and runs a single statement against `db`. Statements with parameters have to be
+/// prepared instead.
+pub fn execute(db: &Database, sql: &str) -> Result<QueryResult> {
+    prepare(db, sql)?.execute(db, &[])
+}
+
+/// Parses `sql` and resolves the table and columns it refers to, so that it can be executed many
+/// times without doing either again. Parameters are written as `?` wherever a literal may appear,
+/// and are bound by position when executing.
+pub fn prepare(db: &Database, sql: &str) -> Result<Prepared> {
+    let mut param_types = Vec::new();
+    let (table, schema, operation) = match parse(sql)? {
+        Statement::CreateTable { table, columns } => (table, None, Operation::CreateTable(columns)),
+        Statement::Insert { table, values } => {
+            let schema = table_schema(db, &table)?;
+            let columns = schema.columns.columns();
+            if values.len() != columns.len() {
+                return Err(Error::Sql(format!(
+                    "{} values for {} columns",
+                    values.len(),
+                    columns.len()
+                )));
+            }
+            let values = columns
+                .iter()
+                .zip(values.iter())
+                .map(|(column, value)| to_operand(column, value, &mut param_types))
+                .collect::<Result<Vec<_>>>()?;
+            (table, Some(schema), Operation::Insert(values))
+        }
+        Statement::Select {
+            table,
+            columns,
+            filter,
+        } => {
+            let schema = table_schema(db, &table)?;
+            let names = columns.unwrap_or_else(|| {
+                schema
+                    .columns
+                    .columns()
+                    .iter()
+                    .map(|column| column.name.clone())
+                    .collect()
+            });
+            let idxs = names
+                .iter()
+                .map(|name| column_idx(&schema, name))
+                .collect::<Result<Vec<_>>>()?;
+            let (key, filter) = where_clause(&schema, filter, &mut param_types)?;
+            let operation = Operation::Select {
+                columns: names,
+                idxs,
+                key,
+                filter,
+            };
+            (table, Some(schema), operation)
+        }
+        Statement::Delete { table, filter } => {
+            let schema = table_schema(db, &table)?;
+            let (key, filter) = where_clause(&schema, filter, &mut param_types)?;
+            (table, Some(schema), Operation::Delete { key, filter })
+        }
+    };
+
+    Ok(Prepared {
+        table,
+        schema,
+        param_types,
+        operation,
+    })
+}
+
+/// A statement resolved by `prepare`.
+#[derive(Debug, Clone)]
+pub struct Prepared {
+    table: String,
+    /// The table's schema when the statement was prepared, `None` for `CREATE TABLE`.
+    schema: Option<TreeSchema>,
+    /// The type of each parameter, taken from the column it's stored in or compared with.
+    param_types: Vec<ColumnType>,
+    operation: Operation,
+}
+
+#[derive(Debug, Clone)]
+enum Operation {
+    CreateTable(Vec<ColumnDef>),
+    Insert(Vec<Operand>),
+    /// `key` is set if the rows are looked up by primary key, and `filter` if they're filtered
+    /// on anything else.
+    Select {
+        columns: Vec<String>,
+        idxs: Vec<usize>,
+        key: Option<Operand>,
+        filter: Option<Expr>,
+    },
+    Delete {
+        key: Option<Operand>,
+        filter: Option<Expr>,
+    },
+}
+
+#[derive(Debug, Clone)]
+enum Operand {
+    Field(Field),
+    Param(usize),
+}
+
+impl Prepared {
+    pub fn param_types(&self) -> &[ColumnType] {
+        &self.param_types
+    }
+
+    /// Runs the statement with `params` bound to its parameters. Fails with
+    /// `Error::TypeMismatch` if the table was since recreated with a different schema.
+    pub fn execute(&self, db: &Database, params: &[Field]) -> Result<QueryResult> {
+        if params.len() != self.param_types.len() {
+            return Err(Error::Sql(format!(
+                "{} parameters given, {} expected",
+                params.len(),
+                self.param_types.len()
+            )));
+        }
+        for (idx, (param, column_type)) in params.iter().zip(self.param_types.iter()).enumerate() {
+            if *param != Field::Null && !param.matches(*column_type) {
+                return Err(Error::TypeMismatch(format!(
+                    "parameter {} is {:?}, expected {:?}",
+                    idx + 1,
+                    param,
+                    column_type
+                )));
+            }
+        }
+        let bind = |operand: &Operand| match operand {
+            Operand::Field(field) => field.clone(),
+            Operand::Param(idx) => params[*idx].clone(),
+        };
+
+        let schema = match (&self.operation, &self.schema) {
+            (Operation::CreateTable(columns), _) => {
+                return create_table(db, &self.table, columns.clone())
+            }
+            (_, Some(schema)) => schema,
+            (_, None) => unreachable!("only CREATE TABLE is prepared without a schema"),
+        };
+        let mut tree = db.open_tree_with_schema(&self.table, schema)?;
+        match &self.operation {
+            Operation::CreateTable(_) => unreachable!(),
+            Operation::Insert(values) => {
+                let fields = values.iter().map(bind).collect::<Vec<_>>();
+                tree.put(&key_bytes(&fields[0])?, &schema.columns.encode(&fields)?)?;
+                Ok(QueryResult::Inserted)
+            }
+            Operation::Select {
+                columns,
+                idxs,
+                key,
+                filter,
+            } => {
+                let key = key.as_ref().map(|key| key_bytes(&bind(key))).transpose()?;
+                let filter = filter
+                    .as_ref()
+                    .map(|filter| filter.bind(params))
+                    .transpose()?;
+                let rows = matching_rows(&tree, &schema.columns, key, filter.as_ref())?
+                    .into_iter()
+                    .map(|(_, row)| {
+                        let row = schema.columns.row_ref(&row)?;
+                        idxs.iter().map(|idx| row.get(*idx)).collect()
+                    })
+                    .collect::<Result<Vec<_>>>()?;
+                Ok(QueryResult::Rows {
+                    columns: columns.clone(),
+                    rows,
+                })
+            }
+            Operation::Delete { key, filter } => {
+                let key = key.as_ref().map(|key| key_bytes(&bind(key))).transpose()?;
+                let filter = filter
+                    .as_ref()
+                    .map(|filter| filter.bind(params))
+                    .transpose()?;
+                let rows = matching_rows(&tree, &schema.columns, key, filter.as_ref())?;
+                for (key, _) in rows.iter() {
+                    tree.delete(key)?;
+                }
+                Ok(QueryResult::Deleted(rows.len()))
+            }
+        }
+    }
+}
+
+fn create_table(db: &Database, table: &str, columns: Vec<ColumnDef>) -> Result<QueryResult> {
+    if columns.iter().skip(1).any(|column| column.primary_key) {
+        return Err(Error::Sql(
+            "only the first column can be the primary key".to_string(),
+        ));
+    }
+    let columns = columns
+        .into_iter()
+        .enumerate()
+        .map(|(idx, column)| {
+            Ok(Column {
+                column_type: column_type(&column.type_name)?,
+                name: column.name,
+                nullable: idx > 0 && !column.not_null,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+
+    let schema = TreeSchema {
+        key_type: columns[0].column_type,
+        columns: Schema::new(columns),
+        unique: true,
+    };
+    db.create_tree(
+        table,
+        TreeOptions {
+            schema: Some(schema),
+            ..TreeOptions::default()
+        },
+    )?;
+    Ok(QueryResult::Created)
+}
+
+fn table_schema(db: &Database, table: &str) -> Result<TreeSchema> {
+    match db.open_tree(table)?.options().schema.clone() {
+        Some(schema) => Ok(schema),
+        None => Err(Error::Sql(format!("{} isn't a table", table))),
+    }
+}
+
+/// The `(key, row)` pairs of the rows with primary key `key`, or of every row if it's `None`, in
+/// key order. Only the rows `filter` matches are returned.
+fn matching_rows(
+    tree: &NamedTree<'_>,
+    schema: &Schema,
+    key: Option<Vec<u8>>,
+    filter: Option<&Expr>,
+) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+    let rows = match key {
+        Some(key) => tree.get(&key)?.map(|row| (key, row)).into_iter().collect(),
+        None => tree.range(..)?.collect::<Result<Vec<_>>>()?,
+    };
+    let filter = match filter {
+        Some(filter) => filter,
+        None => return Ok(rows),
+    };
+    let mut matching = Vec::new();
+    for (key, row) in rows {
+        if filter.matches(&schema.row_ref(&row)?)? {
+            matching.push((key, row));
+        }
+    }
+    Ok(matching)
+}
+
+/// Resolves a `WHERE` clause into the primary key to look up, if the condition only compares the
+/// key column for equality, or the expression to filter every row with otherwise.
+fn where_clause(
+    schema: &TreeSchema,
+    condition: Option<Condition>,
+    param_types: &mut Vec<ColumnType>,
+) -> Result<(Option<Operand>, Option<Expr>)> {
+    let condition = match condition {
+        Some(condition) => condition,
+        None => return Ok((None, None)),
+    };
+    let key_column = &schema.columns.columns()[0];
+    if let Condition::Cmp(CmpOp::Eq, lhs, rhs) = &condition {
+        let is_key =
+            |side: &Condition| matches!(side, Condition::Column(name) if *name == key_column.name);
+        match (lhs.as_ref(), rhs.as_ref()) {
+            (Condition::Literal(value), side) | (side, Condition::Literal(value))
+                if is_key(side) =>
+            {
+                let key = to_operand(key_column, value, param_types)?;
+                return Ok((Some(key), None));
+            }
+            _ => {}
+        }
+    }
+    let filter = compile(schema, &condition, None, param_types)?;
+    Ok((None, Some(filter)))
+}
+
+/// Compiles `condition` into an expression over the table's columns. Literals and parameters
+/// take the type of the column they're compared or combined with, or `hint` if there's none.
+fn compile(
+    schema: &TreeSchema,
+    condition: &Condition,
+    hint: Option<ColumnType>,
+    param_types: &mut Vec<ColumnType>,
+) -> Result<Expr> {
+    let expr = match condition {
+        Condition::Column(name) => Expr::Column(column_idx(schema, name)?),
+        Condition::Literal(Literal::Param) => match hint {
+            Some(column_type) => {
+                param_types.push(column_type);
+                Expr::Param(param_types.len() - 1)
+            }
+            None => {
+                return Err(Error::Sql(
+                    "parameters must be compared or combined with a column".to_string(),
+                ))
+            }
+        },
+        Condition::Literal(literal) => Expr::Const(untyped_field(literal, hint)?),
+        Condition::Cmp(op, lhs, rhs) => {
+            let hint = type_of(schema, lhs).or_else(|| type_of(schema, rhs));
+            let lhs = compile(schema, lhs, hint, param_types)?;
+            Expr::cmp(*op, lhs, compile(schema, rhs, hint, param_types)?)
+        }
+        Condition::Arith(op, lhs, rhs) => {
+            let hint = type_of(schema, lhs)
+                .or_else(|| type_of(schema, rhs))
+                .or(hint);
+            let lhs = compile(schema, lhs, hint, param_types)?;
+            Expr::arith(*op, lhs, compile(schema, rhs, hint, param_types)?)
+        }
+        Condition::And(lhs, rhs) => {
+            let lhs = compile(schema, lhs, Some(ColumnType::Bool), param_types)?;
+            lhs.and(compile(schema, rhs, Some(ColumnType::Bool), param_types)?)
+        }
+        Condition::Or(lhs, rhs) => {
+            let lhs = compile(schema, lhs, Some(ColumnType::Bool), param_types)?;
+            lhs.or(compile(schema, rhs, Some(ColumnType::Bool), param_types)?)
+        }
+        Condition::Not(condition) => {
+            !compile(schema, condition, Some(ColumnType::Bool), param_types)?
+        }
+        Condition::IsNull(condition) => compile(schema, condition, None, param_types)?.is_null(),
+    };
+    Ok(expr)
+}
+
+/// The type of the column `condition` refers to, if any.
+fn type_of(schema: &TreeSchema, condition: &Condition) -> Option<ColumnType> {
+    match condition {
+        Condition::Column(name) => schema
+            .columns
+            .column_idx(name)
+            .map(|idx| schema.columns.columns()[idx].column_type),
+        Condition::Arith(_, lhs, rhs) => type_of(schema, lhs).or_else(|| type_of(schema, rhs)),
+        _ => None,
+    }
+}
+
+/// Converts `literal` to a field of type `hint`, or of the type it's written as if there's no
+/// hint: numbers become `I64`s, or `F64`s if they have a fraction.
+fn untyped_field(literal: &Literal, hint: Option<ColumnType>) -> Result<Field> {
+    let field = match (hint, literal) {
+        (Some(column_type), literal) => literal_field(column_type, literal),
+        (None, Literal::Null) => Some(Field::Null),
+        (None, Literal::Bool(value)) => Some(Field::Bool(*value)),
+        (None, Literal::Number(text)) => text
+            .parse()
+            .map(Field::I64)
+            .or_else(|_| text.parse().map(Field::F64))
+            .ok(),
+        (None, Literal::String(text)) => Some(Field::Text(text.clone())),
+        (None, Literal::Param) => None,
+    };
+    field.ok_or_else(|| match hint {
+        Some(column_type) => {
+            Error::TypeMismatch(format!("{:?} isn't a valid {:?}", literal, column_type))
+        }
+        None => Error::TypeMismatch(format!("{:?} isn't a valid literal", literal)),
+    })
+}
+
+/// Converts `literal` to an operand of `column`'s type, adding a parameter of that type if it's
+/// a `?`.
+fn to_operand(
+    column: &Column,
+    literal: &Literal,
+    param_types: &mut Vec<ColumnType>,
+) -> Result<Operand> {
+    if *literal == Literal::Param {
+        param_types.push(column.column_type);
+        return Ok(Operand::Param(param_types.len() - 1));
+    }
+    to_field(column, literal).map(Operand::Field)
+}
+
+fn column_idx(schema: &TreeSchema, name: &str) -> Result<usize> {
+    schema
+        .columns
+        .column_idx(name)
+        .ok_or_else(|| Error::Sql(format!("no column {}", name)))
+}
+
+fn column_type(type_name: &str) -> Result<ColumnType> {
+    let column_type = match type_name.to_ascii_lowercase().as_str() {
+        "bool" | "boolean" => ColumnType::Bool,
+        "i32" | "int" | "integer" => ColumnType::I32,
+        "i64" | "bigint" => ColumnType::I64,
+        "u32" => ColumnType::U32,
+        "u64" => ColumnType::U64,
+        "f64" | "double" | "real" => ColumnType::F64,
+        "bytes" | "blob" => ColumnType::Bytes,
+        "text" | "varchar" => ColumnType::Text,
+        _ => return Err(Error::Sql(format!("unknown type {}", type_name))),
+    };
+    Ok(column_type)
+}
+
+/// Converts `literal` to a field of `column`'s type. NULLs are left to `Schema::encode` to check.
+fn to_field(column: &Column, literal: &Literal) -> Result<Field> {
+    literal_field(column.column_type, literal).ok_or_else(|| {
+        Error::TypeMismatch(format!(
+            "{:?} doesn't fit column {} of type {:?}",
+            literal, column.name, column.column_type
+        ))
+    })
+}
+
+/// Converts `literal` to a field of `column_type`, or `None` if it isn't one.
+fn literal_field(column_type: ColumnType, literal: &Literal) -> Option<Field> {
+    match (column_type, literal) {
+        (_, Literal::Null) => Some(Field::Null),
+        (ColumnType::Bool, Literal::Bool(value)) => Some(Field::Bool(*value)),
+        (ColumnType::I32, Literal::Number(text)) => text.parse().ok().map(Field::I32),
+        (ColumnType::I64, Literal::Number(text)) => text.parse().ok().map(Field::I64),
+        (ColumnType::U32, Literal::Number(text)) => text.parse().ok().map(Field::U32),
+        (ColumnType::U64, Literal::Number(text)) => text.parse().ok().map(Field::U64),
+        (ColumnType::F64, Literal::Number(text)) => text.parse().ok().map(Field::F64),
+        (ColumnType::Text, Literal::String(text)) => Some(Field::Text(text.clone())),
+        (ColumnType::Bytes, Literal::String(text)) => Some(Field::Bytes(text.clone().into_bytes())),
+        _ => None,
+    }
+}
+
+/// Encodes a key column's field the way `ColumnType::check_value` expects it.
+fn key_bytes(field: &Field) -> Result<Vec<u8>> {
+    let bytes = match field {
+        Field::Null => {
+            return Err(Error::TypeMismatch(
+                "the primary key can't be NULL".to_string(),
+            ))
+        }
+        Field::Bool(value) => vec![*value as u8],
+        Field::I32(value) => value.to_be_bytes().to_vec(),
+        Field::I64(value) => value.to_be_bytes().to_vec(),
+        Field::U32(value) => value.to_be_bytes().to_vec(),
+        Field::U64(value) => value.to_be_bytes().to_vec(),
+        Field::F64(value) => value.to_be_bytes().to_vec(),
+        Field::Bytes(bytes) => bytes.clone(),
+        Field::Text(text) => text.clone().into_bytes(),
+    };
+    Ok(bytes)
+}
+
+fn field_to_string(field: &Field) -> String {
+    match field {
+        Field::Null => "NULL".to_string(),
+        Field::Bool(value) => value.to_string(),
+        Field::I32(value) => value.to_string(),
+        Field::I64(value) => value.to_string(),
+        Field::U32(value) => value.to_string(),
+        Field::U64(value) => value.to_string(),
+        Field::F64(value) => value.to_string(),
+        Field::Bytes(bytes) => String::from_utf8_lossy(bytes).into_owned(),
+        Field::Text(text) => text.clone(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::execute;
+    use super::parse;
+    use super::prepare;
+    use super::Condition;
+    use super::Literal;
+    use super::QueryResult;
+    use super::Statement;
+    use crate::database::Database;
+    use crate::database::Options;
+    use crate::error::Error;
+    use crate::expr::ArithOp;
+    use crate::expr::CmpOp;
+    use crate::row::Field;
+
+    #[test]
+    fn parses_statements() {
+        assert_eq!(
+            parse("select Name, age FROM users where id = -5;").unwrap(),
+            Statement::Select {
+                table: "users".to_string(),
+                columns: Some(vec!["Name".to_string(), "age".to_string()]),
+                filter: Some(Condition::Cmp(
+                    CmpOp::Eq,
+                    Box::new(Condition::Column("id".to_string())),
+                    Box::new(Condition::Literal(Literal::Number("-5".to_string()))),
+                )),
+            }
+        );
+        assert_eq!(
+            parse("DELETE FROM t WHERE a + 1 > 2 * b OR NOT c IS NULL").unwrap(),
+            Statement::Delete {
+                table: "t".to_string(),
+                filter: Some(Condition::Or(
+                    Box::new(Condition::Cmp(
+                        CmpOp::Gt,
+                        Box::new(Condition::Arith(
+                            ArithOp::Add,
+                            Box::new(Condition::Column("a".to_string())),
+                            Box::new(Condition::Literal(Literal::Number("1".to_string()))),
+                        )),
+                        Box::new(Condition::Arith(
+                            ArithOp::Mul,
+                            Box::new(Condition::Literal(Literal::Number("2".to_string()))),
+                            Box::new(Condition::Column("b".to_string())),
+                        )),
+                    )),
+                    Box::new(Condition::Not(Box::new(Condition::IsNull(Box::new(
+                        Condition::Column("c".to_string())
+                    ))))),
+                )),
+            }
+        );
+        assert_eq!(
+            parse("INSERT INTO t VALUES ('it''s', NULL, true, 1.5)").unwrap(),
+            Statement::Insert {
+                table: "t".to_string(),
+                values: vec![
+                    Literal::String("it's".to_string()),
+                    Literal::Null,
+                    Literal::Bool(true),
+                    Literal::Number("1.5".to_string()),
+                ],
+            }
+        );
+        for (sql, op) in [
+            ("a <> 1", CmpOp::Ne),
+            ("a < 1", CmpOp::Lt),
+            ("a <= 1", CmpOp::Le),
+            ("a >= 1", CmpOp::Ge),
+        ] {
+            assert_eq!(
+                parse(&format!("SELECT * FROM t WHERE {}", sql)).unwrap(),
+                Statement::Select {
+                    table: "t".to_string(),
+                    columns: None,
+                    filter: Some(Condition::Cmp(
+                        op,
+                        Box::new(Condition::Column("a".to_string())),
+                        Box::new(Condition::Literal(Literal::Number("1".to_string()))),
+                    )),
+                }
+            );
+        }
+        assert!(matches!(parse("SELECT FROM t"), Err(Error::Sql(_))));
+        assert!(matches!(parse("DROP TABLE t"), Err(Error::Sql(_))));
+    }
+
+    #[test]
+    fn create_insert_select_delete() {
+        let path = std::env::temp_dir().join(format!("johndb-sql-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let db = Database::open(&path, Options::default()).unwrap();
+
+        let run = |sql: &str| execute(&db, sql).unwrap();
+        assert_eq!(
+            run("CREATE TABLE users (id u64 PRIMARY KEY, name text NOT NULL, age int)"),
+            QueryResult::Created
+        );
+        for id in 0..100 {
+            let sql = format!(
+                "INSERT INTO users VALUES ({}, 'user {}', {})",
+                id,
+                id,
+                id % 50
+            );
+            assert_eq!(run(&sql), QueryResult::Inserted);
+        }
+        run("INSERT INTO users VALUES (100, 'ghost', NULL)");
+
+        assert_eq!(
+            run("SELECT age, name FROM users WHERE id = 70"),
+            QueryResult::Rows {
+                columns: vec!["age".to_string(), "name".to_string()],
+                rows: vec![vec![Field::I32(20), Field::Text("user 70".to_string())]],
+            }
+        );
+        assert_eq!(
+            run("SELECT * FROM users WHERE id = 100").to_string(),
+            "id\tname\tage\n100\tghost\tNULL\n(1 rows)\n"
+        );
+        assert_eq!(
+            run("DELETE FROM users WHERE id = 3"),
+            QueryResult::Deleted(1)
+        );
+        assert_eq!(
+            run("DELETE FROM users WHERE id = 3"),
+            QueryResult::Deleted(0)
+        );
+        let ids = |sql: &str| -> Vec<Field> {
+            match run(sql) {
+                QueryResult::Rows { rows, .. } => {
+                    rows.into_iter().map(|row| row[0].clone()).collect()
+                }
+                result => panic!("{:?}", result),
+            }
+        };
+        assert_eq!(
+            ids("SELECT id FROM users WHERE age = 1"),
+            vec![Field::U64(1), Field::U64(51)]
+        );
+        assert_eq!(
+            ids("SELECT id FROM users WHERE age * 2 >= 96 AND NOT id < 50 OR age IS NULL"),
+            vec![Field::U64(98), Field::U64(99), Field::U64(100)]
+        );
+        assert_eq!(
+            ids("SELECT id FROM users WHERE 90 = id + 40 OR name = 'user 5'"),
+            vec![Field::U64(5), Field::U64(50)]
+        );
+        assert_eq!(
+            run("DELETE FROM users WHERE age IS NOT NULL AND age > 48"),
+            QueryResult::Deleted(2)
+        );
+        assert_eq!(
+            run("INSERT INTO users VALUES (49, 'user 49', 49)"),
+            QueryResult::Inserted
+        );
+        assert_eq!(
+            run("INSERT INTO users VALUES (99, 'user 99', 49)"),
+            QueryResult::Inserted
+        );
+        match run("SELECT id FROM users") {
+            QueryResult::Rows { rows, .. } => {
+                assert_eq!(rows.len(), 100);
+                assert_eq!(rows[3], vec![Field::U64(4)]);
+            }
+            result => panic!("{:?}", result),
+        }
+
+        let fails = |sql: &str| execute(&db, sql).unwrap_err();
+        assert!(matches!(
+            fails("INSERT INTO users VALUES (1, 'again', 1)"),
+            Error::DuplicateKey
+        ));
+        assert!(matches!(
+            fails("INSERT INTO users VALUES (200, NULL, 1)"),
+            Error::TypeMismatch(_)
+        ));
+        assert!(matches!(
+            fails("INSERT INTO users VALUES ('x', 'y', 1)"),
+            Error::TypeMismatch(_)
+        ));
+        assert!(matches!(
+            fails("SELECT * FROM users WHERE nickname = 'x'"),
+            Error::Sql(_)
+        ));
+        assert!(matches!(
+            fails("SELECT * FROM users WHERE age = 'x'"),
+            Error::TypeMismatch(_)
+        ));
+        assert!(matches!(
+            fails("SELECT * FROM orders"),
+            Error::TreeNotFound(_)
+        ));
+
+        assert_eq!(run("DELETE FROM users"), QueryResult::Deleted(100));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn prepared_statements() {
+        let path = std::env::temp_dir().join(format!("johndb-sql-prep-{}", std::process::id()));
+        let _ = std::fs::remove_file(&path);
+        let db = Database::open(&path, Options::default()).unwrap();
+        execute(&db, "CREATE TABLE kv (k u32 PRIMARY KEY, v text)").unwrap();
+
+        let insert = prepare(&db, "INSERT INTO kv VALUES (?, ?)").unwrap();
+        for k in 0..20u32 {
+            let params = [Field::U32(k), Field::Text(format!("v{}", k))];
+            assert_eq!(insert.execute(&db, &params).unwrap(), QueryResult::Inserted);
+        }
+        insert.execute(&db, &[Field::U32(20), Field::Null]).unwrap();
+
+        let select = prepare(&db, "SELECT v FROM kv WHERE k = ?").unwrap();
+        assert_eq!(select.param_types().len(), 1);
+        for k in [0u32, 7, 19] {
+            assert_eq!(
+                select.execute(&db, &[Field::U32(k)]).unwrap(),
+                QueryResult::Rows {
+                    columns: vec!["v".to_string()],
+                    rows: vec![vec![Field::Text(format!("v{}", k))]],
+                }
+            );
+        }
+
+        let delete = prepare(&db, "DELETE FROM kv WHERE k = ?").unwrap();
+        assert_eq!(
+            delete.execute(&db, &[Field::U32(7)]).unwrap(),
+            QueryResult::Deleted(1)
+        );
+        assert_eq!(
+            select.execute(&db, &[Field::U32(7)]).unwrap(),
+            QueryResult::Rows {
+                columns: vec!["v".to_string()],
+                rows: vec![],
+            }
+        );
+
+        assert!(matches!(select.execute(&db, &[]), Err(Error::Sql(_))));
+        assert!(matches!(
+            select.execute(&db, &[Field::Text("1".to_string())]),
+            Err(Error::TypeMismatch(_))
+        ));
+        assert!(matches!(
+            execute(&db, "SELECT * FROM kv WHERE k = ?"),
+            Err(Error::Sql(_))
+        ));
+        let by_value = prepare(&db, "SELECT k FROM kv WHERE v = ? OR k > ?").unwrap();
+        assert_eq!(
+            by_value
+                .execute(&db, &[Field::Text("v3".to_string()), Field::U32(18)])
+                .unwrap(),
+            QueryResult::Rows {
+                columns: vec!["k".to_string()],
+                rows: vec![
+                    vec![Field::U32(3)],
+                    vec![Field::U32(19)],
+                    vec![Field::U32(20)]
+                ],
+            }
+        );
+        assert!(matches!(
+            prepare(&db, "SELECT v FROM kv WHERE ? = 1"),
+            Err(Error::Sql(_))
+        ));
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -1,59 +1,153 @@
-%start Expr
-%avoid_insert "INT"
+%start Statement
+%left 'OR'
+%left 'AND'
+%right 'NOT'
+%nonassoc '=' '<>' '<' '<=' '>' '>=' 'IS'
+%left '+' '-'
+%left '*' '/'
 %%
-Expr -> Result<Expr, ()>:
-      SelectQuery { $1 }
-    | InsertQuery { $1 }
-    | UpdateQuery { $1 }
-    | DeleteQuery { $1 }
+Statement -> Result<Statement, ()>:
+      Query { $1 }
+    | Query ';' { $1 }
     ;
 
+Query -> Result<Statement, ()>:
+      'CREATE' 'TABLE' Ident '(' ColumnDefs ')'
+      {
+          Ok(Statement::CreateTable { table: $3?, columns: $5? })
+      }
+    | 'INSERT' 'INTO' Ident 'VALUES' '(' Literals ')'
+      {
+          Ok(Statement::Insert { table: $3?, values: $6? })
+      }
+    | 'SELECT' Projection 'FROM' Ident Filter
+      {
+          Ok(Statement::Select { table: $4?, columns: $2?, filter: $5? })
+      }
+    | 'DELETE' 'FROM' Ident Filter
+      {
+          Ok(Statement::Delete { table: $3?, filter: $4? })
+      }
+    ;
 
-Term -> Result<Expr, ()>:
-      Term '*' Factor { Ok(Expr::Mul{ span: $span, lhs: Box::new($1?), rhs: Box::new($3?) }) }
-    | Factor { $1 }
+ColumnDefs -> Result<Vec<ColumnDef>, ()>:
+      ColumnDef { Ok(vec![$1?]) }
+    | ColumnDefs ',' ColumnDef { push($1, $3) }
     ;
 
-Factor -> Result<Expr, ()>:
-      '(' Expr ')' { $2 }
-    | 'INT' { Ok(Expr::Number{ span: $span }) }
+ColumnDef -> Result<ColumnDef, ()>:
+      Ident Ident Constraints
+      {
+          let (not_null, primary_key) = $3?;
+          Ok(ColumnDef { name: $1?, type_name: $2?, not_null, primary_key })
+      }
+    ;
+
+Constraints -> Result<(bool, bool), ()>:
+      { Ok((false, false)) }
+    | Constraints 'NOT' 'NULL' { Ok((true, $1?.1)) }
+    | Constraints 'PRIMARY' 'KEY' { Ok(($1?.0, true)) }
+    ;
+
+Projection -> Result<Option<Vec<String>>, ()>:
+      '*' { Ok(None) }
+    | Idents { Ok(Some($1?)) }
+    ;
+
+Idents -> Result<Vec<String>, ()>:
+      Ident { Ok(vec![$1?]) }
+    | Idents ',' Ident { push($1, $3) }
+    ;
+
+Filter -> Result<Option<Condition>, ()>:
+      { Ok(None) }
+    | 'WHERE' Condition { Ok(Some($2?)) }
     ;
-%%
 
-use cfgrammar::Span;
+Condition -> Result<Condition, ()>:
+      Condition 'OR' Condition { Ok(Condition::Or(Box::new($1?), Box::new($3?))) }
+    | Condition 'AND' Condition { Ok(Condition::And(Box::new($1?), Box::new($3?))) }
+    | 'NOT' Condition { Ok(Condition::Not(Box::new($2?))) }
+    | Condition '=' Condition { cmp(CmpOp::Eq, $1, $3) }
+    | Condition '<>' Condition { cmp(CmpOp::Ne, $1, $3) }
+    | Condition '<' Condition { cmp(CmpOp::Lt, $1, $3) }
+    | Condition '<=' Condition { cmp(CmpOp::Le, $1, $3) }
+    | Condition '>' Condition { cmp(CmpOp::Gt, $1, $3) }
+    | Condition '>=' Condition { cmp(CmpOp::Ge, $1, $3) }
+    | Condition 'IS' 'NULL' %prec 'IS' { Ok(Condition::IsNull(Box::new($1?))) }
+    | Condition 'IS' 'NOT' 'NULL' %prec 'IS'
+      {
+          Ok(Condition::Not(Box::new(Condition::IsNull(Box::new($1?)))))
+      }
+    | Condition '+' Condition { arith(ArithOp::Add, $1, $3) }
+    | Condition '-' Condition { arith(ArithOp::Sub, $1, $3) }
+    | Condition '*' Condition { arith(ArithOp::Mul, $1, $3) }
+    | Condition '/' Condition { arith(ArithOp::Div, $1, $3) }
+    | '(' Condition ')' { $2 }
+    | Ident { Ok(Condition::Column($1?)) }
+    | Literal { Ok(Condition::Literal($1?)) }
+    ;
 
-#[derive(Debug)]
+Literals -> Result<Vec<Literal>, ()>:
+      Literal { Ok(vec![$1?]) }
+    | Literals ',' Literal { push($1, $3) }
+    ;
 
+Literal -> Result<Literal, ()>:
+      'NUMBER' { Ok(Literal::Number(text($lexer, $1)?.to_string())) }
+    | 'STRING'
+      {
+          let quoted = text($lexer, $1)?;
+          Ok(Literal::String(quoted[1..quoted.len() - 1].replace("''", "'")))
+      }
+    | 'TRUE' { Ok(Literal::Bool(true)) }
+    | 'FALSE' { Ok(Literal::Bool(false)) }
+    | 'NULL' { Ok(Literal::Null) }
+    | '?' { Ok(Literal::Param) }
+    ;
 
-/*
+Ident -> Result<String, ()>:
+      'IDENT' { Ok(text($lexer, $1)?.to_string()) }
+    ;
+%%
 
-SelectQuery -> 'SELECT' Columns 'FROM' Table 'WHERE' 
+use crate::expr::ArithOp;
+use crate::expr::CmpOp;
+use crate::sql::ColumnDef;
+use crate::sql::Condition;
+use crate::sql::Literal;
+use crate::sql::Statement;
+use lrlex::DefaultLexerTypes;
+use lrpar::NonStreamingLexer;
 
+type Token = Result<lrlex::DefaultLexeme, lrlex::DefaultLexeme>;
 
-SELECT *, column, column,
-FROM table
-WHERE column = 'a' OR column = 12345 OR column != 'a' AND column = 'a' OR (column = 'a' OR 'column' = b)
-AND column < 'a' AND column >= 'b'
+/// The source text of `token`, failing if it was inserted by error recovery.
+fn text<'input>(
+    lexer: &dyn NonStreamingLexer<'input, DefaultLexerTypes>,
+    token: Token,
+) -> Result<&'input str, ()> {
+    token.map(|token| lexer.span_str(token.span())).map_err(|_| ())
+}
 
-INSERT INTO table
-VALUES ('a', 1)
+fn push<T>(list: Result<Vec<T>, ()>, item: Result<T, ()>) -> Result<Vec<T>, ()> {
+    let mut list = list?;
+    list.push(item?);
+    Ok(list)
+}
 
-DELETE FROM table
-WHERE condition
-*/
+fn cmp(
+    op: CmpOp,
+    lhs: Result<Condition, ()>,
+    rhs: Result<Condition, ()>,
+) -> Result<Condition, ()> {
+    Ok(Condition::Cmp(op, Box::new(lhs?), Box::new(rhs?)))
+}
 
-pub enum Expr {
-    Add {
-        span: Span,
-        lhs: Box<Expr>,
-        rhs: Box<Expr>,
-    },
-    Mul {
-        span: Span,
-        lhs: Box<Expr>,
-        rhs: Box<Expr>,
-    },
-    Number {
-        span: Span
-    }
+fn arith(
+    op: ArithOp,
+    lhs: Result<Condition, ()>,
+    rhs: Result<Condition, ()>,
+) -> Result<Condition, ()> {
+    Ok(Condition::Arith(op, Box::new(lhs?), Box::new(rhs?)))
 }
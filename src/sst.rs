@@ -0,0 +1,393 @@
+//! Immutable sorted files of key/value entries with a block index, for preparing data offline
+//! and bulk loading it with `Database::ingest_sst`, or for moving data to and from LSM-based
+//! stores. Unlike a dump, see `crate::export`, a sorted file can be read starting at any key
+//! without reading the entries before it.
+//!
+//! All integers are big-endian. The file consists of:
+//!
+//! * Data blocks of roughly `BLOCK_SIZE` bytes, each holding entries as the key and the value,
+//!   each a u32 length followed by its bytes, then a CRC-32 of the block.
+//! * The index: for each block, its first key as a u32 length followed by its bytes, then its
+//!   offset as a u64, its size without the CRC as a u32 and its number of entries as a u32. Then
+//!   a CRC-32 of the index.
+//! * A footer of `FOOTER_SIZE` bytes: the index offset as a u64 and size as a u32, the number of
+//!   entries as a u64, the format version as a u32, a CRC-32 of the preceding footer bytes, and
+//!   the magic bytes `JOHNDBST`.
+
+use crate::checksum::crc32;
+use crate::error::Error;
+use crate::error::Result;
+use crate::export::read_vec;
+use std::convert::TryInto;
+use std::io::Read;
+use std::io::Seek;
+use std::io::SeekFrom;
+use std::io::Write;
+
+const MAGIC: &[u8; 8] = b"JOHNDBST";
+pub const FORMAT_VERSION: u32 = 1;
+
+/// Blocks are closed once they take at least this many bytes.
+const BLOCK_SIZE: usize = 16 << 10;
+const FOOTER_SIZE: usize = 36;
+
+pub struct SstWriter<W: Write> {
+    writer: W,
+    offset: u64,
+    block: Vec<u8>,
+    block_entry_cnt: u32,
+    index: Vec<u8>,
+    entry_cnt: u64,
+}
+
+impl<W: Write> SstWriter<W> {
+    pub fn new(writer: W) -> Self {
+        SstWriter {
+            writer,
+            offset: 0,
+            block: Vec::with_capacity(BLOCK_SIZE),
+            block_entry_cnt: 0,
+            index: Vec::new(),
+            entry_cnt: 0,
+        }
+    }
+
+    /// Appends an entry. Entries are expected in strictly ascending key order, which reading the
+    /// file checks.
+    pub fn write_entry(&mut self, key: &[u8], value: &[u8]) -> Result<()> {
+        let (key_len, value_len) = match (key.len().try_into(), value.len().try_into()) {
+            (Ok(key_len), Ok(value_len)) => (key_len, value_len),
+            _ => return Err(Error::ItemTooLarge(key.len() + value.len())),
+        };
+        if self.block_entry_cnt == 0 {
+            put_bytes(&mut self.index, key);
+        }
+        self.block.extend_from_slice(&u32::to_be_bytes(key_len));
+        self.block.extend_from_slice(key);
+        self.block.extend_from_slice(&u32::to_be_bytes(value_len));
+        self.block.extend_from_slice(value);
+        self.block_entry_cnt += 1;
+        self.entry_cnt += 1;
+
+        if self.block.len() >= BLOCK_SIZE {
+            self.finish_block()?;
+        }
+        Ok(())
+    }
+
+    fn finish_block(&mut self) -> Result<()> {
+        let crc = crc32(&self.block);
+        self.writer.write_all(&self.block)?;
+        self.writer.write_all(&crc.to_be_bytes())?;
+
+        self.index.extend_from_slice(&self.offset.to_be_bytes());
+        self.index
+            .extend_from_slice(&(self.block.len() as u32).to_be_bytes());
+        self.index
+            .extend_from_slice(&self.block_entry_cnt.to_be_bytes());
+        self.offset += self.block.len() as u64 + 4;
+        self.block.clear();
+        self.block_entry_cnt = 0;
+        Ok(())
+    }
+
+    /// Writes the last block, the index and the footer and flushes the underlying writer,
+    /// returning the number of entries written. A file without a footer is rejected when
+    /// reading.
+    pub fn finish(mut self) -> Result<u64> {
+        if self.block_entry_cnt > 0 {
+            self.finish_block()?;
+        }
+        self.writer.write_all(&self.index)?;
+        self.writer.write_all(&crc32(&self.index).to_be_bytes())?;
+
+        let mut footer = self.offset.to_be_bytes().to_vec();
+        footer.extend_from_slice(&(self.index.len() as u32).to_be_bytes());
+        footer.extend_from_slice(&self.entry_cnt.to_be_bytes());
+        footer.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+        footer.extend_from_slice(&crc32(&footer).to_be_bytes());
+        footer.extend_from_slice(MAGIC);
+        self.writer.write_all(&footer)?;
+        self.writer.flush()?;
+        Ok(self.entry_cnt)
+    }
+}
+
+fn put_bytes(buf: &mut Vec<u8>, bytes: &[u8]) {
+    buf.extend_from_slice(&(bytes.len() as u32).to_be_bytes());
+    buf.extend_from_slice(bytes);
+}
+
+#[derive(Debug, Clone)]
+struct BlockHandle {
+    first_key: Vec<u8>,
+    offset: u64,
+    size: u32,
+    entry_cnt: u32,
+}
+
+pub struct SstReader<R: Read + Seek> {
+    reader: R,
+    blocks: Vec<BlockHandle>,
+    entry_cnt: u64,
+}
+
+impl<R: Read + Seek> SstReader<R> {
+    /// Reads and checks the footer and the index. Blocks are only read, and checked, as they're
+    /// iterated over.
+    pub fn open(mut reader: R) -> Result<Self> {
+        reader.seek(SeekFrom::End(-(FOOTER_SIZE as i64)))?;
+        let footer = read_vec(&mut reader, FOOTER_SIZE)?;
+        if &footer[FOOTER_SIZE - MAGIC.len()..] != MAGIC {
+            return Err(Error::corruption("not a johndb sorted file".to_string()));
+        }
+        let crc = u32::from_be_bytes(footer[24..28].try_into().unwrap());
+        if crc != crc32(&footer[..24]) {
+            return Err(Error::corruption(
+                "sorted file footer checksum mismatch".to_string(),
+            ));
+        }
+        let version = u32::from_be_bytes(footer[20..24].try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(Error::corruption(format!(
+                "unsupported sorted file format version {}",
+                version
+            )));
+        }
+        let index_offset = u64::from_be_bytes(footer[..8].try_into().unwrap());
+        let index_size = u32::from_be_bytes(footer[8..12].try_into().unwrap());
+        let entry_cnt = u64::from_be_bytes(footer[12..20].try_into().unwrap());
+
+        reader.seek(SeekFrom::Start(index_offset))?;
+        let index = read_checked(&mut reader, index_size as usize, "index")?;
+        let blocks = parse_index(&index)?;
+        if blocks
+            .iter()
+            .map(|block| block.entry_cnt as u64)
+            .sum::<u64>()
+            != entry_cnt
+        {
+            return Err(Error::corruption(format!(
+                "sorted file index doesn't add up to {} entries",
+                entry_cnt
+            )));
+        }
+
+        Ok(SstReader {
+            reader,
+            blocks,
+            entry_cnt,
+        })
+    }
+
+    pub fn entry_cnt(&self) -> u64 {
+        self.entry_cnt
+    }
+
+    pub fn block_cnt(&self) -> usize {
+        self.blocks.len()
+    }
+
+    /// Iterates over every entry in ascending key order.
+    pub fn iter(&mut self) -> SstIter<'_, R> {
+        SstIter {
+            sst: self,
+            block_idx: 0,
+            entries: Vec::new().into_iter(),
+            start: None,
+            prev_key: None,
+        }
+    }
+
+    /// Iterates over the entries with keys at or after `start`, in ascending key order. Only the
+    /// blocks that may hold such keys are read.
+    pub fn iter_from(&mut self, start: &[u8]) -> SstIter<'_, R> {
+        let block_idx = self
+            .blocks
+            .partition_point(|block| block.first_key.as_slice() <= start)
+            .saturating_sub(1);
+        SstIter {
+            sst: self,
+            block_idx,
+            entries: Vec::new().into_iter(),
+            start: Some(start.to_vec()),
+            prev_key: None,
+        }
+    }
+
+    fn read_block(&mut self, block_idx: usize) -> Result<Vec<(Vec<u8>, Vec<u8>)>> {
+        let block = self.blocks[block_idx].clone();
+        self.reader.seek(SeekFrom::Start(block.offset))?;
+        let bytes = read_checked(&mut self.reader, block.size as usize, "block")?;
+
+        let mut buf = bytes.as_slice();
+        let mut entries = Vec::with_capacity(block.entry_cnt as usize);
+        for _ in 0..block.entry_cnt {
+            let key = take_bytes(&mut buf)?;
+            let value = take_bytes(&mut buf)?;
+            entries.push((key, value));
+        }
+        if !buf.is_empty() || entries.first().map(|(key, _)| key) != Some(&block.first_key) {
+            return Err(Error::corruption(format!(
+                "sorted file block at {} doesn't match the index",
+                block.offset
+            )));
+        }
+        Ok(entries)
+    }
+}
+
+pub struct SstIter<'a, R: Read + Seek> {
+    sst: &'a mut SstReader<R>,
+    block_idx: usize,
+    entries: std::vec::IntoIter<(Vec<u8>, Vec<u8>)>,
+    start: Option<Vec<u8>>,
+    prev_key: Option<Vec<u8>>,
+}
+
+impl<'a, R: Read + Seek> Iterator for SstIter<'a, R> {
+    type Item = Result<(Vec<u8>, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            if let Some((key, value)) = self.entries.next() {
+                if self
+                    .prev_key
+                    .as_ref()
+                    .is_some_and(|prev_key| *prev_key >= key)
+                {
+                    self.block_idx = self.sst.blocks.len();
+                    self.entries = Vec::new().into_iter();
+                    return Some(Err(Error::corruption(format!(
+                        "sorted file key {:?} is out of order",
+                        key
+                    ))));
+                }
+                self.prev_key = Some(key.clone());
+                if self.start.as_ref().is_some_and(|start| key < *start) {
+                    continue;
+                }
+                return Some(Ok((key, value)));
+            }
+
+            if self.block_idx == self.sst.blocks.len() {
+                return None;
+            }
+            match self.sst.read_block(self.block_idx) {
+                Ok(entries) => {
+                    self.entries = entries.into_iter();
+                    self.block_idx += 1;
+                }
+                Err(err) => {
+                    self.block_idx = self.sst.blocks.len();
+                    return Some(Err(err));
+                }
+            }
+        }
+    }
+}
+
+/// Reads `len` bytes followed by their CRC-32, failing if it doesn't match.
+fn read_checked<R: Read>(reader: &mut R, len: usize, what: &str) -> Result<Vec<u8>> {
+    let bytes = read_vec(reader, len + 4)?;
+    let (bytes, crc) = bytes.split_at(len);
+    if u32::from_be_bytes(crc.try_into().unwrap()) != crc32(bytes) {
+        return Err(Error::corruption(format!(
+            "sorted file {} checksum mismatch",
+            what
+        )));
+    }
+    Ok(bytes.to_vec())
+}
+
+fn parse_index(mut buf: &[u8]) -> Result<Vec<BlockHandle>> {
+    let mut blocks: Vec<BlockHandle> = Vec::new();
+    while !buf.is_empty() {
+        let first_key = take_bytes(&mut buf)?;
+        let handle = take(&mut buf, 16)?;
+        if blocks
+            .last()
+            .is_some_and(|prev| prev.first_key >= first_key)
+        {
+            return Err(Error::corruption(
+                "sorted file index is out of order".to_string(),
+            ));
+        }
+        blocks.push(BlockHandle {
+            first_key,
+            offset: u64::from_be_bytes(handle[..8].try_into().unwrap()),
+            size: u32::from_be_bytes(handle[8..12].try_into().unwrap()),
+            entry_cnt: u32::from_be_bytes(handle[12..].try_into().unwrap()),
+        });
+    }
+    Ok(blocks)
+}
+
+fn take_bytes(buf: &mut &[u8]) -> Result<Vec<u8>> {
+    let len = u32::from_be_bytes(take(buf, 4)?.try_into().unwrap());
+    Ok(take(buf, len as usize)?.to_vec())
+}
+
+fn take<'a>(buf: &mut &'a [u8], len: usize) -> Result<&'a [u8]> {
+    if buf.len() < len {
+        return Err(Error::corruption("sorted file is truncated".to_string()));
+    }
+    let (taken, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(taken)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::SstReader;
+    use super::SstWriter;
+    use crate::error::Error;
+    use std::io::Cursor;
+
+    fn key(i: u32) -> Vec<u8> {
+        format!("key-{:06}", i).into_bytes()
+    }
+
+    #[test]
+    fn round_trip_and_corruption() {
+        let mut buf = Vec::new();
+        let mut writer = SstWriter::new(&mut buf);
+        for i in 0..5000 {
+            writer.write_entry(&key(i * 2), &[i as u8; 20]).unwrap();
+        }
+        assert_eq!(writer.finish().unwrap(), 5000);
+
+        let mut reader = SstReader::open(Cursor::new(&buf)).unwrap();
+        assert_eq!(reader.entry_cnt(), 5000);
+        assert!(reader.block_cnt() > 1);
+        let entries = reader.iter().collect::<Result<Vec<_>, _>>().unwrap();
+        assert_eq!(entries.len(), 5000);
+        assert_eq!(entries[7], (key(14), vec![7; 20]));
+
+        let from = reader
+            .iter_from(&key(4001))
+            .map(|entry| entry.unwrap().0)
+            .collect::<Vec<_>>();
+        assert_eq!(from.len(), 2999);
+        assert_eq!(from[0], key(4002));
+
+        // Flip a bit in the first block
+        let mut corrupted = buf.clone();
+        corrupted[100] ^= 1;
+        let mut reader = SstReader::open(Cursor::new(&corrupted)).unwrap();
+        assert!(matches!(
+            reader.iter().next(),
+            Some(Err(Error::Corruption { .. }))
+        ));
+        // Later blocks can still be read
+        assert_eq!(reader.iter_from(&key(9000)).count(), 500);
+
+        // A file cut short loses its footer
+        assert!(SstReader::open(Cursor::new(&buf[..buf.len() - 1])).is_err());
+
+        let mut empty = Vec::new();
+        assert_eq!(SstWriter::new(&mut empty).finish().unwrap(), 0);
+        let mut reader = SstReader::open(Cursor::new(&empty)).unwrap();
+        assert_eq!(reader.iter().count(), 0);
+    }
+}
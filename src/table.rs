@@ -0,0 +1,1336 @@
+//! Rows stored in heap pages alongside a primary B-tree index mapping each key to its row's
+//! `ValueTupleId`, with both living in the same page fetcher, plus any secondary indexes on their
+//! columns.
+
+use crate::btree::export::item_from_bytes;
+use crate::btree::export::item_to_bytes;
+use crate::btree::heap::Snapshot;
+use crate::btree::heap::TxnId;
+use crate::btree::key::Key;
+use crate::btree::key::KeyEncoded;
+use crate::btree::scan::RangeIter;
+use crate::btree::vacuum::Vacuum;
+use crate::btree::vacuum::VacuumStats;
+use crate::btree::value::ValueTupleId;
+use crate::btree::BTree;
+use crate::encoding::encode;
+use crate::error::Error;
+use crate::error::Result;
+use crate::expr::Expr;
+use crate::json::Encoded;
+use crate::page_fetcher::PageFetcher as PageFetcherTrait;
+use crate::planner;
+use crate::planner::AccessPath;
+use crate::planner::Histogram;
+use crate::planner::IndexStats;
+use crate::planner::KeyPredicate;
+use crate::planner::Plan;
+use crate::planner::TableStats;
+use crate::row::Field;
+use crate::row::Projection;
+use crate::row::Schema;
+use std::collections::HashSet;
+use std::ops::Bound;
+use std::ops::RangeBounds;
+
+/// A heap of rows plus the primary index over them.
+///
+/// There's no log, so an operation interrupted by a crash or failing partway may leave a row in
+/// the heap without an index entry. The index is always updated so that it never points at a
+/// deleted row: new rows are stored before they're indexed, and rows are unindexed before
+/// they're deleted. The same goes for secondary indexes, which are updated after the primary
+/// index when a row is stored and before the row is deleted. A row replacing another is indexed
+/// in full before the old one is deleted, and if indexing it fails partway, the index entries
+/// changed so far are put back.
+pub struct Table<K, PageFetcher>
+where
+    K: Key,
+    PageFetcher: PageFetcherTrait,
+{
+    index: BTree<K, ValueTupleId, PageFetcher>,
+    secondaries: Vec<SecondaryIndex<PageFetcher>>,
+}
+
+impl<K, PageFetcher> Table<K, PageFetcher>
+where
+    K: Key,
+    PageFetcher: PageFetcherTrait,
+{
+    /// Wraps `page_fetcher`, creating an empty table if it doesn't hold one yet. See `BTree::new`.
+    pub fn new(page_fetcher: PageFetcher) -> Result<Self> {
+        Ok(Table {
+            index: BTree::new(page_fetcher)?,
+            secondaries: Vec::new(),
+        })
+    }
+
+    pub fn index(&self) -> &BTree<K, ValueTupleId, PageFetcher> {
+        &self.index
+    }
+
+    /// Creates a secondary index defined by `def` on a newly allocated page of `page_fetcher`,
+    /// which should be the table's own, and indexes the rows already stored. Returns the number
+    /// of rows indexed. Fails with `Error::TreeExists` if there's already an index by that name.
+    ///
+    /// Indexes aren't persisted with the table: reopen them with `open_index`.
+    pub fn create_index(&mut self, page_fetcher: PageFetcher, def: IndexDef) -> Result<u64> {
+        if self.secondary(&def.name).is_ok() {
+            return Err(Error::TreeExists(def.name));
+        }
+        let mut secondary = SecondaryIndex {
+            def,
+            tree: BTree::create(page_fetcher)?,
+        };
+        let mut cnt = 0;
+        for entry in self.index.range(..)? {
+            let (key, id) = entry?;
+            if let Some(index_key) = secondary.index_key(&key, &load_row(&self.index, id)?)? {
+                secondary.tree.insert(index_key, id)?;
+                cnt += 1;
+            }
+        }
+        self.secondaries.push(secondary);
+        Ok(cnt)
+    }
+
+    /// Opens the secondary index whose tree's metadata is on `metadata_no`, which must have been
+    /// created with the same `def` and kept up to date with the table since.
+    pub fn open_index(
+        &mut self,
+        page_fetcher: PageFetcher,
+        def: IndexDef,
+        metadata_no: u32,
+    ) -> Result<()> {
+        if self.secondary(&def.name).is_ok() {
+            return Err(Error::TreeExists(def.name));
+        }
+        self.secondaries.push(SecondaryIndex {
+            def,
+            tree: BTree::open(page_fetcher, metadata_no)?,
+        });
+        Ok(())
+    }
+
+    /// The page holding the metadata of the secondary index called `name`, see `open_index`.
+    pub fn index_metadata_no(&self, name: &str) -> Result<u32> {
+        Ok(self.secondary(name)?.tree.metadata_no())
+    }
+
+    fn secondary(&self, name: &str) -> Result<&SecondaryIndex<PageFetcher>> {
+        self.secondaries
+            .iter()
+            .find(|secondary| secondary.def.name == name)
+            .ok_or_else(|| Error::TreeNotFound(name.to_string()))
+    }
+
+    /// Stores `row` under `key`, replacing any existing row.
+    pub fn insert_row(&mut self, key: K, row: &[u8]) -> Result<()> {
+        // Rows the secondary indexes can't make sense of are turned away before anything changes
+        let index_keys = self.index_keys(&key, row)?;
+        let id = self.index.insert_tuple(row)?;
+        let old_id = match self.index_row(&key, id, index_keys) {
+            Ok(old_id) => old_id,
+            Err(err) => {
+                // Best effort, the row would only take up space otherwise
+                let _ = self.index.delete_tuple(id);
+                return Err(err);
+            }
+        };
+        // The row it replaces is only deleted now that nothing points at it anymore. The new row
+        // is in place either way, so this is best effort like above.
+        if let Some(old_id) = old_id {
+            let _ = self.index.delete_tuple(old_id);
+        }
+        Ok(())
+    }
+
+    /// Points `key` at the row `id`, and the secondary indexes at it under `index_keys`, returning
+    /// the row `key` pointed at before. If that fails partway, the indexes are put back as they
+    /// were.
+    fn index_row(
+        &mut self,
+        key: &K,
+        id: ValueTupleId,
+        index_keys: Vec<Option<KeyEncoded>>,
+    ) -> Result<Option<ValueTupleId>> {
+        let old_id = self.index.delete(key.clone())?;
+        if let Err(err) = self.index.insert(key.clone(), id) {
+            // The old entry fits back where it was just deleted from
+            if let Some(old_id) = old_id {
+                self.index.insert(key.clone(), old_id)?;
+            }
+            return Err(err);
+        }
+
+        let new_entries = with_id(index_keys, id);
+        let result = match old_id {
+            Some(old_id) if !self.secondaries.is_empty() => load_row(&self.index, old_id)
+                .and_then(|old_row| self.index_keys(key, &old_row))
+                .map(|old_keys| with_id(old_keys, old_id)),
+            _ => Ok(vec![None; self.secondaries.len()]),
+        }
+        .and_then(|old_entries| self.swap_secondary_entries(&old_entries, &new_entries));
+        if let Err(err) = result {
+            // Best effort, as in `swap_secondary_entries`
+            let _ = self.index.delete(key.clone());
+            if let Some(old_id) = old_id {
+                let _ = self.index.insert(key.clone(), old_id);
+            }
+            return Err(err);
+        }
+        Ok(old_id)
+    }
+
+    /// The key of each secondary index's entry for `row`, stored under `key`.
+    fn index_keys(&self, key: &K, row: &[u8]) -> Result<Vec<Option<KeyEncoded>>> {
+        self.secondaries
+            .iter()
+            .map(|secondary| secondary.index_key(key, row))
+            .collect()
+    }
+
+    /// Replaces each secondary index's entry in `from` with its entry in `to`. If one of them
+    /// fails, the ones replaced before it are swapped back.
+    fn swap_secondary_entries(
+        &mut self,
+        from: &[Option<(KeyEncoded, ValueTupleId)>],
+        to: &[Option<(KeyEncoded, ValueTupleId)>],
+    ) -> Result<()> {
+        let failed = self
+            .secondaries
+            .iter_mut()
+            .zip(from.iter().zip(to))
+            .enumerate()
+            .find_map(|(idx, (secondary, (from, to)))| {
+                swap_entry(&mut secondary.tree, from, to)
+                    .err()
+                    .map(|err| (idx, err))
+            });
+        match failed {
+            Some((idx, err)) => {
+                // Best effort, there's nothing left to fall back on if this fails too
+                for (secondary, (from, to)) in
+                    self.secondaries[..idx].iter_mut().zip(from.iter().zip(to))
+                {
+                    let _ = swap_entry(&mut secondary.tree, to, from);
+                }
+                Err(err)
+            }
+            None => Ok(()),
+        }
+    }
+
+    /// Removes the row `id`, stored under `key`, from the secondary indexes.
+    fn unindex_secondaries(&mut self, key: &K, id: ValueTupleId) -> Result<()> {
+        if self.secondaries.is_empty() {
+            return Ok(());
+        }
+        let row = load_row(&self.index, id)?;
+        for secondary in self.secondaries.iter_mut() {
+            if let Some(index_key) = secondary.index_key(key, &row)? {
+                secondary.tree.delete(index_key)?;
+            }
+        }
+        Ok(())
+    }
+
+    pub fn get_by_key(&self, key: K) -> Result<Option<Vec<u8>>> {
+        self.index
+            .search(key)?
+            .value
+            .map(|id| load_row(&self.index, id))
+            .transpose()
+    }
+
+    /// Removes the row stored under `key`, returning it if there was one.
+    pub fn delete_by_key(&mut self, key: K) -> Result<Option<Vec<u8>>> {
+        let id = match self.index.delete(key.clone())? {
+            Some(id) => id,
+            None => return Ok(None),
+        };
+        self.unindex_secondaries(&key, id)?;
+        match self.index.delete_tuple(id)? {
+            Some(row) => Ok(Some(row)),
+            None => Err(dangling(id)),
+        }
+    }
+
+    /// Vacuums the index and the heap, reclaiming the space of rows deleted for good or by a
+    /// transaction below `horizon`, see `Vacuum::with_heap`.
+    pub fn vacuum(&mut self, horizon: TxnId) -> Result<VacuumStats> {
+        self.index.vacuum(Vacuum::with_heap(horizon))
+    }
+
+    /// Returns the rows with keys within `range` in ascending key order, with the same
+    /// consistency as `BTree::range`.
+    pub fn scan<R>(&self, range: R) -> Result<Scan<'_, K, PageFetcher>>
+    where
+        R: RangeBounds<K>,
+    {
+        Ok(Scan {
+            index: &self.index,
+            iter: self.index.range(range)?,
+            snapshot: None,
+            filter: None,
+        })
+    }
+
+    /// Like `scan`, but only returns the rows visible to `snapshot`, see `BTree::get_tuple_in`.
+    /// Index entries a vacuum hinted as pointing at dead rows are skipped without fetching the
+    /// rows, see `ValueTupleId::is_hinted_dead`.
+    pub fn scan_in<R>(&self, range: R, snapshot: Snapshot) -> Result<Scan<'_, K, PageFetcher>>
+    where
+        R: RangeBounds<K>,
+    {
+        Ok(Scan {
+            index: &self.index,
+            iter: self
+                .index
+                .scan_filter(range, |_, id| !ValueTupleId::is_hinted_dead(id))?,
+            snapshot: Some(snapshot),
+            filter: None,
+        })
+    }
+
+    /// Like `scan`, but only returns the rows for which `filter` returns true. `filter` is
+    /// called with each key and row while the row's heap page is read locked, and only the
+    /// matching rows are copied out, see `BTree::visit_tuple`.
+    pub fn scan_filter<'a, R, F>(
+        &'a self,
+        range: R,
+        mut filter: F,
+    ) -> Result<Scan<'a, K, PageFetcher>>
+    where
+        R: RangeBounds<K>,
+        F: FnMut(&K, &[u8]) -> bool + 'a,
+    {
+        Ok(Scan {
+            index: &self.index,
+            iter: self.index.range(range)?,
+            snapshot: None,
+            filter: Some(Box::new(move |key, row| Ok(filter(key, row)))),
+        })
+    }
+
+    /// Like `scan_filter`, but only returns the rows `expr` matches when decoded with `schema`,
+    /// failing if evaluating it does, see `Expr::matches`.
+    pub fn scan_where<'a, R>(
+        &'a self,
+        range: R,
+        schema: &'a Schema,
+        expr: &'a Expr,
+    ) -> Result<Scan<'a, K, PageFetcher>>
+    where
+        R: RangeBounds<K>,
+    {
+        expr.check(schema)?;
+        Ok(Scan {
+            index: &self.index,
+            iter: self.index.range(range)?,
+            snapshot: None,
+            filter: Some(Box::new(move |_, row| expr.matches(&schema.row_ref(row)?))),
+        })
+    }
+
+    /// Returns the rows matching `filter` in the order of the indexed column of the secondary
+    /// index called `name`, rows with equal values in no particular order, reading only the index entries within the range `filter` puts the
+    /// column in, see `Expr::key_predicate`. Fails with `Error::Eval` if the index is partial and
+    /// `filter` doesn't imply its predicate, as the rows it leaves out could match.
+    pub fn scan_index(&self, name: &str, filter: &Expr) -> Result<Vec<(K, Vec<u8>)>> {
+        let secondary = self.secondary(name)?;
+        let schema = &secondary.def.schema;
+        filter.check(schema)?;
+        if !secondary.def.covers(filter) {
+            return Err(Error::Eval(format!(
+                "the filter doesn't imply the predicate of partial index {}",
+                name
+            )));
+        }
+
+        let (start, end) = match secondary.key_predicate(filter) {
+            KeyPredicate::Eq(value) => (
+                Bound::Included(KeyEncoded::new(&value)),
+                Bound::Excluded(prefix_end(&value)),
+            ),
+            KeyPredicate::Range(start, end) => (
+                match start {
+                    Bound::Included(value) => Bound::Included(KeyEncoded::new(&value)),
+                    Bound::Excluded(value) => Bound::Included(prefix_end(&value)),
+                    Bound::Unbounded => Bound::Unbounded,
+                },
+                match end {
+                    Bound::Included(value) => Bound::Excluded(prefix_end(&value)),
+                    Bound::Excluded(value) => Bound::Excluded(KeyEncoded::new(&value)),
+                    Bound::Unbounded => Bound::Unbounded,
+                },
+            ),
+        };
+        let mut rows = Vec::new();
+        for entry in secondary.tree.range((start, end))? {
+            let (index_key, id) = entry?;
+            let row = load_row(&self.index, id)?;
+            if filter.matches(&schema.row_ref(&row)?)? {
+                let (_, key) = index_key.decode::<(Field, Vec<u8>)>()?;
+                rows.push((item_from_bytes(&key)?, row));
+            }
+        }
+        Ok(rows)
+    }
+
+    /// Like `scan`, but decodes only the `projection`'s fields of each row, straight from its heap
+    /// page, instead of copying the whole row out.
+    pub fn scan_project<'a, R>(
+        &'a self,
+        range: R,
+        projection: Projection<'a>,
+    ) -> Result<ProjectedScan<'a, K, PageFetcher>>
+    where
+        R: RangeBounds<K>,
+    {
+        Ok(ProjectedScan {
+            index: &self.index,
+            iter: self.index.range(range)?,
+            projection,
+        })
+    }
+
+    /// Writes the rows with keys within `range`, decoded with `schema`, to `writer` as a Parquet
+    /// file with a column per schema column, see `crate::parquet_export`. Keys are only exported
+    /// if the rows hold them too. Returns the number of rows written.
+    #[cfg(feature = "parquet")]
+    pub fn export_parquet<R, W>(&self, range: R, schema: &Schema, writer: W) -> Result<u64>
+    where
+        R: RangeBounds<K>,
+        W: std::io::Write + Send,
+    {
+        let rows = self.scan(range)?.map(|row| row.map(|(_, row)| row));
+        crate::parquet_export::write_parquet(schema, rows, writer)
+    }
+
+    /// Collects the statistics `plan` and `plan_where` estimate costs from, with histograms of at
+    /// most `bucket_cnt` buckets over the primary keys and the secondary indexes' values. Reads
+    /// every index entry.
+    pub fn analyze(&self, bucket_cnt: usize) -> Result<Statistics<K>> {
+        let entries = self.index.range(..)?.collect::<Result<Vec<_>>>()?;
+        let heap_pages = entries
+            .iter()
+            .map(|(_, id)| id.page_no)
+            .collect::<HashSet<_>>();
+        let table = TableStats {
+            row_cnt: entries.len() as u64,
+            heap_page_cnt: heap_pages.len() as u64,
+        };
+        let primary = index_stats(&self.index, entries, bucket_cnt)?;
+
+        let mut indexes = Vec::new();
+        for secondary in self.secondaries.iter() {
+            let entries = secondary
+                .tree
+                .range(..)?
+                .map(|entry| {
+                    let (index_key, id) = entry?;
+                    let (value, _) = index_key.decode::<(Field, Vec<u8>)>()?;
+                    Ok((encode(&value), id))
+                })
+                .collect::<Result<Vec<_>>>()?;
+            let stats = index_stats(&secondary.tree, entries, bucket_cnt)?;
+            indexes.push((secondary.def.name.clone(), stats));
+        }
+        Ok(Statistics {
+            table,
+            primary,
+            indexes,
+        })
+    }
+
+    /// Chooses between looking up the rows matching `predicate` through the primary index and
+    /// filtering every row of `scan(..)`, based on `stats` as collected by `analyze`.
+    pub fn plan(&self, stats: &Statistics<K>, predicate: &KeyPredicate<K>) -> Plan {
+        let candidate = stats.primary.candidate(AccessPath::PrimaryIndex, predicate);
+        let rows = candidate.selectivity * stats.table.row_cnt as f64;
+        planner::choose(&stats.table, &[candidate], rows)
+    }
+
+    /// Chooses between looking up the rows matching `filter` through one of the secondary
+    /// indexes, see `scan_index`, and filtering every row of `scan(..)`, based on `stats` as
+    /// collected by `analyze`. Partial indexes are only considered if `filter` implies their
+    /// predicate.
+    pub fn plan_where(&self, stats: &Statistics<K>, filter: &Expr) -> Plan {
+        let row_cnt = stats.table.row_cnt as f64;
+        let candidates = self
+            .secondaries
+            .iter()
+            .filter(|secondary| secondary.def.covers(filter))
+            .filter_map(|secondary| {
+                let name = &secondary.def.name;
+                let (_, index_stats) = stats.indexes.iter().find(|(found, _)| found == name)?;
+                let lookup = match secondary.key_predicate(filter) {
+                    KeyPredicate::Eq(value) => KeyPredicate::Eq(encode(&value)),
+                    KeyPredicate::Range(start, end) => KeyPredicate::Range(
+                        start.map(|value| encode(&value)),
+                        end.map(|value| encode(&value)),
+                    ),
+                };
+                let mut candidate =
+                    index_stats.candidate(AccessPath::SecondaryIndex(name.clone()), &lookup);
+                // The index may only hold some of the rows, either because it's partial or
+                // because it leaves out NULLs
+                if row_cnt > 0.0 {
+                    candidate.selectivity *= index_stats.entry_cnt as f64 / row_cnt;
+                }
+                Some(candidate)
+            })
+            .collect::<Vec<_>>();
+        let rows = candidates
+            .iter()
+            .map(|candidate| candidate.selectivity * row_cnt)
+            .fold(row_cnt, f64::min);
+        planner::choose(&stats.table, &candidates, rows)
+    }
+
+    pub fn into_page_fetcher(self) -> PageFetcher {
+        self.index.into_page_fetcher()
+    }
+}
+
+/// Statistics about a table and its indexes, see `Table::analyze`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Statistics<K> {
+    pub table: TableStats,
+    pub primary: IndexStats<K>,
+    /// The secondary indexes by name, over their values' encodings with `crate::encoding`.
+    pub indexes: Vec<(String, IndexStats<Vec<u8>>)>,
+}
+
+/// The statistics of the index `tree`, given its entries' keys and rows in index order.
+fn index_stats<T, IndexKey, PageFetcher>(
+    tree: &BTree<IndexKey, ValueTupleId, PageFetcher>,
+    entries: Vec<(T, ValueTupleId)>,
+    bucket_cnt: usize,
+) -> Result<IndexStats<T>>
+where
+    T: Ord,
+    IndexKey: Key,
+    PageFetcher: PageFetcherTrait,
+{
+    let tree_stats = tree.analyze()?;
+    let entry_cnt = entries.len() as u64;
+    // Consecutive entries whose rows are stored in the same order
+    let in_order_cnt = entries
+        .windows(2)
+        .filter(|pair| {
+            (pair[0].1.page_no, pair[0].1.offset) < (pair[1].1.page_no, pair[1].1.offset)
+        })
+        .count();
+    let keys = entries.into_iter().map(|(key, _)| key).collect::<Vec<_>>();
+    let distinct_cnt =
+        keys.windows(2).filter(|pair| pair[0] != pair[1]).count() as u64 + !keys.is_empty() as u64;
+    Ok(IndexStats {
+        entry_cnt,
+        distinct_cnt,
+        height: tree_stats.height() as u64,
+        leaf_page_cnt: tree_stats.levels.last().map_or(0, |level| level.page_cnt) as u64,
+        correlation: match entry_cnt {
+            0 | 1 => 1.0,
+            _ => in_order_cnt as f64 / (entry_cnt - 1) as f64,
+        },
+        histogram: Histogram::from_sorted(keys, bucket_cnt),
+    })
+}
+
+/// A secondary index's definition, see `Table::create_index`.
+#[derive(Debug, Clone, PartialEq)]
+pub struct IndexDef {
+    pub name: String,
+    /// The schema the table's rows are encoded with.
+    pub schema: Schema,
+    /// The indexed column. Rows where it's NULL aren't indexed, as no comparison matches them.
+    pub column: usize,
+    /// Makes the index partial: only the rows it matches are indexed, e.g. `status = 'active'`
+    /// to index just a table's hot rows. Filters only get to use a partial index if they imply
+    /// its predicate, see `Expr::implies`.
+    pub predicate: Option<Expr>,
+}
+
+impl IndexDef {
+    /// Defines an index called `name` on the column called `column`, failing with
+    /// `Error::TypeMismatch` if `schema` has no such column.
+    pub fn new(schema: &Schema, name: &str, column: &str) -> Result<Self> {
+        let column = schema
+            .column_idx(column)
+            .ok_or_else(|| Error::TypeMismatch(format!("no column {}", column)))?;
+        Ok(IndexDef {
+            name: name.to_string(),
+            schema: schema.clone(),
+            column,
+            predicate: None,
+        })
+    }
+
+    /// Only indexes the rows `predicate` matches.
+    pub fn with_predicate(mut self, predicate: Expr) -> Result<Self> {
+        predicate.check(&self.schema)?;
+        self.predicate = Some(predicate);
+        Ok(self)
+    }
+
+    /// Whether every row `filter` matches is indexed, unless its column is NULL.
+    fn covers(&self, filter: &Expr) -> bool {
+        self.predicate
+            .as_ref()
+            .is_none_or(|predicate| filter.implies(predicate))
+    }
+}
+
+struct SecondaryIndex<PageFetcher>
+where
+    PageFetcher: PageFetcherTrait,
+{
+    def: IndexDef,
+    // Keyed by the indexed field followed by the row's key, so that keys are unique
+    tree: BTree<KeyEncoded, ValueTupleId, PageFetcher>,
+}
+
+impl<PageFetcher> SecondaryIndex<PageFetcher>
+where
+    PageFetcher: PageFetcherTrait,
+{
+    /// The entry's key for `row`, stored under `key`, or `None` if the row isn't indexed.
+    fn index_key<K: Key>(&self, key: &K, row: &[u8]) -> Result<Option<KeyEncoded>> {
+        let row = self.def.schema.row_ref(row)?;
+        if let Some(predicate) = &self.def.predicate {
+            if !predicate.matches(&row)? {
+                return Ok(None);
+            }
+        }
+        match row.get(self.def.column)? {
+            Field::Null => Ok(None),
+            value => Ok(Some(KeyEncoded::new(&(value, item_to_bytes(key))))),
+        }
+    }
+
+    /// The values of the indexed column `filter` constrains it to. Bounds of another type than
+    /// the column's are left out, as they're encoded differently from its values, and the rows
+    /// within the range are checked against `filter` anyway.
+    fn key_predicate(&self, filter: &Expr) -> KeyPredicate<Field> {
+        let column_type = self.def.schema.columns()[self.def.column].column_type;
+        let bound = |bound: Bound<Field>| match bound {
+            Bound::Included(value) | Bound::Excluded(value) if !value.matches(column_type) => {
+                Bound::Unbounded
+            }
+            bound => bound,
+        };
+        match filter.key_predicate(self.def.column) {
+            Some(KeyPredicate::Eq(value)) if value.matches(column_type) => KeyPredicate::Eq(value),
+            Some(KeyPredicate::Range(start, end)) => KeyPredicate::Range(bound(start), bound(end)),
+            _ => KeyPredicate::Range(Bound::Unbounded, Bound::Unbounded),
+        }
+    }
+}
+
+/// Pairs each of a row's secondary index keys with the row's id.
+fn with_id(
+    index_keys: Vec<Option<KeyEncoded>>,
+    id: ValueTupleId,
+) -> Vec<Option<(KeyEncoded, ValueTupleId)>> {
+    index_keys
+        .into_iter()
+        .map(|index_key| index_key.map(|index_key| (index_key, id)))
+        .collect()
+}
+
+/// Replaces the entry `from` of `tree` with `to`, either of which may be missing, leaving `tree`
+/// as it was if that fails.
+fn swap_entry<PageFetcher>(
+    tree: &mut BTree<KeyEncoded, ValueTupleId, PageFetcher>,
+    from: &Option<(KeyEncoded, ValueTupleId)>,
+    to: &Option<(KeyEncoded, ValueTupleId)>,
+) -> Result<()>
+where
+    PageFetcher: PageFetcherTrait,
+{
+    if let Some((key, _)) = from {
+        tree.delete(key.clone())?;
+    }
+    if let Some((key, id)) = to {
+        if let Err(err) = tree.insert(key.clone(), *id) {
+            if let Some((key, id)) = from {
+                tree.insert(key.clone(), *id)?;
+            }
+            return Err(err);
+        }
+    }
+    Ok(())
+}
+
+/// The smallest index key above the keys of every row whose indexed column is `value`.
+fn prefix_end(value: &Field) -> KeyEncoded {
+    // As in `JsonIndex::lookup`, the encoding starts with a type byte that's never 0xFF for an
+    // indexed value, so bumping the last byte below 0xFF gives an upper bound
+    let mut end = encode(value);
+    while end.last() == Some(&0xFF) {
+        end.pop();
+    }
+    *end.last_mut().unwrap() += 1;
+    KeyEncoded::new(&Encoded(&end))
+}
+
+pub struct Scan<'a, K, PageFetcher>
+where
+    K: Key,
+    PageFetcher: PageFetcherTrait,
+{
+    index: &'a BTree<K, ValueTupleId, PageFetcher>,
+    iter: RangeIter<'a, PageFetcher, K, ValueTupleId>,
+    snapshot: Option<Snapshot>,
+    filter: Option<RowFilter<'a, K>>,
+}
+
+/// A predicate over a key and its row, see `Table::scan_filter` and `Table::scan_where`.
+type RowFilter<'a, K> = Box<dyn FnMut(&K, &[u8]) -> Result<bool> + 'a>;
+
+impl<'a, K, PageFetcher> Iterator for Scan<'a, K, PageFetcher>
+where
+    K: Key,
+    PageFetcher: PageFetcherTrait,
+{
+    type Item = Result<(K, Vec<u8>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let (key, id) = match self.iter.next()? {
+                Ok(entry) => entry,
+                Err(err) => return Some(Err(err)),
+            };
+            let latest = Snapshot::latest();
+            let snapshot = self.snapshot.as_ref().unwrap_or(&latest);
+            let filter = &mut self.filter;
+            let row = self
+                .index
+                .visit_tuple(id, snapshot, |row| {
+                    let matches = match filter.as_mut() {
+                        Some(filter) => filter(&key, row)?,
+                        None => true,
+                    };
+                    Ok(matches.then(|| row.to_vec()))
+                })
+                .and_then(|row| row.transpose());
+            match row {
+                Ok(Some(Some(row))) => return Some(Ok((key, row))),
+                Ok(Some(None)) => continue,
+                // Rows that aren't visible to the scan's own snapshot are skipped, but without
+                // one the index must not point at deleted rows
+                Ok(None) if self.snapshot.is_some() => continue,
+                Ok(None) => return Some(Err(dangling(id))),
+                Err(err) => return Some(Err(err)),
+            }
+        }
+    }
+}
+
+pub struct ProjectedScan<'a, K, PageFetcher>
+where
+    K: Key,
+    PageFetcher: PageFetcherTrait,
+{
+    index: &'a BTree<K, ValueTupleId, PageFetcher>,
+    iter: RangeIter<'a, PageFetcher, K, ValueTupleId>,
+    projection: Projection<'a>,
+}
+
+impl<'a, K, PageFetcher> Iterator for ProjectedScan<'a, K, PageFetcher>
+where
+    K: Key,
+    PageFetcher: PageFetcherTrait,
+{
+    type Item = Result<(K, Vec<Field>)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let projection = &self.projection;
+        let index = self.index;
+        self.iter.next().map(|item| {
+            let (key, id) = item?;
+            let fields = index
+                .visit_tuple(id, &Snapshot::latest(), |row| projection.decode(row))?
+                .ok_or_else(|| dangling(id))??;
+            Ok((key, fields))
+        })
+    }
+}
+
+fn load_row<K, PageFetcher>(
+    index: &BTree<K, ValueTupleId, PageFetcher>,
+    id: ValueTupleId,
+) -> Result<Vec<u8>>
+where
+    K: Key,
+    PageFetcher: PageFetcherTrait,
+{
+    index.get_tuple(id)?.ok_or_else(|| dangling(id))
+}
+
+fn dangling(id: ValueTupleId) -> Error {
+    Error::page_corruption(
+        id.page_no,
+        format!("index points at missing tuple {}", id.offset),
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use super::IndexDef;
+    use super::Table;
+    use crate::btree::heap::Snapshot;
+    use crate::btree::key::KeyU32;
+    use crate::error::Error;
+    use crate::expr::ArithOp;
+    use crate::expr::CmpOp;
+    use crate::expr::Expr;
+    use crate::faulty_page_fetcher::Call;
+    use crate::faulty_page_fetcher::Fault;
+    use crate::faulty_page_fetcher::FaultyPageFetcher;
+    use crate::page_fetcher::InMemoryPageFetcher;
+    use crate::page_fetcher::PageFetcher;
+    use crate::planner::AccessPath;
+    use crate::planner::KeyPredicate;
+    use crate::row::Column;
+    use crate::row::ColumnType;
+    use crate::row::Field;
+    use crate::row::Schema;
+    use std::ops::Bound;
+
+    fn row(key: u32, version: u32) -> Vec<u8> {
+        format!("row {} version {}", key, version)
+            .repeat(key as usize % 7 + 1)
+            .into_bytes()
+    }
+
+    #[test]
+    fn insert_get_scan_delete() {
+        let mut table = Table::new(InMemoryPageFetcher::with_capacity(512)).unwrap();
+        for key in 0..1500u32 {
+            table.insert_row(KeyU32 { key }, &row(key, 0)).unwrap();
+        }
+        for key in (0..1500u32).step_by(2) {
+            table.insert_row(KeyU32 { key }, &row(key, 1)).unwrap();
+        }
+        for key in (0..1500u32).step_by(5) {
+            let version = (key % 2 == 0) as u32;
+            assert_eq!(
+                table.delete_by_key(KeyU32 { key }).unwrap(),
+                Some(row(key, version))
+            );
+        }
+        assert_eq!(table.delete_by_key(KeyU32 { key: 0 }).unwrap(), None);
+
+        assert_eq!(table.get_by_key(KeyU32 { key: 5 }).unwrap(), None);
+        assert_eq!(
+            table.get_by_key(KeyU32 { key: 6 }).unwrap(),
+            Some(row(6, 1))
+        );
+        assert_eq!(
+            table.get_by_key(KeyU32 { key: 7 }).unwrap(),
+            Some(row(7, 0))
+        );
+
+        let rows = table
+            .scan(KeyU32 { key: 100 }..KeyU32 { key: 200 })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let expected = (100..200u32)
+            .filter(|key| key % 5 != 0)
+            .map(|key| (KeyU32 { key }, row(key, (key % 2 == 0) as u32)))
+            .collect::<Vec<_>>();
+        assert_eq!(rows, expected);
+        assert!(table.index().verify().is_ok());
+    }
+
+    #[test]
+    fn scan_in_snapshot() {
+        let mut table = Table::new(InMemoryPageFetcher::new()).unwrap();
+        for key in 0..10u32 {
+            table.insert_row(KeyU32 { key }, &row(key, 0)).unwrap();
+        }
+        // Transaction 5 inserts a row and deletes another without having committed yet
+        let id = table.index.insert_tuple_in(5, &row(10, 0)).unwrap();
+        table.index.insert(KeyU32 { key: 10 }, id).unwrap();
+        let id = table
+            .index
+            .search(KeyU32 { key: 3 })
+            .unwrap()
+            .value
+            .unwrap();
+        table.index.delete_tuple_in(id, 5).unwrap();
+
+        let keys = |snapshot| {
+            table
+                .scan_in(.., snapshot)
+                .unwrap()
+                .map(|entry| entry.unwrap().0.key)
+                .collect::<Vec<_>>()
+        };
+        let running = Snapshot {
+            current: 6,
+            xmax: 7,
+            in_progress: vec![5],
+        };
+        assert_eq!(keys(running), (0..10).collect::<Vec<_>>());
+        let committed = Snapshot {
+            current: 6,
+            xmax: 7,
+            in_progress: vec![],
+        };
+        assert_eq!(
+            keys(committed),
+            (0..11).filter(|key| *key != 3).collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn scan_in_skips_hinted_dead_rows() {
+        let mut table = Table::new(InMemoryPageFetcher::with_capacity(512)).unwrap();
+        for key in 0..2000u32 {
+            table.insert_row(KeyU32 { key }, &row(key, 0)).unwrap();
+        }
+        // Transaction 5 deletes all but every tenth row and commits
+        for key in (0..2000u32).filter(|key| key % 10 != 0) {
+            let id = table.index.search(KeyU32 { key }).unwrap().value.unwrap();
+            table.index.delete_tuple_in(id, 5).unwrap();
+        }
+        let snapshot = Snapshot {
+            current: 6,
+            xmax: 7,
+            in_progress: vec![],
+        };
+        let scan = |table: &Table<KeyU32, InMemoryPageFetcher>| {
+            let fetches = table.index.page_fetcher().metrics().pool_hits.get();
+            let keys = table
+                .scan_in(.., snapshot.clone())
+                .unwrap()
+                .map(|entry| entry.unwrap().0.key)
+                .collect::<Vec<_>>();
+            let fetches = table.index.page_fetcher().metrics().pool_hits.get() - fetches;
+            (keys, fetches)
+        };
+        let expected = (0..2000).step_by(10).collect::<Vec<_>>();
+        let (keys, unhinted_fetches) = scan(&table);
+        assert_eq!(keys, expected);
+
+        // Nothing's hinted below the horizon of a transaction that may not see the deletes
+        let stats = table.vacuum(5).unwrap();
+        assert_eq!(stats.tuples_hinted, 0);
+        let stats = table.vacuum(6).unwrap();
+        assert_eq!(stats.tuples_hinted, 1800);
+        assert_eq!(table.vacuum(6).unwrap().tuples_hinted, 0);
+        let (keys, hinted_fetches) = scan(&table);
+        assert_eq!(keys, expected);
+        assert!(
+            hinted_fetches * 5 < unhinted_fetches,
+            "{} {}",
+            hinted_fetches,
+            unhinted_fetches
+        );
+
+        // Rewritten entries start out without hints
+        table.insert_row(KeyU32 { key: 1 }, &row(1, 1)).unwrap();
+        assert_eq!(scan(&table).0[..2], [0, 1]);
+    }
+
+    #[test]
+    fn scan_filter_on_rows() {
+        let mut table = Table::new(InMemoryPageFetcher::with_capacity(128)).unwrap();
+        for key in 0..500u32 {
+            table.insert_row(KeyU32 { key }, &row(key, 0)).unwrap();
+        }
+
+        let rows = table
+            .scan_filter(KeyU32 { key: 10 }.., |key, row| {
+                key.key % 3 == 0 && row.len() > 40
+            })
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let expected = (10..500u32)
+            .filter(|key| key % 3 == 0 && row(*key, 0).len() > 40)
+            .map(|key| (KeyU32 { key }, row(key, 0)))
+            .collect::<Vec<_>>();
+        assert!(!expected.is_empty());
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn scan_project_decodes_only_requested_columns() {
+        let schema = Schema::new(vec![
+            Column {
+                name: "id".to_string(),
+                column_type: ColumnType::U32,
+                nullable: false,
+            },
+            Column {
+                name: "body".to_string(),
+                column_type: ColumnType::Text,
+                nullable: false,
+            },
+            Column {
+                name: "score".to_string(),
+                column_type: ColumnType::I64,
+                nullable: true,
+            },
+        ]);
+        let mut table = Table::new(InMemoryPageFetcher::with_capacity(256)).unwrap();
+        for key in 0..300u32 {
+            let fields = [
+                Field::U32(key),
+                Field::Text("lorem ipsum ".repeat(100)),
+                Field::I64(-(key as i64)),
+            ];
+            table
+                .insert_row(KeyU32 { key }, &schema.encode(&fields).unwrap())
+                .unwrap();
+        }
+
+        let projection = schema.projection(&["score", "id"]).unwrap();
+        let rows = table
+            .scan_project(KeyU32 { key: 290 }.., projection)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let expected = (290..300u32)
+            .map(|key| {
+                let fields = vec![Field::I64(-(key as i64)), Field::U32(key)];
+                (KeyU32 { key }, fields)
+            })
+            .collect::<Vec<_>>();
+        assert_eq!(rows, expected);
+    }
+
+    #[test]
+    fn scan_where_evaluates_expressions() {
+        let schema = Schema::new(vec![
+            Column {
+                name: "id".to_string(),
+                column_type: ColumnType::U32,
+                nullable: false,
+            },
+            Column {
+                name: "score".to_string(),
+                column_type: ColumnType::I64,
+                nullable: true,
+            },
+        ]);
+        let mut table = Table::new(InMemoryPageFetcher::with_capacity(128)).unwrap();
+        for key in 0..200u32 {
+            let score = match key % 10 {
+                0 => Field::Null,
+                _ => Field::I64(key as i64 * 3),
+            };
+            let row = schema.encode(&[Field::U32(key), score]).unwrap();
+            table.insert_row(KeyU32 { key }, &row).unwrap();
+        }
+
+        // score - id > 300 OR score IS NULL
+        let column = |name| Expr::column(&schema, name).unwrap();
+        let expr = Expr::cmp(
+            CmpOp::Gt,
+            Expr::arith(ArithOp::Sub, column("score"), column("id")),
+            Expr::Const(Field::I64(300)),
+        )
+        .or(column("score").is_null());
+        let keys = table
+            .scan_where(KeyU32 { key: 100 }.., &schema, &expr)
+            .unwrap()
+            .map(|row| row.map(|(key, _)| key.key))
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let expected = (100..200u32)
+            .filter(|key| key % 10 == 0 || key * 2 > 300)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, expected);
+
+        let not_bool = Expr::column(&schema, "score").unwrap();
+        let mut scan = table.scan_where(.., &schema, &not_bool).unwrap();
+        assert!(matches!(scan.next(), Some(Err(Error::TypeMismatch(_)))));
+    }
+
+    #[test]
+    fn plan_from_statistics() {
+        let mut table = Table::new(InMemoryPageFetcher::with_capacity(512)).unwrap();
+        for key in 0..5000u32 {
+            table.insert_row(KeyU32 { key }, &[7; 200]).unwrap();
+        }
+        let stats = table.analyze(50).unwrap();
+        assert_eq!(stats.table.row_cnt, 5000);
+        assert!(stats.table.heap_page_cnt > 100, "{:?}", stats.table);
+        assert_eq!(stats.primary.histogram.bounds.len(), 50);
+        assert_eq!(stats.primary.correlation, 1.0);
+
+        let plan = table.plan(&stats, &KeyPredicate::Eq(KeyU32 { key: 10 }));
+        assert_eq!(plan.path, AccessPath::PrimaryIndex);
+        let range = KeyPredicate::Range(
+            Bound::Included(KeyU32 { key: 100 }),
+            Bound::Excluded(KeyU32 { key: 150 }),
+        );
+        assert_eq!(table.plan(&stats, &range).path, AccessPath::PrimaryIndex);
+        let plan = table.plan(
+            &stats,
+            &KeyPredicate::Range(Bound::Unbounded, Bound::Unbounded),
+        );
+        assert_eq!(plan.path, AccessPath::FullScan);
+        assert!(
+            plan.to_string().starts_with("full scan (rows=5000 "),
+            "{}",
+            plan
+        );
+    }
+
+    #[test]
+    fn partial_index_on_hot_rows() {
+        let column = |name: &str, column_type| Column {
+            name: name.to_string(),
+            column_type,
+            nullable: false,
+        };
+        let schema = Schema::new(vec![
+            column("id", ColumnType::U32),
+            column("status", ColumnType::Text),
+            column("score", ColumnType::I64),
+            column("note", ColumnType::Text),
+        ]);
+        let encode = |key: u32, status: &str| {
+            let fields = [
+                Field::U32(key),
+                Field::Text(status.to_string()),
+                Field::I64(key as i64 / 100),
+                Field::Text("lorem ipsum ".repeat(30)),
+            ];
+            schema.encode(&fields).unwrap()
+        };
+        let fetcher = InMemoryPageFetcher::with_capacity(1024);
+        let mut table = Table::new(&fetcher).unwrap();
+        for key in 0..6000u32 {
+            let status = match key % 100 {
+                0 => "active",
+                _ => "archived",
+            };
+            table
+                .insert_row(KeyU32 { key }, &encode(key, status))
+                .unwrap();
+        }
+
+        let col = |name| Expr::column(&schema, name).unwrap();
+        let active = Expr::cmp(
+            CmpOp::Eq,
+            col("status"),
+            Expr::Const(Field::Text("active".to_string())),
+        );
+        let def = IndexDef::new(&schema, "active_by_score", "score")
+            .unwrap()
+            .with_predicate(active.clone())
+            .unwrap();
+        assert_eq!(table.create_index(&fetcher, def.clone()).unwrap(), 60);
+        assert!(matches!(
+            table.create_index(&fetcher, def),
+            Err(Error::TreeExists(_))
+        ));
+
+        // Rows come and go from the index as they're inserted, replaced and deleted
+        table
+            .insert_row(KeyU32 { key: 6000 }, &encode(6000, "active"))
+            .unwrap();
+        table
+            .insert_row(KeyU32 { key: 100 }, &encode(100, "archived"))
+            .unwrap();
+        table
+            .insert_row(KeyU32 { key: 101 }, &encode(101, "active"))
+            .unwrap();
+        table.delete_by_key(KeyU32 { key: 200 }).unwrap();
+
+        // score < 5 AND status = 'active'
+        let query =
+            Expr::cmp(CmpOp::Lt, col("score"), Expr::Const(Field::I64(5))).and(active.clone());
+        let keys = table
+            .scan_index("active_by_score", &query)
+            .unwrap()
+            .into_iter()
+            .map(|(key, _)| key.key)
+            .collect::<Vec<_>>();
+        assert_eq!(keys, [0, 101, 300, 400]);
+
+        let stats = table.analyze(20).unwrap();
+        assert_eq!(stats.indexes[0].1.entry_cnt, 60);
+        let index = AccessPath::SecondaryIndex("active_by_score".to_string());
+        assert_eq!(table.plan_where(&stats, &query).path, index);
+        assert_eq!(table.plan_where(&stats, &active).path, index);
+        // Archived rows aren't in the index, so it can't serve queries that may match them
+        let all = Expr::cmp(CmpOp::Ge, col("score"), Expr::Const(Field::I64(5)));
+        assert_eq!(table.plan_where(&stats, &all).path, AccessPath::FullScan);
+        assert!(matches!(
+            table.scan_index("active_by_score", &all),
+            Err(Error::Eval(_))
+        ));
+        assert!(matches!(
+            table.scan_index("missing", &active),
+            Err(Error::TreeNotFound(_))
+        ));
+
+        let metadata_no = table.index_metadata_no("active_by_score").unwrap();
+        let def = IndexDef::new(&schema, "active_by_score", "score")
+            .unwrap()
+            .with_predicate(active.clone())
+            .unwrap();
+        let mut reopened = Table::<KeyU32, _>::new(&fetcher).unwrap();
+        reopened.open_index(&fetcher, def, metadata_no).unwrap();
+        assert_eq!(
+            reopened
+                .scan_index("active_by_score", &query)
+                .unwrap()
+                .len(),
+            keys.len()
+        );
+    }
+
+    #[test]
+    fn failed_replace_leaves_indexes_unchanged() {
+        let column = |name: &str, column_type| Column {
+            name: name.to_string(),
+            column_type,
+            nullable: false,
+        };
+        let schema = Schema::new(vec![
+            column("id", ColumnType::U32),
+            column("status", ColumnType::Text),
+            column("score", ColumnType::I64),
+        ]);
+        let encode = |key: u32, status: &str, score: i64| {
+            let fields = [
+                Field::U32(key),
+                Field::Text(status.to_string()),
+                Field::I64(score),
+            ];
+            schema.encode(&fields).unwrap()
+        };
+        let fetcher = FaultyPageFetcher::new(InMemoryPageFetcher::new());
+        let mut table = Table::new(&fetcher).unwrap();
+        for key in 0..200u32 {
+            table
+                .insert_row(KeyU32 { key }, &encode(key, "old", key as i64))
+                .unwrap();
+        }
+        for (name, column) in [("by_status", "status"), ("by_score", "score")] {
+            let def = IndexDef::new(&schema, name, column).unwrap();
+            table.create_index(&fetcher, def).unwrap();
+        }
+
+        let col = |name| Expr::column(&schema, name).unwrap();
+        let lookup = |table: &Table<KeyU32, _>, name, column, value| {
+            let query = Expr::cmp(CmpOp::Eq, col(column), Expr::Const(value));
+            table
+                .scan_index(name, &query)
+                .unwrap()
+                .into_iter()
+                .map(|(key, _)| key.key)
+                .collect::<Vec<_>>()
+        };
+        let old = encode(7, "old", 7);
+        let new = encode(7, "new", 1000);
+        let old_text = || Field::Text("old".to_string());
+        let new_text = || Field::Text("new".to_string());
+
+        // Fail each page write the replace makes in turn, until it gets through
+        let mut failed = 0;
+        for nth in 1.. {
+            fetcher.fault_nth(Call::Write, nth, Fault::Io);
+            let result = table.insert_row(KeyU32 { key: 7 }, &new);
+            fetcher.clear_faults();
+            assert!(table.index().verify().unwrap().is_ok());
+            if let Err(err) = result {
+                assert!(matches!(err.root_cause(), Error::Io(_)), "{:?}", err);
+                assert_eq!(
+                    table.get_by_key(KeyU32 { key: 7 }).unwrap(),
+                    Some(old.clone())
+                );
+                assert_eq!(lookup(&table, "by_status", "status", old_text()).len(), 200);
+                assert_eq!(lookup(&table, "by_status", "status", new_text()), []);
+                assert_eq!(lookup(&table, "by_score", "score", Field::I64(7)), [7]);
+                assert_eq!(lookup(&table, "by_score", "score", Field::I64(1000)), []);
+                failed += 1;
+                continue;
+            }
+
+            assert_eq!(table.get_by_key(KeyU32 { key: 7 }).unwrap(), Some(new));
+            assert_eq!(lookup(&table, "by_status", "status", old_text()).len(), 199);
+            assert_eq!(lookup(&table, "by_status", "status", new_text()), [7]);
+            assert_eq!(lookup(&table, "by_score", "score", Field::I64(7)), []);
+            assert_eq!(lookup(&table, "by_score", "score", Field::I64(1000)), [7]);
+            break;
+        }
+        assert!(failed > 4, "{}", failed);
+    }
+
+    #[cfg(feature = "parquet")]
+    #[test]
+    fn export_parquet() {
+        use parquet::file::reader::FileReader;
+        use parquet::file::reader::SerializedFileReader;
+        use parquet::record::RowAccessor;
+
+        let column = |name: &str, column_type, nullable| Column {
+            name: name.to_string(),
+            column_type,
+            nullable,
+        };
+        let schema = Schema::new(vec![
+            column("id", ColumnType::U32, false),
+            column("name", ColumnType::Text, true),
+            column("balance", ColumnType::I64, false),
+            column("ratio", ColumnType::F64, false),
+            column("active", ColumnType::Bool, false),
+            column("raw", ColumnType::Bytes, false),
+            column("big", ColumnType::U64, false),
+        ]);
+        let mut table = Table::new(InMemoryPageFetcher::with_capacity(64)).unwrap();
+        for key in 0..100u32 {
+            let name = match key % 3 {
+                0 => Field::Null,
+                _ => Field::Text(format!("user-{}", key)),
+            };
+            let fields = [
+                Field::U32(key),
+                name,
+                Field::I64(-(key as i64)),
+                Field::F64(key as f64 / 4.0),
+                Field::Bool(key % 2 == 0),
+                Field::Bytes(vec![key as u8; 3]),
+                Field::U64(u64::MAX - key as u64),
+            ];
+            table
+                .insert_row(KeyU32 { key }, &schema.encode(&fields).unwrap())
+                .unwrap();
+        }
+
+        let path = std::env::temp_dir().join(format!("johndb-parquet-{}", std::process::id()));
+        let file = std::fs::File::create(&path).unwrap();
+        let range = KeyU32 { key: 10 }..KeyU32 { key: 20 };
+        assert_eq!(table.export_parquet(range, &schema, file).unwrap(), 10);
+
+        let reader = SerializedFileReader::new(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(reader.metadata().file_metadata().num_rows(), 10);
+        let rows = reader
+            .get_row_iter(None)
+            .unwrap()
+            .collect::<Result<Vec<_>, _>>()
+            .unwrap();
+        let row = &rows[2];
+        assert_eq!(row.get_uint(0).unwrap(), 12);
+        assert!(row.get_string(1).is_err());
+        assert_eq!(row.get_long(2).unwrap(), -12);
+        assert_eq!(row.get_double(3).unwrap(), 3.0);
+        assert!(row.get_bool(4).unwrap());
+        assert_eq!(row.get_bytes(5).unwrap().data(), &[12, 12, 12]);
+        assert_eq!(row.get_ulong(6).unwrap(), u64::MAX - 12);
+        assert_eq!(rows[3].get_string(1).unwrap(), "user-13");
+
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,121 @@
+//! Batched page I/O through Linux's io_uring, used by `FilePageFetcher` when the `io-uring`
+//! feature is enabled. Up to `BATCH_SIZE` page reads or writes are handed to the kernel with a
+//! single system call and run concurrently, rather than one `read`/`write` call after another.
+
+use crate::error::Error;
+use crate::error::Result;
+use crate::page::Page;
+use crate::page::PAGE_SIZE;
+use io_uring::opcode;
+use io_uring::squeue;
+use io_uring::types;
+use io_uring::IoUring;
+use std::fs::File;
+use std::io;
+use std::os::unix::io::AsRawFd;
+
+/// Pages read or written per submission.
+pub(crate) const BATCH_SIZE: usize = 64;
+
+pub(crate) struct Uring {
+    ring: IoUring,
+}
+
+impl Uring {
+    /// Sets up a ring, or returns `None` if the kernel doesn't offer io_uring (it's often disabled
+    /// in containers), in which case the caller should stick to synchronous I/O.
+    pub(crate) fn new() -> Option<Self> {
+        IoUring::new(BATCH_SIZE as u32)
+            .ok()
+            .map(|ring| Uring { ring })
+    }
+
+    /// Reads the pages starting at `first_page_no` into `pages`.
+    pub(crate) fn read_pages(
+        &mut self,
+        file: &File,
+        first_page_no: usize,
+        pages: &mut [Page],
+    ) -> Result<()> {
+        let fd = types::Fd(file.as_raw_fd());
+        let entries = pages
+            .iter_mut()
+            .enumerate()
+            .map(|(idx, page)| {
+                opcode::Read::new(fd, page.as_bytes_mut().as_mut_ptr(), PAGE_SIZE as u32)
+                    .offset(((first_page_no + idx) * PAGE_SIZE) as u64)
+                    .build()
+            })
+            .collect::<Vec<_>>();
+
+        // `pages` stays borrowed until every read has completed
+        self.run(entries)
+    }
+
+    /// Writes `pages` to the file, starting at `first_page_no`.
+    pub(crate) fn write_pages(
+        &mut self,
+        file: &File,
+        first_page_no: usize,
+        pages: &[&Page],
+    ) -> Result<()> {
+        let fd = types::Fd(file.as_raw_fd());
+        let entries = pages
+            .iter()
+            .enumerate()
+            .map(|(idx, page)| {
+                opcode::Write::new(fd, page.as_bytes().as_ptr(), PAGE_SIZE as u32)
+                    .offset(((first_page_no + idx) * PAGE_SIZE) as u64)
+                    .build()
+            })
+            .collect::<Vec<_>>();
+
+        self.run(entries)
+    }
+
+    /// Submits `entries` and waits for all of them to complete, checking that each one transferred
+    /// a whole page. Their buffers must stay valid until this returns.
+    fn run(&mut self, entries: Vec<squeue::Entry>) -> Result<()> {
+        assert!(entries.len() <= BATCH_SIZE);
+        if entries.is_empty() {
+            return Ok(());
+        }
+
+        {
+            let mut submission = self.ring.submission();
+            for entry in entries.iter() {
+                unsafe { submission.push(entry) }.map_err(|_| {
+                    Error::Io(io::Error::other("io_uring submission queue is full"))
+                })?;
+            }
+        }
+        self.ring.submit_and_wait(entries.len())?;
+
+        // Drain every completion before checking them, so none are left for the next batch
+        let results = self
+            .ring
+            .completion()
+            .map(|cqe| cqe.result())
+            .collect::<Vec<_>>();
+        if results.len() != entries.len() {
+            return Err(Error::Io(io::Error::other(format!(
+                "{} io_uring completions for {} requests",
+                results.len(),
+                entries.len()
+            ))));
+        }
+        for result in results {
+            if result < 0 {
+                return Err(Error::Io(io::Error::from_raw_os_error(-result)));
+            }
+            if result as usize != PAGE_SIZE {
+                return Err(Error::Io(io::Error::new(
+                    io::ErrorKind::UnexpectedEof,
+                    format!("transferred {} of a page's {} bytes", result, PAGE_SIZE),
+                )));
+            }
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,407 @@
+//! The framing of write-ahead log records. Nothing writes a log yet; this pins down the on-disk
+//! format so that recovery and third-party tools agree on how to parse one.
+//!
+//! A log is a series of segment files, each starting with a header and followed by records.
+//! All integers are big-endian and checksums are CRC-32C.
+//!
+//! The segment header is 32 bytes: the magic bytes `JOHNDWAL`, the format version as a u32, the
+//! segment's sequence number as a u64, the LSN of its first record as a u64, then a checksum of
+//! the preceding 28 bytes. Segment sequence numbers increase by one from each segment to the
+//! next, and a segment's first LSN follows on from the last record of the previous one.
+//!
+//! Each record is:
+//!
+//! ```text
+//! length: u32      length of the payload, at most MAX_PAYLOAD_SIZE
+//! type: u8         what the payload holds, never 0
+//! lsn: u64         the record's log sequence number, one more than the previous record's
+//! payload
+//! crc: u32         checksum of the length, type, LSN and payload
+//! ```
+//!
+//! A write interrupted by a crash leaves a torn record at the end of the last segment. Reading
+//! stops at the first record that's cut short, has a length over `MAX_PAYLOAD_SIZE`, a type of 0
+//! (as in zeroed, preallocated space), an unexpected LSN, or a checksum mismatch. That record
+//! and anything after it are discarded, and the segment should be truncated to
+//! `SegmentReader::valid_len` before more records are appended.
+
+use crate::checksum::crc32c;
+use crate::error::Error;
+use crate::error::Result;
+use std::convert::TryInto;
+use std::io;
+use std::io::Read;
+use std::io::Write;
+
+const MAGIC: &[u8; 8] = b"JOHNDWAL";
+pub const FORMAT_VERSION: u32 = 1;
+pub const SEGMENT_HEADER_SIZE: usize = 32;
+pub const RECORD_HEADER_SIZE: usize = 13;
+const RECORD_TRAILER_SIZE: usize = 4;
+/// Longer records are treated as torn, so that a garbage length can't make readers allocate
+/// gigabytes.
+pub const MAX_PAYLOAD_SIZE: usize = 16 << 20;
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub struct SegmentHeader {
+    pub segment_no: u64,
+    pub first_lsn: u64,
+}
+
+impl SegmentHeader {
+    fn to_bytes(self) -> Vec<u8> {
+        let mut bytes = MAGIC.to_vec();
+        bytes.extend_from_slice(&FORMAT_VERSION.to_be_bytes());
+        bytes.extend_from_slice(&self.segment_no.to_be_bytes());
+        bytes.extend_from_slice(&self.first_lsn.to_be_bytes());
+        bytes.extend_from_slice(&crc32c(&bytes).to_be_bytes());
+        bytes
+    }
+
+    fn from_bytes(bytes: &[u8]) -> Result<Self> {
+        if &bytes[..MAGIC.len()] != MAGIC {
+            return Err(Error::corruption("not a johndb log segment".to_string()));
+        }
+        let crc = u32::from_be_bytes(bytes[28..].try_into().unwrap());
+        if crc != crc32c(&bytes[..28]) {
+            return Err(Error::corruption(
+                "log segment header checksum mismatch".to_string(),
+            ));
+        }
+        let version = u32::from_be_bytes(bytes[8..12].try_into().unwrap());
+        if version != FORMAT_VERSION {
+            return Err(Error::corruption(format!(
+                "unsupported log format version {}",
+                version
+            )));
+        }
+        Ok(SegmentHeader {
+            segment_no: u64::from_be_bytes(bytes[12..20].try_into().unwrap()),
+            first_lsn: u64::from_be_bytes(bytes[20..28].try_into().unwrap()),
+        })
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Record {
+    pub lsn: u64,
+    pub record_type: u8,
+    pub payload: Vec<u8>,
+}
+
+/// Appends records to a new segment.
+pub struct SegmentWriter<W: Write> {
+    writer: W,
+    next_lsn: u64,
+    len: u64,
+}
+
+impl<W: Write> SegmentWriter<W> {
+    /// Starts a segment by writing `header` to `writer`.
+    pub fn new(mut writer: W, header: SegmentHeader) -> Result<Self> {
+        writer.write_all(&header.to_bytes())?;
+        Ok(SegmentWriter {
+            writer,
+            next_lsn: header.first_lsn,
+            len: SEGMENT_HEADER_SIZE as u64,
+        })
+    }
+
+    /// Appends a record, returning its LSN. `record_type` must not be 0.
+    pub fn append(&mut self, record_type: u8, payload: &[u8]) -> Result<u64> {
+        assert!(record_type != 0, "record type 0 is reserved");
+        if payload.len() > MAX_PAYLOAD_SIZE {
+            return Err(Error::ItemTooLarge(payload.len()));
+        }
+
+        let lsn = self.next_lsn;
+        let mut record =
+            Vec::with_capacity(RECORD_HEADER_SIZE + payload.len() + RECORD_TRAILER_SIZE);
+        record.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        record.push(record_type);
+        record.extend_from_slice(&lsn.to_be_bytes());
+        record.extend_from_slice(payload);
+        record.extend_from_slice(&crc32c(&record).to_be_bytes());
+        self.writer.write_all(&record)?;
+
+        self.next_lsn += 1;
+        self.len += record.len() as u64;
+        Ok(lsn)
+    }
+
+    /// The LSN the next record will get, which the next segment should start from.
+    pub fn next_lsn(&self) -> u64 {
+        self.next_lsn
+    }
+
+    /// Bytes written to the segment so far, header included.
+    pub fn bytes_written(&self) -> u64 {
+        self.len
+    }
+
+    pub fn flush(&mut self) -> Result<()> {
+        Ok(self.writer.flush()?)
+    }
+
+    pub fn into_inner(self) -> W {
+        self.writer
+    }
+}
+
+/// Reads the records of a segment back, stopping at a torn tail, see `crate::wal`.
+pub struct SegmentReader<R: Read> {
+    reader: R,
+    header: SegmentHeader,
+    next_lsn: u64,
+    valid_len: u64,
+    torn: bool,
+    done: bool,
+}
+
+impl<R: Read> SegmentReader<R> {
+    /// Reads and checks the segment header. Unlike records, a damaged header is an error, since
+    /// headers are written before anything else and never torn by a later write.
+    pub fn new(mut reader: R) -> Result<Self> {
+        let mut bytes = [0; SEGMENT_HEADER_SIZE];
+        reader.read_exact(&mut bytes)?;
+        let header = SegmentHeader::from_bytes(&bytes)?;
+        Ok(SegmentReader {
+            reader,
+            header,
+            next_lsn: header.first_lsn,
+            valid_len: SEGMENT_HEADER_SIZE as u64,
+            torn: false,
+            done: false,
+        })
+    }
+
+    pub fn header(&self) -> SegmentHeader {
+        self.header
+    }
+
+    /// Returns the next intact record, or `None` once the end of the segment or a torn record
+    /// is reached.
+    pub fn next_record(&mut self) -> Result<Option<Record>> {
+        if self.done {
+            return Ok(None);
+        }
+        let record = self.read_record()?;
+        match &record {
+            Some(record) => {
+                self.next_lsn += 1;
+                self.valid_len +=
+                    (RECORD_HEADER_SIZE + record.payload.len() + RECORD_TRAILER_SIZE) as u64;
+            }
+            None => self.done = true,
+        }
+        Ok(record)
+    }
+
+    fn read_record(&mut self) -> Result<Option<Record>> {
+        let mut header = [0; RECORD_HEADER_SIZE];
+        match read_full(&mut self.reader, &mut header)? {
+            0 => return Ok(None),
+            RECORD_HEADER_SIZE => {}
+            _ => return self.torn(),
+        }
+        let len = u32::from_be_bytes(header[..4].try_into().unwrap()) as usize;
+        let record_type = header[4];
+        let lsn = u64::from_be_bytes(header[5..].try_into().unwrap());
+        if len > MAX_PAYLOAD_SIZE || record_type == 0 || lsn != self.next_lsn {
+            return self.torn();
+        }
+
+        let mut rest = vec![0; len + RECORD_TRAILER_SIZE];
+        if read_full(&mut self.reader, &mut rest)? != rest.len() {
+            return self.torn();
+        }
+        let crc = u32::from_be_bytes(rest[len..].try_into().unwrap());
+        rest.truncate(len);
+        let mut checksummed = header.to_vec();
+        checksummed.extend_from_slice(&rest);
+        if crc != crc32c(&checksummed) {
+            return self.torn();
+        }
+
+        Ok(Some(Record {
+            lsn,
+            record_type,
+            payload: rest,
+        }))
+    }
+
+    fn torn(&mut self) -> Result<Option<Record>> {
+        self.torn = true;
+        Ok(None)
+    }
+
+    /// Whether reading stopped at a torn record rather than the end of the segment.
+    pub fn is_torn(&self) -> bool {
+        self.torn
+    }
+
+    /// Bytes of the segment up to the end of the last intact record read, header included.
+    pub fn valid_len(&self) -> u64 {
+        self.valid_len
+    }
+
+    /// The LSN the record after the last intact one gets.
+    pub fn next_lsn(&self) -> u64 {
+        self.next_lsn
+    }
+}
+
+impl<R: Read> Iterator for SegmentReader<R> {
+    type Item = Result<Record>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_record().transpose()
+    }
+}
+
+/// Fills `buf` from `reader` unless it runs out first, returning the number of bytes read.
+fn read_full<R: Read>(reader: &mut R, buf: &mut [u8]) -> Result<usize> {
+    let mut len = 0;
+    while len < buf.len() {
+        match reader.read(&mut buf[len..]) {
+            Ok(0) => break,
+            Ok(read) => len += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err.into()),
+        }
+    }
+    Ok(len)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Record;
+    use super::SegmentHeader;
+    use super::SegmentReader;
+    use super::SegmentWriter;
+    use super::SEGMENT_HEADER_SIZE;
+    use crate::error::Error;
+
+    fn write_segment(payloads: &[&[u8]]) -> Vec<u8> {
+        let header = SegmentHeader {
+            segment_no: 7,
+            first_lsn: 100,
+        };
+        let mut writer = SegmentWriter::new(Vec::new(), header).unwrap();
+        for (i, payload) in payloads.iter().enumerate() {
+            assert_eq!(writer.append(1 + i as u8, payload).unwrap(), 100 + i as u64);
+        }
+        assert_eq!(writer.next_lsn(), 100 + payloads.len() as u64);
+        let len = writer.bytes_written();
+        let bytes = writer.into_inner();
+        assert_eq!(bytes.len() as u64, len);
+        bytes
+    }
+
+    fn read_segment(bytes: &[u8]) -> (Vec<Record>, SegmentReader<&[u8]>) {
+        let mut reader = SegmentReader::new(bytes).unwrap();
+        let records = reader.by_ref().collect::<Result<Vec<_>, _>>().unwrap();
+        (records, reader)
+    }
+
+    #[test]
+    fn round_trip() {
+        let payloads: [&[u8]; 3] = [b"first", b"", &[9; 5000]];
+        let bytes = write_segment(&payloads);
+        let (records, reader) = read_segment(&bytes);
+        assert_eq!(
+            reader.header(),
+            SegmentHeader {
+                segment_no: 7,
+                first_lsn: 100
+            }
+        );
+        assert_eq!(records.len(), 3);
+        for (i, record) in records.iter().enumerate() {
+            assert_eq!(record.lsn, 100 + i as u64);
+            assert_eq!(record.record_type, 1 + i as u8);
+            assert_eq!(record.payload, payloads[i]);
+        }
+        assert!(!reader.is_torn());
+        assert_eq!(reader.valid_len(), bytes.len() as u64);
+        assert_eq!(reader.next_lsn(), 103);
+    }
+
+    #[test]
+    fn torn_tails() {
+        let bytes = write_segment(&[b"kept", b"torn record"]);
+        let kept_len = SEGMENT_HEADER_SIZE + 13 + 4 + 4;
+
+        // Cutting the last record short anywhere drops just that record
+        for len in kept_len + 1..bytes.len() {
+            let (records, reader) = read_segment(&bytes[..len]);
+            assert_eq!(records.len(), 1, "{}", len);
+            assert!(reader.is_torn());
+            assert_eq!(reader.valid_len(), kept_len as u64);
+        }
+        let (records, reader) = read_segment(&bytes[..kept_len]);
+        assert_eq!(records.len(), 1);
+        assert!(!reader.is_torn());
+
+        // As does damaging any of its bytes, or following it with zeroes
+        for pos in kept_len..bytes.len() {
+            let mut damaged = bytes.clone();
+            damaged[pos] ^= 0x40;
+            let (records, reader) = read_segment(&damaged);
+            assert_eq!(records.len(), 1, "{}", pos);
+            assert!(reader.is_torn());
+        }
+        let mut zeroed = bytes.clone();
+        zeroed.extend_from_slice(&[0; 100]);
+        let (records, reader) = read_segment(&zeroed);
+        assert_eq!(records.len(), 2);
+        assert!(reader.is_torn());
+        assert_eq!(reader.valid_len(), bytes.len() as u64);
+
+        let mut bad_header = bytes;
+        bad_header[15] ^= 1;
+        assert!(matches!(
+            SegmentReader::new(&bad_header[..]),
+            Err(Error::Corruption { .. })
+        ));
+    }
+
+    /// Reads and rewrites the segment written by `testdata/gen_golden_pages.py`, which encodes the
+    /// format independently of this crate.
+    #[test]
+    fn golden_segment() {
+        let golden = include_bytes!("../testdata/wal.segment");
+        let expected = [
+            (1, b"put key-1".to_vec()),
+            (2, Vec::new()),
+            (7, (0..20).flat_map(|_| 0..=255u8).collect()),
+        ];
+
+        let (records, reader) = read_segment(golden);
+        assert_eq!(
+            reader.header(),
+            SegmentHeader {
+                segment_no: 3,
+                first_lsn: 1000
+            }
+        );
+        assert!(!reader.is_torn());
+        assert_eq!(records.len(), expected.len());
+        for (i, (record, (record_type, payload))) in records.iter().zip(expected.iter()).enumerate()
+        {
+            assert_eq!(record.lsn, 1000 + i as u64);
+            assert_eq!(record.record_type, *record_type);
+            assert_eq!(record.payload, *payload);
+        }
+
+        let header = reader.header();
+        let mut writer = SegmentWriter::new(Vec::new(), header).unwrap();
+        for record in records {
+            writer.append(record.record_type, &record.payload).unwrap();
+        }
+        assert!(
+            writer.into_inner() == golden,
+            "differs from the golden segment"
+        );
+    }
+}
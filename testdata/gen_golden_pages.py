@@ -0,0 +1,116 @@
+#!/usr/bin/env python3
+"""Writes the golden page files loaded by the on-disk layout tests in `src/btree/mod.rs`, and the
+golden log segment loaded by those in `src/wal.rs`.
+
+The pages are built from the layout documented in `src/page.rs`, and the segment from the one
+documented in `src/wal.rs`, rather than by johndb itself, so the tests catch any change to the
+format, or any dependence on the platform johndb runs on.
+"""
+
+import os
+import struct
+
+PAGE_SIZE = 8192
+HEADER_SIZE = 12
+DATA_SIZE = PAGE_SIZE - HEADER_SIZE
+SPECIAL_SIZE = 8
+
+METADATA, INTERNAL, LEAF = 0, 1, 2
+FLAG_SORTED, FLAG_SEQUENTIAL, FLAG_INVALID_LINK, FLAG_UNBOUNDED = 1, 2, 4, 8
+INVALID_PAGE_NO = 0xFFFFFFFF
+
+
+def fnv1a(text):
+    hash = 0xCBF29CE484222325
+    for byte in text.encode():
+        hash = ((hash ^ byte) * 0x100000001B3) & 0xFFFFFFFFFFFFFFFF
+    return hash
+
+
+def crc32c(data):
+    crc = 0xFFFFFFFF
+    for byte in data:
+        crc ^= byte
+        for _ in range(8):
+            crc = (crc >> 1) ^ (0x82F63B78 if crc & 1 else 0)
+    return crc ^ 0xFFFFFFFF
+
+
+def page(node_type, items, right_sibling=INVALID_PAGE_NO):
+    """`items` are `(bytes, align)` pairs, added in order like `Page::add_item_v2`. The items of a
+    leaf or internal node after its separator are inserted in ascending key order, which leaves the
+    last one's index behind in the special data, and the node flagged as sequential from the second
+    one on. Nodes without a right sibling are the rightmost of their level, so they're flagged
+    unbounded and their separator item is only a placeholder."""
+    data = bytearray(DATA_SIZE)
+    upper, lower = 0, DATA_SIZE - SPECIAL_SIZE
+    for item, align in items:
+        lower = (lower - len(item)) & ~(align - 1)
+        data[lower:lower + len(item)] = item
+        data[upper:upper + 4] = struct.pack("<HH", lower, len(item))
+        upper += 4
+    flags, last_insert = FLAG_INVALID_LINK, 0
+    if node_type in (INTERNAL, LEAF):
+        flags |= FLAG_SORTED
+        last_insert = len(items) - 1
+        if len(items) > 2:
+            flags |= FLAG_SEQUENTIAL
+        if right_sibling == INVALID_PAGE_NO:
+            flags |= FLAG_UNBOUNDED
+    data[DATA_SIZE - SPECIAL_SIZE:] = struct.pack(
+        "<IBBH", right_sibling, node_type, flags, last_insert
+    )
+    return struct.pack("<III", upper, lower, SPECIAL_SIZE) + bytes(data)
+
+
+def metadata(key_type, value_type, root_no):
+    types = struct.pack("<QQ", fnv1a(key_type), fnv1a(value_type))
+    return page(METADATA, [(types, 1), (struct.pack("<I", root_no), 4)])
+
+
+def u32_tuple_id_tree():
+    """`BTree<KeyU32, ValueTupleId>` holding keys 1 to 3."""
+    items = [(struct.pack("<I", 0xFFFFFFFF), 4)]
+    for key in range(1, 4):
+        items.append((struct.pack("<IIH2x", key, 100 + key, key), 4))
+    return (
+        metadata("johndb::btree::key::KeyU32", "johndb::btree::value::ValueTupleId", 1)
+        + page(LEAF, items)
+    )
+
+
+def bytes_tree():
+    """`BTree<KeyBytes, ValueBytes>` holding `a => x`, `bb => yyy` and `ccc => ""`."""
+    items = [(b"\xff", 1)]
+    for key, value in [(b"a", b"x"), (b"bb", b"yyy"), (b"ccc", b"")]:
+        item = key + value
+        item += b"\0" * (len(item) % 2)
+        item += struct.pack("<HHH", len(key), len(value), len(key))
+        items.append((item, 2))
+    return (
+        metadata("johndb::btree::key::KeyBytes", "johndb::btree::value::ValueBytes", 1)
+        + page(LEAF, items)
+    )
+
+
+def wal_segment():
+    """Log segment 3, starting at LSN 1000, holding records of types 1, 2 and 7 with a short, an
+    empty and a 5KB payload."""
+    header = b"JOHNDWAL" + struct.pack(">IQQ", 1, 3, 1000)
+    segment = header + struct.pack(">I", crc32c(header))
+    records = [(1, b"put key-1"), (2, b""), (7, bytes(range(256)) * 20)]
+    for lsn, (record_type, payload) in enumerate(records, start=1000):
+        record = struct.pack(">IBQ", len(payload), record_type, lsn) + payload
+        segment += record + struct.pack(">I", crc32c(record))
+    return segment
+
+
+if __name__ == "__main__":
+    here = os.path.dirname(os.path.abspath(__file__))
+    for name, contents in [
+        ("u32_tuple_id.pages", u32_tuple_id_tree()),
+        ("bytes.pages", bytes_tree()),
+        ("wal.segment", wal_segment()),
+    ]:
+        with open(os.path.join(here, name), "wb") as f:
+            f.write(contents)